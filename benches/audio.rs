@@ -0,0 +1,98 @@
+//! `cargo bench --bench audio`: the cpu work of the audio sent to and received from
+//! the clients, resampling, pcm / wav conversion, base64 and chunking.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use echokit_server::{services::realtime_ws, util};
+
+/// `secs` of a 440Hz tone, pcm16 at `hz`
+fn tone(hz: u32, secs: u32) -> Vec<u8> {
+    let step = std::f32::consts::TAU * 440.0 / hz as f32;
+    (0..hz * secs)
+        .flat_map(|i| (((i as f32 * step).sin() * 8000.0) as i16).to_le_bytes())
+        .collect()
+}
+
+fn wav(hz: u32, secs: u32) -> Bytes {
+    util::pcm_to_wav(
+        &tone(hz, secs),
+        util::WavConfig {
+            sample_rate: hz,
+            channels: 1,
+            bits_per_sample: 16,
+        },
+    )
+}
+
+fn resample(c: &mut Criterion) {
+    let mut group = c.benchmark_group("resample");
+    for (in_hz, out_hz) in [
+        (16000, 16000),
+        (16000, 24000),
+        (24000, 16000),
+        (48000, 16000),
+    ] {
+        let wav = wav(in_hz, 5);
+        group.throughput(Throughput::Bytes(wav.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{in_hz}->{out_hz}")),
+            &wav,
+            |b, wav| b.iter(|| util::wav_to_pcm16(wav.clone(), out_hz).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+fn pcm_to_wav(c: &mut Criterion) {
+    let pcm = tone(24000, 5);
+    let mut group = c.benchmark_group("pcm_to_wav");
+    group.throughput(Throughput::Bytes(pcm.len() as u64));
+    group.bench_function("24k 5s", |b| {
+        b.iter(|| util::pcm_to_wav(&pcm, util::WavConfig::default()))
+    });
+    group.finish();
+}
+
+fn base64(c: &mut Criterion) {
+    let pcm = tone(24000, 5);
+    // 100ms at 24k
+    let chunk = 4800;
+    let deltas = realtime_ws::audio_deltas(&pcm, chunk);
+    let mut group = c.benchmark_group("base64");
+    group.throughput(Throughput::Bytes(pcm.len() as u64));
+    group.bench_function("audio_deltas", |b| {
+        b.iter(|| realtime_ws::audio_deltas(&pcm, chunk))
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            deltas
+                .iter()
+                .map(|d| realtime_ws::decode_base64(d).unwrap().len())
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+fn chunking(c: &mut Criterion) {
+    let pcm = Bytes::from(tone(16000, 5));
+    let mut group = c.benchmark_group("rechunk");
+    group.throughput(Throughput::Bytes(pcm.len() as u64));
+    // the tcp segments of a streaming tts, cut into 100ms deltas
+    for network in [1400, 4096, 16384] {
+        group.bench_with_input(BenchmarkId::from_parameter(network), &network, |b, n| {
+            b.iter(|| {
+                let mut rechunker = util::Rechunker::new(3200);
+                let mut chunks = 0;
+                for i in (0..pcm.len()).step_by(*n) {
+                    chunks += rechunker.push(pcm.slice(i..(i + n).min(pcm.len()))).len();
+                }
+                chunks + rechunker.finish().is_some() as usize
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, resample, pcm_to_wav, base64, chunking);
+criterion_main!(benches);
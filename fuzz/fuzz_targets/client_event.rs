@@ -0,0 +1,25 @@
+//! `cargo +nightly fuzz run client_event`: the parsing of the realtime client events
+//! never panics, and an accepted event is accepted again once serialized.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/ai/openai/realtime.rs"]
+mod realtime;
+#[allow(dead_code)]
+#[path = "../../src/ai/openai/validate.rs"]
+mod validate;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(event) = validate::parse_client_event(text) {
+        let json = serde_json::to_string(&event).unwrap();
+        if let Err(e) = validate::parse_client_event(&json) {
+            panic!("{json} rejected once serialized: {e}");
+        }
+    }
+});
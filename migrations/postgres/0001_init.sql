@@ -0,0 +1,33 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    device_id TEXT,
+    ended_at TEXT,
+    turns BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (id, started_at)
+);
+
+CREATE TABLE IF NOT EXISTS turns (
+    seq BIGSERIAL PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    device_id TEXT,
+    time TEXT NOT NULL,
+    role TEXT NOT NULL,
+    text TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS turns_device_id ON turns (device_id);
+
+CREATE TABLE IF NOT EXISTS devices (
+    device_id TEXT PRIMARY KEY,
+    data JSONB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS memories (
+    seq BIGSERIAL PRIMARY KEY,
+    id TEXT NOT NULL UNIQUE,
+    device_id TEXT NOT NULL,
+    text TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS memories_device_id ON memories (device_id);
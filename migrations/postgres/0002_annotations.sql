@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS annotations (
+    seq BIGSERIAL PRIMARY KEY,
+    id TEXT NOT NULL UNIQUE,
+    session_id TEXT NOT NULL,
+    device_id TEXT,
+    turn_time TEXT NOT NULL,
+    label TEXT NOT NULL,
+    note TEXT,
+    source TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS annotations_device_id ON annotations (device_id);
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS turn_metrics (
+    seq BIGSERIAL PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    device_id TEXT,
+    kind TEXT NOT NULL,
+    time TEXT NOT NULL,
+    first_audio_ms BIGINT,
+    response_ms BIGINT NOT NULL,
+    error BOOLEAN NOT NULL,
+    intent TEXT
+);
+
+CREATE TABLE IF NOT EXISTS daily_stats (
+    day TEXT PRIMARY KEY,
+    data JSONB NOT NULL
+);
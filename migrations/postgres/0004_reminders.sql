@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS reminders (
+    seq BIGSERIAL PRIMARY KEY,
+    id TEXT NOT NULL UNIQUE,
+    device_id TEXT NOT NULL,
+    text TEXT NOT NULL,
+    due_at TEXT NOT NULL,
+    cron TEXT,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS reminders_device_id ON reminders (device_id);
@@ -0,0 +1 @@
+ALTER TABLE turn_metrics ADD COLUMN IF NOT EXISTS usage JSONB;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS tenant_usage (
+    tenant TEXT NOT NULL,
+    day TEXT NOT NULL,
+    turns BIGINT NOT NULL,
+    llm_input_tokens BIGINT NOT NULL,
+    llm_output_tokens BIGINT NOT NULL,
+    tts_chars BIGINT NOT NULL,
+    asr_ms BIGINT NOT NULL,
+    cost DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (tenant, day)
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS prompts (
+    id SMALLINT PRIMARY KEY CHECK (id = 0),
+    data JSONB NOT NULL
+);
@@ -84,6 +84,7 @@ impl CosyVoiceTTS {
         model: CosyVoiceVersion,
         voice: Option<&str>,
         sample_rate: Option<u32>,
+        prosody: crate::config::Prosody,
         text: &str,
     ) -> anyhow::Result<()> {
         let voice = if let Some(v) = voice {
@@ -114,6 +115,8 @@ impl CosyVoiceTTS {
                     "voice": voice,
                     "format": "pcm",
                     "sample_rate": sample_rate.unwrap_or(24000),
+                    "rate": prosody.speed.unwrap_or(1.0),
+                    "pitch": prosody.pitch.unwrap_or(1.0),
                 },
                 "input": {
                     "text": text
@@ -205,9 +208,15 @@ async fn test_cosyvoice_tts() {
     let text = "你好,我是CosyVoice V2";
 
     let mut tts = CosyVoiceTTS::connect(token).await.unwrap();
-    tts.start_synthesis(CosyVoiceVersion::V2, None, Some(24000), text)
-        .await
-        .unwrap();
+    tts.start_synthesis(
+        CosyVoiceVersion::V2,
+        None,
+        Some(24000),
+        Default::default(),
+        text,
+    )
+    .await
+    .unwrap();
 
     let mut audio_data = bytes::BytesMut::new();
     while let Ok(Some(chunk)) = tts.next_audio_chunk().await {
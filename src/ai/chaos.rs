@@ -0,0 +1,68 @@
+//! Fault injection, `[chaos]` with the `chaos` feature: the asr, tts and llm calls are
+//! delayed, fail or have their llm stream cut at random, so the fallbacks, the error
+//! messages and the cancellation run as they would against a flaky backend.
+
+use std::sync::OnceLock;
+
+use crate::config::ChaosConfig;
+
+static CHAOS: OnceLock<ChaosConfig> = OnceLock::new();
+
+/// once at startup, the later calls are ignored
+pub fn set(config: ChaosConfig) {
+    let _ = CHAOS.set(config);
+}
+
+fn config(provider: &str) -> Option<&'static ChaosConfig> {
+    CHAOS
+        .get()
+        .filter(|c| c.providers.is_empty() || c.providers.iter().any(|p| p == provider))
+}
+
+fn roll(chance: f64) -> bool {
+    chance > 0.0 && rand::random::<f64>() < chance
+}
+
+/// `Err` at the `failure` chance, after a delay at the `latency` chance
+pub async fn inject(provider: &str) -> anyhow::Result<()> {
+    let Some(config) = config(provider) else {
+        return Ok(());
+    };
+    if roll(config.latency) {
+        let ms = rand::random_range(0..=config.max_latency_ms);
+        log::warn!("chaos: {provider} delayed {ms}ms");
+        tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+    }
+    if roll(config.failure) {
+        log::warn!("chaos: {provider} failure");
+        anyhow::bail!("chaos: injected {provider} failure");
+    }
+    Ok(())
+}
+
+/// a stream of `provider` is cut here, at the `partial` chance
+pub fn cut(provider: &str) -> bool {
+    let cut = config(provider).is_some_and(|c| roll(c.partial));
+    if cut {
+        log::warn!("chaos: {provider} stream cut");
+    }
+    cut
+}
+
+#[tokio::test]
+async fn test_chaos() {
+    assert!(!roll(0.0));
+    assert!(roll(1.0));
+    // the other tests of the process see it too, none calls a `chaos_test` provider
+    set(ChaosConfig {
+        providers: vec!["chaos_test".to_string()],
+        latency: 1.0,
+        max_latency_ms: 10,
+        failure: 1.0,
+        partial: 1.0,
+    });
+    assert!(inject("chaos_test").await.is_err());
+    assert!(cut("chaos_test"));
+    assert!(inject("llm").await.is_ok());
+    assert!(!cut("llm"));
+}
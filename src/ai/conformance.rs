@@ -0,0 +1,265 @@
+//! Conformance suite of the asr, tts and llm providers: a backend implements
+//! [`AsrProvider`], [`TtsProvider`] or [`LlmProvider`] and passes the `check_*` of its
+//! kind, the same checks whatever the backend.
+//!
+//! - streaming: the llm text comes in several chunks, then `Stop` and only `Stop`
+//! - cancellation: an aborted llm stream fails with [`LlmAborted`], a call dropped midway
+//!   leaves the provider usable
+//! - error mapping: an unreachable backend is an `Err`, not a panic nor an empty success
+//!
+//! the config types implement the traits, so a new backend added to a config enum is
+//! checked by adding its config to the tests at the bottom.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::{llm, AbortHandle, LlmAborted, StableLLMResponseChunk, StableLlmResponse};
+use crate::config::{LLMConfig, TTSConfig, WhisperASRConfig};
+
+/// the audio of the checks, the sessions resample to it
+const SAMPLE_RATE: u32 = 16000;
+
+#[allow(async_fn_in_trait)]
+pub trait AsrProvider: Send + Sync {
+    /// the transcript of a 16k mono wav
+    async fn transcribe(&self, wav: Bytes) -> anyhow::Result<String>;
+}
+
+#[allow(async_fn_in_trait)]
+pub trait TtsProvider: Send + Sync {
+    /// pcm16 at `out_hz`
+    async fn synthesize(&self, text: &str, out_hz: u32) -> anyhow::Result<Bytes>;
+}
+
+#[allow(async_fn_in_trait)]
+pub trait LlmProvider: Send + Sync {
+    /// the streamed answer to `prompts`, aborted by `abort`
+    async fn stream(
+        &self,
+        prompts: &[llm::Content],
+        abort: &AbortHandle,
+    ) -> anyhow::Result<StableLlmResponse>;
+}
+
+impl AsrProvider for WhisperASRConfig {
+    async fn transcribe(&self, wav: Bytes) -> anyhow::Result<String> {
+        let client = reqwest::Client::new();
+        let transcript = super::transcribe(&client, self, wav, "audio.wav", None, false).await?;
+        Ok(transcript.text)
+    }
+}
+
+impl TtsProvider for TTSConfig {
+    async fn synthesize(&self, text: &str, out_hz: u32) -> anyhow::Result<Bytes> {
+        crate::services::offline::render_pcm(self, text, out_hz).await
+    }
+}
+
+impl LlmProvider for LLMConfig {
+    async fn stream(
+        &self,
+        prompts: &[llm::Content],
+        abort: &AbortHandle,
+    ) -> anyhow::Result<StableLlmResponse> {
+        // the calls of the sessions, the mock included
+        let mut chat_session = super::ChatSession::from_config(self, Default::default());
+        chat_session.messages = prompts.iter().cloned().collect();
+        chat_session.abort_handle = abort.clone();
+        chat_session.complete().await
+    }
+}
+
+/// `ms` of a 440Hz tone at `SAMPLE_RATE`
+fn tone_wav(ms: u32) -> Bytes {
+    let step = std::f32::consts::TAU * 440.0 / SAMPLE_RATE as f32;
+    let pcm = (0..SAMPLE_RATE * ms / 1000)
+        .flat_map(|i| (((i as f32 * step).sin() * 8000.0) as i16).to_le_bytes())
+        .collect::<Vec<_>>();
+    crate::util::pcm_to_wav(
+        &pcm,
+        crate::util::WavConfig {
+            sample_rate: SAMPLE_RATE,
+            channels: 1,
+            bits_per_sample: 16,
+        },
+    )
+}
+
+/// the transcript of `wav` is `expected` if given, a call dropped midway leaves the
+/// provider usable
+pub async fn check_asr<P: AsrProvider>(
+    provider: &P,
+    wav: Bytes,
+    expected: Option<&str>,
+) -> anyhow::Result<()> {
+    let transcript = provider.transcribe(wav.clone()).await?;
+    if let Some(expected) = expected {
+        anyhow::ensure!(
+            transcript.trim() == expected,
+            "transcript {transcript:?}, expected {expected:?}"
+        );
+    }
+    anyhow::ensure!(
+        !transcript.contains("-->"),
+        "transcript {transcript:?} has timestamps"
+    );
+
+    let _ = tokio::time::timeout(Duration::from_millis(1), provider.transcribe(wav.clone())).await;
+    provider
+        .transcribe(wav)
+        .await
+        .map_err(|e| anyhow::anyhow!("after a dropped call: {e}"))?;
+    Ok(())
+}
+
+/// the audio is not empty, whole samples, and a longer text is not shorter
+pub async fn check_tts<P: TtsProvider>(provider: &P, text: &str) -> anyhow::Result<()> {
+    let short = provider.synthesize(text, SAMPLE_RATE).await?;
+    anyhow::ensure!(!short.is_empty(), "no audio for {text:?}");
+    anyhow::ensure!(short.len() % 2 == 0, "{} bytes of pcm16", short.len());
+
+    let longer = format!("{text} {text}");
+    let _ = tokio::time::timeout(
+        Duration::from_millis(1),
+        provider.synthesize(&longer, SAMPLE_RATE),
+    )
+    .await;
+    let long = provider
+        .synthesize(&longer, SAMPLE_RATE)
+        .await
+        .map_err(|e| anyhow::anyhow!("after a dropped call: {e}"))?;
+    anyhow::ensure!(
+        long.len() >= short.len(),
+        "{} bytes for {longer:?}, {} for {text:?}",
+        long.len(),
+        short.len()
+    );
+    Ok(())
+}
+
+/// the answer to `prompts` streams in several chunks then stops, and an abort after
+/// the first chunk ends the stream with [`LlmAborted`]
+pub async fn check_llm<P: LlmProvider>(
+    provider: &P,
+    prompts: &[llm::Content],
+) -> anyhow::Result<()> {
+    let abort = AbortHandle::default();
+    let mut response = provider.stream(prompts, &abort).await?;
+    let mut chunks = vec![];
+    loop {
+        match response.next_chunk().await? {
+            StableLLMResponseChunk::Text(text) => chunks.push(text),
+            StableLLMResponseChunk::Functions(_) => anyhow::bail!("tool calls without tools"),
+            StableLLMResponseChunk::Stop => break,
+        }
+    }
+    anyhow::ensure!(chunks.len() > 1, "{} chunk, not streamed", chunks.len());
+    anyhow::ensure!(
+        chunks.iter().all(|c| !c.is_empty()),
+        "empty chunk in {chunks:?}"
+    );
+    anyhow::ensure!(
+        matches!(response.next_chunk().await?, StableLLMResponseChunk::Stop),
+        "a chunk after the stop"
+    );
+
+    let mut response = provider.stream(prompts, &abort).await?;
+    response.next_chunk().await?;
+    abort.abort();
+    match response.next_chunk().await {
+        Err(e) if e.is::<LlmAborted>() => {}
+        Err(e) => anyhow::bail!("aborted stream error {e}, expected {LlmAborted}"),
+        Ok(_) => anyhow::bail!("a chunk after the abort"),
+    }
+    anyhow::ensure!(
+        matches!(response.next_chunk().await?, StableLLMResponseChunk::Stop),
+        "a chunk after the abort"
+    );
+    Ok(())
+}
+
+/// every call of an unreachable provider fails
+pub async fn check_unreachable(
+    asr: &impl AsrProvider,
+    tts: &impl TtsProvider,
+    llm: &impl LlmProvider,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        asr.transcribe(tone_wav(500)).await.is_err(),
+        "asr succeeded"
+    );
+    anyhow::ensure!(
+        tts.synthesize("Hello.", SAMPLE_RATE).await.is_err(),
+        "tts succeeded"
+    );
+    let prompts = [llm::Content {
+        role: llm::Role::User,
+        message: "Hello".to_string(),
+        tool_calls: None,
+        tool_call_id: None,
+        images: vec![],
+    }];
+    let abort = AbortHandle::default();
+    if let Ok(mut response) = llm.stream(&prompts, &abort).await {
+        anyhow::ensure!(response.next_chunk().await.is_err(), "llm succeeded");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conformance() {
+    let asr: WhisperASRConfig =
+        toml::from_str::<crate::config::ASRConfig>(r#"transcripts = ["turn on the light"]"#)
+            .unwrap()
+            .whisper()
+            .unwrap();
+    check_asr(&asr, tone_wav(500), Some("turn on the light"))
+        .await
+        .unwrap();
+
+    let tts: TTSConfig = toml::from_str(r#"platform = "Mock""#).unwrap();
+    check_tts(&tts, "Hello there.").await.unwrap();
+
+    let llm: LLMConfig = toml::from_str(
+        r#"
+        history = 5
+        [mock]
+        replies = ["It is sunny today, with a light breeze from the west. Tomorrow should be a little cooler, with some rain."]
+        chunk_delay_ms = 20
+        "#,
+    )
+    .unwrap();
+    let prompts = [llm::Content {
+        role: llm::Role::User,
+        message: "What's the weather?".to_string(),
+        tool_calls: None,
+        tool_call_id: None,
+        images: vec![],
+    }];
+    check_llm(&llm, &prompts).await.unwrap();
+
+    // nothing listens on the discard port
+    let unreachable = "http://127.0.0.1:9";
+    let asr = WhisperASRConfig {
+        url: format!("{unreachable}/v1/audio/transcriptions"),
+        ..Default::default()
+    };
+    let tts: TTSConfig = toml::from_str(&format!(
+        r#"
+        platform = "Stable"
+        url = "{unreachable}/v1/audio/speech"
+        speaker = "ad"
+        timeout_sec = 2
+        "#
+    ))
+    .unwrap();
+    let llm: LLMConfig = toml::from_str(&format!(
+        r#"
+        llm_chat_url = "{unreachable}/v1/chat/completions"
+        history = 5
+        "#
+    ))
+    .unwrap();
+    check_unreachable(&asr, &tts, &llm).await.unwrap();
+}
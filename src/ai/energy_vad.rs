@@ -0,0 +1,234 @@
+//! RMS energy + hangover vad, the local fallback when the vad service is down.
+//! Much less accurate than a model, but turns still get segmented.
+
+use super::vad::{SpeechSampleIndex, VAD_SPEECH_END, VAD_SPEECH_START};
+use crate::config::EnergyVadConfig;
+
+/// 20ms frames at 16k
+const FRAME: usize = 320;
+
+/// dBFS of a frame of samples
+fn frame_db(frame: &[i16]) -> f32 {
+    if frame.is_empty() {
+        return -100.0;
+    }
+    let sum: f64 = frame.iter().map(|&s| (s as f64) * (s as f64)).sum();
+    let rms = (sum / frame.len() as f64).sqrt() / i16::MAX as f64;
+    (20.0 * rms.max(1e-5).log10()) as f32
+}
+
+/// background level of room tone: the median frame level of 16k samples, in dBFS
+pub fn noise_floor_db(samples: &[i16]) -> Option<f32> {
+    let mut levels = samples
+        .chunks_exact(FRAME)
+        .map(frame_db)
+        .collect::<Vec<f32>>();
+    if levels.is_empty() {
+        return None;
+    }
+    levels.sort_by(|a, b| a.total_cmp(b));
+    Some(levels[levels.len() / 2])
+}
+
+/// the frames of room tone, up to this above the noise floor, are attenuated
+const GATE_MARGIN_DB: f32 = 6.0;
+const GATE_GAIN: f32 = 0.1;
+
+/// a noise gate for the asr: the room tone of 16k pcm16 le is attenuated, the speech is kept
+pub fn denoise(pcm: &[u8], noise_floor_db: f32) -> Vec<u8> {
+    let mut samples = pcm
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect::<Vec<i16>>();
+    for frame in samples.chunks_mut(FRAME) {
+        if frame_db(frame) < noise_floor_db + GATE_MARGIN_DB {
+            frame
+                .iter_mut()
+                .for_each(|s| *s = (*s as f32 * GATE_GAIN) as i16);
+        }
+    }
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+/// streaming detector of 16k pcm16, emits `speech_start` / `speech_end`
+/// like the realtime vad server
+#[derive(Debug, Clone)]
+pub struct EnergyVad {
+    config: EnergyVadConfig,
+    /// samples of the incomplete frame
+    pending: Vec<i16>,
+    /// number of samples processed
+    pos: usize,
+    /// consecutive loud / quiet frames
+    loud_frames: usize,
+    quiet_frames: usize,
+    /// start of the speech in progress
+    speech_start: Option<usize>,
+}
+
+impl EnergyVad {
+    pub fn new(config: EnergyVadConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::with_capacity(FRAME),
+            pos: 0,
+            loud_frames: 0,
+            quiet_frames: 0,
+            speech_start: None,
+        }
+    }
+
+    fn frames_of_ms(ms: u32) -> usize {
+        (ms as usize * 16 / FRAME).max(1)
+    }
+
+    /// returns the speech start / end events, with their sample positions
+    fn push_samples(&mut self, samples: &[i16]) -> Vec<(&'static str, usize)> {
+        let min_speech = Self::frames_of_ms(self.config.min_speech_ms);
+        let hangover = Self::frames_of_ms(self.config.hangover_ms);
+        let mut events = vec![];
+
+        self.pending.extend_from_slice(samples);
+        let frames = self.pending.len() / FRAME;
+        for i in 0..frames {
+            let frame = &self.pending[i * FRAME..(i + 1) * FRAME];
+            let loud = frame_db(frame) >= self.config.threshold_db;
+            self.pos += FRAME;
+
+            if loud {
+                self.loud_frames += 1;
+                self.quiet_frames = 0;
+            } else {
+                self.quiet_frames += 1;
+                if self.speech_start.is_none() {
+                    self.loud_frames = 0;
+                }
+            }
+
+            match self.speech_start {
+                None if self.loud_frames >= min_speech => {
+                    let start = self.pos - self.loud_frames * FRAME;
+                    self.speech_start = Some(start);
+                    events.push((VAD_SPEECH_START, start));
+                }
+                Some(_) if self.quiet_frames >= hangover => {
+                    self.speech_start = None;
+                    self.loud_frames = 0;
+                    events.push((VAD_SPEECH_END, self.pos - self.quiet_frames * FRAME));
+                }
+                _ => {}
+            }
+        }
+        self.pending.drain(..frames * FRAME);
+
+        events
+    }
+
+    /// feed 16k pcm16 little-endian audio
+    pub fn push_audio_16k_chunk(&mut self, pcm: &[u8]) -> Vec<&'static str> {
+        let samples = pcm
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect::<Vec<i16>>();
+        self.push_samples(&samples)
+            .into_iter()
+            .map(|(event, _)| event)
+            .collect()
+    }
+}
+
+/// speech segments of a whole recording of 16k samples
+pub fn speech_segments(samples: &[i16], config: &EnergyVadConfig) -> Vec<SpeechSampleIndex> {
+    let mut vad = EnergyVad::new(config.clone());
+    let mut segments = vec![];
+    let mut start = None;
+    for (event, pos) in vad.push_samples(samples) {
+        if event == VAD_SPEECH_START {
+            start = Some(pos);
+        } else if let Some(start) = start.take() {
+            segments.push(SpeechSampleIndex {
+                start: start as i64,
+                end: pos as i64,
+            });
+        }
+    }
+    if let Some(start) = start {
+        segments.push(SpeechSampleIndex {
+            start: start as i64,
+            end: samples.len() as i64,
+        });
+    }
+    segments
+}
+
+/// same result as the `vad_url` server for a wav file
+pub async fn vad_detect(
+    config: &EnergyVadConfig,
+    wav_audio: bytes::Bytes,
+) -> anyhow::Result<super::vad::VadResponse> {
+    let (pcm, _) = crate::util::wav_to_pcm16_blocking(wav_audio, 16000).await?;
+    let samples = pcm
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect::<Vec<i16>>();
+    Ok(super::vad::VadResponse {
+        timestamps: speech_segments(&samples, config),
+        error: None,
+    })
+}
+
+#[test]
+fn test_noise_floor() {
+    // room tone around -50 dBFS with a door slam
+    let mut samples = (0..16000)
+        .map(|i| if i % 2 == 0 { 104 } else { -104 })
+        .collect::<Vec<i16>>();
+    samples[3200..4800].fill(20000);
+
+    let floor = noise_floor_db(&samples).unwrap();
+    assert!((floor + 50.0).abs() < 0.1);
+    assert_eq!(noise_floor_db(&[0; 100]), None);
+
+    let config = EnergyVadConfig::default();
+    assert_eq!(config.adapted(Some(floor)).threshold_db, floor + 10.0);
+    assert_eq!(config.adapted(Some(-70.0)).threshold_db, -40.0);
+
+    let pcm = samples
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect::<Vec<u8>>();
+    let denoised = denoise(&pcm, floor);
+    let sample = |i: usize| i16::from_le_bytes([denoised[2 * i], denoised[2 * i + 1]]);
+    assert_eq!(sample(0), 10);
+    assert_eq!(sample(3200), 20000);
+}
+
+#[test]
+fn test_energy_vad() {
+    let config = EnergyVadConfig {
+        fallback: true,
+        threshold_db: -40.0,
+        min_speech_ms: 100,
+        hangover_ms: 300,
+        noise_margin_db: 10.0,
+    };
+
+    // 0.5s silence, 1s tone, 1s silence, a 40ms click
+    let mut samples = vec![0i16; 8000];
+    samples.extend((0..16000).map(|i| if i % 16 < 8 { 8000 } else { -8000 }));
+    samples.extend(vec![0i16; 16000]);
+    samples.extend(vec![8000i16; 640]);
+
+    let segments = speech_segments(&samples, &config);
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].start, 8000);
+    assert_eq!(segments[0].end, 24000);
+
+    let mut vad = EnergyVad::new(config);
+    let pcm: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let events: Vec<&str> = pcm
+        .chunks(1000)
+        .flat_map(|chunk| vad.push_audio_16k_chunk(chunk))
+        .collect();
+    assert_eq!(events, vec![VAD_SPEECH_START, VAD_SPEECH_END]);
+}
@@ -1,6 +1,7 @@
 // connect_config: {"setup": {"model": "models/gemini-2.0-flash-live-001", "generationConfig": {"responseModalities": ["TEXT"]}, "systemInstruction": {"parts": [{"text": "You are a helpful assistant and answer in a friendly tone."}]}}}
 
 use base64::Engine;
+use bytes::Bytes;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Content {
@@ -71,13 +72,13 @@ impl Default for Modality {
 }
 
 #[derive(Debug, Clone)]
-pub struct Blob(Vec<u8>);
+pub struct Blob(Bytes);
 impl Blob {
-    pub fn new(data: Vec<u8>) -> Self {
-        Blob(data)
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        Blob(data.into())
     }
 
-    pub fn into_inner(self) -> Vec<u8> {
+    pub fn into_inner(self) -> Bytes {
         self.0
     }
 }
@@ -99,7 +100,7 @@ impl<'de> serde::Deserialize<'de> for Blob {
         let data = base64::prelude::BASE64_STANDARD
             .decode(s)
             .map_err(serde::de::Error::custom)?;
-        Ok(Blob(data))
+        Ok(Blob(data.into()))
     }
 }
 
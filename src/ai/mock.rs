@@ -0,0 +1,159 @@
+//! Providers that answer without network or api keys, to run the whole websocket flow in ci:
+//! `[llm.mock]` streams canned replies, `platform = "Mock"` tts a sine wave and
+//! `transcripts = [...]` asr scripted transcripts.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+
+use super::{llm, StableLlmResponse};
+use crate::config::{MockASRConfig, MockLLMConfig, MockTTS};
+
+/// the items in order, then again from the first
+fn next<'a>(items: &'a [String], counter: &AtomicUsize) -> Option<&'a str> {
+    if items.is_empty() {
+        return None;
+    }
+    let i = counter.fetch_add(1, Ordering::Relaxed);
+    Some(&items[i % items.len()])
+}
+
+/// the transcript of the next utterance, or the scripted error
+pub fn asr(mock: &MockASRConfig) -> anyhow::Result<Vec<String>> {
+    if let Some(error) = &mock.error {
+        anyhow::bail!("mock asr error: {error}");
+    }
+    Ok(next(&mock.transcripts, &mock.next)
+        .map(|text| vec![text.to_string()])
+        .unwrap_or_default())
+}
+
+/// the next reply, or what the user said last
+pub fn reply<'p>(
+    mock: &MockLLMConfig,
+    prompts: impl IntoIterator<Item = &'p llm::Content>,
+) -> String {
+    if let Some(reply) = next(&mock.replies, &mock.next) {
+        return reply.to_string();
+    }
+    let last = prompts
+        .into_iter()
+        .filter(|c| c.role == llm::Role::User)
+        .last();
+    match last {
+        Some(content) => format!("You said: {}", content.message),
+        None => "Hello!".to_string(),
+    }
+}
+
+/// `reply` streamed as the sse of a chat completion, `chunk_chars` per event
+pub fn llm_response(mock: &MockLLMConfig, reply: &str) -> StableLlmResponse {
+    let chars = reply.chars().collect::<Vec<_>>();
+    let events = chars
+        .chunks(mock.chunk_chars.max(1))
+        .map(|chunk| {
+            let delta = serde_json::json!({
+                "choices": [{ "delta": { "content": chunk.iter().collect::<String>() } }]
+            });
+            Bytes::from(format!("data: {delta}\n\n"))
+        })
+        .chain([Bytes::from_static(b"data: [DONE]\n\n")])
+        .collect::<Vec<_>>();
+
+    let delay = std::time::Duration::from_millis(mock.chunk_delay_ms);
+    let stream = futures_util::stream::iter(events).then(move |event| async move {
+        tokio::time::sleep(delay).await;
+        Ok::<_, std::io::Error>(event)
+    });
+    let response = http::Response::new(reqwest::Body::wrap_stream(stream));
+
+    StableLlmResponse {
+        stopped: false,
+        response: Some(response.into()),
+        abort: None,
+        string_buffer: String::new(),
+        fast_first_chunk: false,
+        first_chunk_sent: false,
+        pending_events: Default::default(),
+    }
+}
+
+/// a wav of 16 bit mono pcm at `sample_rate`, `ms_per_char` for each char of the text.
+/// the speed shortens it and the pitch raises the frequency
+pub fn tts(mock: &MockTTS, text: &str, sample_rate: u32) -> Bytes {
+    let speed = mock.prosody.speed.unwrap_or(1.0);
+    let ms = (text.chars().count() as f32 * mock.ms_per_char as f32 / speed) as u64;
+    let samples = sample_rate as u64 * ms / 1000;
+    let frequency_hz = mock.frequency_hz * mock.prosody.pitch.unwrap_or(1.0);
+    let step = std::f32::consts::TAU * frequency_hz / sample_rate as f32;
+    let mut pcm = Vec::with_capacity(samples as usize * 2);
+    for i in 0..samples {
+        let sample = ((i as f32 * step).sin() * i16::MAX as f32 * 0.3) as i16;
+        pcm.extend_from_slice(&sample.to_le_bytes());
+    }
+    crate::util::pcm_to_wav(
+        &pcm,
+        crate::util::WavConfig {
+            sample_rate,
+            channels: 1,
+            bits_per_sample: 16,
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_mock_providers() {
+    let asr_config = MockASRConfig {
+        transcripts: vec!["hello".to_string(), "bye".to_string()],
+        error: None,
+        next: Default::default(),
+    };
+    assert_eq!(asr(&asr_config).unwrap(), ["hello"]);
+    assert_eq!(asr(&asr_config).unwrap(), ["bye"]);
+    assert_eq!(asr(&asr_config).unwrap(), ["hello"]);
+    let failing = MockASRConfig {
+        error: Some("timeout".to_string()),
+        ..asr_config
+    };
+    assert!(asr(&failing).is_err());
+
+    let llm_config = MockLLMConfig {
+        replies: vec![],
+        chunk_chars: 3,
+        chunk_delay_ms: 0,
+        next: Default::default(),
+    };
+    let question = llm::Content {
+        role: llm::Role::User,
+        message: "what time is it?".to_string(),
+        tool_calls: None,
+        tool_call_id: None,
+        images: vec![],
+    };
+    let reply = reply(&llm_config, [&question]);
+    assert_eq!(reply, "You said: what time is it?");
+    let mut response = llm_response(&llm_config, &reply);
+    let mut text = String::new();
+    while let super::StableLLMResponseChunk::Text(chunk) = response.next_chunk().await.unwrap() {
+        text.push_str(&chunk);
+    }
+    assert_eq!(text, reply);
+
+    let tts_config = MockTTS {
+        speaker: String::new(),
+        frequency_hz: 440.0,
+        ms_per_char: 50,
+        prosody: Default::default(),
+    };
+    // 10 chars of 50ms at 16k, 2 bytes a sample after the 44 bytes header
+    assert_eq!(tts(&tts_config, "0123456789", 16000).len(), 44 + 16000);
+    let faster = MockTTS {
+        prosody: crate::config::Prosody {
+            speed: Some(2.0),
+            pitch: None,
+        },
+        ..tts_config
+    };
+    assert_eq!(tts(&faster, "0123456789", 16000).len(), 44 + 8000);
+}
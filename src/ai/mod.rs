@@ -1,5 +1,6 @@
 use std::collections::LinkedList;
 
+use bytes::Bytes;
 use openai::tool::{McpToolAdapter, ToolSet};
 use reqwest::multipart::Part;
 use rmcp::{
@@ -10,11 +11,28 @@ use rmcp::{
 
 /// 阿里百炼
 pub mod bailian;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(test)]
+pub mod conformance;
+pub mod energy_vad;
 pub mod gemini;
+pub mod mock;
+pub mod moderation;
 pub mod openai;
+pub mod reasoning;
+pub mod silero_vad;
+pub mod speech_text;
 pub mod store;
 pub mod tts;
+pub mod turn;
 pub mod vad;
+pub mod wakeword;
+pub mod zh_text;
+
+/// appended to an answer interrupted by the user, see
+/// [`ChatSession::add_truncated_assistant_message`]
+pub const TRUNCATED_MARK: &str = " [interrupted by the user]";
 
 #[derive(Debug, serde::Deserialize)]
 struct AsrResult {
@@ -36,18 +54,19 @@ impl AsrResult {
     }
 }
 
-/// wav_audio: 16bit,16k,single-channel.
-pub async fn asr(
-    client: &reqwest::Client,
-    asr_url: &str,
-    api_key: &str,
+/// the form of a transcription request, `file_name` tells the server the format
+fn asr_form(
+    audio: Bytes,
+    file_name: &str,
     model: &str,
     lang: &str,
     prompt: &str,
-    wav_audio: Vec<u8>,
-) -> anyhow::Result<Vec<String>> {
-    let mut form =
-        reqwest::multipart::Form::new().part("file", Part::bytes(wav_audio).file_name("audio.wav"));
+) -> reqwest::multipart::Form {
+    let len = audio.len() as u64;
+    let mut form = reqwest::multipart::Form::new().part(
+        "file",
+        Part::stream_with_length(audio, len).file_name(file_name.to_string()),
+    );
 
     if !lang.is_empty() {
         form = form.text("language", lang.to_string());
@@ -60,7 +79,16 @@ pub async fn asr(
     if !prompt.is_empty() {
         form = form.text("prompt", prompt.to_string());
     }
+    form
+}
 
+/// shared by the sessions and `/v1/asr`
+async fn send_asr(
+    client: &reqwest::Client,
+    asr_url: &str,
+    api_key: &str,
+    form: reqwest::multipart::Form,
+) -> anyhow::Result<reqwest::Response> {
     let builder = client.post(asr_url).multipart(form);
 
     let res = if !api_key.is_empty() {
@@ -72,6 +100,21 @@ pub async fn asr(
     }
     .send()
     .await?;
+    Ok(res)
+}
+
+/// wav_audio: 16bit,16k,single-channel.
+pub async fn asr(
+    client: &reqwest::Client,
+    asr_url: &str,
+    api_key: &str,
+    model: &str,
+    lang: &str,
+    prompt: &str,
+    wav_audio: Bytes,
+) -> anyhow::Result<Vec<String>> {
+    let form = asr_form(wav_audio, "audio.wav", model, lang, prompt);
+    let res = send_asr(client, asr_url, api_key, form).await?;
 
     let r: serde_json::Value = res.json().await?;
     log::debug!("ASR response: {:#?}", r);
@@ -85,7 +128,7 @@ pub async fn asr(
 async fn test_asr() {
     let asr_url = "http://34.44.85.57:9092/v1/audio/transcriptions";
     let lang = "zh";
-    let wav_audio = std::fs::read("./resources/test/out.wav").unwrap();
+    let wav_audio = Bytes::from(std::fs::read("./resources/test/out.wav").unwrap());
     let client = reqwest::Client::new();
     let text = asr(
         &client,
@@ -107,7 +150,7 @@ async fn test_groq_asr() {
     let groq_api_key = std::env::var("GROQ_API_KEY").unwrap_or_default();
     let asr_url = "https://api.groq.com/openai/v1/audio/transcriptions";
     let lang = "zh";
-    let wav_audio = std::fs::read("./resources/test/out.wav").unwrap();
+    let wav_audio = Bytes::from(std::fs::read("./resources/test/out.wav").unwrap());
     let client = reqwest::Client::new();
 
     let text = asr(
@@ -124,6 +167,155 @@ async fn test_groq_asr() {
     println!("ASR result: {:?}", text);
 }
 
+/// a timed part of a transcript
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TranscriptSegment {
+    pub start_ms: u32,
+    pub end_ms: u32,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Transcript {
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<TranscriptSegment>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct VerboseAsrResult {
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    language: Option<String>,
+    /// `verbose_json` of the openai api, in seconds
+    #[serde(default)]
+    segments: Vec<VerboseAsrSegment>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct VerboseAsrSegment {
+    start: f64,
+    end: f64,
+    text: String,
+}
+
+/// `00:01.500` or `00:00:01.500`
+fn parse_timestamp_ms(ts: &str) -> Option<u32> {
+    let mut secs = 0.0;
+    for part in ts.trim().split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some((secs * 1000.0).round() as u32)
+}
+
+/// `[00:01.500 --> 00:03.000] text`, the lines of whisper.cpp style servers
+fn parse_timestamped_line(line: &str) -> Option<TranscriptSegment> {
+    let (range, text) = line.strip_prefix('[')?.split_once("] ")?;
+    let (start, end) = range.split_once(" --> ")?;
+    Some(TranscriptSegment {
+        start_ms: parse_timestamp_ms(start)?,
+        end_ms: parse_timestamp_ms(end)?,
+        text: text.trim().to_string(),
+    })
+}
+
+/// noise tags like `(music)` are dropped, traditional chinese is converted to simplified
+pub(crate) fn normalize_transcript(text: &str) -> String {
+    let text = text.trim();
+    if text.starts_with('(') {
+        return String::new();
+    }
+    hanconv::tw2sp(text)
+}
+
+/// transcript of an uploaded file, `file_name` tells the server its format
+/// (`audio.wav`, `audio.ogg`), same cleanup as the device sessions
+pub async fn transcribe(
+    client: &reqwest::Client,
+    asr: &crate::config::WhisperASRConfig,
+    audio: Bytes,
+    file_name: &str,
+    language: Option<&str>,
+    timestamps: bool,
+) -> anyhow::Result<Transcript> {
+    #[cfg(feature = "chaos")]
+    chaos::inject("asr").await?;
+
+    if let Some(mock) = &asr.mock {
+        return Ok(Transcript {
+            text: mock::asr(mock)?.join("\n"),
+            language: None,
+            segments: vec![],
+        });
+    }
+    let lang = language.unwrap_or(&asr.lang);
+    let mut form = asr_form(audio, file_name, &asr.model, lang, &asr.prompt);
+    if timestamps {
+        form = form.text("response_format", "verbose_json");
+    }
+    let r: VerboseAsrResult = send_asr(client, &asr.url, &asr.api_key, form)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut segments = r
+        .segments
+        .iter()
+        .map(|s| TranscriptSegment {
+            start_ms: (s.start * 1000.0).round() as u32,
+            end_ms: (s.end * 1000.0).round() as u32,
+            text: normalize_transcript(&s.text),
+        })
+        .collect::<Vec<_>>();
+    if timestamps && segments.is_empty() {
+        segments = r
+            .text
+            .lines()
+            .filter_map(parse_timestamped_line)
+            .map(|s| TranscriptSegment {
+                text: normalize_transcript(&s.text),
+                ..s
+            })
+            .collect();
+    }
+    segments.retain(|s| !s.text.is_empty());
+
+    let text = AsrResult { text: r.text }
+        .parse_text()
+        .iter()
+        .map(|line| normalize_transcript(line))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(Transcript {
+        text,
+        language: r
+            .language
+            .or_else(|| (!lang.is_empty()).then(|| lang.to_string())),
+        segments,
+    })
+}
+
+#[test]
+fn test_parse_timestamped_line() {
+    assert_eq!(
+        parse_timestamped_line("[00:01.500 --> 00:03.000] 你好"),
+        Some(TranscriptSegment {
+            start_ms: 1500,
+            end_ms: 3000,
+            text: "你好".to_string(),
+        })
+    );
+    assert_eq!(parse_timestamp_ms("01:00:02.25"), Some(3602250));
+    assert_eq!(parse_timestamped_line("你好"), None);
+    assert_eq!(normalize_transcript("(Music)"), "");
+}
+
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct StableLlmRequest {
     stream: bool,
@@ -145,14 +337,127 @@ pub enum StableLLMResponseChunk {
     Stop,
 }
 
+/// aborts the in-flight llm streams of a [`ChatSession`], cloneable so it can be
+/// triggered while the session is busy generating
+#[derive(Debug, Clone)]
+pub struct AbortHandle(std::sync::Arc<tokio::sync::watch::Sender<u64>>);
+
+impl Default for AbortHandle {
+    fn default() -> Self {
+        Self(std::sync::Arc::new(tokio::sync::watch::channel(0).0))
+    }
+}
+
+impl AbortHandle {
+    /// abort the streams started before this call
+    pub fn abort(&self) {
+        self.0.send_modify(|n| *n += 1);
+    }
+
+    fn subscribe(&self) -> tokio::sync::watch::Receiver<u64> {
+        self.0.subscribe()
+    }
+
+    /// the aborts from now on
+    pub fn watch(&self) -> AbortWatch {
+        let rx = self.subscribe();
+        let start = *rx.borrow();
+        AbortWatch { rx, start }
+    }
+}
+
+/// see [`AbortHandle::watch`]
+#[derive(Debug)]
+pub struct AbortWatch {
+    rx: tokio::sync::watch::Receiver<u64>,
+    start: u64,
+}
+
+impl AbortWatch {
+    pub fn is_aborted(&self) -> bool {
+        *self.rx.borrow() != self.start
+    }
+
+    /// resolves once aborted
+    pub async fn aborted(&mut self) {
+        let start = self.start;
+        let _ = self.rx.wait_for(|n| *n != start).await;
+    }
+}
+
+/// returned by [`StableLlmResponse::next_chunk`] after [`AbortHandle::abort`]
+#[derive(Debug)]
+pub struct LlmAborted;
+
+impl std::fmt::Display for LlmAborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "llm stream aborted")
+    }
+}
+
+impl std::error::Error for LlmAborted {}
+
+/// a non-success status of the llm endpoint
+#[derive(Debug)]
+pub struct LlmHttpError {
+    pub status: reqwest::StatusCode,
+    pub headers: reqwest::header::HeaderMap,
+    pub body: String,
+}
+
+impl std::fmt::Display for LlmHttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "llm failed, status:{},\nheader:{:?}\n body:{}",
+            self.status, self.headers, self.body
+        )
+    }
+}
+
+impl std::error::Error for LlmHttpError {}
+
+/// worth retrying: a timeout, a dropped connection, a 429 or a 5xx
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    let transient_status = |status: reqwest::StatusCode| {
+        status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || status == reqwest::StatusCode::REQUEST_TIMEOUT
+            || (status.is_server_error() && status != reqwest::StatusCode::NOT_IMPLEMENTED)
+    };
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<LlmHttpError>() {
+            return transient_status(e.status);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout() || e.is_connect() || e.status().is_some_and(transient_status);
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            return matches!(
+                e.kind(),
+                ConnectionReset | ConnectionAborted | BrokenPipe | TimedOut | UnexpectedEof
+            );
+        }
+        cause.is::<tokio::time::error::Elapsed>()
+    })
+}
+
 pub struct StableLlmResponse {
     stopped: bool,
-    response: reqwest::Response,
+    /// dropped on abort, which closes the http request
+    response: Option<reqwest::Response>,
+    abort: Option<tokio::sync::watch::Receiver<u64>>,
     string_buffer: String,
+    /// flush the first clause as soon as it is complete
+    fast_first_chunk: bool,
+    first_chunk_sent: bool,
+    /// sse events of a network chunk not parsed yet, see [`crate::util::is_deterministic`]
+    pending_events: std::collections::VecDeque<String>,
 }
 
 impl StableLlmResponse {
     const CHUNK_SIZE: usize = 50;
+    const FIRST_CLAUSE_MIN_CHARS: usize = 4;
 
     fn return_string_buffer(&mut self) -> anyhow::Result<StableLLMResponseChunk> {
         self.stopped = true;
@@ -197,25 +502,107 @@ impl StableLlmResponse {
         }
     }
 
+    /// Split off the first clause (ending in a comma or sentence punctuation) of the buffer,
+    /// so it can be sent to TTS before the first full sentence is generated.
+    fn push_first_clause(string_buffer: &mut String, s: &str) -> Option<String> {
+        string_buffer.push_str(s);
+
+        let split = string_buffer
+            .char_indices()
+            .enumerate()
+            .find_map(|(n, (i, c))| {
+                if n + 1 < Self::FIRST_CLAUSE_MIN_CHARS {
+                    return None;
+                }
+                if !matches!(
+                    c,
+                    ',' | '.'
+                        | '!'
+                        | '?'
+                        | ';'
+                        | ':'
+                        | '，'
+                        | '、'
+                        | '：'
+                        | '。'
+                        | '！'
+                        | '？'
+                        | '；'
+                        | '\n'
+                ) {
+                    return None;
+                }
+                let split = i + c.len_utf8();
+                // "1,000" or "example.com" is not a clause boundary
+                if c.is_ascii()
+                    && string_buffer[split..]
+                        .chars()
+                        .next()
+                        .is_some_and(|next| !next.is_whitespace())
+                {
+                    return None;
+                }
+                Some(split)
+            })?;
+
+        let rest = string_buffer.split_off(split);
+        Some(std::mem::replace(string_buffer, rest))
+    }
+
     pub async fn next_chunk(&mut self) -> anyhow::Result<StableLLMResponseChunk> {
         let mut chunk_ret = String::new();
         loop {
             if self.stopped {
                 return Ok(StableLLMResponseChunk::Stop);
             }
-
-            let body = self.response.chunk().await?;
-            if body.is_none() {
-                return self.return_string_buffer();
+            #[cfg(feature = "chaos")]
+            if self.first_chunk_sent && chaos::cut("llm") {
+                self.stopped = true;
+                self.response = None;
+                return Err(anyhow::anyhow!("chaos: llm stream cut"));
             }
-            let body = body.unwrap();
-            let body = if chunk_ret.is_empty() {
-                String::from_utf8_lossy(&body).to_string()
+
+            let body = if let Some(event) = self.pending_events.pop_front() {
+                event
+            } else {
+                let Some(response) = self.response.as_mut() else {
+                    return Ok(StableLLMResponseChunk::Stop);
+                };
+                let body = match &mut self.abort {
+                    Some(abort) => tokio::select! {
+                        body = response.chunk() => body?,
+                        Ok(_) = abort.changed() => {
+                            self.stopped = true;
+                            self.response = None;
+                            return Err(LlmAborted.into());
+                        }
+                    },
+                    None => response.chunk().await?,
+                };
+                if body.is_none() {
+                    return self.return_string_buffer();
+                }
+                let body = body.unwrap();
+                if chunk_ret.is_empty() {
+                    String::from_utf8_lossy(&body).to_string()
+                } else {
+                    chunk_ret.push_str(&String::from_utf8_lossy(&body));
+                    let new_body = chunk_ret;
+                    chunk_ret = String::new();
+                    new_body
+                }
+            };
+            // the text is cut the same way however the network grouped the events
+            let body = if crate::util::is_deterministic() {
+                let mut events = body
+                    .split("data: ")
+                    .filter(|s| !s.is_empty())
+                    .map(|s| format!("data: {s}"));
+                let first = events.next().unwrap_or_default();
+                self.pending_events.extend(events);
+                first
             } else {
-                chunk_ret.push_str(&String::from_utf8_lossy(&body));
-                let new_body = chunk_ret;
-                chunk_ret = String::new();
-                new_body
+                body
             };
 
             log::trace!("llm response chunk body: {body}");
@@ -249,8 +636,14 @@ impl StableLlmResponse {
             log::trace!("llm response tools: {:#?}", tools);
 
             if tools.is_empty() {
-                if let Some(new_str) = Self::push_str(&mut self.string_buffer, &chunks) {
+                let new_str = if self.fast_first_chunk && !self.first_chunk_sent {
+                    Self::push_first_clause(&mut self.string_buffer, &chunks)
+                } else {
+                    Self::push_str(&mut self.string_buffer, &chunks)
+                };
+                if let Some(new_str) = new_str {
                     log::trace!("llm response text: {new_str}");
+                    self.first_chunk_sent = true;
                     return Ok(StableLLMResponseChunk::Text(new_str));
                 }
             } else {
@@ -261,6 +654,120 @@ impl StableLlmResponse {
     }
 }
 
+#[test]
+fn test_truncated_assistant_message() {
+    let mut chat_session = ChatSession::new(
+        String::new(),
+        String::new(),
+        String::new(),
+        None,
+        10,
+        ToolSet::default(),
+    );
+    chat_session.add_truncated_assistant_message("  ".to_string());
+    assert!(chat_session.messages.is_empty());
+    chat_session.add_truncated_assistant_message("It is sunny, and tomorrow ".to_string());
+    let message = chat_session.messages.back().unwrap();
+    assert_eq!(message.role, llm::Role::Assistant);
+    assert_eq!(
+        message.message,
+        format!("It is sunny, and tomorrow{TRUNCATED_MARK}")
+    );
+}
+
+#[tokio::test]
+async fn test_retry() {
+    let status = |status: u16| -> anyhow::Error {
+        LlmHttpError {
+            status: reqwest::StatusCode::from_u16(status).unwrap(),
+            headers: Default::default(),
+            body: String::new(),
+        }
+        .into()
+    };
+    assert!(is_transient(&status(429)));
+    assert!(is_transient(&status(503)));
+    assert!(!is_transient(&status(400)));
+    assert!(!is_transient(&status(501)));
+    let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+    assert!(is_transient(
+        &anyhow::Error::from(reset).context("llm stream")
+    ));
+    assert!(!is_transient(&anyhow::anyhow!("invalid api key")));
+
+    let retry = crate::config::RetryConfig {
+        attempts: 3,
+        backoff_ms: 1,
+        max_backoff_ms: 3,
+    };
+    let delays = (0..4).map(|i| retry.backoff(i)).collect::<Vec<_>>();
+    let ms = std::time::Duration::from_millis;
+    assert_eq!(delays, vec![Some(ms(1)), Some(ms(2)), Some(ms(3)), None]);
+
+    let mut chat_session = ChatSession::new(
+        String::new(),
+        String::new(),
+        String::new(),
+        None,
+        10,
+        ToolSet::default(),
+    );
+    chat_session.retry = retry;
+    let mut abort = chat_session.abort_handle.watch();
+    assert!(chat_session.wait_retry(&status(429), 0, &mut abort).await);
+    assert!(!chat_session.wait_retry(&status(429), 3, &mut abort).await);
+    assert!(
+        !chat_session
+            .wait_retry(&LlmAborted.into(), 0, &mut abort)
+            .await
+    );
+    chat_session.abort_handle.abort();
+    assert!(!chat_session.wait_retry(&status(429), 0, &mut abort).await);
+}
+
+#[tokio::test]
+async fn test_abort_before_first_chunk() {
+    // an llm that accepts the request and never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!(
+        "http://{}/v1/chat/completions",
+        listener.local_addr().unwrap()
+    );
+    tokio::spawn(async move {
+        let _conn = listener.accept().await;
+        std::future::pending::<()>().await;
+    });
+
+    let mut chat_session = ChatSession::new(
+        url,
+        String::new(),
+        String::new(),
+        None,
+        10,
+        Default::default(),
+    );
+    chat_session.add_user_message("hello".to_string());
+    let handle = chat_session.abort_handle.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        handle.abort();
+    });
+    let r = tokio::time::timeout(std::time::Duration::from_secs(5), chat_session.complete())
+        .await
+        .expect("the abort ends the request");
+    assert!(r.err().is_some_and(|e| e.is::<LlmAborted>()));
+}
+
+#[test]
+fn test_abort_handle() {
+    let handle = AbortHandle::default();
+    let rx = handle.subscribe();
+    handle.clone().abort();
+    assert!(rx.has_changed().unwrap());
+    // streams started after the abort are not affected
+    assert!(!handle.subscribe().has_changed().unwrap());
+}
+
 #[test]
 fn test_push_str() {
     let mut string_buffer = String::new();
@@ -287,6 +794,21 @@ fn test_push_str() {
     println!("s: {s:?}");
 }
 
+#[test]
+fn test_push_first_clause() {
+    let mut string_buffer = String::new();
+    let s = StableLlmResponse::push_first_clause(&mut string_buffer, "好的，");
+    assert!(s.is_none());
+    let s = StableLlmResponse::push_first_clause(&mut string_buffer, "我来帮你查一下，今天");
+    assert_eq!(s.as_deref(), Some("好的，我来帮你查一下，"));
+    assert_eq!(string_buffer, "今天");
+
+    let mut string_buffer = String::new();
+    let s = StableLlmResponse::push_first_clause(&mut string_buffer, "It costs 1,000 dollars, ");
+    assert_eq!(s.as_deref(), Some("It costs 1,000 dollars,"));
+    assert_eq!(string_buffer, " ");
+}
+
 pub mod llm {
     use std::fmt::Display;
 
@@ -349,19 +871,114 @@ pub mod llm {
         }
     }
 
-    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    #[derive(Debug, Clone, serde::Deserialize)]
+    #[serde(from = "RawContent")]
     pub struct Content {
-        #[serde(default)]
         pub role: Role,
 
-        #[serde(rename = "content")]
-        #[serde(default)]
         pub message: String,
 
-        #[serde(skip_serializing_if = "Option::is_none")]
         pub tool_calls: Option<Vec<ToolCall>>,
-        #[serde(skip_serializing_if = "Option::is_none")]
         pub tool_call_id: Option<String>,
+
+        /// image urls (or `data:` urls) sent along the message to vision models
+        pub images: Vec<String>,
+    }
+
+    /// a [`Content`] as read, `content` is a string or an array of parts
+    #[derive(serde::Deserialize)]
+    struct RawContent {
+        #[serde(default)]
+        role: Role,
+        #[serde(default)]
+        content: Option<RawMessage>,
+        #[serde(default)]
+        tool_calls: Option<Vec<ToolCall>>,
+        #[serde(default)]
+        tool_call_id: Option<String>,
+        #[serde(default)]
+        images: Vec<String>,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum RawMessage {
+        Text(String),
+        Parts(Vec<RawPart>),
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum RawPart {
+        Text {
+            text: String,
+        },
+        ImageUrl {
+            image_url: RawImageUrl,
+        },
+        #[serde(other)]
+        Other,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct RawImageUrl {
+        url: String,
+    }
+
+    impl From<RawContent> for Content {
+        fn from(raw: RawContent) -> Self {
+            let mut message = String::new();
+            let mut images = raw.images;
+            match raw.content {
+                Some(RawMessage::Text(text)) => message = text,
+                Some(RawMessage::Parts(parts)) => {
+                    for part in parts {
+                        match part {
+                            RawPart::Text { text } => message.push_str(&text),
+                            RawPart::ImageUrl { image_url } => images.push(image_url.url),
+                            RawPart::Other => {}
+                        }
+                    }
+                }
+                None => {}
+            }
+            Content {
+                role: raw.role,
+                message,
+                tool_calls: raw.tool_calls,
+                tool_call_id: raw.tool_call_id,
+                images,
+            }
+        }
+    }
+
+    /// `content` is a plain string, or an array of text / image_url parts when there are images
+    impl serde::Serialize for Content {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::SerializeMap;
+
+            let mut map = serializer.serialize_map(None)?;
+            map.serialize_entry("role", &self.role)?;
+            if self.images.is_empty() {
+                map.serialize_entry("content", &self.message)?;
+            } else {
+                let mut parts = Vec::with_capacity(self.images.len() + 1);
+                if !self.message.is_empty() {
+                    parts.push(serde_json::json!({ "type": "text", "text": self.message }));
+                }
+                parts.extend(self.images.iter().map(
+                    |url| serde_json::json!({ "type": "image_url", "image_url": { "url": url } }),
+                ));
+                map.serialize_entry("content", &parts)?;
+            }
+            if let Some(tool_calls) = &self.tool_calls {
+                map.serialize_entry("tool_calls", tool_calls)?;
+            }
+            if let Some(tool_call_id) = &self.tool_call_id {
+                map.serialize_entry("tool_call_id", tool_call_id)?;
+            }
+            map.end()
+        }
     }
 
     impl AsRef<Content> for Content {
@@ -404,12 +1021,63 @@ pub mod llm {
         pub choices: Vec<StableStreamChunkChoices>,
     }
 
+    /// the tokens of `text` for the usage, a cjk character is about a token and the other
+    /// text about four characters a token
+    pub fn estimate_tokens(text: &str) -> u64 {
+        let (wide, narrow) = text.chars().fold((0u64, 0u64), |(wide, narrow), c| {
+            if c.is_ascii() {
+                (wide, narrow + 1)
+            } else {
+                (wide + 1, narrow)
+            }
+        });
+        wide + narrow.div_ceil(4)
+    }
+
     #[test]
     fn test_json() {
         let json_str = r#"{"role":"user","content":null}"#;
         let content = serde_json::from_str::<Content>(json_str);
         println!("content: {:#?}", content);
     }
+
+    #[test]
+    fn test_serialize_images() {
+        let mut content = Content {
+            role: Role::User,
+            message: "这是什么".to_string(),
+            tool_calls: None,
+            tool_call_id: None,
+            images: vec![],
+        };
+        assert_eq!(
+            serde_json::to_value(&content).unwrap(),
+            serde_json::json!({ "role": "user", "content": "这是什么" })
+        );
+
+        content
+            .images
+            .push("data:image/jpeg;base64,/9j/".to_string());
+        assert_eq!(
+            serde_json::to_value(&content).unwrap(),
+            serde_json::json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "这是什么" },
+                    { "type": "image_url", "image_url": { "url": "data:image/jpeg;base64,/9j/" } },
+                ],
+            })
+        );
+
+        // read back the same
+        let json = serde_json::to_string(&content).unwrap();
+        let read = serde_json::from_str::<Content>(&json).unwrap();
+        assert_eq!(read.message, content.message);
+        assert_eq!(read.images, content.images);
+        let read = serde_json::from_str::<Content>(r#"{"role":"user","content":"hi"}"#).unwrap();
+        assert_eq!(read.message, "hi");
+        assert!(read.images.is_empty());
+    }
 }
 
 pub async fn llm_stable<'p, I: IntoIterator<Item = C>, C: AsRef<llm::Content>>(
@@ -452,22 +1120,26 @@ pub async fn llm_stable<'p, I: IntoIterator<Item = C>, C: AsRef<llm::Content>>(
         .send()
         .await?;
 
-    let state = response.status();
-    if !state.is_success() {
+    let status = response.status();
+    if !status.is_success() {
         let headers = response.headers().clone();
         let body = response.text().await?;
-        return Err(anyhow::anyhow!(
-            "llm failed, status:{},\nheader:{:?}\n body:{}",
-            state,
+        return Err(LlmHttpError {
+            status,
             headers,
-            body
-        ));
+            body,
+        }
+        .into());
     }
 
     Ok(StableLlmResponse {
         stopped: false,
-        response,
+        response: Some(response),
+        abort: None,
         string_buffer: String::new(),
+        fast_first_chunk: false,
+        first_chunk_sent: false,
+        pending_events: Default::default(),
     })
 }
 
@@ -483,12 +1155,14 @@ async fn test_stable_llm() {
             message: "你是一个聪明的AI助手，你叫做胡桃".to_string(),
             tool_calls: None,
             tool_call_id: None,
+            images: vec![],
         },
         llm::Content {
             role: llm::Role::User,
             message: "给我介绍一下妲己".to_string(),
             tool_calls: None,
             tool_call_id: None,
+            images: vec![],
         },
     ];
 
@@ -531,6 +1205,13 @@ async fn test_stable_llm() {
     }
 }
 
+/// the history before a turn, see [`ChatSession::rollback`]
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryMark {
+    len: usize,
+    dropped: usize,
+}
+
 pub struct ChatSession {
     pub api_key: String,
     pub model: String,
@@ -540,8 +1221,30 @@ pub struct ChatSession {
     pub history: usize,
 
     pub system_prompts: Vec<llm::Content>,
+    /// system messages of the current turn only, the recalled memories and passages
+    pub context: Vec<llm::Content>,
     pub messages: LinkedList<llm::Content>,
     pub tools: ToolSet<McpToolAdapter>,
+    /// tools executed by the caller instead of mcp, answered with [`ChatSession::add_tool_result`]
+    pub builtin_tools: Vec<llm::Function>,
+
+    /// see [`crate::config::LLMConfig::fast_first_chunk`]
+    pub fast_first_chunk: bool,
+    /// see [`crate::config::LLMConfig::vision`]
+    pub vision: bool,
+    /// spoken responses are cut after this many sentences
+    pub max_spoken_sentences: Option<usize>,
+    /// how code blocks and footnotes of the responses are spoken
+    pub code_blocks: crate::config::CodeBlockConfig,
+    /// see [`crate::config::LLMConfig::mock`]
+    pub mock: Option<crate::config::MockLLMConfig>,
+    /// see [`crate::config::LLMConfig::retry`]
+    pub retry: crate::config::RetryConfig,
+    pub abort_handle: AbortHandle,
+    /// the estimated tokens of the prompts sent since the caller took them
+    pub prompt_tokens: u64,
+    /// the messages dropped out of the `history` so far
+    dropped: usize,
 }
 
 impl ChatSession {
@@ -560,29 +1263,195 @@ impl ChatSession {
             chat_id,
             history,
             system_prompts: Vec::new(),
+            context: Vec::new(),
             messages: LinkedList::new(),
             tools,
+            builtin_tools: Vec::new(),
+            fast_first_chunk: false,
+            vision: false,
+            max_spoken_sentences: None,
+            code_blocks: Default::default(),
+            mock: None,
+            retry: Default::default(),
+            abort_handle: AbortHandle::default(),
+            prompt_tokens: 0,
+            dropped: 0,
+        }
+    }
+
+    /// the provider settings of `llm`: url, key, model, history, mock, retry, first chunk
+    /// and vision. the prompts are left to the caller
+    pub fn from_config(llm: &crate::config::LLMConfig, tools: ToolSet<McpToolAdapter>) -> Self {
+        let mut chat_session = Self::new(
+            llm.llm_chat_url.clone(),
+            llm.api_key.clone().unwrap_or_default(),
+            llm.model.clone(),
+            None,
+            llm.history,
+            tools,
+        );
+        chat_session.fast_first_chunk = llm.fast_first_chunk;
+        chat_session.vision = llm.vision;
+        chat_session.mock = llm.mock.clone();
+        chat_session.retry = llm.retry.clone();
+        chat_session
+    }
+
+    /// the same settings, tools and abort handle, with an empty conversation
+    pub fn empty_like(&self) -> Self {
+        Self {
+            api_key: self.api_key.clone(),
+            model: self.model.clone(),
+            chat_id: self.chat_id.clone(),
+            url: self.url.clone(),
+            history: self.history,
+            system_prompts: self.system_prompts.clone(),
+            context: Vec::new(),
+            messages: LinkedList::new(),
+            tools: self.tools.clone(),
+            builtin_tools: self.builtin_tools.clone(),
+            fast_first_chunk: self.fast_first_chunk,
+            vision: self.vision,
+            max_spoken_sentences: self.max_spoken_sentences,
+            code_blocks: self.code_blocks.clone(),
+            mock: self.mock.clone(),
+            retry: self.retry.clone(),
+            abort_handle: self.abort_handle.clone(),
+            prompt_tokens: 0,
+            dropped: 0,
         }
     }
 
+    pub fn mark(&self) -> HistoryMark {
+        HistoryMark {
+            len: self.messages.len(),
+            dropped: self.dropped,
+        }
+    }
+
+    /// drop the messages added since `mark`, whatever their role
+    pub fn rollback(&mut self, mark: HistoryMark) {
+        let len = mark.len.saturating_sub(self.dropped - mark.dropped);
+        while self.messages.len() > len {
+            self.messages.pop_back();
+        }
+    }
+
+    /// after the backoff of retry `attempt` (from 0), `false` if `e` is not transient,
+    /// the retries are used up or `abort` is aborted, meanwhile or before
+    pub async fn wait_retry(
+        &self,
+        e: &anyhow::Error,
+        attempt: usize,
+        abort: &mut AbortWatch,
+    ) -> bool {
+        if e.is::<LlmAborted>() || !is_transient(e) || abort.is_aborted() {
+            return false;
+        }
+        let Some(delay) = self.retry.backoff(attempt) else {
+            return false;
+        };
+        log::warn!("llm error: {e}, retry {} in {delay:?}", attempt + 1);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => true,
+            _ = abort.aborted() => false,
+        }
+    }
+
+    /// add the system prompt of the style, after `system_prompts` is set
+    pub fn set_spoken_style(&mut self, style: &crate::config::SpokenStyleConfig) {
+        if let Some(prompt) = style.prompt() {
+            self.system_prompts.push(llm::Content {
+                role: llm::Role::System,
+                message: prompt,
+                tool_calls: None,
+                tool_call_id: None,
+                images: vec![],
+            });
+        }
+        self.max_spoken_sentences = style.max_sentences;
+        self.code_blocks = style.code_blocks.clone();
+    }
+
+    /// replace the context of the turn
+    pub fn set_context(&mut self, context: Option<String>) {
+        self.context = context
+            .into_iter()
+            .map(|message| llm::Content {
+                role: llm::Role::System,
+                message,
+                tool_calls: None,
+                tool_call_id: None,
+                images: vec![],
+            })
+            .collect();
+    }
+
     pub fn add_user_message(&mut self, message: String) {
+        self.add_user_message_with_images(message, vec![]);
+    }
+
+    /// images are dropped unless the model is a vision model
+    pub fn add_user_message_with_images(&mut self, message: String, mut images: Vec<String>) {
+        if !self.vision && !images.is_empty() {
+            log::warn!(
+                "model {} is not a vision model, {} images dropped",
+                self.model,
+                images.len()
+            );
+            images.clear();
+        }
         self.messages.push_back(llm::Content {
             role: llm::Role::User,
             message,
             tool_calls: None,
             tool_call_id: None,
+            images,
         });
         if self.messages.len() > self.history * 2 {
             self.messages.pop_front();
+            self.dropped += 1;
         }
     }
 
+    /// a system message in the history, after the current turn
+    pub fn add_system_message(&mut self, message: String) {
+        self.messages.push_back(llm::Content {
+            role: llm::Role::System,
+            message,
+            tool_calls: None,
+            tool_call_id: None,
+            images: vec![],
+        });
+    }
+
     pub fn add_assistant_message(&mut self, message: String) {
         self.messages.push_back(llm::Content {
             role: llm::Role::Assistant,
             message,
             tool_calls: None,
             tool_call_id: None,
+            images: vec![],
+        });
+    }
+
+    /// the part of an answer heard before the user cut it short, marked so the next turn
+    /// knows the rest was never said
+    pub fn add_truncated_assistant_message(&mut self, message: String) {
+        let message = message.trim_end();
+        if message.is_empty() {
+            return;
+        }
+        self.add_assistant_message(format!("{message}{TRUNCATED_MARK}"));
+    }
+
+    pub fn add_tool_result(&mut self, tool_call_id: String, message: String) {
+        self.messages.push_back(llm::Content {
+            role: llm::Role::Tool,
+            message,
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id),
+            images: vec![],
         });
     }
 
@@ -592,13 +1461,18 @@ impl ChatSession {
             message: String::new(),
             tool_calls: Some(tool_call),
             tool_call_id: None,
+            images: vec![],
         });
     }
 
     pub async fn complete(&mut self) -> anyhow::Result<StableLlmResponse> {
         use crate::ai::openai::tool::Tool;
 
-        let prompts = self.system_prompts.iter().chain(self.messages.iter());
+        let prompts = self
+            .system_prompts
+            .iter()
+            .chain(self.context.iter())
+            .chain(self.messages.iter());
 
         let tools = self
             .tools
@@ -612,17 +1486,58 @@ impl ChatSession {
                 }
                 .into()
             })
+            .chain(self.builtin_tools.iter().cloned().map(Into::into))
             .collect::<Vec<llm::Tool>>();
-
-        let response = llm_stable(
-            self.url.as_str(),
-            &self.api_key,
-            &self.model,
-            self.chat_id.clone(),
-            prompts,
-            tools,
-        )
-        .await?;
+        self.prompt_tokens += prompts
+            .clone()
+            .map(|content| llm::estimate_tokens(&content.message))
+            .sum::<u64>();
+        self.prompt_tokens += tools
+            .iter()
+            .map(|tool| llm::estimate_tokens(&serde_json::to_string(tool).unwrap_or_default()))
+            .sum::<u64>();
+
+        // an abort while the request is sent or between the retries counts too
+        let mut abort = self.abort_handle.watch();
+        let mut attempt = 0;
+        let mut response = loop {
+            let request = async {
+                #[cfg(feature = "chaos")]
+                chaos::inject("llm").await?;
+
+                match &self.mock {
+                    Some(mock) => Ok(mock::llm_response(
+                        mock,
+                        &mock::reply(mock, prompts.clone()),
+                    )),
+                    None => {
+                        llm_stable(
+                            self.url.as_str(),
+                            &self.api_key,
+                            &self.model,
+                            self.chat_id.clone(),
+                            prompts.clone(),
+                            tools.clone(),
+                        )
+                        .await
+                    }
+                }
+            };
+            let response: anyhow::Result<StableLlmResponse> = tokio::select! {
+                response = request => response,
+                _ = abort.aborted() => Err(LlmAborted.into()),
+            };
+            match response {
+                Ok(response) => break response,
+                Err(e) if self.wait_retry(&e, attempt, &mut abort).await => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        if abort.is_aborted() {
+            return Err(LlmAborted.into());
+        }
+        response.fast_first_chunk = self.fast_first_chunk;
+        response.abort = Some(abort.rx);
 
         Ok(response)
     }
@@ -646,6 +1561,7 @@ impl ChatSession {
                     ),
                     tool_calls: None,
                     tool_call_id: Some(tool_call.id.clone()),
+                    images: vec![],
                 });
             } else {
                 result.content.iter().for_each(|content| {
@@ -664,6 +1580,7 @@ impl ChatSession {
                                 message: pretty_result,
                                 tool_calls: None,
                                 tool_call_id: Some(tool_call.id.clone()),
+                                images: vec![],
                             });
                         } else {
                             log::info!(
@@ -676,6 +1593,7 @@ impl ChatSession {
                                 message: content_text.text.to_string(),
                                 tool_calls: None,
                                 tool_call_id: Some(tool_call.id.clone()),
+                                images: vec![],
                             });
                         }
                     } else {
@@ -708,6 +1626,7 @@ impl ChatSession {
                 ),
                 tool_calls: None,
                 tool_call_id: Some(tool_call.id.clone()),
+                images: vec![],
             });
             Ok(())
         }
@@ -790,12 +1709,14 @@ async fn test_chat_session() {
             message: "你是一个聪明的AI助手，你叫做胡桃".to_string(),
             tool_calls: None,
             tool_call_id: None,
+            images: vec![],
         },
         llm::Content {
             role: llm::Role::User,
             message: "身高1米6体重180".to_string(),
             tool_calls: None,
             tool_call_id: None,
+            images: vec![],
         },
     ];
 
@@ -852,3 +1773,28 @@ async fn test_chat_session() {
         }
     }
 }
+
+#[test]
+fn test_rollback() {
+    let mut session = ChatSession::new(
+        String::new(),
+        String::new(),
+        String::new(),
+        None,
+        1,
+        ToolSet::default(),
+    );
+    session.add_user_message("hi".to_string());
+    session.add_assistant_message("hello".to_string());
+    let mark = session.mark();
+    // the injected message before the question is not the question
+    session.add_system_message("the door bell rang".to_string());
+    session.add_user_message("who is it".to_string());
+    session.rollback(mark);
+    let messages = session
+        .messages
+        .iter()
+        .map(|m| m.message.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["hello"]);
+}
@@ -0,0 +1,194 @@
+//! Moderation of the llm output before it is spoken or sent as text.
+//!
+//! Each sentence is checked against the keywords of `llm.moderation`, then optionally
+//! an openai compatible `/v1/moderations` endpoint. The first flagged sentence is replaced
+//! with the refusal, the rest of the response is dropped, and the blocked text is appended
+//! to the audit log.
+
+use aho_corasick::AhoCorasick;
+
+use crate::config::ModerationConfig;
+
+#[derive(Debug, serde::Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    /// device or realtime session id
+    source: &'a str,
+    reason: &'a str,
+    text: &'a str,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: std::collections::HashMap<String, bool>,
+}
+
+/// streaming filter of one response
+pub struct ModerationFilter<'a> {
+    config: &'a ModerationConfig,
+    keywords: Option<AhoCorasick>,
+    blocked: bool,
+}
+
+impl<'a> ModerationFilter<'a> {
+    pub fn new(config: &'a ModerationConfig) -> Self {
+        let keywords = config.keywords.iter().filter(|k| !k.is_empty());
+        let keywords = match AhoCorasick::builder()
+            .ascii_case_insensitive(true)
+            .build(keywords)
+        {
+            Ok(ac) if ac.patterns_len() > 0 => Some(ac),
+            Ok(_) => None,
+            Err(e) => {
+                log::warn!("llm.moderation.keywords error: {e}");
+                None
+            }
+        };
+        Self {
+            config,
+            keywords,
+            blocked: false,
+        }
+    }
+
+    /// whether a sentence was blocked, the rest of the response is dropped
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    fn keyword_reason(&self, text: &str) -> Option<String> {
+        let m = self.keywords.as_ref()?.find(text)?;
+        Some(format!("keyword `{}`", &text[m.start()..m.end()]))
+    }
+
+    /// the reason `text` is flagged by the provider. errors let the text through,
+    /// a broken provider must not silence the assistant
+    async fn provider_reason(&self, client: &reqwest::Client, text: &str) -> Option<String> {
+        let url = self.config.url.as_ref()?;
+        let body = serde_json::json!({ "model": self.config.model, "input": text });
+        let mut builder = client
+            .post(url)
+            .json(&body)
+            .timeout(std::time::Duration::from_millis(self.config.timeout_ms));
+        if let Some(api_key) = self.config.api_key.as_ref().filter(|k| !k.is_empty()) {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        let res = async {
+            let res: ModerationResponse = builder.send().await?.error_for_status()?.json().await?;
+            anyhow::Ok(res)
+        }
+        .await;
+        match res {
+            Ok(res) => {
+                let result = res.results.into_iter().find(|r| r.flagged)?;
+                let mut categories = result
+                    .categories
+                    .into_iter()
+                    .filter_map(|(category, flagged)| flagged.then_some(category))
+                    .collect::<Vec<_>>();
+                categories.sort();
+                Some(format!("provider [{}]", categories.join(", ")))
+            }
+            Err(e) => {
+                log::warn!("moderation provider error: {e}");
+                None
+            }
+        }
+    }
+
+    async fn audit(&self, source: &str, reason: &str, text: &str) {
+        let Some(path) = &self.config.audit_path else {
+            return;
+        };
+        let record = AuditRecord {
+            time: chrono::Local::now().to_rfc3339(),
+            source,
+            reason,
+            text,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line + "\n",
+            Err(e) => {
+                log::error!("moderation audit encode error: {e}");
+                return;
+            }
+        };
+
+        use tokio::io::AsyncWriteExt;
+        let r = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(line.as_bytes()).await
+        }
+        .await;
+        if let Err(e) = r {
+            log::error!("moderation audit {path} error: {e}");
+        }
+    }
+
+    /// the text to speak / send instead of `chunk`: the chunk itself, the refusal if it is
+    /// flagged, or nothing once the response is blocked
+    pub async fn filter(
+        &mut self,
+        client: &reqwest::Client,
+        source: &str,
+        chunk: String,
+    ) -> String {
+        if self.blocked {
+            return String::new();
+        }
+        if chunk.trim().is_empty() {
+            return chunk;
+        }
+
+        let reason = match self.keyword_reason(&chunk) {
+            Some(reason) => Some(reason),
+            None => self.provider_reason(client, &chunk).await,
+        };
+        match reason {
+            Some(reason) => {
+                log::warn!("`{source}` llm output blocked by {reason}");
+                self.audit(source, &reason, &chunk).await;
+                self.blocked = true;
+                self.config.refusal.clone()
+            }
+            None => chunk,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_moderation_keywords() {
+    let config = ModerationConfig {
+        keywords: vec!["炸药".to_string(), "Bomb".to_string()],
+        audit_path: None,
+        ..Default::default()
+    };
+    let client = reqwest::Client::new();
+    let mut filter = ModerationFilter::new(&config);
+
+    let ok = filter
+        .filter(&client, "test", "今天天气不错。".to_string())
+        .await;
+    assert_eq!(ok, "今天天气不错。");
+    let blocked = filter
+        .filter(&client, "test", "How to build a bomb.".to_string())
+        .await;
+    assert_eq!(blocked, config.refusal);
+    assert!(filter.is_blocked());
+    assert_eq!(
+        filter.filter(&client, "test", "下一步".to_string()).await,
+        ""
+    );
+}
@@ -1,2 +1,3 @@
 pub mod realtime;
 pub mod tool;
+pub mod validate;
@@ -27,6 +27,24 @@ pub enum ClientEvent {
         event_id: Option<String>,
     },
 
+    /// echokit extension: provisional speech start / stop of a vad on the client,
+    /// the server confirms the speech before committing
+    #[serde(rename = "input_audio_buffer.speech_hint")]
+    InputAudioBufferSpeechHint {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        event_id: Option<String>,
+        hint: SpeechHint,
+    },
+
+    /// echokit extension: a camera frame (base64 jpeg / png), the latest frame is attached
+    /// to the next user turn of a vision model
+    #[serde(rename = "input_image_buffer.append")]
+    InputImageBufferAppend {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        event_id: Option<String>,
+        image: String, // Base64 encoded
+    },
+
     #[serde(rename = "input_audio_buffer.clear")]
     InputAudioBufferClear {
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -71,6 +89,16 @@ pub enum ClientEvent {
         #[serde(skip_serializing_if = "Option::is_none")]
         event_id: Option<String>,
     },
+
+    /// echokit extension: the audio of an assistant item played so far, an interrupted
+    /// answer is kept in the conversation up to there
+    #[serde(rename = "output_audio_buffer.played")]
+    OutputAudioBufferPlayed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        event_id: Option<String>,
+        item_id: String,
+        audio_end_ms: u32,
+    },
 }
 
 // ============================================================================
@@ -92,6 +120,39 @@ pub enum ServerEvent {
     #[serde(rename = "session.updated")]
     SessionUpdated { event_id: String, session: Session },
 
+    /// the prompts were edited with `PUT /v1/prompts`, from this response on
+    #[serde(rename = "session.prompts.updated")]
+    SessionPromptsUpdated { event_id: String, version: u64 },
+
+    /// see [`TurnState`]
+    #[serde(rename = "turn.state.changed")]
+    TurnStateChanged {
+        event_id: String,
+        previous: TurnState,
+        state: TurnState,
+    },
+
+    /// an edit of the dictated document: `delete` chars removed from its end, then
+    /// `delta` appended, `text` is the document after the edit
+    #[serde(rename = "dictation.text.delta")]
+    DictationTextDelta {
+        event_id: String,
+        item_id: String,
+        delete: usize,
+        delta: String,
+        text: String,
+    },
+
+    /// the intent router answers the response without the llm, the client applies a
+    /// device `action` such as `volume_up`
+    #[serde(rename = "intent.matched")]
+    IntentMatched {
+        event_id: String,
+        response_id: String,
+        name: String,
+        action: String,
+    },
+
     #[serde(rename = "conversation.created")]
     ConversationCreated {
         event_id: String,
@@ -158,6 +219,17 @@ pub enum ServerEvent {
         item_id: String,
     },
 
+    /// echokit extension, sent with `asr.vad_diagnostics` after the vad of a commit
+    #[serde(rename = "input_audio_buffer.vad_diagnostics")]
+    InputAudioBufferVadDiagnostics {
+        event_id: String,
+        item_id: String,
+        segments: Vec<SpeechSegment>,
+        speech_ms: u32,
+        /// speech to background energy, None without background audio
+        snr_db: Option<f32>,
+    },
+
     #[serde(rename = "response.created")]
     ResponseCreated {
         event_id: String,
@@ -216,6 +288,15 @@ pub enum ServerEvent {
         delta: String,
     },
 
+    /// not in the openai api, the reasoning stripped by `llm.reasoning_filter`
+    #[serde(rename = "response.reasoning.delta")]
+    ResponseReasoningDelta {
+        event_id: String,
+        response_id: String,
+        item_id: String,
+        delta: String,
+    },
+
     #[serde(rename = "response.text.done")]
     ResponseTextDone {
         event_id: String,
@@ -253,6 +334,9 @@ pub enum ServerEvent {
         output_index: u32,
         content_index: u32,
         delta: String,
+        /// extension: where the delta is spoken in the audio of the item
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        alignment: Option<TranscriptAlignment>,
     },
 
     #[serde(rename = "response.audio.transcript.done")]
@@ -295,6 +379,9 @@ pub enum ServerEvent {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionConfig {
+    /// `transcription`: the committed audio is transcribed, never answered
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub session_type: Option<SessionType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub modalities: Option<Vec<Modality>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -317,10 +404,19 @@ pub struct SessionConfig {
     pub temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_output_tokens: Option<u32>,
+    /// speaking rate of the tts, 1.0 is the voice as is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f32>,
+    /// pitch of the tts as a frequency ratio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<f32>,
 }
 
 impl SessionConfig {
     pub fn merge(&mut self, other: SessionConfig) {
+        if let Some(session_type) = other.session_type {
+            self.session_type = Some(session_type);
+        }
         if let Some(modalities) = other.modalities {
             self.modalities = Some(modalities);
         }
@@ -342,6 +438,12 @@ impl SessionConfig {
         if let Some(turn_detection) = other.turn_detection {
             self.turn_detection = Some(turn_detection);
         }
+        if let Some(speed) = other.speed {
+            self.speed = Some(speed);
+        }
+        if let Some(pitch) = other.pitch {
+            self.pitch = Some(pitch);
+        }
     }
 }
 
@@ -349,6 +451,16 @@ impl SessionConfig {
 // 枚举定义
 // ============================================================================
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionType {
+    #[default]
+    Realtime,
+    Transcription,
+    /// a transcription session writing a document, see src/services/dictation.rs
+    Dictation,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Modality {
@@ -395,6 +507,8 @@ pub enum TurnDetectionType {
 pub struct Session {
     pub id: String,
     pub object: String,
+    #[serde(rename = "type", default)]
+    pub session_type: SessionType,
     pub model: String,
     pub modalities: Vec<Modality>,
     pub instructions: String,
@@ -413,14 +527,35 @@ pub struct Session {
     pub temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<f32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct InputAudioTranscription {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub enabled: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub model: Option<String>, // "whisper-1"
+    /// iso-639-1, "zh"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+}
+
+/// who has the floor, see `services::turn_state`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TurnState {
+    #[default]
+    Idle,
+    Listening,
+    Transcribing,
+    Thinking,
+    Speaking,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -435,6 +570,9 @@ pub struct TurnDetection {
     pub silence_duration_ms: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub create_response: Option<bool>,
+    /// the start of speech cancels the response in progress, on by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interrupt_response: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -489,6 +627,14 @@ pub enum ContentPart {
         #[serde(skip_serializing_if = "Option::is_none")]
         transcript: Option<String>,
     },
+    /// an image url, or a base64 encoded jpeg / png
+    #[serde(rename = "input_image")]
+    InputImage {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image_url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image: Option<String>, // Base64 encoded
+    },
     #[serde(rename = "text")]
     Text { text: String },
     #[serde(rename = "audio")]
@@ -500,6 +646,29 @@ pub enum ContentPart {
     },
 }
 
+impl ContentPart {
+    /// url of an `input_image` part for the llm, base64 images become `data:` urls
+    pub fn image_url(&self) -> Option<String> {
+        match self {
+            ContentPart::InputImage {
+                image_url: Some(url),
+                ..
+            } => Some(url.clone()),
+            ContentPart::InputImage {
+                image: Some(image), ..
+            } => {
+                let mime = if image.starts_with("iVBOR") {
+                    "image/png"
+                } else {
+                    "image/jpeg"
+                };
+                Some(format!("data:{mime};base64,{image}"))
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Conversation {
     pub id: String,
@@ -549,6 +718,9 @@ pub struct Usage {
     pub output_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub input_token_details: Option<InputTokenDetails>,
+    /// estimated with the prices of the server, not in the openai api
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -561,6 +733,32 @@ pub struct InputTokenDetails {
     pub audio_tokens: Option<u32>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeechHint {
+    SpeechStart,
+    SpeechStop,
+}
+
+/// a transcript delta and its audio: the bytes of the pcm16 of the item and the chars of
+/// its transcript before and after the delta, sent once the audio of the delta is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TranscriptAlignment {
+    pub audio_start: u64,
+    pub audio_end: u64,
+    pub audio_start_ms: u64,
+    pub audio_end_ms: u64,
+    pub text_start: usize,
+    pub text_end: usize,
+}
+
+/// detected speech in the committed audio
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpeechSegment {
+    pub start_ms: u32,
+    pub end_ms: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorDetails {
     #[serde(rename = "type")]
@@ -603,18 +801,40 @@ impl ClientEvent {
         }
     }
 
+    /// the `event_id` the client set
+    pub fn event_id(&self) -> Option<&str> {
+        let event_id = match self {
+            Self::SessionUpdate { event_id, .. } => event_id,
+            Self::InputAudioBufferAppend { event_id, .. } => event_id,
+            Self::InputAudioBufferCommit { event_id, .. } => event_id,
+            Self::InputAudioBufferSpeechHint { event_id, .. } => event_id,
+            Self::InputImageBufferAppend { event_id, .. } => event_id,
+            Self::InputAudioBufferClear { event_id, .. } => event_id,
+            Self::ConversationItemCreate { event_id, .. } => event_id,
+            Self::ConversationItemTruncate { event_id, .. } => event_id,
+            Self::ConversationItemDelete { event_id, .. } => event_id,
+            Self::ResponseCreate { event_id, .. } => event_id,
+            Self::ResponseCancel { event_id, .. } => event_id,
+            Self::OutputAudioBufferPlayed { event_id, .. } => event_id,
+        };
+        event_id.as_deref()
+    }
+
     /// 设置事件ID
     pub fn with_event_id(mut self, event_id: String) -> Self {
         match &mut self {
             Self::SessionUpdate { event_id: id, .. } => *id = Some(event_id),
             Self::InputAudioBufferAppend { event_id: id, .. } => *id = Some(event_id),
             Self::InputAudioBufferCommit { event_id: id, .. } => *id = Some(event_id),
+            Self::InputAudioBufferSpeechHint { event_id: id, .. } => *id = Some(event_id),
+            Self::InputImageBufferAppend { event_id: id, .. } => *id = Some(event_id),
             Self::InputAudioBufferClear { event_id: id, .. } => *id = Some(event_id),
             Self::ConversationItemCreate { event_id: id, .. } => *id = Some(event_id),
             Self::ConversationItemTruncate { event_id: id, .. } => *id = Some(event_id),
             Self::ConversationItemDelete { event_id: id, .. } => *id = Some(event_id),
             Self::ResponseCreate { event_id: id, .. } => *id = Some(event_id),
             Self::ResponseCancel { event_id: id, .. } => *id = Some(event_id),
+            Self::OutputAudioBufferPlayed { event_id: id, .. } => *id = Some(event_id),
         }
         self
     }
@@ -627,7 +847,11 @@ impl ServerEvent {
             Self::Error { event_id, .. } => event_id,
             Self::SessionCreated { event_id, .. } => event_id,
             Self::SessionUpdated { event_id, .. } => event_id,
+            Self::SessionPromptsUpdated { event_id, .. } => event_id,
+            Self::TurnStateChanged { event_id, .. } => event_id,
             Self::ConversationCreated { event_id, .. } => event_id,
+            Self::DictationTextDelta { event_id, .. } => event_id,
+            Self::IntentMatched { event_id, .. } => event_id,
             Self::ConversationItemCreated { event_id, .. } => event_id,
             Self::ConversationItemInputAudioTranscriptionCompleted { event_id, .. } => event_id,
             Self::ConversationItemInputAudioTranscriptionFailed { event_id, .. } => event_id,
@@ -637,6 +861,7 @@ impl ServerEvent {
             Self::InputAudioBufferCleared { event_id, .. } => event_id,
             Self::InputAudioBufferSpeechStarted { event_id, .. } => event_id,
             Self::InputAudioBufferSpeechStopped { event_id, .. } => event_id,
+            Self::InputAudioBufferVadDiagnostics { event_id, .. } => event_id,
             Self::ResponseCreated { event_id, .. } => event_id,
             Self::ResponseDone { event_id, .. } => event_id,
             Self::ResponseOutputItemAdded { event_id, .. } => event_id,
@@ -644,6 +869,7 @@ impl ServerEvent {
             Self::ResponseContentPartAdded { event_id, .. } => event_id,
             Self::ResponseContentPartDone { event_id, .. } => event_id,
             Self::ResponseTextDelta { event_id, .. } => event_id,
+            Self::ResponseReasoningDelta { event_id, .. } => event_id,
             Self::ResponseTextDone { event_id, .. } => event_id,
             Self::ResponseAudioDelta { event_id, .. } => event_id,
             Self::ResponseAudioDone { event_id, .. } => event_id,
@@ -702,6 +928,7 @@ mod tests {
 impl Default for SessionConfig {
     fn default() -> Self {
         Self {
+            session_type: None,
             modalities: None,
             instructions: None,
             voice: None,
@@ -713,6 +940,8 @@ impl Default for SessionConfig {
             tool_choice: None,
             temperature: None,
             max_output_tokens: None,
+            speed: None,
+            pitch: None,
         }
     }
 }
@@ -721,6 +950,19 @@ impl Default for SessionConfig {
 // 枚举便捷方法
 // ============================================================================
 
+impl ClientEvent {
+    /// events that carry or act on input audio
+    pub fn is_audio(&self) -> bool {
+        matches!(
+            self,
+            ClientEvent::InputAudioBufferAppend { .. }
+                | ClientEvent::InputAudioBufferCommit { .. }
+                | ClientEvent::InputAudioBufferSpeechHint { .. }
+                | ClientEvent::InputAudioBufferClear { .. }
+        )
+    }
+}
+
 impl Modality {
     pub fn text() -> Self {
         Self::Text
@@ -788,6 +1030,7 @@ impl TurnDetection {
             prefix_padding_ms: None,
             silence_duration_ms: None,
             create_response: Some(true),
+            interrupt_response: None,
         }
     }
 
@@ -798,6 +1041,7 @@ impl TurnDetection {
             prefix_padding_ms: None,
             silence_duration_ms: None,
             create_response: Some(true),
+            interrupt_response: None,
         }
     }
 
@@ -808,6 +1052,7 @@ impl TurnDetection {
             prefix_padding_ms: None,
             silence_duration_ms: None,
             create_response: None,
+            interrupt_response: None,
         }
     }
 }
@@ -0,0 +1,636 @@
+//! Strict parsing of the realtime client events: an unknown field, a wrong type, an
+//! out of range or unsupported value (`session.modalities`, the content parts of a role)
+//! or a field missing for the type of the item is rejected with the path of the field in
+//! `error.param` (`session.turn_detection.threshold`, `item.content[0]`), as the openai
+//! api does, instead of a bare serde message or a silently ignored field.
+//!
+//! only depends on [`super::realtime`], `fuzz/` builds it on its own.
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::realtime::{
+    ClientEvent, ContentPart, ConversationItem, ErrorDetails, Modality, ResponseConfig,
+    SessionConfig, SpeechHint, Tool, ToolChoice, TurnDetection,
+};
+
+/// why a client event was rejected, sent back as an `error` event
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidEvent {
+    pub code: &'static str,
+    pub message: String,
+    pub param: Option<String>,
+    /// of the rejected event, if it has one
+    pub event_id: Option<String>,
+}
+
+impl InvalidEvent {
+    fn new(code: &'static str, param: Option<String>, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            param,
+            event_id: None,
+        }
+    }
+
+    pub fn into_error(self) -> ErrorDetails {
+        ErrorDetails {
+            error_type: "invalid_request_error".to_string(),
+            code: Some(self.code.to_string()),
+            message: self.message,
+            param: self.param,
+            event_id: self.event_id,
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.param {
+            Some(param) => write!(f, "{}: {} ({param})", self.code, self.message),
+            None => write!(f, "{}: {}", self.code, self.message),
+        }
+    }
+}
+
+fn join(path: Option<&str>, field: &str) -> String {
+    match path {
+        Some(path) => format!("{path}.{field}"),
+        None => field.to_string(),
+    }
+}
+
+/// the serde error of the value at `param`
+fn from_serde(e: serde_json::Error, param: Option<String>) -> InvalidEvent {
+    let message = e.to_string();
+    let quoted = |prefix: &str| {
+        let rest = message.strip_prefix(prefix)?;
+        rest.split('`').next().map(str::to_string)
+    };
+    if let Some(field) = quoted("missing field `") {
+        let param = join(param.as_deref(), &field);
+        let message = format!("Missing required parameter: '{param}'.");
+        return InvalidEvent::new("missing_required_parameter", Some(param), message);
+    }
+    if message.starts_with("invalid type") {
+        return InvalidEvent::new("invalid_type", param, message);
+    }
+    // only the tag of the event is a variant at the top
+    let param = param.or_else(|| {
+        message
+            .starts_with("unknown variant")
+            .then(|| "type".to_string())
+    });
+    InvalidEvent::new("invalid_value", param, message)
+}
+
+/// `value` as a `T`, the errors with their path under `key`
+fn nested<T: DeserializeOwned>(key: &str, value: &Value) -> Result<(), InvalidEvent> {
+    serde_path_to_error::deserialize::<_, T>(value)
+        .map(|_| ())
+        .map_err(|e| {
+            let path = e.path().to_string();
+            let param = if path == "." {
+                key.to_string()
+            } else {
+                format!("{key}.{path}")
+            };
+            from_serde(e.into_inner(), Some(param))
+        })
+}
+
+/// the first field of `input` lost by the parsing, `known` is the parsed value
+/// serialized back
+fn unknown_field(input: &Value, known: &Value, path: &str) -> Option<String> {
+    match (input, known) {
+        (Value::Object(input), Value::Object(known)) => input.iter().find_map(|(key, value)| {
+            let path = join((!path.is_empty()).then_some(path), key);
+            match known.get(key) {
+                _ if value.is_null() => None,
+                Some(known) => unknown_field(value, known, &path),
+                None => Some(path),
+            }
+        }),
+        (Value::Array(input), Value::Array(known)) => input
+            .iter()
+            .zip(known)
+            .enumerate()
+            .find_map(|(i, (value, known))| unknown_field(value, known, &format!("{path}[{i}]"))),
+        _ => None,
+    }
+}
+
+/// `kind` is `integer` or `decimal`
+fn check_range(
+    param: &str,
+    kind: &'static str,
+    value: f64,
+    min: f64,
+    max: f64,
+) -> Result<(), InvalidEvent> {
+    let (code, message) = if value < min {
+        (
+            if kind == "integer" {
+                "integer_below_min_value"
+            } else {
+                "decimal_below_min_value"
+            },
+            format!("Invalid '{param}': expected a value >= {min}, got {value}."),
+        )
+    } else if value > max {
+        (
+            if kind == "integer" {
+                "integer_above_max_value"
+            } else {
+                "decimal_above_max_value"
+            },
+            format!("Invalid '{param}': expected a value <= {max}, got {value}."),
+        )
+    } else {
+        return Ok(());
+    };
+    Err(InvalidEvent::new(code, Some(param.to_string()), message))
+}
+
+fn check_generation(
+    prefix: &str,
+    temperature: Option<f32>,
+    max_output_tokens: Option<u32>,
+) -> Result<(), InvalidEvent> {
+    if let Some(temperature) = temperature {
+        let temperature = (temperature as f64 * 1000.0).round() / 1000.0;
+        check_range(
+            &format!("{prefix}.temperature"),
+            "decimal",
+            temperature,
+            0.6,
+            1.2,
+        )?;
+    }
+    if let Some(tokens) = max_output_tokens {
+        check_range(
+            &format!("{prefix}.max_output_tokens"),
+            "integer",
+            tokens as f64,
+            1.0,
+            4096.0,
+        )?;
+    }
+    Ok(())
+}
+
+fn check_turn_detection(turn_detection: &TurnDetection) -> Result<(), InvalidEvent> {
+    if let Some(threshold) = turn_detection.threshold {
+        let threshold = (threshold as f64 * 1000.0).round() / 1000.0;
+        check_range(
+            "session.turn_detection.threshold",
+            "decimal",
+            threshold,
+            0.0,
+            1.0,
+        )?;
+    }
+    Ok(())
+}
+
+/// the ratios of the tts, as `config::Prosody` bounds them
+fn check_prosody(speed: Option<f32>, pitch: Option<f32>) -> Result<(), InvalidEvent> {
+    for (param, value) in [("session.speed", speed), ("session.pitch", pitch)] {
+        if let Some(value) = value {
+            let value = (value as f64 * 1000.0).round() / 1000.0;
+            check_range(param, "decimal", value, 0.5, 2.0)?;
+        }
+    }
+    Ok(())
+}
+
+fn invalid_value(param: &str, message: String) -> InvalidEvent {
+    InvalidEvent::new("invalid_value", Some(param.to_string()), message)
+}
+
+fn missing(param: &str) -> InvalidEvent {
+    let message = format!("Missing required parameter: '{param}'.");
+    InvalidEvent::new(
+        "missing_required_parameter",
+        Some(param.to_string()),
+        message,
+    )
+}
+
+/// `["text"]`, `["audio"]` or `["audio", "text"]`, in any order
+fn check_modalities(param: &str, modalities: &[Modality]) -> Result<(), InvalidEvent> {
+    let has = |m: Modality| modalities.contains(&m);
+    let valid = match modalities.len() {
+        1 => true,
+        2 => has(Modality::Text) && has(Modality::Audio),
+        _ => false,
+    };
+    if valid {
+        return Ok(());
+    }
+    let names = modalities
+        .iter()
+        .map(|m| match m {
+            Modality::Text => "'text'",
+            Modality::Audio => "'audio'",
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(invalid_value(
+        param,
+        format!(
+            "Invalid modalities: [{names}]. Supported combinations are: ['text'], ['audio'] and ['audio', 'text']."
+        ),
+    ))
+}
+
+/// the names of the tools are unique, the tool chosen is one of them
+fn check_tools(
+    tools: Option<&[Tool]>,
+    tool_choice: Option<&ToolChoice>,
+) -> Result<(), InvalidEvent> {
+    let tools = tools.unwrap_or_default();
+    for (i, tool) in tools.iter().enumerate() {
+        let param = format!("session.tools[{i}].name");
+        if tool.name.is_empty() {
+            return Err(invalid_value(
+                &param,
+                format!("Invalid '{param}': empty string."),
+            ));
+        }
+        if tools[..i].iter().any(|t| t.name == tool.name) {
+            return Err(invalid_value(
+                &param,
+                format!("Invalid '{param}': duplicate tool name '{}'.", tool.name),
+            ));
+        }
+    }
+    if let Some(ToolChoice::Function {
+        tool_type,
+        function,
+    }) = tool_choice
+    {
+        if tool_type != "function" {
+            return Err(invalid_value(
+                "session.tool_choice.type",
+                format!(
+                    "Invalid 'session.tool_choice.type': expected 'function', got '{tool_type}'."
+                ),
+            ));
+        }
+        if !tools.is_empty() && !tools.iter().any(|t| t.name == function.name) {
+            return Err(invalid_value(
+                "session.tool_choice.function.name",
+                format!(
+                    "Tool choice '{}' is not one of the session tools.",
+                    function.name
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// the fields each type of item needs, and the content parts each role can send
+fn check_item(item: &ConversationItem) -> Result<(), InvalidEvent> {
+    if let Some(status) = &item.status {
+        if !matches!(status.as_str(), "completed" | "in_progress" | "incomplete") {
+            return Err(invalid_value(
+                "item.status",
+                format!("Invalid 'item.status': '{status}'. Supported values are: 'completed', 'in_progress' and 'incomplete'."),
+            ));
+        }
+    }
+    match item.item_type.as_str() {
+        "message" => {
+            let Some(role) = &item.role else {
+                return Err(missing("item.role"));
+            };
+            let allowed: &[&str] = match role.as_str() {
+                "user" => &["input_text", "input_audio", "input_image"],
+                "system" => &["input_text"],
+                "assistant" => &["text", "audio"],
+                _ => {
+                    return Err(invalid_value(
+                        "item.role",
+                        format!("Invalid 'item.role': '{role}'. Supported values are: 'user', 'assistant' and 'system'."),
+                    ))
+                }
+            };
+            let Some(content) = &item.content else {
+                return Err(missing("item.content"));
+            };
+            for (i, part) in content.iter().enumerate() {
+                let part_type = match part {
+                    ContentPart::InputText { .. } => "input_text",
+                    ContentPart::InputAudio { .. } => "input_audio",
+                    ContentPart::InputImage { .. } => "input_image",
+                    ContentPart::Text { .. } => "text",
+                    ContentPart::Audio { .. } => "audio",
+                };
+                if !allowed.contains(&part_type) {
+                    let param = format!("item.content[{i}].type");
+                    return Err(invalid_value(
+                        &param,
+                        format!("Invalid '{param}': '{part_type}' is not supported for the role '{role}'."),
+                    ));
+                }
+                if let ContentPart::InputAudio { audio, .. } = part {
+                    check_base64(&format!("item.content[{i}].audio"), audio)?;
+                }
+                if let ContentPart::InputImage {
+                    image_url: None,
+                    image: None,
+                } = part
+                {
+                    return Err(missing(&format!("item.content[{i}].image_url")));
+                }
+            }
+        }
+        "function_call" => {
+            for (param, value) in [
+                ("item.call_id", &item.call_id),
+                ("item.name", &item.name),
+                ("item.arguments", &item.arguments),
+            ] {
+                if value.is_none() {
+                    return Err(missing(param));
+                }
+            }
+        }
+        "function_call_output" => {
+            for (param, value) in [("item.call_id", &item.call_id), ("item.output", &item.output)] {
+                if value.is_none() {
+                    return Err(missing(param));
+                }
+            }
+        }
+        other => {
+            return Err(invalid_value(
+                "item.type",
+                format!("Invalid 'item.type': '{other}'. Supported values are: 'message', 'function_call' and 'function_call_output'."),
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// padded standard base64, the audio is decoded later
+fn check_base64(param: &str, data: &str) -> Result<(), InvalidEvent> {
+    let body = data.trim_end_matches('=');
+    let valid = data.len().is_multiple_of(4)
+        && data.len() - body.len() <= 2
+        && body
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/');
+    if valid {
+        return Ok(());
+    }
+    Err(invalid_value(
+        param,
+        format!("Invalid '{param}': expected base64-encoded data."),
+    ))
+}
+
+/// the values the types allow but the api does not
+fn check_values(event: &ClientEvent) -> Result<(), InvalidEvent> {
+    match event {
+        ClientEvent::SessionUpdate { session, .. } => {
+            if let Some(modalities) = &session.modalities {
+                check_modalities("session.modalities", modalities)?;
+            }
+            check_generation("session", session.temperature, session.max_output_tokens)?;
+            check_prosody(session.speed, session.pitch)?;
+            if let Some(turn_detection) = &session.turn_detection {
+                check_turn_detection(turn_detection)?;
+            }
+            check_tools(session.tools.as_deref(), session.tool_choice.as_ref())?;
+        }
+        ClientEvent::ResponseCreate {
+            response: Some(response),
+            ..
+        } => {
+            if let Some(modalities) = &response.modalities {
+                check_modalities("response.modalities", modalities)?;
+            }
+            check_generation("response", response.temperature, response.max_output_tokens)?
+        }
+        ClientEvent::InputAudioBufferAppend { audio, .. } => check_base64("audio", audio)?,
+        ClientEvent::ConversationItemCreate { item, .. } => check_item(item)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// a client event, or why it is rejected
+pub fn parse_client_event(text: &str) -> Result<ClientEvent, InvalidEvent> {
+    let value: Value = serde_json::from_str(text)
+        .map_err(|e| InvalidEvent::new("invalid_json", None, e.to_string()))?;
+    let event_id = value
+        .get("event_id")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    parse_value(value).map_err(|e| InvalidEvent { event_id, ..e })
+}
+
+fn parse_value(value: Value) -> Result<ClientEvent, InvalidEvent> {
+    let Some(fields) = value.as_object() else {
+        return Err(InvalidEvent::new(
+            "invalid_event",
+            None,
+            "The event must be a JSON object.",
+        ));
+    };
+    if !fields.contains_key("type") {
+        return Err(InvalidEvent::new(
+            "missing_required_parameter",
+            Some("type".to_string()),
+            "Missing required parameter: 'type'.",
+        ));
+    }
+
+    for (key, field) in fields {
+        if field.is_null() {
+            continue;
+        }
+        match key.as_str() {
+            "session" => nested::<SessionConfig>(key, field)?,
+            "item" => nested::<ConversationItem>(key, field)?,
+            "response" => nested::<ResponseConfig>(key, field)?,
+            "hint" => nested::<SpeechHint>(key, field)?,
+            "content_index" | "audio_end_ms" => {
+                let Some(n) = field.as_f64().filter(|n| n.fract() == 0.0) else {
+                    return Err(InvalidEvent::new(
+                        "invalid_type",
+                        Some(key.clone()),
+                        format!("Invalid type for '{key}': expected an integer."),
+                    ));
+                };
+                check_range(key, "integer", n, 0.0, u32::MAX as f64)?;
+            }
+            _ => {}
+        }
+    }
+
+    // the other fields are strings, a field the event does not have is reported as
+    // unknown below whatever its type
+    let event: ClientEvent = serde_json::from_value(value.clone()).map_err(|e| {
+        let not_string = fields.iter().find(|(key, field)| {
+            !field.is_null()
+                && !field.is_string()
+                && !matches!(
+                    key.as_str(),
+                    "session" | "item" | "response" | "hint" | "content_index" | "audio_end_ms"
+                )
+        });
+        match not_string {
+            Some((key, _)) if e.to_string().starts_with("invalid type") => InvalidEvent::new(
+                "invalid_type",
+                Some(key.clone()),
+                format!("Invalid type for '{key}': expected a string."),
+            ),
+            _ => from_serde(e, None),
+        }
+    })?;
+    let known = serde_json::to_value(&event).unwrap_or_default();
+    if let Some(param) = unknown_field(&value, &known, "") {
+        let message = format!("Unknown parameter: '{param}'.");
+        return Err(InvalidEvent::new("unknown_parameter", Some(param), message));
+    }
+    check_values(&event)?;
+    Ok(event)
+}
+
+#[test]
+fn test_parse_client_event() {
+    let param = |text: &str| {
+        let e = parse_client_event(text).unwrap_err();
+        (e.code, e.param.unwrap_or_default())
+    };
+
+    assert!(parse_client_event(r#"{"type":"input_audio_buffer.commit"}"#).is_ok());
+    assert!(parse_client_event(
+        r#"{"type":"session.update","event_id":"e1","session":{"modalities":["text"],
+            "turn_detection":{"type":"server_vad","threshold":0.5},"temperature":0.8,
+            "tools":[{"type":"function","name":"f","parameters":{"type":"object","x-any":1}}]}}"#
+    )
+    .is_ok());
+
+    assert_eq!(param("[]").0, "invalid_event");
+    let e = parse_client_event(r#"{"type":"nope","event_id":"e7"}"#).unwrap_err();
+    assert_eq!(e.into_error().event_id.as_deref(), Some("e7"));
+    assert_eq!(param("{").0, "invalid_json");
+    assert_eq!(param("{}"), ("missing_required_parameter", "type".into()));
+    assert_eq!(
+        param(r#"{"type":"nope"}"#),
+        ("invalid_value", "type".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"input_audio_buffer.append"}"#),
+        ("missing_required_parameter", "audio".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"input_audio_buffer.append","audio":1}"#),
+        ("invalid_type", "audio".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"input_audio_buffer.commit","force":true}"#),
+        ("unknown_parameter", "force".into())
+    );
+    assert_eq!(
+        param(
+            r#"{"type":"session.update","session":{"turn_detection":{"type":"server_vad","silence":1}}}"#
+        ),
+        ("unknown_parameter", "session.turn_detection.silence".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"session.update","session":{"modalities":["text","video"]}}"#),
+        ("invalid_value", "session.modalities[1]".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"session.update","session":{"turn_detection":{"threshold":0.5}}}"#),
+        (
+            "missing_required_parameter",
+            "session.turn_detection.type".into()
+        )
+    );
+    assert_eq!(
+        param(
+            r#"{"type":"session.update","session":{"turn_detection":{"type":"server_vad","threshold":1.5}}}"#
+        ),
+        (
+            "decimal_above_max_value",
+            "session.turn_detection.threshold".into()
+        )
+    );
+    assert_eq!(
+        param(r#"{"type":"session.update","session":{"speed":3.0}}"#),
+        ("decimal_above_max_value", "session.speed".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"response.create","response":{"max_output_tokens":0}}"#),
+        (
+            "integer_below_min_value",
+            "response.max_output_tokens".into()
+        )
+    );
+    assert_eq!(
+        param(
+            r#"{"type":"conversation.item.truncate","item_id":"i","content_index":0,"audio_end_ms":-5}"#
+        ),
+        ("integer_below_min_value", "audio_end_ms".into())
+    );
+
+    assert_eq!(
+        param(r#"{"type":"session.update","session":{"modalities":["audio","audio"]}}"#),
+        ("invalid_value", "session.modalities".into())
+    );
+    assert!(
+        parse_client_event(r#"{"type":"session.update","session":{"modalities":["audio"]}}"#)
+            .is_ok()
+    );
+    assert!(parse_client_event(
+        r#"{"type":"response.create","response":{"modalities":["audio","text"]}}"#
+    )
+    .is_ok());
+    assert_eq!(
+        param(
+            r#"{"type":"session.update","session":{"tools":[{"type":"function","name":"f"},
+                {"type":"function","name":"f"}]}}"#
+        ),
+        ("invalid_value", "session.tools[1].name".into())
+    );
+    assert_eq!(
+        param(
+            r#"{"type":"session.update","session":{"tools":[{"type":"function","name":"f"}],
+                "tool_choice":{"type":"function","function":{"name":"g"}}}}"#
+        ),
+        ("invalid_value", "session.tool_choice.function.name".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"input_audio_buffer.append","audio":"not base64!"}"#),
+        ("invalid_value", "audio".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"conversation.item.create","item":{"type":"message","content":[]}}"#),
+        ("missing_required_parameter", "item.role".into())
+    );
+    assert_eq!(
+        param(
+            r#"{"type":"conversation.item.create","item":{"type":"message","role":"assistant",
+                "content":[{"type":"input_text","text":"hi"}]}}"#
+        ),
+        ("invalid_value", "item.content[0].type".into())
+    );
+    assert_eq!(
+        param(
+            r#"{"type":"conversation.item.create","item":{"type":"function_call_output","call_id":"c"}}"#
+        ),
+        ("missing_required_parameter", "item.output".into())
+    );
+    assert_eq!(
+        param(r#"{"type":"conversation.item.create","item":{"type":"note"}}"#),
+        ("invalid_value", "item.type".into())
+    );
+}
@@ -0,0 +1,145 @@
+//! Strips the `<think>…</think>` scratchpad of reasoning models from a streamed
+//! llm response, so it is neither spoken nor sent as text.
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Filtered {
+    /// the answer, spoken / sent as text
+    pub text: String,
+    /// the stripped reasoning
+    pub reasoning: String,
+}
+
+/// Streaming filter, tags may be split across chunks.
+/// A filter without tags passes everything through.
+#[derive(Debug, Clone, Default)]
+pub struct ReasoningFilter {
+    /// (open, close)
+    tags: Vec<(String, String)>,
+    /// index of the tag of the reasoning in progress
+    in_reasoning: Option<usize>,
+    /// tail of the last chunk that may be the start of a tag
+    pending: String,
+}
+
+/// start of the longest suffix of `s` that is a proper prefix of one of `tags`
+fn partial_tag_start<'a>(s: &str, tags: impl Iterator<Item = &'a str> + Clone) -> usize {
+    s.char_indices()
+        .map(|(i, _)| i)
+        .find(|&i| {
+            tags.clone()
+                .any(|tag| tag.len() > s.len() - i && tag.starts_with(&s[i..]))
+        })
+        .unwrap_or(s.len())
+}
+
+impl ReasoningFilter {
+    pub fn new(tags: Vec<(String, String)>) -> Self {
+        Self {
+            tags: tags
+                .into_iter()
+                .filter(|(open, close)| !open.is_empty() && !close.is_empty())
+                .collect(),
+            in_reasoning: None,
+            pending: String::new(),
+        }
+    }
+
+    pub fn push(&mut self, chunk: &str) -> Filtered {
+        let mut out = Filtered::default();
+        if self.tags.is_empty() {
+            out.text.push_str(chunk);
+            return out;
+        }
+
+        let mut buffer = std::mem::take(&mut self.pending);
+        buffer.push_str(chunk);
+        let mut rest = buffer.as_str();
+
+        loop {
+            match self.in_reasoning {
+                Some(i) => {
+                    let close = self.tags[i].1.as_str();
+                    if let Some(n) = rest.find(close) {
+                        out.reasoning.push_str(&rest[..n]);
+                        rest = &rest[n + close.len()..];
+                        self.in_reasoning = None;
+                        continue;
+                    }
+                    let keep = partial_tag_start(rest, std::iter::once(close));
+                    out.reasoning.push_str(&rest[..keep]);
+                    self.pending = rest[keep..].to_string();
+                    break;
+                }
+                None => {
+                    let open = self
+                        .tags
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, (open, _))| rest.find(open.as_str()).map(|n| (n, i)))
+                        .min();
+                    if let Some((n, i)) = open {
+                        out.text.push_str(&rest[..n]);
+                        rest = &rest[n + self.tags[i].0.len()..];
+                        self.in_reasoning = Some(i);
+                        continue;
+                    }
+                    let keep =
+                        partial_tag_start(rest, self.tags.iter().map(|(open, _)| open.as_str()));
+                    out.text.push_str(&rest[..keep]);
+                    self.pending = rest[keep..].to_string();
+                    break;
+                }
+            }
+        }
+
+        out
+    }
+
+    /// flush at the end of the response, an unclosed reasoning is dropped
+    pub fn finish(&mut self) -> Filtered {
+        let pending = std::mem::take(&mut self.pending);
+        match self.in_reasoning.take() {
+            Some(_) => Filtered {
+                text: String::new(),
+                reasoning: pending,
+            },
+            None => Filtered {
+                text: pending,
+                reasoning: String::new(),
+            },
+        }
+    }
+}
+
+#[test]
+fn test_reasoning_filter() {
+    let mut filter = ReasoningFilter::new(vec![("<think>".to_string(), "</think>".to_string())]);
+
+    let mut text = String::new();
+    let mut reasoning = String::new();
+    for chunk in [
+        "<thi",
+        "nk>用户在问天气",
+        "。</th",
+        "ink>\n\n今天",
+        "晴天<",
+        "。",
+    ] {
+        let out = filter.push(chunk);
+        text.push_str(&out.text);
+        reasoning.push_str(&out.reasoning);
+    }
+    let out = filter.finish();
+    text.push_str(&out.text);
+    reasoning.push_str(&out.reasoning);
+
+    assert_eq!(text, "\n\n今天晴天<。");
+    assert_eq!(reasoning, "用户在问天气。");
+}
+
+#[test]
+fn test_reasoning_filter_passthrough() {
+    let mut filter = ReasoningFilter::default();
+    assert_eq!(filter.push("<think>hi</think>").text, "<think>hi</think>");
+    assert_eq!(filter.finish(), Filtered::default());
+}
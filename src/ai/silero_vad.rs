@@ -0,0 +1,184 @@
+//! Built-in Silero VAD (v5 onnx model), an alternative to the `asr.vad_url` server.
+//! The onnx runtime is only linked with the `silero-vad` feature.
+
+use bytes::Bytes;
+
+use super::vad::{SpeechSampleIndex, VadResponse};
+use crate::config::SileroVadConfig;
+
+// the segments are tested without the onnx runtime too
+#[cfg_attr(not(feature = "silero-vad"), allow(dead_code))]
+const SAMPLE_RATE: u32 = 16000;
+/// 32ms windows at 16k
+#[cfg_attr(not(feature = "silero-vad"), allow(dead_code))]
+const WINDOW: usize = 512;
+
+#[cfg(feature = "silero-vad")]
+mod model {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex, OnceLock},
+    };
+
+    use ort::{session::Session, value::Tensor};
+
+    use super::{SAMPLE_RATE, WINDOW};
+
+    /// samples of the previous window prepended to each input, as in the python runner
+    const CONTEXT: usize = 64;
+    const STATE_SIZE: usize = 2 * 128;
+
+    pub struct SileroVad {
+        session: Session,
+    }
+
+    impl SileroVad {
+        pub fn load(model_path: &str) -> anyhow::Result<Self> {
+            let session = Session::builder()?
+                .with_intra_threads(1)?
+                .commit_from_file(model_path)?;
+            Ok(Self { session })
+        }
+
+        /// the model loaded once per path
+        pub fn shared(model_path: &str) -> anyhow::Result<Arc<Self>> {
+            static MODELS: OnceLock<Mutex<HashMap<String, Arc<SileroVad>>>> = OnceLock::new();
+            let mut models = MODELS
+                .get_or_init(Default::default)
+                .lock()
+                .map_err(|_| anyhow::anyhow!("silero vad model cache poisoned"))?;
+            if let Some(model) = models.get(model_path) {
+                return Ok(model.clone());
+            }
+            log::info!("loading silero vad model {model_path}");
+            let model = Arc::new(Self::load(model_path)?);
+            models.insert(model_path.to_string(), model.clone());
+            Ok(model)
+        }
+
+        /// speech probability of each 32ms window of 16k mono samples
+        pub fn speech_probs(&self, samples: &[f32]) -> anyhow::Result<Vec<f32>> {
+            let mut probs = Vec::with_capacity(samples.len() / WINDOW + 1);
+            let mut state = vec![0f32; STATE_SIZE];
+            let mut context = vec![0f32; CONTEXT];
+
+            for window in samples.chunks(WINDOW) {
+                let mut input = Vec::with_capacity(CONTEXT + WINDOW);
+                input.extend_from_slice(&context);
+                input.extend_from_slice(window);
+                input.resize(CONTEXT + WINDOW, 0.0);
+                context.copy_from_slice(&input[WINDOW..]);
+
+                let outputs = self.session.run(ort::inputs![
+                    "input" => Tensor::from_array(([1usize, CONTEXT + WINDOW], input))?,
+                    "state" => Tensor::from_array(([2usize, 1, 128], state.clone()))?,
+                    "sr" => Tensor::from_array((Vec::<i64>::new(), vec![SAMPLE_RATE as i64]))?,
+                ]?)?;
+
+                let (_, prob) = outputs["output"].try_extract_raw_tensor::<f32>()?;
+                probs.push(prob.first().copied().unwrap_or_default());
+                let (_, new_state) = outputs["stateN"].try_extract_raw_tensor::<f32>()?;
+                state.copy_from_slice(&new_state[..STATE_SIZE]);
+            }
+
+            Ok(probs)
+        }
+    }
+}
+
+/// speech segments of the window probabilities, with the hysteresis of the silero runner
+#[cfg_attr(not(feature = "silero-vad"), allow(dead_code))]
+fn speech_segments(probs: &[f32], config: &SileroVadConfig) -> Vec<SpeechSampleIndex> {
+    let samples_per_ms = SAMPLE_RATE as usize / 1000;
+    let min_speech = config.min_speech_ms as usize * samples_per_ms;
+    let min_silence = config.min_silence_ms as usize * samples_per_ms;
+    let neg_threshold = (config.threshold - 0.15).max(0.01);
+
+    let mut segments = Vec::new();
+    let mut push = |start: usize, end: usize| {
+        if end - start >= min_speech {
+            segments.push(SpeechSampleIndex {
+                start: start as i64,
+                end: end as i64,
+            });
+        }
+    };
+
+    let mut start = None;
+    let mut silence_start = None;
+    for (i, &p) in probs.iter().enumerate() {
+        let pos = i * WINDOW;
+        if p >= config.threshold {
+            silence_start = None;
+            start.get_or_insert(pos);
+        } else if p < neg_threshold {
+            if let Some(speech_start) = start {
+                let silence = *silence_start.get_or_insert(pos);
+                if pos + WINDOW - silence >= min_silence {
+                    push(speech_start, silence);
+                    start = None;
+                    silence_start = None;
+                }
+            }
+        }
+    }
+    if let Some(speech_start) = start {
+        push(speech_start, probs.len() * WINDOW);
+    }
+
+    segments
+}
+
+/// same result as the `vad_url` server for a wav file
+#[cfg(feature = "silero-vad")]
+pub async fn vad_detect(config: &SileroVadConfig, wav_audio: Bytes) -> anyhow::Result<VadResponse> {
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || {
+        let model = model::SileroVad::shared(&config.model_path)?;
+        let (pcm, _) = crate::util::wav_to_pcm16(wav_audio, SAMPLE_RATE)?;
+        let samples = pcm
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+            .collect::<Vec<f32>>();
+        let probs = model.speech_probs(&samples)?;
+        Ok(VadResponse {
+            timestamps: speech_segments(&probs, &config),
+            error: None,
+        })
+    })
+    .await?
+}
+
+#[cfg(not(feature = "silero-vad"))]
+pub async fn vad_detect(
+    _config: &SileroVadConfig,
+    _wav_audio: Bytes,
+) -> anyhow::Result<VadResponse> {
+    Err(anyhow::anyhow!(
+        "asr.silero_vad requires building with the `silero-vad` feature"
+    ))
+}
+
+#[test]
+fn test_speech_segments() {
+    let config = SileroVadConfig {
+        model_path: String::new(),
+        threshold: 0.5,
+        min_speech_ms: 250,
+        min_silence_ms: 100,
+    };
+
+    // 11 windows (352ms) of speech with a short dip, then 5 windows of silence
+    let mut probs = vec![0.1; 3];
+    probs.extend([0.9; 5]);
+    probs.push(0.2);
+    probs.extend([0.8; 5]);
+    probs.extend([0.05; 5]);
+    // a 64ms blip is too short to count
+    probs.extend([0.9; 2]);
+
+    let segments = speech_segments(&probs, &config);
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].start, 3 * WINDOW as i64);
+    assert_eq!(segments[0].end, 14 * WINDOW as i64);
+}
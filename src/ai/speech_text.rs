@@ -0,0 +1,495 @@
+//! Rewrites llm text into what should be spoken: tts engines read markdown literally,
+//! so `**`, `#`, list markers and urls are stripped before synthesis.
+//! The text shown on the device is left as is.
+
+use std::collections::HashMap;
+
+use aho_corasick::{AhoCorasick, MatchKind};
+
+use crate::config::{CodeBlockConfig, CodeReading, SpeechTextConfig, UrlReading};
+
+fn is_cjk(c: char) -> bool {
+    matches!(c, '\u{4e00}'..='\u{9fff}' | '\u{3400}'..='\u{4dbf}' | '\u{3040}'..='\u{30ff}')
+}
+
+fn ends_with_punctuation(s: &str) -> bool {
+    s.chars().last().is_some_and(|c| {
+        matches!(
+            c,
+            '。' | '！' | '？' | '；' | '，' | '.' | '!' | '?' | ';' | ',' | ':' | '：'
+        )
+    })
+}
+
+/// `- item`, `* item`, `+ item`, `1. item`, `1) item`
+fn strip_list_marker(line: &str) -> Option<&str> {
+    if let Some(rest) = line
+        .strip_prefix("- ")
+        .or_else(|| line.strip_prefix("* "))
+        .or_else(|| line.strip_prefix("+ "))
+    {
+        return Some(rest);
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 || digits > 3 {
+        return None;
+    }
+    line[digits..]
+        .strip_prefix(". ")
+        .or_else(|| line[digits..].strip_prefix(") "))
+}
+
+/// `---`, `***`, `___`
+fn is_rule(line: &str) -> bool {
+    let marks = line
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<_>>();
+    marks.len() >= 3 && marks.iter().all(|&c| c == marks[0]) && matches!(marks[0], '-' | '*' | '_')
+}
+
+/// `|---|:---:|` of a table
+fn is_table_separator(line: &str) -> bool {
+    line.contains('-') && line.chars().all(|c| matches!(c, '|' | '-' | ':' | ' '))
+}
+
+fn url_host(url: &str) -> &str {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let host = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    host.strip_prefix("www.").unwrap_or(host)
+}
+
+/// end of the url starting at the beginning of `s`
+fn url_len(s: &str) -> usize {
+    let end = s
+        .find(|c: char| {
+            c.is_whitespace() || is_cjk(c) || matches!(c, ')' | '>' | '"' | '，' | '。')
+        })
+        .unwrap_or(s.len());
+    // a trailing `.` or `,` ends the sentence, not the url
+    s[..end].trim_end_matches(['.', ',', ';', '!', '?']).len()
+}
+
+/// inline markup of one line: emphasis, code, links, images, bare urls
+fn strip_inline(line: &str, urls: UrlReading) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        // `[text](url)` / `![alt](url)`
+        if c == '[' || rest.starts_with("![") {
+            let open = if c == '[' { 1 } else { 2 };
+            if let Some(close) = rest[open..].find("](") {
+                let text = &rest[open..open + close];
+                let target = &rest[open + close + 2..];
+                if let Some(end) = target.find(')').filter(|_| !text.contains(']')) {
+                    out.push_str(&strip_inline(text, urls));
+                    rest = &target[end + 1..];
+                    continue;
+                }
+            }
+        }
+
+        if rest.starts_with("http://") || rest.starts_with("https://") {
+            let len = url_len(rest);
+            match urls {
+                UrlReading::Domain => out.push_str(url_host(&rest[..len])),
+                UrlReading::Skip => out.truncate(out.trim_end().len()),
+            }
+            rest = &rest[len..];
+            continue;
+        }
+
+        if rest.starts_with("**") || rest.starts_with("__") || rest.starts_with("~~") {
+            rest = &rest[2..];
+            continue;
+        }
+        // a lone ` * ` is a multiplication, not emphasis
+        let lone_star = c == '*' && out.ends_with(' ') && rest[1..].starts_with(' ');
+        if (c == '*' && !lone_star) || c == '`' {
+            rest = &rest[1..];
+            continue;
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
+
+/// markdown to plain spoken text, list items and table rows become sentences
+pub fn strip_markdown(text: &str, urls: UrlReading) -> String {
+    let mut sentences: Vec<String> = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("```") || is_rule(line) || is_table_separator(line) {
+            continue;
+        }
+
+        let header = line.starts_with('#');
+        let line = line.trim_start_matches('#').trim_start();
+        let line = line.trim_start_matches('>').trim_start();
+        let item = strip_list_marker(line);
+        let line = item.unwrap_or(line);
+        let row = line.starts_with('|');
+
+        let spoken = if row {
+            line.trim_matches('|')
+                .split('|')
+                .map(|cell| strip_inline(cell.trim(), urls))
+                .filter(|cell| !cell.is_empty())
+                .collect::<Vec<_>>()
+                .join("，")
+        } else {
+            strip_inline(line, urls)
+        };
+        let mut spoken = spoken.trim().to_string();
+        if spoken.is_empty() {
+            continue;
+        }
+
+        // a pause between the items instead of reading them as one run-on sentence
+        if (header || item.is_some() || row) && !ends_with_punctuation(&spoken) {
+            spoken.push(if spoken.chars().any(is_cjk) {
+                '。'
+            } else {
+                '.'
+            });
+        }
+        sentences.push(spoken);
+    }
+
+    let mut out = String::new();
+    for s in sentences {
+        let cjk_join = out.chars().last().is_some_and(is_cjk_or_full_stop) && s.starts_with(is_cjk);
+        if !out.is_empty() && !cjk_join {
+            out.push(' ');
+        }
+        out.push_str(&s);
+    }
+    out
+}
+
+fn is_cjk_or_full_stop(c: char) -> bool {
+    is_cjk(c) || matches!(c, '。' | '！' | '？' | '；' | '，' | '：')
+}
+
+/// cuts a streamed response after `max` sentences
+#[derive(Debug, Clone)]
+pub struct SentenceBudget {
+    left: Option<usize>,
+}
+
+impl SentenceBudget {
+    pub fn new(max: Option<usize>) -> Self {
+        Self { left: max }
+    }
+
+    pub fn is_spent(&self) -> bool {
+        self.left == Some(0)
+    }
+
+    /// the part of the chunk within the budget, empty once it is spent
+    pub fn take<'a>(&mut self, chunk: &'a str) -> &'a str {
+        let Some(left) = self.left.as_mut() else {
+            return chunk;
+        };
+        if *left == 0 {
+            return "";
+        }
+
+        let mut chars = chunk.char_indices().peekable();
+        let mut prev = None;
+        while let Some((i, c)) = chars.next() {
+            let next = chars.peek().map(|(_, c)| *c);
+            let end = match c {
+                '。' | '！' | '？' | '!' | '?' => true,
+                // not the point of `3.14` or `example.com`
+                '.' => {
+                    next.is_none_or(char::is_whitespace)
+                        && !prev.is_some_and(|p: char| p.is_ascii_digit())
+                }
+                '”' | '"' | '」' | '）' | ')' => {
+                    prev.is_some_and(|p| matches!(p, '。' | '！' | '？' | '!' | '?' | '.'))
+                }
+                _ => false,
+            };
+            prev = Some(c);
+            // closing quotes and repeated marks belong to the sentence
+            let continued = next.is_some_and(|n| {
+                matches!(
+                    n,
+                    '。' | '！' | '？' | '!' | '?' | '”' | '"' | '」' | '）' | ')'
+                )
+            });
+            if end && !continued {
+                *left -= 1;
+                if *left == 0 {
+                    return &chunk[..i + c.len_utf8()];
+                }
+            }
+        }
+        chunk
+    }
+}
+
+/// length of the footnote marker `[1]`, `[^1]` or `【1】` at the start of `s`
+fn citation_len(s: &str) -> Option<usize> {
+    let close = match s.chars().next()? {
+        '[' => ']',
+        '【' => '】',
+        _ => return None,
+    };
+    let inner = &s[close.len_utf8()..];
+    let inner = inner.strip_prefix('^').unwrap_or(inner);
+    let digits = inner.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 || digits > 3 || !inner[digits..].starts_with(close) {
+        return None;
+    }
+    let len = s.len() - inner.len() + digits + close.len_utf8();
+    // `[1](url)` is a link
+    if s[len..].starts_with('(') {
+        return None;
+    }
+    Some(len)
+}
+
+/// drop the footnote markers and the footnote lines `[^1]: ...`
+pub fn strip_citations(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if citation_len(trimmed).is_some_and(|len| trimmed[len..].starts_with(':')) {
+            continue;
+        }
+
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            match citation_len(rest) {
+                Some(len) => rest = &rest[len..],
+                None => {
+                    out.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+    }
+    out
+}
+
+/// the spoken part of the chunks of a response: a code block, which spans chunks, is
+/// replaced by the summary (once per response) and footnotes are dropped
+#[derive(Debug, Clone)]
+pub struct CodeBlockFilter {
+    config: CodeBlockConfig,
+    in_code: bool,
+    summarized: bool,
+}
+
+impl CodeBlockFilter {
+    pub fn new(config: CodeBlockConfig) -> Self {
+        Self {
+            config,
+            in_code: false,
+            summarized: false,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &str) -> String {
+        let mut out = String::with_capacity(chunk.len());
+        if self.config.code == CodeReading::Read {
+            out.push_str(chunk);
+        } else {
+            for (i, part) in chunk.split("```").enumerate() {
+                if i > 0 {
+                    self.in_code = !self.in_code;
+                    if self.in_code && !self.summarized && self.config.code == CodeReading::Summary
+                    {
+                        self.summarized = true;
+                        out.push('\n');
+                        out.push_str(&self.config.summary);
+                        out.push('\n');
+                    }
+                }
+                if !self.in_code {
+                    out.push_str(part);
+                }
+            }
+        }
+
+        if self.config.skip_citations {
+            strip_citations(&out)
+        } else {
+            out
+        }
+    }
+}
+
+/// replace the terms of the lexicon by their pronunciation, the longest term wins
+pub fn apply_lexicon(text: &str, lexicon: &HashMap<String, String>) -> String {
+    let (terms, readings): (Vec<&str>, Vec<&str>) = lexicon
+        .iter()
+        .filter(|(term, _)| !term.is_empty())
+        .map(|(term, reading)| (term.as_str(), reading.as_str()))
+        .unzip();
+    if terms.is_empty() {
+        return text.to_string();
+    }
+
+    match AhoCorasick::builder()
+        .match_kind(MatchKind::LeftmostLongest)
+        .ascii_case_insensitive(true)
+        .build(&terms)
+    {
+        Ok(ac) => ac.replace_all(text, &readings),
+        Err(e) => {
+            log::warn!("speech_text.lexicon error: {e}");
+            text.to_string()
+        }
+    }
+}
+
+/// main language of a sentence, `None` if it has no letters
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let (mut han, mut kana, mut hangul, mut latin) = (0, 0, 0, 0);
+    for c in text.chars() {
+        match c {
+            '\u{3040}'..='\u{30ff}' => kana += 1,
+            '\u{ac00}'..='\u{d7af}' => hangul += 1,
+            _ if is_cjk(c) => han += 1,
+            _ if c.is_ascii_alphabetic() => latin += 1,
+            _ => {}
+        }
+    }
+    // a han character carries about as much as a short latin word
+    let latin_words = latin / 4;
+    if kana > 0 {
+        Some("ja")
+    } else if hangul > 0 && hangul >= han {
+        Some("ko")
+    } else if han > 0 && han >= latin_words {
+        Some("zh")
+    } else if latin > 0 {
+        Some("en")
+    } else {
+        None
+    }
+}
+
+/// the text sent to tts instead of `text`, empty if there is nothing to say
+pub fn normalize(text: &str, config: &SpeechTextConfig) -> String {
+    let mut text = if config.markdown {
+        strip_markdown(text, config.urls)
+    } else {
+        text.to_string()
+    };
+    if !config.lexicon.is_empty() {
+        text = apply_lexicon(&text, &config.lexicon);
+    }
+    if config.numbers.iter().any(|l| l == "zh") && text.chars().any(is_cjk) {
+        text = super::zh_text::read_numbers(&text);
+    }
+    text
+}
+
+#[test]
+fn test_sentence_budget() {
+    let mut budget = SentenceBudget::new(Some(3));
+    assert_eq!(budget.take("今天晴，气温25.5度。"), "今天晴，气温25.5度。");
+    assert_eq!(
+        budget.take("适合出门！真的吗？？还有"),
+        "适合出门！真的吗？？"
+    );
+    assert!(budget.is_spent());
+    assert_eq!(budget.take("还有很多要说的。"), "");
+
+    let mut budget = SentenceBudget::new(None);
+    assert_eq!(budget.take("One. Two. Three."), "One. Two. Three.");
+}
+
+#[test]
+fn test_code_block_filter() {
+    let mut filter = CodeBlockFilter::new(CodeBlockConfig::default());
+    let chunks = [
+        "可以这样写[1]：\n```rust\nfn main() {\n",
+        "    println!(\"hi\");\n}\n```\n运行即可。",
+        "再比如：\n```\nls -l\n```",
+        "\n[^1]: https://doc.rust-lang.org",
+    ];
+    let spoken = chunks.map(|c| filter.push(c)).concat();
+    assert_eq!(
+        strip_markdown(&spoken, UrlReading::Skip),
+        "可以这样写：我把代码发到了屏幕上。运行即可。再比如："
+    );
+
+    let mut filter = CodeBlockFilter::new(CodeBlockConfig {
+        code: CodeReading::Read,
+        skip_citations: false,
+        ..Default::default()
+    });
+    assert_eq!(filter.push("看[1]\n```\nls\n```"), "看[1]\n```\nls\n```");
+    assert_eq!(
+        strip_citations("见【2】和[链接](https://a.com)[3](https://b.com)"),
+        "见和[链接](https://a.com)[3](https://b.com)"
+    );
+}
+
+#[test]
+fn test_apply_lexicon() {
+    let lexicon = HashMap::from([
+        ("EchoKit".to_string(), "艾扣凯特".to_string()),
+        ("Echo".to_string(), "艾扣".to_string()),
+        ("单于".to_string(), "缠于".to_string()),
+    ]);
+    assert_eq!(
+        apply_lexicon("echokit 和 Echo 都读对了，单于也是", &lexicon),
+        "艾扣凯特 和 艾扣 都读对了，缠于也是"
+    );
+    assert_eq!(apply_lexicon("hi", &HashMap::new()), "hi");
+}
+
+#[test]
+fn test_detect_language() {
+    assert_eq!(detect_language("今天天气不错"), Some("zh"));
+    assert_eq!(detect_language("我在用 iPhone"), Some("zh"));
+    assert_eq!(
+        detect_language("The Great Wall (长城) is very long."),
+        Some("en")
+    );
+    assert_eq!(detect_language("こんにちは、元気ですか"), Some("ja"));
+    assert_eq!(detect_language("안녕하세요"), Some("ko"));
+    assert_eq!(detect_language("123!"), None);
+}
+
+#[test]
+fn test_strip_markdown() {
+    let text = "## 今天的安排\n\n1. **上午**开会\n2. 下午去`健身房`\n---\n详情见 [日程](https://example.com/cal)。";
+    assert_eq!(
+        strip_markdown(text, UrlReading::Skip),
+        "今天的安排。上午开会。下午去健身房。详情见 日程。"
+    );
+
+    let text = "- Buy *milk*\n- Call mom\n\n> see https://www.example.com/a?b=1.";
+    assert_eq!(
+        strip_markdown(text, UrlReading::Skip),
+        "Buy milk. Call mom. see."
+    );
+    assert_eq!(
+        strip_markdown(text, UrlReading::Domain),
+        "Buy milk. Call mom. see example.com."
+    );
+
+    let table = "| 城市 | 温度 |\n|---|---|\n| 北京 | 25℃ |";
+    assert_eq!(
+        strip_markdown(table, UrlReading::Skip),
+        "城市，温度。北京，25℃。"
+    );
+
+    assert_eq!(
+        strip_markdown("snake_case 2 * 3", UrlReading::Skip),
+        "snake_case 2 * 3"
+    );
+    assert_eq!(strip_markdown("```", UrlReading::Skip), "");
+}
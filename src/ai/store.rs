@@ -0,0 +1,205 @@
+//! Embedding index of the long-term memories and the documents of the rag, `[vectors]`.
+//!
+//! - `backend = "flat"` (default) exact search in memory, saved to a json file, fine for
+//!   some thousands of points
+//! - `backend = "qdrant"` a qdrant server, shared by several instances
+//!
+//! the points of a collection carry a json payload, the searches can require one of its
+//! fields to be equal to a value (`device_id` for the memories).
+
+use crate::config::{VectorIndexConfig, VectorsConfig};
+
+pub mod flat;
+pub mod qdrant;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Point {
+    /// a uuid
+    pub id: String,
+    pub vector: Vec<f32>,
+    #[serde(default)]
+    pub payload: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Hit {
+    pub id: String,
+    /// cosine similarity
+    pub score: f32,
+    pub payload: serde_json::Map<String, serde_json::Value>,
+}
+
+/// `payload[key] == value`
+#[derive(Debug, Clone, Copy)]
+pub struct Filter<'a> {
+    pub key: &'a str,
+    pub value: &'a str,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait VectorIndex: Send + Sync {
+    /// insert or replace the points with the same ids
+    async fn upsert(&self, collection: &str, points: Vec<Point>) -> anyhow::Result<()>;
+    /// the `limit` nearest points, best first
+    async fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: Option<Filter<'_>>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Hit>>;
+    async fn delete(&self, collection: &str, ids: &[String]) -> anyhow::Result<()>;
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let (mut dot, mut na, mut nb) = (0.0f32, 0.0f32, 0.0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        na += x * x;
+        nb += y * y;
+    }
+    if na == 0.0 || nb == 0.0 {
+        0.0
+    } else {
+        dot / (na.sqrt() * nb.sqrt())
+    }
+}
+
+/// the backend of `[vectors.index]`
+#[derive(Debug)]
+pub enum VectorStore {
+    Flat(flat::FlatIndex),
+    Qdrant(qdrant::QdrantIndex),
+}
+
+impl VectorStore {
+    pub async fn open(config: &VectorIndexConfig) -> anyhow::Result<Self> {
+        match config {
+            VectorIndexConfig::Flat { path } => {
+                Ok(VectorStore::Flat(flat::FlatIndex::open(path).await?))
+            }
+            VectorIndexConfig::Qdrant {
+                url,
+                api_key,
+                collection_prefix,
+            } => Ok(VectorStore::Qdrant(qdrant::QdrantIndex::new(
+                url,
+                api_key.clone(),
+                collection_prefix,
+            ))),
+        }
+    }
+}
+
+impl VectorIndex for VectorStore {
+    async fn upsert(&self, collection: &str, points: Vec<Point>) -> anyhow::Result<()> {
+        match self {
+            VectorStore::Flat(index) => index.upsert(collection, points).await,
+            VectorStore::Qdrant(index) => index.upsert(collection, points).await,
+        }
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: Option<Filter<'_>>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Hit>> {
+        match self {
+            VectorStore::Flat(index) => index.search(collection, vector, filter, limit).await,
+            VectorStore::Qdrant(index) => index.search(collection, vector, filter, limit).await,
+        }
+    }
+
+    async fn delete(&self, collection: &str, ids: &[String]) -> anyhow::Result<()> {
+        match self {
+            VectorStore::Flat(index) => index.delete(collection, ids).await,
+            VectorStore::Qdrant(index) => index.delete(collection, ids).await,
+        }
+    }
+}
+
+/// an openai compatible `/v1/embeddings`
+#[derive(Debug, Clone)]
+pub struct Embedder {
+    url: String,
+    api_key: Option<String>,
+    model: String,
+    client: reqwest::Client,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl Embedder {
+    pub fn new(config: &VectorsConfig) -> Self {
+        Self {
+            url: config.embedding_url.clone(),
+            api_key: config.api_key.clone(),
+            model: config.model.clone(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// one vector per text, in order
+    pub async fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut req = self.client.post(&self.url).json(&serde_json::json!({
+            "model": self.model,
+            "input": texts,
+        }));
+        if let Some(api_key) = &self.api_key {
+            req = req.bearer_auth(api_key);
+        }
+        let resp = req.send().await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            anyhow::bail!("embedding error {status}: {}", resp.text().await?);
+        }
+        let mut data = resp.json::<EmbeddingResponse>().await?.data;
+        if data.len() != texts.len() {
+            anyhow::bail!("{} embeddings for {} texts", data.len(), texts.len());
+        }
+        data.sort_by_key(|d| d.index);
+        Ok(data.into_iter().map(|d| d.embedding).collect())
+    }
+
+    pub async fn embed_one(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let mut vectors = self.embed(&[text.to_string()]).await?;
+        vectors.pop().ok_or_else(|| anyhow::anyhow!("no embedding"))
+    }
+}
+
+/// a stable uuid of `name`, the documents keep the ids of their chunks when indexed again
+pub fn point_id(name: &str) -> String {
+    use sha2::Digest;
+    let digest = sha2::Sha256::digest(name.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_random_bytes(bytes)
+        .into_uuid()
+        .to_string()
+}
+
+#[test]
+fn test_cosine() {
+    assert!((cosine(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+    assert!(cosine(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+    assert_eq!(cosine(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    assert_eq!(point_id("a.md#0"), point_id("a.md#0"));
+    assert_ne!(point_id("a.md#0"), point_id("a.md#1"));
+}
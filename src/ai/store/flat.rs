@@ -0,0 +1,139 @@
+//! The embedded index: every point in memory, searched exhaustively and written back to
+//! a json file after each change.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use tokio::sync::RwLock;
+
+use super::{cosine, Filter, Hit, Point, VectorIndex};
+
+#[derive(Debug)]
+pub struct FlatIndex {
+    /// kept in memory only if empty
+    path: Option<PathBuf>,
+    collections: RwLock<HashMap<String, Vec<Point>>>,
+}
+
+impl FlatIndex {
+    pub async fn open(path: &str) -> anyhow::Result<Self> {
+        let path = (!path.is_empty()).then(|| PathBuf::from(path));
+        let collections = match &path {
+            Some(path) => match tokio::fs::read(path).await {
+                Ok(data) => serde_json::from_slice(&data)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(e) => return Err(e.into()),
+            },
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            path,
+            collections: RwLock::new(collections),
+        })
+    }
+
+    async fn save(&self, collections: &HashMap<String, Vec<Point>>) -> anyhow::Result<()> {
+        if let Some(path) = &self.path {
+            tokio::fs::write(path, serde_json::to_vec(collections)?).await?;
+        }
+        Ok(())
+    }
+}
+
+impl VectorIndex for FlatIndex {
+    async fn upsert(&self, collection: &str, points: Vec<Point>) -> anyhow::Result<()> {
+        let mut collections = self.collections.write().await;
+        let existing = collections.entry(collection.to_string()).or_default();
+        for point in points {
+            match existing.iter_mut().find(|p| p.id == point.id) {
+                Some(p) => *p = point,
+                None => existing.push(point),
+            }
+        }
+        self.save(&collections).await
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: Option<Filter<'_>>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Hit>> {
+        let collections = self.collections.read().await;
+        let Some(points) = collections.get(collection) else {
+            return Ok(vec![]);
+        };
+        let mut hits = points
+            .iter()
+            .filter(|p| {
+                filter
+                    .is_none_or(|f| p.payload.get(f.key).and_then(|v| v.as_str()) == Some(f.value))
+            })
+            .map(|p| Hit {
+                id: p.id.clone(),
+                score: cosine(vector, &p.vector),
+                payload: p.payload.clone(),
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(limit);
+        Ok(hits)
+    }
+
+    async fn delete(&self, collection: &str, ids: &[String]) -> anyhow::Result<()> {
+        let mut collections = self.collections.write().await;
+        if let Some(points) = collections.get_mut(collection) {
+            points.retain(|p| !ids.contains(&p.id));
+        }
+        self.save(&collections).await
+    }
+}
+
+#[tokio::test]
+async fn test_flat_index() {
+    let index = FlatIndex::open("").await.unwrap();
+    let point = |id: &str, vector: Vec<f32>, device_id: &str| Point {
+        id: id.to_string(),
+        vector,
+        payload: serde_json::json!({"device_id": device_id})
+            .as_object()
+            .unwrap()
+            .clone(),
+    };
+    index
+        .upsert(
+            "memories",
+            vec![
+                point("a", vec![1.0, 0.0], "dev1"),
+                point("b", vec![0.6, 0.8], "dev1"),
+                point("c", vec![1.0, 0.1], "dev2"),
+            ],
+        )
+        .await
+        .unwrap();
+
+    let filter = Filter {
+        key: "device_id",
+        value: "dev1",
+    };
+    let hits = index
+        .search("memories", &[1.0, 0.0], Some(filter), 10)
+        .await
+        .unwrap();
+    assert_eq!(
+        hits.iter().map(|h| h.id.as_str()).collect::<Vec<_>>(),
+        ["a", "b"]
+    );
+
+    index.delete("memories", &["a".to_string()]).await.unwrap();
+    let hits = index
+        .search("memories", &[1.0, 0.0], None, 1)
+        .await
+        .unwrap();
+    assert_eq!(hits[0].id, "c");
+    assert!(index
+        .search("documents", &[1.0, 0.0], None, 1)
+        .await
+        .unwrap()
+        .is_empty());
+}
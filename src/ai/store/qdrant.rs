@@ -0,0 +1,183 @@
+//! A qdrant server through its rest api, the collections are created on the first upsert
+//! with the size of its vectors.
+
+use std::collections::HashSet;
+
+use tokio::sync::Mutex;
+
+use super::{Filter, Hit, Point, VectorIndex};
+
+#[derive(Debug)]
+pub struct QdrantIndex {
+    url: String,
+    api_key: Option<String>,
+    collection_prefix: String,
+    client: reqwest::Client,
+    /// the collections known to exist
+    created: Mutex<HashSet<String>>,
+}
+
+#[derive(serde::Deserialize)]
+struct SearchResponse {
+    result: Vec<ScoredPoint>,
+}
+
+#[derive(serde::Deserialize)]
+struct ScoredPoint {
+    id: serde_json::Value,
+    score: f32,
+    #[serde(default)]
+    payload: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl QdrantIndex {
+    pub fn new(url: &str, api_key: Option<String>, collection_prefix: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            collection_prefix: collection_prefix.to_string(),
+            client: reqwest::Client::new(),
+            created: Default::default(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let req = self.client.request(method, format!("{}{path}", self.url));
+        match &self.api_key {
+            Some(api_key) => req.header("api-key", api_key),
+            None => req,
+        }
+    }
+
+    async fn send(req: reqwest::RequestBuilder) -> anyhow::Result<reqwest::Response> {
+        let resp = req.send().await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            anyhow::bail!("qdrant error {status}: {}", resp.text().await?);
+        }
+        Ok(resp)
+    }
+
+    fn collection(&self, collection: &str) -> String {
+        format!("{}{collection}", self.collection_prefix)
+    }
+
+    async fn ensure_collection(&self, collection: &str, size: usize) -> anyhow::Result<()> {
+        let mut created = self.created.lock().await;
+        if created.contains(collection) {
+            return Ok(());
+        }
+        let path = format!("/collections/{collection}");
+        let resp = self.request(reqwest::Method::GET, &path).send().await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            Self::send(
+                self.request(reqwest::Method::PUT, &path)
+                    .json(&serde_json::json!({
+                        "vectors": {"size": size, "distance": "Cosine"},
+                    })),
+            )
+            .await?;
+            Self::send(
+                self.request(reqwest::Method::PUT, &format!("{path}/index"))
+                    .json(&serde_json::json!({
+                        "field_name": "device_id",
+                        "field_schema": "keyword",
+                    })),
+            )
+            .await?;
+            log::info!("qdrant collection {collection} created");
+        } else if !resp.status().is_success() {
+            let status = resp.status();
+            anyhow::bail!("qdrant error {status}: {}", resp.text().await?);
+        }
+        created.insert(collection.to_string());
+        Ok(())
+    }
+}
+
+impl VectorIndex for QdrantIndex {
+    async fn upsert(&self, collection: &str, points: Vec<Point>) -> anyhow::Result<()> {
+        let Some(size) = points.first().map(|p| p.vector.len()) else {
+            return Ok(());
+        };
+        let collection = self.collection(collection);
+        self.ensure_collection(&collection, size).await?;
+        Self::send(
+            self.request(
+                reqwest::Method::PUT,
+                &format!("/collections/{collection}/points?wait=true"),
+            )
+            .json(&serde_json::json!({ "points": points })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: Option<Filter<'_>>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Hit>> {
+        let collection = self.collection(collection);
+        let mut body = serde_json::json!({
+            "vector": vector,
+            "limit": limit,
+            "with_payload": true,
+        });
+        if let Some(filter) = filter {
+            body["filter"] = serde_json::json!({
+                "must": [{"key": filter.key, "match": {"value": filter.value}}],
+            });
+        }
+        let resp = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/collections/{collection}/points/search"),
+            )
+            .json(&body)
+            .send()
+            .await?;
+        // nothing was ever stored
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        if !resp.status().is_success() {
+            let status = resp.status();
+            anyhow::bail!("qdrant error {status}: {}", resp.text().await?);
+        }
+        let result = resp.json::<SearchResponse>().await?.result;
+        Ok(result
+            .into_iter()
+            .map(|p| Hit {
+                id: match p.id {
+                    serde_json::Value::String(id) => id,
+                    id => id.to_string(),
+                },
+                score: p.score,
+                payload: p.payload.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn delete(&self, collection: &str, ids: &[String]) -> anyhow::Result<()> {
+        let collection = self.collection(collection);
+        let resp = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/collections/{collection}/points/delete?wait=true"),
+            )
+            .json(&serde_json::json!({ "points": ids }))
+            .send()
+            .await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        if !resp.status().is_success() {
+            let status = resp.status();
+            anyhow::bail!("qdrant error {status}: {}", resp.text().await?);
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,42 @@
 use bytes::Bytes;
 
+use crate::config::TTSConfig;
+
+/// the audio of `tts` as pcm16 at `out_hz`, with the prosody the backend has no control
+/// for applied, see [`TTSConfig::post_prosody`]
+/// return: (pcm_data, duration)
+pub async fn to_pcm16(
+    tts: &TTSConfig,
+    audio: Bytes,
+    out_hz: u32,
+) -> anyhow::Result<(Bytes, std::time::Duration)> {
+    let raw_format = tts.raw_format();
+    let prosody = tts.post_prosody();
+    tokio::task::spawn_blocking(move || {
+        let (pcm, duration) = crate::util::audio_to_pcm16(audio, raw_format, out_hz)?;
+        if prosody == Default::default() {
+            return Ok((pcm, duration));
+        }
+        let pcm = crate::util::change_prosody(&pcm, out_hz, prosody);
+        let duration = std::time::Duration::from_secs_f32(pcm.len() as f32 / 2.0 / out_hz as f32);
+        Ok((pcm, duration))
+    })
+    .await?
+}
+
 /// return: wav_audio: 16bit,32k,single-channel.
 pub async fn gsv(
     tts_url: &str,
     speaker: &str,
     text: &str,
     sample_rate: Option<usize>,
+    speed: Option<f32>,
 ) -> anyhow::Result<Bytes> {
     log::debug!("speaker: {speaker}, text: {text}");
     let client = reqwest::Client::new();
     let res = client
         .post(tts_url)
-        .json(&serde_json::json!({"speaker": speaker, "input": text, "sample_rate": sample_rate}))
+        .json(&serde_json::json!({"speaker": speaker, "input": text, "sample_rate": sample_rate, "speed": speed}))
         // .body(serde_json::json!({"speaker": speaker, "input": text}).to_string())
         .send()
         .await?;
@@ -34,7 +59,9 @@ async fn test_gsv() {
     let tts_url = "http://localhost:8000/v1/audio/speech";
     let speaker = "ad";
     let text = "你好，我是胡桃";
-    let wav_audio = gsv(tts_url, speaker, text, Some(16000)).await.unwrap();
+    let wav_audio = gsv(tts_url, speaker, text, Some(16000), None)
+        .await
+        .unwrap();
     let header = hound::WavReader::new(wav_audio.as_ref()).unwrap();
     let spec = header.spec();
     println!("wav header: {:?}", spec);
@@ -48,12 +75,13 @@ pub async fn stream_gsv(
     speaker: &str,
     text: &str,
     sample_rate: Option<usize>,
+    speed: Option<f32>,
 ) -> anyhow::Result<reqwest::Response> {
     log::debug!("speaker: {speaker}, text: {text}");
     let client = reqwest::Client::new();
     let res = client
         .post(tts_url)
-        .json(&serde_json::json!({"speaker": speaker, "input": text, "sample_rate": sample_rate}))
+        .json(&serde_json::json!({"speaker": speaker, "input": text, "sample_rate": sample_rate, "speed": speed}))
         // .body(serde_json::json!({"speaker": speaker, "input": text}).to_string())
         .send()
         .await?;
@@ -69,7 +97,13 @@ pub async fn stream_gsv(
 }
 
 /// return: wav_audio: 16bit,48k,single-channel.
-pub async fn groq(model: &str, token: &str, voice: &str, text: &str) -> anyhow::Result<Bytes> {
+pub async fn groq(
+    model: &str,
+    token: &str,
+    voice: &str,
+    text: &str,
+    speed: Option<f32>,
+) -> anyhow::Result<Bytes> {
     log::debug!("groq tts. voice: {voice}, text: {text}");
     let client = reqwest::Client::new();
     let res = client
@@ -79,7 +113,8 @@ pub async fn groq(model: &str, token: &str, voice: &str, text: &str) -> anyhow::
             "model":model,
             "voice": voice,
             "input": text,
-            "response_format": "wav"
+            "response_format": "wav",
+            "speed": speed,
         }))
         .send()
         .await?;
@@ -102,7 +137,9 @@ async fn test_groq() {
     let token = std::env::var("GROQ_API_KEY").unwrap();
     let speaker = "Aaliyah-PlayAI";
     let text = "你好，我是胡桃";
-    let wav_audio = groq("playai-tts", &token, speaker, text).await.unwrap();
+    let wav_audio = groq("playai-tts", &token, speaker, text, None)
+        .await
+        .unwrap();
     let mut reader = wav_io::reader::Reader::from_vec(wav_audio.to_vec()).unwrap();
     let head = reader.read_header().unwrap();
     println!("wav header: {:?}", head);
@@ -120,10 +157,17 @@ struct FishTTSRequest {
     reference_id: String,
     normalize: bool,
     latency: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prosody: Option<FishProsody>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct FishProsody {
+    speed: f32,
 }
 
 impl FishTTSRequest {
-    fn new(speaker: String, text: String, format: String) -> Self {
+    fn new(speaker: String, text: String, format: String, speed: Option<f32>) -> Self {
         Self {
             text,
             chunk_length: 200,
@@ -132,11 +176,17 @@ impl FishTTSRequest {
             reference_id: speaker,
             normalize: true,
             latency: "normal".to_string(),
+            prosody: speed.map(|speed| FishProsody { speed }),
         }
     }
 }
 
-pub async fn fish_tts(token: &str, speaker: &str, text: &str) -> anyhow::Result<Bytes> {
+pub async fn fish_tts(
+    token: &str,
+    speaker: &str,
+    text: &str,
+    speed: Option<f32>,
+) -> anyhow::Result<Bytes> {
     let client = reqwest::Client::new();
     let res = client
         .post("https://api.fish.audio/v1/tts")
@@ -146,6 +196,7 @@ pub async fn fish_tts(token: &str, speaker: &str, text: &str) -> anyhow::Result<
             speaker.to_string(),
             text.to_string(),
             "wav".to_string(),
+            speed,
         ))?)
         .send()
         .await?;
@@ -172,9 +223,10 @@ async fn test_fish_tts() {
         speaker.to_string(),
         text.to_string(),
         "wav".to_string(),
+        None,
     ));
     println!("{:x?}", r);
 
-    let wav_audio = fish_tts(&token, speaker, text).await.unwrap();
+    let wav_audio = fish_tts(&token, speaker, text, None).await.unwrap();
     std::fs::write("./resources/test/out.wav", wav_audio).unwrap();
 }
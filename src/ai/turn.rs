@@ -0,0 +1,133 @@
+//! Semantic end-of-turn detection.
+//!
+//! Silence alone cuts people off at a pause in the middle of a sentence, so after the vad
+//! reports the end of speech the transcript so far is checked: a local heuristic for
+//! trailing connectives / punctuation, then optionally a small llm.
+
+use crate::config::SemanticTurnConfig;
+
+const EOT_PROMPT: &str =
+    "You decide whether a speaker has finished their turn in a voice conversation. \
+The user message is the transcript so far. Reply with only `yes` if it is a complete request or \
+statement, or `no` if the speaker paused mid-sentence and is likely to continue.";
+
+/// trailing words after which the speaker is almost always going to continue
+const ZH_CONTINUATIONS: &[&str] = &[
+    "然后", "还有", "而且", "但是", "可是", "因为", "所以", "如果", "就是", "那个", "这个", "还是",
+    "或者", "和", "跟", "嗯", "呃",
+];
+const EN_CONTINUATIONS: &[&str] = &[
+    "and", "but", "or", "so", "because", "if", "then", "the", "a", "an", "to", "of", "with", "um",
+    "uh", "like", "my",
+];
+
+/// whether the transcript obviously stops mid-sentence
+pub fn looks_incomplete(text: &str) -> bool {
+    let text = text.trim();
+    let Some(last) = text.chars().last() else {
+        return false;
+    };
+
+    match last {
+        '。' | '？' | '！' | '.' | '?' | '!' => return false,
+        ',' | '，' | '、' | '…' | '-' | '—' | ':' | '：' => return true,
+        _ => {}
+    }
+
+    if ZH_CONTINUATIONS.iter().any(|w| text.ends_with(w)) {
+        return true;
+    }
+    text.rsplit(|c: char| !c.is_alphanumeric() && c != '\'')
+        .find(|w| !w.is_empty())
+        .map(|w| EN_CONTINUATIONS.contains(&w.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ChatCompletion {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ChatMessage {
+    #[serde(default)]
+    content: String,
+}
+
+async fn llm_end_of_turn(
+    client: &reqwest::Client,
+    url: &str,
+    config: &SemanticTurnConfig,
+    text: &str,
+) -> anyhow::Result<bool> {
+    let body = serde_json::json!({
+        "model": config.model,
+        "messages": [
+            { "role": "system", "content": EOT_PROMPT },
+            { "role": "user", "content": text },
+        ],
+        "max_tokens": 3,
+        "temperature": 0,
+        "stream": false,
+    });
+
+    let mut builder = client.post(url).json(&body);
+    if let Some(api_key) = config.api_key.as_ref().filter(|k| !k.is_empty()) {
+        builder = builder.bearer_auth(api_key);
+    }
+    let res: ChatCompletion = builder.send().await?.error_for_status()?.json().await?;
+    let answer = res
+        .choices
+        .first()
+        .map(|c| c.message.content.trim().to_lowercase())
+        .unwrap_or_default();
+    log::debug!("semantic eot of {text:?}: {answer}");
+
+    Ok(!answer.starts_with("no"))
+}
+
+/// whether `text` is the end of the user's turn. any error of the llm counts as the end,
+/// so a broken detector never holds the response back
+pub async fn is_end_of_turn(
+    client: &reqwest::Client,
+    config: &SemanticTurnConfig,
+    text: &str,
+) -> bool {
+    if looks_incomplete(text) {
+        return false;
+    }
+    let Some(url) = &config.llm_url else {
+        return true;
+    };
+
+    let timeout = std::time::Duration::from_millis(config.timeout_ms);
+    match tokio::time::timeout(timeout, llm_end_of_turn(client, url, config, text)).await {
+        Ok(Ok(end)) => end,
+        Ok(Err(e)) => {
+            log::warn!("semantic eot error: {e}");
+            true
+        }
+        Err(_) => {
+            log::warn!("semantic eot timeout after {timeout:?}");
+            true
+        }
+    }
+}
+
+#[test]
+fn test_looks_incomplete() {
+    assert!(looks_incomplete("我想问一下明天的天气，"));
+    assert!(looks_incomplete("帮我订一张票然后"));
+    assert!(looks_incomplete("I want to go to the"));
+    assert!(looks_incomplete("turn on the light and"));
+    assert!(!looks_incomplete("明天天气怎么样？"));
+    assert!(!looks_incomplete("帮我关灯"));
+    assert!(!looks_incomplete("What time is it"));
+    assert!(!looks_incomplete("Band"));
+    assert!(!looks_incomplete(""));
+}
@@ -19,15 +19,68 @@ pub struct VadResponse {
     pub error: Option<String>,
 }
 
+/// per-session overrides of the vad (`turn_detection` of realtime sessions),
+/// `None` keeps the default of the vad
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct VadParams {
+    /// speech probability threshold, 0.0-1.0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f32>,
+    /// audio kept before the detected start of speech
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix_padding_ms: Option<u32>,
+    /// silence that ends the speech
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub silence_duration_ms: Option<u32>,
+    /// calibrated noise floor of the device, adapts the energy vad
+    #[serde(skip)]
+    pub noise_floor_db: Option<f32>,
+}
+
+/// (total speech ms, snr dB) of 16k samples and their speech timestamps.
+/// the snr compares the energy inside and outside the segments
+pub fn speech_stats(samples: &[i16], timestamps: &[SpeechSampleIndex]) -> (u32, Option<f32>) {
+    let mut speech = (0f64, 0usize);
+    let mut noise = (0f64, 0usize);
+    for (i, &s) in samples.iter().enumerate() {
+        let power = s as f64 * s as f64;
+        let i = i as i64;
+        if timestamps.iter().any(|t| t.start <= i && i < t.end) {
+            speech = (speech.0 + power, speech.1 + 1);
+        } else {
+            noise = (noise.0 + power, noise.1 + 1);
+        }
+    }
+
+    let speech_ms = (speech.1 as u64 * 1000 / 16000) as u32;
+    if speech.1 == 0 || noise.1 == 0 {
+        return (speech_ms, None);
+    }
+    let speech_power = speech.0 / speech.1 as f64;
+    // digital silence, 1 lsb of noise
+    let noise_power = (noise.0 / noise.1 as f64).max(1.0);
+    let snr = 10.0 * (speech_power.max(1.0) / noise_power).log10();
+    (speech_ms, Some(snr as f32))
+}
+
 pub async fn vad_detect(
     client: &reqwest::Client,
     vad_url: &str,
-    wav_audio: Vec<u8>,
+    params: &VadParams,
+    wav_audio: bytes::Bytes,
 ) -> anyhow::Result<VadResponse> {
-    let form = reqwest::multipart::Form::new()
-        .part("audio", Part::bytes(wav_audio).file_name("audio.wav"));
+    let len = wav_audio.len() as u64;
+    let form = reqwest::multipart::Form::new().part(
+        "audio",
+        Part::stream_with_length(wav_audio, len).file_name("audio.wav"),
+    );
 
-    let res = client.post(vad_url).multipart(form).send().await?;
+    let res = client
+        .post(vad_url)
+        .query(params)
+        .multipart(form)
+        .send()
+        .await?;
 
     let r: serde_json::Value = res.json().await?;
     log::debug!("VAD response: {:#?}", r);
@@ -37,22 +90,91 @@ pub async fn vad_detect(
     Ok(vad_result)
 }
 
+/// vad of a recorded utterance: the built-in silero vad if `asr.silero_vad` is set,
+/// else the `asr.vad_url` server, with the energy vad as fallback if they fail.
+/// None if no vad is configured.
+pub async fn detect_speech(
+    client: &reqwest::Client,
+    asr: &crate::config::WhisperASRConfig,
+    params: &VadParams,
+    wav_audio: bytes::Bytes,
+) -> Option<anyhow::Result<VadResponse>> {
+    let r = configured_vad_detect(client, asr, params, wav_audio.clone()).await?;
+    match r {
+        Err(e) if asr.energy_vad.fallback => {
+            log::warn!("vad error: {e}, falling back to the energy vad");
+            let energy_vad = asr.energy_vad.adapted(params.noise_floor_db);
+            Some(super::energy_vad::vad_detect(&energy_vad, wav_audio).await)
+        }
+        r => Some(r),
+    }
+}
+
+async fn configured_vad_detect(
+    client: &reqwest::Client,
+    asr: &crate::config::WhisperASRConfig,
+    params: &VadParams,
+    wav_audio: bytes::Bytes,
+) -> Option<anyhow::Result<VadResponse>> {
+    if let Some(silero) = &asr.silero_vad {
+        let mut silero = silero.clone();
+        if let Some(threshold) = params.threshold {
+            silero.threshold = threshold;
+        }
+        if let Some(silence_duration_ms) = params.silence_duration_ms {
+            silero.min_silence_ms = silence_duration_ms;
+        }
+        return Some(super::silero_vad::vad_detect(&silero, wav_audio).await);
+    }
+    let vad_url = asr.vad_url.as_ref()?;
+    Some(vad_detect(client, vad_url, params, wav_audio).await)
+}
+
+#[test]
+fn test_speech_stats() {
+    let mut samples = vec![100i16; 16000];
+    samples[4000..12000].fill(10000);
+    let timestamps = [SpeechSampleIndex {
+        start: 4000,
+        end: 12000,
+    }];
+
+    let (speech_ms, snr) = speech_stats(&samples, &timestamps);
+    assert_eq!(speech_ms, 500);
+    assert!((snr.unwrap() - 40.0).abs() < 0.01);
+    assert_eq!(speech_stats(&samples, &[]), (0, None));
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(untagged)]
 pub enum VadRealtimeEvent {
-    Event { event: String },
-    Error { error: String, message: String },
+    Event {
+        event: String,
+    },
+    Error {
+        error: String,
+        message: String,
+    },
+    /// not sent by the server: the wait for the rest of an incomplete turn is over
+    #[serde(skip)]
+    TurnTimeout,
 }
 
+/// `VadRealtimeEvent::Event` names of the realtime vad server
+pub const VAD_SPEECH_START: &str = "speech_start";
+pub const VAD_SPEECH_END: &str = "speech_end";
+
 pub struct VadRealtimeClient(pub SplitSink<WebSocket, reqwest_websocket::Message>);
 pub struct VadRealtimeRx(pub SplitStream<WebSocket>);
 
 pub async fn vad_realtime_client(
     client: &reqwest::Client,
     vad_ws_url: String,
+    params: &VadParams,
 ) -> anyhow::Result<(VadRealtimeClient, VadRealtimeRx)> {
     let response = client
         .get(&vad_ws_url)
+        .query(params)
         .upgrade() // Prepares the WebSocket upgrade.
         .send()
         .await?;
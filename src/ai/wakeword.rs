@@ -0,0 +1,119 @@
+//! Client of a streaming wake word server (e.g. openWakeWord / porcupine ONNX
+//! models served over websocket, like silero_vad_server does for VAD).
+//!
+//! Protocol: the server receives 16k 16bit le mono pcm as binary messages and
+//! answers with json text messages:
+//! `{"event":"wakeword","keyword":"hey_jarvis","score":0.93}`
+
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use reqwest_websocket::{RequestBuilderExt, WebSocket};
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum WakeWordEvent {
+    Detected {
+        event: String,
+        keyword: String,
+        #[serde(default)]
+        score: f32,
+    },
+    Event {
+        event: String,
+    },
+    Error {
+        error: String,
+        message: String,
+    },
+}
+
+pub struct WakeWordClient(pub SplitSink<WebSocket, reqwest_websocket::Message>);
+pub struct WakeWordRx(pub SplitStream<WebSocket>);
+
+pub async fn wake_word_client(
+    client: &reqwest::Client,
+    url: &str,
+) -> anyhow::Result<(WakeWordClient, WakeWordRx)> {
+    let response = client.get(url).upgrade().send().await?;
+
+    let ws = response.into_websocket().await?;
+    let (tx, rx) = ws.split();
+
+    Ok((WakeWordClient(tx), WakeWordRx(rx)))
+}
+
+impl WakeWordClient {
+    pub async fn push_audio_16k_chunk(&mut self, audio_16k: bytes::Bytes) -> anyhow::Result<()> {
+        self.0
+            .send(reqwest_websocket::Message::Binary(audio_16k))
+            .await?;
+        Ok(())
+    }
+}
+
+impl WakeWordRx {
+    pub async fn next_event(&mut self) -> anyhow::Result<WakeWordEvent> {
+        loop {
+            match self.0.next().await {
+                Some(Ok(reqwest_websocket::Message::Text(text))) => {
+                    let event: WakeWordEvent = serde_json::from_str(&text)
+                        .map_err(|e| anyhow::anyhow!("Failed to parse wake word event: {}", e))?;
+                    return Ok(event);
+                }
+                Some(Ok(reqwest_websocket::Message::Close { .. })) => {
+                    return Err(anyhow::anyhow!("WebSocket connection closed"));
+                }
+                Some(Err(e)) => {
+                    return Err(anyhow::anyhow!("WebSocket error: {}", e));
+                }
+                Some(_) => {
+                    continue;
+                }
+                None => {
+                    return Err(anyhow::anyhow!("WebSocket stream ended unexpectedly"));
+                }
+            }
+        }
+    }
+
+    /// wait until one of `keywords` (any keyword if empty) is detected with `score >= threshold`
+    pub async fn wait_wake_word(
+        &mut self,
+        keywords: &[String],
+        threshold: f32,
+    ) -> anyhow::Result<String> {
+        loop {
+            match self.next_event().await? {
+                WakeWordEvent::Detected { keyword, score, .. } => {
+                    if score < threshold {
+                        log::debug!("wake word `{keyword}` score {score} below threshold");
+                        continue;
+                    }
+                    if keywords.is_empty() || keywords.contains(&keyword) {
+                        return Ok(keyword);
+                    }
+                }
+                WakeWordEvent::Event { event } => {
+                    log::debug!("wake word server event: {event}");
+                }
+                WakeWordEvent::Error { error, message } => {
+                    return Err(anyhow::anyhow!("wake word error: {error}: {message}"));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_wake_word_event() {
+    let event: WakeWordEvent =
+        serde_json::from_str(r#"{"event":"wakeword","keyword":"hey_jarvis","score":0.9}"#).unwrap();
+    assert!(
+        matches!(event, WakeWordEvent::Detected { ref keyword, .. } if keyword == "hey_jarvis")
+    );
+
+    let event: WakeWordEvent = serde_json::from_str(r#"{"event":"ready"}"#).unwrap();
+    assert!(matches!(event, WakeWordEvent::Event { .. }));
+}
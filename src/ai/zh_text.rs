@@ -0,0 +1,372 @@
+//! Chinese reading of numbers, dates, times, temperatures and currency for tts,
+//! e.g. `25℃` is read as `二十五摄氏度` and `2025-10-14` as `二零二五年十月十四日`.
+
+const DIGITS: [char; 10] = ['零', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+
+/// (suffix, reading) of units after a number, longest first
+const UNITS: &[(&str, &str)] = &[
+    ("km/h", "公里每小时"),
+    ("m/s", "米每秒"),
+    ("km", "公里"),
+    ("kg", "公斤"),
+    ("cm", "厘米"),
+    ("mm", "毫米"),
+    ("ml", "毫升"),
+    ("GB", "G"),
+    ("MB", "兆"),
+];
+
+/// digit by digit, for years and phone numbers
+pub fn read_digits(digits: &str) -> String {
+    digits
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .map(|d| DIGITS[d as usize])
+        .collect()
+}
+
+fn read_under_10k(n: u64, out: &mut String) {
+    let places = [
+        (n / 1000, "千"),
+        (n / 100 % 10, "百"),
+        (n / 10 % 10, "十"),
+        (n % 10, ""),
+    ];
+    let mut started = false;
+    let mut zero = false;
+    for (d, place) in places {
+        if d == 0 {
+            zero = started;
+            continue;
+        }
+        if zero {
+            out.push('零');
+            zero = false;
+        }
+        out.push(DIGITS[d as usize]);
+        out.push_str(place);
+        started = true;
+    }
+}
+
+/// `10010` is read as `一万零一十`
+pub fn read_integer(mut n: u64) -> String {
+    if n == 0 {
+        return "零".to_string();
+    }
+    let mut sections = vec![];
+    while n > 0 {
+        sections.push(n % 10000);
+        n /= 10000;
+    }
+
+    let places = ["", "万", "亿", "万亿"];
+    let mut out = String::new();
+    let mut zero = false;
+    for (i, &section) in sections.iter().enumerate().rev() {
+        if section == 0 {
+            zero = !out.is_empty();
+            continue;
+        }
+        if !out.is_empty() && (zero || section < 1000) {
+            out.push('零');
+        }
+        read_under_10k(section, &mut out);
+        out.push_str(places.get(i).copied().unwrap_or_default());
+        zero = false;
+    }
+
+    match out.strip_prefix("一十") {
+        Some(rest) => format!("十{rest}"),
+        None => out,
+    }
+}
+
+/// the digits at the start of `chars`
+fn digits_at(chars: &[char]) -> usize {
+    chars.iter().take_while(|c| c.is_ascii_digit()).count()
+}
+
+fn parse(chars: &[char]) -> u64 {
+    chars.iter().collect::<String>().parse().unwrap_or_default()
+}
+
+/// `2025-10-14`, `2025/10/14` or `2025.10.14`
+fn read_date(chars: &[char]) -> Option<(String, usize)> {
+    if digits_at(chars) != 4 {
+        return None;
+    }
+    let sep = *chars.get(4).filter(|c| matches!(c, '-' | '/' | '.'))?;
+    let month_len = digits_at(&chars[5..]);
+    let month_end = 5 + month_len;
+    if !(1..=2).contains(&month_len) || chars.get(month_end) != Some(&sep) {
+        return None;
+    }
+    let day_len = digits_at(&chars[month_end + 1..]);
+    if !(1..=2).contains(&day_len) {
+        return None;
+    }
+    let month = parse(&chars[5..month_end]);
+    let day = parse(&chars[month_end + 1..month_end + 1 + day_len]);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let year = chars[..4].iter().collect::<String>();
+    let spoken = format!(
+        "{}年{}月{}日",
+        read_digits(&year),
+        read_integer(month),
+        read_integer(day)
+    );
+    Some((spoken, month_end + 1 + day_len))
+}
+
+/// minutes / seconds of a clock time, `05` is read as `零五`
+fn read_clock_part(n: u64, unit: &str) -> String {
+    match n {
+        0 => String::new(),
+        1..=9 => format!("零{}{unit}", read_integer(n)),
+        _ => format!("{}{unit}", read_integer(n)),
+    }
+}
+
+/// `14:30` or `08:05:10`
+fn read_time(chars: &[char]) -> Option<(String, usize)> {
+    let hour_len = digits_at(chars);
+    if !(1..=2).contains(&hour_len) || !matches!(chars.get(hour_len), Some(':' | '：')) {
+        return None;
+    }
+    let minute = &chars[hour_len + 1..];
+    if digits_at(minute) != 2 {
+        return None;
+    }
+    let hour = parse(&chars[..hour_len]);
+    let min = parse(&minute[..2]);
+    if hour > 24 || min >= 60 {
+        return None;
+    }
+
+    let mut len = hour_len + 3;
+    let mut spoken = match hour {
+        2 => "两点".to_string(),
+        h => format!("{}点", read_integer(h)),
+    };
+    spoken.push_str(&read_clock_part(min, "分"));
+
+    let second = &chars[len..];
+    if matches!(second.first(), Some(':' | '：')) && digits_at(&second[1..]) == 2 {
+        let sec = parse(&second[1..3]);
+        if sec < 60 {
+            spoken.push_str(&read_clock_part(sec, "秒"));
+            len += 3;
+        }
+    }
+    if digits_at(&chars[len..]) > 0 {
+        return None;
+    }
+    Some((spoken, len))
+}
+
+struct Number {
+    int: String,
+    frac: Option<String>,
+    grouped: bool,
+    len: usize,
+}
+
+/// `1,280.50`
+fn parse_number(chars: &[char]) -> Option<Number> {
+    let mut len = digits_at(chars);
+    if len == 0 {
+        return None;
+    }
+    let mut int: String = chars[..len].iter().collect();
+    let mut grouped = false;
+    while chars.get(len) == Some(&',')
+        && digits_at(&chars[len + 1..]) == 3
+        && (grouped || int.len() <= 3)
+    {
+        int.extend(&chars[len + 1..len + 4]);
+        len += 4;
+        grouped = true;
+    }
+
+    let mut frac = None;
+    if chars.get(len) == Some(&'.') {
+        let frac_len = digits_at(&chars[len + 1..]);
+        if frac_len > 0 {
+            frac = Some(chars[len + 1..len + 1 + frac_len].iter().collect());
+            len += 1 + frac_len;
+        }
+    }
+
+    Some(Number {
+        int,
+        frac,
+        grouped,
+        len,
+    })
+}
+
+impl Number {
+    fn read(&self) -> String {
+        // codes and phone numbers are read digit by digit
+        let mut spoken = if (self.int.len() > 1 && self.int.starts_with('0'))
+            || (!self.grouped && self.int.len() >= 7)
+            || self.int.len() > 16
+        {
+            read_digits(&self.int)
+        } else {
+            read_integer(self.int.parse().unwrap_or_default())
+        };
+        if let Some(frac) = &self.frac {
+            spoken.push('点');
+            spoken.push_str(&read_digits(frac));
+        }
+        spoken
+    }
+}
+
+fn starts_with(chars: &[char], s: &str) -> bool {
+    s.chars().enumerate().all(|(i, c)| chars.get(i) == Some(&c))
+}
+
+/// the reading of the number at the start of `chars`, with the number of chars it replaces
+fn read_at(chars: &[char], prev: Option<char>) -> Option<(String, usize)> {
+    // part of a word like `mp3`, or of a version like `1.2.3`
+    if prev.is_some_and(|p| p.is_ascii_alphanumeric() || p == '.' || p == '_') {
+        // `3-5`, `3~5`
+        if prev.is_some_and(|p| p.is_ascii_digit())
+            && matches!(chars[0], '-' | '~' | '～')
+            && chars.get(1).is_some_and(|c| c.is_ascii_digit())
+        {
+            return Some(("到".to_string(), 1));
+        }
+        return None;
+    }
+
+    if let Some(read) = read_date(chars).or_else(|| read_time(chars)) {
+        return Some(read);
+    }
+
+    let mut i = 0;
+    let currency = match chars[0] {
+        '¥' | '￥' => Some("元"),
+        '$' => Some("美元"),
+        '€' => Some("欧元"),
+        '£' => Some("英镑"),
+        _ => None,
+    };
+    if currency.is_some() {
+        i += 1;
+    }
+    let negative = matches!(chars.get(i), Some('-' | '−'));
+    if negative {
+        i += 1;
+    }
+    let number = parse_number(&chars[i..])?;
+    let value = number.read();
+    let rest = &chars[i + number.len..];
+    let len = i + number.len;
+
+    if let Some(currency) = currency {
+        let sign = if negative { "负" } else { "" };
+        return Some((format!("{sign}{value}{currency}"), len));
+    }
+
+    let sign = if negative { "负" } else { "" };
+    if matches!(rest.first(), Some('%' | '％')) {
+        return Some((format!("百分之{sign}{value}"), len + 1));
+    }
+
+    let temperature = [
+        ("℃", "摄氏度"),
+        ("°C", "摄氏度"),
+        ("℉", "华氏度"),
+        ("°F", "华氏度"),
+        ("°", "度"),
+    ];
+    if let Some((unit, reading)) = temperature.iter().find(|(unit, _)| starts_with(rest, unit)) {
+        let sign = if negative { "零下" } else { "" };
+        return Some((
+            format!("{sign}{value}{reading}"),
+            len + unit.chars().count(),
+        ));
+    }
+
+    // `2025年`
+    if rest.first() == Some(&'年') && number.int.len() == 4 && number.frac.is_none() && !negative {
+        return Some((read_digits(&number.int), len));
+    }
+
+    if let Some((unit, reading)) = UNITS.iter().find(|(unit, _)| {
+        starts_with(rest, unit)
+            && !rest
+                .get(unit.chars().count())
+                .is_some_and(|c| c.is_ascii_alphabetic())
+    }) {
+        return Some((
+            format!("{sign}{value}{reading}"),
+            len + unit.chars().count(),
+        ));
+    }
+
+    Some((format!("{sign}{value}"), len))
+}
+
+/// spell out the numbers of a chinese sentence
+pub fn read_numbers(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut out = String::with_capacity(text.len() * 2);
+    let mut i = 0;
+    while i < chars.len() {
+        let prev = i.checked_sub(1).map(|p| chars[p]);
+        match read_at(&chars[i..], prev) {
+            Some((spoken, len)) => {
+                out.push_str(&spoken);
+                i += len;
+            }
+            None => {
+                out.push(chars[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_read_integer() {
+    assert_eq!(read_integer(0), "零");
+    assert_eq!(read_integer(10), "十");
+    assert_eq!(read_integer(25), "二十五");
+    assert_eq!(read_integer(105), "一百零五");
+    assert_eq!(read_integer(110), "一百一十");
+    assert_eq!(read_integer(10010), "一万零一十");
+    assert_eq!(read_integer(100000005), "一亿零五");
+    assert_eq!(read_integer(12345678), "一千二百三十四万五千六百七十八");
+}
+
+#[test]
+fn test_read_numbers() {
+    assert_eq!(
+        read_numbers("今天25℃，明天-5°C"),
+        "今天二十五摄氏度，明天零下五摄氏度"
+    );
+    assert_eq!(
+        read_numbers("2025-10-14 14:30 开会"),
+        "二零二五年十月十四日 十四点三十分 开会"
+    );
+    assert_eq!(read_numbers("2025年3月8日"), "二零二五年三月八日");
+    assert_eq!(read_numbers("闹钟 08:05"), "闹钟 八点零五分");
+    assert_eq!(read_numbers("一共¥1,280.50"), "一共一千二百八十点五零元");
+    assert_eq!(read_numbers("涨了25%"), "涨了百分之二十五");
+    assert_eq!(
+        read_numbers("电话13800138000"),
+        "电话一三八零零一三八零零零"
+    );
+    assert_eq!(read_numbers("3-5个"), "三到五个");
+    assert_eq!(read_numbers("时速120km/h"), "时速一百二十公里每小时");
+    assert_eq!(read_numbers("mp3 格式 v1.2.3"), "mp3 格式 v1.2.3");
+}
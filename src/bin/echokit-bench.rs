@@ -0,0 +1,307 @@
+//! Load test of the realtime endpoint: N concurrent sessions stream a pre-recorded wav,
+//! commit it and wait for the response, then the latency percentiles and the error rate
+//! are printed.
+//!
+//! `echokit-bench <ws://host:port/v1/realtime> <audio.wav> [--sessions 10] [--turns 3]
+//!     [--chunk-ms 100] [--timeout-sec 30] [--text] [--json]`
+
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use echokit_server::util;
+use futures_util::{SinkExt, StreamExt};
+use reqwest_websocket::{Message, RequestBuilderExt};
+
+/// the realtime sessions expect 24k mono pcm16 by default
+const SAMPLE_RATE: u32 = 24000;
+
+#[derive(Debug, Clone)]
+struct Options {
+    url: String,
+    wav: String,
+    sessions: usize,
+    turns: usize,
+    chunk_ms: u64,
+    timeout: Duration,
+    /// text responses only, the first text delta counts as the first audio
+    text: bool,
+    json: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut positional = vec![];
+        let mut options = Options {
+            url: String::new(),
+            wav: String::new(),
+            sessions: 10,
+            turns: 3,
+            chunk_ms: 100,
+            timeout: Duration::from_secs(30),
+            text: false,
+            json: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{name} needs a value"))
+            };
+            match arg.as_str() {
+                "--sessions" => options.sessions = value(arg)?.parse()?,
+                "--turns" => options.turns = value(arg)?.parse()?,
+                "--chunk-ms" => options.chunk_ms = value(arg)?.parse()?,
+                "--timeout-sec" => options.timeout = Duration::from_secs(value(arg)?.parse()?),
+                "--text" => options.text = true,
+                "--json" => options.json = true,
+                _ if arg.starts_with("--") => anyhow::bail!("unknown option {arg}"),
+                _ => positional.push(arg.clone()),
+            }
+        }
+        let [url, wav] = positional.as_slice() else {
+            anyhow::bail!(
+                "usage: echokit-bench <ws://host:port/v1/realtime> <audio.wav> [--sessions N] \
+                 [--turns N] [--chunk-ms MS] [--timeout-sec S] [--text] [--json]"
+            );
+        };
+        options.url = url.clone();
+        options.wav = wav.clone();
+        options.chunk_ms = options.chunk_ms.max(10);
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Turn {
+    /// from the commit to the first audio (or text) delta
+    first_audio_ms: Option<u64>,
+    /// from the commit to `response.done`
+    response_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct SessionResult {
+    turns: Vec<Turn>,
+    errors: Vec<String>,
+}
+
+fn event(value: serde_json::Value) -> Message {
+    Message::Text(value.to_string())
+}
+
+async fn run_session(options: &Options, pcm: &[u8]) -> SessionResult {
+    let mut result = SessionResult::default();
+    let mut websocket = match connect(&options.url).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            result.errors.push(format!("connect: {e}"));
+            return result;
+        }
+    };
+    let modalities = if options.text {
+        serde_json::json!(["text"])
+    } else {
+        serde_json::json!(["text", "audio"])
+    };
+    let update = event(serde_json::json!({
+        "type": "session.update",
+        "session": { "modalities": modalities },
+    }));
+    if let Err(e) = websocket.send(update).await {
+        result.errors.push(format!("session.update: {e}"));
+        return result;
+    }
+
+    let chunk_bytes = (SAMPLE_RATE as u64 * 2 * options.chunk_ms / 1000) as usize;
+    for _ in 0..options.turns {
+        match run_turn(&mut websocket, options, pcm, chunk_bytes).await {
+            Ok(turn) => result.turns.push(turn),
+            Err(e) => {
+                result.errors.push(e.to_string());
+                break;
+            }
+        }
+    }
+    let _ = websocket
+        .send(Message::Close {
+            code: reqwest_websocket::CloseCode::Normal,
+            reason: String::new(),
+        })
+        .await;
+    result
+}
+
+async fn connect(url: &str) -> anyhow::Result<reqwest_websocket::WebSocket> {
+    let response = reqwest::Client::new().get(url).upgrade().send().await?;
+    Ok(response.into_websocket().await?)
+}
+
+/// stream the audio at its real pace, commit, and wait for `response.done`
+async fn run_turn(
+    websocket: &mut reqwest_websocket::WebSocket,
+    options: &Options,
+    pcm: &[u8],
+    chunk_bytes: usize,
+) -> anyhow::Result<Turn> {
+    let mut pace = tokio::time::interval(Duration::from_millis(options.chunk_ms));
+    for chunk in pcm.chunks(chunk_bytes) {
+        pace.tick().await;
+        let audio = base64::prelude::BASE64_STANDARD.encode(chunk);
+        websocket
+            .send(event(serde_json::json!({
+                "type": "input_audio_buffer.append",
+                "audio": audio,
+            })))
+            .await?;
+    }
+    websocket
+        .send(event(
+            serde_json::json!({ "type": "input_audio_buffer.commit" }),
+        ))
+        .await?;
+    let committed = Instant::now();
+
+    let mut turn = Turn::default();
+    let deadline = tokio::time::Instant::now() + options.timeout;
+    loop {
+        let message = tokio::time::timeout_at(deadline, websocket.next())
+            .await
+            .map_err(|_| anyhow::anyhow!("no response.done after {:?}", options.timeout))?;
+        let Some(message) = message else {
+            anyhow::bail!("connection closed");
+        };
+        let Message::Text(text) = message? else {
+            continue;
+        };
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) else {
+            continue;
+        };
+        let elapsed = committed.elapsed().as_millis() as u64;
+        match value["type"].as_str().unwrap_or_default() {
+            "response.audio.delta" => {
+                turn.first_audio_ms.get_or_insert(elapsed);
+            }
+            "response.text.delta" if options.text => {
+                turn.first_audio_ms.get_or_insert(elapsed);
+            }
+            "response.done" => {
+                turn.response_ms = elapsed;
+                return Ok(turn);
+            }
+            "error" => {
+                anyhow::bail!("error event: {}", value["error"]["message"]);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+fn percentiles(mut values: Vec<u64>) -> serde_json::Value {
+    values.sort_unstable();
+    serde_json::json!({
+        "p50": percentile(&values, 0.5),
+        "p90": percentile(&values, 0.9),
+        "p99": percentile(&values, 0.99),
+        "max": values.last().copied().unwrap_or(0),
+    })
+}
+
+fn report(options: &Options, results: &[SessionResult], elapsed: Duration) -> serde_json::Value {
+    let turns = results.iter().flat_map(|r| &r.turns).collect::<Vec<_>>();
+    let errors = results.iter().flat_map(|r| &r.errors).collect::<Vec<_>>();
+    let attempted = turns.len() + errors.len();
+    serde_json::json!({
+        "sessions": options.sessions,
+        "turns": turns.len(),
+        "errors": errors.len(),
+        "error_rate": if attempted == 0 { 0.0 } else { errors.len() as f64 / attempted as f64 },
+        "first_audio_ms": percentiles(turns.iter().filter_map(|t| t.first_audio_ms).collect()),
+        "response_ms": percentiles(turns.iter().map(|t| t.response_ms).collect()),
+        "elapsed_sec": elapsed.as_secs_f64(),
+        "error_samples": errors.iter().take(10).collect::<Vec<_>>(),
+    })
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let options = Options::parse(&args)?;
+    let pcm = std::sync::Arc::new(util::load_pcm(&options.wav, SAMPLE_RATE)?);
+    log::info!(
+        "{} sessions x {} turns of {}ms audio against {}",
+        options.sessions,
+        options.turns,
+        pcm.len() as u64 * 1000 / (SAMPLE_RATE as u64 * 2),
+        options.url
+    );
+
+    let start = Instant::now();
+    let tasks = (0..options.sessions)
+        .map(|_| {
+            let options = options.clone();
+            let pcm = pcm.clone();
+            tokio::spawn(async move { run_session(&options, &pcm).await })
+        })
+        .collect::<Vec<_>>();
+    let mut results = vec![];
+    for task in tasks {
+        results.push(task.await?);
+    }
+    let report = report(&options, &results, start.elapsed());
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!(
+            "sessions: {}  turns: {}  errors: {} ({:.1}%)  in {:.1}s",
+            report["sessions"],
+            report["turns"],
+            report["errors"],
+            report["error_rate"].as_f64().unwrap_or(0.0) * 100.0,
+            report["elapsed_sec"].as_f64().unwrap_or(0.0),
+        );
+        for name in ["first_audio_ms", "response_ms"] {
+            let p = &report[name];
+            println!(
+                "{name:>15}: p50 {}  p90 {}  p99 {}  max {}",
+                p["p50"], p["p90"], p["p99"], p["max"]
+            );
+        }
+        for error in report["error_samples"].as_array().into_iter().flatten() {
+            println!("error: {}", error.as_str().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_bench_helpers() {
+    let p = percentiles(vec![300, 100, 200]);
+    assert_eq!(
+        (p["p50"].as_u64(), p["max"].as_u64()),
+        (Some(200), Some(300))
+    );
+
+    let args = [
+        "ws://localhost:9090/v1/realtime",
+        "a.wav",
+        "--sessions",
+        "50",
+        "--text",
+    ]
+    .map(str::to_string);
+    let options = Options::parse(&args).unwrap();
+    assert_eq!(
+        (options.sessions, options.turns, options.text),
+        (50, 3, true)
+    );
+    assert!(Options::parse(&args[..1]).is_err());
+}
@@ -0,0 +1,498 @@
+//! Simulated device: connects to `/ws/{id}` as the firmware does, streams a wav file or
+//! the microphone, plays and saves the audio of the answers and prints the events. the
+//! reference client of the device protocol: 16k pcm16 binary frames up, `End:Normal` at
+//! the end of an utterance, [`ServerEvent`] in msgpack down.
+//!
+//! `echokit-client <ws://host:port/ws/{id}> [audio.wav] [--mic] [--turns 1]
+//!     [--chunk-ms 100] [--out reply.wav] [--out-hz 16000] [--no-play] [--timeout-sec 60]`
+//!
+//! with `--mic` each enter ends an utterance. the microphone and the playback need
+//! `--features client-audio`, without it the answers are only saved with `--out`.
+
+use std::time::Duration;
+
+use echokit_server::{
+    protocol::{DeviceControl, ServerEvent},
+    util,
+};
+use futures_util::{SinkExt, StreamExt};
+use reqwest_websocket::{Message, RequestBuilderExt};
+use tokio::io::AsyncBufReadExt;
+
+/// the devices send 16k mono pcm16
+const IN_HZ: u32 = 16000;
+
+#[derive(Debug, Clone)]
+struct Options {
+    url: String,
+    wav: Option<String>,
+    mic: bool,
+    turns: usize,
+    chunk_ms: u64,
+    out: Option<String>,
+    /// `stream.output_sample_rate` of the server
+    out_hz: u32,
+    play: bool,
+    timeout: Duration,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut positional = vec![];
+        let mut options = Options {
+            url: String::new(),
+            wav: None,
+            mic: false,
+            turns: 1,
+            chunk_ms: 100,
+            out: None,
+            out_hz: 16000,
+            play: true,
+            timeout: Duration::from_secs(60),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{name} needs a value"))
+            };
+            match arg.as_str() {
+                "--mic" => options.mic = true,
+                "--turns" => options.turns = value(arg)?.parse()?,
+                "--chunk-ms" => options.chunk_ms = value(arg)?.parse()?,
+                "--out" => options.out = Some(value(arg)?.clone()),
+                "--out-hz" => options.out_hz = value(arg)?.parse()?,
+                "--no-play" => options.play = false,
+                "--timeout-sec" => options.timeout = Duration::from_secs(value(arg)?.parse()?),
+                _ if arg.starts_with("--") => anyhow::bail!("unknown option {arg}"),
+                _ => positional.push(arg.clone()),
+            }
+        }
+        match (positional.as_slice(), options.mic) {
+            ([url], true) => options.url = url.clone(),
+            ([url, wav], false) => {
+                options.url = url.clone();
+                options.wav = Some(wav.clone());
+            }
+            _ => anyhow::bail!(
+                "usage: echokit-client <ws://host:port/ws/{{id}}> <audio.wav | --mic> [--turns N] \
+                 [--chunk-ms MS] [--out reply.wav] [--out-hz HZ] [--no-play] [--timeout-sec S]"
+            ),
+        }
+        options.chunk_ms = options.chunk_ms.max(10);
+        options.turns = options.turns.max(1);
+        Ok(options)
+    }
+}
+
+#[cfg_attr(not(feature = "client-audio"), allow(dead_code))]
+fn to_bytes(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+fn to_samples(pcm: &[u8]) -> Vec<i16> {
+    pcm.chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect()
+}
+
+#[cfg(feature = "client-audio")]
+mod audio {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use echokit_server::util;
+
+    pub type Mic = cpal::Stream;
+
+    /// the default input device, sent as chunks of `IN_HZ` mono pcm16
+    pub fn microphone(tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>) -> anyhow::Result<Mic> {
+        let device = cpal::default_host()
+            .default_input_device()
+            .ok_or_else(|| anyhow::anyhow!("no microphone"))?;
+        let config = device.default_input_config()?;
+        let channels = config.channels().max(1) as usize;
+        let rate = config.sample_rate().0;
+        let send = move |mono: Vec<i16>| {
+            let _ = tx.send(super::to_bytes(&util::resample(&mono, rate, super::IN_HZ)));
+        };
+        let on_error = |e| log::error!("microphone error: {e}");
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => device.build_input_stream(
+                &config.config(),
+                move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                    send(
+                        data.chunks(channels)
+                            .map(|f| {
+                                (f.iter().sum::<f32>() / f.len() as f32 * i16::MAX as f32) as i16
+                            })
+                            .collect(),
+                    )
+                },
+                on_error,
+                None,
+            )?,
+            cpal::SampleFormat::I16 => device.build_input_stream(
+                &config.config(),
+                move |data: &[i16], _: &cpal::InputCallbackInfo| {
+                    send(
+                        data.chunks(channels)
+                            .map(|f| {
+                                (f.iter().map(|s| *s as i32).sum::<i32>() / f.len() as i32) as i16
+                            })
+                            .collect(),
+                    )
+                },
+                on_error,
+                None,
+            )?,
+            format => anyhow::bail!("unsupported microphone format {format}"),
+        };
+        stream.play()?;
+        Ok(stream)
+    }
+
+    /// the default output device, fed from a queue
+    pub struct Speaker {
+        queue: Arc<Mutex<VecDeque<f32>>>,
+        rate: u32,
+        _stream: cpal::Stream,
+    }
+
+    impl Speaker {
+        pub fn open() -> anyhow::Result<Self> {
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| anyhow::anyhow!("no speaker"))?;
+            let config = device.default_output_config()?;
+            if config.sample_format() != cpal::SampleFormat::F32 {
+                anyhow::bail!("unsupported speaker format {}", config.sample_format());
+            }
+            let channels = config.channels().max(1) as usize;
+            let queue = Arc::new(Mutex::new(VecDeque::<f32>::new()));
+            let samples = queue.clone();
+            let stream = device.build_output_stream(
+                &config.config(),
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    let mut samples = samples.lock().unwrap();
+                    for frame in data.chunks_mut(channels) {
+                        frame.fill(samples.pop_front().unwrap_or(0.0));
+                    }
+                },
+                |e| log::error!("speaker error: {e}"),
+                None,
+            )?;
+            stream.play()?;
+            Ok(Self {
+                queue,
+                rate: config.sample_rate().0,
+                _stream: stream,
+            })
+        }
+
+        pub fn play(&self, samples: &[i16], hz: u32) {
+            let samples = util::resample(samples, hz, self.rate);
+            self.queue
+                .lock()
+                .unwrap()
+                .extend(samples.iter().map(|s| *s as f32 / i16::MAX as f32));
+        }
+    }
+}
+
+#[cfg(not(feature = "client-audio"))]
+mod audio {
+    pub type Mic = ();
+
+    pub fn microphone(_: tokio::sync::mpsc::UnboundedSender<Vec<u8>>) -> anyhow::Result<Mic> {
+        anyhow::bail!("--mic needs `--features client-audio`")
+    }
+
+    pub struct Speaker;
+
+    impl Speaker {
+        pub fn open() -> anyhow::Result<Self> {
+            anyhow::bail!("the playback needs `--features client-audio`")
+        }
+
+        pub fn play(&self, _: &[i16], _: u32) {}
+    }
+}
+
+/// what a device does with the events of the server
+struct Device {
+    out_hz: u32,
+    speaker: Option<audio::Speaker>,
+    out: Option<hound::WavWriter<std::io::BufWriter<std::fs::File>>>,
+    control: DeviceControl,
+    /// bytes of the audio being received
+    audio_bytes: usize,
+}
+
+impl Device {
+    fn audio_ms(&self) -> u64 {
+        self.audio_bytes as u64 * 1000 / (self.out_hz as u64 * 2)
+    }
+
+    /// the line printed for the event, and the text to send back
+    fn handle(&mut self, event: ServerEvent) -> anyhow::Result<(Option<String>, Option<String>)> {
+        let line = match event {
+            ServerEvent::HelloStart | ServerEvent::StartVideo | ServerEvent::EndVideo => None,
+            ServerEvent::BGStart | ServerEvent::BGChunk { .. } => None,
+            ServerEvent::BGEnd => Some("background image".to_string()),
+            ServerEvent::HelloChunk { data } => {
+                self.audio_bytes += data.len();
+                None
+            }
+            ServerEvent::HelloEnd => {
+                let line = format!("hello: {}ms of audio", self.audio_ms());
+                self.audio_bytes = 0;
+                Some(line)
+            }
+            ServerEvent::ASR { text } => Some(format!("asr: {text}")),
+            ServerEvent::Action { action } => Some(format!("action: {action}")),
+            ServerEvent::StartAudio { text } => Some(format!("speak: {text}")),
+            ServerEvent::AudioChunk { data } => {
+                self.audio_bytes += data.len();
+                let samples = to_samples(&data);
+                if let Some(speaker) = &self.speaker {
+                    speaker.play(&samples, self.out_hz);
+                }
+                if let Some(out) = &mut self.out {
+                    for sample in samples {
+                        out.write_sample(sample)?;
+                    }
+                }
+                None
+            }
+            ServerEvent::EndAudio => {
+                let line = format!("audio: {}ms", self.audio_ms());
+                self.audio_bytes = 0;
+                Some(line)
+            }
+            ServerEvent::EndResponse => Some("end of response".to_string()),
+            ServerEvent::Control(control) => {
+                self.control.merge(control);
+                let ack = format!("Ack:Control:{}", serde_json::to_string(&self.control)?);
+                return Ok((Some(format!("control: {:?}", self.control)), Some(ack)));
+            }
+            ServerEvent::StartListening { timeout_ms } => {
+                Some(format!("listening, {timeout_ms}ms"))
+            }
+            ServerEvent::EndListening => Some("not listening".to_string()),
+            ServerEvent::Calibrated { noise_floor_db } => {
+                Some(format!("calibrated: noise floor {noise_floor_db:.1}dB"))
+            }
+            ServerEvent::PromptsUpdated { version } => {
+                Some(format!("prompts updated: version {version}"))
+            }
+            ServerEvent::Translation(t) => Some(format!(
+                "translation ({}): {:?} -> {:?}",
+                t.language, t.source, t.text
+            )),
+            ServerEvent::Alarm(alarm) => Some(format!(
+                "{:?} {}: {:?}, {}ms left",
+                alarm.kind, alarm.id, alarm.state, alarm.remaining_ms
+            )),
+            ServerEvent::Media(media) => Some(format!(
+                "media {:?}: {}",
+                media.state,
+                media.title.unwrap_or_default()
+            )),
+            ServerEvent::Routine(routine) => Some(format!(
+                "routine {} {:?}: {}/{} {}",
+                routine.name,
+                routine.state,
+                routine.step,
+                routine.steps,
+                routine.error.unwrap_or_default()
+            )),
+        };
+        Ok((line, None))
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let options = Options::parse(&args)?;
+    let pcm = match &options.wav {
+        Some(wav) => util::load_pcm(wav, IN_HZ)?,
+        None => vec![],
+    };
+
+    let speaker = if options.play {
+        audio::Speaker::open()
+            .map_err(|e| log::warn!("no playback: {e}"))
+            .ok()
+    } else {
+        None
+    };
+    let out = match &options.out {
+        Some(path) => Some(hound::WavWriter::create(
+            path,
+            hound::WavSpec {
+                channels: 1,
+                sample_rate: options.out_hz,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            },
+        )?),
+        None => None,
+    };
+    let mut device = Device {
+        out_hz: options.out_hz,
+        speaker,
+        out,
+        control: DeviceControl::default(),
+        audio_bytes: 0,
+    };
+
+    let response = reqwest::Client::new()
+        .get(&options.url)
+        .upgrade()
+        .send()
+        .await?;
+    let mut websocket = response.into_websocket().await?;
+    println!("connected to {}", options.url);
+
+    let (mic_tx, mut mic_rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+    let _mic = if options.mic {
+        println!("speak, then press enter");
+        Some(audio::microphone(mic_tx)?)
+    } else {
+        drop(mic_tx);
+        None
+    };
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+
+    let chunk_bytes = (IN_HZ as u64 * 2 * options.chunk_ms / 1000) as usize;
+    let mut chunks = pcm.chunks(chunk_bytes);
+    let mut streaming = options.wav.is_some();
+    let mut turns = 0;
+    let mut pace = tokio::time::interval(Duration::from_millis(options.chunk_ms));
+    let deadline = tokio::time::sleep(Duration::MAX);
+    tokio::pin!(deadline);
+    let mut waiting = false;
+
+    loop {
+        tokio::select! {
+            _ = pace.tick(), if streaming => {
+                match chunks.next() {
+                    Some(chunk) => websocket.send(Message::Binary(chunk.to_vec().into())).await?,
+                    None => {
+                        websocket.send(Message::Text("End:Normal".to_string())).await?;
+                        streaming = false;
+                        waiting = true;
+                        deadline.as_mut().reset(tokio::time::Instant::now() + options.timeout);
+                    }
+                }
+            }
+            Some(chunk) = mic_rx.recv() => {
+                websocket.send(Message::Binary(chunk.into())).await?;
+            }
+            line = stdin.next_line(), if options.mic => {
+                if line?.is_none() {
+                    break;
+                }
+                websocket.send(Message::Text("End:Normal".to_string())).await?;
+            }
+            message = websocket.next() => {
+                let Some(message) = message else {
+                    println!("disconnected");
+                    break;
+                };
+                let data = match message? {
+                    Message::Binary(data) => data,
+                    Message::Text(text) => {
+                        println!("text: {text}");
+                        continue;
+                    }
+                    Message::Close { code, reason } => {
+                        println!("closed: {code:?} {reason}");
+                        break;
+                    }
+                    _ => continue,
+                };
+                let event = match rmp_serde::from_slice::<ServerEvent>(&data) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        log::warn!("invalid event: {e}");
+                        continue;
+                    }
+                };
+                let end = matches!(event, ServerEvent::EndResponse);
+                let (line, reply) = device.handle(event)?;
+                if let Some(line) = line {
+                    println!("{line}");
+                }
+                if let Some(reply) = reply {
+                    websocket.send(Message::Text(reply)).await?;
+                }
+                if end && waiting {
+                    waiting = false;
+                    turns += 1;
+                    if turns >= options.turns {
+                        break;
+                    }
+                    chunks = pcm.chunks(chunk_bytes);
+                    streaming = true;
+                }
+            }
+            _ = &mut deadline, if waiting => {
+                anyhow::bail!("no response after {:?}", options.timeout);
+            }
+        }
+    }
+
+    if let Some(out) = device.out.take() {
+        out.finalize()?;
+    }
+    // the end of the answer is still in the queue of the speaker
+    if device.speaker.is_some() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_client_helpers() {
+    let args = ["ws://localhost:8080/ws/dev1", "a.wav", "--turns", "2"].map(str::to_string);
+    let options = Options::parse(&args).unwrap();
+    assert_eq!(
+        (options.wav.as_deref(), options.turns, options.mic),
+        (Some("a.wav"), 2, false)
+    );
+    assert!(Options::parse(&args[..1]).is_err());
+    let args = ["ws://localhost:8080/ws/dev1", "--mic"].map(str::to_string);
+    assert!(Options::parse(&args).unwrap().mic);
+
+    assert_eq!(to_samples(&to_bytes(&[1, -2, 300])), [1, -2, 300]);
+
+    let mut device = Device {
+        out_hz: 16000,
+        speaker: None,
+        out: None,
+        control: DeviceControl::default(),
+        audio_bytes: 0,
+    };
+    let (line, _) = device
+        .handle(ServerEvent::AudioChunk {
+            data: vec![0; 3200],
+        })
+        .unwrap();
+    assert_eq!(line, None);
+    let (line, _) = device.handle(ServerEvent::EndAudio).unwrap();
+    assert_eq!(line.as_deref(), Some("audio: 100ms"));
+    let (_, ack) = device
+        .handle(ServerEvent::Control(DeviceControl {
+            volume: Some(30),
+            ..Default::default()
+        }))
+        .unwrap();
+    assert_eq!(ack.as_deref(), Some(r#"Ack:Control:{"volume":30}"#));
+}
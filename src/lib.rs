@@ -0,0 +1,9 @@
+//! The server as a library: `main.rs` runs it, the benches and the tools under `src/bin`
+//! can call its modules.
+
+pub mod ai;
+pub mod config;
+pub mod protocol;
+pub mod services;
+pub mod storage;
+pub mod util;
@@ -1,21 +1,34 @@
 use std::sync::Arc;
 
-use axum::{routing::any, Router};
-use config::Config;
-
-use crate::{config::ASRConfig, services::realtime_ws::StableRealtimeConfig};
-
-pub mod ai;
-pub mod config;
-pub mod protocol;
-pub mod services;
-pub mod util;
+use axum::{
+    routing::{any, post},
+    Router,
+};
+use echokit_server::{
+    ai,
+    config::{self, Config},
+    services::{self, realtime_ws::StableRealtimeConfig},
+    storage, util,
+};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let config_path = std::env::args().nth(1).unwrap_or("config.toml".to_string());
-    let config = config::Config::load(&config_path).unwrap();
+    #[cfg(feature = "tokio-console")]
+    console_subscriber::init();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some(command @ ("export" | "import")) = args.first().map(String::as_str) {
+        if let Err(e) = services::archive::run_command(command, &args[1..]).await {
+            log::error!("{command} error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let config_path = args.first().cloned().unwrap_or("config.toml".to_string());
+    let config = config::Config::load(&config_path)
+        .unwrap_or_else(|e| panic!("Failed to load {config_path}: {e}"));
 
     let listener = tokio::net::TcpListener::bind(&config.addr).await.unwrap();
     let mut mcp_clients = vec![];
@@ -34,23 +47,201 @@ async fn routes(
 ) -> Router {
     log::info!("Start with: {:#?}", config);
 
+    if let Some(deterministic) = &config.deterministic {
+        log::warn!("Deterministic mode, seed {}", deterministic.seed);
+        util::set_deterministic(deterministic.seed);
+    }
+
+    if let Some(chaos) = &config.chaos {
+        #[cfg(feature = "chaos")]
+        {
+            log::warn!("Chaos mode: {chaos:?}");
+            ai::chaos::set(chaos.clone());
+        }
+        #[cfg(not(feature = "chaos"))]
+        log::warn!("[chaos] {chaos:?} ignored, build with the chaos feature");
+    }
+
     let hello_wav = config.hello_wav.as_ref().and_then(|wav| {
         log::info!("Hello WAV: {}", wav);
         std::fs::read(wav).ok()
     });
 
+    let storage = storage::Storage::open(
+        &config.storage,
+        config.registry.as_ref().map(|r| r.path.as_str()),
+    )
+    .await
+    .map(Arc::new)
+    .unwrap_or_else(|e| panic!("Failed to open storage: {e}"));
+
+    let cluster = match config.redis.clone() {
+        Some(redis) => match services::cluster::Cluster::connect(redis).await {
+            Ok(cluster) => Some(cluster),
+            Err(e) => {
+                log::error!("Failed to connect to redis: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let tenants = Arc::new(
+        services::tenants::Tenants::new(config.tenants.clone(), config.admin_token.clone())
+            .with_store(storage.clone()),
+    );
+
+    let registry = match config.registry.clone() {
+        Some(registry) => match services::registry::DeviceRegistry::load(
+            registry,
+            config.profiles.clone(),
+            storage.clone(),
+            cluster.clone(),
+        )
+        .await
+        {
+            Ok(registry) => {
+                let registry = Arc::new(registry.with_tenants(tenants.clone()));
+                registry.follow_cluster();
+                Some(registry)
+            }
+            Err(e) => {
+                log::error!("Failed to load device registry: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let offline = match (&config.offline, &config.config) {
+        (
+            Some(offline),
+            config::AIConfig::Stable { tts, .. } | config::AIConfig::GeminiAndTTS { tts, .. },
+        ) => match services::offline::OfflineAnswers::load(
+            offline.clone(),
+            tts,
+            config.stream.output_sample_rate,
+        )
+        .await
+        {
+            Ok(offline) => Some(Arc::new(offline)),
+            Err(e) => {
+                log::error!("Failed to load offline answers: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let phrase_audio = Arc::new(services::phrases::PhraseAudio::new(
+        &config.phrases,
+        config.stream.output_sample_rate,
+    ));
+    if let config::AIConfig::Stable { tts, .. } | config::AIConfig::GeminiAndTTS { tts, .. } =
+        &config.config
+    {
+        phrase_audio.spawn_render(
+            tts.clone(),
+            config.speech_text.clone(),
+            config.phrases.clone(),
+            config.profiles.clone(),
+        );
+    }
+
+    let earcon_audio = Arc::new(services::earcons::EarconAudio::load(
+        &config.earcons,
+        &config.profiles,
+        config.stream.output_sample_rate,
+    ));
+
+    let speech = services::speech::SpeechService {
+        tts: match &config.config {
+            config::AIConfig::Stable { tts, .. } | config::AIConfig::GeminiAndTTS { tts, .. } => {
+                Some(tts.clone())
+            }
+            config::AIConfig::Gemini { .. } => None,
+        },
+        asr: match &config.config {
+            config::AIConfig::Stable { asr, .. } => asr.whisper(),
+            _ => None,
+        },
+        speech_text: config.speech_text.clone(),
+        output_sample_rate: config.stream.output_sample_rate,
+        admin_token: config.admin_token.clone(),
+    };
+
+    let transcription_jobs = services::transcription_jobs::TranscriptionJobs::new(
+        speech.asr.clone(),
+        config.admin_token.clone(),
+    );
+
+    let history = config.history.as_ref().map(|_| {
+        Arc::new(services::history::History::new(
+            storage.clone(),
+            config.admin_token.clone(),
+        ))
+    });
+
+    let analytics = config.analytics.clone().map(|analytics| {
+        let analytics = Arc::new(services::analytics::Analytics::new(
+            analytics,
+            config.admin_token.clone(),
+            storage.clone(),
+        ));
+        analytics.spawn();
+        analytics
+    });
+    let prompts = match &config.config {
+        config::AIConfig::Stable { llm, .. } => services::prompts::Prompts {
+            sys_prompts: llm.sys_prompts.clone(),
+            dynamic_prompts: llm.dynamic_prompts.iter().cloned().collect(),
+        },
+        _ => Default::default(),
+    };
+    let prompts = services::prompts::LivePrompts::new(prompts).with_store(storage.clone());
+    if let Err(e) = prompts.load().await {
+        log::error!("error loading the prompts: {e}");
+    }
+    let sessions = Arc::new(
+        services::sessions::SessionManager::new(
+            config.admin_token.clone(),
+            history.clone(),
+            cluster.clone(),
+            analytics.clone(),
+        )
+        .with_prompts(prompts)
+        .with_costs(config.costs.clone())
+        .with_tenants(tenants.clone()),
+    );
+    if let Some(cluster) = &cluster {
+        cluster.spawn_session_sync(sessions.clone());
+    }
+    sessions.follow_cluster();
+
+    let webhooks = Arc::new(services::webhooks::Webhooks::new(config.webhooks.clone()));
+
     let mut tool_set = ai::openai::tool::ToolSet::default();
     let mut real_config: Option<StableRealtimeConfig> = None;
     match &config.config {
-        config::AIConfig::Stable {
-            llm,
-            tts,
-            asr: ASRConfig::Whisper(asr),
-        } => {
+        config::AIConfig::Stable { llm, tts, asr } if asr.whisper().is_some() => {
             real_config = Some(StableRealtimeConfig {
                 llm: llm.clone(),
                 tts: tts.clone(),
-                asr: asr.clone(),
+                asr: asr.whisper().unwrap_or_default(),
+                stream: config.stream.clone(),
+                speech_text: config.speech_text.clone(),
+                registry: registry.clone(),
+                sessions: sessions.clone(),
+                webhooks: webhooks.clone(),
+                capture: config.capture.clone(),
+                phrases: config.phrases.clone(),
+                phrase_audio: phrase_audio.clone(),
+                earcons: config.earcons.clone(),
+                earcon_audio: earcon_audio.clone(),
+                intents: Arc::new(services::intents::IntentRouter::new(
+                    &config.intents,
+                    config.vectors.as_ref(),
+                )),
             });
             for server in &llm.mcp_server {
                 match server.type_ {
@@ -75,15 +266,250 @@ async fn routes(
         _ => {}
     }
 
+    let chat_completions = match &config.config {
+        config::AIConfig::Stable { llm, .. } => {
+            Some(services::chat_completions::ChatCompletionsService {
+                llm: llm.clone(),
+                tool_set: tool_set.clone(),
+                admin_token: config.admin_token.clone(),
+                webhooks: webhooks.clone(),
+                sessions: sessions.clone(),
+            })
+        }
+        _ => None,
+    };
+
+    let console = services::console::ConsoleService {
+        admin_token: config.admin_token.clone(),
+        providers: services::console::health_targets(&config.config),
+        output_sample_rate: config.stream.output_sample_rate,
+        sessions: sessions.clone(),
+        webhooks: webhooks.clone(),
+    };
+
+    let recordings = storage::recordings::Recordings::new(&config.recordings)
+        .map(Arc::new)
+        .unwrap_or_else(|e| panic!("Failed to open the recordings: {e}"));
+
+    let knowledge = match &config.vectors {
+        Some(vectors) if config.memory.is_some() || config.rag.is_some() => {
+            match services::knowledge::Knowledge::new(
+                vectors,
+                config.memory.clone(),
+                config.rag.clone(),
+                storage.clone(),
+                config.admin_token.clone(),
+            )
+            .await
+            {
+                Ok(knowledge) => {
+                    let knowledge = Arc::new(knowledge);
+                    let indexer = knowledge.clone();
+                    tokio::spawn(async move {
+                        match indexer.index_documents().await {
+                            Ok(0) => {}
+                            Ok(n) => log::info!("{n} passages of the documents indexed"),
+                            Err(e) => log::error!("Failed to index the documents: {e}"),
+                        }
+                    });
+                    Some(knowledge)
+                }
+                Err(e) => {
+                    log::error!("Failed to open the vector index: {e}");
+                    None
+                }
+            }
+        }
+        Some(_) => None,
+        None => {
+            if config.memory.is_some() || config.rag.is_some() {
+                log::warn!("[memory] and [rag] need [vectors]");
+            }
+            None
+        }
+    };
+
+    let reminders = config.reminders.clone().map(|reminders| {
+        Arc::new(services::reminders::Reminders::new(
+            reminders,
+            config.admin_token.clone(),
+            storage.clone(),
+        ))
+    });
+
+    let alarms = config
+        .alarms
+        .clone()
+        .map(|alarms| Arc::new(services::alarms::Alarms::new(alarms)));
+
+    let briefing = config
+        .briefing
+        .clone()
+        .map(|briefing| Arc::new(services::briefing::Briefing::new(briefing)));
+
+    let pool = services::ws::WsPool::new(services::ws::WsPoolConfig {
+        hello_wav,
+        bg_gif: None,
+        config: config.config,
+        stream: config.stream,
+        speech_text: config.speech_text,
+        tool_set,
+        registry: registry.clone(),
+        admin_token: config.admin_token.clone(),
+        groups: config.groups.clone(),
+        offline,
+        sessions: sessions.clone(),
+        webhooks,
+        cluster,
+        recordings: recordings.clone(),
+        knowledge: knowledge.clone(),
+        reminders: reminders.clone(),
+        alarms: alarms.clone(),
+        media: config
+            .media
+            .clone()
+            .map(|media| Arc::new(services::media::Media::new(media))),
+        briefing: briefing.clone(),
+        routines: (!config.routines.is_empty())
+            .then(|| Arc::new(services::routines::Routines::new(config.routines.clone()))),
+        phrases: config.phrases.clone(),
+        phrase_audio,
+        earcons: config.earcons.clone(),
+        earcon_audio,
+    })
+    .map(Arc::new)
+    .unwrap_or_else(|e| panic!("Failed to create the ws pool: {e}"));
+    pool.follow_cluster();
+    if let Some(reminders) = &reminders {
+        reminders.spawn(pool.clone());
+    }
+    if let Some(alarms) = &alarms {
+        alarms.spawn(pool.clone());
+    }
+    if let Some(briefing) = &briefing {
+        briefing.spawn(pool.clone());
+    }
+    let briefing_router = briefing
+        .is_some()
+        .then(|| services::briefing::new_briefing_service(pool.clone()));
+
+    let retention = config.retention.clone().map(|retention| {
+        let retention = Arc::new(services::retention::Retention::new(
+            retention,
+            config.admin_token.clone(),
+            storage.clone(),
+            recordings.clone(),
+            registry.clone(),
+        ));
+        retention.spawn();
+        retention
+    });
+
     let mut router = Router::new()
         // .route("/", get(handler))
         .route("/ws/{id}", any(services::ws::ws_handler))
-        .nest("/record", services::file::new_file_service("./record"))
-        .layer(axum::Extension(Arc::new(services::ws::WsPool::new(
-            hello_wav,
-            config.config,
-            tool_set,
-        ))));
+        .route(
+            "/devices/{id}/announce",
+            post(services::ws::announce_handler),
+        )
+        .route(
+            "/devices/{id}/control",
+            post(services::ws::control_handler).get(services::ws::get_control_handler),
+        )
+        .route(
+            "/groups/{group}/announce",
+            post(services::ws::broadcast_handler),
+        )
+        .nest(
+            "/record",
+            services::file::new_file_service(recordings.clone()),
+        )
+        .layer(axum::Extension(pool))
+        .merge(services::prompts::new_prompts_service(sessions.clone()))
+        .merge(services::sessions::new_sessions_service(sessions.clone()))
+        .merge(services::console::new_console_service(console))
+        .merge(services::speech::new_speech_service(speech))
+        .merge(services::transcription_jobs::new_transcription_jobs_service(transcription_jobs))
+        .merge(services::openapi::new_openapi_service());
+
+    if let Some(chat_completions) = chat_completions {
+        log::info!("Adding chat completions handler at /v1/chat/completions");
+        router = router.merge(services::chat_completions::new_chat_completions_service(
+            chat_completions,
+        ));
+    }
+
+    if let Some(knowledge) = &knowledge {
+        log::info!("Adding memory handlers at /v1/devices/{{id}}/memories");
+        router = router.merge(services::knowledge::new_knowledge_service(
+            knowledge.clone(),
+        ));
+    }
+
+    if let Some(reminders) = reminders {
+        log::info!("Adding reminder handlers at /v1/devices/{{id}}/reminders");
+        router = router.merge(services::reminders::new_reminders_service(reminders));
+    }
+
+    if let Some(briefing_router) = briefing_router {
+        log::info!("Adding briefing handlers at /v1/briefing");
+        router = router.merge(briefing_router);
+    }
+
+    router = router.merge(services::archive::new_archive_service(Arc::new(
+        services::archive::ArchiveService {
+            admin_token: config.admin_token.clone(),
+            store: storage.clone(),
+            recordings: recordings.clone(),
+            registry: registry.clone(),
+            knowledge: knowledge.clone(),
+            sessions: sessions.clone(),
+        },
+    )));
+
+    if let Some(retention) = retention {
+        log::info!("Adding retention handler at /v1/retention");
+        router = router.merge(services::retention::new_retention_service(retention));
+    }
+
+    if let Some(analytics) = analytics {
+        log::info!("Adding analytics handler at /v1/stats");
+        router = router.merge(services::analytics::new_analytics_service(analytics));
+    }
+
+    if let Some(history) = history {
+        log::info!("Adding history handler at /v1/history/search");
+        router = router.merge(services::history::new_history_service(history));
+    }
+
+    if tenants.enabled() {
+        log::info!("Adding tenants handler at /v1/tenants");
+        router = router.merge(services::tenants::new_tenants_service(tenants));
+    }
+
+    if let Some(registry) = registry {
+        log::info!("Adding device registry handler at /devices and /admin/devices");
+        router = router.merge(services::registry::new_registry_service(registry));
+    }
+
+    if let Some(ota) = config.ota {
+        match services::ota::new_ota_service(ota) {
+            Ok(ota_router) => {
+                log::info!("Adding OTA handler at /ota");
+                router = router.merge(ota_router);
+            }
+            Err(e) => log::error!("Failed to load OTA service: {}", e),
+        }
+    }
+
+    #[cfg(feature = "pprof")]
+    {
+        log::info!("Adding pprof handler at /debug/pprof");
+        router = router.nest(
+            "/debug/pprof",
+            services::profiling::new_profiling_service(config.admin_token.clone()),
+        );
+    }
 
     if let Some(real_config) = real_config {
         log::info!(
@@ -92,6 +518,8 @@ async fn routes(
         );
         router = router
             .route("/v1/realtime", any(services::realtime_ws::ws_handler))
+            .route("/v1/chat/ws", any(services::realtime_ws::chat_ws_handler))
+            .route("/device/ws", any(services::device_ws::ws_handler))
             .layer(axum::Extension(Arc::new(real_config)));
     }
 
@@ -7,6 +7,11 @@ pub enum ServerEvent {
     HelloChunk { data: Vec<u8> },
     HelloEnd,
 
+    // set Background
+    BGStart,
+    BGChunk { data: Vec<u8> },
+    BGEnd,
+
     ASR { text: String },
     Action { action: String },
     StartAudio { text: String },
@@ -15,6 +20,129 @@ pub enum ServerEvent {
     StartVideo,
     EndVideo,
     EndResponse,
+    Control(DeviceControl),
+    StartListening { timeout_ms: u32 },
+    EndListening,
+    Calibrated { noise_floor_db: f32 },
+    // the prompts were edited with `PUT /v1/prompts`, from this turn on
+    PromptsUpdated { version: u64 },
+    // a translation device: the transcript, sent as `ASR` before, and its translation,
+    // spoken next
+    Translation(Translation),
+    // a timer or an alarm of the device changed, sent again when it reconnects
+    Alarm(AlarmEvent),
+    // the stream of a tool played on the device, its audio is sent as an audio response
+    Media(MediaEvent),
+    // the progress of a routine triggered by voice, one event per step
+    Routine(RoutineEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutineState {
+    Started,
+    StepDone,
+    /// the routine goes on unless it stops on errors
+    StepFailed,
+    /// every step was run, `error` is the last failure if any
+    Done,
+    /// stopped on the failed step
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoutineEvent {
+    pub name: String,
+    pub state: RoutineState,
+    /// the steps run so far
+    pub step: usize,
+    pub steps: usize,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaState {
+    Playing,
+    Paused,
+    /// ended, stopped by voice or replaced by another stream
+    Stopped,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MediaEvent {
+    pub state: MediaState,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmKind {
+    /// rings after a duration
+    Timer,
+    /// rings at a time of day
+    Alarm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmState {
+    Set,
+    /// the time left of a timer, every `tick_sec`
+    Tick,
+    /// the device plays its ring until `stopped` or `cancelled`
+    Ringing,
+    /// cancelled by voice or by the llm
+    Cancelled,
+    /// rang for `ring_sec` without being cancelled
+    Stopped,
+}
+
+/// the state of a timer or an alarm, the device renders the countdown and rings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlarmEvent {
+    pub id: String,
+    pub kind: AlarmKind,
+    pub label: Option<String>,
+    pub state: AlarmState,
+    /// until it rings, 0 once ringing
+    pub remaining_ms: u64,
+}
+
+/// the translation of what a device of a translation profile heard
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Translation {
+    pub source: String,
+    pub text: String,
+    /// the target language of the profile
+    pub language: String,
+}
+
+/// playback volume, led and mic mute of a device, `None` fields are left unchanged.
+/// the device acks with text `Ack:Control:{json}` of its current state
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceControl {
+    /// 0-100
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub led: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+}
+
+impl DeviceControl {
+    pub fn merge(&mut self, other: DeviceControl) {
+        if other.volume.is_some() {
+            self.volume = other.volume;
+        }
+        if other.led.is_some() {
+            self.led = other.led;
+        }
+        if other.mute.is_some() {
+            self.mute = other.mute;
+        }
+    }
 }
 
 #[test]
@@ -36,3 +164,21 @@ fn test_rmp_command() {
         _ => panic!("Unexpected command: {:?}", cmd),
     }
 }
+
+#[test]
+fn test_device_control_merge() {
+    let mut state = DeviceControl {
+        volume: Some(60),
+        led: Some("on".to_string()),
+        mute: None,
+    };
+    state.merge(serde_json::from_str(r#"{"volume": 30, "mute": true}"#).unwrap());
+    assert_eq!(
+        state,
+        DeviceControl {
+            volume: Some(30),
+            led: Some("on".to_string()),
+            mute: Some(true),
+        }
+    );
+}
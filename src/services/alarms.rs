@@ -0,0 +1,367 @@
+//! Timers and alarms, `[alarms]`: kept by the server for each device, not for its
+//! session, so a countdown goes on and a ring is not lost when the device reconnects.
+//!
+//! the llm sets them with the `set_timer` tool. the device gets an `Alarm` event on
+//! `/ws/{id}` for each change:
+//! - `set`, then a `tick` with the time left of a timer every `tick_sec`
+//! - `ringing` when due, the ring phrase is spoken and the device plays its ring
+//! - `stopped` after ringing `ring_sec`, `cancelled` by voice
+//!
+//! an alarm due while its device is away rings when it is back. on a reconnect the state
+//! of each one is sent again.
+//!
+//! `取消闹钟` / `关闭闹钟` cancels the alarms, `取消计时` the timers, `别响了` / `停止响铃`
+//! stops what is ringing. they are answered without the llm, a cancel while something
+//! rings only stops the ringing ones.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Local};
+
+use crate::{
+    config::AlarmsConfig,
+    protocol::{AlarmEvent, AlarmKind, AlarmState},
+    services::ws::{WsCommand, WsPool},
+};
+
+pub const SET_TIMER_TOOL: &str = "set_timer";
+
+/// what the asr may put around a command
+const ASR_PUNCTUATION: &[char] = &['，', '。', '？', '！', ',', '.', '?', '!', ' '];
+
+#[derive(Debug, Clone)]
+struct Alarm {
+    id: String,
+    kind: AlarmKind,
+    label: Option<String>,
+    due_at: DateTime<Local>,
+    ringing_since: Option<DateTime<Local>>,
+}
+
+impl Alarm {
+    fn event(&self, state: AlarmState, now: DateTime<Local>) -> AlarmEvent {
+        AlarmEvent {
+            id: self.id.clone(),
+            kind: self.kind,
+            label: self.label.clone(),
+            state,
+            remaining_ms: (self.due_at - now).num_milliseconds().max(0) as u64,
+        }
+    }
+}
+
+/// a voice command cancelling something
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cancel {
+    /// what is ringing, whatever it is
+    Ringing,
+    Kind(AlarmKind),
+}
+
+pub fn cancel_command(utterance: &str) -> Option<Cancel> {
+    let text = utterance.trim_matches(ASR_PUNCTUATION);
+    if text.chars().count() > 10 {
+        return None;
+    }
+    if matches!(text, "别响了" | "不要响了" | "停止响铃" | "关掉铃声") {
+        return Some(Cancel::Ringing);
+    }
+    if !["取消", "关闭", "关掉", "停止", "删除"]
+        .iter()
+        .any(|verb| text.contains(verb))
+    {
+        return None;
+    }
+    if text.contains("闹钟") {
+        Some(Cancel::Kind(AlarmKind::Alarm))
+    } else if text.contains("计时") || text.contains("定时") {
+        Some(Cancel::Kind(AlarmKind::Timer))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct SetTimerArgs {
+    #[serde(default)]
+    seconds: Option<f64>,
+    #[serde(default)]
+    at: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Alarms {
+    config: AlarmsConfig,
+    /// by device id
+    devices: Mutex<HashMap<String, Vec<Alarm>>>,
+}
+
+impl Alarms {
+    pub fn new(config: AlarmsConfig) -> Self {
+        Self {
+            config,
+            devices: Default::default(),
+        }
+    }
+
+    /// the built-in tool of the llm if `tool`
+    pub fn tool(&self) -> Option<crate::ai::llm::Function> {
+        if !self.config.tool {
+            return None;
+        }
+        Some(crate::ai::llm::Function {
+            name: SET_TIMER_TOOL.to_string(),
+            description: "Set a timer ringing after `seconds`, or an alarm ringing `at` a \
+                time of day, on the user's device."
+                .to_string(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "seconds": {"type": "number", "description": "the duration of a timer"},
+                    "at": {"type": "string", "description": "the local time of an alarm, `HH:MM` for the next one or `YYYY-MM-DD HH:MM`"},
+                    "label": {"type": "string", "description": "what it is for, a few words"}
+                }
+            }),
+        })
+    }
+
+    /// set a timer or an alarm from the tool arguments, its `set` event
+    pub fn set(
+        &self,
+        device_id: &str,
+        arguments: &str,
+        now: DateTime<Local>,
+    ) -> anyhow::Result<AlarmEvent> {
+        let args: SetTimerArgs = serde_json::from_str(arguments)?;
+        let (kind, due_at) = match (args.seconds, args.at) {
+            (Some(seconds), None) if seconds > 0.0 => (
+                AlarmKind::Timer,
+                now + chrono::Duration::milliseconds((seconds * 1000.0) as i64),
+            ),
+            (None, Some(at)) => (AlarmKind::Alarm, super::reminders::parse_at(&at, now)?),
+            _ => anyhow::bail!("one of a positive `seconds` or `at` is needed"),
+        };
+        let alarm = Alarm {
+            id: crate::util::new_uuid().to_string(),
+            kind,
+            label: args.label.filter(|l| !l.trim().is_empty()),
+            due_at,
+            ringing_since: None,
+        };
+        let mut devices = self.devices.lock().unwrap();
+        let alarms = devices.entry(device_id.to_string()).or_default();
+        if alarms.len() >= self.config.max_per_device {
+            anyhow::bail!("the device has {} timers and alarms already", alarms.len());
+        }
+        let event = alarm.event(AlarmState::Set, now);
+        alarms.push(alarm);
+        Ok(event)
+    }
+
+    /// the state of each timer and alarm of a device, for a reconnect
+    pub fn events(&self, device_id: &str, now: DateTime<Local>) -> Vec<AlarmEvent> {
+        let devices = self.devices.lock().unwrap();
+        let alarms = devices
+            .get(device_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        alarms
+            .iter()
+            .map(|alarm| match alarm.ringing_since {
+                Some(_) => alarm.event(AlarmState::Ringing, now),
+                None => alarm.event(AlarmState::Set, now),
+            })
+            .collect()
+    }
+
+    /// cancel what `cancel` selects, the ringing ones only if one rings. none if there is
+    /// nothing to stop ringing, the utterance is for the llm then
+    pub fn cancel(
+        &self,
+        device_id: &str,
+        cancel: Cancel,
+        now: DateTime<Local>,
+    ) -> Option<Vec<AlarmEvent>> {
+        let mut devices = self.devices.lock().unwrap();
+        let alarms = devices.entry(device_id.to_string()).or_default();
+        let selected = |alarm: &Alarm| match cancel {
+            Cancel::Ringing => true,
+            Cancel::Kind(kind) => alarm.kind == kind,
+        };
+        let ringing = alarms
+            .iter()
+            .any(|a| selected(a) && a.ringing_since.is_some());
+        if cancel == Cancel::Ringing && !ringing {
+            return None;
+        }
+        let (cancelled, kept) = std::mem::take(alarms)
+            .into_iter()
+            .partition::<Vec<_>, _>(|a| selected(a) && (!ringing || a.ringing_since.is_some()));
+        *alarms = kept;
+        Some(
+            cancelled
+                .iter()
+                .map(|a| a.event(AlarmState::Cancelled, now))
+                .collect(),
+        )
+    }
+
+    /// advance the timers and alarms as of `now`, the events of each device and the ones
+    /// starting to ring. those of a device not in `online` wait for it
+    fn tick(&self, now: DateTime<Local>, online: &HashSet<String>) -> Vec<(String, AlarmEvent)> {
+        let ring = chrono::Duration::seconds(self.config.ring_sec as i64);
+        let mut events = vec![];
+        let mut devices = self.devices.lock().unwrap();
+        for (device_id, alarms) in devices.iter_mut() {
+            if !online.contains(device_id) {
+                continue;
+            }
+            alarms.retain_mut(|alarm| {
+                let event = match alarm.ringing_since {
+                    Some(since) if now - since >= ring => {
+                        events.push((device_id.clone(), alarm.event(AlarmState::Stopped, now)));
+                        return false;
+                    }
+                    Some(_) => return true,
+                    None if alarm.due_at <= now => {
+                        alarm.ringing_since = Some(now);
+                        alarm.event(AlarmState::Ringing, now)
+                    }
+                    None if alarm.kind == AlarmKind::Timer => alarm.event(AlarmState::Tick, now),
+                    None => return true,
+                };
+                events.push((device_id.clone(), event));
+                true
+            });
+        }
+        devices.retain(|_, alarms| !alarms.is_empty());
+        events
+    }
+
+    /// send the events of the devices connected to this instance, speak the rings
+    pub async fn run(&self, pool: &Arc<WsPool>) {
+        let online = pool.connections.read().await.keys().cloned().collect();
+        for (device_id, event) in self.tick(Local::now(), &online) {
+            let ringing = event.state == AlarmState::Ringing;
+            let text = match &event.label {
+                Some(label) => format!("{}：{label}", self.config.ring_text),
+                None => self.config.ring_text.clone(),
+            };
+            if let Err(e) = pool.send(&device_id, WsCommand::Alarm(event)).await {
+                log::warn!("`{device_id}` alarm error: {e}");
+                continue;
+            }
+            if ringing {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let r = super::ws::announce(&pool, &device_id, text, Default::default()).await;
+                    if let Err(e) = r {
+                        log::error!("`{device_id}` ring error: {e}");
+                    }
+                });
+            }
+        }
+    }
+
+    /// advance every `tick_sec`
+    pub fn spawn(self: &Arc<Self>, pool: Arc<WsPool>) {
+        let alarms = self.clone();
+        tokio::spawn(async move {
+            let period = std::time::Duration::from_secs(alarms.config.tick_sec.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                alarms.run(&pool).await;
+            }
+        });
+    }
+
+    /// a turn cancelling timers or alarms, answered without the llm
+    pub async fn cancel_turn(
+        &self,
+        pool: &WsPool,
+        id: &str,
+        text: String,
+        cancelled: Vec<AlarmEvent>,
+    ) -> anyhow::Result<()> {
+        pool.send(id, WsCommand::AsrResult(vec![text.clone()]))
+            .await?;
+        pool.webhooks.transcription_completed(id, &text);
+        pool.sessions.record(id, crate::ai::llm::Role::User, &text);
+        pool.sessions.add_turn(id);
+        pool.sessions.turn_intent(id, "cancel_alarm");
+
+        let reply = if cancelled.is_empty() {
+            self.config.none_text.clone()
+        } else {
+            self.config.cancelled_text.clone()
+        };
+        log::info!("`{id}` cancelled {} timers and alarms", cancelled.len());
+        for event in cancelled {
+            pool.send(id, WsCommand::Alarm(event)).await?;
+        }
+        pool.sessions
+            .record(id, crate::ai::llm::Role::Assistant, &reply);
+        pool.send(id, WsCommand::StartAudio(reply.clone())).await?;
+        let r = super::ws::tts_and_send(pool, id, reply).await;
+        pool.send(id, WsCommand::EndAudio).await?;
+        r
+    }
+}
+
+#[test]
+fn test_alarms() {
+    let config: AlarmsConfig = toml::from_str("ring_sec = 30").unwrap();
+    let alarms = Alarms::new(config);
+    let now = Local::now();
+    let timer = alarms
+        .set("dev1", r#"{"seconds": 60, "label": "煮鸡蛋"}"#, now)
+        .unwrap();
+    assert_eq!((timer.kind, timer.remaining_ms), (AlarmKind::Timer, 60_000));
+    alarms
+        .set("dev1", r#"{"at": "2099-01-01 07:00"}"#, now)
+        .unwrap();
+    assert!(alarms.set("dev1", "{}", now).is_err());
+
+    // away, then back after a reconnect
+    let later = now + chrono::Duration::seconds(90);
+    assert!(alarms.tick(later, &HashSet::new()).is_empty());
+    let online = HashSet::from(["dev1".to_string()]);
+    let events = alarms.tick(later, &online);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].1.state, AlarmState::Ringing);
+    let states = alarms.events("dev1", later);
+    assert_eq!(states.len(), 2);
+
+    assert_eq!(
+        cancel_command("取消闹钟。"),
+        Some(Cancel::Kind(AlarmKind::Alarm))
+    );
+    assert_eq!(cancel_command("别响了"), Some(Cancel::Ringing));
+    assert_eq!(cancel_command("闹钟是怎么发明的"), None);
+    // the ringing timer is stopped, the alarm of tomorrow is kept
+    let cancelled = alarms.cancel("dev1", Cancel::Ringing, later).unwrap();
+    assert_eq!(cancelled[0].id, timer.id);
+    assert!(alarms.cancel("dev1", Cancel::Ringing, later).is_none());
+    let cancelled = alarms
+        .cancel("dev1", Cancel::Kind(AlarmKind::Alarm), later)
+        .unwrap();
+    assert_eq!(cancelled.len(), 1);
+    assert_eq!(
+        alarms.cancel("dev1", Cancel::Kind(AlarmKind::Timer), later),
+        Some(vec![])
+    );
+
+    // rang for `ring_sec`
+    alarms.set("dev2", r#"{"seconds": 1}"#, now).unwrap();
+    let online = HashSet::from(["dev2".to_string()]);
+    alarms.tick(now + chrono::Duration::seconds(2), &online);
+    let events = alarms.tick(now + chrono::Duration::seconds(40), &online);
+    assert_eq!(events[0].1.state, AlarmState::Stopped);
+    assert!(alarms.events("dev2", now).is_empty());
+}
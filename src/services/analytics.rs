@@ -0,0 +1,469 @@
+//! Analytics of the turns, `[analytics]`: the sessions write a [`TurnMetric`] per turn,
+//! a rollup every `interval_sec` reads the metrics of the days before today, turns them
+//! into [`DailyStats`] and deletes the metrics it read, so that a query reads a row per day.
+//! a day rolled twice sums its histograms, so its percentiles stay those of all its turns.
+//!
+//! admin (bearer `admin_token`):
+//! - `GET /v1/stats?from=2025-01-01&to=2025-01-31` the stats of each day, oldest first,
+//!   the days not rolled yet are aggregated on the fly. the usage and the estimated cost of
+//!   the providers, see [`crate::services::costs`], add up per day and per device
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    extract::Query,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
+
+use crate::{
+    config::AnalyticsConfig,
+    storage::{
+        DailyStats, DeviceUsage, Histogram, IntentCount, Percentiles, Storage, Store, TurnMetric,
+        Usage,
+    },
+};
+
+/// intents kept in the stats of a day
+const TOP_INTENTS: usize = 10;
+
+fn percentiles(mut values: Vec<u64>) -> Percentiles {
+    if values.is_empty() {
+        return Percentiles::default();
+    }
+    values.sort_unstable();
+    let at = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+    Percentiles {
+        p50: at(0.5),
+        p90: at(0.9),
+        p99: at(0.99),
+    }
+}
+
+/// `1234` -> `1200`
+fn bucket(value: u64) -> u64 {
+    let mut scale = 1;
+    while value / scale >= 100 {
+        scale *= 10;
+    }
+    value / scale * scale
+}
+
+fn histogram(values: impl IntoIterator<Item = u64>) -> Histogram {
+    let mut histogram = Histogram::default();
+    for value in values {
+        *histogram.0.entry(bucket(value)).or_insert(0) += 1;
+    }
+    histogram
+}
+
+fn add_histogram(a: &Histogram, b: &Histogram) -> Histogram {
+    let mut sum = a.clone();
+    for (value, count) in &b.0 {
+        *sum.0.entry(*value).or_insert(0) += count;
+    }
+    sum
+}
+
+/// the same ranks as [`percentiles`], within a bucket
+fn histogram_percentiles(histogram: &Histogram) -> Percentiles {
+    let n = histogram.0.values().sum::<u64>();
+    if n == 0 {
+        return Percentiles::default();
+    }
+    let at = |p: f64| {
+        let rank = ((n - 1) as f64 * p).round() as u64;
+        let mut seen = 0;
+        for (value, count) in &histogram.0 {
+            seen += count;
+            if seen > rank {
+                return *value;
+            }
+        }
+        0
+    };
+    Percentiles {
+        p50: at(0.5),
+        p90: at(0.9),
+        p99: at(0.99),
+    }
+}
+
+fn top_intents(counts: BTreeMap<String, u64>) -> Vec<IntentCount> {
+    let mut intents = counts
+        .into_iter()
+        .map(|(intent, count)| IntentCount { intent, count })
+        .collect::<Vec<_>>();
+    intents.sort_by(|a, b| b.count.cmp(&a.count).then(a.intent.cmp(&b.intent)));
+    intents.truncate(TOP_INTENTS);
+    intents
+}
+
+/// most expensive first
+fn device_usage(devices: BTreeMap<String, DeviceUsage>) -> Vec<DeviceUsage> {
+    let mut devices = devices.into_values().collect::<Vec<_>>();
+    devices.sort_by(|a, b| {
+        b.usage
+            .cost
+            .total_cmp(&a.usage.cost)
+            .then(a.device_id.cmp(&b.device_id))
+    });
+    devices
+}
+
+fn add_device_usage(
+    devices: &mut BTreeMap<String, DeviceUsage>,
+    device_id: &str,
+    turns: u64,
+    usage: &Usage,
+) {
+    let device = devices
+        .entry(device_id.to_string())
+        .or_insert_with(|| DeviceUsage {
+            device_id: device_id.to_string(),
+            turns: 0,
+            usage: Usage::default(),
+        });
+    device.turns += turns;
+    device.usage.add(usage);
+}
+
+/// `2025-01-31` in local time
+fn day_of(time: &str) -> Option<String> {
+    let time = chrono::DateTime::parse_from_rfc3339(time).ok()?;
+    Some(
+        time.with_timezone(&chrono::Local)
+            .format("%Y-%m-%d")
+            .to_string(),
+    )
+}
+
+/// the metrics of each day
+fn by_day(metrics: Vec<TurnMetric>) -> BTreeMap<String, Vec<TurnMetric>> {
+    let mut days: BTreeMap<String, Vec<TurnMetric>> = BTreeMap::new();
+    for metric in metrics {
+        if let Some(day) = day_of(&metric.time) {
+            days.entry(day).or_default().push(metric);
+        }
+    }
+    days
+}
+
+pub fn aggregate(day: &str, metrics: &[TurnMetric]) -> DailyStats {
+    let turns = metrics.len() as u64;
+    let errors = metrics.iter().filter(|m| m.error).count() as u64;
+    let mut intents = BTreeMap::new();
+    for intent in metrics.iter().filter_map(|m| m.intent.as_ref()) {
+        *intents.entry(intent.clone()).or_insert(0) += 1;
+    }
+    let mut usage = Usage::default();
+    let mut devices = BTreeMap::new();
+    for m in metrics {
+        usage.add(&m.usage);
+        if let Some(device_id) = &m.device_id {
+            add_device_usage(&mut devices, device_id, 1, &m.usage);
+        }
+    }
+    DailyStats {
+        day: day.to_string(),
+        turns,
+        errors,
+        error_rate: if turns == 0 {
+            0.0
+        } else {
+            errors as f64 / turns as f64
+        },
+        first_audio_ms: percentiles(metrics.iter().filter_map(|m| m.first_audio_ms).collect()),
+        response_ms: percentiles(metrics.iter().map(|m| m.response_ms).collect()),
+        first_audio_histogram: histogram(metrics.iter().filter_map(|m| m.first_audio_ms)),
+        response_histogram: histogram(metrics.iter().map(|m| m.response_ms)),
+        intents: top_intents(intents),
+        usage,
+        devices: device_usage(devices),
+    }
+}
+
+/// the stats of a day rolled twice, the percentiles come from the sum of the histograms,
+/// or are weighted by the turns when a side was rolled before the histograms
+pub fn merge(a: &DailyStats, b: &DailyStats) -> DailyStats {
+    let turns = a.turns + b.turns;
+    let errors = a.errors + b.errors;
+    let weighted = |x: u64, y: u64| (x * a.turns + y * b.turns).checked_div(turns).unwrap_or(0);
+    let mix = |x: &Percentiles, y: &Percentiles| Percentiles {
+        p50: weighted(x.p50, y.p50),
+        p90: weighted(x.p90, y.p90),
+        p99: weighted(x.p99, y.p99),
+    };
+    let legacy = |s: &DailyStats| s.turns > 0 && s.response_histogram.0.is_empty();
+    let first_audio_histogram = add_histogram(&a.first_audio_histogram, &b.first_audio_histogram);
+    let response_histogram = add_histogram(&a.response_histogram, &b.response_histogram);
+    let (first_audio_ms, response_ms) = if legacy(a) || legacy(b) {
+        (
+            mix(&a.first_audio_ms, &b.first_audio_ms),
+            mix(&a.response_ms, &b.response_ms),
+        )
+    } else {
+        (
+            histogram_percentiles(&first_audio_histogram),
+            histogram_percentiles(&response_histogram),
+        )
+    };
+    let mut intents = BTreeMap::new();
+    for i in a.intents.iter().chain(&b.intents) {
+        *intents.entry(i.intent.clone()).or_insert(0) += i.count;
+    }
+    let mut usage = a.usage.clone();
+    usage.add(&b.usage);
+    let mut devices = BTreeMap::new();
+    for d in a.devices.iter().chain(&b.devices) {
+        add_device_usage(&mut devices, &d.device_id, d.turns, &d.usage);
+    }
+    DailyStats {
+        day: a.day.clone(),
+        turns,
+        errors,
+        error_rate: if turns == 0 {
+            0.0
+        } else {
+            errors as f64 / turns as f64
+        },
+        first_audio_ms,
+        response_ms,
+        first_audio_histogram,
+        response_histogram,
+        intents: top_intents(intents),
+        usage,
+        devices: device_usage(devices),
+    }
+}
+
+#[derive(Debug)]
+pub struct Analytics {
+    config: AnalyticsConfig,
+    admin_token: Option<String>,
+    store: Arc<Storage>,
+}
+
+impl Analytics {
+    pub fn new(config: AnalyticsConfig, admin_token: Option<String>, store: Arc<Storage>) -> Self {
+        Self {
+            config,
+            admin_token,
+            store,
+        }
+    }
+
+    /// written in the background
+    pub fn record(&self, metric: TurnMetric) {
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = store.append_turn_metric(&metric).await {
+                log::error!("analytics write error: {e}");
+            }
+        });
+    }
+
+    /// roll the days before the day of `now`, the number of days rolled. reads only the
+    /// metrics before midnight and deletes only those, a turn written meanwhile stays raw
+    pub async fn rollup(&self, now: chrono::DateTime<chrono::Local>) -> anyhow::Result<usize> {
+        let today = now.format("%Y-%m-%d").to_string();
+        let Some(midnight) = now
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .and_then(|t| t.and_local_timezone(chrono::Local).earliest())
+        else {
+            anyhow::bail!("no local midnight on {today}");
+        };
+
+        let mut rolled = self
+            .store
+            .daily_stats()
+            .await?
+            .into_iter()
+            .map(|s| (s.day.clone(), s))
+            .collect::<BTreeMap<_, _>>();
+        let metrics = self
+            .store
+            .turn_metrics(Some(&midnight.with_timezone(&chrono::Utc)))
+            .await?;
+        let ids = metrics.iter().map(|m| m.id.clone()).collect::<Vec<_>>();
+        let mut days = 0;
+        for (day, metrics) in by_day(metrics) {
+            let mut stats = aggregate(&day, &metrics);
+            if let Some(old) = rolled.remove(&day) {
+                stats = merge(&old, &stats);
+            }
+            self.store.put_daily_stats(&stats).await?;
+            days += 1;
+        }
+        self.store.delete_turn_metrics(&ids).await?;
+        Ok(days)
+    }
+
+    /// every `interval_sec`, the first time right away
+    pub fn spawn(self: &Arc<Self>) {
+        let analytics = self.clone();
+        tokio::spawn(async move {
+            let period = std::time::Duration::from_secs(analytics.config.interval_sec.max(60));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match analytics.rollup(chrono::Local::now()).await {
+                    Ok(days) => log::info!("analytics: {days} days rolled"),
+                    Err(e) => log::error!("analytics rollup error: {e}"),
+                }
+            }
+        });
+    }
+
+    /// the days between `from` and `to` included, rolled or not
+    pub async fn stats(
+        &self,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> anyhow::Result<Vec<DailyStats>> {
+        let in_range =
+            |day: &str| from.is_none_or(|from| day >= from) && to.is_none_or(|to| day <= to);
+        let mut days = self
+            .store
+            .daily_stats()
+            .await?
+            .into_iter()
+            .filter(|s| in_range(&s.day))
+            .map(|s| (s.day.clone(), s))
+            .collect::<BTreeMap<_, _>>();
+        for (day, metrics) in by_day(self.store.turn_metrics(None).await?) {
+            if !in_range(&day) {
+                continue;
+            }
+            let stats = aggregate(&day, &metrics);
+            let stats = match days.get(&day) {
+                Some(rolled) => merge(rolled, &stats),
+                None => stats,
+            };
+            days.insert(day, stats);
+        }
+        Ok(days.into_values().collect())
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StatsParams {
+    #[serde(default)]
+    from: Option<String>,
+    #[serde(default)]
+    to: Option<String>,
+}
+
+/// GET /v1/stats
+async fn get_stats(
+    Extension(analytics): Extension<Arc<Analytics>>,
+    headers: HeaderMap,
+    Query(params): Query<StatsParams>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &analytics.admin_token) {
+        return code.into_response();
+    }
+    match analytics
+        .stats(params.from.as_deref(), params.to.as_deref())
+        .await
+    {
+        Ok(days) => Json(days).into_response(),
+        Err(e) => {
+            log::error!("analytics read error: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+pub fn new_analytics_service(analytics: Arc<Analytics>) -> Router {
+    Router::new()
+        .route("/v1/stats", get(get_stats))
+        .layer(Extension(analytics))
+}
+
+#[tokio::test]
+async fn test_rollup() {
+    use crate::storage::file::FileStore;
+
+    let dir = std::env::temp_dir().join(format!("echokit_analytics_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let store = Arc::new(Storage::File(FileStore::new(&dir.to_string_lossy(), None)));
+    let now = chrono::Local::now();
+    let metric = |days: i64, response_ms: u64, error: bool, intent: Option<&str>| TurnMetric {
+        id: String::new(),
+        session_id: "dev1".to_string(),
+        device_id: Some("dev1".to_string()),
+        kind: "ws".to_string(),
+        time: (now - chrono::Duration::days(days)).to_rfc3339(),
+        first_audio_ms: Some(response_ms / 2),
+        response_ms,
+        error,
+        intent: intent.map(str::to_string),
+        usage: Usage {
+            tts_chars: 100,
+            cost: 0.5,
+            ..Default::default()
+        },
+    };
+    for m in [
+        metric(2, 1000, false, Some("get_weather")),
+        metric(2, 3000, true, None),
+        metric(2, 2000, false, Some("get_weather")),
+        metric(0, 500, false, None),
+    ] {
+        store.append_turn_metric(&m).await.unwrap();
+    }
+
+    let config = AnalyticsConfig { interval_sec: 3600 };
+    let analytics = Analytics::new(config, None, store.clone());
+    assert_eq!(analytics.rollup(now).await.unwrap(), 1);
+    // today stays raw
+    assert_eq!(store.turn_metrics(None).await.unwrap().len(), 1);
+
+    let days = analytics.stats(None, None).await.unwrap();
+    let day = &days[0];
+    assert_eq!((day.turns, day.errors), (3, 1));
+    assert_eq!(day.response_ms.p50, 2000);
+    assert_eq!(day.response_ms.p99, 3000);
+    assert_eq!(
+        day.intents,
+        [IntentCount {
+            intent: "get_weather".to_string(),
+            count: 2
+        }]
+    );
+    assert_eq!(day.usage.tts_chars, 300);
+    assert_eq!(day.devices[0].device_id, "dev1");
+    assert_eq!(day.devices[0].turns, 3);
+    assert_eq!(merge(day, day).devices[0].usage.cost, 3.0);
+    // rolled again, the percentiles are those of all the turns of the day
+    let again = [metric(2, 9000, false, None), metric(2, 9000, false, None)];
+    let twice = merge(day, &aggregate(&day.day, &again));
+    assert_eq!(twice.response_ms.p50, 3000);
+    assert_eq!(twice.response_ms.p99, 9000);
+    assert_eq!(days.len(), 2);
+    assert_eq!(days[1].turns, 1);
+    let today = now.format("%Y-%m-%d").to_string();
+    assert_eq!(analytics.stats(Some(&today), None).await.unwrap().len(), 1);
+
+    // only the metrics read are deleted, a turn written meanwhile stays
+    let read = store
+        .turn_metrics(None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|m| m.id)
+        .collect::<Vec<_>>();
+    store
+        .append_turn_metric(&metric(1, 4000, false, None))
+        .await
+        .unwrap();
+    assert_eq!(store.delete_turn_metrics(&read).await.unwrap(), 1);
+    let left = store.turn_metrics(None).await.unwrap();
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].response_ms, 4000);
+    let _ = std::fs::remove_dir_all(dir);
+}
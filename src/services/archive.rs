@@ -0,0 +1,267 @@
+//! Export, import and deletion of the stored data, the format is [`crate::storage::archive`].
+//!
+//! admin (bearer `admin_token`):
+//! - `GET /v1/export?device=...` the archive of everything, or of one device
+//! - `POST /v1/import` an archive of up to 256 MB as the body, returns what was imported
+//! - `DELETE /v1/devices/{id}/data` the transcripts, annotations, recordings, memories,
+//!   reminders and telemetry of a device, returns what was deleted. the device stays paired,
+//!   while it is connected the deletion is refused with a 409
+//!
+//! the same without a running server:
+//! - `echokit_server export <archive.tar> [config.toml] [device_id]`
+//! - `echokit_server import <archive.tar> [config.toml]`
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+
+use crate::{
+    services::{knowledge::Knowledge, registry::DeviceRegistry, sessions::SessionManager},
+    storage::{archive::Archive, recordings::Recordings, Storage, Store},
+};
+
+/// of an uploaded archive, spooled to a temporary file
+const MAX_IMPORT_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct ArchiveService {
+    pub admin_token: Option<String>,
+    pub store: Arc<Storage>,
+    pub recordings: Arc<Recordings>,
+    pub registry: Option<Arc<DeviceRegistry>>,
+    pub knowledge: Option<Arc<Knowledge>>,
+    pub sessions: Arc<SessionManager>,
+}
+
+impl ArchiveService {
+    pub async fn import(
+        &self,
+        path: &std::path::Path,
+    ) -> anyhow::Result<crate::storage::archive::ImportReport> {
+        let file = std::fs::File::open(path)?;
+        let archive = tokio::task::spawn_blocking(move || Archive::from_tar(file)).await??;
+        let report = archive
+            .import(&self.store, &self.recordings, self.registry.as_deref())
+            .await?;
+        if let Some(knowledge) = &self.knowledge {
+            knowledge.index_memories(&archive.memories).await?;
+        }
+        Ok(report)
+    }
+
+    /// the index first, it finds the memories through the storage
+    pub async fn delete_device_data(&self, device_id: &str) -> anyhow::Result<DeletionReport> {
+        let mut report = DeletionReport {
+            device_id: device_id.to_string(),
+            ..Default::default()
+        };
+        if let Some(knowledge) = &self.knowledge {
+            report.indexed_memories = knowledge.unindex_device(device_id).await?;
+        }
+        let deleted = self.store.delete_device_data(device_id).await?;
+        report.sessions = deleted.sessions;
+        report.turns = deleted.turns;
+        report.memories = deleted.memories;
+        report.annotations = deleted.annotations;
+        report.reminders = deleted.reminders;
+        report.metrics = deleted.metrics;
+        report.recordings = self.recordings.delete_device(device_id).await?;
+        if let Some(registry) = &self.registry {
+            report.telemetry = registry.erase_telemetry(device_id).await;
+        }
+        Ok(report)
+    }
+}
+
+/// what `DELETE /v1/devices/{id}/data` deleted
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct DeletionReport {
+    pub device_id: String,
+    pub sessions: u64,
+    pub turns: u64,
+    pub memories: u64,
+    pub annotations: u64,
+    pub reminders: u64,
+    pub metrics: u64,
+    /// of the vector index
+    pub indexed_memories: usize,
+    pub recordings: usize,
+    pub telemetry: usize,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    device: Option<String>,
+}
+
+/// GET /v1/export
+async fn export(
+    Extension(service): Extension<Arc<ArchiveService>>,
+    headers: HeaderMap,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &service.admin_token) {
+        return code.into_response();
+    }
+    let archive = match Archive::collect(
+        &service.store,
+        &service.recordings,
+        params.device.as_deref(),
+    )
+    .await
+    {
+        Ok(archive) => archive,
+        Err(e) => {
+            log::error!("export error: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    match archive.to_tar() {
+        Ok(tar) => {
+            let name = format!(
+                "attachment; filename=\"echokit-{}.tar\"",
+                chrono::Local::now().format("%Y%m%d-%H%M%S")
+            );
+            (
+                [
+                    (header::CONTENT_TYPE, "application/x-tar".to_string()),
+                    (header::CONTENT_DISPOSITION, name),
+                ],
+                tar,
+            )
+                .into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// write the body to `path`, false once it is over `MAX_IMPORT_SIZE`
+async fn spool(body: Body, path: &std::path::Path) -> anyhow::Result<bool> {
+    use futures_util::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut stream = body.into_data_stream();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        if size > MAX_IMPORT_SIZE {
+            return Ok(false);
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(true)
+}
+
+/// POST /v1/import
+async fn import(
+    Extension(service): Extension<Arc<ArchiveService>>,
+    headers: HeaderMap,
+    body: Body,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &service.admin_token) {
+        return code.into_response();
+    }
+    let path = std::env::temp_dir().join(format!("echokit_import_{}.tar", uuid::Uuid::new_v4()));
+    let r = match spool(body, &path).await {
+        Ok(true) => service.import(&path).await,
+        Ok(false) => {
+            let _ = tokio::fs::remove_file(&path).await;
+            return (StatusCode::PAYLOAD_TOO_LARGE, "the archive is too large").into_response();
+        }
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_file(&path).await;
+    match r {
+        Ok(report) => {
+            log::info!("import: {report:?}");
+            Json(report).into_response()
+        }
+        Err(e) => {
+            log::error!("import error: {e}");
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+    }
+}
+
+/// DELETE /v1/devices/{id}/data
+async fn delete_data(
+    Extension(service): Extension<Arc<ArchiveService>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &service.admin_token) {
+        return code.into_response();
+    }
+    // a live session would write again what is deleted
+    if service.sessions.is_connected(&id).await {
+        return (StatusCode::CONFLICT, "the device is connected").into_response();
+    }
+    match service.delete_device_data(&id).await {
+        Ok(report) => {
+            log::info!("`{id}` data deleted: {report:?}");
+            Json(report).into_response()
+        }
+        Err(e) => {
+            log::error!("`{id}` data deletion error: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+pub fn new_archive_service(service: Arc<ArchiveService>) -> Router {
+    Router::new()
+        .route("/v1/export", get(export))
+        .route("/v1/import", post(import))
+        .route("/v1/devices/{id}/data", delete(delete_data))
+        .layer(Extension(service))
+}
+
+/// `export <archive.tar> [config.toml] [device_id]` or `import <archive.tar> [config.toml]`
+pub async fn run_command(command: &str, args: &[String]) -> anyhow::Result<()> {
+    let Some(path) = args.first() else {
+        anyhow::bail!("usage: echokit_server {command} <archive.tar> [config.toml]");
+    };
+    let config_path = args.get(1).map(String::as_str).unwrap_or("config.toml");
+    let config = crate::config::Config::load(config_path)?;
+    let store = Arc::new(
+        Storage::open(
+            &config.storage,
+            config.registry.as_ref().map(|r| r.path.as_str()),
+        )
+        .await?,
+    );
+    let recordings = Recordings::new(&config.recordings)?;
+
+    match command {
+        "export" => {
+            let archive =
+                Archive::collect(&store, &recordings, args.get(2).map(String::as_str)).await?;
+            tokio::fs::write(path, archive.to_tar()?).await?;
+            println!("{}", serde_json::to_string_pretty(&archive.manifest)?);
+        }
+        _ => {
+            let file = std::fs::File::open(path)?;
+            let archive = tokio::task::spawn_blocking(move || Archive::from_tar(file)).await??;
+            let report = archive.import(&store, &recordings, None).await?;
+            if let (Some(vectors), Some(memory)) = (&config.vectors, &config.memory) {
+                let knowledge =
+                    Knowledge::new(vectors, Some(memory.clone()), None, store.clone(), None)
+                        .await?;
+                knowledge.index_memories(&archive.memories).await?;
+            }
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+    Ok(())
+}
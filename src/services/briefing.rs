@@ -0,0 +1,661 @@
+//! The daily briefing, `[briefing]`: a spoken summary of the day from the weather, a
+//! calendar and news feeds.
+//!
+//! ```toml
+//! [briefing]
+//! schedule = "30 7 * * *"
+//! devices = ["kitchen"]
+//!
+//! [briefing.weather]
+//! latitude = 31.23
+//! longitude = 121.47
+//! location = "上海"
+//!
+//! [briefing.calendar]
+//! url = "https://calendar.example.com/family.ics"
+//!
+//! [[briefing.news]]
+//! name = "科技"
+//! url = "https://news.example.com/tech.rss"
+//! ```
+//!
+//! - the weather is the forecast of the day of an open-meteo api
+//! - the calendar is an ics feed, its events of the day. the recurring ones are expanded:
+//!   a daily, weekly, monthly or yearly `RRULE` with `INTERVAL`, `COUNT`, `UNTIL` and the
+//!   weekdays of `BYDAY`, less its `EXDATE`. another rule gives its first occurrence only,
+//!   and a time with a `TZID` is read as the local time of the server
+//! - the news are the first titles of each rss or atom feed
+//!
+//! a source that fails is left out. the briefing is spoken on `/ws/{id}` when the device
+//! hears one of the `keywords`, at each time of `schedule` on the `devices` connected (all
+//! of them if empty), or on a request: admin (bearer `admin_token`):
+//! - `GET /v1/briefing` the briefing as of now, as text
+//! - `POST /v1/devices/{id}/briefing` speak it on the device
+
+use std::sync::Arc;
+
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Router,
+};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use quick_xml::events::Event;
+
+use crate::{
+    config::{BriefingConfig, WeatherConfig},
+    services::{
+        reminders::Cron,
+        ws::{WsCommand, WsPool},
+    },
+};
+
+/// what the asr may put around a keyword
+const ASR_PUNCTUATION: &[char] = &['，', '。', '？', '！', ',', '.', '?', '!', ' '];
+
+#[derive(Debug)]
+pub struct Briefing {
+    config: BriefingConfig,
+    client: reqwest::Client,
+    schedule: Option<Cron>,
+}
+
+/// the wmo weather code of open-meteo
+fn weather_name(code: u64) -> &'static str {
+    match code {
+        0 => "晴",
+        1..=3 => "多云",
+        45 | 48 => "有雾",
+        51..=57 => "有毛毛雨",
+        61..=67 => "有雨",
+        71..=77 => "有雪",
+        80..=82 => "有阵雨",
+        85 | 86 => "有阵雪",
+        95..=99 => "有雷雨",
+        _ => "天气多变",
+    }
+}
+
+/// the forecast of the day of an open-meteo response
+fn weather_text(config: &WeatherConfig, forecast: &serde_json::Value) -> Option<String> {
+    let daily = &forecast["daily"];
+    let first = |key: &str| daily[key].get(0).and_then(|v| v.as_f64());
+    let code = first("weather_code")? as u64;
+    let (min, max) = (first("temperature_2m_min")?, first("temperature_2m_max")?);
+    let mut text = format!(
+        "{}今天{}，{:.0}到{:.0}度",
+        config.location.as_deref().unwrap_or_default(),
+        weather_name(code),
+        min,
+        max
+    );
+    if let Some(rain) = first("precipitation_probability_max").filter(|p| *p > 0.0) {
+        text += &format!("，降水概率{rain:.0}%");
+    }
+    Some(text)
+}
+
+/// the lines of an ics feed, the folded ones joined
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in ics.lines() {
+        let line = line.trim_end_matches('\r');
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// the day and local start of a `DTSTART`, no start for an all-day event
+fn event_start(params: &str, value: &str) -> Option<(NaiveDate, Option<DateTime<Local>>)> {
+    if params.contains("VALUE=DATE") || value.len() == 8 {
+        return Some((NaiveDate::parse_from_str(value, "%Y%m%d").ok()?, None));
+    }
+    let time = NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S").ok()?;
+    let start = if value.ends_with('Z') {
+        Utc.from_utc_datetime(&time).with_timezone(&Local)
+    } else {
+        // a `TZID` is taken as the local time
+        Local.from_local_datetime(&time).earliest()?
+    };
+    Some((start.date_naive(), Some(start)))
+}
+
+/// the day of a `20250131` or `20250131T090000Z`
+fn ics_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value.get(..8)?, "%Y%m%d").ok()
+}
+
+/// `MO`, the ordinal ones of a monthly `BYDAY` like `1MO` are not
+fn ics_weekday(day: &str) -> Option<Weekday> {
+    Some(match day {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Freq {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// the `RRULE` of an event, the weekdays of `BYDAY` for the daily and weekly ones only
+#[derive(Debug, Clone, PartialEq)]
+struct Rrule {
+    freq: Freq,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<NaiveDate>,
+    by_day: Vec<Weekday>,
+}
+
+impl Rrule {
+    /// none for a rule it can't expand, the event is then its first occurrence only
+    fn parse(value: &str) -> Option<Rrule> {
+        let mut rule = Rrule {
+            freq: Freq::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+        };
+        let mut freq = None;
+        for part in value.split(';') {
+            let (key, value) = part.split_once('=')?;
+            match key {
+                "FREQ" => {
+                    freq = Some(match value {
+                        "DAILY" => Freq::Daily,
+                        "WEEKLY" => Freq::Weekly,
+                        "MONTHLY" => Freq::Monthly,
+                        "YEARLY" => Freq::Yearly,
+                        _ => return None,
+                    })
+                }
+                "INTERVAL" => rule.interval = value.parse().ok().filter(|i| *i > 0)?,
+                "COUNT" => rule.count = Some(value.parse().ok()?),
+                "UNTIL" => rule.until = Some(ics_date(value)?),
+                "BYDAY" => {
+                    rule.by_day = value.split(',').map(ics_weekday).collect::<Option<_>>()?
+                }
+                "WKST" => {}
+                _ => return None,
+            }
+        }
+        rule.freq = freq?;
+        if !rule.by_day.is_empty() && !matches!(rule.freq, Freq::Daily | Freq::Weekly) {
+            return None;
+        }
+        Some(rule)
+    }
+
+    /// `day` is a candidate of the rule of an event starting on `start`
+    fn matches(&self, start: NaiveDate, day: NaiveDate) -> bool {
+        let interval = self.interval as i64;
+        let by_day = self.by_day.is_empty() || self.by_day.contains(&day.weekday());
+        match self.freq {
+            Freq::Daily => (day - start).num_days() % interval == 0 && by_day,
+            Freq::Weekly => {
+                let monday =
+                    |d: NaiveDate| d - chrono::Days::new(d.weekday().num_days_from_monday() as u64);
+                let weekday = if self.by_day.is_empty() {
+                    day.weekday() == start.weekday()
+                } else {
+                    by_day
+                };
+                (monday(day) - monday(start)).num_days() / 7 % interval == 0 && weekday
+            }
+            Freq::Monthly => {
+                let months = (day.year() - start.year()) as i64 * 12 + day.month() as i64
+                    - start.month() as i64;
+                months % interval == 0 && day.day() == start.day()
+            }
+            Freq::Yearly => {
+                (day.year() - start.year()) as i64 % interval == 0
+                    && (day.month(), day.day()) == (start.month(), start.day())
+            }
+        }
+    }
+
+    /// `day` is an occurrence of the rule of an event starting on `start`
+    fn occurs_on(&self, start: NaiveDate, day: NaiveDate) -> bool {
+        let mut n = 0;
+        for candidate in start.iter_days() {
+            if candidate > day || self.until.is_some_and(|until| candidate > until) {
+                return false;
+            }
+            if !self.matches(start, candidate) {
+                continue;
+            }
+            n += 1;
+            if self.count.is_some_and(|count| n > count) {
+                return false;
+            }
+            if candidate == day {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// the event being read of an ics feed
+#[derive(Debug, Default)]
+struct IcsEvent {
+    start: Option<(NaiveDate, Option<DateTime<Local>>)>,
+    summary: String,
+    rrule: Option<Rrule>,
+    exdates: Vec<NaiveDate>,
+}
+
+impl IcsEvent {
+    /// the local start of the event on `day`, `Some(None)` for an all-day event
+    fn on(&self, day: NaiveDate) -> Option<Option<DateTime<Local>>> {
+        let (date, start) = self.start?;
+        let occurs = match &self.rrule {
+            Some(rule) => rule.occurs_on(date, day),
+            None => date == day,
+        };
+        if !occurs || self.exdates.contains(&day) {
+            return None;
+        }
+        match start {
+            Some(start) => Some(Some(
+                Local
+                    .from_local_datetime(&day.and_time(start.time()))
+                    .earliest()?,
+            )),
+            None => Some(None),
+        }
+    }
+}
+
+/// the events of `day` in an ics feed, `(start, summary)` ordered, the all-day ones first
+fn calendar_events(ics: &str, day: NaiveDate) -> Vec<(Option<DateTime<Local>>, String)> {
+    let mut events = vec![];
+    let mut current: Option<IcsEvent> = None;
+    for line in unfold(ics) {
+        if line == "BEGIN:VEVENT" {
+            current = Some(IcsEvent::default());
+            continue;
+        }
+        let Some(event) = current.as_mut() else {
+            continue;
+        };
+        if line == "END:VEVENT" {
+            if let Some(start) = event.on(day).filter(|_| !event.summary.is_empty()) {
+                events.push((start, std::mem::take(&mut event.summary)));
+            }
+            current = None;
+            continue;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, params) = name.split_once(';').unwrap_or((name, ""));
+        match name {
+            "DTSTART" => event.start = event_start(params, value),
+            "SUMMARY" => event.summary = value.replace("\\,", ",").replace("\\n", " "),
+            "RRULE" => event.rrule = Rrule::parse(value),
+            "EXDATE" => event.exdates.extend(value.split(',').filter_map(ics_date)),
+            _ => {}
+        }
+    }
+    events.sort_by_key(|(time, _)| *time);
+    events
+}
+
+fn calendar_text(events: &[(Option<DateTime<Local>>, String)]) -> String {
+    if events.is_empty() {
+        return "今天没有日程".to_string();
+    }
+    let items = events
+        .iter()
+        .map(|(time, summary)| match time {
+            Some(time) => format!("{} {summary}", time.format("%H:%M")),
+            None => summary.clone(),
+        })
+        .collect::<Vec<_>>();
+    format!("今天有{}个日程：{}", events.len(), items.join("，"))
+}
+
+/// the first `max` titles of an rss or atom feed, the title of each `item` or `entry`
+fn feed_titles(feed: &str, max: usize) -> Vec<String> {
+    let mut reader = quick_xml::Reader::from_str(feed);
+    let mut titles = vec![];
+    let mut in_item = false;
+    // the title of the item being read
+    let mut title: Option<String> = None;
+    while titles.len() < max {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"item" | b"entry" => in_item = true,
+                b"title" if in_item => title = Some(String::new()),
+                _ => {}
+            },
+            Ok(Event::Text(text)) => {
+                if let Some(title) = title.as_mut() {
+                    match text.unescape() {
+                        Ok(text) => title.push_str(&text),
+                        Err(_) => title.push_str(&String::from_utf8_lossy(&text)),
+                    }
+                }
+            }
+            Ok(Event::CData(text)) => {
+                if let Some(title) = title.as_mut() {
+                    title.push_str(&String::from_utf8_lossy(&text));
+                }
+            }
+            Ok(Event::End(e)) => match e.name().as_ref() {
+                b"title" => {
+                    if let Some(title) = title.take() {
+                        // one title per item
+                        in_item = false;
+                        if !title.trim().is_empty() {
+                            titles.push(title.trim().to_string());
+                        }
+                    }
+                }
+                b"item" | b"entry" => in_item = false,
+                _ => {}
+            },
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                log::warn!("briefing feed error: {e}");
+                break;
+            }
+            _ => {}
+        }
+    }
+    titles
+}
+
+impl Briefing {
+    pub fn new(config: BriefingConfig) -> Self {
+        let schedule = config.schedule.as_deref().and_then(|s| {
+            s.parse()
+                .inspect_err(|e| log::warn!("briefing schedule `{s}` ignored: {e}"))
+                .ok()
+        });
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            schedule,
+        }
+    }
+
+    /// the utterance asks for the briefing
+    pub fn is_trigger(&self, utterance: &str) -> bool {
+        let text = utterance.trim_matches(ASR_PUNCTUATION);
+        !text.is_empty()
+            && self
+                .config
+                .keywords
+                .iter()
+                .any(|k| text.contains(k.as_str()))
+    }
+
+    async fn weather(&self, config: &WeatherConfig) -> anyhow::Result<String> {
+        let forecast: serde_json::Value = self
+            .client
+            .get(&config.url)
+            .query(&[
+                ("latitude", config.latitude.to_string()),
+                ("longitude", config.longitude.to_string()),
+                (
+                    "daily",
+                    "weather_code,temperature_2m_max,temperature_2m_min,\
+                     precipitation_probability_max"
+                        .to_string(),
+                ),
+                ("timezone", "auto".to_string()),
+                ("forecast_days", "1".to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        weather_text(config, &forecast).ok_or_else(|| anyhow::anyhow!("no daily forecast"))
+    }
+
+    async fn fetch(&self, url: &str) -> anyhow::Result<String> {
+        Ok(self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    /// the briefing as of `now`, the sources fetched together
+    pub async fn compose(&self, now: DateTime<Local>) -> String {
+        let weather = async {
+            let config = self.config.weather.as_ref()?;
+            self.weather(config)
+                .await
+                .inspect_err(|e| log::warn!("briefing weather error: {e}"))
+                .ok()
+        };
+        let calendar = async {
+            let config = self.config.calendar.as_ref()?;
+            let ics = self
+                .fetch(&config.url)
+                .await
+                .inspect_err(|e| log::warn!("briefing calendar error: {e}"))
+                .ok()?;
+            Some(calendar_text(&calendar_events(&ics, now.date_naive())))
+        };
+        let news = futures_util::future::join_all(self.config.news.iter().map(|source| async {
+            let feed = self
+                .fetch(&source.url)
+                .await
+                .inspect_err(|e| log::warn!("briefing news {} error: {e}", source.name))
+                .ok()?;
+            let titles = feed_titles(&feed, self.config.news_items);
+            (!titles.is_empty()).then(|| format!("{}新闻：{}", source.name, titles.join("；")))
+        }));
+        let (weather, calendar, news) = tokio::join!(weather, calendar, news);
+
+        let greeting = self
+            .config
+            .greeting
+            .replace("{date}", &format!("{}月{}日", now.month(), now.day()))
+            .replace("{weekday}", super::intents::weekday(now.weekday()));
+        let mut parts = vec![greeting];
+        parts.extend(weather);
+        parts.extend(calendar);
+        parts.extend(news.into_iter().flatten());
+        parts
+            .iter()
+            .map(|p| p.trim_end_matches(['。', '.']))
+            .collect::<Vec<_>>()
+            .join("。")
+            + "。"
+    }
+
+    /// a turn asking for the briefing, answered without the llm
+    pub async fn turn(&self, pool: &WsPool, id: &str, text: String) -> anyhow::Result<()> {
+        pool.send(id, WsCommand::AsrResult(vec![text.clone()]))
+            .await?;
+        pool.webhooks.transcription_completed(id, &text);
+        pool.sessions.record(id, crate::ai::llm::Role::User, &text);
+        pool.sessions.add_turn(id);
+        pool.sessions.turn_intent(id, "briefing");
+
+        let briefing = self.compose(Local::now()).await;
+        pool.sessions
+            .record(id, crate::ai::llm::Role::Assistant, &briefing);
+        pool.send(id, WsCommand::StartAudio(briefing.clone()))
+            .await?;
+        let r = super::ws::tts_and_send(pool, id, briefing).await;
+        pool.send(id, WsCommand::EndAudio).await?;
+        r
+    }
+
+    /// speak the briefing on the devices of the config connected here, all of them if none
+    async fn deliver(&self, pool: &Arc<WsPool>) {
+        let online = pool
+            .connections
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        let devices = online
+            .into_iter()
+            .filter(|id| self.config.devices.is_empty() || self.config.devices.contains(id))
+            .collect::<Vec<_>>();
+        if devices.is_empty() {
+            return;
+        }
+        let briefing = self.compose(Local::now()).await;
+        for id in devices {
+            let pool = pool.clone();
+            let briefing = briefing.clone();
+            tokio::spawn(async move {
+                if let Err(e) = super::ws::announce(&pool, &id, briefing, Default::default()).await
+                {
+                    log::error!("`{id}` briefing error: {e}");
+                }
+            });
+        }
+    }
+
+    /// speak the briefing at each time of `schedule`
+    pub fn spawn(self: &Arc<Self>, pool: Arc<WsPool>) {
+        let Some(schedule) = self.schedule.clone() else {
+            return;
+        };
+        let briefing = self.clone();
+        tokio::spawn(async move {
+            while let Some(next) = schedule.next_after(Local::now()) {
+                let wait = (next - Local::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+                briefing.deliver(&pool).await;
+            }
+        });
+    }
+}
+
+/// GET /v1/briefing
+async fn get_briefing(
+    Extension(pool): Extension<Arc<WsPool>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &pool.admin_token) {
+        return code.into_response();
+    }
+    let Some(briefing) = &pool.briefing else {
+        return (StatusCode::NOT_FOUND, "no [briefing]").into_response();
+    };
+    briefing.compose(Local::now()).await.into_response()
+}
+
+/// POST /v1/devices/{id}/briefing
+async fn post_briefing(
+    Extension(pool): Extension<Arc<WsPool>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &pool.admin_token) {
+        return code.into_response();
+    }
+    let Some(briefing) = pool.briefing.clone() else {
+        return (StatusCode::NOT_FOUND, "no [briefing]").into_response();
+    };
+    if !pool.is_online(&id).await {
+        return (StatusCode::NOT_FOUND, "device not connected").into_response();
+    }
+    tokio::spawn(async move {
+        let text = briefing.compose(Local::now()).await;
+        if let Err(e) = super::ws::announce(&pool, &id, text, Default::default()).await {
+            log::error!("`{id}` briefing error: {e}");
+        }
+    });
+    StatusCode::ACCEPTED.into_response()
+}
+
+pub fn new_briefing_service(pool: Arc<WsPool>) -> Router {
+    Router::new()
+        .route("/v1/briefing", get(get_briefing))
+        .route("/v1/devices/{id}/briefing", post(post_briefing))
+        .layer(Extension(pool))
+}
+
+#[test]
+fn test_briefing() {
+    let weather: WeatherConfig = toml::from_str(
+        r#"
+        latitude = 31.23
+        longitude = 121.47
+        location = "上海"
+        "#,
+    )
+    .unwrap();
+    let forecast = serde_json::json!({"daily": {
+        "weather_code": [61],
+        "temperature_2m_max": [12.4],
+        "temperature_2m_min": [6.6],
+        "precipitation_probability_max": [80]
+    }});
+    assert_eq!(
+        weather_text(&weather, &forecast).unwrap(),
+        "上海今天有雨，7到12度，降水概率80%"
+    );
+
+    let ics = "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nDTSTART:20250131T140000\r\nSUMMARY:牙医\r\n\
+        END:VEVENT\r\nBEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20250131\r\nSUMMARY:妈妈\r\n 的生日\r\n\
+        END:VEVENT\r\nBEGIN:VEVENT\r\nDTSTART:20250201T090000\r\nSUMMARY:开会\r\nEND:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+    let day = NaiveDate::from_ymd_opt(2025, 1, 31).unwrap();
+    let events = calendar_events(ics, day);
+    assert_eq!(
+        calendar_text(&events),
+        "今天有2个日程：妈妈的生日，14:00 牙医"
+    );
+
+    let ics = "BEGIN:VEVENT\r\nDTSTART:20250106T190000\r\nRRULE:FREQ=WEEKLY;BYDAY=MO,FR\r\n\
+        SUMMARY:游泳\r\nEND:VEVENT\r\nBEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20000131\r\n\
+        RRULE:FREQ=YEARLY\r\nEXDATE;VALUE=DATE:20240131\r\nSUMMARY:纪念日\r\nEND:VEVENT\r\n\
+        BEGIN:VEVENT\r\nDTSTART:20250101T080000\r\nRRULE:FREQ=DAILY;COUNT=3\r\n\
+        SUMMARY:吃药\r\nEND:VEVENT\r\n";
+    assert_eq!(
+        calendar_text(&calendar_events(ics, day)),
+        "今天有2个日程：纪念日，19:00 游泳"
+    );
+    let day = |d: &str| NaiveDate::parse_from_str(d, "%Y-%m-%d").unwrap();
+    assert!(calendar_events(ics, day("2024-01-31")).is_empty());
+    assert_eq!(calendar_events(ics, day("2025-01-03"))[0].1, "吃药");
+    assert!(calendar_events(ics, day("2025-01-04")).is_empty());
+    assert_eq!(Rrule::parse("FREQ=MONTHLY;BYDAY=1MO"), None);
+
+    let rss = r#"<rss><channel><title>Feed</title>
+        <item><title><![CDATA[First & news]]></title></item>
+        <item><title>Second &amp; more</title></item>
+        <item><title>Third</title></item></channel></rss>"#;
+    assert_eq!(feed_titles(rss, 2), ["First & news", "Second & more"]);
+    let atom = r#"<feed><title>Feed</title><entry><title type="html">Atom &lt;b&gt;</title>
+        <media:title>Thumbnail</media:title></entry></feed>"#;
+    assert_eq!(feed_titles(atom, 5), ["Atom <b>"]);
+
+    let config: BriefingConfig = toml::from_str("").unwrap();
+    let briefing = Briefing::new(config);
+    assert!(briefing.is_trigger("播放今日简报。"));
+    assert!(!briefing.is_trigger("今天天气怎么样"));
+}
@@ -0,0 +1,272 @@
+//! OpenAI compatible chat completions, answered by the same assistant as the devices.
+//!
+//! bearer `admin_token`:
+//! - `POST /v1/chat/completions` `{"messages": [{"role": "user", "content": "..."}], "stream": true}`
+//!
+//! the system messages of the client are replaced with the live prompts, `llm.sys_prompts`
+//! and `llm.dynamic_prompts` or their edit by `PUT /v1/prompts`, and the mcp tools are called on the server, the client only
+//! sees the final answer. `model` and client `tools` are not supported.
+
+use std::{collections::LinkedList, convert::Infallible, sync::Arc};
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, Sse},
+        IntoResponse,
+    },
+    routing::post,
+    Extension, Json, Router,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    ai::{
+        llm,
+        moderation::ModerationFilter,
+        openai::tool::{McpToolAdapter, ToolSet},
+        ChatSession, StableLLMResponseChunk,
+    },
+    config::LLMConfig,
+};
+
+/// tool call rounds of one completion, a model calling tools forever must not hang the request
+const MAX_TOOL_ROUNDS: usize = 8;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChatCompletionRequest {
+    pub messages: Vec<llm::Content>,
+    #[serde(default)]
+    pub stream: bool,
+    /// ignored, `llm.model` answers
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub tools: Vec<serde_json::Value>,
+}
+
+pub struct ChatCompletionsService {
+    pub llm: LLMConfig,
+    pub tool_set: ToolSet<McpToolAdapter>,
+    pub admin_token: Option<String>,
+    pub webhooks: Arc<crate::services::webhooks::Webhooks>,
+    /// the live prompts
+    pub sessions: Arc<crate::services::sessions::SessionManager>,
+}
+
+impl ChatCompletionsService {
+    fn chat_session(&self, messages: LinkedList<llm::Content>) -> ChatSession {
+        let mut chat_session = ChatSession::from_config(&self.llm, self.tool_set.clone());
+        chat_session.system_prompts = self.llm.sys_prompts.clone();
+        chat_session.messages = self.llm.dynamic_prompts.clone();
+        self.sessions.prompts().start(&mut chat_session);
+        chat_session.messages.extend(messages);
+        chat_session
+    }
+}
+
+/// the conversation of the client without its system messages
+fn client_messages(messages: Vec<llm::Content>) -> Result<LinkedList<llm::Content>, &'static str> {
+    let messages = messages
+        .into_iter()
+        .filter(|m| m.role != llm::Role::System)
+        .collect::<LinkedList<_>>();
+    match messages.back() {
+        None => Err("no messages"),
+        Some(last) if last.role == llm::Role::Assistant => {
+            Err("the last message must not be an assistant message")
+        }
+        Some(_) => Ok(messages),
+    }
+}
+
+/// run the completion and the tool calls, the text of the answer is passed to `on_text`
+async fn generate(
+    service: &ChatCompletionsService,
+    id: &str,
+    chat_session: &mut ChatSession,
+    mut on_text: impl FnMut(String),
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let mut moderation = service.llm.moderation.as_ref().map(ModerationFilter::new);
+    let mut reasoning_filter = service.llm.reasoning_filter();
+    let mut tool_rounds = 0;
+    let mut answer = String::new();
+
+    let mut resp = chat_session.complete().await?;
+    loop {
+        let (text, done) = match resp.next_chunk().await? {
+            StableLLMResponseChunk::Text(chunk) => (reasoning_filter.push(&chunk).text, false),
+            StableLLMResponseChunk::Functions(functions) => {
+                tool_rounds += 1;
+                if tool_rounds > MAX_TOOL_ROUNDS {
+                    anyhow::bail!("more than {MAX_TOOL_ROUNDS} rounds of tool calls");
+                }
+                log::info!("chat completion functions: {:#?}", functions);
+                chat_session.add_assistant_tool_call(functions.clone());
+                for function in &functions {
+                    service.webhooks.tool_invoked(
+                        id,
+                        &function.function.name,
+                        &function.function.arguments,
+                    );
+                    chat_session.execute_tool(function).await?;
+                }
+                resp = chat_session.complete().await?;
+                continue;
+            }
+            StableLLMResponseChunk::Stop => (reasoning_filter.finish().text, true),
+        };
+
+        let text = match moderation.as_mut() {
+            Some(moderation) => moderation.filter(&client, "chat_completions", text).await,
+            None => text,
+        };
+        if !text.is_empty() {
+            answer.push_str(&text);
+            on_text(text);
+        }
+        if done {
+            service.webhooks.response_done(id, &answer);
+            return Ok(());
+        }
+    }
+}
+
+/// `chat.completion.chunk` of a stream
+fn completion_chunk(
+    id: &str,
+    created: i64,
+    model: &str,
+    delta: serde_json::Value,
+    finish_reason: Option<&str>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+    })
+}
+
+/// POST /v1/chat/completions
+async fn chat_completions_handler(
+    Extension(service): Extension<Arc<ChatCompletionsService>>,
+    headers: HeaderMap,
+    Json(req): Json<ChatCompletionRequest>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &service.admin_token) {
+        return code.into_response();
+    }
+    if !req.tools.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "client tools are not supported, the tools of the server are used",
+        )
+            .into_response();
+    }
+    let messages = match client_messages(req.messages) {
+        Ok(messages) => messages,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let mut chat_session = service.chat_session(messages);
+    let id = format!("chatcmpl-{}", crate::util::new_uuid().simple());
+    let created = crate::util::now().timestamp();
+
+    if !req.stream {
+        let mut answer = String::new();
+        let r = generate(&service, &id, &mut chat_session, |text| {
+            answer.push_str(&text)
+        })
+        .await;
+        if let Err(e) = r {
+            log::error!("/v1/chat/completions error: {e}");
+            service.webhooks.error(&id, &e);
+            return (StatusCode::BAD_GATEWAY, e.to_string()).into_response();
+        }
+        return Json(serde_json::json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": created,
+            "model": service.llm.model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": answer},
+                "finish_reason": "stop",
+            }],
+        }))
+        .into_response();
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel::<Event>();
+    tokio::spawn(async move {
+        let model = service.llm.model.clone();
+        // the client is gone if the send fails, the answer is finished anyway
+        let send = |data: serde_json::Value| {
+            let _ = tx.send(Event::default().json_data(data).unwrap_or_default());
+        };
+        let role = serde_json::json!({"role": "assistant", "content": ""});
+        send(completion_chunk(&id, created, &model, role, None));
+
+        let r = generate(&service, &id, &mut chat_session, |text| {
+            let delta = serde_json::json!({ "content": text });
+            send(completion_chunk(&id, created, &model, delta, None));
+        })
+        .await;
+        match r {
+            Ok(()) => send(completion_chunk(
+                &id,
+                created,
+                &model,
+                serde_json::json!({}),
+                Some("stop"),
+            )),
+            Err(e) => {
+                log::error!("/v1/chat/completions stream error: {e}");
+                service.webhooks.error(&id, &e);
+                send(serde_json::json!({"error": {"message": e.to_string()}}));
+            }
+        }
+        let _ = tx.send(Event::default().data("[DONE]"));
+    });
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        let event = rx.recv().await?;
+        Some((Ok::<_, Infallible>(event), rx))
+    });
+    Sse::new(stream).into_response()
+}
+
+pub fn new_chat_completions_service(service: ChatCompletionsService) -> Router {
+    Router::new()
+        .route("/v1/chat/completions", post(chat_completions_handler))
+        .layer(Extension(Arc::new(service)))
+}
+
+#[test]
+fn test_client_messages() {
+    let req: ChatCompletionRequest = serde_json::from_str(
+        r#"{"model": "gpt-4o", "messages": [
+            {"role": "system", "content": "you are a pirate"},
+            {"role": "user", "content": "你好"},
+            {"role": "assistant", "content": "你好呀"},
+            {"role": "user", "content": "讲个笑话"}
+        ]}"#,
+    )
+    .unwrap();
+    assert!(!req.stream);
+    let messages = client_messages(req.messages).unwrap();
+    assert_eq!(messages.len(), 3);
+    assert!(messages.iter().all(|m| m.role != llm::Role::System));
+
+    let req: ChatCompletionRequest = serde_json::from_str(
+        r#"{"messages": [{"role": "system", "content": "you are a pirate"}]}"#,
+    )
+    .unwrap();
+    assert!(client_messages(req.messages).is_err());
+
+    let chunk = completion_chunk("id", 0, "m", serde_json::json!({}), Some("stop"));
+    assert_eq!(chunk["choices"][0]["finish_reason"], "stop");
+}
@@ -0,0 +1,431 @@
+//! State shared by several servers behind a load balancer, `[redis]` with the `redis` feature.
+//!
+//! - the live sessions of every instance, `GET /v1/sessions` lists them all and
+//!   `GET /v1/sessions/{id}` reads them on any instance, with the `instance` that owns them
+//! - the messages of `POST /v1/sessions/{id}/messages`, taken by the instance of the session
+//!   with the same answers as on that instance
+//! - a device connecting to another instance goes on with its conversation, read from the
+//!   `[history]`, see [`crate::services::sessions::HANDOFF_WINDOW`]. the realtime sessions are
+//!   not handed over, their clients open a new one
+//! - the changes of the device registry, applied to the cache of the other instances
+//! - the announcements: a device is spoken to by the instance it is connected to
+//! - the rate limit counters
+//!
+//! keys under `redis.prefix`: `session:{id}` expire unless the instance refreshes them and
+//! are deleted once the session is closed, `sessions` is the sorted set of their ids by
+//! expiry,
+//! `rate:{key}` count the hits of a window, the events go through the `events` channel.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast;
+
+use crate::{
+    config::{Prosody, RedisConfig},
+    protocol::DeviceControl,
+    services::{
+        registry::Device,
+        sessions::{InjectedMessage, SessionManager},
+    },
+};
+
+/// the sessions of an instance are written again every `SESSION_SYNC`
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+const SESSION_SYNC: Duration = Duration::from_secs(10);
+/// and expire after `SESSION_TTL` if the instance is gone
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+const SESSION_TTL: Duration = Duration::from_secs(30);
+
+/// the session key is deleted if it is still the one of the instance
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+const DELETE_OWN_SESSION: &str = r#"
+local value = redis.call('GET', KEYS[1])
+if value and cjson.decode(value).instance == ARGV[1] then
+    redis.call('ZREM', KEYS[2], ARGV[2])
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// published to the other instances
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    Device {
+        device: Device,
+    },
+    PairingCode {
+        code: String,
+        profile: Option<String>,
+        #[serde(default)]
+        tenant: Option<String>,
+        ttl_sec: u64,
+    },
+    PairingCodeUsed {
+        code: String,
+    },
+    /// the telemetry samples of a device are deleted
+    TelemetryErased {
+        device_id: String,
+    },
+    Announce {
+        device_id: String,
+        text: String,
+        #[serde(default)]
+        prosody: Prosody,
+    },
+    /// the members connected to each instance play it, with `ask` each instance asks the llm
+    Broadcast {
+        members: Vec<String>,
+        text: String,
+        ask: bool,
+        #[serde(default)]
+        prosody: Prosody,
+    },
+    Control {
+        device_id: String,
+        control: DeviceControl,
+    },
+    /// for the instance of the session
+    SessionMessage {
+        session_id: String,
+        message: InjectedMessage,
+    },
+}
+
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Envelope {
+    instance: String,
+    event: ClusterEvent,
+}
+
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub struct Cluster {
+    config: RedisConfig,
+    instance_id: String,
+    events: broadcast::Sender<ClusterEvent>,
+    #[cfg(feature = "redis")]
+    conn: redis::aio::ConnectionManager,
+}
+
+impl std::fmt::Debug for Cluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cluster")
+            .field("prefix", &self.config.prefix)
+            .field("instance_id", &self.instance_id)
+            .finish()
+    }
+}
+
+impl Cluster {
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    fn key(&self, name: &str) -> String {
+        format!("{}{name}", self.config.prefix)
+    }
+
+    /// the events published by the other instances
+    pub fn subscribe(&self) -> broadcast::Receiver<ClusterEvent> {
+        self.events.subscribe()
+    }
+
+    /// `redis.instance`, or a random one
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+impl Cluster {
+    pub async fn connect(_config: RedisConfig) -> anyhow::Result<Arc<Self>> {
+        anyhow::bail!("`[redis]` needs the `redis` feature")
+    }
+
+    pub fn publish(&self, _event: ClusterEvent) {}
+
+    pub fn spawn_session_sync(self: &Arc<Self>, _sessions: Arc<SessionManager>) {}
+
+    pub async fn sessions(&self) -> anyhow::Result<Vec<serde_json::Value>> {
+        Ok(vec![])
+    }
+
+    pub async fn session(&self, _id: &str) -> anyhow::Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    pub async fn is_online(&self, _id: &str) -> bool {
+        false
+    }
+
+    async fn incr(&self, _key: &str, _window: Duration) -> anyhow::Result<u64> {
+        anyhow::bail!("no redis")
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Cluster {
+    /// connect and follow the events of the other instances
+    pub async fn connect(config: RedisConfig) -> anyhow::Result<Arc<Self>> {
+        use futures_util::StreamExt;
+
+        let client = redis::Client::open(config.url.as_str())?;
+        let conn = redis::aio::ConnectionManager::new(client.clone()).await?;
+        let (events, _) = broadcast::channel(64);
+        let instance_id = config
+            .instance
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+        let cluster = Arc::new(Self {
+            config,
+            instance_id,
+            events,
+            conn,
+        });
+
+        let follower = cluster.clone();
+        tokio::spawn(async move {
+            let channel = follower.key("events");
+            loop {
+                let r = async {
+                    let mut pubsub = client.get_async_pubsub().await?;
+                    pubsub.subscribe(&channel).await?;
+                    let mut messages = pubsub.on_message();
+                    while let Some(msg) = messages.next().await {
+                        let payload: String = msg.get_payload()?;
+                        match serde_json::from_str::<Envelope>(&payload) {
+                            Ok(envelope) if envelope.instance != follower.instance_id => {
+                                let _ = follower.events.send(envelope.event);
+                            }
+                            Ok(_) => {}
+                            Err(e) => log::warn!("cluster bad event: {e}"),
+                        }
+                    }
+                    anyhow::Ok(())
+                }
+                .await;
+                log::warn!("cluster subscription ended: {r:?}, reconnecting");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        log::info!("cluster instance {}", cluster.instance_id);
+        Ok(cluster)
+    }
+
+    /// send `event` to the other instances in the background
+    pub fn publish(&self, event: ClusterEvent) {
+        let envelope = Envelope {
+            instance: self.instance_id.clone(),
+            event,
+        };
+        let payload = match serde_json::to_string(&envelope) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("cluster event encode error: {e}");
+                return;
+            }
+        };
+        let mut conn = self.conn.clone();
+        let channel = self.key("events");
+        tokio::spawn(async move {
+            let r: redis::RedisResult<i64> = redis::cmd("PUBLISH")
+                .arg(channel)
+                .arg(payload)
+                .query_async(&mut conn)
+                .await;
+            if let Err(e) = r {
+                log::error!("cluster publish error: {e}");
+            }
+        });
+    }
+
+    /// keep the live sessions of this instance in redis
+    pub fn spawn_session_sync(self: &Arc<Self>, sessions: Arc<SessionManager>) {
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SESSION_SYNC);
+            let mut synced = std::collections::HashSet::new();
+            loop {
+                interval.tick().await;
+                let mut pipe = redis::pipe();
+                let live = sessions.list();
+                let expiry = chrono::Utc::now().timestamp() + SESSION_TTL.as_secs() as i64;
+                for info in &live {
+                    let Ok(mut value) = serde_json::to_value(info) else {
+                        continue;
+                    };
+                    value["instance"] = cluster.instance_id.clone().into();
+                    value["takes_messages"] = sessions.takes_messages(&info.id).into();
+                    pipe.cmd("SET")
+                        .arg(cluster.key(&format!("session:{}", info.id)))
+                        .arg(value.to_string())
+                        .arg("EX")
+                        .arg(SESSION_TTL.as_secs())
+                        .ignore();
+                    pipe.cmd("ZADD")
+                        .arg(cluster.key("sessions"))
+                        .arg(expiry)
+                        .arg(&info.id)
+                        .ignore();
+                }
+                let live: std::collections::HashSet<String> =
+                    live.into_iter().map(|info| info.id).collect();
+                // a device may be connected to another instance by now
+                for closed in synced.difference(&live) {
+                    pipe.cmd("EVAL")
+                        .arg(DELETE_OWN_SESSION)
+                        .arg(2)
+                        .arg(cluster.key(&format!("session:{closed}")))
+                        .arg(cluster.key("sessions"))
+                        .arg(&cluster.instance_id)
+                        .arg(closed)
+                        .ignore();
+                }
+                synced = live;
+                let mut conn = cluster.conn.clone();
+                let r: redis::RedisResult<()> = pipe.query_async(&mut conn).await;
+                if let Err(e) = r {
+                    log::error!("cluster session sync error: {e}");
+                }
+            }
+        });
+    }
+
+    /// the live sessions of all the instances, oldest first
+    pub async fn sessions(&self) -> anyhow::Result<Vec<serde_json::Value>> {
+        let mut conn = self.conn.clone();
+        // the ids of the instances that are gone expired
+        let index = self.key("sessions");
+        let (ids,): (Vec<String>,) = redis::pipe()
+            .cmd("ZREMRANGEBYSCORE")
+            .arg(&index)
+            .arg("-inf")
+            .arg(chrono::Utc::now().timestamp())
+            .ignore()
+            .cmd("ZRANGE")
+            .arg(&index)
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut conn)
+            .await?;
+        let keys = ids
+            .iter()
+            .map(|id| self.key(&format!("session:{id}")))
+            .collect::<Vec<_>>();
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let values: Vec<Option<String>> =
+            redis::cmd("MGET").arg(&keys).query_async(&mut conn).await?;
+        let mut sessions = values
+            .into_iter()
+            .flatten()
+            .filter_map(|value| serde_json::from_str::<serde_json::Value>(&value).ok())
+            .collect::<Vec<_>>();
+        let sort_key = |s: &serde_json::Value| {
+            let field = |name: &str| s[name].as_str().unwrap_or_default().to_string();
+            (field("created_at"), field("id"))
+        };
+        sessions.sort_by_key(sort_key);
+        Ok(sessions)
+    }
+
+    /// a live session of one of the instances
+    pub async fn session(&self, id: &str) -> anyhow::Result<Option<serde_json::Value>> {
+        let mut conn = self.conn.clone();
+        let value: Option<String> = redis::cmd("GET")
+            .arg(self.key(&format!("session:{id}")))
+            .query_async(&mut conn)
+            .await?;
+        Ok(value.and_then(|value| serde_json::from_str(&value).ok()))
+    }
+
+    /// the device has a live session on one of the instances
+    pub async fn is_online(&self, id: &str) -> bool {
+        let mut conn = self.conn.clone();
+        let r: redis::RedisResult<bool> = redis::cmd("EXISTS")
+            .arg(self.key(&format!("session:{id}")))
+            .query_async(&mut conn)
+            .await;
+        r.unwrap_or(false)
+    }
+
+    async fn incr(&self, key: &str, window: Duration) -> anyhow::Result<u64> {
+        let mut conn = self.conn.clone();
+        let key = self.key(&format!("rate:{key}"));
+        // the first hit of a window creates the key with its expiry
+        let (count,): (u64,) = redis::pipe()
+            .cmd("SET")
+            .arg(&key)
+            .arg(0)
+            .arg("EX")
+            .arg(window.as_secs().max(1))
+            .arg("NX")
+            .ignore()
+            .cmd("INCR")
+            .arg(&key)
+            .query_async(&mut conn)
+            .await?;
+        Ok(count)
+    }
+}
+
+/// hits of a key in a fixed window, shared by the instances with a cluster, local otherwise
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    cluster: Option<Arc<Cluster>>,
+    local: Mutex<HashMap<String, (Instant, u64)>>,
+}
+
+impl RateLimiter {
+    pub fn new(cluster: Option<Arc<Cluster>>) -> Self {
+        Self {
+            cluster,
+            local: Default::default(),
+        }
+    }
+
+    /// count a hit, the hits of the current window are returned
+    pub async fn hit(&self, key: &str, window: Duration) -> u64 {
+        if let Some(cluster) = &self.cluster {
+            match cluster.incr(key, window).await {
+                Ok(count) => return count,
+                Err(e) => log::warn!("cluster rate limit error, counting locally: {e}"),
+            }
+        }
+        let now = Instant::now();
+        let mut local = self.local.lock().unwrap();
+        local.retain(|_, (start, _)| now.duration_since(*start) < window);
+        let (_, count) = local.entry(key.to_string()).or_insert((now, 0));
+        *count += 1;
+        *count
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    let limiter = RateLimiter::new(None);
+    let window = Duration::from_millis(50);
+    assert_eq!(limiter.hit("pair:aa", window).await, 1);
+    assert_eq!(limiter.hit("pair:aa", window).await, 2);
+    assert_eq!(limiter.hit("pair:bb", window).await, 1);
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(limiter.hit("pair:aa", window).await, 1);
+
+    let event: ClusterEvent =
+        serde_json::from_str(r#"{"type": "announce", "device_id": "aa", "text": "hi"}"#).unwrap();
+    assert!(matches!(event, ClusterEvent::Announce { .. }));
+    let event: ClusterEvent = serde_json::from_str(
+        r#"{"type": "session_message", "session_id": "abc",
+            "message": {"role": "system", "content": "门铃响了"}}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        event,
+        ClusterEvent::SessionMessage { message, .. } if message.generate
+    ));
+}
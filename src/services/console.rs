@@ -0,0 +1,211 @@
+//! Embedded admin console, to check a config without writing a client.
+//!
+//! - `GET /console` the page, compiled into the binary
+//! - `GET /console/status` (bearer `admin_token`) live sessions, provider health and the
+//!   recent errors
+//!
+//! the microphone test of the page talks to `/v1/realtime` with push to talk.
+
+use std::sync::Arc;
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse},
+    routing::get,
+    Extension, Json, Router,
+};
+
+use crate::{
+    config::{AIConfig, ASRConfig, TTSConfig},
+    services::{sessions::SessionManager, webhooks::Webhooks},
+};
+
+const CONSOLE_HTML: &str = include_str!("../../resources/console.html");
+
+/// timeout of a provider probe
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ProviderHealth {
+    pub name: &'static str,
+    pub url: String,
+    /// the provider answered, whatever the status
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct ConsoleService {
+    pub admin_token: Option<String>,
+    /// (name, url) of the providers probed by `/console/status`
+    pub providers: Vec<(&'static str, String)>,
+    pub output_sample_rate: u32,
+    pub sessions: Arc<SessionManager>,
+    pub webhooks: Arc<Webhooks>,
+}
+
+/// (name, url) of the providers of the config. websocket providers are probed over https
+pub fn health_targets(config: &AIConfig) -> Vec<(&'static str, String)> {
+    let mut targets = vec![];
+    let (tts, asr) = match config {
+        AIConfig::Stable { llm, tts, asr } => {
+            if llm.mock.is_none() {
+                targets.push(("llm", llm.llm_chat_url.clone()));
+            }
+            (Some(tts), Some(asr))
+        }
+        AIConfig::GeminiAndTTS { tts, .. } => {
+            targets.push((
+                "gemini",
+                "wss://generativelanguage.googleapis.com".to_string(),
+            ));
+            (Some(tts), None)
+        }
+        AIConfig::Gemini { .. } => {
+            targets.push((
+                "gemini",
+                "wss://generativelanguage.googleapis.com".to_string(),
+            ));
+            (None, None)
+        }
+    };
+    match asr {
+        Some(ASRConfig::Whisper(asr)) => {
+            targets.push(("asr", asr.url.clone()));
+            if let Some(vad_url) = &asr.vad_url {
+                targets.push(("vad", vad_url.clone()));
+            }
+        }
+        Some(ASRConfig::ParaformerV2(_)) => {
+            targets.push(("asr", "wss://dashscope.aliyuncs.com".to_string()));
+        }
+        Some(ASRConfig::Mock(_)) | None => {}
+    }
+    let tts_url = match tts {
+        Some(TTSConfig::Stable(tts)) => Some(tts.url.clone()),
+        Some(TTSConfig::StreamGSV(tts)) => Some(tts.url.clone()),
+        Some(TTSConfig::Fish(_)) => Some("https://api.fish.audio".to_string()),
+        Some(TTSConfig::Groq(_)) => Some("https://api.groq.com".to_string()),
+        Some(TTSConfig::CosyVoice(_)) => Some("wss://dashscope.aliyuncs.com".to_string()),
+        Some(TTSConfig::Mock(_)) | None => None,
+    };
+    targets.extend(tts_url.map(|url| ("tts", url)));
+
+    targets
+        .into_iter()
+        .map(|(name, url)| {
+            let url = match url.strip_prefix("wss://") {
+                Some(rest) => format!("https://{rest}"),
+                None => match url.strip_prefix("ws://") {
+                    Some(rest) => format!("http://{rest}"),
+                    None => url,
+                },
+            };
+            (name, url)
+        })
+        .collect()
+}
+
+async fn probe(client: &reqwest::Client, name: &'static str, url: &str) -> ProviderHealth {
+    let st = std::time::Instant::now();
+    let r = client.get(url).timeout(PROBE_TIMEOUT).send().await;
+    let latency_ms = st.elapsed().as_millis() as u64;
+    let (ok, status, error) = match r {
+        Ok(res) => (true, Some(res.status().as_u16()), None),
+        Err(e) => (false, None, Some(e.to_string())),
+    };
+    ProviderHealth {
+        name,
+        url: url.to_string(),
+        ok,
+        status,
+        latency_ms,
+        error,
+    }
+}
+
+/// GET /console
+async fn console_page() -> impl IntoResponse {
+    Html(CONSOLE_HTML)
+}
+
+/// GET /console/status
+async fn console_status(
+    Extension(console): Extension<Arc<ConsoleService>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &console.admin_token) {
+        return code.into_response();
+    }
+
+    let client = reqwest::Client::new();
+    let providers = futures_util::future::join_all(
+        console
+            .providers
+            .iter()
+            .map(|(name, url)| probe(&client, name, url)),
+    )
+    .await;
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "sessions": console.sessions.list(),
+            "providers": providers,
+            "errors": console.webhooks.recent_errors(),
+            "output_sample_rate": console.output_sample_rate,
+        })),
+    )
+        .into_response()
+}
+
+pub fn new_console_service(console: ConsoleService) -> Router {
+    Router::new()
+        .route("/console", get(console_page))
+        .route("/console/status", get(console_status))
+        .layer(Extension(Arc::new(console)))
+}
+
+#[test]
+fn test_health_targets() {
+    let config: crate::config::Config = toml::from_str(
+        r#"
+        addr = "0.0.0.0:8080"
+        [tts]
+        platform = "Groq"
+        api_key = "key"
+        model = "playai-tts"
+        voice = "Aaliyah-PlayAI"
+        [asr]
+        url = "http://localhost:8000/v1/audio/transcriptions"
+        vad_url = "http://localhost:8000/v1/audio/vad"
+        [llm]
+        llm_chat_url = "http://localhost:8000/v1/chat/completions"
+        history = 5
+        "#,
+    )
+    .unwrap();
+    let targets = health_targets(&config.config);
+    let names = targets.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    assert_eq!(names, ["llm", "asr", "vad", "tts"]);
+    assert_eq!(targets[3].1, "https://api.groq.com");
+
+    let config: crate::config::Config = toml::from_str(
+        r#"
+        addr = "0.0.0.0:8080"
+        [gemini]
+        api_key = "key"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        health_targets(&config.config),
+        [(
+            "gemini",
+            "https://generativelanguage.googleapis.com".to_string()
+        )]
+    );
+}
@@ -0,0 +1,85 @@
+//! Estimated costs, `[costs]`: the llm tokens, the tts characters and the asr audio of each
+//! turn are priced as they are used.
+//!
+//! ```toml
+//! [costs.llm]
+//! input_per_million = 0.15
+//! output_per_million = 0.6
+//!
+//! [costs.tts]
+//! per_million_chars = 15.0
+//!
+//! [costs.asr]
+//! per_minute = 0.006
+//! ```
+//!
+//! the llm streams report no usage, the tokens are estimated from the text of the prompts
+//! and the answers. the usage adds up in the session, `GET /v1/sessions`, and in each turn,
+//! per day and per device at `GET /v1/stats`. the realtime sessions get it in the `usage`
+//! of `response.done` too.
+
+use crate::{config::CostsConfig, storage::Usage};
+
+impl CostsConfig {
+    pub fn price(&self, usage: &Usage) -> f64 {
+        usage.llm_input_tokens as f64 * self.llm.input_per_million / 1e6
+            + usage.llm_output_tokens as f64 * self.llm.output_per_million / 1e6
+            + usage.tts_chars as f64 * self.tts.per_million_chars / 1e6
+            + usage.asr_ms as f64 * self.asr.per_minute / 60_000.0
+    }
+}
+
+/// of the 16 bits mono `pcm` at `sample_rate`
+pub fn pcm_duration_ms(pcm: &[u8], sample_rate: u32) -> u64 {
+    if sample_rate == 0 {
+        return 0;
+    }
+    (pcm.len() / 2) as u64 * 1000 / sample_rate as u64
+}
+
+/// of a pcm wav with a plain 44 bytes header
+pub fn wav_duration_ms(wav: &[u8]) -> u64 {
+    let Some(byte_rate) = wav.get(28..32) else {
+        return 0;
+    };
+    let byte_rate = u32::from_le_bytes([byte_rate[0], byte_rate[1], byte_rate[2], byte_rate[3]]);
+    if byte_rate == 0 {
+        return 0;
+    }
+    wav.len().saturating_sub(44) as u64 * 1000 / byte_rate as u64
+}
+
+#[test]
+fn test_costs() {
+    let costs: CostsConfig = toml::from_str(
+        r#"
+        [llm]
+        input_per_million = 1.0
+        output_per_million = 2.0
+
+        [asr]
+        per_minute = 0.006
+        "#,
+    )
+    .unwrap();
+    let usage = Usage {
+        llm_input_tokens: 1_000_000,
+        llm_output_tokens: 500_000,
+        tts_chars: 1000,
+        asr_ms: 60_000,
+        cost: 0.0,
+    };
+    assert!((costs.price(&usage) - 2.006).abs() < 1e-9);
+
+    assert_eq!(crate::ai::llm::estimate_tokens("今天天气怎么样"), 7);
+    assert_eq!(crate::ai::llm::estimate_tokens("hello world"), 3);
+    assert_eq!(pcm_duration_ms(&[0; 32000], 16000), 1000);
+    let wav = crate::util::pcm_to_wav(
+        &[0; 16000],
+        crate::util::WavConfig {
+            sample_rate: 16000,
+            ..Default::default()
+        },
+    );
+    assert_eq!(wav_duration_ms(&wav), 500);
+}
@@ -0,0 +1,522 @@
+//! `/device/ws`: a compact binary protocol for the EchoKit firmware, mapped onto
+//! `RealtimeSession`, so the device doesn't have to speak the OpenAI JSON protocol.
+//!
+//! with a `[registry]` the device connects with `?device_id=...&token=...`, without a device
+//! id or with a wrong token it is refused with a 401.
+//!
+//! Every websocket binary message is one frame: `[opcode: u8][payload]`.
+//!
+//! device -> server:
+//! - `0x01` audio: raw pcm16 le, 16k mono
+//! - `0x02` commit: end of speech, run ASR + LLM + TTS
+//! - `0x03` clear: drop the buffered audio
+//! - `0x04` cancel: interrupt the current response
+//! - `0x05` mode: one byte, `0` push-to-talk (commit-driven), `1` continuous (server vad)
+//! - `0x06` telemetry: json, see [`Telemetry`]
+//! - `0x07` speech hint: one byte, `0` speech start, `1` speech stop, from a local vad;
+//!   the stop commits once the server vad confirms the speech
+//! - `0x08` camera frame: a jpeg, attached to the next user turn when `llm.vision` is on
+//!
+//! server -> device:
+//! - `0x81` state: one byte, see [`DeviceState`]
+//! - `0x82` audio: raw pcm16 le, `stream.output_sample_rate` mono, the profile one if set
+//! - `0x83` transcript: utf8 ASR result
+//! - `0x84` text: utf8 response text delta
+//! - `0x85` end: response done
+//! - `0x86` mode: one byte, the active mode
+//! - `0x87` document: utf8 dictated document after each edit, a profile with
+//!   `dictation = true`
+//! - `0x8f` error: utf8 message
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Extension, Query, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::{
+    ai::openai::realtime::{Modality, ServerEvent, SpeechHint, TurnDetection, TurnDetectionType},
+    config::ProfileConfig,
+    services::{
+        dictation::Dictation,
+        realtime_ws::{self, RealtimeSession, StableRealtimeConfig},
+        registry::Telemetry,
+        tenants::scoped_id,
+        turn_state::TurnEvent,
+    },
+};
+
+/// sample rate of the pcm sent by the device
+pub const DEVICE_INPUT_SAMPLE_RATE: u32 = 16000;
+
+pub mod opcode {
+    pub const AUDIO: u8 = 0x01;
+    pub const COMMIT: u8 = 0x02;
+    pub const CLEAR: u8 = 0x03;
+    pub const CANCEL: u8 = 0x04;
+    pub const MODE: u8 = 0x05;
+    pub const TELEMETRY: u8 = 0x06;
+    pub const SPEECH_HINT: u8 = 0x07;
+    pub const CAMERA_FRAME: u8 = 0x08;
+
+    pub const STATE: u8 = 0x81;
+    pub const AUDIO_OUT: u8 = 0x82;
+    pub const TRANSCRIPT: u8 = 0x83;
+    pub const TEXT: u8 = 0x84;
+    pub const END: u8 = 0x85;
+    pub const MODE_OUT: u8 = 0x86;
+    pub const DOCUMENT: u8 = 0x87;
+    pub const ERROR: u8 = 0x8f;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeviceState {
+    Idle = 0,
+    Listening = 1,
+    Thinking = 2,
+    Speaking = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeviceMode {
+    PushToTalk = 0,
+    Continuous = 1,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DeviceFrame {
+    Audio(Bytes),
+    Commit,
+    Clear,
+    Cancel,
+    Mode(DeviceMode),
+    Telemetry(Telemetry),
+    SpeechHint(SpeechHint),
+    CameraFrame(Bytes),
+}
+
+impl DeviceFrame {
+    pub fn decode(mut data: Bytes) -> anyhow::Result<Self> {
+        if data.is_empty() {
+            return Err(anyhow::anyhow!("empty frame"));
+        }
+        let op = data.split_to(1)[0];
+        match op {
+            opcode::AUDIO => Ok(DeviceFrame::Audio(data)),
+            opcode::COMMIT => Ok(DeviceFrame::Commit),
+            opcode::CLEAR => Ok(DeviceFrame::Clear),
+            opcode::CANCEL => Ok(DeviceFrame::Cancel),
+            opcode::MODE => match data.first() {
+                Some(0) => Ok(DeviceFrame::Mode(DeviceMode::PushToTalk)),
+                Some(1) => Ok(DeviceFrame::Mode(DeviceMode::Continuous)),
+                m => Err(anyhow::anyhow!("invalid mode: {m:?}")),
+            },
+            opcode::TELEMETRY => Ok(DeviceFrame::Telemetry(serde_json::from_slice(&data)?)),
+            opcode::SPEECH_HINT => match data.first() {
+                Some(0) => Ok(DeviceFrame::SpeechHint(SpeechHint::SpeechStart)),
+                Some(1) => Ok(DeviceFrame::SpeechHint(SpeechHint::SpeechStop)),
+                h => Err(anyhow::anyhow!("invalid speech hint: {h:?}")),
+            },
+            opcode::CAMERA_FRAME if data.is_empty() => Err(anyhow::anyhow!("empty camera frame")),
+            opcode::CAMERA_FRAME => Ok(DeviceFrame::CameraFrame(data)),
+            op => Err(anyhow::anyhow!("unknown opcode: {op:#04x}")),
+        }
+    }
+}
+
+pub fn encode_frame(op: u8, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + payload.len());
+    buf.put_u8(op);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+pub fn encode_state(state: DeviceState) -> Bytes {
+    encode_frame(opcode::STATE, &[state as u8])
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DeviceQuery {
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub async fn ws_handler(
+    Extension(config): Extension<Arc<StableRealtimeConfig>>,
+    Query(query): Query<DeviceQuery>,
+    headers: axum::http::HeaderMap,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let mut profile = None;
+    let mut paired = None;
+    if let Some(registry) = &config.registry {
+        // with a registry every device says who it is
+        let Some(device_id) = &query.device_id else {
+            log::warn!("device session without a device_id rejected");
+            return (http::StatusCode::UNAUTHORIZED, "device_id is required").into_response();
+        };
+        match registry.authorize(device_id, query.token.as_deref()).await {
+            Ok(device) => paired = device,
+            Err(e) => {
+                log::warn!("`{device_id}` rejected: {e}");
+                return (http::StatusCode::UNAUTHORIZED, e.to_string()).into_response();
+            }
+        }
+        profile = registry.profile(device_id).await;
+    }
+
+    // a paired device belongs to the tenant of its pairing code
+    // the other devices of a tenant get an id of the tenant
+    let tenants = config.sessions.tenants();
+    let (tenant, device_id) = match paired {
+        Some(device) => match tenants.connect(device.tenant.as_deref()).await {
+            Ok(()) => (device.tenant, query.device_id),
+            Err(code) => return code.into_response(),
+        },
+        None => match tenants.authorize(&headers).await {
+            Ok(tenant) => {
+                let device_id = query.device_id.map(|id| scoped_id(tenant.as_deref(), &id));
+                (tenant, device_id)
+            }
+            Err(code) => return code.into_response(),
+        },
+    };
+    let config = realtime_ws::tenant_config(config, tenant.as_deref());
+
+    ws.on_upgrade(|socket| handle_socket(config, device_id, profile, tenant, socket))
+        .into_response()
+}
+
+/// translate the realtime server events into device frames
+async fn event_to_frames(event: ServerEvent, speaking: &mut bool) -> anyhow::Result<Vec<Bytes>> {
+    let frames = match event {
+        ServerEvent::InputAudioBufferCommitted { .. } => {
+            vec![encode_state(DeviceState::Thinking)]
+        }
+        ServerEvent::ConversationItemInputAudioTranscriptionCompleted { transcript, .. } => {
+            vec![encode_frame(opcode::TRANSCRIPT, transcript.as_bytes())]
+        }
+        ServerEvent::ResponseTextDelta { delta, .. } => {
+            vec![encode_frame(opcode::TEXT, delta.as_bytes())]
+        }
+        ServerEvent::ResponseAudioDelta { delta, .. } => {
+            let pcm =
+                tokio::task::spawn_blocking(move || realtime_ws::decode_base64(&delta)).await??;
+            let mut frames = Vec::with_capacity(2);
+            if !*speaking {
+                *speaking = true;
+                frames.push(encode_state(DeviceState::Speaking));
+            }
+            frames.push(encode_frame(opcode::AUDIO_OUT, &pcm));
+            frames
+        }
+        ServerEvent::ResponseDone { .. } | ServerEvent::ConversationInterrupted { .. } => {
+            *speaking = false;
+            vec![
+                encode_frame(opcode::END, &[]),
+                encode_state(DeviceState::Listening),
+            ]
+        }
+        ServerEvent::Error { error, .. } => {
+            vec![encode_frame(opcode::ERROR, error.message.as_bytes())]
+        }
+        // dictation is not answered, the device listens again
+        ServerEvent::DictationTextDelta { text, .. } => {
+            vec![
+                encode_frame(opcode::DOCUMENT, text.as_bytes()),
+                encode_state(DeviceState::Listening),
+            ]
+        }
+        ServerEvent::SessionUpdated { session, .. } => {
+            let continuous = matches!(
+                session.turn_detection,
+                Some(TurnDetection {
+                    turn_type: TurnDetectionType::ServerVad | TurnDetectionType::SemanticVad,
+                    ..
+                })
+            );
+            let mode = if continuous {
+                DeviceMode::Continuous
+            } else {
+                DeviceMode::PushToTalk
+            };
+            vec![encode_frame(opcode::MODE_OUT, &[mode as u8])]
+        }
+        _ => vec![],
+    };
+    Ok(frames)
+}
+
+async fn handle_frame(
+    frame: DeviceFrame,
+    device_id: Option<&str>,
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<()> {
+    match frame {
+        DeviceFrame::Audio(pcm) => realtime_ws::handle_input_audio(session, tx, config, &pcm).await,
+        DeviceFrame::Telemetry(telemetry) => {
+            match (&config.registry, device_id) {
+                (Some(registry), Some(device_id)) => {
+                    registry.record_telemetry(device_id, telemetry).await
+                }
+                _ => log::debug!("device session `{}` telemetry: {telemetry:?}", session.id),
+            }
+            Ok(())
+        }
+        DeviceFrame::Clear => {
+            session.input_audio_buffer.clear();
+            Ok(())
+        }
+        DeviceFrame::Cancel => {
+            session.turn_event(tx, TurnEvent::ResponseDone).await;
+            let _ = tx
+                .send(ServerEvent::ConversationInterrupted {
+                    event_id: uuid::Uuid::new_v4().to_string(),
+                })
+                .await;
+            Ok(())
+        }
+        DeviceFrame::Mode(mode) => {
+            session.config.turn_detection = Some(match mode {
+                DeviceMode::PushToTalk => TurnDetection::none(),
+                DeviceMode::Continuous => TurnDetection::server_vad(),
+            });
+            if let Err(e) = session.apply_turn_detection(&config.asr).await {
+                session.config.turn_detection = Some(TurnDetection::none());
+                return Err(e);
+            }
+            let _ = tx
+                .send(ServerEvent::SessionUpdated {
+                    event_id: uuid::Uuid::new_v4().to_string(),
+                    session: realtime_ws::session_info(session, config),
+                })
+                .await;
+            config
+                .sessions
+                .set_config(&session.id, realtime_ws::session_info(session, config));
+            Ok(())
+        }
+        DeviceFrame::SpeechHint(hint) => {
+            realtime_ws::handle_speech_hint(hint, session, tx, config).await
+        }
+        DeviceFrame::CameraFrame(jpeg) => {
+            if session.chat_session.vision {
+                let image = realtime_ws::encode_base64_blocking(jpeg).await?;
+                session.set_camera_frame(image);
+            }
+            Ok(())
+        }
+        DeviceFrame::Commit => {
+            if realtime_ws::handle_audio_buffer_commit(session, tx, None, config).await? {
+                realtime_ws::generate_response(session, tx, config).await
+            } else {
+                // nothing to answer, back to listening
+                let _ = tx
+                    .send(ServerEvent::ConversationInterrupted {
+                        event_id: uuid::Uuid::new_v4().to_string(),
+                    })
+                    .await;
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn handle_socket(
+    config: Arc<StableRealtimeConfig>,
+    device_id: Option<String>,
+    profile: Option<ProfileConfig>,
+    tenant: Option<String>,
+    socket: WebSocket,
+) {
+    let (mut sender, receiver) = socket.split();
+
+    // bind the profile of the device
+    let chat_session = realtime_ws::new_chat_session(&config, profile.as_ref());
+    let dictation = profile.as_ref().is_some_and(|p| p.dictation);
+    let earcon_hz = config.stream.output_sample_rate;
+    let config = match profile {
+        Some(profile) => {
+            let mut config = config.as_ref().clone();
+            if let Some(voice) = &profile.voice {
+                config.tts = config.tts.with_voice(voice);
+            }
+            config.phrases = config.phrases.with_profile(Some(&profile));
+            config.earcons = config.earcons.with_profile(Some(&profile));
+            config.stream = config.stream.with_profile(Some(&profile));
+            Arc::new(config)
+        }
+        None => config,
+    };
+    let (tx, mut rx) = mpsc::channel::<ServerEvent>(config.stream.event_channel_capacity);
+
+    let mut session = RealtimeSession::new(chat_session);
+    session.prompts = Some(config.sessions.prompts().start(&mut session.chat_session));
+    session.earcons = config
+        .earcon_audio
+        .earcons(&config.earcons)
+        .resampled(earcon_hz, config.stream.output_sample_rate);
+    session.input_sample_rate = DEVICE_INPUT_SAMPLE_RATE;
+    if let (Some(registry), Some(device_id)) = (&config.registry, &device_id) {
+        session.noise_floor_db = registry
+            .noise_profile(device_id)
+            .await
+            .map(|p| p.noise_floor_db);
+    }
+    session.config.modalities = Some(vec![Modality::Text, Modality::Audio]);
+    if dictation {
+        session.transcription_only = true;
+        session.dictation = Some(Dictation::default());
+    }
+    log::info!("device session `{}` connected", session.id);
+    let session_handle = config
+        .sessions
+        .open(session.id.clone(), "device", device_id.clone());
+    config.sessions.set_tenant(&session.id, tenant);
+    let mut inbox = session_handle.inbox();
+    config
+        .sessions
+        .set_config(&session.id, realtime_ws::session_info(&session, &config));
+
+    if sender
+        .send(Message::Binary(encode_state(DeviceState::Listening)))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let mut receiver = realtime_ws::spawn_socket_reader(
+        receiver,
+        session.chat_session.abort_handle.clone(),
+        |msg| matches!(msg, Message::Binary(data) if data.first() == Some(&opcode::CANCEL)),
+        config.stream.event_channel_capacity,
+    );
+
+    let send_task = tokio::spawn(async move {
+        let mut speaking = false;
+        while let Some(event) = rx.recv().await {
+            let frames = match event_to_frames(event, &mut speaking).await {
+                Ok(frames) => frames,
+                Err(e) => {
+                    log::error!("device frame encode error: {e}");
+                    continue;
+                }
+            };
+            for frame in frames {
+                if sender.send(Message::Binary(frame)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut response = realtime_ws::ResponseTask::default();
+    session.duplex = true;
+    loop {
+        response.start_requested(&mut session, &tx, &config).await;
+        let r = tokio::select! {
+            done = response.done() => session.attach_response(&tx, done).await,
+            msg = receiver.recv() => {
+                let data = match msg {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+
+                config.sessions.touch(&session.id);
+                match DeviceFrame::decode(data) {
+                    Ok(frame) => {
+                        handle_frame(frame, device_id.as_deref(), &mut session, &tx, &config).await
+                    }
+                    Err(e) => {
+                        log::warn!("device session `{}` invalid frame: {e}", session.id);
+                        continue;
+                    }
+                }
+            }
+            event = session.next_vad_event() => {
+                realtime_ws::handle_vad_event(event, &mut session, &tx, &config).await
+            }
+            Some(message) = inbox.recv() => {
+                realtime_ws::handle_injected_message(message, &mut session, &tx, &config).await
+            }
+        };
+
+        if let Err(e) = r {
+            log::error!("device session `{}` error: {e}", session.id);
+            let _ = tx
+                .send(ServerEvent::Error {
+                    event_id: uuid::Uuid::new_v4().to_string(),
+                    error: crate::ai::openai::realtime::ErrorDetails {
+                        error_type: "server_error".to_string(),
+                        code: None,
+                        message: e.to_string(),
+                        param: None,
+                        event_id: None,
+                    },
+                })
+                .await;
+        }
+    }
+    response.finish(&mut session, &tx).await;
+
+    drop(tx);
+    if let Err(e) = send_task.await {
+        log::error!("device send task error: {e}");
+    }
+    log::info!("device session `{}` disconnected", session.id);
+}
+
+#[test]
+fn test_device_frame() {
+    let frame = DeviceFrame::decode(Bytes::from_static(&[opcode::AUDIO, 1, 2, 3, 4])).unwrap();
+    assert_eq!(frame, DeviceFrame::Audio(Bytes::from_static(&[1, 2, 3, 4])));
+    assert_eq!(
+        DeviceFrame::decode(Bytes::from_static(&[opcode::COMMIT])).unwrap(),
+        DeviceFrame::Commit
+    );
+    assert_eq!(
+        DeviceFrame::decode(Bytes::from_static(&[opcode::MODE, 1])).unwrap(),
+        DeviceFrame::Mode(DeviceMode::Continuous)
+    );
+    assert!(DeviceFrame::decode(Bytes::from_static(&[opcode::MODE])).is_err());
+    assert_eq!(
+        DeviceFrame::decode(Bytes::from_static(&[opcode::SPEECH_HINT, 1])).unwrap(),
+        DeviceFrame::SpeechHint(SpeechHint::SpeechStop)
+    );
+    assert_eq!(
+        DeviceFrame::decode(Bytes::from_static(&[opcode::CAMERA_FRAME, 0xff, 0xd8])).unwrap(),
+        DeviceFrame::CameraFrame(Bytes::from_static(&[0xff, 0xd8]))
+    );
+    assert!(DeviceFrame::decode(Bytes::from_static(&[opcode::CAMERA_FRAME])).is_err());
+    let frame = DeviceFrame::decode(Bytes::from_static(b"\x06{\"battery\":42}")).unwrap();
+    assert!(matches!(
+        frame,
+        DeviceFrame::Telemetry(Telemetry {
+            battery: Some(42),
+            ..
+        })
+    ));
+    assert!(DeviceFrame::decode(Bytes::new()).is_err());
+    assert!(DeviceFrame::decode(Bytes::from_static(&[0x7f])).is_err());
+
+    assert_eq!(
+        encode_state(DeviceState::Speaking).as_ref(),
+        &[opcode::STATE, 3]
+    );
+    assert_eq!(encode_frame(opcode::TEXT, b"hi").as_ref(), b"\x84hi");
+}
@@ -0,0 +1,220 @@
+//! Dictation: the transcripts of the committed audio are written into a document, the
+//! spoken punctuation and editing commands are applied instead of written.
+//!
+//! a realtime session dictates with `session.update` `{"type": "dictation"}`, a device of
+//! a profile with `dictation = true` on `/device/ws`. the audio is never answered:
+//!
+//! - `逗号` `句号` `问号` `感叹号` `冒号` `分号` are the punctuation, `换行` and `新段落`
+//!   break the line
+//! - an utterance of `删除上一句` deletes the last sentence, `撤销` undoes the last edit,
+//!   `清空` clears the document and `完成听写` ends it
+//!
+//! each edit is sent as `dictation.text.delta`: `delete` chars removed from the end of the
+//! document, then `delta` appended. the ended document, or the one in progress when the
+//! session stops dictating, is sent as a `conversation.item.created` of its own.
+
+use crate::{
+    ai::openai::realtime::{ContentPart, ConversationItem, ServerEvent},
+    util::new_uuid,
+};
+
+/// spoken punctuation, written in place of the words
+const PUNCTUATION: &[(&str, &str)] = &[
+    ("新段落", "\n\n"),
+    ("换行", "\n"),
+    ("逗号", "，"),
+    ("句号", "。"),
+    ("问号", "？"),
+    ("感叹号", "！"),
+    ("冒号", "："),
+    ("分号", "；"),
+];
+
+/// the end of a sentence, for `删除上一句`
+const SENTENCE_END: &[char] = &['。', '？', '！', '.', '?', '!', '\n'];
+
+/// what the asr may put around a spoken punctuation or a command
+const ASR_PUNCTUATION: &[char] = &[
+    '，', '。', '？', '！', '、', ',', '.', '?', '!', ' ', '：', '；',
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    DeleteSentence,
+    Undo,
+    Clear,
+    Finish,
+}
+
+fn command(utterance: &str) -> Option<Command> {
+    match utterance.trim_matches(ASR_PUNCTUATION) {
+        "删除上一句" | "删掉上一句" => Some(Command::DeleteSentence),
+        "撤销" | "撤回" => Some(Command::Undo),
+        "清空" => Some(Command::Clear),
+        "完成听写" | "结束听写" => Some(Command::Finish),
+        _ => None,
+    }
+}
+
+/// `transcript` with the spoken punctuation written, the punctuation the asr put
+/// around it dropped
+pub fn write_punctuation(transcript: &str) -> String {
+    let mut out = String::with_capacity(transcript.len());
+    let mut rest = transcript;
+    while !rest.is_empty() {
+        let spoken = PUNCTUATION.iter().find(|(word, _)| rest.starts_with(word));
+        match spoken {
+            Some((word, mark)) => {
+                let kept = out.trim_end_matches(ASR_PUNCTUATION).len();
+                out.truncate(kept);
+                out.push_str(mark);
+                rest = rest[word.len()..].trim_start_matches(ASR_PUNCTUATION);
+            }
+            None => {
+                let c = rest.chars().next().unwrap();
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    out
+}
+
+/// `text` without its last sentence
+fn delete_sentence(text: &str) -> &str {
+    let body = text.trim_end_matches(|c: char| SENTENCE_END.contains(&c) || c.is_whitespace());
+    match body.rfind(SENTENCE_END) {
+        Some(i) => &text[..i + body[i..].chars().next().map_or(0, char::len_utf8)],
+        None => "",
+    }
+}
+
+/// the document being dictated
+#[derive(Debug, Clone)]
+pub struct Dictation {
+    pub item_id: String,
+    pub text: String,
+    /// the text before each edit, for `撤销`
+    undo: Vec<String>,
+}
+
+impl Default for Dictation {
+    fn default() -> Self {
+        Self {
+            item_id: new_uuid().to_string(),
+            text: String::new(),
+            undo: vec![],
+        }
+    }
+}
+
+impl Dictation {
+    /// apply the transcript of an utterance, the events of the edit
+    pub fn push(&mut self, transcript: &str) -> Vec<ServerEvent> {
+        if transcript.trim().is_empty() {
+            return vec![];
+        }
+        let text = match command(transcript) {
+            Some(Command::Finish) => return self.finish().into_iter().collect(),
+            Some(Command::Undo) => match self.undo.pop() {
+                Some(text) => text,
+                None => return vec![],
+            },
+            Some(Command::DeleteSentence) => {
+                let text = delete_sentence(&self.text).to_string();
+                self.undo.push(self.text.clone());
+                text
+            }
+            Some(Command::Clear) => {
+                self.undo.push(self.text.clone());
+                String::new()
+            }
+            None => {
+                self.undo.push(self.text.clone());
+                format!("{}{}", self.text, write_punctuation(transcript.trim()))
+            }
+        };
+        let delta = self.set_text(text);
+        delta.into_iter().collect()
+    }
+
+    /// `text` is the document, the `dictation.text.delta` from the previous one if it changed
+    fn set_text(&mut self, text: String) -> Option<ServerEvent> {
+        let common = self
+            .text
+            .chars()
+            .zip(text.chars())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let delete = self.text.chars().count() - common;
+        let delta: String = text.chars().skip(common).collect();
+        self.text = text;
+        (delete > 0 || !delta.is_empty()).then(|| ServerEvent::DictationTextDelta {
+            event_id: new_uuid().to_string(),
+            item_id: self.item_id.clone(),
+            delete,
+            delta,
+            text: self.text.clone(),
+        })
+    }
+
+    /// the document as an item, a new one is started, nothing if it is empty
+    pub fn finish(&mut self) -> Option<ServerEvent> {
+        let done = std::mem::take(self);
+        if done.text.is_empty() {
+            return None;
+        }
+        let item = ConversationItem {
+            id: Some(done.item_id),
+            object: Some("realtime.item".to_string()),
+            item_type: "message".to_string(),
+            status: Some("completed".to_string()),
+            role: Some("user".to_string()),
+            content: Some(vec![ContentPart::InputText { text: done.text }]),
+            call_id: None,
+            name: None,
+            arguments: None,
+            output: None,
+        };
+        Some(ServerEvent::ConversationItemCreated {
+            event_id: new_uuid().to_string(),
+            previous_item_id: None,
+            item,
+        })
+    }
+}
+
+#[test]
+fn test_dictation() {
+    assert_eq!(
+        write_punctuation("今天天气很好，逗号，我们去公园吧句号"),
+        "今天天气很好，我们去公园吧。"
+    );
+
+    let mut dictation = Dictation::default();
+    dictation.push("今天天气很好逗号我们去公园吧句号");
+    dictation.push("换行");
+    dictation.push("明天下雨吗问号");
+    assert_eq!(dictation.text, "今天天气很好，我们去公园吧。\n明天下雨吗？");
+
+    let events = dictation.push("删除上一句。");
+    let Some(ServerEvent::DictationTextDelta { delete, delta, .. }) = events.first() else {
+        panic!("no delta: {events:?}");
+    };
+    assert_eq!((*delete, delta.as_str()), (6, ""));
+    assert_eq!(dictation.text, "今天天气很好，我们去公园吧。\n");
+    dictation.push("撤销");
+    assert_eq!(dictation.text, "今天天气很好，我们去公园吧。\n明天下雨吗？");
+    dictation.push("清空");
+    assert!(dictation.text.is_empty());
+    dictation.push("撤销");
+
+    let item_id = dictation.item_id.clone();
+    let events = dictation.push("完成听写");
+    let Some(ServerEvent::ConversationItemCreated { item, .. }) = events.first() else {
+        panic!("no item: {events:?}");
+    };
+    assert_eq!(item.id.as_ref(), Some(&item_id));
+    assert!(dictation.text.is_empty());
+    assert_ne!(dictation.item_id, item_id);
+}
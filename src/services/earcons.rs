@@ -0,0 +1,146 @@
+//! Earcons: the short tones played on their own, when the wake word is detected, when
+//! the server starts listening and before the error phrase.
+//!
+//! `[earcons]` and `[profiles.x.earcons]` give a wav file for each of them, the files
+//! are read and resampled to the output rate at startup. on `/ws/{id}` an earcon is sent
+//! as an audio response of its own, on the realtime endpoints as `response.audio.delta`
+//! events of the response `earcon`.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::config::{EarconsConfig, ProfileConfig};
+
+/// `response_id` of the audio deltas of an earcon on the realtime endpoints
+pub const EARCON_RESPONSE_ID: &str = "earcon";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Earcon {
+    Wake,
+    Listening,
+    Error,
+}
+
+/// the pcm16 of the earcons of a device
+#[derive(Debug, Clone, Default)]
+pub struct Earcons {
+    pub wake: Option<Bytes>,
+    pub listening: Option<Bytes>,
+    pub error: Option<Bytes>,
+}
+
+impl Earcons {
+    pub fn get(&self, earcon: Earcon) -> Option<Bytes> {
+        match earcon {
+            Earcon::Wake => self.wake.clone(),
+            Earcon::Listening => self.listening.clone(),
+            Earcon::Error => self.error.clone(),
+        }
+    }
+
+    /// the earcons loaded at `in_hz` for a device played at `out_hz`
+    pub fn resampled(self, in_hz: u32, out_hz: u32) -> Earcons {
+        if in_hz == out_hz {
+            return self;
+        }
+        let at = |pcm: Option<Bytes>| {
+            crate::services::ws::resample_pcm16(&pcm?, in_hz, out_hz)
+                .map_err(|e| log::warn!("earcon resample error: {e}"))
+                .ok()
+        };
+        Earcons {
+            wake: at(self.wake),
+            listening: at(self.listening),
+            error: at(self.error),
+        }
+    }
+}
+
+/// the pcm16 of the earcon files at the output rate, by path
+#[derive(Debug, Default)]
+pub struct EarconAudio {
+    audio: HashMap<String, Bytes>,
+}
+
+impl EarconAudio {
+    /// read the earcons of `config` and of each profile, a file that cannot be read is
+    /// skipped with a warning
+    pub fn load(
+        config: &EarconsConfig,
+        profiles: &HashMap<String, ProfileConfig>,
+        out_hz: u32,
+    ) -> Self {
+        let mut audio = HashMap::new();
+        let configs =
+            std::iter::once(config).chain(profiles.values().filter_map(|p| p.earcons.as_ref()));
+        for path in configs.flat_map(EarconsConfig::paths) {
+            if audio.contains_key(path) {
+                continue;
+            }
+            let pcm = std::fs::read(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|wav| crate::util::wav_to_pcm16(Bytes::from(wav), out_hz));
+            match pcm {
+                Ok((pcm, duration)) => {
+                    log::info!("earcon {path}: {}ms", duration.as_millis());
+                    audio.insert(path.to_string(), pcm);
+                }
+                Err(e) => log::warn!("earcon {path} error: {e}"),
+            }
+        }
+        Self { audio }
+    }
+
+    /// the earcons of `config`, already merged with the profile of the device
+    pub fn earcons(&self, config: &EarconsConfig) -> Earcons {
+        let get = |path: &Option<String>| path.as_ref().and_then(|p| self.audio.get(p)).cloned();
+        Earcons {
+            wake: get(&config.wake),
+            listening: get(&config.listening),
+            error: get(&config.error),
+        }
+    }
+}
+
+#[test]
+fn test_earcons() {
+    let dir = std::env::temp_dir().join(format!("echokit-earcons-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let wav = |name: &str, samples: usize| {
+        let pcm = vec![0u8; 2 * samples];
+        let path = dir.join(name);
+        let wav_config = crate::util::WavConfig {
+            sample_rate: 16000,
+            ..Default::default()
+        };
+        std::fs::write(&path, crate::util::pcm_to_wav(&pcm, wav_config)).unwrap();
+        path.to_string_lossy().to_string()
+    };
+    let config = EarconsConfig {
+        wake: Some(wav("wake.wav", 1600)),
+        listening: Some(dir.join("missing.wav").to_string_lossy().to_string()),
+        error: None,
+    };
+    let kids = ProfileConfig {
+        earcons: Some(EarconsConfig {
+            error: Some(wav("error.wav", 800)),
+            wake: Some(String::new()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let profiles = HashMap::from([("kids".to_string(), kids.clone())]);
+
+    let audio = EarconAudio::load(&config, &profiles, 16000);
+    let earcons = audio.earcons(&config);
+    assert_eq!(earcons.get(Earcon::Wake).map(|a| a.len()), Some(3200));
+    // the file could not be read
+    assert!(earcons.listening.is_none());
+    assert!(earcons.error.is_none());
+
+    let earcons = audio.earcons(&config.with_profile(Some(&kids)));
+    assert!(earcons.wake.is_none());
+    assert_eq!(earcons.get(Earcon::Error).map(|a| a.len()), Some(1600));
+    let _ = std::fs::remove_dir_all(dir);
+}
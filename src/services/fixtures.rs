@@ -0,0 +1,182 @@
+//! Golden transcript fixtures: each directory under `resources/fixtures/<name>/` holds an
+//! `audio.wav` and an `expected.toml`, the audio is streamed to a realtime session with
+//! the mock providers and the response is compared to the expected.
+//! the mock asr does not listen to the audio, its transcript is scripted: a fixture checks
+//! that the audio is committed and transcribed, not what the transcript is.
+//!
+//! ```toml
+//! # what the mock asr returns for the audio
+//! transcript = "What's the weather?"
+//! # what the mock llm answers, in order for each turn, none echoes the transcript
+//! replies = ["Sunny, 25 degrees."]
+//! # the response text expected, the first reply or `You said: <transcript>` if unset
+//! response = "Sunny, 25 degrees."
+//! ```
+//!
+//! a new case is a new directory, `test_fixtures` runs them all.
+
+use base64::Engine;
+
+use crate::{
+    ai::openai::realtime::ServerEvent,
+    config::MockASRConfig,
+    services::{
+        realtime_capture::{self, Capture, CaptureLine},
+        realtime_ws::StableRealtimeConfig,
+    },
+};
+
+/// the realtime sessions expect 24k pcm16 by default
+const SAMPLE_RATE: u32 = 24000;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Expected {
+    pub transcript: String,
+    #[serde(default)]
+    pub replies: Vec<String>,
+    #[serde(default)]
+    pub response: Option<String>,
+}
+
+impl Expected {
+    pub fn response(&self) -> String {
+        self.response
+            .clone()
+            .or_else(|| self.replies.first().cloned())
+            .unwrap_or_else(|| format!("You said: {}", self.transcript))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    pub name: String,
+    /// pcm16 at `SAMPLE_RATE`
+    pub pcm: bytes::Bytes,
+    pub expected: Expected,
+}
+
+/// what a session made of the audio of a fixture
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Outcome {
+    pub transcript: String,
+    pub response: String,
+}
+
+/// the fixtures of the directories of `dir`, sorted by name
+pub fn load_fixtures(dir: &str) -> anyhow::Result<Vec<Fixture>> {
+    let mut fixtures = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let wav = std::fs::read(path.join("audio.wav"))
+            .map_err(|e| anyhow::anyhow!("fixture {name}: audio.wav: {e}"))?;
+        let (pcm, _) = crate::util::wav_to_pcm16(wav.into(), SAMPLE_RATE)
+            .map_err(|e| anyhow::anyhow!("fixture {name}: audio.wav: {e}"))?;
+        let expected = std::fs::read_to_string(path.join("expected.toml"))
+            .map_err(|e| anyhow::anyhow!("fixture {name}: expected.toml: {e}"))?;
+        let expected = toml::from_str(&expected)
+            .map_err(|e| anyhow::anyhow!("fixture {name}: expected.toml: {e}"))?;
+        fixtures.push(Fixture {
+            name,
+            pcm,
+            expected,
+        });
+    }
+    fixtures.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(fixtures)
+}
+
+/// the mock providers scripted by the expected of the fixture
+fn mock_config(expected: &Expected) -> StableRealtimeConfig {
+    StableRealtimeConfig::mock(
+        expected.replies.clone(),
+        MockASRConfig {
+            transcripts: vec![expected.transcript.clone()],
+            error: None,
+            next: Default::default(),
+        },
+    )
+}
+
+impl Fixture {
+    /// the audio appended in chunks of 100ms then committed, as a client would
+    fn capture(&self) -> Capture {
+        let client = |event: serde_json::Value| CaptureLine::Client {
+            at_ms: 0,
+            event: serde_json::from_value(event).unwrap(),
+        };
+        let mut lines = vec![client(serde_json::json!({
+            "type": "session.update",
+            "session": { "modalities": ["text", "audio"] }
+        }))];
+        for chunk in self.pcm.chunks(SAMPLE_RATE as usize * 2 / 10) {
+            let audio = base64::prelude::BASE64_STANDARD.encode(chunk);
+            lines.push(client(serde_json::json!({
+                "type": "input_audio_buffer.append",
+                "audio": audio,
+            })));
+        }
+        lines.push(client(
+            serde_json::json!({ "type": "input_audio_buffer.commit" }),
+        ));
+        Capture {
+            text_only: false,
+            lines,
+        }
+    }
+
+    pub async fn run(&self) -> anyhow::Result<Outcome> {
+        let events =
+            realtime_capture::replay_events(&self.capture(), &mock_config(&self.expected)).await?;
+        let mut outcome = Outcome::default();
+        for event in events {
+            match event {
+                ServerEvent::ConversationItemInputAudioTranscriptionCompleted {
+                    transcript,
+                    ..
+                } => outcome.transcript = transcript,
+                ServerEvent::ResponseTextDone { text, .. } => outcome.response = text,
+                ServerEvent::Error { error, .. } => {
+                    anyhow::bail!("fixture {}: error event: {}", self.name, error.message)
+                }
+                _ => {}
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// the differences with the expected, empty if none
+    pub async fn check(&self) -> anyhow::Result<Vec<String>> {
+        let outcome = self.run().await?;
+        let mut diffs = vec![];
+        if outcome.transcript.is_empty() {
+            diffs.push(format!("{}: the audio was not transcribed", self.name));
+        }
+        let response = self.expected.response();
+        if outcome.response != response {
+            diffs.push(format!(
+                "{}: response {:?}, expected {:?}",
+                self.name, outcome.response, response
+            ));
+        }
+        Ok(diffs)
+    }
+}
+
+#[tokio::test]
+async fn test_fixtures() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/fixtures");
+    let fixtures = load_fixtures(dir).unwrap();
+    assert!(!fixtures.is_empty());
+    let mut diffs = vec![];
+    for fixture in &fixtures {
+        diffs.extend(fixture.check().await.unwrap());
+    }
+    assert!(diffs.is_empty(), "{}", diffs.join("\n"));
+}
@@ -0,0 +1,514 @@
+//! Transcripts of the sessions, kept in the storage when `[history]` is configured.
+//!
+//! admin (bearer `admin_token`):
+//! - `GET /v1/history/search?q=...&device=...&context=2&limit=20` turns containing all the
+//!   words of `q` (case insensitive), newest first, with the turns around them in the
+//!   same session
+//! - `POST /v1/history/annotations` `{"session_id", "turn_time", "label", "note"}` tag a
+//!   turn, `bad_asr`, `wrong_answer`..., without `turn_time` the last answer of the session
+//! - `GET /v1/history/annotations?label=...&device=...&context=1` the annotated turns,
+//!   newest first, with the turns before them in the same session
+//! - `DELETE /v1/history/annotations/{id}`
+//!
+//! a device tags the last answer with text `Feedback:{"label": "thumbs_up"}` on `/ws/{id}`.
+//!
+//! the turns and the sessions are written in the background, in order.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    ai::llm::Role,
+    storage::{Annotation, SessionRecord, Storage, Store, Turn},
+};
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub turn: Turn,
+    /// turns of the same session before the match, oldest first
+    pub before: Vec<Turn>,
+    /// turns of the same session after the match
+    pub after: Vec<Turn>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AnnotatedTurn {
+    #[serde(flatten)]
+    pub annotation: Annotation,
+    /// none once the turn is purged
+    pub turn: Option<Turn>,
+    /// turns of the same session before it, oldest first
+    pub before: Vec<Turn>,
+}
+
+/// the body of `POST /v1/history/annotations`, and of a `Feedback:` of a device
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct AnnotationRequest {
+    #[serde(default)]
+    pub session_id: String,
+    #[serde(default)]
+    pub turn_time: Option<String>,
+    pub label: String,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// short words: letters, digits, `_`, `-` and `.`
+fn valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 64
+        && label
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// the turn at `turn_time` in the session, or its last answer
+fn find_turn<'a>(turns: &'a [Turn], session_id: &str, turn_time: Option<&str>) -> Option<&'a Turn> {
+    let mut session = turns.iter().rev().filter(|t| t.session_id == session_id);
+    match turn_time {
+        Some(time) => session.find(|t| t.time == time),
+        None => session.find(|t| t.role == Role::Assistant),
+    }
+}
+
+#[derive(Debug)]
+enum Write {
+    Session(SessionRecord),
+    Turn(Turn),
+}
+
+#[derive(Debug)]
+pub struct History {
+    admin_token: Option<String>,
+    store: Arc<Storage>,
+    tx: mpsc::UnboundedSender<Write>,
+}
+
+impl History {
+    /// spawns the writer to `store`
+    pub fn new(store: Arc<Storage>, admin_token: Option<String>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Write>();
+        let writer = store.clone();
+        tokio::spawn(async move {
+            while let Some(write) = rx.recv().await {
+                let r = match &write {
+                    Write::Session(session) => writer.put_session(session).await,
+                    Write::Turn(turn) => writer.append_turn(turn).await,
+                };
+                if let Err(e) = r {
+                    log::error!("history write error: {e}");
+                }
+            }
+        });
+        Self {
+            admin_token,
+            store,
+            tx,
+        }
+    }
+
+    pub fn record(&self, session_id: &str, device_id: Option<String>, role: Role, text: &str) {
+        if text.trim().is_empty() {
+            return;
+        }
+        let _ = self.tx.send(Write::Turn(Turn {
+            session_id: session_id.to_string(),
+            device_id,
+            time: chrono::Local::now().to_rfc3339(),
+            role,
+            text: text.to_string(),
+        }));
+    }
+
+    /// the turns of a device since `since`, oldest first
+    pub async fn turns_since(
+        &self,
+        device_id: &str,
+        since: &chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Vec<Turn>> {
+        let mut turns = self.store.turns(Some(device_id)).await?;
+        turns.retain(|turn| {
+            chrono::DateTime::parse_from_rfc3339(&turn.time).is_ok_and(|time| time >= *since)
+        });
+        Ok(turns)
+    }
+
+    /// a session opened, or ended with `ended_at`
+    pub fn record_session(&self, session: SessionRecord) {
+        let _ = self.tx.send(Write::Session(session));
+    }
+
+    /// none if the session has no such turn, `device_id` narrows the search
+    pub async fn annotate(
+        &self,
+        request: AnnotationRequest,
+        device_id: Option<&str>,
+        source: &str,
+    ) -> anyhow::Result<Option<Annotation>> {
+        if !valid_label(&request.label) {
+            anyhow::bail!("bad label `{}`", request.label);
+        }
+        let turns = self.store.turns(device_id).await?;
+        let Some(turn) = find_turn(&turns, &request.session_id, request.turn_time.as_deref())
+        else {
+            return Ok(None);
+        };
+        let annotation = Annotation {
+            id: uuid::Uuid::new_v4().to_string(),
+            session_id: turn.session_id.clone(),
+            device_id: turn.device_id.clone(),
+            turn_time: turn.time.clone(),
+            label: request.label,
+            note: request.note.filter(|note| !note.trim().is_empty()),
+            source: source.to_string(),
+            created_at: chrono::Local::now().to_rfc3339(),
+        };
+        self.store.add_annotation(&annotation).await?;
+        Ok(Some(annotation))
+    }
+
+    /// tag the last answer of a session in the background, a `Feedback:` of a device
+    pub fn feedback(
+        self: &Arc<Self>,
+        session_id: &str,
+        device_id: Option<String>,
+        request: AnnotationRequest,
+    ) {
+        let history = self.clone();
+        let request = AnnotationRequest {
+            session_id: session_id.to_string(),
+            ..request
+        };
+        tokio::spawn(async move {
+            match history
+                .annotate(request, device_id.as_deref(), "client")
+                .await
+            {
+                Ok(Some(annotation)) => log::info!("feedback: {annotation:?}"),
+                Ok(None) => log::warn!("feedback on a session without answers"),
+                Err(e) => log::warn!("feedback error: {e}"),
+            }
+        });
+    }
+}
+
+/// the annotations with `label` joined with their turns, newest first
+pub fn annotated(
+    turns: &[Turn],
+    annotations: Vec<Annotation>,
+    label: Option<&str>,
+    context: usize,
+    limit: usize,
+) -> Vec<AnnotatedTurn> {
+    annotations
+        .into_iter()
+        .rev()
+        .filter(|a| label.is_none_or(|label| a.label == label))
+        .take(limit)
+        .map(|annotation| {
+            let i = turns.iter().position(|t| {
+                t.session_id == annotation.session_id && t.time == annotation.turn_time
+            });
+            let before = match i {
+                Some(i) => {
+                    let mut before = turns[..i]
+                        .iter()
+                        .rev()
+                        .filter(|t| t.session_id == annotation.session_id)
+                        .take(context)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    before.reverse();
+                    before
+                }
+                None => vec![],
+            };
+            AnnotatedTurn {
+                turn: i.map(|i| turns[i].clone()),
+                before,
+                annotation,
+            }
+        })
+        .collect()
+}
+
+/// turns containing all the words of `q`, newest first
+pub fn search(
+    turns: &[Turn],
+    q: &str,
+    device: Option<&str>,
+    context: usize,
+    limit: usize,
+) -> Vec<SearchHit> {
+    let words = q
+        .split_whitespace()
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return vec![];
+    }
+    let same_session = |i: &usize, turn: &Turn| turns[*i].session_id == turn.session_id;
+
+    let mut hits = vec![];
+    for (i, turn) in turns.iter().enumerate().rev() {
+        if hits.len() >= limit {
+            break;
+        }
+        if device.is_some_and(|device| turn.device_id.as_deref() != Some(device)) {
+            continue;
+        }
+        let text = turn.text.to_lowercase();
+        if !words.iter().all(|w| text.contains(w.as_str())) {
+            continue;
+        }
+        let mut before = (0..i)
+            .rev()
+            .filter(|j| same_session(j, turn))
+            .take(context)
+            .map(|j| turns[j].clone())
+            .collect::<Vec<_>>();
+        before.reverse();
+        let after = (i + 1..turns.len())
+            .filter(|j| same_session(j, turn))
+            .take(context)
+            .map(|j| turns[j].clone())
+            .collect();
+        hits.push(SearchHit {
+            turn: turn.clone(),
+            before,
+            after,
+        });
+    }
+    hits
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SearchParams {
+    q: String,
+    #[serde(default)]
+    device: Option<String>,
+    #[serde(default = "SearchParams::default_context")]
+    context: usize,
+    #[serde(default = "SearchParams::default_limit")]
+    limit: usize,
+}
+
+impl SearchParams {
+    fn default_context() -> usize {
+        2
+    }
+
+    fn default_limit() -> usize {
+        20
+    }
+}
+
+/// GET /v1/history/search
+async fn search_history(
+    Extension(history): Extension<Arc<History>>,
+    headers: HeaderMap,
+    Query(params): Query<SearchParams>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &history.admin_token) {
+        return code.into_response();
+    }
+    if params.q.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty q").into_response();
+    }
+    let turns = match history.store.turns(params.device.as_deref()).await {
+        Ok(turns) => turns,
+        Err(e) => {
+            log::error!("history read error: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    let hits = search(
+        &turns,
+        &params.q,
+        params.device.as_deref(),
+        params.context.min(20),
+        params.limit.min(200),
+    );
+    Json(hits).into_response()
+}
+
+/// POST /v1/history/annotations
+async fn add_annotation(
+    Extension(history): Extension<Arc<History>>,
+    headers: HeaderMap,
+    Json(request): Json<AnnotationRequest>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &history.admin_token) {
+        return code.into_response();
+    }
+    match history.annotate(request, None, "admin").await {
+        Ok(Some(annotation)) => (StatusCode::CREATED, Json(annotation)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "no such turn").into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AnnotationsParams {
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    device: Option<String>,
+    #[serde(default = "AnnotationsParams::default_context")]
+    context: usize,
+    #[serde(default = "SearchParams::default_limit")]
+    limit: usize,
+}
+
+impl AnnotationsParams {
+    fn default_context() -> usize {
+        1
+    }
+}
+
+/// GET /v1/history/annotations
+async fn list_annotations(
+    Extension(history): Extension<Arc<History>>,
+    headers: HeaderMap,
+    Query(params): Query<AnnotationsParams>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &history.admin_token) {
+        return code.into_response();
+    }
+    let device = params.device.as_deref();
+    let read = async {
+        let annotations = history.store.annotations(device).await?;
+        let turns = history.store.turns(device).await?;
+        anyhow::Ok((annotations, turns))
+    };
+    match read.await {
+        Ok((annotations, turns)) => Json(annotated(
+            &turns,
+            annotations,
+            params.label.as_deref(),
+            params.context.min(20),
+            params.limit.min(1000),
+        ))
+        .into_response(),
+        Err(e) => {
+            log::error!("history read error: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+/// DELETE /v1/history/annotations/{id}
+async fn delete_annotation(
+    Extension(history): Extension<Arc<History>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &history.admin_token) {
+        return code.into_response();
+    }
+    match history.store.delete_annotation(&id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+pub fn new_history_service(history: Arc<History>) -> Router {
+    Router::new()
+        .route("/v1/history/search", get(search_history))
+        .route(
+            "/v1/history/annotations",
+            get(list_annotations).post(add_annotation),
+        )
+        .route("/v1/history/annotations/{id}", delete(delete_annotation))
+        .layer(Extension(history))
+}
+
+#[test]
+fn test_history_search() {
+    let turn = |session_id: &str, device_id: Option<&str>, role: Role, text: &str| Turn {
+        session_id: session_id.to_string(),
+        device_id: device_id.map(str::to_string),
+        time: String::new(),
+        role,
+        text: text.to_string(),
+    };
+    let turns = [
+        turn("dev1", Some("dev1"), Role::User, "明天开会吗"),
+        turn(
+            "dev1",
+            Some("dev1"),
+            Role::Assistant,
+            "明天上午十点 Team meeting",
+        ),
+        turn("abc", None, Role::User, "team lunch is on friday"),
+        turn("dev1", Some("dev1"), Role::User, "谢谢"),
+        turn("dev2", Some("dev2"), Role::User, "提醒我开会"),
+    ];
+
+    let hits = search(&turns, "TEAM", None, 2, 20);
+    assert_eq!(hits.len(), 2);
+    assert_eq!(hits[0].turn.session_id, "abc");
+    assert!(hits[0].before.is_empty() && hits[0].after.is_empty());
+    // the context stays in the session of the match
+    assert_eq!(hits[1].before, turns[..1]);
+    assert_eq!(hits[1].after, turns[3..4]);
+
+    let hits = search(&turns, "开会", Some("dev1"), 0, 20);
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].turn.text, "明天开会吗");
+    assert_eq!(search(&turns, "team friday", None, 2, 20).len(), 1);
+    assert!(search(&turns, "  ", None, 2, 20).is_empty());
+    assert_eq!(search(&turns, "开会", None, 2, 1).len(), 1);
+}
+
+#[test]
+fn test_annotated() {
+    let turn = |role: Role, time: &str, text: &str| Turn {
+        session_id: "dev1".to_string(),
+        device_id: Some("dev1".to_string()),
+        time: time.to_string(),
+        role,
+        text: text.to_string(),
+    };
+    let turns = [
+        turn(Role::User, "1", "几点了"),
+        turn(Role::Assistant, "2", "十点"),
+        turn(Role::User, "3", "谢谢"),
+    ];
+    assert_eq!(find_turn(&turns, "dev1", None), Some(&turns[1]));
+    assert_eq!(find_turn(&turns, "dev1", Some("3")), Some(&turns[2]));
+    assert_eq!(find_turn(&turns, "dev2", None), None);
+    assert!(valid_label("wrong_answer") && !valid_label("a b") && !valid_label(""));
+
+    let annotation = |id: &str, turn_time: &str, label: &str| Annotation {
+        id: id.to_string(),
+        session_id: "dev1".to_string(),
+        device_id: Some("dev1".to_string()),
+        turn_time: turn_time.to_string(),
+        label: label.to_string(),
+        note: None,
+        source: "client".to_string(),
+        created_at: String::new(),
+    };
+    let annotations = vec![
+        annotation("a1", "2", "thumbs_down"),
+        annotation("a2", "9", "bad_asr"),
+    ];
+    let hits = annotated(&turns, annotations.clone(), None, 1, 20);
+    assert_eq!(hits.len(), 2);
+    assert_eq!(hits[0].annotation.id, "a2");
+    assert!(hits[0].turn.is_none());
+    let hits = annotated(&turns, annotations, Some("thumbs_down"), 1, 20);
+    assert_eq!(hits[0].turn.as_ref(), Some(&turns[1]));
+    assert_eq!(hits[0].before, turns[..1]);
+}
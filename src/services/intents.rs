@@ -0,0 +1,249 @@
+//! The intent router: simple commands ("几点了", "音量调大") answered by a built-in
+//! handler before the llm is asked, which saves its latency and tokens.
+//!
+//! ```toml
+//! [[intents.rules]]
+//! name = "time"
+//! action = "time"
+//! keywords = ["几点了", "现在几点"]
+//!
+//! [[intents.rules]]
+//! name = "louder"
+//! action = "volume_up"
+//! patterns = ["^(把)?音量调大"]
+//! examples = ["turn it up"]
+//! ```
+//!
+//! a rule matches on a keyword the utterance contains, a regex, or an example close enough
+//! by embedding when `[vectors]` is set. the matched command is sent as `intent.matched`
+//! on the realtime endpoints, then its templated reply is the response and is spoken.
+
+use std::collections::HashMap;
+
+use chrono::Datelike;
+use regex::Regex;
+use tokio::sync::OnceCell;
+
+use crate::{
+    ai::store::{cosine, Embedder},
+    config::{IntentAction, IntentRule, IntentsConfig, VectorsConfig},
+};
+
+const DEFAULT_THRESHOLD: f32 = 0.85;
+
+impl IntentAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntentAction::Reply => "reply",
+            IntentAction::Time => "time",
+            IntentAction::Date => "date",
+            IntentAction::VolumeUp => "volume_up",
+            IntentAction::VolumeDown => "volume_down",
+            IntentAction::Mute => "mute",
+            IntentAction::Unmute => "unmute",
+        }
+    }
+
+    fn default_reply(&self) -> &'static str {
+        match self {
+            IntentAction::Reply => "",
+            IntentAction::Time => "现在是{time}",
+            IntentAction::Date => "今天是{date}，{weekday}",
+            IntentAction::VolumeUp => "好的，音量调大了",
+            IntentAction::VolumeDown => "好的，音量调小了",
+            IntentAction::Mute => "好的，已静音",
+            IntentAction::Unmute => "好的，已取消静音",
+        }
+    }
+}
+
+/// a command the router recognized
+#[derive(Debug, Clone, PartialEq)]
+pub struct Intent {
+    pub name: String,
+    pub action: IntentAction,
+    pub reply: String,
+}
+
+#[derive(Debug)]
+struct Rule {
+    rule: IntentRule,
+    patterns: Vec<Regex>,
+}
+
+#[derive(Debug, Default)]
+pub struct IntentRouter {
+    rules: Vec<Rule>,
+    threshold: f32,
+    embedder: Option<Embedder>,
+    /// (rule, vector) of the examples, embedded on the first utterance
+    examples: OnceCell<Vec<(usize, Vec<f32>)>>,
+}
+
+impl IntentRouter {
+    /// a rule with an invalid regex is skipped with a warning, the examples are ignored
+    /// without `[vectors]`
+    pub fn new(config: &IntentsConfig, vectors: Option<&VectorsConfig>) -> Self {
+        let mut rules = vec![];
+        for rule in &config.rules {
+            let patterns = rule
+                .patterns
+                .iter()
+                .map(|p| Regex::new(p))
+                .collect::<Result<Vec<_>, _>>();
+            match patterns {
+                Ok(_) if rule.action == IntentAction::Reply && rule.reply.is_none() => {
+                    log::warn!("intent {} skipped: no reply", rule.name)
+                }
+                Ok(patterns) => rules.push(Rule {
+                    rule: rule.clone(),
+                    patterns,
+                }),
+                Err(e) => log::warn!("intent {} skipped: {e}", rule.name),
+            }
+        }
+        let has_examples = rules.iter().any(|r| !r.rule.examples.is_empty());
+        if has_examples && vectors.is_none() {
+            log::warn!("intent examples ignored, `[vectors]` is not set");
+        }
+        Self {
+            rules,
+            threshold: config.threshold.unwrap_or(DEFAULT_THRESHOLD),
+            embedder: vectors.filter(|_| has_examples).map(Embedder::new),
+            examples: OnceCell::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// the intent of `text` with its reply filled in, `None` for the llm to answer
+    pub async fn route(&self, text: &str) -> Option<Intent> {
+        let text = text.trim();
+        if text.is_empty() || self.rules.is_empty() {
+            return None;
+        }
+        let lower = text.to_lowercase();
+        for rule in &self.rules {
+            if rule
+                .rule
+                .keywords
+                .iter()
+                .any(|k| lower.contains(&k.to_lowercase()))
+            {
+                return Some(self.intent(&rule.rule, text, HashMap::new()));
+            }
+            for pattern in &rule.patterns {
+                let Some(captures) = pattern.captures(text) else {
+                    continue;
+                };
+                let groups = pattern
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| Some((name, captures.name(name)?.as_str())))
+                    .collect();
+                return Some(self.intent(&rule.rule, text, groups));
+            }
+        }
+        let i = self.nearest_example(text).await?;
+        Some(self.intent(&self.rules[i].rule, text, HashMap::new()))
+    }
+
+    /// the rule of the example closest to `text` over the threshold
+    async fn nearest_example(&self, text: &str) -> Option<usize> {
+        let embedder = self.embedder.as_ref()?;
+        let examples = self
+            .examples
+            .get_or_try_init(|| async {
+                let mut examples = vec![];
+                for (i, rule) in self.rules.iter().enumerate() {
+                    let vectors = embedder.embed(&rule.rule.examples).await?;
+                    examples.extend(vectors.into_iter().map(|v| (i, v)));
+                }
+                anyhow::Ok(examples)
+            })
+            .await
+            .inspect_err(|e| log::warn!("embed intent examples error: {e}"))
+            .ok()?;
+        let vector = embedder
+            .embed_one(text)
+            .await
+            .inspect_err(|e| log::warn!("embed utterance error: {e}"))
+            .ok()?;
+        examples
+            .iter()
+            .map(|(i, v)| (*i, cosine(&vector, v)))
+            .filter(|(_, score)| *score >= self.threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    }
+
+    fn intent(&self, rule: &IntentRule, text: &str, groups: HashMap<&str, &str>) -> Intent {
+        let template = rule.reply.as_deref().unwrap_or(rule.action.default_reply());
+        let now = chrono::Local::now();
+        let mut reply = template
+            .replace("{time}", &now.format("%H:%M").to_string())
+            .replace("{date}", &now.format("%Y-%m-%d").to_string())
+            .replace("{weekday}", weekday(now.weekday()))
+            .replace("{text}", text);
+        for (name, value) in groups {
+            reply = reply.replace(&format!("{{{name}}}"), value);
+        }
+        Intent {
+            name: rule.name.clone(),
+            action: rule.action,
+            reply,
+        }
+    }
+}
+
+pub fn weekday(day: chrono::Weekday) -> &'static str {
+    match day {
+        chrono::Weekday::Mon => "星期一",
+        chrono::Weekday::Tue => "星期二",
+        chrono::Weekday::Wed => "星期三",
+        chrono::Weekday::Thu => "星期四",
+        chrono::Weekday::Fri => "星期五",
+        chrono::Weekday::Sat => "星期六",
+        chrono::Weekday::Sun => "星期日",
+    }
+}
+
+#[tokio::test]
+async fn test_intent_router() {
+    let config: IntentsConfig = toml::from_str(
+        r#"
+        [[rules]]
+        name = "time"
+        action = "time"
+        keywords = ["几点了"]
+
+        [[rules]]
+        name = "weather"
+        patterns = ["^(?P<city>.+)天气怎么样"]
+        reply = "{city}的天气请看窗外"
+
+        [[rules]]
+        name = "broken"
+        patterns = ["("]
+
+        [[rules]]
+        name = "louder"
+        action = "volume_up"
+        examples = ["turn it up"]
+        "#,
+    )
+    .unwrap();
+    let router = IntentRouter::new(&config, None);
+    assert_eq!(router.rules.len(), 3);
+
+    let time = router.route("现在几点了？").await.unwrap();
+    assert_eq!(time.action, IntentAction::Time);
+    assert!(time.reply.starts_with("现在是"));
+    let weather = router.route("北京天气怎么样").await.unwrap();
+    assert_eq!(weather.reply, "北京的天气请看窗外");
+    // the examples need `[vectors]`, the llm answers
+    assert!(router.route("turn it up").await.is_none());
+    assert!(router.route("讲个故事").await.is_none());
+}
@@ -0,0 +1,438 @@
+//! Long-term memory and document rag on the index of `[vectors]`.
+//!
+//! admin (bearer `admin_token`):
+//! - `GET /v1/devices/{id}/memories` the memories of a device, oldest first
+//! - `POST /v1/devices/{id}/memories` `{"text": "..."}` keep a fact about its user
+//! - `DELETE /v1/devices/{id}/memories/{memory_id}`
+//! - `GET /v1/knowledge/search?q=...&device=...` the memories and passages a question recalls
+//!
+//! the memories are kept in the `[storage]` and indexed in the `memories` collection, the
+//! passages of `rag.dir` in `documents`. before a turn of `/ws/{id}` the relevant ones are
+//! given to the llm as a system message.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+
+use crate::{
+    ai::store::{point_id, Embedder, Filter, Point, VectorIndex, VectorStore},
+    config::{MemoryConfig, RagConfig, VectorsConfig},
+    storage::{Memory, Storage, Store},
+};
+
+pub const REMEMBER_TOOL: &str = "remember";
+
+const MEMORIES: &str = "memories";
+const DOCUMENTS: &str = "documents";
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Recalled {
+    pub memories: Vec<RecalledText>,
+    pub passages: Vec<RecalledText>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RecalledText {
+    pub id: String,
+    /// the file of a passage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub text: String,
+    pub score: f32,
+}
+
+#[derive(Debug)]
+pub struct Knowledge {
+    admin_token: Option<String>,
+    store: Arc<Storage>,
+    index: VectorStore,
+    embedder: Embedder,
+    memory: Option<MemoryConfig>,
+    rag: Option<RagConfig>,
+}
+
+/// the paragraphs of `text` grouped in passages of about `max_chars`
+pub fn chunk(text: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut chunks = vec![];
+    let mut current = String::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        let len = paragraph.chars().count();
+        if !current.is_empty() && current.chars().count() + len + 2 > max_chars {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if len > max_chars {
+            let chars = paragraph.chars().collect::<Vec<_>>();
+            chunks.extend(
+                chars
+                    .chunks(max_chars)
+                    .map(|c| c.iter().collect::<String>()),
+            );
+            continue;
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(paragraph);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+fn payload_str(payload: &serde_json::Map<String, serde_json::Value>, key: &str) -> String {
+    payload
+        .get(key)
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl Knowledge {
+    pub async fn new(
+        vectors: &VectorsConfig,
+        memory: Option<MemoryConfig>,
+        rag: Option<RagConfig>,
+        store: Arc<Storage>,
+        admin_token: Option<String>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            admin_token,
+            store,
+            index: VectorStore::open(&vectors.index).await?,
+            embedder: Embedder::new(vectors),
+            memory,
+            rag,
+        })
+    }
+
+    /// the built-in tool of the llm if `memory.remember_tool`
+    pub fn remember_tool(&self) -> Option<crate::ai::llm::Function> {
+        self.memory.as_ref().filter(|m| m.remember_tool)?;
+        Some(crate::ai::llm::Function {
+            name: REMEMBER_TOOL.to_string(),
+            description: "Keep a lasting fact about the user, like their name, preferences \
+                or important dates, to recall it in later conversations."
+                .to_string(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "text": {"type": "string", "description": "the fact, one short sentence"}
+                },
+                "required": ["text"]
+            }),
+        })
+    }
+
+    pub async fn remember(&self, device_id: &str, text: &str) -> anyhow::Result<Memory> {
+        let memory = Memory {
+            id: uuid::Uuid::new_v4().to_string(),
+            device_id: device_id.to_string(),
+            text: text.trim().to_string(),
+            created_at: chrono::Local::now().to_rfc3339(),
+        };
+        let vector = self.embedder.embed_one(&memory.text).await?;
+        self.store.add_memory(&memory).await?;
+        let point = Point {
+            id: memory.id.clone(),
+            vector,
+            payload: serde_json::json!({"device_id": device_id, "text": memory.text})
+                .as_object()
+                .cloned()
+                .unwrap_or_default(),
+        };
+        self.index.upsert(MEMORIES, vec![point]).await?;
+        Ok(memory)
+    }
+
+    /// index stored memories again, those of an import
+    pub async fn index_memories(&self, memories: &[Memory]) -> anyhow::Result<()> {
+        for batch in memories.chunks(64) {
+            let texts = batch.iter().map(|m| m.text.clone()).collect::<Vec<_>>();
+            let vectors = self.embedder.embed(&texts).await?;
+            let points = batch
+                .iter()
+                .zip(vectors)
+                .map(|(memory, vector)| Point {
+                    id: memory.id.clone(),
+                    vector,
+                    payload:
+                        serde_json::json!({"device_id": memory.device_id, "text": memory.text})
+                            .as_object()
+                            .cloned()
+                            .unwrap_or_default(),
+                })
+                .collect();
+            self.index.upsert(MEMORIES, points).await?;
+        }
+        Ok(())
+    }
+
+    /// false if the device has no such memory
+    pub async fn forget(&self, device_id: &str, memory_id: &str) -> anyhow::Result<bool> {
+        let memories = self.store.memories(device_id).await?;
+        if !memories.iter().any(|m| m.id == memory_id) {
+            return Ok(false);
+        }
+        self.index
+            .delete(MEMORIES, &[memory_id.to_string()])
+            .await?;
+        self.store.delete_memory(memory_id).await
+    }
+
+    /// remove the memories of a device from the index, the storage keeps them
+    pub async fn unindex_device(&self, device_id: &str) -> anyhow::Result<usize> {
+        let ids = self
+            .store
+            .memories(device_id)
+            .await?
+            .into_iter()
+            .map(|m| m.id)
+            .collect::<Vec<_>>();
+        if !ids.is_empty() {
+            self.index.delete(MEMORIES, &ids).await?;
+        }
+        Ok(ids.len())
+    }
+
+    /// index the passages of `rag.dir`, the ids are stable so that they are replaced
+    pub async fn index_documents(&self) -> anyhow::Result<usize> {
+        let Some(rag) = &self.rag else {
+            return Ok(0);
+        };
+        let mut names = vec![];
+        let mut entries = tokio::fs::read_dir(&rag.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".md") || name.ends_with(".txt") {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        let mut count = 0;
+        for name in names {
+            let text =
+                tokio::fs::read_to_string(std::path::Path::new(&rag.dir).join(&name)).await?;
+            let chunks = chunk(&text, rag.chunk_chars);
+            for (batch_index, batch) in chunks.chunks(64).enumerate() {
+                let vectors = self.embedder.embed(batch).await?;
+                let points = batch
+                    .iter()
+                    .zip(vectors)
+                    .enumerate()
+                    .map(|(i, (text, vector))| Point {
+                        id: point_id(&format!("{name}#{}", batch_index * 64 + i)),
+                        vector,
+                        payload: serde_json::json!({"source": name, "text": text})
+                            .as_object()
+                            .cloned()
+                            .unwrap_or_default(),
+                    })
+                    .collect::<Vec<_>>();
+                count += points.len();
+                self.index.upsert(DOCUMENTS, points).await?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// the memories of `device_id` and the passages close to `query`
+    pub async fn recall(&self, device_id: Option<&str>, query: &str) -> anyhow::Result<Recalled> {
+        let mut recalled = Recalled {
+            memories: vec![],
+            passages: vec![],
+        };
+        let memory = self.memory.as_ref().zip(device_id);
+        if memory.is_none() && self.rag.is_none() {
+            return Ok(recalled);
+        }
+        let vector = self.embedder.embed_one(query).await?;
+
+        if let Some((memory, device_id)) = memory {
+            let filter = Filter {
+                key: "device_id",
+                value: device_id,
+            };
+            let hits = self
+                .index
+                .search(MEMORIES, &vector, Some(filter), memory.recall)
+                .await?;
+            recalled.memories = hits
+                .into_iter()
+                .filter(|hit| hit.score >= memory.min_score)
+                .map(|hit| RecalledText {
+                    text: payload_str(&hit.payload, "text"),
+                    id: hit.id,
+                    source: None,
+                    score: hit.score,
+                })
+                .collect();
+        }
+        if let Some(rag) = &self.rag {
+            let hits = self
+                .index
+                .search(DOCUMENTS, &vector, None, rag.top_k)
+                .await?;
+            recalled.passages = hits
+                .into_iter()
+                .filter(|hit| hit.score >= rag.min_score)
+                .map(|hit| RecalledText {
+                    text: payload_str(&hit.payload, "text"),
+                    source: Some(payload_str(&hit.payload, "source")),
+                    id: hit.id,
+                    score: hit.score,
+                })
+                .collect();
+        }
+        Ok(recalled)
+    }
+
+    /// the system message of a turn, none if nothing is relevant
+    pub async fn context(&self, device_id: &str, query: &str) -> Option<String> {
+        let recalled = match self.recall(Some(device_id), query).await {
+            Ok(recalled) => recalled,
+            Err(e) => {
+                log::warn!("`{device_id}` recall error: {e}");
+                return None;
+            }
+        };
+        let mut parts = vec![];
+        if !recalled.memories.is_empty() {
+            let facts = recalled
+                .memories
+                .iter()
+                .map(|m| format!("- {}", m.text))
+                .collect::<Vec<_>>()
+                .join("\n");
+            parts.push(format!("What you remember about the user:\n{facts}"));
+        }
+        if !recalled.passages.is_empty() {
+            let passages = recalled
+                .passages
+                .iter()
+                .map(|p| format!("[{}]\n{}", p.source.as_deref().unwrap_or_default(), p.text))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            parts.push(format!(
+                "Passages of the reference documents, use them if they answer the question:\n{passages}"
+            ));
+        }
+        (!parts.is_empty()).then(|| parts.join("\n\n"))
+    }
+}
+
+/// GET /v1/devices/{id}/memories
+async fn list_memories(
+    Extension(knowledge): Extension<Arc<Knowledge>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &knowledge.admin_token) {
+        return code.into_response();
+    }
+    match knowledge.store.memories(&id).await {
+        Ok(memories) => Json(memories).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct NewMemory {
+    text: String,
+}
+
+/// POST /v1/devices/{id}/memories
+async fn add_memory(
+    Extension(knowledge): Extension<Arc<Knowledge>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(new): Json<NewMemory>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &knowledge.admin_token) {
+        return code.into_response();
+    }
+    if new.text.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty text").into_response();
+    }
+    match knowledge.remember(&id, &new.text).await {
+        Ok(memory) => Json(memory).into_response(),
+        Err(e) => {
+            log::error!("`{id}` remember error: {e}");
+            (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+        }
+    }
+}
+
+/// DELETE /v1/devices/{id}/memories/{memory_id}
+async fn delete_memory(
+    Extension(knowledge): Extension<Arc<Knowledge>>,
+    headers: HeaderMap,
+    Path((id, memory_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &knowledge.admin_token) {
+        return code.into_response();
+    }
+    match knowledge.forget(&id, &memory_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "memory not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SearchParams {
+    q: String,
+    #[serde(default)]
+    device: Option<String>,
+}
+
+/// GET /v1/knowledge/search
+async fn search_knowledge(
+    Extension(knowledge): Extension<Arc<Knowledge>>,
+    headers: HeaderMap,
+    Query(params): Query<SearchParams>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &knowledge.admin_token) {
+        return code.into_response();
+    }
+    if params.q.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty q").into_response();
+    }
+    match knowledge.recall(params.device.as_deref(), &params.q).await {
+        Ok(recalled) => Json(recalled).into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    }
+}
+
+pub fn new_knowledge_service(knowledge: Arc<Knowledge>) -> Router {
+    Router::new()
+        .route(
+            "/v1/devices/{id}/memories",
+            get(list_memories).post(add_memory),
+        )
+        .route(
+            "/v1/devices/{id}/memories/{memory_id}",
+            delete(delete_memory),
+        )
+        .route("/v1/knowledge/search", get(search_knowledge))
+        .layer(Extension(knowledge))
+}
+
+#[test]
+fn test_chunk() {
+    let text = "first paragraph\n\nsecond one\n\n\n\nthird paragraph here";
+    assert_eq!(
+        chunk(text, 30),
+        ["first paragraph\n\nsecond one", "third paragraph here"]
+    );
+    assert_eq!(chunk("abcdef", 4), ["abcd", "ef"]);
+    assert!(chunk("  \n\n ", 10).is_empty());
+}
@@ -0,0 +1,345 @@
+//! Media playback, `[media]`: a tool returns the url of an audio stream (a radio, a
+//! podcast, white noise) and the server plays it on the device.
+//!
+//! a tool result with an `audio_url` is played, `{"audio_url": "https://...", "title":
+//! "..."}`, once the spoken answer of the turn is done. the server fetches the stream,
+//! transcodes it with `ffmpeg` to the output pcm of `[stream]` and sends it on `/ws/{id}`
+//! as an audio response, `lead_ms` ahead of the playback. the device never sees the url.
+//!
+//! `暂停` pauses it, `继续播放` resumes it, `停止播放` / `别放了` stops it, answered without
+//! the llm. any other utterance pauses it for its answer. each change is sent as a `Media`
+//! event.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+};
+
+use crate::{
+    config::MediaConfig,
+    protocol::{MediaEvent, MediaState},
+    services::ws::{WsCommand, WsPool},
+    util::Rechunker,
+};
+
+/// what the asr may put around a command
+const ASR_PUNCTUATION: &[char] = &['，', '。', '？', '！', ',', '.', '?', '!', ' '];
+
+/// the audio of each `Audio` command
+const CHUNK_MS: u64 = 100;
+
+/// an audio stream returned by a tool
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct MediaSource {
+    #[serde(rename = "audio_url")]
+    pub url: String,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// the stream of a tool result, a json object with an http `audio_url`
+pub fn source_of(tool_result: &str) -> Option<MediaSource> {
+    let source: MediaSource = serde_json::from_str(tool_result).ok()?;
+    let scheme = reqwest::Url::parse(&source.url).ok()?.scheme().to_string();
+    matches!(scheme.as_str(), "http" | "https").then_some(source)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaCommand {
+    Pause,
+    Resume,
+    Stop,
+}
+
+pub fn media_command(utterance: &str) -> Option<MediaCommand> {
+    match utterance
+        .trim_matches(ASR_PUNCTUATION)
+        .to_lowercase()
+        .as_str()
+    {
+        "暂停" | "暂停播放" | "先停一下" | "pause" => Some(MediaCommand::Pause),
+        "继续" | "继续播放" | "接着放" | "resume" => Some(MediaCommand::Resume),
+        "停止" | "停止播放" | "别放了" | "不听了" | "关掉音乐" | "stop" => {
+            Some(MediaCommand::Stop)
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+struct Player {
+    generation: u64,
+    control: watch::Sender<MediaState>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Debug)]
+pub struct Media {
+    config: MediaConfig,
+    client: reqwest::Client,
+    /// the player of each device
+    players: Mutex<HashMap<String, Player>>,
+    /// played once the answer of the turn is spoken
+    pending: Mutex<HashMap<String, MediaSource>>,
+    generation: std::sync::atomic::AtomicU64,
+}
+
+impl Media {
+    pub fn new(config: MediaConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            players: Default::default(),
+            pending: Default::default(),
+            generation: Default::default(),
+        }
+    }
+
+    /// play `source` on the device at the end of the turn
+    pub fn queue(&self, id: &str, source: MediaSource) {
+        log::info!("`{id}` media queued: {}", source.url);
+        self.pending.lock().unwrap().insert(id.to_string(), source);
+    }
+
+    pub fn has_player(&self, id: &str) -> bool {
+        self.players.lock().unwrap().contains_key(id)
+    }
+
+    /// false if the device plays nothing
+    pub fn control(&self, id: &str, command: MediaCommand) -> bool {
+        let players = self.players.lock().unwrap();
+        let Some(player) = players.get(id) else {
+            return false;
+        };
+        let state = match command {
+            MediaCommand::Pause => MediaState::Paused,
+            MediaCommand::Resume => MediaState::Playing,
+            MediaCommand::Stop => MediaState::Stopped,
+        };
+        player.control.send_replace(state);
+        true
+    }
+
+    /// start the stream queued by the turn, the one playing is stopped first
+    pub async fn start_pending(self: &Arc<Self>, pool: &Arc<WsPool>, id: &str) {
+        let Some(source) = self.pending.lock().unwrap().remove(id) else {
+            return;
+        };
+        let previous = self.players.lock().unwrap().remove(id);
+        if let Some(previous) = previous {
+            previous.control.send_replace(MediaState::Stopped);
+            let _ = previous.task.await;
+        }
+
+        let generation = self
+            .generation
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let (control, rx) = watch::channel(MediaState::Playing);
+        // the task cannot remove its player before it is inserted
+        let mut players = self.players.lock().unwrap();
+        let media = self.clone();
+        let pool = pool.clone();
+        let id_ = id.to_string();
+        let task = tokio::spawn(async move {
+            let title = source.title.clone();
+            if let Err(e) = media.play(&pool, &id_, source, rx).await {
+                log::warn!("`{id_}` media error: {e}");
+            }
+            let _ = pool.send(&id_, WsCommand::EndAudio).await;
+            let _ = pool.send(&id_, WsCommand::EndResponse).await;
+            let event = MediaEvent {
+                state: MediaState::Stopped,
+                title,
+            };
+            let _ = pool.send(&id_, WsCommand::Media(event)).await;
+            let mut players = media.players.lock().unwrap();
+            if players
+                .get(&id_)
+                .is_some_and(|p| p.generation == generation)
+            {
+                players.remove(&id_);
+            }
+        });
+        let player = Player {
+            generation,
+            control,
+            task,
+        };
+        players.insert(id.to_string(), player);
+    }
+
+    /// fetch, transcode and send the stream until it ends or is stopped
+    async fn play(
+        &self,
+        pool: &WsPool,
+        id: &str,
+        source: MediaSource,
+        mut control: watch::Receiver<MediaState>,
+    ) -> anyhow::Result<()> {
+        let out_hz = pool.stream(id).await.output_sample_rate;
+        let mut ffmpeg = tokio::process::Command::new(&self.config.ffmpeg)
+            .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0"])
+            .args(["-f", "s16le", "-acodec", "pcm_s16le", "-ac", "1"])
+            .args(["-ar", &out_hz.to_string(), "pipe:1"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("{} error: {e}", self.config.ffmpeg))?;
+        let (Some(mut stdin), Some(mut stdout)) = (ffmpeg.stdin.take(), ffmpeg.stdout.take())
+        else {
+            anyhow::bail!("no ffmpeg pipes");
+        };
+
+        let resp = self
+            .client
+            .get(&source.url)
+            .send()
+            .await?
+            .error_for_status()?;
+        // the body is fed as fast as ffmpeg reads it, a paused stream holds both back
+        let feed = tokio::spawn(async move {
+            let mut body = resp.bytes_stream();
+            while let Some(chunk) = body.next().await {
+                stdin.write_all(&chunk?).await?;
+            }
+            anyhow::Ok(())
+        });
+        let _feed = AbortOnDrop(feed);
+
+        let title = source.title.unwrap_or_default();
+        let chunk_size = out_hz as usize * 2 * CHUNK_MS as usize / 1000;
+        let mut rechunker = Rechunker::new(chunk_size);
+        let mut buf = vec![0u8; chunk_size];
+        let max = self.config.max_duration_sec.map(|s| s * 1000);
+        let mut played_ms = 0;
+        // since the start or the resume, the device plays in real time
+        let mut clock = Instant::now();
+        let mut sent_ms = 0;
+
+        self.event(pool, id, MediaState::Playing, &title).await?;
+        pool.send(id, WsCommand::StartAudio(title.clone())).await?;
+        loop {
+            let state = *control.borrow_and_update();
+            match state {
+                MediaState::Stopped => break,
+                MediaState::Paused => {
+                    pool.send(id, WsCommand::EndAudio).await?;
+                    pool.send(id, WsCommand::EndResponse).await?;
+                    self.event(pool, id, MediaState::Paused, &title).await?;
+                    let resumed = control.wait_for(|s| *s != MediaState::Paused).await;
+                    if resumed.map(|s| *s).ok() != Some(MediaState::Playing) {
+                        break;
+                    }
+                    self.event(pool, id, MediaState::Playing, &title).await?;
+                    pool.send(id, WsCommand::StartAudio(title.clone())).await?;
+                    clock = Instant::now();
+                    sent_ms = 0;
+                    continue;
+                }
+                MediaState::Playing => {}
+            }
+            if max.is_some_and(|max| played_ms >= max) {
+                log::info!("`{id}` media stopped after {played_ms}ms");
+                break;
+            }
+
+            let n = tokio::select! {
+                n = stdout.read(&mut buf) => n?,
+                changed = control.changed() => match changed {
+                    Ok(()) => continue,
+                    Err(_) => break,
+                },
+            };
+            if n == 0 {
+                break;
+            }
+            for chunk in rechunker.push(Bytes::copy_from_slice(&buf[..n])) {
+                pool.send(id, WsCommand::Audio(chunk)).await?;
+                sent_ms += CHUNK_MS;
+                played_ms += CHUNK_MS;
+            }
+
+            let ahead =
+                sent_ms.saturating_sub(clock.elapsed().as_millis() as u64 + self.config.lead_ms);
+            if ahead > 0 {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_millis(ahead)) => {}
+                    _ = control.changed() => {}
+                }
+            }
+        }
+        if let Some(rest) = rechunker.finish() {
+            pool.send(id, WsCommand::Audio(rest)).await?;
+        }
+        Ok(())
+    }
+
+    async fn event(
+        &self,
+        pool: &WsPool,
+        id: &str,
+        state: MediaState,
+        title: &str,
+    ) -> anyhow::Result<()> {
+        let event = MediaEvent {
+            state,
+            title: (!title.is_empty()).then(|| title.to_string()),
+        };
+        pool.send(id, WsCommand::Media(event)).await
+    }
+
+    /// a turn of `暂停` / `继续播放` / `停止播放`, applied without an answer
+    pub async fn command_turn(
+        &self,
+        pool: &WsPool,
+        id: &str,
+        text: String,
+        command: MediaCommand,
+    ) -> anyhow::Result<()> {
+        pool.send(id, WsCommand::AsrResult(vec![text.clone()]))
+            .await?;
+        pool.webhooks.transcription_completed(id, &text);
+        pool.sessions.record(id, crate::ai::llm::Role::User, &text);
+        pool.sessions.add_turn(id);
+        pool.sessions.turn_intent(id, "media");
+        log::info!("`{id}` media {command:?}");
+        self.control(id, command);
+        Ok(())
+    }
+}
+
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[test]
+fn test_media() {
+    let source =
+        source_of(r#"{"audio_url": "https://radio.example.com/live.mp3", "title": "FM 97.4"}"#)
+            .unwrap();
+    assert_eq!(source.title.as_deref(), Some("FM 97.4"));
+    assert!(source_of(r#"{"audio_url": "file:///etc/passwd"}"#).is_none());
+    assert!(source_of(r#"{"weather": "sunny"}"#).is_none());
+    assert!(source_of("not json").is_none());
+
+    assert_eq!(media_command("暂停。"), Some(MediaCommand::Pause));
+    assert_eq!(media_command("继续播放"), Some(MediaCommand::Resume));
+    assert_eq!(media_command("Stop!"), Some(MediaCommand::Stop));
+    assert_eq!(media_command("暂停一下今天的安排"), None);
+
+    let media = Media::new(toml::from_str("").unwrap());
+    assert!(!media.control("dev1", MediaCommand::Pause));
+}
@@ -1,3 +1,86 @@
+pub mod alarms;
+pub mod analytics;
+pub mod archive;
+pub mod briefing;
+pub mod chat_completions;
+pub mod cluster;
+pub mod console;
+pub mod costs;
+pub mod device_ws;
+pub mod dictation;
+pub mod earcons;
 pub mod file;
+#[cfg(test)]
+pub mod fixtures;
+pub mod history;
+pub mod intents;
+pub mod knowledge;
+pub mod media;
+pub mod offline;
+pub mod openapi;
+pub mod ota;
+pub mod phrases;
+#[cfg(feature = "pprof")]
+pub mod profiling;
+pub mod prompts;
+pub mod realtime_capture;
 pub mod realtime_ws;
+pub mod registry;
+pub mod reminders;
+pub mod retention;
+pub mod routines;
+pub mod sessions;
+pub mod speech;
+pub mod tenants;
+pub mod transcription_jobs;
+pub mod translation;
+pub mod turn_state;
+pub mod webhooks;
 pub mod ws;
+
+/// check `Authorization: Bearer <token>` of an admin request, `401` if it is missing or
+/// wrong. the admin api is disabled if no token is configured, every request is `401`.
+pub fn check_admin_token(
+    headers: &http::HeaderMap,
+    token: &Option<String>,
+) -> Result<(), http::StatusCode> {
+    let auth = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match (token, auth) {
+        (Some(token), Some(auth)) if same_secret(token, auth) => Ok(()),
+        _ => Err(http::StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// compare the digests, the time does not depend on where the secrets differ
+pub(crate) fn same_secret(a: &str, b: &str) -> bool {
+    use sha2::Digest;
+    let (a, b) = (sha2::Sha256::digest(a), sha2::Sha256::digest(b));
+    a.iter()
+        .zip(b.iter())
+        .fold(0, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+#[test]
+fn test_check_admin_token() {
+    let mut headers = http::HeaderMap::new();
+    let token = Some("s3cret".to_string());
+    assert_eq!(
+        check_admin_token(&headers, &token),
+        Err(http::StatusCode::UNAUTHORIZED)
+    );
+    headers.insert(http::header::AUTHORIZATION, "Bearer s3cre".parse().unwrap());
+    assert!(check_admin_token(&headers, &token).is_err());
+    headers.insert(
+        http::header::AUTHORIZATION,
+        "Bearer s3cret".parse().unwrap(),
+    );
+    assert_eq!(check_admin_token(&headers, &token), Ok(()));
+    assert_eq!(
+        check_admin_token(&headers, &None),
+        Err(http::StatusCode::UNAUTHORIZED)
+    );
+}
@@ -0,0 +1,189 @@
+//! Offline fallback answers.
+//!
+//! The answers of `[offline]` are rendered with the configured tts at startup and cached
+//! in `cache_dir` as raw pcm16 at `stream.output_sample_rate`, so they can still be spoken
+//! when the asr / llm / tts providers are unreachable.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::config::{OfflineConfig, TTSConfig};
+
+#[derive(Debug)]
+pub struct OfflineAnswers {
+    config: OfflineConfig,
+    out_hz: u32,
+    /// answer text -> pcm16
+    audio: HashMap<String, Bytes>,
+}
+
+impl OfflineAnswers {
+    /// load the cached audio, render the missing answers with `tts`
+    pub async fn load(config: OfflineConfig, tts: &TTSConfig, out_hz: u32) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&config.cache_dir).await?;
+
+        let mut answers = Self {
+            config,
+            out_hz,
+            audio: HashMap::new(),
+        };
+
+        let texts = answers.texts();
+        let total = texts.len();
+        for text in texts {
+            let path = answers.cache_path(&text);
+            if let Ok(pcm) = tokio::fs::read(&path).await {
+                answers.audio.insert(text, Bytes::from(pcm));
+                continue;
+            }
+
+            match render_pcm(tts, &text, out_hz).await {
+                Ok(pcm) => {
+                    if let Err(e) = tokio::fs::write(&path, &pcm).await {
+                        log::warn!("write offline audio {} error: {e}", path.display());
+                    }
+                    answers.audio.insert(text, pcm);
+                }
+                Err(e) => log::warn!("render offline answer {text:?} error: {e}"),
+            }
+        }
+        log::info!(
+            "offline answers: {} cached of {}",
+            answers.audio.len(),
+            total
+        );
+
+        Ok(answers)
+    }
+
+    fn texts(&self) -> Vec<String> {
+        let mut texts = vec![self.config.default_text.clone()];
+        texts.extend(self.config.intents.iter().map(|i| i.text.clone()));
+        texts.sort();
+        texts.dedup();
+        texts
+    }
+
+    fn cache_path(&self, text: &str) -> PathBuf {
+        let digest = Sha256::digest(format!("{}:{text}", self.out_hz).as_bytes());
+        let name: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+        PathBuf::from(&self.config.cache_dir).join(format!("{name}.pcm"))
+    }
+
+    /// the canned answer of the question, the default answer if unknown
+    pub fn answer_text(&self, question: Option<&str>) -> &str {
+        question
+            .and_then(|q| {
+                self.config
+                    .intents
+                    .iter()
+                    .find(|i| i.keywords.iter().any(|k| !k.is_empty() && q.contains(k)))
+            })
+            .map(|i| i.text.as_str())
+            .unwrap_or(&self.config.default_text)
+    }
+
+    /// pre-rendered pcm16 of an answer
+    pub fn audio(&self, text: &str) -> Option<Bytes> {
+        self.audio.get(text).cloned()
+    }
+}
+
+/// synthesize `text` to pcm16 at `out_hz`
+pub(crate) async fn render_pcm(tts: &TTSConfig, text: &str, out_hz: u32) -> anyhow::Result<Bytes> {
+    #[cfg(feature = "chaos")]
+    crate::ai::chaos::inject("tts").await?;
+
+    let wav_data = match tts {
+        TTSConfig::Stable(tts) => {
+            super::ws::retry_tts(
+                &tts.url,
+                &tts.speaker,
+                text,
+                Some(tts.sample_rate.unwrap_or(out_hz as usize)),
+                tts.prosody.speed,
+                3,
+                std::time::Duration::from_secs(tts.timeout_sec.unwrap_or(15)),
+            )
+            .await?
+        }
+        TTSConfig::Fish(fish) => {
+            crate::ai::tts::fish_tts(&fish.api_key, &fish.speaker, text, fish.prosody.speed).await?
+        }
+        TTSConfig::Groq(groq) => {
+            crate::ai::tts::groq(
+                &groq.model,
+                &groq.api_key,
+                &groq.voice,
+                text,
+                groq.prosody.speed,
+            )
+            .await?
+        }
+        TTSConfig::Mock(mock) => crate::ai::mock::tts(mock, text, out_hz),
+        TTSConfig::StreamGSV(stream_tts) => {
+            let resp = crate::ai::tts::stream_gsv(
+                &stream_tts.url,
+                &stream_tts.speaker,
+                text,
+                Some(out_hz as usize),
+                stream_tts.prosody.speed,
+            )
+            .await?;
+            let mut pcm = bytes::BytesMut::new();
+            let mut stream = resp.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                pcm.extend_from_slice(&chunk?);
+            }
+            return Ok(pcm.freeze());
+        }
+        TTSConfig::CosyVoice(cosyvoice) => {
+            let mut tts =
+                crate::ai::bailian::cosyvoice::CosyVoiceTTS::connect(cosyvoice.token.clone())
+                    .await?;
+            tts.start_synthesis(
+                cosyvoice.version,
+                cosyvoice.speaker.as_deref(),
+                Some(out_hz),
+                cosyvoice.prosody,
+                text,
+            )
+            .await?;
+            let mut pcm = bytes::BytesMut::new();
+            while let Some(chunk) = tts.next_audio_chunk().await? {
+                pcm.extend_from_slice(&chunk);
+            }
+            return Ok(pcm.freeze());
+        }
+    };
+
+    let (pcm, _) = crate::ai::tts::to_pcm16(tts, wav_data, out_hz).await?;
+    Ok(pcm)
+}
+
+#[test]
+fn test_answer_text() {
+    let answers = OfflineAnswers {
+        config: OfflineConfig {
+            cache_dir: "./offline".to_string(),
+            default_text: "我暂时无法连接网络".to_string(),
+            intents: vec![crate::config::OfflineIntent {
+                keywords: vec!["天气".to_string(), "下雨".to_string()],
+                text: "暂时查不到天气".to_string(),
+            }],
+        },
+        out_hz: 16000,
+        audio: HashMap::new(),
+    };
+
+    assert_eq!(answers.answer_text(Some("明天会下雨吗")), "暂时查不到天气");
+    assert_eq!(answers.answer_text(Some("讲个故事")), "我暂时无法连接网络");
+    assert_eq!(answers.answer_text(None), "我暂时无法连接网络");
+    assert_ne!(
+        answers.cache_path("暂时查不到天气"),
+        answers.cache_path("我暂时无法连接网络")
+    );
+}
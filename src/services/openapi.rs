@@ -0,0 +1,63 @@
+//! OpenAPI 3.1 document of the REST api, to generate client sdks.
+//!
+//! - `GET /openapi.json`
+//!
+//! the document is written by hand next to the handlers, keep
+//! `resources/openapi.json` in sync when a route or a body changes.
+
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+
+const OPENAPI_JSON: &str = include_str!("../../resources/openapi.json");
+
+/// GET /openapi.json
+async fn openapi_json() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI_JSON)
+}
+
+pub fn new_openapi_service() -> Router {
+    Router::new().route("/openapi.json", get(openapi_json))
+}
+
+#[test]
+fn test_openapi_document() {
+    let doc: serde_json::Value = serde_json::from_str(OPENAPI_JSON).unwrap();
+    assert_eq!(doc["openapi"], "3.1.0");
+
+    let paths = doc["paths"].as_object().unwrap();
+    for path in [
+        "/v1/sessions",
+        "/v1/prompts",
+        "/v1/tts",
+        "/v1/asr",
+        "/v1/transcriptions/jobs",
+        "/v1/chat/completions",
+        "/admin/devices",
+        "/admin/ota/releases",
+    ] {
+        assert!(paths.contains_key(path), "{path} is missing");
+    }
+
+    let schemas = doc["components"]["schemas"].as_object().unwrap();
+    let methods = ["get", "post", "put", "delete"];
+    for (path, item) in paths {
+        for (method, op) in item.as_object().unwrap() {
+            if !methods.contains(&method.as_str()) {
+                continue;
+            }
+            assert!(
+                op["responses"].is_object(),
+                "{method} {path} has no responses"
+            );
+        }
+    }
+    // every `$ref` points to a schema of the document
+    let refs = OPENAPI_JSON
+        .match_indices("\"#/components/schemas/")
+        .map(|(i, m)| {
+            let name = &OPENAPI_JSON[i + m.len()..];
+            &name[..name.find('"').unwrap()]
+        });
+    for name in refs {
+        assert!(schemas.contains_key(name), "schema {name} is missing");
+    }
+}
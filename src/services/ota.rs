@@ -0,0 +1,350 @@
+//! OTA firmware distribution.
+//!
+//! device:
+//! - `GET /ota/check?device_id=xx&version=1.0.0` -> release manifest, or 204 if up to date
+//! - `GET /ota/firmware/{version}/chunk/{index}` -> firmware chunk, `x-chunk-sha256` header
+//!
+//! admin (bearer `ota.admin_token`):
+//! - `GET /admin/ota/releases`
+//! - `PUT /admin/ota/releases/{version}?rollout_percent=10` with the firmware binary as body
+//! - `POST /admin/ota/releases/{version}/rollout` `{"rollout_percent": 50}`
+//! - `DELETE /admin/ota/releases/{version}`
+
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::RwLock,
+};
+
+use crate::config::OtaConfig;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Release {
+    pub version: String,
+    pub size: usize,
+    pub sha256: String,
+    /// 0-100, share of devices that are offered this release
+    pub rollout_percent: u8,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    /// oldest first, the last release is the current one
+    pub releases: Vec<Release>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct UpdateInfo {
+    pub version: String,
+    pub size: usize,
+    pub sha256: String,
+    pub chunk_size: usize,
+    pub chunks: usize,
+}
+
+pub struct OtaService {
+    config: OtaConfig,
+    manifest: RwLock<Manifest>,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// stable bucket 0..100 of a device for a release, so a device stays in (or out of)
+/// the rollout while the percentage grows
+fn rollout_bucket(device_id: &str, version: &str) -> u8 {
+    let digest = Sha256::digest(format!("{device_id}:{version}").as_bytes());
+    (u16::from_be_bytes([digest[0], digest[1]]) % 100) as u8
+}
+
+fn valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+impl OtaService {
+    pub fn load(config: OtaConfig) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        let manifest_path = PathBuf::from(&config.dir).join("manifest.json");
+        let manifest = if manifest_path.exists() {
+            serde_json::from_slice(&std::fs::read(&manifest_path)?)?
+        } else {
+            Manifest::default()
+        };
+        Ok(Self {
+            config,
+            manifest: RwLock::new(manifest),
+        })
+    }
+
+    fn firmware_path(&self, version: &str) -> PathBuf {
+        PathBuf::from(&self.config.dir).join(format!("{version}.bin"))
+    }
+
+    async fn save_manifest(&self, manifest: &Manifest) -> anyhow::Result<()> {
+        let path = PathBuf::from(&self.config.dir).join("manifest.json");
+        tokio::fs::write(path, serde_json::to_vec_pretty(manifest)?).await?;
+        Ok(())
+    }
+
+    fn check_admin(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        super::check_admin_token(headers, &self.config.admin_token)
+    }
+
+    fn update_info(&self, release: &Release) -> UpdateInfo {
+        UpdateInfo {
+            version: release.version.clone(),
+            size: release.size,
+            sha256: release.sha256.clone(),
+            chunk_size: self.config.chunk_size,
+            chunks: release.size.div_ceil(self.config.chunk_size),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CheckParams {
+    device_id: String,
+    version: String,
+}
+
+async fn check_update(
+    Extension(ota): Extension<Arc<OtaService>>,
+    Query(params): Query<CheckParams>,
+) -> impl IntoResponse {
+    let manifest = ota.manifest.read().await;
+    match manifest.releases.last() {
+        Some(release)
+            if release.version != params.version
+                && rollout_bucket(&params.device_id, &release.version)
+                    < release.rollout_percent =>
+        {
+            log::info!(
+                "`{}` offered firmware {} (current {})",
+                params.device_id,
+                release.version,
+                params.version
+            );
+            (StatusCode::OK, Json(ota.update_info(release))).into_response()
+        }
+        _ => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+async fn get_chunk(
+    Extension(ota): Extension<Arc<OtaService>>,
+    Path((version, index)): Path<(String, usize)>,
+) -> impl IntoResponse {
+    if !valid_version(&version) {
+        return (StatusCode::BAD_REQUEST, "invalid version").into_response();
+    }
+    let mut firmware = match tokio::fs::File::open(ota.firmware_path(&version)).await {
+        Ok(firmware) => firmware,
+        Err(_) => return (StatusCode::NOT_FOUND, "firmware not found").into_response(),
+    };
+    let size = match firmware.metadata().await {
+        Ok(metadata) => metadata.len() as usize,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let chunk_size = ota.config.chunk_size;
+    let start = index.saturating_mul(chunk_size);
+    if start >= size {
+        return (StatusCode::NOT_FOUND, "chunk out of range").into_response();
+    }
+    let mut chunk = vec![0; chunk_size.min(size - start)];
+    let read = async {
+        firmware
+            .seek(std::io::SeekFrom::Start(start as u64))
+            .await?;
+        firmware.read_exact(&mut chunk).await
+    };
+    if let Err(e) = read.await {
+        log::error!("firmware {version} chunk {index} error: {e}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+
+    (
+        StatusCode::OK,
+        [
+            (
+                http::header::CONTENT_TYPE,
+                "application/octet-stream".to_string(),
+            ),
+            (
+                http::header::HeaderName::from_static("x-chunk-sha256"),
+                sha256_hex(&chunk),
+            ),
+        ],
+        chunk,
+    )
+        .into_response()
+}
+
+async fn list_releases(
+    Extension(ota): Extension<Arc<OtaService>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = ota.check_admin(&headers) {
+        return code.into_response();
+    }
+    Json(ota.manifest.read().await.clone()).into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RolloutParams {
+    #[serde(default)]
+    rollout_percent: u8,
+}
+
+async fn upload_release(
+    Extension(ota): Extension<Arc<OtaService>>,
+    headers: HeaderMap,
+    Path(version): Path<String>,
+    Query(params): Query<RolloutParams>,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err(code) = ota.check_admin(&headers) {
+        return code.into_response();
+    }
+    if !valid_version(&version) {
+        return (StatusCode::BAD_REQUEST, "invalid version").into_response();
+    }
+    if body.is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty firmware").into_response();
+    }
+
+    let release = Release {
+        version: version.clone(),
+        size: body.len(),
+        sha256: sha256_hex(&body),
+        rollout_percent: params.rollout_percent.min(100),
+        created_at: chrono::Local::now().to_rfc3339(),
+    };
+
+    if let Err(e) = tokio::fs::write(ota.firmware_path(&version), &body).await {
+        log::error!("write firmware {version} error: {e}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+
+    let mut manifest = ota.manifest.write().await;
+    manifest.releases.retain(|r| r.version != version);
+    manifest.releases.push(release.clone());
+    if let Err(e) = ota.save_manifest(&manifest).await {
+        log::error!("save ota manifest error: {e}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    log::info!(
+        "firmware {version} uploaded, rollout {}%",
+        release.rollout_percent
+    );
+
+    (StatusCode::CREATED, Json(release)).into_response()
+}
+
+async fn set_rollout(
+    Extension(ota): Extension<Arc<OtaService>>,
+    headers: HeaderMap,
+    Path(version): Path<String>,
+    Json(params): Json<RolloutParams>,
+) -> impl IntoResponse {
+    if let Err(code) = ota.check_admin(&headers) {
+        return code.into_response();
+    }
+
+    let mut manifest = ota.manifest.write().await;
+    let Some(release) = manifest.releases.iter_mut().find(|r| r.version == version) else {
+        return (StatusCode::NOT_FOUND, "release not found").into_response();
+    };
+    release.rollout_percent = params.rollout_percent.min(100);
+    let release = release.clone();
+
+    if let Err(e) = ota.save_manifest(&manifest).await {
+        log::error!("save ota manifest error: {e}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    log::info!("firmware {version} rollout {}%", release.rollout_percent);
+
+    Json(release).into_response()
+}
+
+async fn delete_release(
+    Extension(ota): Extension<Arc<OtaService>>,
+    headers: HeaderMap,
+    Path(version): Path<String>,
+) -> impl IntoResponse {
+    if let Err(code) = ota.check_admin(&headers) {
+        return code.into_response();
+    }
+
+    let mut manifest = ota.manifest.write().await;
+    let len = manifest.releases.len();
+    manifest.releases.retain(|r| r.version != version);
+    if manifest.releases.len() == len {
+        return (StatusCode::NOT_FOUND, "release not found").into_response();
+    }
+    if let Err(e) = ota.save_manifest(&manifest).await {
+        log::error!("save ota manifest error: {e}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    if valid_version(&version) {
+        let _ = tokio::fs::remove_file(ota.firmware_path(&version)).await;
+    }
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub fn new_ota_service(config: OtaConfig) -> anyhow::Result<Router> {
+    let max_firmware_size = config.max_firmware_size;
+    let ota = Arc::new(OtaService::load(config)?);
+
+    Ok(Router::new()
+        .route("/ota/check", get(check_update))
+        .route("/ota/firmware/{version}/chunk/{index}", get(get_chunk))
+        .route("/admin/ota/releases", get(list_releases))
+        .route(
+            "/admin/ota/releases/{version}",
+            put(upload_release)
+                .delete(delete_release)
+                .layer(DefaultBodyLimit::max(max_firmware_size)),
+        )
+        .route("/admin/ota/releases/{version}/rollout", post(set_rollout))
+        .layer(Extension(ota)))
+}
+
+#[test]
+fn test_rollout_bucket() {
+    let bucket = rollout_bucket("device-1", "1.0.0");
+    assert!(bucket < 100);
+    assert_eq!(bucket, rollout_bucket("device-1", "1.0.0"));
+
+    // roughly `percent` of the devices are in the rollout
+    let n = (0..1000)
+        .filter(|i| rollout_bucket(&format!("device-{i}"), "1.0.0") < 30)
+        .count();
+    assert!((200..400).contains(&n), "{n}");
+}
+
+#[test]
+fn test_valid_version() {
+    assert!(valid_version("1.2.3-rc1"));
+    assert!(!valid_version("../manifest"));
+    assert!(!valid_version(""));
+}
@@ -0,0 +1,295 @@
+//! The phrases the server speaks on its own: the error and clarification replies, the
+//! greeting and the filler before the tool calls.
+//!
+//! a phrase is looked up in the `[profiles.x.phrases.<locale>]` of the device, then in
+//! `[phrases.<locale>]`, then in the built-in table of the locale (`zh`, `en`, `ja`),
+//! the greeting and the filler have none and are only spoken if configured.
+//!
+//! [`PhraseAudio`] renders them at startup with each voice they are spoken with, and
+//! caches the pcm16 in `cache_dir`, so a fallback is spoken at once even when the tts
+//! provider is the one failing.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use crate::config::{PhraseTable, PhrasesConfig, ProfileConfig, SpeechTextConfig, TTSConfig};
+
+const DEFAULT_LOCALE: &str = "zh";
+
+/// (error, clarification) of a locale
+fn builtin(locale: &str) -> Option<(&'static str, &'static str)> {
+    // `en-US` is `en`
+    let language = locale.split(['-', '_']).next().unwrap_or(locale);
+    match language.to_ascii_lowercase().as_str() {
+        "zh" => Some((
+            "抱歉，出了点问题，请稍后再试一下",
+            "抱歉，我没能理解您的回复。请您换种表达方式重新说一下",
+        )),
+        "en" => Some((
+            "Sorry, something went wrong. Please try again in a moment.",
+            "Sorry, I didn't quite get that. Could you say it another way?",
+        )),
+        "ja" => Some((
+            "申し訳ありません、問題が発生しました。もう一度お試しください。",
+            "すみません、よく分かりませんでした。言い方を変えてもう一度お願いします。",
+        )),
+        _ => None,
+    }
+}
+
+/// the phrases of a device, resolved for its locale
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phrases {
+    pub error: String,
+    pub clarification: String,
+    pub greeting: Option<String>,
+    pub thinking: Option<String>,
+}
+
+impl Default for Phrases {
+    fn default() -> Self {
+        Self::new(&PhrasesConfig::default())
+    }
+}
+
+impl Phrases {
+    /// `config` merged with the profile of the device, see [`PhrasesConfig::with_profile`]
+    pub fn new(config: &PhrasesConfig) -> Self {
+        let locale = config.locale.as_deref().unwrap_or(DEFAULT_LOCALE);
+        let table = config.tables.get(locale).cloned().unwrap_or_default();
+        let (error, clarification) = builtin(locale)
+            .or_else(|| builtin(DEFAULT_LOCALE))
+            .unwrap_or_default();
+        let PhraseTable {
+            error: error_,
+            clarification: clarification_,
+            greeting,
+            thinking,
+        } = table;
+        Self {
+            error: error_.unwrap_or_else(|| error.to_string()),
+            clarification: clarification_.unwrap_or_else(|| clarification.to_string()),
+            greeting: greeting.filter(|g| !g.trim().is_empty()),
+            thinking: thinking.filter(|t| !t.trim().is_empty()),
+        }
+    }
+
+    /// every phrase with a fixed text
+    pub fn all(&self) -> Vec<&str> {
+        let mut all = vec![self.error.as_str(), self.clarification.as_str()];
+        all.extend(self.greeting.as_deref());
+        all.extend(self.thinking.as_deref());
+        all.dedup();
+        all
+    }
+}
+
+/// the pcm16 of the phrases at `out_hz`, by voice
+#[derive(Debug, Default)]
+pub struct PhraseAudio {
+    cache_dir: PathBuf,
+    out_hz: u32,
+    /// (voice, text) -> pcm16
+    audio: RwLock<HashMap<(String, String), Bytes>>,
+}
+
+impl PhraseAudio {
+    pub fn new(config: &PhrasesConfig, out_hz: u32) -> Self {
+        Self {
+            cache_dir: PathBuf::from(config.cache_dir.as_deref().unwrap_or("./phrases")),
+            out_hz,
+            audio: Default::default(),
+        }
+    }
+
+    /// the voice `text` is spoken with by `tts`, as [`TTSConfig::with_voice`] takes it
+    fn voice<'a>(tts: &'a TTSConfig, speech_text: &'a SpeechTextConfig, text: &str) -> &'a str {
+        speech_text
+            .voice_of(text)
+            .map(String::as_str)
+            .unwrap_or_else(|| tts.voice())
+    }
+
+    fn cache_path(&self, voice: &str, text: &str) -> PathBuf {
+        let digest = Sha256::digest(format!("{}:{voice}:{text}", self.out_hz).as_bytes());
+        let name: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+        self.cache_dir.join(format!("{name}.pcm"))
+    }
+
+    /// load or render the phrases of `phrases` and of each profile, in the voice of the
+    /// profile, a phrase that fails to render is spoken with the live tts
+    pub async fn render(
+        &self,
+        tts: &TTSConfig,
+        speech_text: &SpeechTextConfig,
+        phrases: &PhrasesConfig,
+        profiles: &HashMap<String, ProfileConfig>,
+    ) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.cache_dir).await?;
+
+        let mut jobs = vec![];
+        for profile in std::iter::once(None).chain(profiles.values().map(Some)) {
+            let tts = match profile.and_then(|p| p.voice.as_deref()) {
+                Some(voice) => tts.with_voice(voice),
+                None => tts.clone(),
+            };
+            for text in Phrases::new(&phrases.with_profile(profile)).all() {
+                let voice = Self::voice(&tts, speech_text, text).to_string();
+                let job = (voice, text.to_string());
+                if !jobs.contains(&job) {
+                    jobs.push(job);
+                }
+            }
+        }
+
+        let total = jobs.len();
+        let mut rendered = 0;
+        for (voice, text) in jobs {
+            let path = self.cache_path(&voice, &text);
+            let pcm = match tokio::fs::read(&path).await {
+                Ok(pcm) => Bytes::from(pcm),
+                Err(_) => {
+                    let spoken = crate::ai::speech_text::normalize(&text, speech_text);
+                    let pcm = match super::offline::render_pcm(
+                        &tts.with_voice(&voice),
+                        &spoken,
+                        self.out_hz,
+                    )
+                    .await
+                    {
+                        Ok(pcm) => pcm,
+                        Err(e) => {
+                            log::warn!("render phrase {text:?} ({voice}) error: {e}");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = tokio::fs::write(&path, &pcm).await {
+                        log::warn!("write phrase audio {} error: {e}", path.display());
+                    }
+                    pcm
+                }
+            };
+            rendered += 1;
+            self.audio.write().unwrap().insert((voice, text), pcm);
+        }
+        log::info!("phrases: {rendered} of {total} pre-rendered");
+        Ok(())
+    }
+
+    /// render in the background, the phrases are spoken with the live tts until then
+    pub fn spawn_render(
+        self: &Arc<Self>,
+        tts: TTSConfig,
+        speech_text: SpeechTextConfig,
+        phrases: PhrasesConfig,
+        profiles: HashMap<String, ProfileConfig>,
+    ) {
+        let audio = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = audio.render(&tts, &speech_text, &phrases, &profiles).await {
+                log::error!("render phrases error: {e}");
+            }
+        });
+    }
+
+    /// the pre-rendered pcm16 of `text` spoken by `tts`
+    pub fn get(
+        &self,
+        tts: &TTSConfig,
+        speech_text: &SpeechTextConfig,
+        text: &str,
+    ) -> Option<Bytes> {
+        let voice = Self::voice(tts, speech_text, text).to_string();
+        self.audio
+            .read()
+            .unwrap()
+            .get(&(voice, text.to_string()))
+            .cloned()
+    }
+}
+
+#[test]
+fn test_phrases() {
+    let phrases = Phrases::default();
+    assert!(phrases.error.starts_with("抱歉"));
+    assert!(phrases.greeting.is_none());
+
+    let config: PhrasesConfig = toml::from_str(
+        r#"
+        locale = "en-US"
+        [en-US]
+        greeting = "Hello!"
+        "#,
+    )
+    .unwrap();
+    let phrases = Phrases::new(&config);
+    assert!(phrases.error.starts_with("Sorry, something"));
+    assert_eq!(phrases.greeting.as_deref(), Some("Hello!"));
+    assert_eq!(phrases.all().len(), 3);
+
+    let kids: crate::config::ProfileConfig = toml::from_str(
+        r#"
+        [phrases.en-US]
+        error = "Oops, try again!"
+        "#,
+    )
+    .unwrap();
+    let kids = Phrases::new(&config.with_profile(Some(&kids)));
+    assert_eq!(kids.error, "Oops, try again!");
+    assert_eq!(kids.greeting.as_deref(), Some("Hello!"));
+    assert!(kids.clarification.starts_with("Sorry, I didn't"));
+
+    let ja: crate::config::ProfileConfig =
+        toml::from_str(r#"phrases = { locale = "ja" }"#).unwrap();
+    let ja = Phrases::new(&config.with_profile(Some(&ja)));
+    assert!(ja.error.starts_with("申し訳"));
+    assert!(ja.greeting.is_none());
+}
+
+#[tokio::test]
+async fn test_phrase_audio() {
+    let dir = std::env::temp_dir().join(format!("echokit-phrases-{}", uuid::Uuid::new_v4()));
+    let phrases = PhrasesConfig {
+        cache_dir: Some(dir.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    let tts: TTSConfig = toml::from_str(r#"platform = "Mock""#).unwrap();
+    let mut profiles = HashMap::new();
+    profiles.insert(
+        "kids".to_string(),
+        toml::from_str::<ProfileConfig>(r#"voice = "kid""#).unwrap(),
+    );
+    let speech_text = SpeechTextConfig::default();
+
+    let audio = PhraseAudio::new(&phrases, 16000);
+    audio
+        .render(&tts, &speech_text, &phrases, &profiles)
+        .await
+        .unwrap();
+    let error = Phrases::default().error;
+    assert!(audio.get(&tts, &speech_text, &error).is_some());
+    assert!(audio
+        .get(&tts.with_voice("kid"), &speech_text, &error)
+        .is_some());
+    assert!(audio
+        .get(&tts.with_voice("other"), &speech_text, &error)
+        .is_none());
+
+    // the second start reads the cache
+    let cached = PhraseAudio::new(&phrases, 16000);
+    cached
+        .render(&tts, &speech_text, &phrases, &HashMap::new())
+        .await
+        .unwrap();
+    assert_eq!(
+        cached.get(&tts, &speech_text, &error),
+        audio.get(&tts, &speech_text, &error)
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,68 @@
+use axum::{extract::Query, http::HeaderMap, response::IntoResponse, Extension, Router};
+
+#[derive(Debug, serde::Deserialize)]
+struct ProfileParams {
+    #[serde(default = "default_seconds")]
+    seconds: u64,
+    #[serde(default = "default_frequency")]
+    frequency: i32,
+}
+
+fn default_seconds() -> u64 {
+    10
+}
+
+fn default_frequency() -> i32 {
+    99
+}
+
+fn cpu_profile(seconds: u64, frequency: i32) -> anyhow::Result<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    std::thread::sleep(std::time::Duration::from_secs(seconds));
+
+    let report = guard.report().build()?;
+    let mut svg = Vec::new();
+    report.flamegraph(&mut svg)?;
+    Ok(svg)
+}
+
+/// GET /debug/pprof/profile?seconds=10, bearer `admin_token`
+/// return: cpu flamegraph (svg) of the whole process
+async fn profile(
+    Extension(admin_token): Extension<Option<String>>,
+    headers: HeaderMap,
+    Query(params): Query<ProfileParams>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &admin_token) {
+        return code.into_response();
+    }
+    let seconds = params.seconds.clamp(1, 300);
+    log::info!("start cpu profiling for {seconds}s");
+
+    let r = tokio::task::spawn_blocking(move || cpu_profile(seconds, params.frequency)).await;
+    match r {
+        Ok(Ok(svg)) => (
+            http::StatusCode::OK,
+            [(http::header::CONTENT_TYPE, "image/svg+xml")],
+            svg,
+        )
+            .into_response(),
+        Ok(Err(e)) => {
+            log::error!("cpu profiling error: {e}");
+            (http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+        Err(e) => {
+            log::error!("cpu profiling task error: {e}");
+            (http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+pub fn new_profiling_service(admin_token: Option<String>) -> Router {
+    Router::new()
+        .route("/profile", axum::routing::get(profile))
+        .layer(Extension(admin_token))
+}
@@ -0,0 +1,305 @@
+//! Live prompt editing.
+//!
+//! admin (bearer `admin_token`):
+//! - `GET /v1/prompts`
+//! - `PUT /v1/prompts` `{"sys_prompts": [...], "dynamic_prompts": [...]}` replaces the
+//!   `llm.sys_prompts` and `llm.dynamic_prompts` of the config
+//!
+//! the connected sessions take the new prompts at their next turn and tell their client,
+//! `session.prompts.updated` on the realtime endpoints and `PromptsUpdated` on `/ws/{id}`.
+//! a session keeps the prompts it no longer has from the config, the ones of a device
+//! profile or of a client `system` item. the last edit is kept by the [`Store`] and
+//! replaces the prompts of the config after a restart.
+
+use std::sync::Arc;
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
+use tokio::sync::watch;
+
+use crate::{
+    ai::{llm::Content, ChatSession},
+    services::sessions::SessionManager,
+    storage::{Storage, Store},
+};
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Prompts {
+    #[serde(default)]
+    pub sys_prompts: Vec<Content>,
+    #[serde(default)]
+    pub dynamic_prompts: Vec<Content>,
+}
+
+/// body of `GET /v1/prompts`, and the edit kept by the store
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PromptsInfo {
+    /// 0 for the prompts of the config, incremented by each edit
+    pub version: u64,
+    #[serde(flatten)]
+    pub prompts: Prompts,
+}
+
+/// the current prompts, shared by the sessions
+#[derive(Debug)]
+pub struct LivePrompts {
+    /// the prompts of the config, the sessions are created with
+    config: Prompts,
+    tx: watch::Sender<(u64, Arc<Prompts>)>,
+    store: Option<Arc<Storage>>,
+    /// one edit at a time, kept in the order of the versions
+    edit: tokio::sync::Mutex<()>,
+}
+
+impl Default for LivePrompts {
+    fn default() -> Self {
+        Self::new(Prompts::default())
+    }
+}
+
+impl LivePrompts {
+    pub fn new(prompts: Prompts) -> Self {
+        Self {
+            tx: watch::channel((0, Arc::new(prompts.clone()))).0,
+            config: prompts,
+            store: None,
+            edit: Default::default(),
+        }
+    }
+
+    /// the edits are kept by `store`
+    pub fn with_store(mut self, store: Arc<Storage>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// the last edit kept by the store replaces the prompts of the config
+    pub async fn load(&self) -> anyhow::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        if let Some(info) = store.prompts().await? {
+            log::info!("prompts version {} from the store", info.version);
+            self.tx.send_replace((info.version, Arc::new(info.prompts)));
+        }
+        Ok(())
+    }
+
+    pub fn get(&self) -> (u64, Arc<Prompts>) {
+        self.tx.borrow().clone()
+    }
+
+    /// the new version, the sessions are not told if the store fails
+    pub async fn set(&self, prompts: Prompts) -> anyhow::Result<u64> {
+        let _edit = self.edit.lock().await;
+        let version = self.tx.borrow().0 + 1;
+        if let Some(store) = &self.store {
+            let info = PromptsInfo {
+                version,
+                prompts: prompts.clone(),
+            };
+            store.put_prompts(&info).await?;
+        }
+        self.tx.send_replace((version, Arc::new(prompts)));
+        Ok(version)
+    }
+
+    /// a session created with the prompts of the config takes the current ones, the
+    /// watch has the edits made from now on
+    pub fn start(&self, chat_session: &mut ChatSession) -> PromptWatch {
+        let mut rx = self.tx.subscribe();
+        let applied = rx.borrow_and_update().1.clone();
+        replace_prompts(chat_session, &self.config, &applied);
+        PromptWatch { rx, applied }
+    }
+}
+
+/// the edits of the prompts of one session
+#[derive(Debug)]
+pub struct PromptWatch {
+    rx: watch::Receiver<(u64, Arc<Prompts>)>,
+    /// the prompts the session has from the config
+    applied: Arc<Prompts>,
+}
+
+impl PromptWatch {
+    /// apply the last edit since the previous call, its version if there is one
+    pub fn apply(&mut self, chat_session: &mut ChatSession) -> Option<u64> {
+        if !self.rx.has_changed().unwrap_or(false) {
+            return None;
+        }
+        let (version, prompts) = self.rx.borrow_and_update().clone();
+        replace_prompts(chat_session, &self.applied, &prompts);
+        self.applied = prompts;
+        Some(version)
+    }
+}
+
+fn same(a: &[&Content], b: &[Content]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.role == b.role && a.message == b.message)
+}
+
+/// the `old` prompts the session still starts with are replaced by the `new` ones
+fn replace_prompts(chat_session: &mut ChatSession, old: &Prompts, new: &Prompts) {
+    let n = old.sys_prompts.len();
+    let sys_prompts = chat_session
+        .system_prompts
+        .iter()
+        .take(n)
+        .collect::<Vec<_>>();
+    if same(&sys_prompts, &old.sys_prompts) {
+        // the spoken style prompt is after the config ones
+        let style = chat_session.system_prompts.split_off(n);
+        chat_session.system_prompts = new.sys_prompts.clone();
+        chat_session.system_prompts.extend(style);
+    }
+
+    let n = old.dynamic_prompts.len();
+    let dynamic_prompts = chat_session.messages.iter().take(n).collect::<Vec<_>>();
+    if same(&dynamic_prompts, &old.dynamic_prompts) {
+        let rest = chat_session.messages.split_off(n);
+        chat_session.messages = new.dynamic_prompts.iter().cloned().collect();
+        chat_session.messages.extend(rest);
+    }
+}
+
+/// GET /v1/prompts
+async fn get_prompts(
+    Extension(sessions): Extension<Arc<SessionManager>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &sessions.admin_token) {
+        return code.into_response();
+    }
+    let (version, prompts) = sessions.prompts().get();
+    Json(PromptsInfo {
+        version,
+        prompts: prompts.as_ref().clone(),
+    })
+    .into_response()
+}
+
+/// PUT /v1/prompts
+async fn put_prompts(
+    Extension(sessions): Extension<Arc<SessionManager>>,
+    headers: HeaderMap,
+    Json(prompts): Json<Prompts>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &sessions.admin_token) {
+        return code.into_response();
+    }
+    let version = match sessions.prompts().set(prompts.clone()).await {
+        Ok(version) => version,
+        Err(e) => {
+            log::error!("error saving the prompts: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    log::info!(
+        "prompts updated to version {version}, {} system and {} dynamic",
+        prompts.sys_prompts.len(),
+        prompts.dynamic_prompts.len()
+    );
+    Json(PromptsInfo { version, prompts }).into_response()
+}
+
+pub fn new_prompts_service(sessions: Arc<SessionManager>) -> Router {
+    Router::new()
+        .route("/v1/prompts", get(get_prompts).put(put_prompts))
+        .layer(Extension(sessions))
+}
+
+#[tokio::test]
+async fn test_prompt_watch() {
+    let content = |role, message: &str| Content {
+        role,
+        message: message.to_string(),
+        tool_calls: None,
+        tool_call_id: None,
+        images: vec![],
+    };
+    use crate::ai::llm::Role;
+
+    let config = Prompts {
+        sys_prompts: vec![content(Role::System, "You are Hu Tao.")],
+        dynamic_prompts: vec![
+            content(Role::User, "Hi"),
+            content(Role::Assistant, "Hello!"),
+        ],
+    };
+    let live = LivePrompts::new(config.clone());
+    let new_session = || {
+        let mut chat_session = ChatSession::new(
+            String::new(),
+            String::new(),
+            String::new(),
+            None,
+            10,
+            Default::default(),
+        );
+        chat_session.system_prompts = config.sys_prompts.clone();
+        chat_session.messages = config.dynamic_prompts.iter().cloned().collect();
+        chat_session
+    };
+    let mut session = new_session();
+    session
+        .system_prompts
+        .push(content(Role::System, "Be brief."));
+    session.add_user_message("What time is it?".to_string());
+    let mut watch = live.start(&mut session);
+    // a profile replaced the system prompts
+    let mut profiled = new_session();
+    let mut profiled_watch = live.start(&mut profiled);
+    profiled.system_prompts = vec![content(Role::System, "You are a teacher.")];
+
+    assert_eq!(watch.apply(&mut session), None);
+    let pirate = Prompts {
+        sys_prompts: vec![content(Role::System, "You are a pirate.")],
+        dynamic_prompts: vec![],
+    };
+    let version = live.set(pirate.clone()).await.unwrap();
+    assert_eq!(version, 1);
+    assert_eq!(watch.apply(&mut session), Some(1));
+    assert_eq!(watch.apply(&mut session), None);
+    let system = session.system_prompts.iter().map(|c| c.message.as_str());
+    assert_eq!(
+        system.collect::<Vec<_>>(),
+        ["You are a pirate.", "Be brief."]
+    );
+    let messages = session.messages.iter().map(|c| c.message.as_str());
+    assert_eq!(messages.collect::<Vec<_>>(), ["What time is it?"]);
+
+    assert_eq!(profiled_watch.apply(&mut profiled), Some(1));
+    assert_eq!(profiled.system_prompts[0].message, "You are a teacher.");
+    assert!(profiled.messages.is_empty());
+
+    // a later session starts with the edit
+    let mut later = new_session();
+    let mut later_watch = live.start(&mut later);
+    assert_eq!(later.system_prompts[0].message, "You are a pirate.");
+    assert!(later.messages.is_empty());
+    assert_eq!(later_watch.apply(&mut later), None);
+
+    // the edit is back after a restart
+    let dir = std::env::temp_dir().join(format!("echokit_prompts_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let store = Arc::new(Storage::File(crate::storage::file::FileStore::new(
+        &dir.to_string_lossy(),
+        None,
+    )));
+    let live = LivePrompts::new(config.clone()).with_store(store.clone());
+    live.set(pirate).await.unwrap();
+    let restarted = LivePrompts::new(config.clone()).with_store(store);
+    restarted.load().await.unwrap();
+    let (version, prompts) = restarted.get();
+    assert_eq!(version, 1);
+    assert_eq!(prompts.sys_prompts[0].message, "You are a pirate.");
+    let _ = std::fs::remove_dir_all(dir);
+}
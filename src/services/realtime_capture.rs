@@ -0,0 +1,344 @@
+//! Capture of the realtime sessions, `[capture]`: a jsonl file per session under `dir`
+//! with the client events as received, the audio included (binary frames are written as
+//! `input_audio_buffer.append`), and the type of each server event sent.
+//!
+//! [`replay`] feeds the client events of a capture to a new session through
+//! `handle_client_message` and returns the server event types, a test compares them to
+//! the captured ones so a change of the event order is caught. the events are replayed
+//! one after the other, a `response.cancel` sent during a response lands after it.
+
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+use crate::{
+    ai::openai::realtime::{ClientEvent, ServerEvent},
+    config::CaptureConfig,
+    services::realtime_ws::{RealtimeSession, StableRealtimeConfig},
+};
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "capture", rename_all = "snake_case")]
+pub enum CaptureLine {
+    Start {
+        session_id: String,
+        text_only: bool,
+        time: String,
+    },
+    Client {
+        at_ms: u64,
+        event: ClientEvent,
+    },
+    Server {
+        at_ms: u64,
+        event_type: String,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    pub text_only: bool,
+    pub lines: Vec<CaptureLine>,
+}
+
+impl Capture {
+    pub fn parse(jsonl: &str) -> anyhow::Result<Self> {
+        let mut capture = Capture::default();
+        for (n, line) in jsonl.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line: CaptureLine = serde_json::from_str(line)
+                .map_err(|e| anyhow::anyhow!("capture line {}: {e}", n + 1))?;
+            if let CaptureLine::Start { text_only, .. } = &line {
+                capture.text_only = *text_only;
+            }
+            capture.lines.push(line);
+        }
+        Ok(capture)
+    }
+
+    pub async fn load(path: &str) -> anyhow::Result<Self> {
+        Self::parse(&tokio::fs::read_to_string(path).await?)
+    }
+
+    pub fn to_jsonl(&self) -> anyhow::Result<String> {
+        let mut jsonl = String::new();
+        for line in &self.lines {
+            jsonl.push_str(&serde_json::to_string(line)?);
+            jsonl.push('\n');
+        }
+        Ok(jsonl)
+    }
+
+    pub fn client_events(&self) -> impl Iterator<Item = &ClientEvent> {
+        self.lines.iter().filter_map(|line| match line {
+            CaptureLine::Client { event, .. } => Some(event),
+            _ => None,
+        })
+    }
+
+    pub fn server_event_types(&self) -> Vec<String> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                CaptureLine::Server { event_type, .. } => Some(event_type.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// the `type` of the event
+pub fn event_type(event: &ServerEvent) -> String {
+    serde_json::to_value(event)
+        .ok()
+        .and_then(|v| v.get("type")?.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// the types with each run of a same `*.delta` kept once, their number depends on
+/// the chunking of the llm and tts
+pub fn event_sequence<I: IntoIterator<Item = String>>(types: I) -> Vec<String> {
+    let mut sequence: Vec<String> = vec![];
+    for t in types {
+        if t.ends_with(".delta") && sequence.last() == Some(&t) {
+            continue;
+        }
+        sequence.push(t);
+    }
+    sequence
+}
+
+/// writes the lines of a session in the background, the clones write to the same file
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    tx: mpsc::UnboundedSender<CaptureLine>,
+    start: std::time::Instant,
+}
+
+impl Recorder {
+    pub fn start(config: &CaptureConfig, session_id: &str, text_only: bool) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<CaptureLine>();
+        let path = std::path::Path::new(&config.dir).join(format!("{session_id}.jsonl"));
+        let _ = tx.send(CaptureLine::Start {
+            session_id: session_id.to_string(),
+            text_only,
+            time: chrono::Utc::now().to_rfc3339(),
+        });
+        tokio::spawn(async move {
+            let open = async {
+                tokio::fs::create_dir_all(path.parent().unwrap_or(&path)).await?;
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await
+            };
+            let mut file = match open.await {
+                Ok(file) => file,
+                Err(e) => {
+                    log::error!("capture {} open error: {e}", path.display());
+                    return;
+                }
+            };
+            while let Some(line) = rx.recv().await {
+                let Ok(mut json) = serde_json::to_string(&line) else {
+                    continue;
+                };
+                json.push('\n');
+                if let Err(e) = file.write_all(json.as_bytes()).await {
+                    log::error!("capture {} write error: {e}", path.display());
+                    return;
+                }
+            }
+        });
+        Self {
+            tx,
+            start: std::time::Instant::now(),
+        }
+    }
+
+    fn at_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    pub fn client(&self, event: &ClientEvent) {
+        let _ = self.tx.send(CaptureLine::Client {
+            at_ms: self.at_ms(),
+            event: event.clone(),
+        });
+    }
+
+    pub fn server(&self, event: &ServerEvent) {
+        let _ = self.tx.send(CaptureLine::Server {
+            at_ms: self.at_ms(),
+            event_type: event_type(event),
+        });
+    }
+}
+
+/// the types of the server events of the client events of `capture`, in order
+pub async fn replay(
+    capture: &Capture,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<Vec<String>> {
+    Ok(replay_events(capture, config)
+        .await?
+        .iter()
+        .map(event_type)
+        .collect())
+}
+
+/// the server events of the client events of `capture`, in order
+pub async fn replay_events(
+    capture: &Capture,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<Vec<ServerEvent>> {
+    let chat_session = super::realtime_ws::new_chat_session(config, None);
+    let mut session = if capture.text_only {
+        RealtimeSession::new_text_only(chat_session)
+    } else {
+        RealtimeSession::new(chat_session)
+    };
+
+    let (tx, mut rx) = mpsc::channel::<ServerEvent>(config.stream.event_channel_capacity);
+    let collector = tokio::spawn(async move {
+        let mut events = vec![];
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        events
+    });
+
+    for event in capture.client_events() {
+        if let Err(e) =
+            super::realtime_ws::handle_client_message(event.clone(), &mut session, &tx, config)
+                .await
+        {
+            log::error!("replay error: {e}");
+        }
+    }
+    drop(tx);
+    Ok(collector.await?)
+}
+
+#[tokio::test]
+async fn test_replay() {
+    use crate::config::{ASRConfig, LLMConfig, TTSConfig};
+    use std::sync::Arc;
+
+    let llm: LLMConfig = toml::from_str(
+        r#"
+        history = 5
+        [mock]
+        replies = ["Hi there!"]
+        chunk_delay_ms = 0
+        "#,
+    )
+    .unwrap();
+    let tts: TTSConfig = toml::from_str(r#"platform = "Mock""#).unwrap();
+    let asr: ASRConfig = toml::from_str(r#"transcripts = ["hello"]"#).unwrap();
+    let config = StableRealtimeConfig {
+        llm,
+        tts,
+        asr: asr.whisper().unwrap(),
+        stream: Default::default(),
+        speech_text: Default::default(),
+        registry: None,
+        sessions: Arc::new(crate::services::sessions::SessionManager::new(
+            None, None, None, None,
+        )),
+        webhooks: Arc::new(crate::services::webhooks::Webhooks::new(vec![])),
+        capture: None,
+        phrases: Default::default(),
+        phrase_audio: Default::default(),
+        earcons: Default::default(),
+        earcon_audio: Default::default(),
+        intents: Default::default(),
+    };
+
+    let client = |at_ms: u64, event: serde_json::Value| CaptureLine::Client {
+        at_ms,
+        event: serde_json::from_value(event).unwrap(),
+    };
+    let mut lines = vec![
+        CaptureLine::Start {
+            session_id: "s1".to_string(),
+            text_only: false,
+            time: chrono::Utc::now().to_rfc3339(),
+        },
+        client(
+            0,
+            serde_json::json!({
+                "type": "session.update",
+                "session": { "modalities": ["text", "audio"] }
+            }),
+        ),
+        // 100ms of silence at 24k
+        client(
+            10,
+            serde_json::json!({ "type": "input_audio_buffer.append", "audio": "A".repeat(6400) }),
+        ),
+        client(
+            20,
+            serde_json::json!({ "type": "input_audio_buffer.commit" }),
+        ),
+        client(
+            900,
+            serde_json::json!({
+                "type": "conversation.item.create",
+                "item": {
+                    "type": "message",
+                    "role": "user",
+                    "content": [{ "type": "input_text", "text": "and now?" }]
+                }
+            }),
+        ),
+        client(910, serde_json::json!({ "type": "response.create" })),
+    ];
+    let turn = "turn.state.changed";
+    let response = [
+        "response.created",
+        turn,
+        "response.output_item.added",
+        "response.content_part.added",
+        "response.content_part.added",
+        "response.text.delta",
+        "response.audio.delta",
+        "response.audio.transcript.delta",
+        "response.text.done",
+        "response.content_part.done",
+        "response.audio.done",
+        "response.audio.transcript.done",
+        "response.content_part.done",
+        "response.output_item.done",
+        "response.done",
+        turn,
+    ];
+    let expected = ["session.updated", turn, turn]
+        .into_iter()
+        .chain([
+            "input_audio_buffer.committed",
+            "conversation.item.created",
+            "conversation.item.input_audio_transcription.completed",
+            turn,
+        ])
+        .chain(response)
+        .chain(["conversation.item.created", turn])
+        .chain(response);
+    lines.extend(expected.map(|t| CaptureLine::Server {
+        at_ms: 0,
+        event_type: t.to_string(),
+    }));
+    let capture = Capture {
+        text_only: false,
+        lines,
+    };
+
+    let capture = Capture::parse(&capture.to_jsonl().unwrap()).unwrap();
+    assert_eq!(capture.client_events().count(), 5);
+    let replayed = replay(&capture, &config).await.unwrap();
+    assert_eq!(
+        event_sequence(replayed),
+        event_sequence(capture.server_event_types())
+    );
+}
@@ -1,40 +1,149 @@
 use axum::{
-    extract::{ws::WebSocket, Extension, WebSocketUpgrade},
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Extension, Query, WebSocketUpgrade,
+    },
     response::IntoResponse,
 };
 use base64::Engine;
-use bytes::BufMut;
+use bytes::{Bytes, BytesMut};
 use futures_util::{sink::SinkExt, stream::StreamExt};
 use std::{sync::Arc, vec};
 use tokio::sync::mpsc;
-use uuid::Uuid;
 
 use crate::{
     ai::{openai::realtime::*, ChatSession},
     config::*,
+    services::{
+        dictation::Dictation,
+        earcons::{Earcon, Earcons, EARCON_RESPONSE_ID},
+        prompts::PromptWatch,
+        turn_state::{TurnEvent, TurnStateMachine},
+    },
+    util::new_uuid,
 };
 
-// 添加常量定义
-const STANDARD_ERROR_RESPONSE: &str = "抱歉，我没能理解您的回复。请您换种表达方式重新说一下";
+/// consecutive malformed client events before the socket is closed
+const MAX_INVALID_EVENTS: usize = 10;
+/// camera frames older than this are not attached to a user turn
+const CAMERA_FRAME_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(30);
 
 fn encode_base64(data: &[u8]) -> String {
     base64::prelude::BASE64_STANDARD.encode(data)
 }
 
-fn decode_base64(data: &str) -> anyhow::Result<Vec<u8>> {
+/// the base64 `response.audio.delta` payloads of the pcm16, `chunk_size` bytes each
+pub fn audio_deltas(audio: &[u8], chunk_size: usize) -> Vec<String> {
+    audio.chunks(chunk_size.max(2)).map(encode_base64).collect()
+}
+
+pub fn decode_base64(data: &str) -> anyhow::Result<Vec<u8>> {
     base64::prelude::BASE64_STANDARD
         .decode(data)
         .map_err(|e| anyhow::anyhow!("Base64 decode error: {}", e))
 }
 
+// base64 of audio payloads is CPU-bound, keep it off the async executor
+pub(crate) async fn encode_base64_blocking(data: Bytes) -> anyhow::Result<String> {
+    Ok(tokio::task::spawn_blocking(move || encode_base64(&data)).await?)
+}
+
+async fn decode_base64_blocking(data: String) -> anyhow::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || decode_base64(&data)).await?
+}
+
 pub struct RealtimeSession {
     pub client: reqwest::Client,
     pub chat_session: ChatSession,
     pub id: String,
     pub config: SessionConfig,
     // pub conversation: Vec<ConversationItem>,
-    pub input_audio_buffer: Vec<u8>,
-    pub is_generating: bool,
+    pub input_audio_buffer: BytesMut,
+    /// sample rate of the pcm16 in `input_audio_buffer`
+    pub input_sample_rate: u32,
+    /// who has the floor, see [`crate::services::turn_state`]
+    pub turn: TurnStateMachine,
+    /// set in continuous-listening mode (`turn_detection.type = server_vad / semantic_vad`)
+    pub server_vad: Option<ServerVad>,
+    /// item id of the utterance started by a client speech hint
+    pub speech_hint_item_id: Option<String>,
+    /// latest camera frame as a `data:` url, with the time it arrived
+    pub camera_frame: Option<(std::time::Instant, String)>,
+    /// a `/v1/chat/ws` session: text in and out, the audio events are rejected
+    pub text_only: bool,
+    /// a transcription session (`session.type = transcription`): the committed audio is
+    /// transcribed and never answered, the transcripts are not kept in the conversation
+    pub transcription_only: bool,
+    /// the document of a dictation session, which is a transcription session too
+    pub dictation: Option<Dictation>,
+    /// the edits of the live prompts, see [`crate::services::prompts`]
+    pub prompts: Option<PromptWatch>,
+    /// the responses run in a [`ResponseTask`] next to the receive loop, which keeps
+    /// taking the input audio and the cancels meanwhile
+    pub duplex: bool,
+    /// a response asked of a duplex session, started by its receive loop
+    pub response_requested: bool,
+    /// played when the user takes the floor and before the error phrase
+    pub earcons: Earcons,
+    /// the last answer spoken, `conversation.item.truncate` cuts its transcript
+    pub spoken: Option<SpokenItem>,
+    /// the playback of an item acked with `output_audio_buffer.played`
+    pub played: Option<(String, u64)>,
+    /// a truncate of an answer still generated by the [`ResponseTask`]
+    pub pending_truncate: Option<(String, u64)>,
+    /// calibrated noise floor of a `/device/ws` device, adapts the vad and denoises the
+    /// 16k input audio
+    pub noise_floor_db: Option<f32>,
+}
+
+/// streaming vad of the continuous-listening mode, commits the input audio buffer
+/// at the end of each utterance
+pub struct ServerVad {
+    source: VadSource,
+    /// item id of the utterance in progress
+    item_id: Option<String>,
+    /// params the vad was connected with, a change reconnects it
+    params: crate::ai::vad::VadParams,
+    /// semantic end-of-turn detection
+    semantic: Option<crate::config::SemanticTurnConfig>,
+    /// transcript of the turn so far, the turn may span several utterances
+    turn_text: String,
+    /// the response of an incomplete turn is generated at this time if the user stays silent
+    turn_deadline: Option<tokio::time::Instant>,
+}
+
+/// where the speech start / end events come from
+enum VadSource {
+    /// the `asr.vad_realtime_url` server
+    Remote(
+        crate::ai::vad::VadRealtimeClient,
+        crate::ai::vad::VadRealtimeRx,
+    ),
+    /// local fallback while the server is down, events queued by `push_input_audio`
+    Energy(
+        crate::ai::energy_vad::EnergyVad,
+        std::collections::VecDeque<&'static str>,
+    ),
+}
+
+impl VadSource {
+    fn energy(asr: &WhisperASRConfig, params: &crate::ai::vad::VadParams) -> Self {
+        let mut config = asr.energy_vad.adapted(params.noise_floor_db);
+        if let Some(silence_duration_ms) = params.silence_duration_ms {
+            config.hangover_ms = silence_duration_ms;
+        }
+        Self::Energy(
+            crate::ai::energy_vad::EnergyVad::new(config),
+            Default::default(),
+        )
+    }
+}
+
+impl ServerVad {
+    fn reset_turn(&mut self) {
+        self.turn_text.clear();
+        self.turn_deadline = None;
+    }
 }
 
 impl RealtimeSession {
@@ -42,55 +151,1129 @@ impl RealtimeSession {
         Self {
             client: reqwest::Client::new(),
             chat_session,
-            id: Uuid::new_v4().to_string(),
+            id: new_uuid().to_string(),
             config: SessionConfig::default(),
             // conversation: Vec::new(),
-            input_audio_buffer: Vec::new(),
-            is_generating: false,
+            input_audio_buffer: BytesMut::new(),
+            input_sample_rate: crate::util::WavConfig::default().sample_rate,
+            turn: TurnStateMachine::default(),
+            server_vad: None,
+            speech_hint_item_id: None,
+            camera_frame: None,
+            text_only: false,
+            transcription_only: false,
+            dictation: None,
+            prompts: None,
+            duplex: false,
+            response_requested: false,
+            earcons: Earcons::default(),
+            spoken: None,
+            played: None,
+            pending_truncate: None,
+            noise_floor_db: None,
+        }
+    }
+
+    /// text-only session of `/v1/chat/ws`
+    pub fn new_text_only(chat_session: ChatSession) -> Self {
+        let mut session = Self::new(chat_session);
+        session.text_only = true;
+        session.config.modalities = Some(vec![Modality::Text]);
+        session
+    }
+
+    pub fn session_type(&self) -> SessionType {
+        match (self.dictation.is_some(), self.transcription_only) {
+            (true, _) => SessionType::Dictation,
+            (false, true) => SessionType::Transcription,
+            (false, false) => SessionType::Realtime,
+        }
+    }
+
+    /// start or stop dictating, the document in progress is sent when it stops
+    pub async fn set_session_type(&mut self, tx: &mpsc::Sender<ServerEvent>, t: SessionType) {
+        self.transcription_only = t != SessionType::Realtime;
+        match (t == SessionType::Dictation, &mut self.dictation) {
+            (true, None) => self.dictation = Some(Dictation::default()),
+            (false, Some(dictation)) => {
+                if let Some(item) = dictation.finish() {
+                    let _ = tx.send(item).await;
+                }
+                self.dictation = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// whether responses are spoken, see `session.modalities`
+    pub fn wants_audio(&self) -> bool {
+        !self.text_only
+            && self
+                .config
+                .modalities
+                .as_ref()
+                .is_some_and(|m| m.contains(&Modality::Audio))
+    }
+
+    /// whether responses are only spoken, `session.modalities = ["audio"]`: no text part
+    /// and no `response.text.*` events, the transcript comes with the audio
+    pub fn audio_only(&self) -> bool {
+        self.wants_audio()
+            && self
+                .config
+                .modalities
+                .as_ref()
+                .is_some_and(|m| !m.contains(&Modality::Text))
+    }
+
+    /// the asr of the session: the model, language and prompt of
+    /// `input_audio_transcription` over the ones of `[asr]`
+    pub fn transcription(&self, asr: &WhisperASRConfig) -> InputAudioTranscription {
+        let requested = self
+            .config
+            .input_audio_transcription
+            .clone()
+            .unwrap_or_default();
+        let or_asr = |value: Option<String>, default: &str| {
+            value
+                .filter(|v| !v.is_empty())
+                .or_else(|| (!default.is_empty()).then(|| default.to_string()))
+        };
+        let model = requested.model.filter(|m| asr.models.contains(m));
+        InputAudioTranscription {
+            enabled: requested.enabled,
+            model: or_asr(model, &asr.model),
+            language: or_asr(requested.language, &asr.lang),
+            prompt: or_asr(requested.prompt, &asr.prompt),
+        }
+    }
+
+    /// keep the latest camera frame (base64 jpeg / png), ignored unless the llm is a vision model
+    pub fn set_camera_frame(&mut self, image: String) {
+        if !self.chat_session.vision {
+            log::debug!(
+                "session {} camera frame ignored, llm.vision is off",
+                self.id
+            );
+            return;
+        }
+        let part = ContentPart::InputImage {
+            image_url: None,
+            image: Some(image),
+        };
+        self.camera_frame = part.image_url().map(|url| (std::time::Instant::now(), url));
+    }
+
+    /// the camera frame for the next user turn, if it is recent enough
+    pub fn take_camera_frame(&mut self) -> Option<String> {
+        self.camera_frame
+            .take()
+            .filter(|(at, _)| at.elapsed() <= CAMERA_FRAME_MAX_AGE)
+            .map(|(_, url)| url)
+    }
+
+    pub fn is_continuous(&self) -> bool {
+        self.server_vad.is_some()
+    }
+
+    /// switch between push-to-talk and continuous listening according to
+    /// `config.turn_detection`
+    pub async fn apply_turn_detection(&mut self, asr: &WhisperASRConfig) -> anyhow::Result<()> {
+        let turn_type = self.config.turn_detection.as_ref().map(|td| &td.turn_type);
+        let semantic = match turn_type {
+            Some(TurnDetectionType::SemanticVad) => {
+                Some(asr.semantic_turn.clone().unwrap_or_default())
+            }
+            Some(TurnDetectionType::ServerVad) => asr.semantic_turn.clone(),
+            _ => {
+                if self.server_vad.take().is_some() {
+                    log::info!("`{}` switched to push-to-talk", self.id);
+                }
+                return Ok(());
+            }
+        };
+
+        let params = self.vad_params();
+        if let Some(vad) = &mut self.server_vad {
+            if vad.params == params {
+                vad.semantic = semantic;
+                return Ok(());
+            }
+            log::info!("`{}` vad params changed to {params:?}", self.id);
+        }
+
+        let vad_url = asr
+            .vad_realtime_url
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("server vad requires asr.vad_realtime_url"))?;
+        let source =
+            match crate::ai::vad::vad_realtime_client(&self.client, vad_url.clone(), &params).await
+            {
+                Ok((client, rx)) => VadSource::Remote(client, rx),
+                Err(e) if asr.energy_vad.fallback => {
+                    log::warn!("`{}` server vad error: {e}, using the energy vad", self.id);
+                    VadSource::energy(asr, &params)
+                }
+                Err(e) => return Err(e),
+            };
+        let was_continuous = self.server_vad.is_some();
+        self.server_vad = Some(ServerVad {
+            source,
+            item_id: None,
+            params,
+            semantic,
+            turn_text: String::new(),
+            turn_deadline: None,
+        });
+        if !was_continuous {
+            log::info!("`{}` switched to continuous listening", self.id);
+        }
+        Ok(())
+    }
+
+    /// vad params of `config.turn_detection`
+    pub fn vad_params(&self) -> crate::ai::vad::VadParams {
+        match &self.config.turn_detection {
+            Some(td) => crate::ai::vad::VadParams {
+                threshold: td.threshold,
+                prefix_padding_ms: td.prefix_padding_ms,
+                silence_duration_ms: td.silence_duration_ms,
+                noise_floor_db: self.noise_floor_db,
+            },
+            None => crate::ai::vad::VadParams {
+                noise_floor_db: self.noise_floor_db,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// keep only `prefix_padding_ms` of the audio before the start of speech
+    fn trim_input_audio_prefix(&mut self, prefix_padding_ms: u32) {
+        let keep = 2 * (self.input_sample_rate as u64 * prefix_padding_ms as u64 / 1000) as usize;
+        let len = self.input_audio_buffer.len();
+        if len > keep {
+            let _ = self.input_audio_buffer.split_to(len - keep);
+        }
+    }
+
+    /// append to the input audio buffer, and feed the server vad in continuous mode
+    pub async fn push_input_audio(&mut self, pcm: &[u8]) -> anyhow::Result<()> {
+        self.input_audio_buffer.extend_from_slice(pcm);
+
+        if let Some(vad) = &mut self.server_vad {
+            let in_hz = self.input_sample_rate;
+            let audio_16k = if in_hz == 16000 {
+                Bytes::copy_from_slice(pcm)
+            } else {
+                let pcm = pcm.to_vec();
+                tokio::task::spawn_blocking(move || {
+                    crate::services::ws::resample_pcm16(&pcm, in_hz, 16000)
+                })
+                .await??
+            };
+            match &mut vad.source {
+                VadSource::Remote(client, _) => client.push_audio_16k_chunk(audio_16k).await?,
+                VadSource::Energy(energy, events) => {
+                    events.extend(energy.push_audio_16k_chunk(&audio_16k))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// next event of the server vad, pending forever in push-to-talk mode
+    pub async fn next_vad_event(&mut self) -> anyhow::Result<crate::ai::vad::VadRealtimeEvent> {
+        let Some(vad) = &mut self.server_vad else {
+            return std::future::pending().await;
+        };
+
+        if let VadSource::Energy(_, events) = &mut vad.source {
+            if let Some(event) = events.pop_front() {
+                return Ok(crate::ai::vad::VadRealtimeEvent::Event {
+                    event: event.to_string(),
+                });
+            }
+        }
+
+        let deadline = vad.turn_deadline;
+        let wait = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now));
+        let source = &mut vad.source;
+        let next = async move {
+            match source {
+                VadSource::Remote(_, rx) => rx.next_event().await,
+                VadSource::Energy(..) => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            event = next => return event,
+            _ = wait, if deadline.is_some() => {}
+        }
+        vad.turn_deadline = None;
+        Ok(crate::ai::vad::VadRealtimeEvent::TurnTimeout)
+    }
+
+    /// with semantic turn detection, whether the turn so far looks unfinished.
+    /// if so the response is held back until the user speaks again or `max_wait_ms` passes
+    async fn wait_for_turn_end(&mut self) -> bool {
+        let Some(vad) = &mut self.server_vad else {
+            return false;
+        };
+        let Some(semantic) = &vad.semantic else {
+            return false;
+        };
+        if crate::ai::turn::is_end_of_turn(&self.client, semantic, &vad.turn_text).await {
+            return false;
+        }
+        vad.turn_deadline = Some(
+            tokio::time::Instant::now() + std::time::Duration::from_millis(semantic.max_wait_ms),
+        );
+        true
+    }
+
+    /// whether appending `incoming` bytes would exceed `max_ms` of buffered audio
+    fn input_audio_overflows(&self, incoming: usize, max_ms: u32) -> bool {
+        let max_bytes = 2 * self.input_sample_rate as u64 * max_ms as u64 / 1000;
+        (self.input_audio_buffer.len() + incoming) as u64 > max_bytes
+    }
+
+    /// duration of the input audio buffer
+    fn input_audio_ms(&self) -> u32 {
+        (self.input_audio_buffer.len() as u64 / 2 * 1000 / self.input_sample_rate as u64) as u32
+    }
+
+    /// the last message is an answer, there is nothing to respond to
+    fn answered(&self) -> bool {
+        self.chat_session
+            .messages
+            .back()
+            .is_some_and(|m| m.role == crate::ai::llm::Role::Assistant)
+    }
+
+    /// the conversation handed to a response task. the session keeps an empty one with
+    /// the same settings, the input committed and the settings updated meanwhile go there
+    fn detach_response(&mut self) -> RealtimeSession {
+        let pending = self.chat_session.empty_like();
+        let mut response = RealtimeSession::new(std::mem::replace(&mut self.chat_session, pending));
+        response.client = self.client.clone();
+        response.id = self.id.clone();
+        response.config = self.config.clone();
+        response.text_only = self.text_only;
+        response.turn = self.turn.clone();
+        response.earcons = self.earcons.clone();
+        response
+    }
+
+    /// apply `event` to the turn state and tell the client of the change, `false` if it
+    /// does not apply in the current state
+    pub(crate) async fn turn_event(
+        &self,
+        tx: &mpsc::Sender<ServerEvent>,
+        event: TurnEvent,
+    ) -> bool {
+        match self.turn.on(event) {
+            Ok(changed) => {
+                if let Some(changed) = changed {
+                    let _ = tx.send(changed).await;
+                    if self.turn.state() == TurnState::Listening {
+                        self.play_earcon(tx, Earcon::Listening).await;
+                    }
+                }
+                true
+            }
+            Err(state) => {
+                log::debug!("`{}` {event:?} ignored while {state:?}", self.id);
+                false
+            }
+        }
+    }
+
+    /// the audio of an earcon as the deltas of the response `earcon`, if the session is
+    /// spoken to
+    async fn play_earcon(&self, tx: &mpsc::Sender<ServerEvent>, earcon: Earcon) {
+        let Some(audio) = self.earcons.get(earcon).filter(|_| self.wants_audio()) else {
+            return;
+        };
+        if let Err(e) = send_audio_delta(tx, &OutputItem::earcon(), audio).await {
+            log::warn!("`{}` earcon {earcon:?} error: {e}", self.id);
+        }
+    }
+
+    /// the transcript committed last is not answered after all
+    async fn skip_response(&self, tx: &mpsc::Sender<ServerEvent>) {
+        if self.turn.state() == TurnState::Transcribing {
+            self.turn_event(tx, TurnEvent::Unanswered).await;
+        }
+    }
+
+    /// the conversation back from the [`ResponseTask`], the messages added meanwhile
+    /// come after the answer and the settings updated meanwhile are kept
+    pub(crate) async fn attach_response(
+        &mut self,
+        tx: &mpsc::Sender<ServerEvent>,
+        done: ResponseDone,
+    ) -> anyhow::Result<()> {
+        let (response, r) = match done {
+            Ok(done) => done,
+            Err(e) => {
+                self.turn_event(tx, TurnEvent::ResponseDone).await;
+                return Err(anyhow::anyhow!("response task error: {e}"));
+            }
+        };
+        let mut messages = response.chat_session.messages;
+        messages.append(&mut self.chat_session.messages);
+        self.chat_session.messages = messages;
+        self.chat_session.prompt_tokens += response.chat_session.prompt_tokens;
+        if response.spoken.is_some() {
+            self.spoken = response.spoken;
+            self.apply_playback();
+        }
+        r
+    }
+
+    /// the truncate that came while the answer was generated, or the playback acked if
+    /// the answer was interrupted
+    fn apply_playback(&mut self) {
+        let Some(spoken) = &self.spoken else {
+            return;
+        };
+        let item_id = spoken.item_id.clone();
+        let position = match self.pending_truncate.take() {
+            Some((id, ms)) if id == item_id => Some(ms),
+            _ if spoken.interrupted => self
+                .played
+                .as_ref()
+                .filter(|(id, _)| *id == item_id)
+                .map(|(_, ms)| *ms),
+            _ => None,
+        };
+        if let Some(ms) = position {
+            self.truncate_spoken(&item_id, ms);
+        }
+    }
+
+    /// keep the transcript of the spoken `item_id` up to `audio_end_ms` in the conversation,
+    /// the position cut at, at most the audio delivered, or none if it is not the last
+    /// answer spoken
+    fn truncate_spoken(&mut self, item_id: &str, audio_end_ms: u64) -> Option<u64> {
+        if self.spoken.as_ref().is_none_or(|s| s.item_id != item_id) {
+            return None;
+        }
+        let spoken = self.spoken.take()?;
+        let audio_end_ms = audio_end_ms.min(spoken.delivered_ms());
+        let heard = spoken.heard(audio_end_ms);
+        let full = spoken.text.trim_end();
+        if heard.trim_end() == full {
+            return Some(audio_end_ms);
+        }
+        let answer = self
+            .chat_session
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|m| m.role == crate::ai::llm::Role::Assistant)
+            .filter(|m| m.message.starts_with(full));
+        if let Some(answer) = answer {
+            answer.message = format!("{}{}", heard.trim_end(), crate::ai::TRUNCATED_MARK)
+                .trim_start()
+                .to_string();
+        }
+        Some(audio_end_ms)
+    }
+    /// the start of speech cancels the response of a duplex session
+    fn interrupts_response(&self) -> bool {
+        self.duplex
+            && self.turn.is_responding()
+            && self
+                .config
+                .turn_detection
+                .as_ref()
+                .is_none_or(|td| td.interrupt_response != Some(false))
+    }
+}
+
+/// the conversation of a finished [`ResponseTask`] and the result of the response
+pub(crate) type ResponseDone =
+    Result<(RealtimeSession, anyhow::Result<()>), tokio::task::JoinError>;
+
+/// the response of a duplex session, generated in its own task while the session
+/// takes the input
+#[derive(Default)]
+pub(crate) struct ResponseTask(
+    Option<tokio::task::JoinHandle<(RealtimeSession, anyhow::Result<()>)>>,
+);
+
+impl ResponseTask {
+    /// start the response asked by the last event, unless one is running
+    pub async fn start_requested(
+        &mut self,
+        session: &mut RealtimeSession,
+        tx: &mpsc::Sender<ServerEvent>,
+        config: &Arc<StableRealtimeConfig>,
+    ) {
+        if self.0.is_some() || !std::mem::take(&mut session.response_requested) {
+            return;
+        }
+        if session.answered() {
+            log::debug!("Skipping response generation, last message is from assistant");
+            session.skip_response(tx).await;
+            return;
+        }
+        if let Some(vad) = &mut session.server_vad {
+            vad.reset_turn();
+        }
+        apply_prompts(session, tx).await;
+
+        let mut response = session.detach_response();
+        let (tx, config) = (tx.clone(), config.clone());
+        self.0 = Some(tokio::spawn(async move {
+            use futures_util::FutureExt;
+
+            // a panic fails the response but the conversation comes back
+            let r = std::panic::AssertUnwindSafe(generate_response(&mut response, &tx, &config))
+                .catch_unwind()
+                .await;
+            let r = match r {
+                Ok(r) => r,
+                Err(_) => {
+                    response.turn_event(&tx, TurnEvent::ResponseDone).await;
+                    Err(anyhow::anyhow!("response task panicked"))
+                }
+            };
+            (response, r)
+        }));
+    }
+
+    /// the end of the response, for [`RealtimeSession::attach_response`], pending forever
+    /// if there is none
+    pub async fn done(&mut self) -> ResponseDone {
+        let Some(task) = &mut self.0 else {
+            return std::future::pending().await;
+        };
+        let done = task.await;
+        self.0 = None;
+        done
+    }
+
+    /// cut the response of a closed session, the part generated so far is recorded
+    pub async fn finish(mut self, session: &mut RealtimeSession, tx: &mpsc::Sender<ServerEvent>) {
+        if self.0.is_some() {
+            session.chat_session.abort_handle.abort();
+            let done = self.done().await;
+            if let Err(e) = session.attach_response(tx, done).await {
+                log::error!("Error generating response: {}", e);
+            }
+        }
+    }
+}
+
+/// append to the input audio buffer, applying `stream.input_audio_overflow`
+/// when the buffer would grow beyond `stream.max_input_audio_ms`
+pub(crate) async fn handle_input_audio(
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    config: &StableRealtimeConfig,
+    pcm: &[u8],
+) -> anyhow::Result<()> {
+    if session.turn.state() == TurnState::Idle {
+        session.turn_event(tx, TurnEvent::AudioAppended).await;
+    }
+    let denoised;
+    let pcm = match session.noise_floor_db {
+        Some(floor) => {
+            denoised = crate::ai::energy_vad::denoise(pcm, floor);
+            &denoised[..]
+        }
+        None => pcm,
+    };
+    let max_ms = config.stream.max_input_audio_ms;
+    if !session.input_audio_overflows(pcm.len(), max_ms) {
+        return session.push_input_audio(pcm).await;
+    }
+
+    let policy = config.stream.input_audio_overflow;
+    log::warn!(
+        "session {} input audio buffer exceeds {max_ms}ms, {policy:?}",
+        session.id
+    );
+    let _ = tx
+        .send(ServerEvent::Error {
+            event_id: new_uuid().to_string(),
+            error: ErrorDetails {
+                error_type: "invalid_request_error".to_string(),
+                code: Some("input_audio_buffer_full".to_string()),
+                message: format!(
+                    "Input audio buffer exceeds {max_ms}ms ({} ms buffered), policy: {policy:?}",
+                    session.input_audio_ms()
+                ),
+                param: Some("audio".to_string()),
+                event_id: None,
+            },
+        })
+        .await;
+
+    match policy {
+        InputAudioOverflow::Reject => return Ok(()),
+        InputAudioOverflow::Clear => {
+            session.input_audio_buffer.clear();
+            let _ = tx
+                .send(ServerEvent::InputAudioBufferCleared {
+                    event_id: new_uuid().to_string(),
+                })
+                .await;
+        }
+        InputAudioOverflow::Commit => {
+            if handle_audio_buffer_commit(session, tx, None, config).await? {
+                generate_response(session, tx, config).await?;
+            }
+        }
+    }
+
+    // 单次追加本身就超过上限时直接丢弃
+    if session.input_audio_overflows(pcm.len(), max_ms) {
+        return Ok(());
+    }
+    session.push_input_audio(pcm).await
+}
+
+/// a chat session of the tests, keeping `history` turns
+#[cfg(test)]
+fn test_session(history: usize) -> ChatSession {
+    ChatSession::new(
+        String::new(),
+        String::new(),
+        String::new(),
+        None,
+        history,
+        Default::default(),
+    )
+}
+
+#[test]
+fn test_input_audio_overflows() {
+    let mut session = RealtimeSession::new(test_session(0));
+    session.input_sample_rate = 16000;
+    // 1s of 16k pcm16 is 32000 bytes
+    session.input_audio_buffer.extend_from_slice(&[0u8; 30000]);
+    assert!(!session.input_audio_overflows(2000, 1000));
+    assert!(session.input_audio_overflows(2002, 1000));
+
+    // 300ms prefix padding of 16k pcm16 is 9600 bytes
+    session.trim_input_audio_prefix(300);
+    assert_eq!(session.input_audio_buffer.len(), 9600);
+    session.trim_input_audio_prefix(1000);
+    assert_eq!(session.input_audio_buffer.len(), 9600);
+}
+
+#[test]
+fn test_text_only_session() {
+    let mut session = RealtimeSession::new(test_session(0));
+    assert!(!session.wants_audio());
+    session.config.modalities = Some(vec![Modality::Text, Modality::Audio]);
+    assert!(session.wants_audio());
+    assert!(!session.audio_only());
+    session.config.modalities = Some(vec![Modality::Audio]);
+    assert!(session.audio_only());
+
+    let mut session = RealtimeSession::new_text_only(test_session(0));
+    session.config.modalities = Some(vec![Modality::Text, Modality::Audio]);
+    assert!(!session.wants_audio());
+    assert!(ClientEvent::InputAudioBufferCommit { event_id: None }.is_audio());
+    assert!(!ClientEvent::ResponseCancel { event_id: None }.is_audio());
+}
+
+#[test]
+fn test_session_transcription() {
+    let asr = WhisperASRConfig {
+        model: "whisper-large-v3".to_string(),
+        models: vec!["whisper-large-v3-turbo".to_string()],
+        lang: "auto".to_string(),
+        ..Default::default()
+    };
+    let mut session = RealtimeSession::new(test_session(0));
+    let transcription = session.transcription(&asr);
+    assert_eq!(transcription.model.as_deref(), Some("whisper-large-v3"));
+    assert_eq!(transcription.language.as_deref(), Some("auto"));
+    assert_eq!(transcription.prompt, None);
+
+    session.config.input_audio_transcription = Some(InputAudioTranscription {
+        model: Some("whisper-large-v3-turbo".to_string()),
+        language: Some("zh".to_string()),
+        ..Default::default()
+    });
+    let transcription = session.transcription(&asr);
+    assert_eq!(
+        transcription.model.as_deref(),
+        Some("whisper-large-v3-turbo")
+    );
+    assert_eq!(transcription.language.as_deref(), Some("zh"));
+
+    // a model the server does not offer
+    session.config.input_audio_transcription = Some(InputAudioTranscription {
+        model: Some("whisper-1".to_string()),
+        ..Default::default()
+    });
+    let transcription = session.transcription(&asr);
+    assert_eq!(transcription.model.as_deref(), Some("whisper-large-v3"));
+    assert_eq!(transcription.language.as_deref(), Some("auto"));
+}
+
+#[tokio::test]
+async fn test_duplex_response() {
+    let mut chat_session = test_session(10);
+    chat_session.add_user_message("Tell me a story.".to_string());
+    let mut session = RealtimeSession::new(chat_session);
+    session.duplex = true;
+    session.config.turn_detection = Some(TurnDetection::server_vad());
+    let (tx, mut rx) = mpsc::channel(8);
+
+    let mut response = session.detach_response();
+    assert!(response.turn_event(&tx, TurnEvent::ResponseStarted).await);
+    assert!(session.interrupts_response());
+    // the input goes on while the response is generated
+    session.push_input_audio(&[0; 640]).await.unwrap();
+    session
+        .chat_session
+        .add_user_message("Stop, what time is it?".to_string());
+    // updated while the response is generated
+    session.chat_session.max_spoken_sentences = Some(2);
+    response
+        .chat_session
+        .add_truncated_assistant_message("Once upon a time".to_string());
+
+    response.turn_event(&tx, TurnEvent::ResponseDone).await;
+    session
+        .attach_response(&tx, Ok((response, Ok(()))))
+        .await
+        .unwrap();
+    assert!(!session.turn.is_responding());
+    assert!(matches!(
+        rx.recv().await,
+        Some(ServerEvent::TurnStateChanged {
+            state: TurnState::Thinking,
+            ..
+        })
+    ));
+    assert_eq!(session.input_audio_buffer.len(), 640);
+    let messages = session.chat_session.messages.iter();
+    let roles = messages.map(|m| m.role).collect::<Vec<_>>();
+    use crate::ai::llm::Role;
+    assert_eq!(roles, [Role::User, Role::Assistant, Role::User]);
+    assert!(!session.answered());
+    assert_eq!(session.chat_session.max_spoken_sentences, Some(2));
+}
+
+/// handle a server vad event: speech start / end, auto-commit at the end of speech
+pub(crate) async fn handle_vad_event(
+    event: anyhow::Result<crate::ai::vad::VadRealtimeEvent>,
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<()> {
+    use crate::ai::vad::{VadRealtimeEvent, VAD_SPEECH_END, VAD_SPEECH_START};
+
+    let event = match event {
+        Ok(VadRealtimeEvent::Error { error, message }) => {
+            Err(anyhow::anyhow!("vad error: {error}: {message}"))
+        }
+        event => event,
+    };
+
+    let event = match event {
+        Ok(event) => event,
+        Err(e) if config.asr.energy_vad.fallback => {
+            // the vad server is gone, segment the turns locally
+            log::warn!(
+                "`{}` server vad error: {e}, using the energy vad",
+                session.id
+            );
+            let params = session.vad_params();
+            if let Some(vad) = &mut session.server_vad {
+                vad.source = VadSource::energy(&config.asr, &params);
+            }
+            return Ok(());
+        }
+        Err(e) => {
+            // the vad is gone, fall back to push-to-talk
+            session.server_vad = None;
+            let _ = tx
+                .send(ServerEvent::Error {
+                    event_id: new_uuid().to_string(),
+                    error: ErrorDetails {
+                        error_type: "server_error".to_string(),
+                        code: Some("server_vad_error".to_string()),
+                        message: e.to_string(),
+                        param: Some("turn_detection".to_string()),
+                        event_id: None,
+                    },
+                })
+                .await;
+            return Err(e);
+        }
+    };
+
+    let event = match event {
+        VadRealtimeEvent::Event { event } => event,
+        VadRealtimeEvent::TurnTimeout => {
+            log::debug!("Turn wait timeout, generating response");
+            return generate_response(session, tx, config).await;
+        }
+        VadRealtimeEvent::Error { .. } => return Ok(()),
+    };
+
+    if event == VAD_SPEECH_START {
+        if session.interrupts_response() {
+            log::info!("`{}` speech started, cancelling the response", session.id);
+            session.chat_session.abort_handle.abort();
+            session.turn_event(tx, TurnEvent::Interrupted).await;
+        } else if !session.turn.is_responding() {
+            session.turn_event(tx, TurnEvent::SpeechStarted).await;
+        }
+        let item_id = new_uuid().to_string();
+        if let Some(prefix_padding_ms) = session.vad_params().prefix_padding_ms {
+            session.trim_input_audio_prefix(prefix_padding_ms);
+        }
+        let audio_start_ms = session.input_audio_ms();
+        if let Some(vad) = &mut session.server_vad {
+            vad.item_id = Some(item_id.clone());
+            // the user goes on with the turn
+            vad.turn_deadline = None;
+        }
+        let _ = tx
+            .send(ServerEvent::InputAudioBufferSpeechStarted {
+                event_id: new_uuid().to_string(),
+                audio_start_ms,
+                item_id,
+            })
+            .await;
+    } else if event == VAD_SPEECH_END {
+        let item_id = session
+            .server_vad
+            .as_mut()
+            .and_then(|vad| vad.item_id.take())
+            .unwrap_or_else(|| new_uuid().to_string());
+        let _ = tx
+            .send(ServerEvent::InputAudioBufferSpeechStopped {
+                event_id: new_uuid().to_string(),
+                audio_end_ms: session.input_audio_ms(),
+                item_id: item_id.clone(),
+            })
+            .await;
+
+        commit_speech(session, tx, item_id, config).await?;
+    } else {
+        log::debug!("vad event: {event}");
+    }
+
+    Ok(())
+}
+
+/// commit at the end of speech and respond, unless the turn looks unfinished
+async fn commit_speech(
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    item_id: String,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<()> {
+    if handle_audio_buffer_commit(session, tx, Some(item_id), config).await? {
+        if session.wait_for_turn_end().await {
+            log::debug!("Speech end, waiting for the rest of the turn");
+            session.turn_event(tx, TurnEvent::AwaitingTurnEnd).await;
+            return Ok(());
+        }
+        log::debug!("Speech end, generating response");
+        generate_response(session, tx, config).await?;
+    }
+    Ok(())
+}
+
+/// a speech start / stop hint of the client vad. the start is reported right away, the
+/// stop commits only once the server vad confirms there is speech in the buffer
+pub(crate) async fn handle_speech_hint(
+    hint: SpeechHint,
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<()> {
+    match hint {
+        SpeechHint::SpeechStart => {
+            if !session.turn.is_responding() {
+                session.turn_event(tx, TurnEvent::SpeechStarted).await;
+            }
+            let item_id = new_uuid().to_string();
+            session.speech_hint_item_id = Some(item_id.clone());
+            if let Some(vad) = &mut session.server_vad {
+                vad.turn_deadline = None;
+            }
+            let _ = tx
+                .send(ServerEvent::InputAudioBufferSpeechStarted {
+                    event_id: new_uuid().to_string(),
+                    audio_start_ms: session.input_audio_ms(),
+                    item_id,
+                })
+                .await;
+        }
+        SpeechHint::SpeechStop => {
+            let item_id = session
+                .speech_hint_item_id
+                .take()
+                .unwrap_or_else(|| new_uuid().to_string());
+            let _ = tx
+                .send(ServerEvent::InputAudioBufferSpeechStopped {
+                    event_id: new_uuid().to_string(),
+                    audio_end_ms: session.input_audio_ms(),
+                    item_id: item_id.clone(),
+                })
+                .await;
+
+            // the commit runs `vad_url` / silero itself, without them confirm with the energy vad
+            let asr = &config.asr;
+            if asr.vad_url.is_none() && asr.silero_vad.is_none() {
+                let wav_audio = crate::util::pcm_to_wav(
+                    &session.input_audio_buffer,
+                    crate::util::WavConfig {
+                        sample_rate: session.input_sample_rate,
+                        ..Default::default()
+                    },
+                );
+                let vad = crate::ai::energy_vad::vad_detect(&asr.energy_vad, wav_audio).await?;
+                if vad.timestamps.is_empty() {
+                    log::debug!("`{}` speech hint not confirmed, clearing", session.id);
+                    session.input_audio_buffer.clear();
+                    let _ = tx
+                        .send(ServerEvent::InputAudioBufferCleared {
+                            event_id: new_uuid().to_string(),
+                        })
+                        .await;
+                    return Ok(());
+                }
+            }
+
+            commit_speech(session, tx, item_id, config).await?;
         }
     }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
 pub struct StableRealtimeConfig {
     pub llm: LLMConfig,
     pub tts: TTSConfig,
-    pub asr: ASRConfig,
+    pub asr: WhisperASRConfig,
+    pub stream: StreamConfig,
+    pub speech_text: SpeechTextConfig,
+    pub registry: Option<Arc<crate::services::registry::DeviceRegistry>>,
+    pub sessions: Arc<crate::services::sessions::SessionManager>,
+    pub webhooks: Arc<crate::services::webhooks::Webhooks>,
+    pub capture: Option<CaptureConfig>,
+    /// merged with the profile of the device on `/device/ws`
+    pub phrases: PhrasesConfig,
+    pub phrase_audio: Arc<crate::services::phrases::PhraseAudio>,
+    /// merged with the profile of the device on `/device/ws`
+    pub earcons: EarconsConfig,
+    pub earcon_audio: Arc<crate::services::earcons::EarconAudio>,
+    pub intents: Arc<crate::services::intents::IntentRouter>,
+}
+
+#[cfg(test)]
+impl StableRealtimeConfig {
+    /// the mock providers: `replies` of the llm, then an echo of the user
+    pub(crate) fn mock(replies: Vec<String>, asr: MockASRConfig) -> Self {
+        let llm = LLMConfig {
+            mock: Some(MockLLMConfig {
+                replies,
+                chunk_chars: 8,
+                chunk_delay_ms: 0,
+                next: Default::default(),
+            }),
+            ..toml::from_str("history = 5").unwrap()
+        };
+        Self {
+            llm,
+            tts: toml::from_str(r#"platform = "Mock""#).unwrap(),
+            asr: ASRConfig::Mock(asr).whisper().unwrap(),
+            stream: Default::default(),
+            speech_text: Default::default(),
+            registry: None,
+            sessions: Arc::new(crate::services::sessions::SessionManager::new(
+                None, None, None, None,
+            )),
+            webhooks: Arc::new(crate::services::webhooks::Webhooks::new(vec![])),
+            capture: None,
+            phrases: Default::default(),
+            phrase_audio: Default::default(),
+            earcons: Default::default(),
+            earcon_audio: Default::default(),
+            intents: Default::default(),
+        }
+    }
+}
+
+/// read the socket in its own task, so a cancel or a disconnect aborts the llm stream
+/// even while the session is busy generating a response
+pub(crate) fn spawn_socket_reader(
+    mut receiver: futures_util::stream::SplitStream<WebSocket>,
+    abort: crate::ai::AbortHandle,
+    is_cancel: fn(&Message) -> bool,
+    capacity: usize,
+) -> mpsc::Receiver<Result<Message, axum::Error>> {
+    let (msg_tx, msg_rx) = mpsc::channel(capacity);
+    tokio::spawn(async move {
+        while let Some(msg) = receiver.next().await {
+            let cancel = match &msg {
+                Ok(Message::Close(_)) | Err(_) => true,
+                Ok(msg) => is_cancel(msg),
+            };
+            if cancel {
+                abort.abort();
+            }
+            if msg_tx.send(msg).await.is_err() {
+                break;
+            }
+        }
+        abort.abort();
+    });
+    msg_rx
+}
+
+fn is_response_cancel(msg: &Message) -> bool {
+    #[derive(serde::Deserialize)]
+    struct EventType<'a> {
+        #[serde(rename = "type", borrow)]
+        event_type: &'a str,
+    }
+
+    match msg {
+        Message::Text(text) => {
+            serde_json::from_str::<EventType>(text).is_ok_and(|e| e.event_type == "response.cancel")
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RealtimeQuery {
+    /// `transcription` or `dictation` starts the session of that type, as `session.type`
+    /// does
+    #[serde(default)]
+    pub intent: Option<String>,
+}
+
+/// the config with the keys and the prompts of `tenant`, see [`crate::services::tenants`]
+pub(crate) fn tenant_config(
+    config: Arc<StableRealtimeConfig>,
+    tenant: Option<&str>,
+) -> Arc<StableRealtimeConfig> {
+    match tenant.and_then(|name| config.sessions.tenants().get(name)) {
+        Some(tenant) => {
+            let mut tenant_config = config.as_ref().clone();
+            tenant.apply(&mut tenant_config);
+            Arc::new(tenant_config)
+        }
+        None => config,
+    }
 }
 
 pub async fn ws_handler(
     Extension(config): Extension<Arc<StableRealtimeConfig>>,
+    Query(query): Query<RealtimeQuery>,
+    headers: axum::http::HeaderMap,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(config, socket))
+    let tenant = match config.sessions.tenants().authorize(&headers).await {
+        Ok(tenant) => tenant,
+        Err(code) => return code.into_response(),
+    };
+    let config = tenant_config(config, tenant.as_deref());
+    let session_type = match query.intent.as_deref() {
+        Some("transcription") => SessionType::Transcription,
+        Some("dictation") => SessionType::Dictation,
+        _ => SessionType::Realtime,
+    };
+    ws.on_upgrade(move |socket| handle_socket(config, socket, false, session_type, tenant))
+        .into_response()
 }
 
-async fn handle_socket(config: Arc<StableRealtimeConfig>, socket: WebSocket) {
-    let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = mpsc::channel::<ServerEvent>(1024);
+/// `/v1/chat/ws`: the realtime protocol without audio, for text clients that only want
+/// the configured prompts and tools
+pub async fn chat_ws_handler(
+    Extension(config): Extension<Arc<StableRealtimeConfig>>,
+    headers: axum::http::HeaderMap,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let tenant = match config.sessions.tenants().authorize(&headers).await {
+        Ok(tenant) => tenant,
+        Err(code) => return code.into_response(),
+    };
+    let config = tenant_config(config, tenant.as_deref());
+    ws.on_upgrade(|socket| handle_socket(config, socket, true, SessionType::Realtime, tenant))
+        .into_response()
+}
 
-    let mut chat_session = ChatSession::new(
-        config.llm.llm_chat_url.clone(),
-        config.llm.api_key.clone().unwrap_or_default(),
-        config.llm.model.clone(),
-        None,
-        config.llm.history,
-        crate::ai::openai::tool::ToolSet::default(),
-    );
+/// the chat session of the config, with the prompts and style of the device profile if any
+pub(crate) fn new_chat_session(
+    config: &StableRealtimeConfig,
+    profile: Option<&ProfileConfig>,
+) -> ChatSession {
+    let mut chat_session = ChatSession::from_config(&config.llm, Default::default());
     chat_session.system_prompts = config.llm.sys_prompts.clone();
     chat_session.messages = config.llm.dynamic_prompts.clone();
+    if let Some(profile) = profile.filter(|p| !p.sys_prompts.is_empty()) {
+        chat_session.system_prompts = profile.sys_prompts.clone();
+    }
+    let style = profile
+        .and_then(|p| p.spoken_style.as_ref())
+        .unwrap_or(&config.llm.spoken_style);
+    chat_session.set_spoken_style(style);
+    chat_session
+}
+
+async fn handle_socket(
+    config: Arc<StableRealtimeConfig>,
+    socket: WebSocket,
+    text_only: bool,
+    session_type: SessionType,
+    tenant: Option<String>,
+) {
+    let (mut sender, receiver) = socket.split();
+    let (tx, mut rx) = mpsc::channel::<ServerEvent>(config.stream.event_channel_capacity);
 
     // 创建新的 Realtime 会话
-    let mut session = RealtimeSession::new(chat_session);
+    let mut session = if text_only {
+        RealtimeSession::new_text_only(new_chat_session(&config, None))
+    } else {
+        RealtimeSession::new(new_chat_session(&config, None))
+    };
+    session.prompts = Some(config.sessions.prompts().start(&mut session.chat_session));
+    session.earcons = config.earcon_audio.earcons(&config.earcons);
+    session.transcription_only = session_type != SessionType::Realtime;
+    if session_type == SessionType::Dictation {
+        session.dictation = Some(Dictation::default());
+    }
+    let kind = match (text_only, session.transcription_only) {
+        (true, _) => "chat",
+        (false, true) => "transcription",
+        (false, false) => "realtime",
+    };
+    let session_handle = config.sessions.open(session.id.clone(), kind, None);
+    config.sessions.set_tenant(&session.id, tenant);
+    let mut inbox = session_handle.inbox();
+    config
+        .sessions
+        .set_config(&session.id, session_info(&session, &config));
 
     // 发送初始 session.created 事件
     let session_created = ServerEvent::SessionCreated {
-        event_id: Uuid::new_v4().to_string(),
+        event_id: new_uuid().to_string(),
         session: Session {
             id: session.id.clone(),
             object: "realtime.session".to_string(),
+            session_type: session.session_type(),
             model: "gpt-4o-realtime-preview".to_string(),
-            modalities: vec![Modality::Text, Modality::Audio],
+            modalities: if text_only {
+                vec![Modality::Text]
+            } else {
+                vec![Modality::Text, Modality::Audio]
+            },
             instructions: "You are a helpful assistant.".to_string(),
             voice: "default".to_string(),
             input_audio_format: AudioFormat::Pcm16,
@@ -101,47 +1284,105 @@ async fn handle_socket(config: Arc<StableRealtimeConfig>, socket: WebSocket) {
             tool_choice: Some(ToolChoice::Auto),
             temperature: Some(0.8),
             max_output_tokens: None,
+            speed: None,
+            pitch: None,
         },
     };
 
     if let Ok(json) = serde_json::to_string(&session_created) {
-        if sender
-            .send(axum::extract::ws::Message::Text(json.into()))
-            .await
-            .is_err()
-        {
+        if sender.send(Message::Text(json.into())).await.is_err() {
             return;
         }
     }
 
     // 发送 conversation.created 事件
     let conversation_created = ServerEvent::ConversationCreated {
-        event_id: Uuid::new_v4().to_string(),
+        event_id: new_uuid().to_string(),
         conversation: Conversation {
-            id: Uuid::new_v4().to_string(),
+            id: new_uuid().to_string(),
             object: "realtime.conversation".to_string(),
         },
     };
 
     if let Ok(json) = serde_json::to_string(&conversation_created) {
-        if sender
-            .send(axum::extract::ws::Message::Text(json.into()))
-            .await
-            .is_err()
-        {
+        if sender.send(Message::Text(json.into())).await.is_err() {
             return;
         }
     }
 
+    // ping / close frames of the receive loop
+    let (ctl_tx, mut ctl_rx) = mpsc::channel::<Message>(8);
+    let ping_interval = std::time::Duration::from_secs(config.stream.ping_interval_sec);
+    let idle_timeout = std::time::Duration::from_secs(config.stream.idle_timeout_sec);
+
+    let recorder = config.capture.as_ref().map(|capture| {
+        crate::services::realtime_capture::Recorder::start(capture, &session.id, text_only)
+    });
+    let send_recorder = recorder.clone();
+
+    let sessions = config.sessions.clone();
+    let session_id = session.id.clone();
     // 处理从服务器发送到客户端的消息
     let send_task = tokio::spawn(async move {
-        while let Some(event) = rx.recv().await {
+        // start of the current turn, used to report the time to first audio delta
+        let mut turn_start: Option<std::time::Instant> = None;
+        let mut ping = tokio::time::interval_at(
+            tokio::time::Instant::now() + ping_interval,
+            ping_interval.max(std::time::Duration::from_secs(1)),
+        );
+        loop {
+            let event = tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                Some(msg) = ctl_rx.recv() => {
+                    let is_close = matches!(msg, Message::Close(_));
+                    if sender.send(msg).await.is_err() || is_close {
+                        break;
+                    }
+                    continue;
+                }
+                _ = ping.tick(), if !ping_interval.is_zero() => {
+                    if sender.send(Message::Ping(Bytes::new())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            match &event {
+                ServerEvent::InputAudioBufferCommitted { .. } => {
+                    turn_start = Some(std::time::Instant::now());
+                    sessions.turn_started(&session_id);
+                }
+                ServerEvent::ResponseCreated { .. } => {
+                    if turn_start.is_none() {
+                        sessions.turn_started(&session_id);
+                    }
+                    turn_start.get_or_insert_with(std::time::Instant::now);
+                }
+                ServerEvent::ResponseAudioDelta { response_id, .. } => {
+                    if let Some(st) = turn_start.take() {
+                        log::info!(
+                            "`{response_id}` time to first audio delta: {:?}",
+                            st.elapsed()
+                        );
+                        sessions.turn_first_audio(&session_id, st.elapsed());
+                    }
+                }
+                ServerEvent::ResponseDone { .. } => {
+                    turn_start = None;
+                    sessions.turn_done(&session_id);
+                }
+                _ => {}
+            }
+            if let Some(recorder) = &send_recorder {
+                recorder.server(&event);
+            }
+
             if let Ok(json) = serde_json::to_string(&event) {
-                if sender
-                    .send(axum::extract::ws::Message::Text(json.into()))
-                    .await
-                    .is_err()
-                {
+                if sender.send(Message::Text(json.into())).await.is_err() {
                     break;
                 }
             }
@@ -149,67 +1390,274 @@ async fn handle_socket(config: Arc<StableRealtimeConfig>, socket: WebSocket) {
     });
 
     // 处理从客户端接收的消息 (直接在当前协程中处理)
-    while let Some(msg) = receiver.next().await {
-        if let Ok(msg) = msg {
-            match msg {
-                axum::extract::ws::Message::Text(text) => {
-                    if let Err(e) = handle_client_message(
-                        text.to_string(),
-                        &mut session,
-                        &tx,
-                        &config.llm,
-                        &config.tts,
-                        &config.asr,
-                    )
-                    .await
-                    {
-                        log::error!("Error handling client message: {}", e);
+    let mut receiver = spawn_socket_reader(
+        receiver,
+        session.chat_session.abort_handle.clone(),
+        is_response_cancel,
+        config.stream.event_channel_capacity,
+    );
+    let mut last_active = tokio::time::Instant::now();
+    let mut invalid_events = 0;
+    let mut response = ResponseTask::default();
+    session.duplex = true;
+    let close = loop {
+        response.start_requested(&mut session, &tx, &config).await;
+        tokio::select! {
+            done = response.done() => {
+                if let Err(e) = session.attach_response(&tx, done).await {
+                    log::error!("Error generating response: {}", e);
+                }
+            }
+            msg = receiver.recv() => {
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        match crate::ai::openai::validate::parse_client_event(&text) {
+                            Ok(client_event) => {
+                                invalid_events = 0;
+                                if let Some(recorder) = &recorder {
+                                    recorder.client(&client_event);
+                                }
+                                if let Err(e) =
+                                    handle_client_message(client_event, &mut session, &tx, &config).await
+                                {
+                                    log::error!("Error handling client message: {}", e);
+                                }
+                            }
+                            Err(e) => {
+                                invalid_events += 1;
+                                log::warn!("session {} invalid client event: {e}", session.id);
+                                let _ = tx
+                                    .send(ServerEvent::Error {
+                                        event_id: new_uuid().to_string(),
+                                        error: e.into_error(),
+                                    })
+                                    .await;
+                                if invalid_events >= MAX_INVALID_EVENTS {
+                                    break Some((close_code::POLICY, "too many invalid events"));
+                                }
+                            }
+                        }
+                    }
+                    // binary frames are raw pcm16, the same as `input_audio_buffer.append`
+                    Some(Ok(Message::Binary(data))) => {
+                        if session.text_only {
+                            break Some((close_code::UNSUPPORTED, "text-only session"));
+                        }
+                        if data.len() % 2 != 0 {
+                            break Some((close_code::INVALID, "binary audio must be pcm16"));
+                        }
+                        if let Some(recorder) = &recorder {
+                            recorder.client(&ClientEvent::InputAudioBufferAppend {
+                                event_id: None,
+                                audio: encode_base64(&data),
+                            });
+                        }
+                        if let Err(e) = handle_input_audio(&mut session, &tx, &config, &data).await {
+                            log::error!("Error handling binary audio: {}", e);
+                        }
+                    }
+                    // pong 由 axum 自动回复
+                    Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+                    Some(Ok(Message::Close(frame))) => {
+                        log::info!("session {} closed by client: {frame:?}", session.id);
+                        break None;
+                    }
+                    Some(Err(e)) => {
+                        log::warn!("session {} websocket error: {e}", session.id);
+                        break None;
                     }
+                    None => break None,
+                }
+                last_active = tokio::time::Instant::now();
+                config.sessions.touch(&session.id);
+            }
+            event = session.next_vad_event() => {
+                if let Err(e) = handle_vad_event(event, &mut session, &tx, &config).await {
+                    log::error!("Error handling vad event: {}", e);
                 }
-                axum::extract::ws::Message::Close(_) => break,
-                _ => {}
             }
+            Some(message) = inbox.recv() => {
+                if let Err(e) = handle_injected_message(message, &mut session, &tx, &config).await {
+                    log::error!("Error handling injected message: {}", e);
+                }
+            }
+            _ = tokio::time::sleep_until(last_active + idle_timeout), if !idle_timeout.is_zero() => {
+                log::info!("session {} idle for {idle_timeout:?}, closing", session.id);
+                break Some((close_code::AWAY, "idle timeout"));
+            }
+        }
+    };
+
+    if let Some((code, reason)) = close {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        let _ = ctl_tx.send(Message::Close(Some(frame))).await;
+    }
+    response.finish(&mut session, &tx).await;
+
+    // 等待发送任务完成
+    drop(ctl_tx);
+    drop(tx);
+    if let Err(e) = send_task.await {
+        log::error!("Send task error: {}", e);
+    }
+}
+
+/// a message of `POST /v1/sessions/{id}/messages`, shown to the client as a
+/// `conversation.item.created`
+pub(crate) async fn handle_injected_message(
+    message: crate::services::sessions::InjectedMessage,
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<()> {
+    let role = match message.role {
+        crate::ai::llm::Role::System => {
+            session
+                .chat_session
+                .add_system_message(message.content.clone());
+            "system"
+        }
+        _ => {
+            session
+                .chat_session
+                .add_user_message(message.content.clone());
+            "user"
+        }
+    };
+    log::info!("session {} injected {role} message", session.id);
+
+    let item = ConversationItem {
+        id: Some(new_uuid().to_string()),
+        object: Some("realtime.item".to_string()),
+        item_type: "message".to_string(),
+        status: Some("completed".to_string()),
+        role: Some(role.to_string()),
+        content: Some(vec![ContentPart::InputText {
+            text: message.content,
+        }]),
+        call_id: None,
+        name: None,
+        arguments: None,
+        output: None,
+    };
+    let _ = tx
+        .send(ServerEvent::ConversationItemCreated {
+            event_id: new_uuid().to_string(),
+            previous_item_id: None,
+            item,
+        })
+        .await;
+
+    if message.generate {
+        if session.turn.is_responding() {
+            log::warn!(
+                "session {} is busy, injected message not answered",
+                session.id
+            );
+            return Ok(());
         }
+        generate_response(session, tx, config).await?;
     }
-
-    // 等待发送任务完成
-    drop(tx);
-    if let Err(e) = send_task.await {
-        log::error!("Send task error: {}", e);
-    }
+    Ok(())
 }
 
-async fn handle_client_message(
-    text: String,
-    session: &mut RealtimeSession,
-    tx: &mpsc::Sender<ServerEvent>,
-    llm: &LLMConfig,
-    tts: &TTSConfig,
-    asr: &ASRConfig,
-) -> anyhow::Result<()> {
-    let client_event: ClientEvent = serde_json::from_str(&text)?;
-    let tts_voice = match tts {
+/// the `session` of `session.updated`
+pub(crate) fn session_info(session: &RealtimeSession, config: &StableRealtimeConfig) -> Session {
+    let tts_voice = match &config.tts {
+        _ if !session.wants_audio() => String::new(),
         TTSConfig::Stable(tts) => tts.speaker.clone(),
         TTSConfig::Fish(fish) => fish.speaker.clone(),
         TTSConfig::Groq(groq) => groq.voice.clone(),
         TTSConfig::StreamGSV(stream_tts) => stream_tts.speaker.clone(),
+        TTSConfig::CosyVoice(cosyvoice) => cosyvoice.speaker.clone().unwrap_or_default(),
+        TTSConfig::Mock(mock) => mock.speaker.clone(),
     };
 
+    Session {
+        id: session.id.clone(),
+        object: "realtime.session".to_string(),
+        session_type: session.session_type(),
+        model: config.llm.model.clone(),
+        modalities: session
+            .config
+            .modalities
+            .clone()
+            .unwrap_or_else(|| vec![Modality::Text, Modality::Audio]),
+        instructions: session
+            .config
+            .instructions
+            .clone()
+            .unwrap_or_else(|| "You are a helpful assistant.".to_string()),
+        voice: tts_voice,
+        input_audio_format: session
+            .config
+            .input_audio_format
+            .clone()
+            .unwrap_or(AudioFormat::Pcm16),
+        output_audio_format: session
+            .config
+            .output_audio_format
+            .clone()
+            .unwrap_or(AudioFormat::Pcm16),
+        input_audio_transcription: Some(session.transcription(&config.asr)),
+        turn_detection: session.config.turn_detection.clone(),
+        tools: session.config.tools.clone(),
+        tool_choice: session.config.tool_choice.clone(),
+        temperature: session.config.temperature,
+        max_output_tokens: session.config.max_output_tokens,
+        speed: session.config.speed,
+        pitch: session.config.pitch,
+    }
+}
+
+pub(crate) async fn handle_client_message(
+    client_event: ClientEvent,
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<()> {
+    let StableRealtimeConfig { asr, .. } = config;
+    // echoed in the errors of the event
+    let event_id = client_event.event_id().map(str::to_string);
+
+    if session.text_only && client_event.is_audio() {
+        let error_event = ServerEvent::Error {
+            event_id: new_uuid().to_string(),
+            error: ErrorDetails {
+                error_type: "invalid_request_error".to_string(),
+                code: Some("unsupported_event".to_string()),
+                message: "Audio events are not available on a text-only session".to_string(),
+                param: None,
+                event_id: event_id.clone(),
+            },
+        };
+        let _ = tx.send(error_event).await;
+        return Ok(());
+    }
+
     match client_event {
         ClientEvent::SessionUpdate {
             event_id: _,
-            session: config,
+            session: mut session_config,
         } => {
-            if let Some(ref input_format) = config.input_audio_format {
+            if session.text_only {
+                session_config.modalities = Some(vec![Modality::Text]);
+                session_config.turn_detection = None;
+            }
+
+            if let Some(ref input_format) = session_config.input_audio_format {
                 if *input_format != AudioFormat::Pcm16 {
                     let error_event = ServerEvent::Error {
-                        event_id: Uuid::new_v4().to_string(),
+                        event_id: new_uuid().to_string(),
                         error: ErrorDetails {
                             error_type: "invalid_request_error".to_string(),
                             code: Some("unsupported_audio_format".to_string()),
                             message: "Only PCM16 input audio format is supported".to_string(),
                             param: Some("input_audio_format".to_string()),
-                            event_id: None,
+                            event_id: event_id.clone(),
                         },
                     };
                     let _ = tx.send(error_event).await;
@@ -217,16 +1665,16 @@ async fn handle_client_message(
                 }
             }
 
-            if let Some(ref output_format) = config.output_audio_format {
+            if let Some(ref output_format) = session_config.output_audio_format {
                 if *output_format != AudioFormat::Pcm16 {
                     let error_event = ServerEvent::Error {
-                        event_id: Uuid::new_v4().to_string(),
+                        event_id: new_uuid().to_string(),
                         error: ErrorDetails {
                             error_type: "invalid_request_error".to_string(),
                             code: Some("unsupported_audio_format".to_string()),
                             message: "Only PCM16 output audio format is supported".to_string(),
                             param: Some("output_audio_format".to_string()),
-                            event_id: None,
+                            event_id: event_id.clone(),
                         },
                     };
                     let _ = tx.send(error_event).await;
@@ -234,83 +1682,110 @@ async fn handle_client_message(
                 }
             }
 
-            if let Some(ref turn_detection) = config.turn_detection {
-                if turn_detection.turn_type == TurnDetectionType::ServerVad {
+            if let Some(ref turn_detection) = session_config.turn_detection {
+                let unsupported = match turn_detection.turn_type {
+                    TurnDetectionType::ServerVad | TurnDetectionType::SemanticVad
+                        if asr.vad_realtime_url.is_none() =>
+                    {
+                        Some("Server VAD turn detection requires asr.vad_realtime_url")
+                    }
+                    _ => None,
+                };
+                if let Some(message) = unsupported {
                     let error_event = ServerEvent::Error {
-                        event_id: Uuid::new_v4().to_string(),
+                        event_id: new_uuid().to_string(),
                         error: ErrorDetails {
                             error_type: "invalid_request_error".to_string(),
                             code: Some("unsupported_turn_detection".to_string()),
-                            message: "Server VAD turn detection is not supported".to_string(),
+                            message: message.to_string(),
                             param: Some("turn_detection.type".to_string()),
-                            event_id: None,
+                            event_id: event_id.clone(),
                         },
                     };
                     let _ = tx.send(error_event).await;
                     return Ok(());
                 }
+
+                if let Some(threshold) = turn_detection.threshold {
+                    if !(0.0..=1.0).contains(&threshold) {
+                        let error_event = ServerEvent::Error {
+                            event_id: new_uuid().to_string(),
+                            error: ErrorDetails {
+                                error_type: "invalid_request_error".to_string(),
+                                code: Some("invalid_value".to_string()),
+                                message: "turn_detection.threshold must be between 0.0 and 1.0"
+                                    .to_string(),
+                                param: Some("turn_detection.threshold".to_string()),
+                                event_id: event_id.clone(),
+                            },
+                        };
+                        let _ = tx.send(error_event).await;
+                        return Ok(());
+                    }
+                }
+            }
+
+            if let Some(session_type) = session_config.session_type {
+                session.set_session_type(tx, session_type).await;
             }
+            session.config = session_config;
 
-            session.config = config;
+            // push-to-talk or continuous listening
+            if let Err(e) = session.apply_turn_detection(asr).await {
+                log::error!("`{}` apply turn detection error: {e}", session.id);
+                session.config.turn_detection = Some(TurnDetection::none());
+                let error_event = ServerEvent::Error {
+                    event_id: new_uuid().to_string(),
+                    error: ErrorDetails {
+                        error_type: "server_error".to_string(),
+                        code: Some("server_vad_error".to_string()),
+                        message: e.to_string(),
+                        param: Some("turn_detection".to_string()),
+                        event_id: event_id.clone(),
+                    },
+                };
+                let _ = tx.send(error_event).await;
+            }
 
             // 发送 session.updated 确认
-            let updated_session = Session {
-                id: session.id.clone(),
-                object: "realtime.session".to_string(),
-                model: llm.model.clone(),
-                modalities: session
-                    .config
-                    .modalities
-                    .clone()
-                    .unwrap_or_else(|| vec![Modality::Text, Modality::Audio]),
-                instructions: session
-                    .config
-                    .instructions
-                    .clone()
-                    .unwrap_or_else(|| "You are a helpful assistant.".to_string()),
-                voice: tts_voice,
-                input_audio_format: session
-                    .config
-                    .input_audio_format
-                    .clone()
-                    .unwrap_or(AudioFormat::Pcm16),
-                output_audio_format: session
-                    .config
-                    .output_audio_format
-                    .clone()
-                    .unwrap_or(AudioFormat::Pcm16),
-                input_audio_transcription: session.config.input_audio_transcription.clone(),
-                turn_detection: session.config.turn_detection.clone(),
-                tools: session.config.tools.clone(),
-                tool_choice: session.config.tool_choice.clone(),
-                temperature: session.config.temperature,
-                max_output_tokens: session.config.max_output_tokens,
-            };
+            let updated_session = session_info(session, config);
+            config.sessions.set_config(&session.id, &updated_session);
 
             let event = ServerEvent::SessionUpdated {
-                event_id: Uuid::new_v4().to_string(),
+                event_id: new_uuid().to_string(),
                 session: updated_session,
             };
             let _ = tx.send(event).await;
         }
 
         ClientEvent::InputAudioBufferAppend { event_id: _, audio } => {
-            let audio_data = decode_base64(&audio)?;
-            session.input_audio_buffer.extend(audio_data);
+            let audio_data = decode_base64_blocking(audio).await?;
+            handle_input_audio(session, tx, config, &audio_data).await?;
         }
 
         ClientEvent::InputAudioBufferCommit { event_id: _ } => {
-            if handle_audio_buffer_commit(session, tx, None, asr).await? {
+            if handle_audio_buffer_commit(session, tx, None, config).await? {
                 log::debug!("Audio buffer committed, generating response");
-                generate_response(session, tx, tts).await?;
+                generate_response(session, tx, config).await?;
             }
         }
 
+        ClientEvent::InputAudioBufferSpeechHint { event_id: _, hint } => {
+            handle_speech_hint(hint, session, tx, config).await?;
+        }
+
+        ClientEvent::InputImageBufferAppend { event_id: _, image } => {
+            session.set_camera_frame(image);
+        }
+
         ClientEvent::InputAudioBufferClear { event_id: _ } => {
             session.input_audio_buffer.clear();
+            if session.turn.state() == TurnState::Listening {
+                session.turn_event(tx, TurnEvent::BufferCleared).await;
+            }
 
             let event = ServerEvent::InputAudioBufferCleared {
-                event_id: Uuid::new_v4().to_string(),
+                event_id: new_uuid().to_string(),
             };
             let _ = tx.send(event).await;
         }
@@ -325,7 +1800,14 @@ async fn handle_client_message(
                     Some("user") => {
                         if let Some(content) = &item.content {
                             let text = extract_text_from_content(content);
-                            session.chat_session.add_user_message(text);
+                            let mut images: Vec<String> =
+                                content.iter().filter_map(ContentPart::image_url).collect();
+                            if images.is_empty() {
+                                images.extend(session.take_camera_frame());
+                            }
+                            session
+                                .chat_session
+                                .add_user_message_with_images(text, images);
                         }
                     }
                     Some("assistant") => {
@@ -357,7 +1839,7 @@ async fn handle_client_message(
                                 role: crate::ai::llm::Role::Assistant,
                                 message: String::new(),
                                 tool_calls: Some(vec![crate::ai::llm::ToolCall {
-                                    id: item.id.clone().unwrap_or_default(),
+                                    id: item.call_id.clone().unwrap_or_default(),
                                     type_: "function".to_string(),
                                     function: crate::ai::llm::ToolFunction {
                                         name: item.name.clone().unwrap_or_default(),
@@ -365,6 +1847,7 @@ async fn handle_client_message(
                                     },
                                 }]),
                                 tool_call_id: None,
+                                images: vec![],
                             });
                     }
                 }
@@ -377,7 +1860,8 @@ async fn handle_client_message(
                                 role: crate::ai::llm::Role::Tool,
                                 message: output.clone(),
                                 tool_calls: None,
-                                tool_call_id: item.id.clone(),
+                                tool_call_id: item.call_id.clone(),
+                                images: vec![],
                             });
                     }
                 }
@@ -386,41 +1870,113 @@ async fn handle_client_message(
                 }
             }
 
+            let auto_respond = config.llm.auto_respond_tool_output
+                && item.item_type == "function_call_output"
+                && !session.transcription_only
+                && !session.turn.is_responding()
+                && tool_calls_answered(&session.chat_session.messages);
             let event = ServerEvent::ConversationItemCreated {
-                event_id: Uuid::new_v4().to_string(),
+                event_id: new_uuid().to_string(),
                 previous_item_id,
                 item,
             };
             let _ = tx.send(event).await;
+            if auto_respond {
+                log::debug!("Answering the tool output of session: {}", session.id);
+                generate_response(session, tx, config).await?;
+            }
         }
 
         ClientEvent::ResponseCreate {
             event_id: _,
             response: _,
         } => {
-            if session.is_generating {
+            if session.transcription_only {
+                let error_event = ServerEvent::Error {
+                    event_id: new_uuid().to_string(),
+                    error: ErrorDetails {
+                        error_type: "invalid_request_error".to_string(),
+                        code: Some("transcription_session".to_string()),
+                        message: "A transcription session does not generate responses".to_string(),
+                        param: None,
+                        event_id: event_id.clone(),
+                    },
+                };
+                let _ = tx.send(error_event).await;
+                return Ok(());
+            }
+            if session.turn.is_responding() {
                 let error_event = ServerEvent::Error {
-                    event_id: Uuid::new_v4().to_string(),
+                    event_id: new_uuid().to_string(),
                     error: ErrorDetails {
                         error_type: "invalid_request_error".to_string(),
                         code: Some("response_in_progress".to_string()),
                         message: "A response is already being generated".to_string(),
                         param: None,
-                        event_id: None,
+                        event_id: event_id.clone(),
                     },
                 };
                 let _ = tx.send(error_event).await;
                 return Ok(());
             }
             log::debug!("Generating response for session: {}", session.id);
-            generate_response(session, tx, tts).await?;
+            generate_response(session, tx, config).await?;
+        }
+
+        ClientEvent::ConversationItemTruncate {
+            event_id: _,
+            item_id,
+            content_index,
+            audio_end_ms,
+        } => {
+            let truncated = match session.truncate_spoken(&item_id, audio_end_ms as u64) {
+                Some(ms) => Some(ms as u32),
+                // the answer may still be generated, it is cut once done
+                None if session.duplex => {
+                    session.pending_truncate = Some((item_id.clone(), audio_end_ms as u64));
+                    Some(audio_end_ms)
+                }
+                None => None,
+            };
+            let event = match truncated {
+                Some(audio_end_ms) => ServerEvent::ConversationItemTruncated {
+                    event_id: new_uuid().to_string(),
+                    item_id,
+                    content_index,
+                    audio_end_ms,
+                },
+                None => ServerEvent::Error {
+                    event_id: new_uuid().to_string(),
+                    error: ErrorDetails {
+                        error_type: "invalid_request_error".to_string(),
+                        code: Some("item_not_found".to_string()),
+                        message: format!("No spoken assistant item with id '{item_id}'."),
+                        param: Some("item_id".to_string()),
+                        event_id: event_id.clone(),
+                    },
+                },
+            };
+            let _ = tx.send(event).await;
+        }
+
+        ClientEvent::OutputAudioBufferPlayed {
+            event_id: _,
+            item_id,
+            audio_end_ms,
+        } => {
+            session.played = Some((item_id, audio_end_ms as u64));
         }
 
         ClientEvent::ResponseCancel { event_id: _ } => {
-            session.is_generating = false;
+            session.chat_session.abort_handle.abort();
+            // the response task of a duplex session ends on its cancelled path, which moves
+            // the turn on once it is attached back
+            if !(session.duplex && session.turn.is_responding()) {
+                session.turn_event(tx, TurnEvent::ResponseDone).await;
+            }
 
             let event = ServerEvent::ConversationInterrupted {
-                event_id: Uuid::new_v4().to_string(),
+                event_id: new_uuid().to_string(),
             };
             let _ = tx.send(event).await;
         }
@@ -433,38 +1989,69 @@ async fn handle_client_message(
     Ok(())
 }
 
-async fn handle_audio_buffer_commit(
+/// transcribe the input audio buffer, `true` if the transcript is to be answered
+pub(crate) async fn handle_audio_buffer_commit(
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    item_id: Option<String>,
+    config: &StableRealtimeConfig,
+) -> anyhow::Result<bool> {
+    let transcribing = session.turn_event(tx, TurnEvent::AudioCommitted).await;
+    let r = commit_audio_buffer(session, tx, item_id, config).await;
+    if transcribing && !matches!(r, Ok(true)) {
+        session.turn_event(tx, TurnEvent::Unanswered).await;
+    }
+    r
+}
+
+async fn commit_audio_buffer(
     session: &mut RealtimeSession,
     tx: &mpsc::Sender<ServerEvent>,
     item_id: Option<String>,
-    config: &ASRConfig,
+    config: &StableRealtimeConfig,
 ) -> anyhow::Result<bool> {
-    let mut audio_data = Vec::new();
-    std::mem::swap(&mut audio_data, &mut session.input_audio_buffer);
+    let asr = &config.asr;
+    let audio_data = session.input_audio_buffer.split().freeze();
 
-    let item_id = item_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let item_id = item_id.unwrap_or_else(|| new_uuid().to_string());
 
     if audio_data.is_empty() {
         return Ok(false);
     }
 
-    // 24k pcm to wav
-    let wav_audio = crate::util::pcm_to_wav(audio_data.clone(), crate::util::WavConfig::default());
+    // pcm to wav
+    let wav_audio = crate::util::pcm_to_wav(
+        &audio_data,
+        crate::util::WavConfig {
+            sample_rate: session.input_sample_rate,
+            ..Default::default()
+        },
+    );
 
     // 发送 input_audio_buffer.committed 事件
     let committed_event = ServerEvent::InputAudioBufferCommitted {
-        event_id: Uuid::new_v4().to_string(),
+        event_id: new_uuid().to_string(),
         previous_item_id: None,
         item_id: item_id.clone(),
     };
     let _ = tx.send(committed_event).await;
 
-    if let Some(vad_url) = &config.vad_url {
-        let vad = crate::ai::vad_detect(&session.client, vad_url, wav_audio.clone()).await?;
+    if let Some(vad) = crate::ai::vad::detect_speech(
+        &session.client,
+        asr,
+        &session.vad_params(),
+        wav_audio.clone(),
+    )
+    .await
+    {
+        let vad = vad?;
+        if asr.vad_diagnostics {
+            send_vad_diagnostics(tx, &item_id, wav_audio.clone(), &vad).await;
+        }
         if vad.timestamps.is_empty() {
             let transcription_completed =
                 ServerEvent::ConversationItemInputAudioTranscriptionCompleted {
-                    event_id: Uuid::new_v4().to_string(),
+                    event_id: new_uuid().to_string(),
                     item_id: item_id.clone(),
                     content_index: 0,
                     transcript: String::new(),
@@ -475,17 +2062,60 @@ async fn handle_audio_buffer_commit(
     }
 
     // 执行 ASR
-    let text_results = crate::ai::asr(
-        &session.client,
-        &config.url,
-        &config.api_key,
-        &config.model,
-        &config.lang,
-        &config.prompt,
-        wav_audio.clone(),
-    )
-    .await?;
+    let transcription = session.transcription(asr);
+    let text_results = async {
+        #[cfg(feature = "chaos")]
+        crate::ai::chaos::inject("asr").await?;
+
+        match &asr.mock {
+            Some(mock) => crate::ai::mock::asr(mock),
+            None => {
+                crate::ai::asr(
+                    &session.client,
+                    &asr.url,
+                    &asr.api_key,
+                    transcription.model.as_deref().unwrap_or_default(),
+                    transcription.language.as_deref().unwrap_or_default(),
+                    transcription.prompt.as_deref().unwrap_or_default(),
+                    wav_audio,
+                )
+                .await
+            }
+        }
+    }
+    .await;
+    // the client is told, it may ask the user to say it again
+    let text_results = match text_results {
+        Ok(text_results) => text_results,
+        Err(e) => {
+            log::error!("`{}` asr error: {e}", session.id);
+            config
+                .webhooks
+                .error(&session.id, format!("asr error: {e}"));
+            let transcription_failed = ServerEvent::ConversationItemInputAudioTranscriptionFailed {
+                event_id: new_uuid().to_string(),
+                item_id,
+                content_index: 0,
+                error: ErrorDetails {
+                    error_type: "server_error".to_string(),
+                    code: Some("transcription_failed".to_string()),
+                    message: e.to_string(),
+                    param: None,
+                    event_id: None,
+                },
+            };
+            let _ = tx.send(transcription_failed).await;
+            return Ok(false);
+        }
+    };
     let transcript = text_results.join("\n");
+    let usage = crate::storage::Usage {
+        asr_ms: crate::services::costs::pcm_duration_ms(&audio_data, session.input_sample_rate),
+        ..Default::default()
+    };
+    config.sessions.add_usage(&session.id, usage);
+
+    let audio = encode_base64_blocking(audio_data).await?;
 
     // 创建用户消息项
     let user_item = ConversationItem {
@@ -495,7 +2125,7 @@ async fn handle_audio_buffer_commit(
         status: Some("completed".to_string()),
         role: Some("user".to_string()),
         content: Some(vec![ContentPart::InputAudio {
-            audio: encode_base64(&audio_data),
+            audio,
             transcript: Some(transcript.clone()),
         }]),
         call_id: None,
@@ -505,24 +2135,50 @@ async fn handle_audio_buffer_commit(
     };
 
     // 添加到对话历史
-    session.chat_session.add_user_message(transcript.clone());
+    if !session.transcription_only {
+        let images = session.take_camera_frame().into_iter().collect();
+        session
+            .chat_session
+            .add_user_message_with_images(transcript.clone(), images);
+        if let Some(vad) = &mut session.server_vad {
+            if vad.semantic.is_some() {
+                if !vad.turn_text.is_empty() {
+                    vad.turn_text.push(' ');
+                }
+                vad.turn_text.push_str(&transcript);
+            }
+        }
+    }
 
     // 发送 conversation.item.created 事件
     let item_created = ServerEvent::ConversationItemCreated {
-        event_id: Uuid::new_v4().to_string(),
+        event_id: new_uuid().to_string(),
         previous_item_id: None,
         item: user_item,
     };
     let _ = tx.send(item_created).await;
 
     // 发送转录完成事件
+    config
+        .webhooks
+        .transcription_completed(&session.id, &transcript);
+    config
+        .sessions
+        .record(&session.id, crate::ai::llm::Role::User, &transcript);
+    let edits = match &mut session.dictation {
+        Some(dictation) => dictation.push(&transcript),
+        None => vec![],
+    };
     let transcription_completed = ServerEvent::ConversationItemInputAudioTranscriptionCompleted {
-        event_id: Uuid::new_v4().to_string(),
+        event_id: new_uuid().to_string(),
         item_id: item_id.clone(),
         content_index: 0,
         transcript,
     };
     let _ = tx.send(transcription_completed).await;
+    for edit in edits {
+        let _ = tx.send(edit).await;
+    }
 
     // 如果启用自动响应生成，开始生成响应
     let should_generate_response = session
@@ -532,38 +2188,643 @@ async fn handle_audio_buffer_commit(
         .and_then(|td| td.create_response)
         .unwrap_or(true);
 
-    Ok(should_generate_response)
+    Ok(should_generate_response && !session.transcription_only)
+}
+
+#[tokio::test]
+async fn test_transcription_session() {
+    let config = StableRealtimeConfig::mock(
+        vec![],
+        MockASRConfig {
+            transcripts: vec!["hello".to_string()],
+            error: None,
+            next: Default::default(),
+        },
+    );
+    let mut session = RealtimeSession::new(new_chat_session(&config, None));
+    let (tx, mut rx) = mpsc::channel(64);
+    let client = |event: serde_json::Value| serde_json::from_value(event).unwrap();
+    for event in [
+        serde_json::json!({ "type": "session.update", "session": { "type": "transcription" } }),
+        // 100ms at 24k
+        serde_json::json!({ "type": "input_audio_buffer.append", "audio": "A".repeat(6400) }),
+        serde_json::json!({ "type": "input_audio_buffer.commit" }),
+        serde_json::json!({ "type": "response.create" }),
+    ] {
+        handle_client_message(client(event), &mut session, &tx, &config)
+            .await
+            .unwrap();
+    }
+    drop(tx);
+
+    let mut session_type = None;
+    let mut transcript = None;
+    let mut errors = vec![];
+    while let Some(event) = rx.recv().await {
+        match event {
+            ServerEvent::SessionUpdated { session, .. } => {
+                session_type = Some(session.session_type)
+            }
+            ServerEvent::ConversationItemInputAudioTranscriptionCompleted {
+                transcript: t, ..
+            } => transcript = Some(t),
+            ServerEvent::ResponseCreated { .. } | ServerEvent::ResponseTextDone { .. } => {
+                panic!("a transcription session generated a response")
+            }
+            ServerEvent::Error { error, .. } => errors.push(error.code),
+            _ => {}
+        }
+    }
+    assert_eq!(session_type, Some(SessionType::Transcription));
+    assert_eq!(transcript.as_deref(), Some("hello"));
+    // the transcript is not kept for a response
+    assert!(session.chat_session.messages.is_empty());
+    assert_eq!(errors, [Some("transcription_session".to_string())]);
+}
+
+#[tokio::test]
+async fn test_transcription_failed() {
+    let config = StableRealtimeConfig::mock(
+        vec![],
+        MockASRConfig {
+            transcripts: vec![],
+            error: Some("timeout".to_string()),
+            next: Default::default(),
+        },
+    );
+    let mut session = RealtimeSession::new(new_chat_session(&config, None));
+    let (tx, mut rx) = mpsc::channel(64);
+    session.push_input_audio(&[0; 4800]).await.unwrap();
+    assert!(!commit_audio_buffer(&mut session, &tx, None, &config)
+        .await
+        .unwrap());
+    drop(tx);
+
+    let mut failed = None;
+    while let Some(event) = rx.recv().await {
+        match event {
+            ServerEvent::ConversationItemInputAudioTranscriptionFailed { error, .. } => {
+                failed = error.code
+            }
+            ServerEvent::ConversationItemInputAudioTranscriptionCompleted { .. } => {
+                panic!("the failed audio was transcribed")
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(failed.as_deref(), Some("transcription_failed"));
+    assert!(session.chat_session.messages.is_empty());
+}
+
+async fn send_vad_diagnostics(
+    tx: &mpsc::Sender<ServerEvent>,
+    item_id: &str,
+    wav_audio: Bytes,
+    vad: &crate::ai::vad::VadResponse,
+) {
+    // the vad timestamps are 16k sample indexes
+    let samples = match crate::util::wav_to_pcm16_blocking(wav_audio, 16000).await {
+        Ok((pcm, _)) => pcm
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect::<Vec<i16>>(),
+        Err(e) => {
+            log::warn!("vad diagnostics decode error: {e}");
+            return;
+        }
+    };
+    let (speech_ms, snr_db) = crate::ai::vad::speech_stats(&samples, &vad.timestamps);
+    let segments = vad
+        .timestamps
+        .iter()
+        .map(|t| SpeechSegment {
+            start_ms: (t.start.max(0) / 16) as u32,
+            end_ms: (t.end.max(0) / 16) as u32,
+        })
+        .collect();
+
+    let _ = tx
+        .send(ServerEvent::InputAudioBufferVadDiagnostics {
+            event_id: new_uuid().to_string(),
+            item_id: item_id.to_string(),
+            segments,
+            speech_ms,
+            snr_db,
+        })
+        .await;
+}
+
+/// the assistant item a response streams into, every delta / done event of the text
+/// carries the same `item_id` so clients can correlate them
+#[derive(Debug, Clone)]
+struct OutputItem {
+    response_id: String,
+    item_id: String,
+    /// `session.modalities = ["audio"]`: the item has no text part, the audio is its first
+    audio_only: bool,
+}
+
+impl OutputItem {
+    /// the earcons are not part of an item
+    fn earcon() -> Self {
+        Self {
+            response_id: EARCON_RESPONSE_ID.to_string(),
+            item_id: String::new(),
+            audio_only: false,
+        }
+    }
+
+    /// of the audio part, after the text part if there is one
+    fn audio_index(&self) -> u32 {
+        if self.audio_only {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn audio_delta(&self, delta: String) -> ServerEvent {
+        ServerEvent::ResponseAudioDelta {
+            event_id: new_uuid().to_string(),
+            response_id: self.response_id.clone(),
+            item_id: self.item_id.clone(),
+            output_index: 0,
+            content_index: self.audio_index(),
+            delta,
+        }
+    }
+
+    fn text_delta(&self, delta: String) -> ServerEvent {
+        ServerEvent::ResponseTextDelta {
+            event_id: new_uuid().to_string(),
+            response_id: self.response_id.clone(),
+            item_id: self.item_id.clone(),
+            output_index: 0,
+            content_index: 0,
+            delta,
+        }
+    }
+
+    fn reasoning_delta(&self, delta: String) -> ServerEvent {
+        ServerEvent::ResponseReasoningDelta {
+            event_id: new_uuid().to_string(),
+            response_id: self.response_id.clone(),
+            item_id: self.item_id.clone(),
+            delta,
+        }
+    }
+
+    fn text_done(&self, text: String) -> ServerEvent {
+        ServerEvent::ResponseTextDone {
+            event_id: new_uuid().to_string(),
+            response_id: self.response_id.clone(),
+            item_id: self.item_id.clone(),
+            output_index: 0,
+            content_index: 0,
+            text,
+        }
+    }
+
+    fn transcript_delta(&self, delta: String, alignment: TranscriptAlignment) -> ServerEvent {
+        ServerEvent::ResponseAudioTranscriptDelta {
+            event_id: new_uuid().to_string(),
+            response_id: self.response_id.clone(),
+            item_id: self.item_id.clone(),
+            output_index: 0,
+            content_index: self.audio_index(),
+            delta,
+            alignment: Some(alignment),
+        }
+    }
+
+    fn transcript_done(&self, transcript: String) -> ServerEvent {
+        ServerEvent::ResponseAudioTranscriptDone {
+            event_id: new_uuid().to_string(),
+            response_id: self.response_id.clone(),
+            item_id: self.item_id.clone(),
+            output_index: 0,
+            content_index: self.audio_index(),
+            transcript,
+        }
+    }
+}
+
+/// the `response.output_item.added` and `response.content_part.added` of the message item,
+/// the first output of a response, the function calls come once its text is done
+async fn send_message_added(
+    tx: &mpsc::Sender<ServerEvent>,
+    item: &OutputItem,
+    should_generate_audio: bool,
+) {
+    let assistant_item = ConversationItem {
+        id: Some(item.item_id.clone()),
+        object: Some("realtime.item".to_string()),
+        item_type: "message".to_string(),
+        status: Some("in_progress".to_string()),
+        role: Some("assistant".to_string()),
+        content: Some(vec![]),
+        call_id: None,
+        name: None,
+        arguments: None,
+        output: None,
+    };
+    let _ = tx
+        .send(ServerEvent::ResponseOutputItemAdded {
+            event_id: new_uuid().to_string(),
+            response_id: item.response_id.clone(),
+            output_index: 0,
+            item: assistant_item,
+        })
+        .await;
+
+    if !item.audio_only {
+        let _ = tx
+            .send(ServerEvent::ResponseContentPartAdded {
+                event_id: new_uuid().to_string(),
+                response_id: item.response_id.clone(),
+                item_id: item.item_id.clone(),
+                output_index: 0,
+                content_index: 0,
+                part: ContentPart::Text {
+                    text: String::new(),
+                },
+            })
+            .await;
+    }
+    if should_generate_audio {
+        let _ = tx
+            .send(ServerEvent::ResponseContentPartAdded {
+                event_id: new_uuid().to_string(),
+                response_id: item.response_id.clone(),
+                item_id: item.item_id.clone(),
+                output_index: 0,
+                content_index: item.audio_index(),
+                part: ContentPart::Audio {
+                    audio: None,
+                    transcript: None,
+                },
+            })
+            .await;
+    }
+}
+
+/// `session.tools`, none with `tool_choice = "none"`
+fn client_tools(config: &SessionConfig) -> Vec<crate::ai::llm::Function> {
+    if matches!(config.tool_choice, Some(ToolChoice::None)) {
+        return vec![];
+    }
+    config
+        .tools
+        .iter()
+        .flatten()
+        .map(|tool| crate::ai::llm::Function {
+            name: tool.name.clone(),
+            description: tool.description.clone().unwrap_or_default(),
+            parameters: tool
+                .parameters
+                .clone()
+                .unwrap_or_else(|| serde_json::json!({ "type": "object", "properties": {} })),
+        })
+        .collect()
+}
+
+/// a tool call of the llm as a `function_call` item, its output comes from the client
+fn function_call_item(
+    item_id: &str,
+    call: &crate::ai::llm::ToolCall,
+    status: &str,
+) -> ConversationItem {
+    ConversationItem {
+        id: Some(item_id.to_string()),
+        object: Some("realtime.item".to_string()),
+        item_type: "function_call".to_string(),
+        status: Some(status.to_string()),
+        role: None,
+        content: None,
+        call_id: Some(call.id.clone()),
+        name: Some(call.function.name.clone()),
+        arguments: Some(call.function.arguments.clone()),
+        output: None,
+    }
+}
+
+/// the events of a `function_call` item at `output_index`, it is complete as the llm
+/// streams the calls at once
+async fn send_function_call(
+    tx: &mpsc::Sender<ServerEvent>,
+    response_id: &str,
+    output_index: u32,
+    call: &crate::ai::llm::ToolCall,
+) -> ConversationItem {
+    let item_id = new_uuid().to_string();
+    let mut added = function_call_item(&item_id, call, "in_progress");
+    added.arguments = Some(String::new());
+    let arguments = call.function.arguments.clone();
+    let events = [
+        ServerEvent::ResponseOutputItemAdded {
+            event_id: new_uuid().to_string(),
+            response_id: response_id.to_string(),
+            output_index,
+            item: added,
+        },
+        ServerEvent::ResponseFunctionCallArgumentsDelta {
+            event_id: new_uuid().to_string(),
+            response_id: response_id.to_string(),
+            item_id: item_id.clone(),
+            output_index,
+            content_index: 0,
+            delta: arguments.clone(),
+        },
+        ServerEvent::ResponseFunctionCallArgumentsDone {
+            event_id: new_uuid().to_string(),
+            response_id: response_id.to_string(),
+            item_id: item_id.clone(),
+            output_index,
+            content_index: 0,
+            arguments,
+        },
+    ];
+    for event in events {
+        let _ = tx.send(event).await;
+    }
+    let item = function_call_item(&item_id, call, "completed");
+    let _ = tx
+        .send(ServerEvent::ResponseOutputItemDone {
+            event_id: new_uuid().to_string(),
+            response_id: response_id.to_string(),
+            output_index,
+            item: item.clone(),
+        })
+        .await;
+    item
+}
+
+#[tokio::test]
+async fn test_function_call_items() {
+    let (tx, mut rx) = mpsc::channel(16);
+    let call = crate::ai::llm::ToolCall {
+        id: "call_1".to_string(),
+        type_: "function".to_string(),
+        function: crate::ai::llm::ToolFunction {
+            name: "get_weather".to_string(),
+            arguments: r#"{"city":"Beijing"}"#.to_string(),
+        },
+    };
+    let item = send_function_call(&tx, "resp_1", 1, &call).await;
+    assert_eq!(item.call_id.as_deref(), Some("call_1"));
+    assert_eq!(item.status.as_deref(), Some("completed"));
+    drop(tx);
+
+    let mut types = vec![];
+    while let Some(event) = rx.recv().await {
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["output_index"], 1);
+        types.push(value["type"].as_str().unwrap().to_string());
+    }
+    assert_eq!(
+        types,
+        [
+            "response.output_item.added",
+            "response.function_call_arguments.delta",
+            "response.function_call_arguments.done",
+            "response.output_item.done",
+        ]
+    );
+
+    let mut config: SessionConfig = serde_json::from_value(serde_json::json!({
+        "tools": [{ "type": "function", "name": "get_weather" }]
+    }))
+    .unwrap();
+    assert_eq!(client_tools(&config)[0].name, "get_weather");
+    config.tool_choice = Some(ToolChoice::None);
+    assert!(client_tools(&config).is_empty());
+}
+
+/// the audio and the transcript of an item so far, each spoken delta is aligned after it
+#[derive(Debug, Default)]
+struct AlignmentClock {
+    audio_bytes: u64,
+    text_chars: usize,
+    alignments: Vec<TranscriptAlignment>,
+}
+
+impl AlignmentClock {
+    /// `delta` was spoken in `audio_bytes` of pcm16 at `sample_rate`
+    fn push(&mut self, delta: &str, audio_bytes: usize, sample_rate: u32) -> TranscriptAlignment {
+        let ms = |bytes: u64| bytes / 2 * 1000 / sample_rate.max(1) as u64;
+        let start = (self.audio_bytes, self.text_chars);
+        self.audio_bytes += audio_bytes as u64;
+        self.text_chars += delta.chars().count();
+        let alignment = TranscriptAlignment {
+            audio_start: start.0,
+            audio_end: self.audio_bytes,
+            audio_start_ms: ms(start.0),
+            audio_end_ms: ms(self.audio_bytes),
+            text_start: start.1,
+            text_end: self.text_chars,
+        };
+        self.alignments.push(alignment);
+        alignment
+    }
+}
+
+/// an answer sent with audio, its transcript aligned with the audio delivered
+#[derive(Debug, Clone)]
+pub struct SpokenItem {
+    item_id: String,
+    text: String,
+    alignments: Vec<TranscriptAlignment>,
+    /// cancelled before its end
+    interrupted: bool,
+}
+
+impl SpokenItem {
+    fn delivered_ms(&self) -> u64 {
+        self.alignments.last().map_or(0, |a| a.audio_end_ms)
+    }
+
+    /// the transcript spoken by `audio_end_ms`, linearly within a delta
+    fn heard(&self, audio_end_ms: u64) -> String {
+        let chars = self
+            .alignments
+            .iter()
+            .find(|a| audio_end_ms < a.audio_end_ms)
+            .map_or(self.text.chars().count(), |a| {
+                let span = (a.audio_end_ms - a.audio_start_ms).max(1);
+                let elapsed = audio_end_ms.saturating_sub(a.audio_start_ms);
+                a.text_start + (elapsed * (a.text_end - a.text_start) as u64 / span) as usize
+            });
+        self.text.chars().take(chars).collect()
+    }
+}
+
+#[test]
+fn test_output_item_ids() {
+    let item = OutputItem {
+        response_id: new_uuid().to_string(),
+        item_id: new_uuid().to_string(),
+        audio_only: false,
+    };
+
+    let events = vec![
+        item.text_delta("你好".to_string()),
+        item.text_delta("，世界".to_string()),
+        item.text_done("你好，世界".to_string()),
+    ];
+    let mut event_ids = std::collections::HashSet::new();
+    for event in events {
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["item_id"], item.item_id.as_str());
+        assert_eq!(value["response_id"], item.response_id.as_str());
+        assert!(event_ids.insert(value["event_id"].as_str().unwrap().to_string()));
+    }
+}
+
+#[test]
+fn test_alignment_clock() {
+    let mut clock = AlignmentClock::default();
+    // 500ms then 250ms of 16k pcm16
+    let first = clock.push("你好，", 16000, 16000);
+    let second = clock.push("world", 8000, 16000);
+    assert_eq!((first.text_start, first.text_end), (0, 3));
+    assert_eq!((first.audio_start_ms, first.audio_end_ms), (0, 500));
+    assert_eq!((second.text_start, second.text_end), (3, 8));
+    assert_eq!((second.audio_start, second.audio_end), (16000, 24000));
+    assert_eq!(second.audio_end_ms, 750);
+
+    let item = OutputItem {
+        response_id: new_uuid().to_string(),
+        item_id: new_uuid().to_string(),
+        audio_only: false,
+    };
+    let value = serde_json::to_value(item.transcript_delta("world".to_string(), second)).unwrap();
+    assert_eq!(value["type"], "response.audio.transcript.delta");
+    assert_eq!(value["alignment"]["text_start"], 3);
+    assert_eq!(value["content_index"], 1);
+
+    let item = OutputItem {
+        audio_only: true,
+        ..item
+    };
+    let value = serde_json::to_value(item.audio_delta(String::new())).unwrap();
+    assert_eq!(value["content_index"], 0);
+}
+
+#[test]
+fn test_truncate_spoken() {
+    let mut session = RealtimeSession::new(test_session(0));
+    session
+        .chat_session
+        .add_assistant_message("你好，world".to_string());
+    let mut clock = AlignmentClock::default();
+    clock.push("你好，", 16000, 16000);
+    clock.push("world", 8000, 16000);
+    let spoken = SpokenItem {
+        item_id: "item1".to_string(),
+        text: "你好，world".to_string(),
+        alignments: clock.alignments,
+        interrupted: false,
+    };
+    assert_eq!(spoken.heard(0), "");
+    assert_eq!(spoken.heard(500), "你好，");
+    assert_eq!(spoken.heard(600), "你好，wo");
+    assert_eq!(spoken.heard(10_000), "你好，world");
+
+    session.spoken = Some(spoken);
+    assert_eq!(session.truncate_spoken("item2", 600), None);
+    assert_eq!(session.truncate_spoken("item1", 600), Some(600));
+    assert_eq!(
+        session.chat_session.messages.back().unwrap().message,
+        format!("你好，wo{}", crate::ai::TRUNCATED_MARK)
+    );
+    assert_eq!(session.truncate_spoken("item1", 100), None);
 }
 
-async fn generate_response(
+/// the edits of the live prompts since the last response
+async fn apply_prompts(session: &mut RealtimeSession, tx: &mpsc::Sender<ServerEvent>) {
+    let Some(prompts) = &mut session.prompts else {
+        return;
+    };
+    if let Some(version) = prompts.apply(&mut session.chat_session) {
+        log::info!("`{}` prompts updated to version {version}", session.id);
+        let _ = tx
+            .send(ServerEvent::SessionPromptsUpdated {
+                event_id: new_uuid().to_string(),
+                version,
+            })
+            .await;
+    }
+}
+
+pub(crate) async fn generate_response(
     session: &mut RealtimeSession,
     tx: &mpsc::Sender<ServerEvent>,
-    tts_config: &TTSConfig,
+    config: &StableRealtimeConfig,
 ) -> anyhow::Result<()> {
-    if let Some(last_message) = session.chat_session.messages.back() {
-        if last_message.role == crate::ai::llm::Role::Assistant {
-            log::debug!("Skipping response generation, last message is from assistant");
-            return Ok(());
-        }
+    if session.transcription_only {
+        session.skip_response(tx).await;
+        return Ok(());
+    }
+    if session.duplex {
+        session.response_requested = true;
+        return Ok(());
+    }
+    if session.answered() {
+        log::debug!("Skipping response generation, last message is from assistant");
+        session.skip_response(tx).await;
+        return Ok(());
+    }
+    if let Err(e) = config.sessions.check_quota(&session.id).await {
+        log::warn!("{}: {e}", session.id);
+        let _ = tx
+            .send(ServerEvent::Error {
+                event_id: new_uuid().to_string(),
+                error: ErrorDetails {
+                    error_type: "invalid_request_error".to_string(),
+                    code: Some("quota_exceeded".to_string()),
+                    message: e.to_string(),
+                    param: None,
+                    event_id: None,
+                },
+            })
+            .await;
+        return Ok(());
     }
     // 检查是否需要生成音频
-    let should_generate_audio = session
-        .config
-        .modalities
-        .as_ref()
-        .map(|m| m.contains(&Modality::Audio))
-        .unwrap_or(false);
+    let should_generate_audio = session.wants_audio();
 
-    if session.is_generating {
+    if !session.turn_event(tx, TurnEvent::ResponseStarted).await {
         return Ok(());
     }
-    session.is_generating = true;
+    // back to idle whether the response completes, is cancelled or fails
+    let r = run_response(session, tx, config, should_generate_audio).await;
+    session.turn_event(tx, TurnEvent::ResponseDone).await;
+    r
+}
+
+async fn run_response(
+    session: &mut RealtimeSession,
+    tx: &mpsc::Sender<ServerEvent>,
+    config: &StableRealtimeConfig,
+    should_generate_audio: bool,
+) -> anyhow::Result<()> {
+    config.sessions.add_turn(&session.id);
+    apply_prompts(session, tx).await;
+    let phrases = crate::services::phrases::Phrases::new(&config.phrases);
+    // `session.speed` / `session.pitch` over the ones of `[tts]`
+    let tts = config.tts.with_prosody(Prosody {
+        speed: session.config.speed,
+        pitch: session.config.pitch,
+    });
+    if let Some(vad) = &mut session.server_vad {
+        vad.reset_turn();
+    }
 
-    let response_id = Uuid::new_v4().to_string();
+    let response_id = new_uuid().to_string();
 
     // 发送 response.created 事件
     let response_created = ServerEvent::ResponseCreated {
-        event_id: Uuid::new_v4().to_string(),
+        event_id: new_uuid().to_string(),
         response: Response {
             id: response_id.clone(),
             object: "realtime.response".to_string(),
@@ -575,222 +2836,412 @@ async fn generate_response(
     };
     let _ = tx.send(response_created).await;
 
-    let item_id = Uuid::new_v4().to_string();
-
-    // 发送 response.output_item.added 事件
-    let assistant_item = ConversationItem {
-        id: Some(item_id.clone()),
-        object: Some("realtime.item".to_string()),
-        item_type: "message".to_string(),
-        status: Some("in_progress".to_string()),
-        role: Some("assistant".to_string()),
-        content: Some(vec![ContentPart::Text {
-            text: String::new(),
-        }]),
-        call_id: None,
-        name: None,
-        arguments: None,
-        output: None,
-    };
-
-    let output_item_added = ServerEvent::ResponseOutputItemAdded {
-        event_id: Uuid::new_v4().to_string(),
-        response_id: response_id.clone(),
-        output_index: 0,
-        item: assistant_item.clone(),
-    };
-    let _ = tx.send(output_item_added).await;
-
-    // 发送 response.content_part.added 事件
-    let content_part_added = ServerEvent::ResponseContentPartAdded {
-        event_id: Uuid::new_v4().to_string(),
+    let item_id = new_uuid().to_string();
+    let audio_only = should_generate_audio && session.audio_only();
+    let output_item = OutputItem {
         response_id: response_id.clone(),
         item_id: item_id.clone(),
-        output_index: 0,
-        content_index: 0,
-        part: ContentPart::Text {
-            text: String::new(),
-        },
+        audio_only,
     };
-    let _ = tx.send(content_part_added).await;
 
-    if should_generate_audio {
-        // 发送 response.content_part.added 事件用于音频
-        let audio_part_added = ServerEvent::ResponseContentPartAdded {
-            event_id: Uuid::new_v4().to_string(),
-            response_id: response_id.clone(),
-            item_id: item_id.clone(),
-            output_index: 0,
-            content_index: 1,
-            part: ContentPart::Audio {
-                audio: None,
-                transcript: None,
-            },
-        };
-        let _ = tx.send(audio_part_added).await;
-    }
+    // the client tools of `session.tools`, their calls are function_call items
+    session.chat_session.builtin_tools = client_tools(&session.config);
+    // the message item is added with its first text, a response may only call functions
+    let mut message_added = false;
+    let mut function_calls = vec![];
 
     let mut llm_response = String::new();
+    let mut output_tokens = 0;
+    let mut tts_chars = 0;
+    // each transcript delta is sent after its audio
+    let mut clock = AlignmentClock::default();
+    let out_hz = config.stream.output_sample_rate;
     let mut has_valid_response = false;
+    let mut cancelled = false;
+    let mut abort = session.chat_session.abort_handle.watch();
+    // the response is one of the phrases
+    let mut fallback = false;
+    let mut budget =
+        crate::ai::speech_text::SentenceBudget::new(session.chat_session.max_spoken_sentences);
+    let mut code_blocks =
+        crate::ai::speech_text::CodeBlockFilter::new(session.chat_session.code_blocks.clone());
+    let mut moderation = config
+        .llm
+        .moderation
+        .as_ref()
+        .map(crate::ai::moderation::ModerationFilter::new);
 
-    // 调用 LLM 生成文本响应
-    {
+    // a simple command is answered by the intent router
+    let question = session
+        .chat_session
+        .messages
+        .back()
+        .filter(|m| m.role == crate::ai::llm::Role::User)
+        .map(|m| m.message.clone())
+        .unwrap_or_default();
+    let intent = config.intents.route(&question).await;
+    let routed = intent.is_some();
+
+    if let Some(intent) = intent {
+        log::info!("session {} intent {}", session.id, intent.name);
+        let _ = tx
+            .send(ServerEvent::IntentMatched {
+                event_id: new_uuid().to_string(),
+                response_id: response_id.clone(),
+                name: intent.name,
+                action: intent.action.as_str().to_string(),
+            })
+            .await;
+        llm_response = intent.reply;
+        has_valid_response = true;
+        if !llm_response.is_empty() {
+            session.turn_event(tx, TurnEvent::OutputStarted).await;
+            message_added = true;
+            send_message_added(tx, &output_item, should_generate_audio).await;
+            if !audio_only {
+                let _ = tx.send(output_item.text_delta(llm_response.clone())).await;
+            }
+            if should_generate_audio {
+                tts_chars += llm_response.chars().count() as u64;
+                let bytes = tts_and_send(
+                    tx,
+                    &tts,
+                    &config.stream,
+                    &config.speech_text,
+                    &output_item,
+                    llm_response.clone(),
+                )
+                .await
+                .unwrap_or_else(|e| {
+                    log::error!("Error during TTS: {}", e);
+                    0
+                });
+                let alignment = clock.push(&llm_response, bytes, out_hz);
+                let _ = tx
+                    .send(output_item.transcript_delta(llm_response.clone(), alignment))
+                    .await;
+            }
+        }
+    } else {
+        // 调用 LLM 生成文本响应
         let mut response = session.chat_session.complete().await?;
+        let mut retries = 0;
+        let mut reasoning_filter = config.llm.reasoning_filter();
+        let forward_reasoning = config
+            .llm
+            .reasoning_filter
+            .as_ref()
+            .is_some_and(|f| f.forward);
 
         loop {
-            match response.next_chunk().await {
-                Ok(crate::ai::StableLLMResponseChunk::Text(chunk)) => {
-                    // 检查是否为空或无效响应
-                    if !chunk.trim().is_empty() && chunk.trim() != "()" && chunk.trim() != "[]" {
-                        has_valid_response = true;
-                    }
-                    
-                    llm_response.push_str(&chunk);
-                    
-                    // 发送 response.text.delta 事件
-                    let text_delta = ServerEvent::ResponseTextDelta {
-                        event_id: Uuid::new_v4().to_string(),
-                        response_id: response_id.clone(),
-                        item_id: Uuid::new_v4().to_string(), // 使用新的 UUID
-                        output_index: 0,
-                        content_index: 0,
-                        delta: chunk.clone(),
-                    };
-                    let _ = tx.send(text_delta).await;
-                    if should_generate_audio {
-                        // 发送 TTS 事件
-                        if let Err(e) = tts_and_send(
-                            tx,
-                            tts_config,
-                            response_id.clone(),
-                            Some(item_id.clone()),
-                            chunk.clone(),
-                        )
-                        .await
-                        {
-                            log::error!("Error during TTS: {}", e);
+            // nothing sent yet, the same answer can be asked again
+            let chunk = match response.next_chunk().await {
+                Err(e)
+                    if !e.is::<crate::ai::LlmAborted>()
+                        && llm_response.is_empty()
+                        && session
+                            .chat_session
+                            .wait_retry(&e, retries, &mut abort)
+                            .await =>
+                {
+                    retries += 1;
+                    match session.chat_session.complete().await {
+                        Ok(retried) => {
+                            response = retried;
+                            continue;
                         }
+                        Err(e) => Err(e),
+                    }
+                }
+                chunk => chunk,
+            };
+            let (filtered, stop) = match chunk {
+                Ok(crate::ai::StableLLMResponseChunk::Text(chunk)) => {
+                    output_tokens += crate::ai::llm::estimate_tokens(&chunk);
+                    (reasoning_filter.push(&chunk), false)
+                }
+                Ok(crate::ai::StableLLMResponseChunk::Stop) => (reasoning_filter.finish(), true),
+                Ok(crate::ai::StableLLMResponseChunk::Functions(calls)) => {
+                    for call in &calls {
+                        output_tokens += crate::ai::llm::estimate_tokens(&call.function.name)
+                            + crate::ai::llm::estimate_tokens(&call.function.arguments);
                     }
+                    function_calls.extend(calls);
+                    continue;
+                }
+                Err(e) if e.is::<crate::ai::LlmAborted>() => {
+                    // 客户端取消或断开，保留已生成的部分
+                    log::info!("session {} response {response_id} cancelled", session.id);
+                    cancelled = true;
+                    break;
                 }
-                Ok(crate::ai::StableLLMResponseChunk::Stop) => break,
-                Ok(crate::ai::StableLLMResponseChunk::Functions(_)) => continue,
                 Err(e) => {
                     // LLM 出错时发送标准错误回复
                     log::error!("LLM error: {}", e);
-                    llm_response = STANDARD_ERROR_RESPONSE.to_string();
+                    config.webhooks.error(&session.id, &e);
+                    config.sessions.turn_error(&session.id);
+                    if should_generate_audio {
+                        session.play_earcon(tx, Earcon::Error).await;
+                    }
+                    llm_response = phrases.error.clone();
+                    fallback = true;
                     has_valid_response = true; // 标记为有有效响应（虽然是错误回复）
                     break;
                 }
+            };
+
+            if forward_reasoning && !audio_only && !filtered.reasoning.is_empty() {
+                if !message_added {
+                    message_added = true;
+                    send_message_added(tx, &output_item, should_generate_audio).await;
+                }
+                let _ = tx
+                    .send(output_item.reasoning_delta(filtered.reasoning))
+                    .await;
+            }
+
+            let mut chunk = budget.take(&filtered.text).to_string();
+            if let Some(moderation) = moderation.as_mut() {
+                chunk = moderation.filter(&session.client, &session.id, chunk).await;
+            }
+            if !chunk.trim().is_empty() {
+                // 检查是否为空或无效响应
+                if chunk.trim() != "()" && chunk.trim() != "[]" {
+                    has_valid_response = true;
+                }
+
+                llm_response.push_str(&chunk);
+
+                if session.turn.state() == TurnState::Thinking {
+                    session.turn_event(tx, TurnEvent::OutputStarted).await;
+                }
+                if !message_added {
+                    message_added = true;
+                    send_message_added(tx, &output_item, should_generate_audio).await;
+                }
+                // 发送 response.text.delta 事件
+                if !audio_only {
+                    let _ = tx.send(output_item.text_delta(chunk.clone())).await;
+                }
+                if should_generate_audio {
+                    tts_chars += chunk.chars().count() as u64;
+                    // 发送 TTS 事件, a cancel stops the audio of the sentence too
+                    let bytes = tokio::select! {
+                        r = tts_and_send(
+                            tx,
+                            &tts,
+                            &config.stream,
+                            &config.speech_text,
+                            &output_item,
+                            code_blocks.push(&chunk),
+                        ) => r.unwrap_or_else(|e| {
+                            log::error!("Error during TTS: {}", e);
+                            0
+                        }),
+                        _ = abort.aborted() => 0,
+                    };
+                    if abort.is_aborted() {
+                        log::info!("session {} response {response_id} cancelled", session.id);
+                        cancelled = true;
+                        break;
+                    }
+                    let alignment = clock.push(&chunk, bytes, out_hz);
+                    let _ = tx
+                        .send(output_item.transcript_delta(chunk, alignment))
+                        .await;
+                }
+            }
+
+            if stop {
+                break;
+            }
+            // the rest of the answer would not be spoken, stop the llm
+            if budget.is_spent() {
+                session.chat_session.abort_handle.abort();
+                break;
             }
         }
     }
 
     // 检查是否有有效响应，如果没有则使用标准错误回复
-    if !has_valid_response || llm_response.trim().is_empty() {
-        log::warn!("Empty or invalid LLM response, using standard error message");
-        llm_response = STANDARD_ERROR_RESPONSE.to_string();
-    }
-    
-    // send response.text.done event
-    let text_done = ServerEvent::ResponseTextDone {
-        event_id: Uuid::new_v4().to_string(),
-        response_id: response_id.clone(),
-        item_id: Uuid::new_v4().to_string(), // 使用新的 UUID
-        output_index: 0,
-        content_index: 0,
-        text: llm_response.clone(),
-    };
-    let _ = tx.send(text_done).await;
-
-    // send response.part.done event done
-    let text_part_done = ServerEvent::ResponseContentPartDone {
-        event_id: Uuid::new_v4().to_string(),
-        response_id: response_id.clone(),
-        item_id: item_id.clone(),
-        output_index: 0,
-        content_index: 0,
-        part: ContentPart::Text {
-            text: llm_response.clone(),
-        },
-    };
-    let _ = tx.send(text_part_done).await;
+    // a device command can have no reply, nor a response calling the client tools
+    if !cancelled
+        && !routed
+        && function_calls.is_empty()
+        && (!has_valid_response || llm_response.trim().is_empty())
+    {
+        log::warn!("Empty or invalid LLM response, using the clarification phrase");
+        llm_response = phrases.clarification.clone();
+        fallback = true;
+    }
+    if fallback && session.turn.state() == TurnState::Thinking {
+        session.turn_event(tx, TurnEvent::OutputStarted).await;
+    }
+    if fallback && !message_added {
+        message_added = true;
+        send_message_added(tx, &output_item, should_generate_audio).await;
+    }
+    if fallback && should_generate_audio {
+        let bytes = send_phrase(tx, config, &output_item, &llm_response)
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("Error during phrase TTS: {}", e);
+                0
+            });
+        let alignment = clock.push(&llm_response, bytes, out_hz);
+        let _ = tx
+            .send(output_item.transcript_delta(llm_response.clone(), alignment))
+            .await;
+    }
 
-    if should_generate_audio {
-        let audio_done = ServerEvent::ResponseAudioDone {
-            event_id: Uuid::new_v4().to_string(),
-            response_id: response_id.clone(),
+    if should_generate_audio && !clock.alignments.is_empty() {
+        session.spoken = Some(SpokenItem {
             item_id: item_id.clone(),
-            output_index: 0,
-            content_index: 1,
-        };
-        let _ = tx.send(audio_done).await;
+            text: llm_response.clone(),
+            alignments: std::mem::take(&mut clock.alignments),
+            interrupted: cancelled,
+        });
+    }
 
-        let audio_part_done = ServerEvent::ResponseContentPartDone {
-            event_id: Uuid::new_v4().to_string(),
-            response_id: response_id.clone(),
-            item_id: item_id.clone(),
-            output_index: 0,
-            content_index: 1,
-            part: ContentPart::Audio {
-                audio: None,
-                transcript: Some(llm_response.clone()),
-            },
-        };
-        let _ = tx.send(audio_part_done).await;
+    if !llm_response.is_empty() {
+        config.webhooks.response_done(&session.id, &llm_response);
+        config
+            .sessions
+            .record(&session.id, crate::ai::llm::Role::Assistant, &llm_response);
+        if cancelled {
+            session
+                .chat_session
+                .add_truncated_assistant_message(llm_response.clone());
+        } else {
+            session
+                .chat_session
+                .add_assistant_message(llm_response.clone());
+        }
     }
 
-    // 更新对话历史
-    let final_item = ConversationItem {
-        id: Some(item_id.clone()),
-        object: Some("realtime.item".to_string()),
-        item_type: "message".to_string(),
-        status: Some("completed".to_string()),
-        role: Some("assistant".to_string()),
-        content: Some(if should_generate_audio {
-            vec![
-                ContentPart::Text {
+    let mut output = vec![];
+    if message_added {
+        if !audio_only {
+            // send response.text.done event
+            let _ = tx.send(output_item.text_done(llm_response.clone())).await;
+
+            // send response.part.done event done
+            let text_part_done = ServerEvent::ResponseContentPartDone {
+                event_id: new_uuid().to_string(),
+                response_id: response_id.clone(),
+                item_id: item_id.clone(),
+                output_index: 0,
+                content_index: 0,
+                part: ContentPart::Text {
                     text: llm_response.clone(),
                 },
-                ContentPart::Audio {
+            };
+            let _ = tx.send(text_part_done).await;
+        }
+
+        if should_generate_audio {
+            let audio_done = ServerEvent::ResponseAudioDone {
+                event_id: new_uuid().to_string(),
+                response_id: response_id.clone(),
+                item_id: item_id.clone(),
+                output_index: 0,
+                content_index: output_item.audio_index(),
+            };
+            let _ = tx.send(audio_done).await;
+            let _ = tx
+                .send(output_item.transcript_done(llm_response.clone()))
+                .await;
+
+            let audio_part_done = ServerEvent::ResponseContentPartDone {
+                event_id: new_uuid().to_string(),
+                response_id: response_id.clone(),
+                item_id: item_id.clone(),
+                output_index: 0,
+                content_index: output_item.audio_index(),
+                part: ContentPart::Audio {
                     audio: None,
                     transcript: Some(llm_response.clone()),
                 },
-            ]
-        } else {
-            vec![ContentPart::Text {
-                text: llm_response.clone(),
-            }]
-        }),
-        call_id: None,
-        name: None,
-        arguments: None,
-        output: None,
-    };
+            };
+            let _ = tx.send(audio_part_done).await;
+        }
 
-    session
-        .chat_session
-        .add_assistant_message(llm_response.clone());
-    session.is_generating = false;
+        // 更新对话历史, the text part then the audio part
+        let text_part = ContentPart::Text {
+            text: llm_response.clone(),
+        };
+        let audio_part = ContentPart::Audio {
+            audio: None,
+            transcript: Some(llm_response.clone()),
+        };
+        let final_item = ConversationItem {
+            id: Some(item_id.clone()),
+            object: Some("realtime.item".to_string()),
+            item_type: "message".to_string(),
+            status: Some(if cancelled { "incomplete" } else { "completed" }.to_string()),
+            role: Some("assistant".to_string()),
+            content: Some(match (audio_only, should_generate_audio) {
+                (true, _) => vec![audio_part],
+                (false, true) => vec![text_part, audio_part],
+                (false, false) => vec![text_part],
+            }),
+            call_id: None,
+            name: None,
+            arguments: None,
+            output: None,
+        };
 
-    // 发送 response.output_item.done 事件
-    let output_item_done = ServerEvent::ResponseOutputItemDone {
-        event_id: Uuid::new_v4().to_string(),
-        response_id: response_id.clone(),
-        output_index: 0,
-        item: final_item,
+        // 发送 response.output_item.done 事件
+        let output_item_done = ServerEvent::ResponseOutputItemDone {
+            event_id: new_uuid().to_string(),
+            response_id: response_id.clone(),
+            output_index: 0,
+            item: final_item.clone(),
+        };
+        let _ = tx.send(output_item_done).await;
+        output.push(final_item);
+    }
+
+    // the calls of the client tools follow the message
+    if !cancelled && !function_calls.is_empty() {
+        log::info!(
+            "session {} function calls: {:?}",
+            session.id,
+            function_calls
+        );
+        session
+            .chat_session
+            .add_assistant_tool_call(function_calls.clone());
+        for call in &function_calls {
+            let output_index = output.len() as u32;
+            output.push(send_function_call(tx, &response_id, output_index, call).await);
+        }
+    }
+
+    let usage = crate::storage::Usage {
+        llm_input_tokens: std::mem::take(&mut session.chat_session.prompt_tokens),
+        llm_output_tokens: output_tokens,
+        tts_chars,
+        ..Default::default()
     };
-    let _ = tx.send(output_item_done).await;
+    let usage = config.sessions.add_usage(&session.id, usage);
 
     // 发送 response.done 事件
     let response_done = ServerEvent::ResponseDone {
-        event_id: Uuid::new_v4().to_string(),
+        event_id: new_uuid().to_string(),
         response: Response {
             id: response_id,
             object: "realtime.response".to_string(),
-            status: "completed".to_string(),
+            status: if cancelled { "cancelled" } else { "completed" }.to_string(),
             status_details: None,
-            output: None,
-            usage: None,
+            output: Some(output),
+            usage: Some(Usage {
+                total_tokens: Some((usage.llm_input_tokens + usage.llm_output_tokens) as u32),
+                input_tokens: Some(usage.llm_input_tokens as u32),
+                output_tokens: Some(usage.llm_output_tokens as u32),
+                input_token_details: None,
+                cost: config.sessions.costs().map(|_| usage.cost),
+            }),
         },
     };
     let _ = tx.send(response_done).await;
@@ -806,195 +3257,239 @@ fn extract_text_from_content(content: &[ContentPart]) -> String {
             ContentPart::InputText { text } => Some(text.clone()),
             ContentPart::InputAudio { transcript, .. } => transcript.clone(),
             ContentPart::Audio { transcript, .. } => transcript.clone(),
+            ContentPart::InputImage { .. } => None,
         })
         .collect::<Vec<_>>()
         .join(" ")
 }
 
+/// every tool call since the last user message has its output
+fn tool_calls_answered(messages: &std::collections::LinkedList<crate::ai::llm::Content>) -> bool {
+    let mut answered = std::collections::HashSet::new();
+    for message in messages.iter().rev() {
+        match message.role {
+            crate::ai::llm::Role::User => break,
+            crate::ai::llm::Role::Tool => answered.extend(message.tool_call_id.as_deref()),
+            _ => {
+                let mut calls = message.tool_calls.iter().flatten();
+                if calls.any(|call| !answered.contains(call.id.as_str())) {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+#[test]
+fn test_tool_calls_answered() {
+    use crate::ai::llm::{Content, Role, ToolCall, ToolFunction};
+    let content = |role, tool_calls, tool_call_id: Option<&str>| Content {
+        role,
+        message: String::new(),
+        tool_calls,
+        tool_call_id: tool_call_id.map(str::to_string),
+        images: vec![],
+    };
+    let call = |id: &str| ToolCall {
+        id: id.to_string(),
+        type_: "function".to_string(),
+        function: ToolFunction {
+            name: "get_weather".to_string(),
+            arguments: "{}".to_string(),
+        },
+    };
+    let mut messages = std::collections::LinkedList::new();
+    messages.push_back(content(Role::User, None, None));
+    messages.push_back(content(Role::Assistant, Some(vec![call("c1")]), None));
+    messages.push_back(content(Role::Assistant, Some(vec![call("c2")]), None));
+    messages.push_back(content(Role::Tool, None, Some("c1")));
+    assert!(!tool_calls_answered(&messages));
+    messages.push_back(content(Role::Tool, None, Some("c2")));
+    assert!(tool_calls_answered(&messages));
+}
+
 async fn send_wav(
     tx: &mpsc::Sender<ServerEvent>,
-    response_id: String,
-    item_id: Option<String>,
+    stream: &StreamConfig,
+    item: &OutputItem,
     text: String,
-    wav_data: bytes::Bytes,
-) -> anyhow::Result<std::time::Duration> {
-    let mut reader = wav_io::reader::Reader::from_vec(wav_data.into())
-        .map_err(|e| anyhow::anyhow!("wav_io reader error: {e}"))?;
-
-    let header = reader.read_header()?;
-    let mut samples = reader.get_samples_f32()?;
-    let duration_sec = samples.len() as f32 / (header.sample_rate as f32 * header.channels as f32);
-    let duration_sec = std::time::Duration::from_secs_f32(duration_sec);
-
-    let out_hz = 16000;
-
-    if header.sample_rate != out_hz {
-        // resample to 16000
-        log::info!("resampling from {} to 16000", header.sample_rate);
-        samples = wav_io::resample::linear(samples, header.channels, header.sample_rate, out_hz);
-    }
-    let audio_16k = wav_io::convert_samples_f32_to_i16(&samples);
+    wav_data: Bytes,
+    tts: &TTSConfig,
+) -> anyhow::Result<(std::time::Duration, usize)> {
+    let out_hz = stream.output_sample_rate;
+    let (audio, duration_sec) = crate::ai::tts::to_pcm16(tts, wav_data, out_hz).await?;
+    let bytes = audio.len();
 
     log::info!("llm chunk:{:?}", text);
 
-    for chunk in audio_16k.chunks(5 * out_hz as usize / 10) {
-        let buff = if cfg!(target_endian = "big") {
-            let mut buff = Vec::with_capacity(chunk.len() * 2);
-            for i in chunk {
-                buff.extend_from_slice(&i.to_le_bytes());
-            }
-            buff
-        } else {
-            let chunk_bytes =
-                unsafe { std::slice::from_raw_parts(chunk.as_ptr() as *const u8, chunk.len() * 2) };
-            chunk_bytes.to_vec()
-        };
+    let chunk_size = stream.audio_chunk_bytes();
+    let deltas = tokio::task::spawn_blocking(move || audio_deltas(&audio, chunk_size)).await?;
 
+    for delta in deltas {
         //send to server
-        tx.send(ServerEvent::ResponseAudioDelta {
-            event_id: Uuid::new_v4().to_string(),
-            response_id: response_id.clone(),
-            item_id: item_id.clone().unwrap_or_default(),
-            output_index: 0,
-            content_index: 1,
-            delta: encode_base64(&buff),
-        })
-        .await
-        .map_err(|_| anyhow::anyhow!("send audio error"))?;
+        tx.send(item.audio_delta(delta))
+            .await
+            .map_err(|_| anyhow::anyhow!("send audio error"))?;
     }
 
-    Ok(duration_sec)
+    Ok((duration_sec, bytes))
 }
 
 async fn send_stream_chunk(
     tx: &mpsc::Sender<ServerEvent>,
-    response_id: String,
-    item_id: Option<String>,
+    stream_config: &StreamConfig,
+    item: &OutputItem,
     text: String,
     resp: reqwest::Response,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<usize> {
     log::info!("llm chunk:{:?}", text);
 
-    let in_hz = 16000;
     let mut stream = resp.bytes_stream();
-    let mut rest = bytes::BytesMut::new();
-    let read_chunk_size = 2 * 5 * in_hz as usize / 10; // 0.5 seconds of audio at 16kHz
+    let mut rechunker = crate::util::Rechunker::new(stream_config.audio_chunk_bytes());
+    let mut bytes = 0;
 
-    'next_chunk: while let Some(item) = stream.next().await {
+    while let Some(chunk) = stream.next().await {
         // 小端字节序
-        let mut chunk = item?;
-
+        let chunk = chunk?;
         log::trace!("Received audio chunk of size: {}", chunk.len());
-
-        if rest.len() > 0 {
-            log::trace!("chunk size: {}, rest size: {}", chunk.len(), rest.len());
-            if chunk.len() + rest.len() > read_chunk_size {
-                let n = read_chunk_size - rest.len();
-                rest.put(chunk.slice(..n));
-                debug_assert_eq!(rest.len(), read_chunk_size);
-                let audio_16k = rest.to_vec();
-                log::trace!("Sending audio chunk of size: {}", audio_16k.len());
-
-                // send server audio delta
-                tx.send(ServerEvent::ResponseAudioDelta {
-                    event_id: Uuid::new_v4().to_string(),
-                    response_id: response_id.clone(),
-                    item_id: item_id.clone().unwrap_or_default(),
-                    output_index: 0,
-                    content_index: 1,
-                    delta: encode_base64(&audio_16k),
-                })
-                .await
-                .map_err(|_| anyhow::anyhow!("send audio error"))?;
-
-                rest.clear();
-                chunk = chunk.slice(n..);
-            } else {
-                rest.extend_from_slice(&chunk);
-                continue 'next_chunk;
-            }
-        }
-
-        for samples_16k_data in chunk.chunks(read_chunk_size) {
-            if samples_16k_data.len() < read_chunk_size {
-                log::trace!("Received audio chunk with odd length, skipping");
-                rest.extend_from_slice(&samples_16k_data);
-                continue 'next_chunk;
-            }
-            let audio_16k = samples_16k_data.to_vec();
-            log::trace!("Sending audio chunk of size: {}", audio_16k.len());
-            // send server audio delta
-            tx.send(ServerEvent::ResponseAudioDelta {
-                event_id: Uuid::new_v4().to_string(),
-                response_id: response_id.clone(),
-                item_id: item_id.clone().unwrap_or_default(),
-                output_index: 0,
-                content_index: 1,
-                delta: encode_base64(&audio_16k),
-            })
-            .await
-            .map_err(|_| anyhow::anyhow!("send audio error"))?;
+        for audio in rechunker.push(chunk) {
+            bytes += audio.len();
+            send_audio_delta(tx, item, audio).await?;
         }
     }
+    if let Some(audio) = rechunker.finish() {
+        bytes += audio.len();
+        send_audio_delta(tx, item, audio).await?;
+    }
 
-    if rest.len() > 0 {
-        let audio_16k = rest.to_vec();
-        log::trace!("Sending audio chunk of size: {}", audio_16k.len());
-        // send server audio delta
-        tx.send(ServerEvent::ResponseAudioDelta {
-            event_id: Uuid::new_v4().to_string(),
-            response_id: response_id.clone(),
-            item_id: item_id.clone().unwrap_or_default(),
-            output_index: 0,
-            content_index: 1,
-            delta: encode_base64(&audio_16k),
-        })
+    Ok(bytes)
+}
+
+async fn send_audio_delta(
+    tx: &mpsc::Sender<ServerEvent>,
+    item: &OutputItem,
+    audio: Bytes,
+) -> anyhow::Result<()> {
+    log::trace!("Sending audio chunk of size: {}", audio.len());
+    tx.send(item.audio_delta(encode_base64_blocking(audio).await?))
         .await
-        .map_err(|_| anyhow::anyhow!("send audio error"))?;
-    }
+        .map_err(|_| anyhow::anyhow!("send audio error"))
+}
 
-    Ok(())
+/// a fallback phrase, pre-rendered if it could be, the bytes of pcm16 sent
+async fn send_phrase(
+    tx: &mpsc::Sender<ServerEvent>,
+    config: &StableRealtimeConfig,
+    item: &OutputItem,
+    text: &str,
+) -> anyhow::Result<usize> {
+    let Some(audio) = config
+        .phrase_audio
+        .get(&config.tts, &config.speech_text, text)
+    else {
+        return tts_and_send(
+            tx,
+            &config.tts,
+            &config.stream,
+            &config.speech_text,
+            item,
+            text.to_string(),
+        )
+        .await;
+    };
+    for chunk in audio.chunks(config.stream.audio_chunk_bytes()) {
+        send_audio_delta(tx, item, audio.slice_ref(chunk)).await?;
+    }
+    Ok(audio.len())
 }
 
+/// the bytes of pcm16 sent
 async fn tts_and_send(
     tx: &mpsc::Sender<ServerEvent>,
     tts_config: &TTSConfig,
-    response_id: String,
-    item_id: Option<String>,
+    stream: &StreamConfig,
+    speech_text: &SpeechTextConfig,
+    item: &OutputItem,
     text: String,
-) -> anyhow::Result<()> {
-    match tts_config {
+) -> anyhow::Result<usize> {
+    let out_hz = stream.output_sample_rate;
+
+    let spoken = crate::ai::speech_text::normalize(&text, speech_text);
+    if spoken.trim().is_empty() {
+        return Ok(0);
+    }
+
+    let tts_config = match speech_text.voice_of(&text) {
+        Some(voice) => std::borrow::Cow::Owned(tts_config.with_voice(voice)),
+        None => std::borrow::Cow::Borrowed(tts_config),
+    };
+
+    #[cfg(feature = "chaos")]
+    crate::ai::chaos::inject("tts").await?;
+
+    match tts_config.as_ref() {
         crate::config::TTSConfig::Stable(tts) => {
-            let wav_data = crate::ai::tts::gsv(&tts.url, &tts.speaker, &text, Some(32000)).await?;
-            let duration_sec = send_wav(tx, response_id, item_id, text, wav_data).await?;
+            let sample_rate = tts.sample_rate.unwrap_or(out_hz as usize);
+            let wav_data = crate::ai::tts::gsv(
+                &tts.url,
+                &tts.speaker,
+                &spoken,
+                Some(sample_rate),
+                tts.prosody.speed,
+            )
+            .await?;
+            let (duration_sec, bytes) =
+                send_wav(tx, stream, item, text, wav_data, &tts_config).await?;
             log::info!("Stable TTS duration: {:?}", duration_sec);
-            Ok(())
+            Ok(bytes)
         }
         crate::config::TTSConfig::Fish(fish) => {
-            let wav_data = crate::ai::tts::fish_tts(&fish.api_key, &fish.speaker, &text).await?;
-            let duration_sec = send_wav(tx, response_id, item_id, text, wav_data).await?;
+            let wav_data =
+                crate::ai::tts::fish_tts(&fish.api_key, &fish.speaker, &spoken, fish.prosody.speed)
+                    .await?;
+            let (duration_sec, bytes) =
+                send_wav(tx, stream, item, text, wav_data, &tts_config).await?;
             log::info!("Fish TTS duration: {:?}", duration_sec);
-            Ok(())
+            Ok(bytes)
         }
         crate::config::TTSConfig::Groq(groq) => {
-            let wav_data =
-                crate::ai::tts::groq(&groq.model, &groq.api_key, &groq.voice, &text).await?;
-            let duration_sec = send_wav(tx, response_id, item_id, text, wav_data).await?;
+            let wav_data = crate::ai::tts::groq(
+                &groq.model,
+                &groq.api_key,
+                &groq.voice,
+                &spoken,
+                groq.prosody.speed,
+            )
+            .await?;
+            let (duration_sec, bytes) =
+                send_wav(tx, stream, item, text, wav_data, &tts_config).await?;
             log::info!("Fish TTS duration: {:?}", duration_sec);
-            Ok(())
+            Ok(bytes)
+        }
+        crate::config::TTSConfig::Mock(mock) => {
+            let wav_data = crate::ai::mock::tts(mock, &spoken, out_hz);
+            let (duration_sec, bytes) =
+                send_wav(tx, stream, item, text, wav_data, &tts_config).await?;
+            log::info!("Mock TTS duration: {:?}", duration_sec);
+            Ok(bytes)
         }
         crate::config::TTSConfig::StreamGSV(stream_tts) => {
             let resp = crate::ai::tts::stream_gsv(
                 &stream_tts.url,
                 &stream_tts.speaker,
-                &text,
-                Some(16000),
+                &spoken,
+                Some(out_hz as usize),
+                stream_tts.prosody.speed,
             )
             .await?;
 
-            send_stream_chunk(tx, response_id, item_id, text, resp).await?;
+            let bytes = send_stream_chunk(tx, stream, item, text, resp).await?;
             log::info!("Stream GSV TTS sent");
-            Ok(())
+            Ok(bytes)
+        }
+        crate::config::TTSConfig::CosyVoice(_) => {
+            anyhow::bail!("the realtime api does not support the cosyvoice tts")
         }
     }
 }
@@ -0,0 +1,838 @@
+//! Device registry and pairing.
+//!
+//! 1. admin creates a pairing code: `POST /admin/pairing` `{"profile": "kids"}`
+//! 2. device posts it with its hardware id: `POST /devices/pair` `{"code": "123456", "hardware_id": "aa:bb"}`
+//!    and gets `{"device_id", "token", "profile"}`
+//! 3. device connects with `?token=xxx`, the session uses the bound profile
+//!
+//! admin (bearer `registry.admin_token`):
+//! - `GET /admin/devices`
+//! - `POST /admin/devices/{id}/rename` `{"name": "kitchen"}`
+//! - `POST /admin/devices/{id}/profile` `{"profile": "kids"}`
+//! - `DELETE /admin/devices/{id}` revoke
+//! - `GET /admin/devices/{id}/telemetry`
+//! - `POST /admin/devices/{id}/groups` `{"groups": ["living_room"]}`
+//!
+//! the pairing attempts are rate limited, and with a cluster the devices and the pairing
+//! codes are shared by the instances.
+//!
+//! with [`crate::services::tenants`], the token of a tenant is also an admin token: its
+//! pairing codes pair the devices to the tenant, up to its `quota.max_devices`, and the
+//! admin api only sees and changes the devices of the tenant.
+//!
+//! telemetry: `POST /devices/{id}/telemetry` (bearer device token) `{"battery": 80, "rssi": -60}`,
+//! or over the device websocket
+//!
+//! noise calibration: over the device websocket, text `Calibrate:Start`, a few seconds of
+//! room tone (10s at most), then `Calibrate:End`. the noise floor is stored with the device,
+//! raises the energy vad threshold of that device and gates the room tone out of the audio
+//! sent to the asr, on `/ws/{id}` and `/device/ws`
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    config::{ProfileConfig, RegistryConfig},
+    services::{
+        cluster::{Cluster, ClusterEvent, RateLimiter},
+        same_secret,
+        tenants::Tenants,
+    },
+    storage::{Storage, Store},
+};
+
+/// pairing attempts of a hardware id, and of all of them, in a `PAIR_WINDOW`
+const MAX_PAIR_ATTEMPTS: u64 = 10;
+const MAX_PAIR_ATTEMPTS_TOTAL: u64 = 100;
+const PAIR_WINDOW: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Device {
+    /// the hardware id, also the `{id}` of `/ws/{id}`
+    pub device_id: String,
+    #[serde(default)]
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub profile: Option<String>,
+    pub paired_at: String,
+    #[serde(default)]
+    pub revoked: bool,
+    /// broadcast groups
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noise_profile: Option<NoiseProfile>,
+    /// the tenant of its pairing code, see [`crate::services::tenants`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+/// room tone of a device, measured by the `Calibrate:Start` / `Calibrate:End` routine
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct NoiseProfile {
+    /// dBFS
+    pub noise_floor_db: f32,
+    pub calibrated_at: String,
+}
+
+/// samples of telemetry kept per device
+const TELEMETRY_HISTORY: usize = 100;
+
+/// periodic health report of a device
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Telemetry {
+    /// battery level, 0-100
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery: Option<u8>,
+    /// wifi rssi in dBm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<String>,
+    /// temperature in celsius
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// set by the server
+    #[serde(default, skip_deserializing)]
+    pub received_at: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DeviceStatus {
+    #[serde(flatten)]
+    pub device: Device,
+    pub telemetry: Option<Telemetry>,
+}
+
+#[derive(Debug)]
+struct PairingCode {
+    profile: Option<String>,
+    tenant: Option<String>,
+    expires_at: Instant,
+}
+
+/// who calls the admin api
+#[derive(Debug, Clone, PartialEq)]
+enum Admin {
+    /// `registry.admin_token`
+    All,
+    /// the token of a tenant, for its own devices
+    Tenant(String),
+}
+
+impl Admin {
+    fn tenant(&self) -> Option<String> {
+        match self {
+            Admin::All => None,
+            Admin::Tenant(tenant) => Some(tenant.clone()),
+        }
+    }
+
+    fn owns(&self, device: &Device) -> bool {
+        match self {
+            Admin::All => true,
+            Admin::Tenant(tenant) => device.tenant.as_ref() == Some(tenant),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Revoked,
+    InvalidToken,
+    NotPaired,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Revoked => write!(f, "device revoked"),
+            AuthError::InvalidToken => write!(f, "invalid device token"),
+            AuthError::NotPaired => write!(f, "device not paired"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DeviceRegistry {
+    config: RegistryConfig,
+    profiles: HashMap<String, ProfileConfig>,
+    devices: RwLock<HashMap<String, Device>>,
+    pairing_codes: Mutex<HashMap<String, PairingCode>>,
+    telemetry: RwLock<HashMap<String, VecDeque<Telemetry>>>,
+    store: Arc<Storage>,
+    cluster: Option<Arc<Cluster>>,
+    pair_attempts: RateLimiter,
+    tenants: Arc<Tenants>,
+}
+
+impl DeviceRegistry {
+    pub async fn load(
+        config: RegistryConfig,
+        profiles: HashMap<String, ProfileConfig>,
+        store: Arc<Storage>,
+        cluster: Option<Arc<Cluster>>,
+    ) -> anyhow::Result<Self> {
+        let devices = store
+            .devices()
+            .await?
+            .into_iter()
+            .map(|device| (device.device_id.clone(), device))
+            .collect();
+        Ok(Self {
+            config,
+            profiles,
+            devices: RwLock::new(devices),
+            pairing_codes: Mutex::new(HashMap::new()),
+            telemetry: RwLock::new(HashMap::new()),
+            store,
+            pair_attempts: RateLimiter::new(cluster.clone()),
+            cluster,
+            tenants: Default::default(),
+        })
+    }
+
+    /// the tokens of the tenants are admin tokens of their devices
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    fn admin(&self, headers: &HeaderMap) -> Result<Admin, StatusCode> {
+        match super::check_admin_token(headers, &self.config.admin_token) {
+            Ok(()) => Ok(Admin::All),
+            Err(code) => match self.tenants.by_bearer(headers) {
+                Some(tenant) => Ok(Admin::Tenant(tenant.to_string())),
+                None => Err(code),
+            },
+        }
+    }
+
+    /// the admin of `device_id`, a not found for the devices of another tenant
+    async fn admin_of(&self, headers: &HeaderMap, device_id: &str) -> Result<Admin, StatusCode> {
+        let admin = self.admin(headers)?;
+        if admin != Admin::All
+            && !self
+                .devices
+                .read()
+                .await
+                .get(device_id)
+                .is_some_and(|device| admin.owns(device))
+        {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(admin)
+    }
+
+    /// apply the changes of the other instances
+    pub fn follow_cluster(self: &Arc<Self>) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        let mut events = cluster.subscribe();
+        let registry = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("registry missed {n} cluster events");
+                        continue;
+                    }
+                    Err(_) => return,
+                };
+                match event {
+                    ClusterEvent::Device { device } => {
+                        let mut devices = registry.devices.write().await;
+                        devices.insert(device.device_id.clone(), device);
+                    }
+                    ClusterEvent::PairingCode {
+                        code,
+                        profile,
+                        tenant,
+                        ttl_sec,
+                    } => {
+                        let expires_at = Instant::now() + Duration::from_secs(ttl_sec);
+                        let mut codes = registry.pairing_codes.lock().await;
+                        codes.insert(
+                            code,
+                            PairingCode {
+                                profile,
+                                tenant,
+                                expires_at,
+                            },
+                        );
+                    }
+                    ClusterEvent::PairingCodeUsed { code } => {
+                        registry.pairing_codes.lock().await.remove(&code);
+                    }
+                    ClusterEvent::TelemetryErased { device_id } => {
+                        registry.telemetry.write().await.remove(&device_id);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    fn publish(&self, event: ClusterEvent) {
+        if let Some(cluster) = &self.cluster {
+            cluster.publish(event);
+        }
+    }
+
+    /// false once a hardware id, or all of them, tried too many codes
+    pub async fn allow_pair_attempt(&self, hardware_id: &str) -> bool {
+        let attempts = self
+            .pair_attempts
+            .hit(&format!("pair:{hardware_id}"), PAIR_WINDOW)
+            .await;
+        let total = self.pair_attempts.hit("pair", PAIR_WINDOW).await;
+        attempts <= MAX_PAIR_ATTEMPTS && total <= MAX_PAIR_ATTEMPTS_TOTAL
+    }
+
+    /// a code pairing the device to `profile` and to `tenant`
+    pub async fn create_pairing_code(
+        &self,
+        profile: Option<String>,
+        tenant: Option<String>,
+    ) -> String {
+        let mut codes = self.pairing_codes.lock().await;
+        let now = Instant::now();
+        codes.retain(|_, c| c.expires_at > now);
+
+        let code = loop {
+            let code = format!("{:06}", rand::random_range(0..1_000_000));
+            if !codes.contains_key(&code) {
+                break code;
+            }
+        };
+        codes.insert(
+            code.clone(),
+            PairingCode {
+                profile: profile.clone(),
+                tenant: tenant.clone(),
+                expires_at: now + Duration::from_secs(self.config.pairing_code_ttl_sec),
+            },
+        );
+        self.publish(ClusterEvent::PairingCode {
+            code: code.clone(),
+            profile,
+            tenant,
+            ttl_sec: self.config.pairing_code_ttl_sec,
+        });
+        code
+    }
+
+    /// consume a pairing code, (re)pair the device and issue a new token
+    pub async fn pair(&self, code: &str, hardware_id: &str) -> anyhow::Result<Device> {
+        let pairing = {
+            let mut codes = self.pairing_codes.lock().await;
+            codes
+                .remove(code)
+                .filter(|c| c.expires_at > Instant::now())
+                .ok_or_else(|| anyhow::anyhow!("invalid or expired pairing code"))?
+        };
+        self.publish(ClusterEvent::PairingCodeUsed {
+            code: code.to_string(),
+        });
+
+        let mut devices = self.devices.write().await;
+        let max_devices = pairing
+            .tenant
+            .as_deref()
+            .and_then(|t| self.tenants.get(t))
+            .and_then(|t| t.quota.max_devices);
+        if let Some(max) = max_devices {
+            let paired = devices
+                .values()
+                .filter(|d| !d.revoked && d.tenant == pairing.tenant && d.device_id != hardware_id)
+                .count();
+            if paired >= max {
+                anyhow::bail!("the tenant has paired its {max} devices already");
+            }
+        }
+        let (name, groups, noise_profile) = devices
+            .get(hardware_id)
+            .map(|d| (d.name.clone(), d.groups.clone(), d.noise_profile.clone()))
+            .unwrap_or_default();
+        let device = Device {
+            device_id: hardware_id.to_string(),
+            name,
+            token: uuid::Uuid::new_v4().simple().to_string(),
+            profile: pairing
+                .profile
+                .or_else(|| self.config.default_profile.clone()),
+            paired_at: chrono::Local::now().to_rfc3339(),
+            revoked: false,
+            groups,
+            noise_profile,
+            tenant: pairing.tenant,
+        };
+        self.store.put_device(&device).await?;
+        devices.insert(hardware_id.to_string(), device.clone());
+        self.publish(ClusterEvent::Device {
+            device: device.clone(),
+        });
+        log::info!(
+            "`{hardware_id}` paired, profile {:?}, tenant {:?}",
+            device.profile,
+            device.tenant
+        );
+
+        Ok(device)
+    }
+
+    /// check a connecting device, return its registry entry if it is paired
+    pub async fn authorize(
+        &self,
+        device_id: &str,
+        token: Option<&str>,
+    ) -> Result<Option<Device>, AuthError> {
+        let devices = self.devices.read().await;
+        match devices.get(device_id) {
+            Some(device) if device.revoked => Err(AuthError::Revoked),
+            Some(device) => match token {
+                Some(token) if same_secret(token, &device.token) => Ok(Some(device.clone())),
+                None if !self.config.require_token => Ok(Some(device.clone())),
+                _ => Err(AuthError::InvalidToken),
+            },
+            None if self.config.require_token => Err(AuthError::NotPaired),
+            None => Ok(None),
+        }
+    }
+
+    /// the profile bound to a device, or the default profile
+    pub async fn profile(&self, device_id: &str) -> Option<ProfileConfig> {
+        let devices = self.devices.read().await;
+        let name = devices
+            .get(device_id)
+            .and_then(|d| d.profile.clone())
+            .or_else(|| self.config.default_profile.clone())?;
+        self.profiles.get(&name).cloned()
+    }
+
+    pub async fn list(&self) -> Vec<DeviceStatus> {
+        self.list_as(&Admin::All).await
+    }
+
+    async fn list_as(&self, admin: &Admin) -> Vec<DeviceStatus> {
+        let telemetry = self.telemetry.read().await;
+        self.devices
+            .read()
+            .await
+            .values()
+            .filter(|device| admin.owns(device))
+            .map(|device| DeviceStatus {
+                device: device.clone(),
+                telemetry: telemetry
+                    .get(&device.device_id)
+                    .and_then(|t| t.back().cloned()),
+            })
+            .collect()
+    }
+
+    pub async fn devices_in_group(&self, group: &str) -> Vec<String> {
+        self.devices
+            .read()
+            .await
+            .values()
+            .filter(|d| !d.revoked && d.groups.iter().any(|g| g == group))
+            .map(|d| d.device_id.clone())
+            .collect()
+    }
+
+    pub async fn record_telemetry(&self, device_id: &str, mut telemetry: Telemetry) {
+        log::debug!("`{device_id}` telemetry: {telemetry:?}");
+        telemetry.received_at = chrono::Local::now().to_rfc3339();
+
+        let mut all = self.telemetry.write().await;
+        let history = all.entry(device_id.to_string()).or_default();
+        if history.len() >= TELEMETRY_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(telemetry);
+    }
+
+    pub async fn noise_profile(&self, device_id: &str) -> Option<NoiseProfile> {
+        self.devices
+            .read()
+            .await
+            .get(device_id)
+            .and_then(|d| d.noise_profile.clone())
+    }
+
+    pub async fn set_noise_profile(
+        &self,
+        device_id: &str,
+        profile: NoiseProfile,
+    ) -> anyhow::Result<()> {
+        self.update(device_id, |d| d.noise_profile = Some(profile))
+            .await?;
+        Ok(())
+    }
+
+    /// delete the samples received before `before`, the number deleted
+    pub async fn purge_telemetry(&self, before: &chrono::DateTime<chrono::Utc>) -> usize {
+        let mut all = self.telemetry.write().await;
+        let mut purged = 0;
+        for history in all.values_mut() {
+            let len = history.len();
+            history.retain(|t| {
+                !chrono::DateTime::parse_from_rfc3339(&t.received_at)
+                    .is_ok_and(|received_at| received_at < *before)
+            });
+            purged += len - history.len();
+        }
+        all.retain(|_, history| !history.is_empty());
+        purged
+    }
+
+    /// delete the samples of a device, on every instance, the number deleted here
+    pub async fn erase_telemetry(&self, device_id: &str) -> usize {
+        self.publish(ClusterEvent::TelemetryErased {
+            device_id: device_id.to_string(),
+        });
+        let mut all = self.telemetry.write().await;
+        all.remove(device_id).map(|t| t.len()).unwrap_or(0)
+    }
+
+    pub async fn telemetry_history(&self, device_id: &str) -> Vec<Telemetry> {
+        self.telemetry
+            .read()
+            .await
+            .get(device_id)
+            .map(|t| t.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// insert or replace a device of an archive
+    pub async fn import(&self, device: Device) -> anyhow::Result<()> {
+        self.store.put_device(&device).await?;
+        let mut devices = self.devices.write().await;
+        devices.insert(device.device_id.clone(), device.clone());
+        self.publish(ClusterEvent::Device { device });
+        Ok(())
+    }
+
+    async fn update<F: FnOnce(&mut Device)>(
+        &self,
+        device_id: &str,
+        f: F,
+    ) -> anyhow::Result<Option<Device>> {
+        let mut devices = self.devices.write().await;
+        let Some(device) = devices.get_mut(device_id) else {
+            return Ok(None);
+        };
+        let mut updated = device.clone();
+        f(&mut updated);
+        self.store.put_device(&updated).await?;
+        *device = updated.clone();
+        self.publish(ClusterEvent::Device {
+            device: updated.clone(),
+        });
+        Ok(Some(updated))
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PairingParams {
+    #[serde(default)]
+    profile: Option<String>,
+}
+
+async fn create_pairing(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    headers: HeaderMap,
+    Json(params): Json<PairingParams>,
+) -> impl IntoResponse {
+    let admin = match registry.admin(&headers) {
+        Ok(admin) => admin,
+        Err(code) => return code.into_response(),
+    };
+    if let Some(profile) = &params.profile {
+        if !registry.profiles.contains_key(profile) {
+            return (StatusCode::BAD_REQUEST, "unknown profile").into_response();
+        }
+    }
+
+    let code = registry
+        .create_pairing_code(params.profile, admin.tenant())
+        .await;
+    Json(serde_json::json!({
+        "code": code,
+        "expires_in": registry.config.pairing_code_ttl_sec,
+    }))
+    .into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PairParams {
+    code: String,
+    hardware_id: String,
+}
+
+async fn pair_device(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    Json(params): Json<PairParams>,
+) -> impl IntoResponse {
+    if params.hardware_id.is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty hardware_id").into_response();
+    }
+    if !registry.allow_pair_attempt(&params.hardware_id).await {
+        log::warn!("`{}` too many pairing attempts", params.hardware_id);
+        return (StatusCode::TOO_MANY_REQUESTS, "too many pairing attempts").into_response();
+    }
+    match registry.pair(&params.code, &params.hardware_id).await {
+        Ok(device) => Json(serde_json::json!({
+            "device_id": device.device_id,
+            "token": device.token,
+            "profile": device.profile,
+        }))
+        .into_response(),
+        Err(e) => {
+            log::warn!("`{}` pairing failed: {e}", params.hardware_id);
+            (StatusCode::FORBIDDEN, e.to_string()).into_response()
+        }
+    }
+}
+
+async fn list_devices(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let admin = match registry.admin(&headers) {
+        Ok(admin) => admin,
+        Err(code) => return code.into_response(),
+    };
+    Json(registry.list_as(&admin).await).into_response()
+}
+
+fn update_response(r: anyhow::Result<Option<Device>>) -> axum::response::Response {
+    match r {
+        Ok(Some(device)) => Json(device).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "device not found").into_response(),
+        Err(e) => {
+            log::error!("save device registry error: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RenameParams {
+    name: String,
+}
+
+async fn rename_device(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(params): Json<RenameParams>,
+) -> impl IntoResponse {
+    if let Err(code) = registry.admin_of(&headers, &id).await {
+        return code.into_response();
+    }
+    update_response(registry.update(&id, |d| d.name = params.name).await)
+}
+
+async fn bind_profile(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(params): Json<PairingParams>,
+) -> impl IntoResponse {
+    if let Err(code) = registry.admin_of(&headers, &id).await {
+        return code.into_response();
+    }
+    if let Some(profile) = &params.profile {
+        if !registry.profiles.contains_key(profile) {
+            return (StatusCode::BAD_REQUEST, "unknown profile").into_response();
+        }
+    }
+    update_response(registry.update(&id, |d| d.profile = params.profile).await)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct GroupsParams {
+    groups: Vec<String>,
+}
+
+async fn set_groups(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(params): Json<GroupsParams>,
+) -> impl IntoResponse {
+    if let Err(code) = registry.admin_of(&headers, &id).await {
+        return code.into_response();
+    }
+    update_response(registry.update(&id, |d| d.groups = params.groups).await)
+}
+
+async fn revoke_device(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(code) = registry.admin_of(&headers, &id).await {
+        return code.into_response();
+    }
+    log::info!("`{id}` revoked");
+    update_response(registry.update(&id, |d| d.revoked = true).await)
+}
+
+async fn post_telemetry(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(telemetry): Json<Telemetry>,
+) -> impl IntoResponse {
+    let token = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if let Err(e) = registry.authorize(&id, token).await {
+        return (StatusCode::UNAUTHORIZED, e.to_string()).into_response();
+    }
+    registry.record_telemetry(&id, telemetry).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn get_telemetry(
+    Extension(registry): Extension<Arc<DeviceRegistry>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(code) = registry.admin_of(&headers, &id).await {
+        return code.into_response();
+    }
+    Json(registry.telemetry_history(&id).await).into_response()
+}
+
+pub fn new_registry_service(registry: Arc<DeviceRegistry>) -> Router {
+    Router::new()
+        .route("/devices/pair", post(pair_device))
+        .route("/devices/{id}/telemetry", post(post_telemetry))
+        .route("/admin/pairing", post(create_pairing))
+        .route("/admin/devices", get(list_devices))
+        .route("/admin/devices/{id}", delete(revoke_device))
+        .route("/admin/devices/{id}/rename", post(rename_device))
+        .route("/admin/devices/{id}/profile", post(bind_profile))
+        .route("/admin/devices/{id}/telemetry", get(get_telemetry))
+        .route("/admin/devices/{id}/groups", post(set_groups))
+        .layer(Extension(registry))
+}
+
+#[tokio::test]
+async fn test_pairing() {
+    let path = std::env::temp_dir().join(format!("echokit_devices_{}.json", uuid::Uuid::new_v4()));
+    let config = RegistryConfig {
+        path: path.to_string_lossy().to_string(),
+        admin_token: None,
+        pairing_code_ttl_sec: 60,
+        default_profile: None,
+        require_token: true,
+    };
+    let mut profiles = HashMap::new();
+    profiles.insert("kids".to_string(), ProfileConfig::default());
+    let store = Arc::new(Storage::File(crate::storage::file::FileStore::new(
+        ".",
+        Some(&config.path),
+    )));
+    let registry = DeviceRegistry::load(config, profiles, store, None)
+        .await
+        .unwrap();
+
+    let code = registry
+        .create_pairing_code(Some("kids".to_string()), None)
+        .await;
+    assert_eq!(code.len(), 6);
+    let device = registry.pair(&code, "aa:bb").await.unwrap();
+    assert_eq!(device.profile.as_deref(), Some("kids"));
+    // a pairing code is single use
+    assert!(registry.pair(&code, "cc:dd").await.is_err());
+
+    assert!(registry
+        .authorize("aa:bb", Some(&device.token))
+        .await
+        .unwrap()
+        .is_some());
+    assert!(registry.authorize("aa:bb", None).await.is_err());
+    assert!(registry.authorize("cc:dd", None).await.is_err());
+    assert!(registry.profile("aa:bb").await.is_some());
+
+    registry
+        .update("aa:bb", |d| d.revoked = true)
+        .await
+        .unwrap();
+    assert!(matches!(
+        registry.authorize("aa:bb", Some(&device.token)).await,
+        Err(AuthError::Revoked)
+    ));
+
+    let tenants: HashMap<String, crate::config::TenantConfig> = toml::from_str(
+        r#"
+        [smith]
+        token = "t-smith"
+        quota = { max_devices = 1 }
+        "#,
+    )
+    .unwrap();
+    let registry = registry.with_tenants(Arc::new(Tenants::new(tenants, None)));
+    let code = registry
+        .create_pairing_code(None, Some("smith".to_string()))
+        .await;
+    let device = registry.pair(&code, "ee:ff").await.unwrap();
+    assert_eq!(device.tenant.as_deref(), Some("smith"));
+    let code = registry
+        .create_pairing_code(None, Some("smith".to_string()))
+        .await;
+    assert!(registry.pair(&code, "11:22").await.is_err());
+    let smith = Admin::Tenant("smith".to_string());
+    let listed = registry.list_as(&smith).await;
+    assert_eq!(listed.len(), 1);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn test_telemetry_history() {
+    let config = RegistryConfig {
+        path: String::new(),
+        admin_token: None,
+        pairing_code_ttl_sec: 60,
+        default_profile: None,
+        require_token: false,
+    };
+    let store = Arc::new(Storage::File(crate::storage::file::FileStore::new(
+        ".",
+        Some(&config.path),
+    )));
+    let registry = DeviceRegistry::load(config, HashMap::new(), store, None)
+        .await
+        .unwrap();
+
+    for i in 0..(TELEMETRY_HISTORY + 10) {
+        let telemetry: Telemetry =
+            serde_json::from_str(&format!(r#"{{"battery": {}, "rssi": -60}}"#, i % 100)).unwrap();
+        registry.record_telemetry("aa:bb", telemetry).await;
+    }
+    let history = registry.telemetry_history("aa:bb").await;
+    assert_eq!(history.len(), TELEMETRY_HISTORY);
+    assert_eq!(history.last().unwrap().battery, Some(9));
+    assert!(!history[0].received_at.is_empty());
+}
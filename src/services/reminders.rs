@@ -0,0 +1,488 @@
+//! Reminders, `[reminders]`: a one-off time or a cron schedule, spoken to the device when
+//! due.
+//!
+//! the llm sets them with the `remind` tool, an admin (bearer `admin_token`) with:
+//! - `GET /v1/devices/{id}/reminders` the reminders of a device, oldest first
+//! - `POST /v1/devices/{id}/reminders` `{"text": "...", "at": "...", "in_minutes": 10,
+//!   "cron": "0 8 * * 1-5"}` with one of `at`, `in_minutes` or `cron`
+//! - `DELETE /v1/devices/{id}/reminders/{reminder_id}`
+//!
+//! the reminders are kept in the `[storage]` and checked every `interval_sec`. a due one is
+//! announced on `/ws/{id}` if the device is connected to this instance, else it waits for
+//! the next connection. then a one-off reminder is deleted and a recurring one moved to
+//! its next occurrence, the occurrences missed while the device was away are spoken once.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, TimeZone, Timelike};
+
+use crate::{
+    config::RemindersConfig,
+    services::ws::WsPool,
+    storage::{Reminder, Storage, Store},
+};
+
+pub const REMIND_TOOL: &str = "remind";
+
+/// a `minute hour day month weekday` schedule, each field `*`, `5`, `1-5`, `*/15` or a
+/// list of them, the weekday 0 or 7 is sunday. `@hourly`, `@daily` and `@weekly` too
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// the day and the weekday are both restricted, either one matches like in crontab
+    either_day: bool,
+}
+
+/// the bits of `min..=max` a field selects, and if it is restricted
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<(u64, bool)> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (a.parse()?, b.parse()?),
+                None if step > 1 => (range.parse()?, max),
+                None => (range.parse()?, range.parse()?),
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            anyhow::bail!("bad cron field `{field}`");
+        }
+        for n in (start..=end).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok((bits, !field.starts_with('*')))
+}
+
+fn has(bits: u64, n: u32) -> bool {
+    bits & (1 << n) != 0
+}
+
+impl std::str::FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            s => s,
+        };
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            anyhow::bail!("a cron needs 5 fields: minute hour day month weekday");
+        };
+        let (mut weekdays, weekday_restricted) = parse_field(weekday, 0, 7)?;
+        if has(weekdays, 7) {
+            weekdays |= 1;
+        }
+        let (days, day_restricted) = parse_field(day, 1, 31)?;
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?.0,
+            hours: parse_field(hour, 0, 23)?.0,
+            days,
+            months: parse_field(month, 1, 12)?.0,
+            weekdays,
+            either_day: day_restricted && weekday_restricted,
+        })
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, date: chrono::NaiveDate) -> bool {
+        if !has(self.months, date.month()) {
+            return false;
+        }
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// the first minute of the schedule after `after`, none within 5 years
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local() + chrono::Duration::minutes(1);
+        let mut date = start.date();
+        for _ in 0..366 * 5 {
+            if self.matches_day(date) {
+                let first_hour = if date == start.date() {
+                    start.hour()
+                } else {
+                    0
+                };
+                for hour in (first_hour..24).filter(|h| has(self.hours, *h)) {
+                    let first_minute = if date == start.date() && hour == start.hour() {
+                        start.minute()
+                    } else {
+                        0
+                    };
+                    for minute in (first_minute..60).filter(|m| has(self.minutes, *m)) {
+                        let time = date.and_hms_opt(hour, minute, 0)?;
+                        // none in the gap of a dst change
+                        if let Some(time) = Local.from_local_datetime(&time).earliest() {
+                            return Some(time);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+/// `2025-01-31T08:00:00+08:00`, `2025-01-31 08:00` local, or `08:00` the next one
+pub fn parse_at(at: &str, now: DateTime<Local>) -> anyhow::Result<DateTime<Local>> {
+    let at = at.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(at) {
+        return Ok(time.with_timezone(&Local));
+    }
+    let local = |time: NaiveDateTime| {
+        Local
+            .from_local_datetime(&time)
+            .earliest()
+            .ok_or_else(|| anyhow::anyhow!("`{at}` does not exist in the local time"))
+    };
+    if let Ok(time) = NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M") {
+        return local(time);
+    }
+    let time = NaiveTime::parse_from_str(at, "%H:%M").map_err(|_| {
+        anyhow::anyhow!("bad time `{at}`, expected rfc3339, `YYYY-MM-DD HH:MM` or `HH:MM`")
+    })?;
+    let today = local(now.date_naive().and_time(time))?;
+    if today > now {
+        return Ok(today);
+    }
+    local((now.date_naive() + chrono::Duration::days(1)).and_time(time))
+}
+
+/// a reminder to set, with one of `at`, `in_minutes` or `cron`
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct NewReminder {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub at: Option<String>,
+    #[serde(default)]
+    pub in_minutes: Option<f64>,
+    #[serde(default)]
+    pub cron: Option<String>,
+}
+
+impl NewReminder {
+    /// the reminder of a device, its first due time as of `now`
+    pub fn reminder(self, device_id: &str, now: DateTime<Local>) -> anyhow::Result<Reminder> {
+        let text = self.text.trim().to_string();
+        if text.is_empty() {
+            anyhow::bail!("empty text");
+        }
+        let due_at = match (self.at, self.in_minutes, self.cron.as_deref()) {
+            (Some(at), None, None) => parse_at(&at, now)?,
+            (None, Some(minutes), None) if minutes > 0.0 => {
+                now + chrono::Duration::milliseconds((minutes * 60_000.0) as i64)
+            }
+            (None, None, Some(cron)) => cron
+                .parse::<Cron>()?
+                .next_after(now)
+                .ok_or_else(|| anyhow::anyhow!("`{cron}` never happens"))?,
+            _ => anyhow::bail!("one of `at`, a positive `in_minutes` or `cron` is needed"),
+        };
+        Ok(Reminder {
+            id: uuid::Uuid::new_v4().to_string(),
+            device_id: device_id.to_string(),
+            text,
+            due_at: due_at.to_rfc3339(),
+            cron: self.cron.map(|c| c.trim().to_string()),
+            created_at: now.to_rfc3339(),
+        })
+    }
+}
+
+fn is_due(reminder: &Reminder, now: DateTime<Local>) -> bool {
+    DateTime::parse_from_rfc3339(&reminder.due_at).is_ok_and(|due| due <= now)
+}
+
+#[derive(Debug)]
+pub struct Reminders {
+    config: RemindersConfig,
+    admin_token: Option<String>,
+    store: Arc<Storage>,
+    /// the reminders being spoken, a check does not speak them twice
+    delivering: Mutex<HashSet<String>>,
+}
+
+impl Reminders {
+    pub fn new(config: RemindersConfig, admin_token: Option<String>, store: Arc<Storage>) -> Self {
+        Self {
+            config,
+            admin_token,
+            store,
+            delivering: Default::default(),
+        }
+    }
+
+    /// the built-in tool of the llm if `tool`
+    pub fn tool(&self) -> Option<crate::ai::llm::Function> {
+        if !self.config.tool {
+            return None;
+        }
+        Some(crate::ai::llm::Function {
+            name: REMIND_TOOL.to_string(),
+            description: "Set a reminder spoken to the user when it is due, once or on a \
+                schedule. Give one of `at`, `in_minutes` or `cron`."
+                .to_string(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "text": {"type": "string", "description": "what to remind the user of, one short sentence"},
+                    "at": {"type": "string", "description": "local time, `HH:MM` for the next one or `YYYY-MM-DD HH:MM`"},
+                    "in_minutes": {"type": "number", "description": "minutes from now"},
+                    "cron": {"type": "string", "description": "a recurring reminder, `minute hour day month weekday`, e.g. `0 8 * * 1-5`"}
+                },
+                "required": ["text"]
+            }),
+        })
+    }
+
+    pub async fn add(&self, device_id: &str, new: NewReminder) -> anyhow::Result<Reminder> {
+        let reminder = new.reminder(device_id, Local::now())?;
+        self.store.put_reminder(&reminder).await?;
+        log::info!(
+            "`{device_id}` reminder {} at {}",
+            reminder.id,
+            reminder.due_at
+        );
+        Ok(reminder)
+    }
+
+    /// false if the device has no such reminder
+    pub async fn cancel(&self, device_id: &str, reminder_id: &str) -> anyhow::Result<bool> {
+        let reminders = self.store.reminders(Some(device_id)).await?;
+        if !reminders.iter().any(|r| r.id == reminder_id) {
+            return Ok(false);
+        }
+        self.store.delete_reminder(reminder_id).await
+    }
+
+    /// speak the due reminders of a connected device, the number spoken
+    pub async fn deliver(&self, pool: &WsPool, device_id: &str) -> anyhow::Result<usize> {
+        let now = Local::now();
+        let due = self
+            .store
+            .reminders(Some(device_id))
+            .await?
+            .into_iter()
+            .filter(|r| is_due(r, now))
+            .filter(|r| self.delivering.lock().unwrap().insert(r.id.clone()))
+            .collect::<Vec<_>>();
+        let mut spoken = 0;
+        for reminder in due {
+            let r = self.deliver_one(pool, &reminder, now).await;
+            self.delivering.lock().unwrap().remove(&reminder.id);
+            match r {
+                Ok(()) => spoken += 1,
+                // kept for the next check
+                Err(e) => log::warn!("`{device_id}` reminder {} error: {e}", reminder.id),
+            }
+        }
+        Ok(spoken)
+    }
+
+    async fn deliver_one(
+        &self,
+        pool: &WsPool,
+        reminder: &Reminder,
+        now: DateTime<Local>,
+    ) -> anyhow::Result<()> {
+        let text = self.config.template.replace("{text}", &reminder.text);
+        super::ws::announce(pool, &reminder.device_id, text, Default::default()).await?;
+        let next = reminder
+            .cron
+            .as_deref()
+            .and_then(|cron| cron.parse::<Cron>().ok())
+            .and_then(|cron| cron.next_after(now));
+        match next {
+            Some(next) => {
+                let reminder = Reminder {
+                    due_at: next.to_rfc3339(),
+                    ..reminder.clone()
+                };
+                self.store.put_reminder(&reminder).await
+            }
+            None => self.store.delete_reminder(&reminder.id).await.map(|_| ()),
+        }
+    }
+
+    /// speak the due reminders of the devices connected to this instance
+    pub async fn run(&self, pool: &WsPool) {
+        let now = Local::now();
+        let reminders = match self.store.reminders(None).await {
+            Ok(reminders) => reminders,
+            Err(e) => {
+                log::error!("reminders error: {e}");
+                return;
+            }
+        };
+        let devices = reminders
+            .iter()
+            .filter(|r| is_due(r, now))
+            .map(|r| r.device_id.clone())
+            .collect::<HashSet<_>>();
+        for device_id in devices {
+            if pool.is_online(&device_id).await {
+                if let Err(e) = self.deliver(pool, &device_id).await {
+                    log::error!("`{device_id}` reminders error: {e}");
+                }
+            }
+        }
+    }
+
+    /// check every `interval_sec`
+    pub fn spawn(self: &Arc<Self>, pool: Arc<WsPool>) {
+        let reminders = self.clone();
+        tokio::spawn(async move {
+            let period = std::time::Duration::from_secs(reminders.config.interval_sec.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                reminders.run(&pool).await;
+            }
+        });
+    }
+}
+
+/// GET /v1/devices/{id}/reminders
+async fn list_reminders(
+    Extension(reminders): Extension<Arc<Reminders>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &reminders.admin_token) {
+        return code.into_response();
+    }
+    match reminders.store.reminders(Some(&id)).await {
+        Ok(reminders) => Json(reminders).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// POST /v1/devices/{id}/reminders
+async fn add_reminder(
+    Extension(reminders): Extension<Arc<Reminders>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(new): Json<NewReminder>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &reminders.admin_token) {
+        return code.into_response();
+    }
+    let reminder = match new.reminder(&id, Local::now()) {
+        Ok(reminder) => reminder,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    match reminders.store.put_reminder(&reminder).await {
+        Ok(()) => Json(reminder).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// DELETE /v1/devices/{id}/reminders/{reminder_id}
+async fn delete_reminder(
+    Extension(reminders): Extension<Arc<Reminders>>,
+    headers: HeaderMap,
+    Path((id, reminder_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &reminders.admin_token) {
+        return code.into_response();
+    }
+    match reminders.cancel(&id, &reminder_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "reminder not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+pub fn new_reminders_service(reminders: Arc<Reminders>) -> Router {
+    Router::new()
+        .route(
+            "/v1/devices/{id}/reminders",
+            get(list_reminders).post(add_reminder),
+        )
+        .route(
+            "/v1/devices/{id}/reminders/{reminder_id}",
+            delete(delete_reminder),
+        )
+        .layer(Extension(reminders))
+}
+
+#[test]
+fn test_reminders() {
+    let now = Local.with_ymd_and_hms(2025, 1, 31, 9, 30, 20).unwrap();
+    let at = |y, m, d, h, min| Local.with_ymd_and_hms(y, m, d, h, min, 0).unwrap();
+
+    let weekdays: Cron = "0 8 * * 1-5".parse().unwrap();
+    // friday 09:30, the next one is monday
+    assert_eq!(weekdays.next_after(now), Some(at(2025, 2, 3, 8, 0)));
+    let quarters: Cron = "*/15 * * * *".parse().unwrap();
+    assert_eq!(quarters.next_after(now), Some(at(2025, 1, 31, 9, 45)));
+    // the 1st of the month or a sunday
+    let either: Cron = "30 7 1 * 7".parse().unwrap();
+    assert_eq!(either.next_after(now), Some(at(2025, 2, 1, 7, 30)));
+    assert_eq!(
+        either.next_after(at(2025, 2, 1, 8, 0)),
+        Some(at(2025, 2, 2, 7, 30))
+    );
+    assert!("0 8 * *".parse::<Cron>().is_err());
+    assert!("61 8 * * *".parse::<Cron>().is_err());
+    assert!("0 0 31 2 *"
+        .parse::<Cron>()
+        .unwrap()
+        .next_after(now)
+        .is_none());
+
+    assert_eq!(parse_at("10:00", now).unwrap(), at(2025, 1, 31, 10, 0));
+    assert_eq!(parse_at("09:00", now).unwrap(), at(2025, 2, 1, 9, 0));
+    assert_eq!(
+        parse_at("2025-03-01 12:00", now).unwrap(),
+        at(2025, 3, 1, 12, 0)
+    );
+
+    let new = NewReminder {
+        text: " 喝水 ".to_string(),
+        in_minutes: Some(10.0),
+        ..Default::default()
+    };
+    let reminder = new.reminder("dev1", now).unwrap();
+    assert_eq!(reminder.text, "喝水");
+    assert!(!is_due(&reminder, now));
+    assert!(is_due(&reminder, now + chrono::Duration::minutes(10)));
+    let both = NewReminder {
+        text: "喝水".to_string(),
+        at: Some("10:00".to_string()),
+        cron: Some("@daily".to_string()),
+        ..Default::default()
+    };
+    assert!(both.reminder("dev1", now).is_err());
+}
@@ -0,0 +1,192 @@
+//! Retention of the stored data, `[retention]`: a reaper deletes what is older than the
+//! ttl of its class every `interval_sec`.
+//!
+//! - `transcripts_days` the turns and the sessions of the `[storage]`
+//! - `recordings_days` the audio of `[recordings]`
+//! - `telemetry_days` the telemetry samples of the registry
+//!
+//! admin (bearer `admin_token`):
+//! - `GET /v1/retention` the ttls and the items purged since the start
+
+use std::sync::{Arc, Mutex};
+
+use axum::{http::HeaderMap, response::IntoResponse, routing::get, Extension, Json, Router};
+
+use crate::{
+    config::RetentionConfig,
+    services::registry::DeviceRegistry,
+    storage::{recordings::Recordings, Storage, Store},
+};
+
+/// items purged since the start
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct RetentionStats {
+    pub runs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run_at: Option<String>,
+    pub purged_transcripts: u64,
+    pub purged_recordings: u64,
+    pub purged_telemetry: u64,
+    pub errors: u64,
+}
+
+#[derive(Debug)]
+pub struct Retention {
+    config: RetentionConfig,
+    admin_token: Option<String>,
+    store: Arc<Storage>,
+    recordings: Arc<Recordings>,
+    registry: Option<Arc<DeviceRegistry>>,
+    stats: Mutex<RetentionStats>,
+}
+
+fn cutoff(
+    now: chrono::DateTime<chrono::Utc>,
+    days: Option<u64>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    Some(now - chrono::Duration::days(days? as i64))
+}
+
+impl Retention {
+    pub fn new(
+        config: RetentionConfig,
+        admin_token: Option<String>,
+        store: Arc<Storage>,
+        recordings: Arc<Recordings>,
+        registry: Option<Arc<DeviceRegistry>>,
+    ) -> Self {
+        Self {
+            config,
+            admin_token,
+            store,
+            recordings,
+            registry,
+            stats: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> RetentionStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// purge every class once, as of `now`
+    pub async fn run(&self, now: chrono::DateTime<chrono::Utc>) {
+        let mut run = RetentionStats {
+            runs: 1,
+            last_run_at: Some(now.to_rfc3339()),
+            ..Default::default()
+        };
+        if let Some(before) = cutoff(now, self.config.transcripts_days) {
+            match self.store.purge_transcripts(&before).await {
+                Ok(n) => run.purged_transcripts = n,
+                Err(e) => {
+                    log::error!("retention: transcripts purge error: {e}");
+                    run.errors += 1;
+                }
+            }
+        }
+        if let Some(before) = cutoff(now, self.config.recordings_days) {
+            match self.recordings.purge(&before).await {
+                Ok(n) => run.purged_recordings = n as u64,
+                Err(e) => {
+                    log::error!("retention: recordings purge error: {e}");
+                    run.errors += 1;
+                }
+            }
+        }
+        if let (Some(before), Some(registry)) =
+            (cutoff(now, self.config.telemetry_days), &self.registry)
+        {
+            run.purged_telemetry = registry.purge_telemetry(&before).await as u64;
+        }
+        log::info!(
+            "retention: purged {} turns, {} recordings, {} telemetry samples",
+            run.purged_transcripts,
+            run.purged_recordings,
+            run.purged_telemetry
+        );
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.runs += run.runs;
+        stats.last_run_at = run.last_run_at;
+        stats.purged_transcripts += run.purged_transcripts;
+        stats.purged_recordings += run.purged_recordings;
+        stats.purged_telemetry += run.purged_telemetry;
+        stats.errors += run.errors;
+    }
+
+    /// run every `interval_sec`, the first time right away
+    pub fn spawn(self: &Arc<Self>) {
+        let retention = self.clone();
+        tokio::spawn(async move {
+            let period = std::time::Duration::from_secs(retention.config.interval_sec.max(60));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                retention.run(chrono::Utc::now()).await;
+            }
+        });
+    }
+}
+
+/// GET /v1/retention
+async fn get_retention(
+    Extension(retention): Extension<Arc<Retention>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &retention.admin_token) {
+        return code.into_response();
+    }
+    Json(serde_json::json!({
+        "config": retention.config,
+        "stats": retention.stats(),
+    }))
+    .into_response()
+}
+
+pub fn new_retention_service(retention: Arc<Retention>) -> Router {
+    Router::new()
+        .route("/v1/retention", get(get_retention))
+        .layer(Extension(retention))
+}
+
+#[tokio::test]
+async fn test_retention() {
+    use crate::storage::{file::FileStore, Turn};
+
+    let dir = std::env::temp_dir().join(format!("echokit_retention_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let store = Arc::new(Storage::File(FileStore::new(&dir.to_string_lossy(), None)));
+    let now = chrono::Utc::now();
+    for days in [40, 1] {
+        let turn = Turn {
+            session_id: "dev1".to_string(),
+            device_id: Some("dev1".to_string()),
+            time: (now - chrono::Duration::days(days)).to_rfc3339(),
+            role: crate::ai::llm::Role::User,
+            text: format!("{days} days ago"),
+        };
+        store.append_turn(&turn).await.unwrap();
+    }
+
+    let config = RetentionConfig {
+        transcripts_days: Some(30),
+        recordings_days: None,
+        telemetry_days: None,
+        interval_sec: 3600,
+    };
+    let recordings = Arc::new(Recordings::Local(dir.join("record")));
+    let retention = Retention::new(config, None, store.clone(), recordings, None);
+    retention.run(now).await;
+    retention.run(now).await;
+
+    let turns = store.turns(None).await.unwrap();
+    assert_eq!(turns.len(), 1);
+    assert_eq!(turns[0].text, "1 days ago");
+    let stats = retention.stats();
+    assert_eq!(
+        (stats.runs, stats.purged_transcripts, stats.errors),
+        (2, 1, 0)
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,191 @@
+//! Routines, `[[routines]]`: a named sequence of tool calls that one voice command runs,
+//! answered without the llm.
+//!
+//! ```toml
+//! [[routines]]
+//! name = "晚安模式"
+//! keywords = ["晚安模式", "我要睡了"]
+//! reply = "晚安，好梦"
+//!
+//! [[routines.steps]]
+//! name = "关灯"
+//! tool = "set_light"
+//! arguments = { room = "bedroom", on = false }
+//! say = "灯关好了"
+//!
+//! [[routines.steps]]
+//! tool = "set_timer"
+//! arguments = { at = "07:00", label = "起床" }
+//! ```
+//!
+//! a step calls a built-in tool or one of the mcp servers. the device gets a `Routine` event
+//! as the routine starts and after each step; the `say` of a step is spoken once it is
+//! done, the `failed_text` when it fails, and the routine stops there unless
+//! `stop_on_error = false`.
+
+use crate::{
+    config::{RoutineConfig, RoutineStep},
+    protocol::{RoutineEvent, RoutineState},
+    services::ws::{self, WsCommand, WsPool},
+};
+
+/// what the asr may put around a keyword
+const ASR_PUNCTUATION: &[char] = &['，', '。', '？', '！', ',', '.', '?', '!', ' '];
+
+#[derive(Debug)]
+pub struct Routines {
+    routines: Vec<RoutineConfig>,
+}
+
+impl RoutineStep {
+    fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.tool)
+    }
+}
+
+impl Routines {
+    pub fn new(routines: Vec<RoutineConfig>) -> Self {
+        Self { routines }
+    }
+
+    /// the routine the utterance runs, on one of its keywords or its name
+    pub fn find(&self, utterance: &str) -> Option<&RoutineConfig> {
+        let text = utterance.trim_matches(ASR_PUNCTUATION);
+        if text.is_empty() {
+            return None;
+        }
+        self.routines.iter().find(|routine| {
+            if routine.keywords.is_empty() {
+                text.contains(routine.name.as_str())
+            } else {
+                routine.keywords.iter().any(|k| text.contains(k.as_str()))
+            }
+        })
+    }
+
+    /// a turn running `routine`, each step reported as a `Routine` event
+    pub async fn turn(
+        &self,
+        pool: &WsPool,
+        id: &str,
+        text: String,
+        routine: &RoutineConfig,
+    ) -> anyhow::Result<()> {
+        pool.send(id, WsCommand::AsrResult(vec![text.clone()]))
+            .await?;
+        pool.webhooks.transcription_completed(id, &text);
+        pool.sessions.record(id, crate::ai::llm::Role::User, &text);
+        pool.sessions.add_turn(id);
+        pool.sessions.turn_intent(id, "routine");
+        log::info!("`{id}` routine {}", routine.name);
+
+        let mut event = RoutineEvent {
+            name: routine.name.clone(),
+            state: RoutineState::Started,
+            step: 0,
+            steps: routine.steps.len(),
+            error: None,
+        };
+        pool.send(id, WsCommand::Routine(event.clone())).await?;
+
+        let mut spoken = vec![];
+        for step in &routine.steps {
+            event.step += 1;
+            let arguments = serde_json::Value::Object(step.arguments.clone()).to_string();
+            pool.webhooks.tool_invoked(id, &step.tool, &arguments);
+            match ws::call_tool(pool, id, &step.tool, &arguments).await {
+                Ok(_) => {
+                    event.state = RoutineState::StepDone;
+                    pool.send(id, WsCommand::Routine(event.clone())).await?;
+                    if let Some(say) = &step.say {
+                        spoken.push(say.clone());
+                        speak(pool, id, say.clone()).await?;
+                    }
+                }
+                Err(e) => {
+                    log::warn!(
+                        "`{id}` routine {} step {} error: {e}",
+                        routine.name,
+                        event.step
+                    );
+                    event.error = Some(format!("{}: {e}", step.label()));
+                    event.state = if routine.stop_on_error {
+                        RoutineState::Failed
+                    } else {
+                        RoutineState::StepFailed
+                    };
+                    pool.send(id, WsCommand::Routine(event.clone())).await?;
+                    let failed = routine.failed_text.replace("{step}", step.label());
+                    spoken.push(failed.clone());
+                    speak(pool, id, failed).await?;
+                    if routine.stop_on_error {
+                        pool.sessions.record(
+                            id,
+                            crate::ai::llm::Role::Assistant,
+                            &spoken.join(" "),
+                        );
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        event.state = RoutineState::Done;
+        pool.send(id, WsCommand::Routine(event)).await?;
+        if let Some(reply) = &routine.reply {
+            spoken.push(reply.clone());
+            speak(pool, id, reply.clone()).await?;
+        }
+        pool.sessions
+            .record(id, crate::ai::llm::Role::Assistant, &spoken.join(" "));
+        Ok(())
+    }
+}
+
+async fn speak(pool: &WsPool, id: &str, text: String) -> anyhow::Result<()> {
+    pool.send(id, WsCommand::StartAudio(text.clone())).await?;
+    let r = ws::tts_and_send(pool, id, text).await;
+    pool.send(id, WsCommand::EndAudio).await?;
+    r
+}
+
+#[test]
+fn test_routines() {
+    #[derive(serde::Deserialize)]
+    struct Config {
+        routines: Vec<RoutineConfig>,
+    }
+    let config: Config = toml::from_str(
+        r#"
+        [[routines]]
+        name = "晚安模式"
+        keywords = ["晚安模式", "我要睡了"]
+
+        [[routines.steps]]
+        name = "关灯"
+        tool = "set_light"
+        arguments = { on = false }
+
+        [[routines.steps]]
+        tool = "device_control"
+        arguments = { volume = 20 }
+
+        [[routines]]
+        name = "回家模式"
+        stop_on_error = false
+        steps = []
+        "#,
+    )
+    .unwrap();
+    let night = &config.routines[0];
+    assert!(night.stop_on_error);
+    assert_eq!(night.steps[0].label(), "关灯");
+    assert_eq!(night.steps[1].label(), "device_control");
+    assert_eq!(night.steps[1].arguments["volume"], 20);
+
+    let routines = Routines::new(config.routines);
+    assert_eq!(routines.find("我要睡了。").unwrap().name, "晚安模式");
+    assert_eq!(routines.find("打开回家模式").unwrap().name, "回家模式");
+    assert!(routines.find("晚安").is_none());
+    assert!(routines.find("").is_none());
+}
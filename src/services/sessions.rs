@@ -0,0 +1,689 @@
+//! Live sessions of all the websocket endpoints.
+//!
+//! admin (bearer `admin_token`):
+//! - `GET /v1/sessions`, or with the token of a tenant the sessions of the tenant
+//! - `GET /v1/sessions/{id}`
+//! - `POST /v1/sessions/{id}/messages` `{"role": "user", "content": "...", "generate": true}`
+//!   appends a `user` or `system` message, and with `generate` the device speaks the answer
+//!   right away, e.g. a doorbell or a calendar reminder
+//!
+//! a `/ws/{id}` session is registered under its device id, the realtime sessions
+//! (`/v1/realtime`, `/v1/chat/ws`, `/device/ws`) under the id of `session.created`.
+//! the transcripts of the turns go to the [`History`] if there is one, their timings and
+//! usage to the [`Analytics`], and to the [`Tenants`] for their quotas. with a cluster,
+//! `GET /v1/sessions` lists the sessions of all the instances, and the sessions of the other
+//! instances are read and take messages on any of them, see [`Cluster`]. a `/ws/{id}` device
+//! that connects again within [`HANDOFF_WINDOW`], to any instance, goes on with the turns of
+//! its last conversation read from the history
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    ai::llm::Role,
+    config::CostsConfig,
+    services::{
+        analytics::Analytics,
+        cluster::{Cluster, ClusterEvent},
+        history::{AnnotationRequest, History},
+        prompts::LivePrompts,
+        tenants::Tenants,
+    },
+    storage::{SessionRecord, Turn, TurnMetric, Usage},
+};
+
+/// with a cluster and a history, how recent the turns of a device taken over are
+pub const HANDOFF_WINDOW: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    /// `ws`, `realtime`, `chat` or `device`
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// see [`crate::services::tenants`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub created_at: String,
+    pub last_activity: String,
+    /// responses generated so far
+    pub turns: usize,
+    /// current config, the `session` of `session.updated` for the realtime sessions
+    pub config: serde_json::Value,
+    /// of the providers so far, see [`crate::services::costs`]
+    pub usage: Usage,
+    /// time to the first audio of the last turn
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_audio_ms: Option<u64>,
+}
+
+/// body of `POST /v1/sessions/{id}/messages`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct InjectedMessage {
+    /// `user` or `system`
+    pub role: Role,
+    pub content: String,
+    /// generate a response right away
+    #[serde(default = "InjectedMessage::default_generate")]
+    pub generate: bool,
+}
+
+impl InjectedMessage {
+    fn default_generate() -> bool {
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// generation of the handle that opened it
+    generation: u64,
+    info: SessionInfo,
+    /// set by the sessions that take injected messages
+    inbox: Option<mpsc::UnboundedSender<InjectedMessage>>,
+    turn: Option<OpenTurn>,
+    /// used before the turn opens, the asr of its transcript
+    pending_usage: Usage,
+}
+
+/// the turn in progress, a [`TurnMetric`] once it ends
+#[derive(Debug)]
+struct OpenTurn {
+    started: std::time::Instant,
+    time: String,
+    first_audio_ms: Option<u64>,
+    error: bool,
+    intent: Option<String>,
+    usage: Usage,
+}
+
+impl OpenTurn {
+    fn new(usage: Usage) -> Self {
+        Self {
+            started: std::time::Instant::now(),
+            time: chrono::Local::now().to_rfc3339(),
+            first_audio_ms: None,
+            error: false,
+            intent: None,
+            usage,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SessionManager {
+    pub(crate) admin_token: Option<String>,
+    sessions: Mutex<HashMap<String, Entry>>,
+    generation: AtomicU64,
+    history: Option<Arc<History>>,
+    cluster: Option<Arc<Cluster>>,
+    analytics: Option<Arc<Analytics>>,
+    prompts: LivePrompts,
+    costs: Option<CostsConfig>,
+    tenants: Arc<Tenants>,
+}
+
+/// keeps the session listed until it is dropped
+#[derive(Debug)]
+pub struct SessionHandle {
+    manager: Arc<SessionManager>,
+    id: String,
+    generation: u64,
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        let mut sessions = self.manager.sessions.lock().unwrap();
+        // a reconnect of the same device replaced this session already
+        if sessions
+            .get(&self.id)
+            .is_some_and(|entry| entry.generation == self.generation)
+        {
+            let entry = sessions.remove(&self.id);
+            if let (Some(history), Some(entry)) = (&self.manager.history, entry) {
+                let mut record = entry.info.record();
+                record.ended_at = Some(chrono::Local::now().to_rfc3339());
+                history.record_session(record);
+            }
+        }
+    }
+}
+
+impl SessionInfo {
+    fn record(&self) -> SessionRecord {
+        SessionRecord {
+            id: self.id.clone(),
+            kind: self.kind.to_string(),
+            device_id: self.device_id.clone(),
+            started_at: self.created_at.clone(),
+            ended_at: None,
+            turns: self.turns,
+        }
+    }
+}
+
+impl SessionHandle {
+    /// the messages posted to the session from now on
+    pub fn inbox(&self) -> mpsc::UnboundedReceiver<InjectedMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut sessions = self.manager.sessions.lock().unwrap();
+        if let Some(entry) = sessions
+            .get_mut(&self.id)
+            .filter(|entry| entry.generation == self.generation)
+        {
+            entry.inbox = Some(tx);
+        }
+        rx
+    }
+}
+
+impl SessionManager {
+    pub fn new(
+        admin_token: Option<String>,
+        history: Option<Arc<History>>,
+        cluster: Option<Arc<Cluster>>,
+        analytics: Option<Arc<Analytics>>,
+    ) -> Self {
+        Self {
+            admin_token,
+            history,
+            cluster,
+            analytics,
+            ..Default::default()
+        }
+    }
+
+    /// the prompts of the config, edited by `PUT /v1/prompts`
+    pub fn with_prompts(mut self, prompts: LivePrompts) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    pub fn prompts(&self) -> &LivePrompts {
+        &self.prompts
+    }
+
+    /// the prices of `[costs]`, the usage is not priced without them
+    pub fn with_costs(mut self, costs: Option<CostsConfig>) -> Self {
+        self.costs = costs;
+        self
+    }
+
+    pub fn costs(&self) -> Option<&CostsConfig> {
+        self.costs.as_ref()
+    }
+
+    /// the turns and the usage of the sessions of a tenant count for its quotas
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    pub fn tenants(&self) -> &Tenants {
+        &self.tenants
+    }
+
+    /// register a session, a session with the same id is replaced
+    pub fn open(
+        self: &Arc<Self>,
+        id: String,
+        kind: &'static str,
+        device_id: Option<String>,
+    ) -> SessionHandle {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let now = chrono::Local::now().to_rfc3339();
+        let info = SessionInfo {
+            id: id.clone(),
+            kind,
+            device_id,
+            tenant: None,
+            created_at: now.clone(),
+            last_activity: now,
+            turns: 0,
+            config: serde_json::Value::Null,
+            usage: Usage::default(),
+            first_audio_ms: None,
+        };
+        if let Some(history) = &self.history {
+            history.record_session(info.record());
+        }
+        let entry = Entry {
+            generation,
+            info,
+            inbox: None,
+            turn: None,
+            pending_usage: Usage::default(),
+        };
+        self.sessions.lock().unwrap().insert(id.clone(), entry);
+        SessionHandle {
+            manager: self.clone(),
+            id,
+            generation,
+        }
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut SessionInfo)) {
+        if let Some(entry) = self.sessions.lock().unwrap().get_mut(id) {
+            f(&mut entry.info);
+        }
+    }
+
+    pub fn touch(&self, id: &str) {
+        let now = chrono::Local::now().to_rfc3339();
+        self.update(id, |info| info.last_activity = now);
+    }
+
+    pub fn add_turn(&self, id: &str) {
+        let now = chrono::Local::now().to_rfc3339();
+        let mut tenant = None;
+        self.update(id, |info| {
+            info.turns += 1;
+            info.last_activity = now;
+            tenant = info.tenant.clone();
+        });
+        if let Some(tenant) = tenant {
+            self.tenants.add_turn(&tenant);
+        }
+    }
+
+    /// the session belongs to `tenant`, from now on
+    pub fn set_tenant(&self, id: &str, tenant: Option<String>) {
+        self.update(id, |info| info.tenant = tenant);
+    }
+
+    pub fn tenant(&self, id: &str) -> Option<String> {
+        self.get(id).and_then(|info| info.tenant)
+    }
+
+    /// an error once the tenant of the session used up a quota
+    pub async fn check_quota(&self, id: &str) -> anyhow::Result<()> {
+        match self.tenant(id) {
+            Some(tenant) => self.tenants.check_quota(&tenant).await,
+            None => Ok(()),
+        }
+    }
+
+    /// keep a transcript of the session in the history
+    pub fn record(&self, id: &str, role: Role, text: &str) {
+        let Some(history) = &self.history else {
+            return;
+        };
+        let device_id = self.get(id).and_then(|info| info.device_id);
+        history.record(id, device_id, role, text);
+    }
+
+    fn update_turn(&self, id: &str, f: impl FnOnce(&mut OpenTurn)) {
+        if self.analytics.is_none() {
+            return;
+        }
+        if let Some(entry) = self.sessions.lock().unwrap().get_mut(id) {
+            let pending = &mut entry.pending_usage;
+            f(entry
+                .turn
+                .get_or_insert_with(|| OpenTurn::new(std::mem::take(pending))));
+        }
+    }
+
+    /// priced and added to the session and its turn, the priced usage
+    pub fn add_usage(&self, id: &str, mut usage: Usage) -> Usage {
+        usage.cost = self.costs.as_ref().map_or(0.0, |costs| costs.price(&usage));
+        if let Some(entry) = self.sessions.lock().unwrap().get_mut(id) {
+            entry.info.usage.add(&usage);
+            if let Some(tenant) = &entry.info.tenant {
+                self.tenants.add_usage(tenant, &usage);
+            }
+            if self.analytics.is_some() {
+                match &mut entry.turn {
+                    Some(turn) => turn.usage.add(&usage),
+                    None => entry.pending_usage.add(&usage),
+                }
+            }
+        }
+        usage
+    }
+
+    /// the transcript is ready, a turn still open ends here
+    pub fn turn_started(&self, id: &str) {
+        self.turn_done(id);
+        self.update_turn(id, |_| {});
+    }
+
+    /// `elapsed` since the end of the question, shown by `GET /v1/sessions`
+    pub fn turn_first_audio(&self, id: &str, elapsed: std::time::Duration) {
+        if let Some(entry) = self.sessions.lock().unwrap().get_mut(id) {
+            entry.info.first_audio_ms = Some(elapsed.as_millis() as u64);
+        }
+        self.update_turn(id, |turn| {
+            let ms = turn.started.elapsed().as_millis() as u64;
+            turn.first_audio_ms.get_or_insert(ms);
+        });
+    }
+
+    /// the first tool the llm called in the turn
+    pub fn turn_intent(&self, id: &str, name: &str) {
+        self.update_turn(id, |turn| {
+            turn.intent.get_or_insert_with(|| name.to_string());
+        });
+    }
+
+    pub fn turn_error(&self, id: &str) {
+        self.update_turn(id, |turn| turn.error = true);
+    }
+
+    /// the response ended, its metric goes to the analytics
+    pub fn turn_done(&self, id: &str) {
+        let Some(analytics) = &self.analytics else {
+            return;
+        };
+        let metric = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let Some(entry) = sessions.get_mut(id) else {
+                return;
+            };
+            let Some(turn) = entry.turn.take() else {
+                return;
+            };
+            TurnMetric {
+                id: String::new(),
+                session_id: id.to_string(),
+                device_id: entry.info.device_id.clone(),
+                kind: entry.info.kind.to_string(),
+                time: turn.time,
+                first_audio_ms: turn.first_audio_ms,
+                response_ms: turn.started.elapsed().as_millis() as u64,
+                error: turn.error,
+                intent: turn.intent,
+                usage: turn.usage,
+            }
+        };
+        analytics.record(metric);
+    }
+
+    /// tag the last answer of the session in the history
+    pub fn feedback(&self, id: &str, request: AnnotationRequest) {
+        let Some(history) = &self.history else {
+            return;
+        };
+        let device_id = self.get(id).and_then(|info| info.device_id);
+        history.feedback(id, device_id, request);
+    }
+
+    pub fn set_config(&self, id: &str, config: impl serde::Serialize) {
+        match serde_json::to_value(config) {
+            Ok(config) => self.update(id, |info| info.config = config),
+            Err(e) => log::warn!("session `{id}` config encode error: {e}"),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(id).map(|entry| entry.info.clone())
+    }
+
+    /// a session of `device_id` is open here or, with a cluster, on another instance
+    pub async fn is_connected(&self, device_id: &str) -> bool {
+        let local = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .any(|entry| entry.info.device_id.as_deref() == Some(device_id));
+        if local {
+            return true;
+        }
+        let Some(cluster) = &self.cluster else {
+            return false;
+        };
+        match cluster.sessions().await {
+            Ok(remote) => remote.iter().any(|s| s["device_id"] == device_id),
+            Err(e) => {
+                log::error!("cluster sessions error: {e}");
+                false
+            }
+        }
+    }
+
+    /// oldest first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut list = sessions
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+        list
+    }
+
+    pub fn takes_messages(&self, id: &str) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(id)
+            .is_some_and(|entry| entry.inbox.as_ref().is_some_and(|inbox| !inbox.is_closed()))
+    }
+
+    /// with a cluster, the recent turns of `device_id` on any instance, see [`HANDOFF_WINDOW`]
+    pub async fn handoff(&self, device_id: &str) -> Vec<Turn> {
+        let (Some(_), Some(history)) = (&self.cluster, &self.history) else {
+            return vec![];
+        };
+        let since = chrono::Utc::now() - HANDOFF_WINDOW;
+        history
+            .turns_since(device_id, &since)
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("`{device_id}` handoff error: {e}");
+                vec![]
+            })
+    }
+
+    /// false if the session does not take messages or is closing
+    pub fn send_message(&self, id: &str, message: InjectedMessage) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(id)
+            .and_then(|entry| entry.inbox.as_ref())
+            .is_some_and(|inbox| inbox.send(message).is_ok())
+    }
+
+    /// take the messages posted on the other instances to the sessions of this one
+    pub fn follow_cluster(self: &Arc<Self>) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        let mut events = cluster.subscribe();
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("sessions missed {n} cluster events");
+                        continue;
+                    }
+                    Err(_) => return,
+                };
+                if let ClusterEvent::SessionMessage {
+                    session_id,
+                    message,
+                } = event
+                {
+                    if sessions.get(&session_id).is_some()
+                        && !sessions.send_message(&session_id, message)
+                    {
+                        log::warn!("`{session_id}` does not take the message of the cluster");
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// GET /v1/sessions
+async fn list_sessions(
+    Extension(sessions): Extension<Arc<SessionManager>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Some(tenant) = sessions.tenants.by_bearer(&headers) {
+        let own = sessions
+            .list()
+            .into_iter()
+            .filter(|info| info.tenant.as_deref() == Some(tenant))
+            .collect::<Vec<_>>();
+        return Json(own).into_response();
+    }
+    if let Err(code) = super::check_admin_token(&headers, &sessions.admin_token) {
+        return code.into_response();
+    }
+    let Some(cluster) = &sessions.cluster else {
+        return Json(sessions.list()).into_response();
+    };
+    let local = sessions.list();
+    let mut all = local
+        .iter()
+        .filter_map(|info| serde_json::to_value(info).ok())
+        .collect::<Vec<_>>();
+    match cluster.sessions().await {
+        Ok(remote) => all.extend(
+            remote
+                .into_iter()
+                .filter(|s| !local.iter().any(|info| s["id"] == info.id.as_str())),
+        ),
+        Err(e) => log::error!("cluster sessions error: {e}"),
+    }
+    all.sort_by_key(|s| {
+        let field = |name: &str| s[name].as_str().unwrap_or_default().to_string();
+        (field("created_at"), field("id"))
+    });
+    Json(all).into_response()
+}
+
+/// GET /v1/sessions/{id}
+async fn get_session(
+    Extension(sessions): Extension<Arc<SessionManager>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &sessions.admin_token) {
+        return code.into_response();
+    }
+    if let Some(info) = sessions.get(&id) {
+        return Json(info).into_response();
+    }
+    if let Some(cluster) = &sessions.cluster {
+        match cluster.session(&id).await {
+            Ok(Some(info)) => return Json(info).into_response(),
+            Ok(None) => {}
+            Err(e) => log::error!("cluster session error: {e}"),
+        }
+    }
+    (StatusCode::NOT_FOUND, "session not found").into_response()
+}
+
+/// POST /v1/sessions/{id}/messages
+async fn post_message(
+    Extension(sessions): Extension<Arc<SessionManager>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(message): Json<InjectedMessage>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &sessions.admin_token) {
+        return code.into_response();
+    }
+    if !matches!(message.role, Role::User | Role::System) {
+        return (StatusCode::BAD_REQUEST, "role must be user or system").into_response();
+    }
+    if message.content.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty content").into_response();
+    }
+    if sessions.get(&id).is_none() {
+        // the instance of the session takes it
+        if let Some(cluster) = &sessions.cluster {
+            if let Ok(Some(info)) = cluster.session(&id).await {
+                if info["takes_messages"] == false {
+                    return (StatusCode::CONFLICT, "session does not take messages")
+                        .into_response();
+                }
+                cluster.publish(ClusterEvent::SessionMessage {
+                    session_id: id,
+                    message,
+                });
+                return StatusCode::ACCEPTED.into_response();
+            }
+        }
+        return (StatusCode::NOT_FOUND, "session not found").into_response();
+    }
+    if !sessions.send_message(&id, message) {
+        return (StatusCode::CONFLICT, "session does not take messages").into_response();
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+pub fn new_sessions_service(sessions: Arc<SessionManager>) -> Router {
+    Router::new()
+        .route("/v1/sessions", get(list_sessions))
+        .route("/v1/sessions/{id}", get(get_session))
+        .route("/v1/sessions/{id}/messages", post(post_message))
+        .layer(Extension(sessions))
+}
+
+#[tokio::test]
+async fn test_session_manager() {
+    let sessions = Arc::new(SessionManager::new(None, None, None, None));
+    let first = sessions.open("dev1".to_string(), "ws", Some("dev1".to_string()));
+    sessions.add_turn("dev1");
+    sessions.set_config("dev1", serde_json::json!({"voice": "alloy"}));
+    let usage = Usage {
+        tts_chars: 10,
+        ..Default::default()
+    };
+    sessions.add_usage("dev1", usage.clone());
+    sessions.add_usage("dev1", usage);
+    sessions.turn_first_audio("dev1", std::time::Duration::from_millis(850));
+    let info = sessions.get("dev1").unwrap();
+    assert_eq!(info.turns, 1);
+    assert_eq!(info.first_audio_ms, Some(850));
+    assert_eq!(info.config["voice"], "alloy");
+    assert_eq!(info.usage.tts_chars, 20);
+
+    // the device reconnects before the old socket is closed
+    let second = sessions.open("dev1".to_string(), "ws", Some("dev1".to_string()));
+    drop(first);
+    assert_eq!(sessions.get("dev1").unwrap().turns, 0);
+    let _realtime = sessions.open("abc".to_string(), "realtime", None);
+    assert_eq!(sessions.list().len(), 2);
+    assert!(sessions.is_connected("dev1").await);
+    assert!(!sessions.is_connected("abc").await);
+    sessions.set_tenant("abc", Some("smith".to_string()));
+    sessions.add_turn("abc");
+    assert_eq!(sessions.tenant("abc").as_deref(), Some("smith"));
+    assert_eq!(sessions.tenants().usage("smith").await.turns, 1);
+
+    let message: InjectedMessage =
+        serde_json::from_str(r#"{"role": "system", "content": "门铃响了"}"#).unwrap();
+    assert!(message.generate);
+    assert!(!sessions.send_message("abc", message.clone()));
+    assert!(!sessions.takes_messages("abc"));
+    assert!(sessions.handoff("dev1").await.is_empty());
+    let mut inbox = second.inbox();
+    assert!(sessions.send_message("dev1", message));
+    assert_eq!(inbox.try_recv().unwrap().content, "门铃响了");
+
+    drop(second);
+    assert!(sessions.get("dev1").is_none());
+    assert_eq!(sessions.list().len(), 1);
+}
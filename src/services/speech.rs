@@ -0,0 +1,228 @@
+//! Standalone speech api, the same voices as the device sessions without a websocket.
+//!
+//! bearer `admin_token`:
+//! - `POST /v1/tts` `{"text": "...", "voice": "xx", "format": "wav", "sample_rate": 16000}`
+//!   -> the audio, `wav` or `pcm` (pcm16 le mono, rate in the `x-sample-rate` header)
+//! - `POST /v1/asr?language=zh&timestamps=true` with a wav or ogg / opus file as body
+//!   -> `{"text", "language", "segments": [{"start_ms", "end_ms", "text"}]}`
+
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Extension, Json, Router,
+};
+
+use crate::config::{SpeechTextConfig, TTSConfig, WhisperASRConfig};
+
+/// largest upload of `/v1/asr`
+const MAX_ASR_UPLOAD: usize = 25 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioFormat {
+    #[default]
+    Wav,
+    Pcm,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TtsRequest {
+    pub text: String,
+    /// replaces the speaker / voice of the tts config
+    #[serde(default)]
+    pub voice: Option<String>,
+    #[serde(default)]
+    pub format: AudioFormat,
+    /// `stream.output_sample_rate` if unset
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AsrParams {
+    /// `asr.lang` if unset
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub timestamps: bool,
+}
+
+pub struct SpeechService {
+    pub tts: Option<TTSConfig>,
+    /// only whisper compatible asr servers take uploads
+    pub asr: Option<WhisperASRConfig>,
+    pub speech_text: SpeechTextConfig,
+    pub output_sample_rate: u32,
+    pub admin_token: Option<String>,
+}
+
+/// POST /v1/tts
+async fn tts_handler(
+    Extension(service): Extension<Arc<SpeechService>>,
+    headers: HeaderMap,
+    Json(req): Json<TtsRequest>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &service.admin_token) {
+        return code.into_response();
+    }
+    let Some(tts) = &service.tts else {
+        return (StatusCode::NOT_IMPLEMENTED, "no tts configured").into_response();
+    };
+    let sample_rate = req.sample_rate.unwrap_or(service.output_sample_rate);
+    if !(8000..=48000).contains(&sample_rate) {
+        return (StatusCode::BAD_REQUEST, "sample_rate out of range").into_response();
+    }
+
+    let spoken = crate::ai::speech_text::normalize(&req.text, &service.speech_text);
+    if spoken.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty text").into_response();
+    }
+    let voice = req
+        .voice
+        .as_ref()
+        .or_else(|| service.speech_text.voice_of(&req.text));
+    let tts = match voice {
+        Some(voice) => std::borrow::Cow::Owned(tts.with_voice(voice)),
+        None => std::borrow::Cow::Borrowed(tts),
+    };
+
+    let pcm = match super::offline::render_pcm(&tts, &spoken, sample_rate).await {
+        Ok(pcm) => pcm,
+        Err(e) => {
+            log::error!("/v1/tts error: {e}");
+            return (StatusCode::BAD_GATEWAY, e.to_string()).into_response();
+        }
+    };
+
+    match req.format {
+        AudioFormat::Pcm => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (
+                    header::HeaderName::from_static("x-sample-rate"),
+                    sample_rate.to_string(),
+                ),
+            ],
+            pcm,
+        )
+            .into_response(),
+        AudioFormat::Wav => {
+            let wav = crate::util::pcm_to_wav(
+                &pcm,
+                crate::util::WavConfig {
+                    sample_rate,
+                    ..Default::default()
+                },
+            );
+            ([(header::CONTENT_TYPE, "audio/wav")], wav).into_response()
+        }
+    }
+}
+
+/// file name of the upload for the asr server, `None` if the format is not supported
+fn upload_file_name(audio: &[u8]) -> Option<&'static str> {
+    if audio.starts_with(b"RIFF") {
+        Some("audio.wav")
+    } else if audio.starts_with(b"OggS") {
+        Some("audio.ogg")
+    } else if audio.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        Some("audio.webm")
+    } else {
+        None
+    }
+}
+
+/// POST /v1/asr
+async fn asr_handler(
+    Extension(service): Extension<Arc<SpeechService>>,
+    headers: HeaderMap,
+    Query(params): Query<AsrParams>,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &service.admin_token) {
+        return code.into_response();
+    }
+    let Some(asr) = &service.asr else {
+        return (StatusCode::NOT_IMPLEMENTED, "no whisper asr configured").into_response();
+    };
+    let Some(file_name) = upload_file_name(&body) else {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected a wav, ogg / opus or webm file",
+        )
+            .into_response();
+    };
+
+    // wav is resampled to 16k mono like the device audio, the other containers
+    // are decoded by the asr server
+    let audio = if file_name == "audio.wav" {
+        match crate::util::wav_to_pcm16_blocking(body, 16000).await {
+            Ok((pcm, _)) => crate::util::pcm_to_wav(
+                &pcm,
+                crate::util::WavConfig {
+                    sample_rate: 16000,
+                    ..Default::default()
+                },
+            ),
+            Err(e) => {
+                return (StatusCode::BAD_REQUEST, format!("invalid wav: {e}")).into_response()
+            }
+        }
+    } else {
+        body
+    };
+
+    let client = reqwest::Client::new();
+    match crate::ai::transcribe(
+        &client,
+        asr,
+        audio,
+        file_name,
+        params.language.as_deref().filter(|l| !l.is_empty()),
+        params.timestamps,
+    )
+    .await
+    {
+        Ok(transcript) => Json(transcript).into_response(),
+        Err(e) => {
+            log::error!("/v1/asr error: {e}");
+            (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+        }
+    }
+}
+
+pub fn new_speech_service(service: SpeechService) -> Router {
+    Router::new()
+        .route("/v1/tts", post(tts_handler))
+        .route(
+            "/v1/asr",
+            post(asr_handler).layer(DefaultBodyLimit::max(MAX_ASR_UPLOAD)),
+        )
+        .layer(Extension(Arc::new(service)))
+}
+
+#[test]
+fn test_upload_file_name() {
+    assert_eq!(upload_file_name(b"RIFF\0\0\0\0WAVE"), Some("audio.wav"));
+    assert_eq!(upload_file_name(b"OggS\0\x02"), Some("audio.ogg"));
+    assert_eq!(upload_file_name(b"ID3\x03"), None);
+}
+
+#[test]
+fn test_tts_request() {
+    let req: TtsRequest = serde_json::from_str(r#"{"text": "你好"}"#).unwrap();
+    assert_eq!(req.format, AudioFormat::Wav);
+    assert_eq!(req.sample_rate, None);
+
+    let req: TtsRequest = serde_json::from_str(
+        r#"{"text": "hi", "voice": "alloy", "format": "pcm", "sample_rate": 24000}"#,
+    )
+    .unwrap();
+    assert_eq!(req.format, AudioFormat::Pcm);
+    assert_eq!(req.voice.as_deref(), Some("alloy"));
+}
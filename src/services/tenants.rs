@@ -0,0 +1,366 @@
+//! Tenants, `[tenants.<name>]`: one hosted instance serves several households or
+//! customers, each with its own api keys, prompts, devices and quotas.
+//!
+//! ```toml
+//! [tenants.smith]
+//! token = "tenant-smith-secret"
+//! llm_api_key = "sk-xxx"
+//! sys_prompts = [{ role = "system", content = "你是史密斯一家的语音助手。" }]
+//!
+//! [tenants.smith.quota]
+//! max_devices = 5
+//! daily_turns = 500
+//! daily_cost = 2.0
+//! ```
+//!
+//! once there is a tenant, `/v1/realtime`, `/v1/chat/ws` and the devices out of the
+//! registry connect with the token of their tenant in an `Authorization: Bearer` header,
+//! or the `admin_token`, and the session takes the keys and the prompts of the tenant.
+//! the paired devices belong to the tenant of their pairing code, the others get the id
+//! `{tenant}:{id}` so that a tenant cannot take the device id of another one.
+//!
+//! the token of a tenant is the admin token of its own devices, `POST /admin/pairing`,
+//! `GET /admin/devices` and the rest of the registry admin api only see them.
+//! `GET /v1/tenants` (bearer `admin_token`, or a tenant token for its own) is the usage
+//! of today of each tenant. over a quota the connections are refused with a 429 and the
+//! turns answered with an error. the usage is kept in the `[storage]`, the instances
+//! sharing a database count it together.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
+
+use crate::{
+    ai::ChatSession,
+    config::{TenantConfig, TenantQuota},
+    services::{realtime_ws::StableRealtimeConfig, same_secret},
+    storage::{Storage, Store, TenantDay, Usage},
+};
+
+/// the usage of a tenant today, `GET /v1/tenants`
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TenantUsage {
+    pub tenant: String,
+    /// local date, `%Y-%m-%d`
+    pub date: String,
+    /// responses generated
+    pub turns: u64,
+    pub usage: Usage,
+    pub quota: TenantQuota,
+}
+
+#[derive(Debug, Default)]
+pub struct Tenants {
+    admin_token: Option<String>,
+    tenants: HashMap<String, TenantConfig>,
+    store: Option<Arc<Storage>>,
+    /// the usage of today without a store
+    usage: Mutex<HashMap<String, TenantDay>>,
+}
+
+impl TenantConfig {
+    /// the realtime config with the keys and the prompts of the tenant
+    pub fn apply(&self, config: &mut StableRealtimeConfig) {
+        if let Some(api_key) = &self.llm_api_key {
+            config.llm.api_key = Some(api_key.clone());
+        }
+        if let Some(api_key) = &self.tts_api_key {
+            config.tts = config.tts.with_api_key(api_key);
+        }
+        if let Some(api_key) = &self.asr_api_key {
+            config.asr.api_key = api_key.clone();
+        }
+        if !self.sys_prompts.is_empty() {
+            config.llm.sys_prompts = self.sys_prompts.clone();
+        }
+    }
+
+    /// the llm key and the prompts of the tenant, before those of the profile
+    pub fn apply_chat(&self, chat_session: &mut ChatSession) {
+        if let Some(api_key) = &self.llm_api_key {
+            chat_session.api_key = api_key.clone();
+        }
+        if !self.sys_prompts.is_empty() {
+            chat_session.system_prompts = self.sys_prompts.clone();
+        }
+    }
+}
+
+fn today() -> String {
+    chrono::Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// the id of a device of `tenant` that is not in the registry
+pub fn scoped_id(tenant: Option<&str>, id: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{tenant}:{id}"),
+        None => id.to_string(),
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+impl Tenants {
+    pub fn new(tenants: HashMap<String, TenantConfig>, admin_token: Option<String>) -> Self {
+        Self {
+            admin_token,
+            tenants,
+            store: None,
+            usage: Default::default(),
+        }
+    }
+
+    /// the usage is kept in `store`
+    pub fn with_store(mut self, store: Arc<Storage>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// the connections need the token of a tenant
+    pub fn enabled(&self) -> bool {
+        !self.tenants.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TenantConfig> {
+        self.tenants.get(name)
+    }
+
+    /// the tenant of a token
+    pub fn by_token(&self, token: &str) -> Option<&str> {
+        self.tenants
+            .iter()
+            .find(|(_, tenant)| !tenant.token.is_empty() && same_secret(&tenant.token, token))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// the tenant of the bearer token of a request
+    pub fn by_bearer(&self, headers: &HeaderMap) -> Option<&str> {
+        self.by_token(bearer(headers)?)
+    }
+
+    /// the tenant of a connection by its bearer token, `None` without tenants or with the
+    /// `admin_token`
+    pub async fn authorize(&self, headers: &HeaderMap) -> Result<Option<String>, StatusCode> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+        let token = bearer(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+        if self
+            .admin_token
+            .as_deref()
+            .is_some_and(|admin| same_secret(admin, token))
+        {
+            return Ok(None);
+        }
+        let name = self.by_token(token).ok_or(StatusCode::UNAUTHORIZED)?;
+        self.connect(Some(name)).await?;
+        Ok(Some(name.to_string()))
+    }
+
+    /// a connection of `tenant` is refused once it is over its quota
+    pub async fn connect(&self, tenant: Option<&str>) -> Result<(), StatusCode> {
+        let Some(tenant) = tenant else {
+            return Ok(());
+        };
+        self.check_quota(tenant).await.map_err(|e| {
+            log::warn!("{e}");
+            StatusCode::TOO_MANY_REQUESTS
+        })
+    }
+
+    /// add to the usage of today, written to the store in the background
+    fn add(&self, name: &str, turns: u64, usage: Usage) {
+        let day = TenantDay {
+            tenant: name.to_string(),
+            day: today(),
+            turns,
+            usage,
+        };
+        let Some(store) = self.store.clone() else {
+            let mut all = self.usage.lock().unwrap();
+            let today = all.entry(name.to_string()).or_default();
+            if today.day != day.day {
+                *today = TenantDay {
+                    tenant: day.tenant,
+                    day: day.day,
+                    ..Default::default()
+                };
+            }
+            today.turns += day.turns;
+            today.usage.add(&day.usage);
+            return;
+        };
+        tokio::spawn(async move {
+            if let Err(e) = store.add_tenant_usage(&day).await {
+                log::error!("tenant `{}` usage error: {e}", day.tenant);
+            }
+        });
+    }
+
+    pub fn add_turn(&self, name: &str) {
+        self.add(name, 1, Usage::default());
+    }
+
+    /// priced already, see [`crate::services::sessions::SessionManager::add_usage`]
+    pub fn add_usage(&self, name: &str, usage: &Usage) {
+        self.add(name, 0, usage.clone());
+    }
+
+    pub async fn usage(&self, name: &str) -> TenantUsage {
+        let date = today();
+        let day = match &self.store {
+            Some(store) => store.tenant_usage(name, &date).await.unwrap_or_else(|e| {
+                log::error!("tenant `{name}` usage error: {e}");
+                None
+            }),
+            None => self
+                .usage
+                .lock()
+                .unwrap()
+                .get(name)
+                .filter(|day| day.day == date)
+                .cloned(),
+        }
+        .unwrap_or_default();
+        TenantUsage {
+            tenant: name.to_string(),
+            date,
+            turns: day.turns,
+            usage: day.usage,
+            quota: self.get(name).map(|t| t.quota.clone()).unwrap_or_default(),
+        }
+    }
+
+    /// an error once `name` used up a daily quota
+    pub async fn check_quota(&self, name: &str) -> anyhow::Result<()> {
+        let Some(tenant) = self.get(name) else {
+            return Ok(());
+        };
+        let usage = self.usage(name).await;
+        if tenant
+            .quota
+            .daily_turns
+            .is_some_and(|max| usage.turns >= max)
+        {
+            anyhow::bail!("tenant `{name}` used up its {} turns of today", usage.turns);
+        }
+        if tenant
+            .quota
+            .daily_cost
+            .is_some_and(|max| usage.usage.cost >= max)
+        {
+            anyhow::bail!("tenant `{name}` used up its cost quota of today");
+        }
+        Ok(())
+    }
+}
+
+async fn list_tenants(
+    Extension(tenants): Extension<Arc<Tenants>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Some(name) = tenants.by_bearer(&headers) {
+        return Json(vec![tenants.usage(name).await]).into_response();
+    }
+    if let Err(code) = super::check_admin_token(&headers, &tenants.admin_token) {
+        return code.into_response();
+    }
+    let mut names = tenants.tenants.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut all = Vec::with_capacity(names.len());
+    for name in names {
+        all.push(tenants.usage(name).await);
+    }
+    Json(all).into_response()
+}
+
+pub fn new_tenants_service(tenants: Arc<Tenants>) -> Router {
+    Router::new()
+        .route("/v1/tenants", get(list_tenants))
+        .layer(Extension(tenants))
+}
+
+#[tokio::test]
+async fn test_tenants() {
+    let config: HashMap<String, TenantConfig> = toml::from_str(
+        r#"
+        [smith]
+        token = "t-smith"
+        llm_api_key = "sk-smith"
+
+        [smith.quota]
+        daily_turns = 2
+
+        [jones]
+        token = "t-jones"
+        "#,
+    )
+    .unwrap();
+    let tenants = Tenants::new(config, Some("admin".to_string()));
+
+    let mut headers = HeaderMap::new();
+    assert_eq!(
+        tenants.authorize(&headers).await,
+        Err(StatusCode::UNAUTHORIZED)
+    );
+    headers.insert(http::header::AUTHORIZATION, "Bearer admin".parse().unwrap());
+    assert_eq!(tenants.authorize(&headers).await, Ok(None));
+    headers.insert(
+        http::header::AUTHORIZATION,
+        "Bearer t-smith".parse().unwrap(),
+    );
+    assert_eq!(
+        tenants.authorize(&headers).await,
+        Ok(Some("smith".to_string()))
+    );
+
+    tenants.add_turn("smith");
+    tenants.add_turn("smith");
+    assert!(tenants.check_quota("smith").await.is_err());
+    assert_eq!(
+        tenants.authorize(&headers).await,
+        Err(StatusCode::TOO_MANY_REQUESTS)
+    );
+    assert!(tenants.check_quota("jones").await.is_ok());
+    assert_eq!(tenants.usage("smith").await.turns, 2);
+    assert!(!Tenants::default().enabled());
+    assert_eq!(scoped_id(Some("smith"), "dev1"), "smith:dev1");
+    assert_eq!(scoped_id(None, "dev1"), "dev1");
+
+    // the instances sharing a store add up
+    let dir = std::env::temp_dir().join(format!("echokit_tenants_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let store = Storage::File(crate::storage::file::FileStore::new(
+        &dir.to_string_lossy(),
+        None,
+    ));
+    let day = TenantDay {
+        tenant: "smith".to_string(),
+        day: today(),
+        turns: 1,
+        usage: Usage {
+            cost: 0.5,
+            ..Default::default()
+        },
+    };
+    store.add_tenant_usage(&day).await.unwrap();
+    store.add_tenant_usage(&day).await.unwrap();
+    let tenants = Tenants::default().with_store(Arc::new(store));
+    let usage = tenants.usage("smith").await;
+    assert_eq!(usage.turns, 2);
+    assert!((usage.usage.cost - 1.0).abs() < 1e-9);
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,410 @@
+//! Asynchronous transcription of long recordings with the asr of the config.
+//!
+//! bearer `admin_token`:
+//! - `POST /v1/transcriptions/jobs?language=zh` with a wav file as body -> `202 {"id", "status"}`
+//! - `GET /v1/transcriptions/jobs` all the jobs, newest first
+//! - `GET /v1/transcriptions/jobs/{id}` `{"id", "status", "chunks_done", "chunks_total", ...}`
+//! - `GET /v1/transcriptions/jobs/{id}/result` the transcript of a completed job
+//!
+//! the audio is cut at the pauses found by the vad into chunks of at most
+//! [`MAX_CHUNK_MS`], each chunk is sent to the asr server like a device utterance.
+//! jobs are kept in memory for [`JOB_TTL`] after they finish.
+//! an upload over 64 MB is refused with `413`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+
+use crate::{
+    ai::{Transcript, TranscriptSegment},
+    config::WhisperASRConfig,
+};
+
+/// largest upload of a job, the wav and its decoded pcm are held in memory
+/// until the job is done: about 30 minutes of 16 khz mono audio
+const MAX_JOB_UPLOAD: usize = 64 * 1024 * 1024;
+/// longest chunk sent to the asr server
+pub const MAX_CHUNK_MS: usize = 30_000;
+/// audio kept around the speech of a chunk
+const CHUNK_PADDING_MS: usize = 200;
+/// jobs transcribed at the same time, the others wait in the queue
+const MAX_RUNNING_JOBS: usize = 2;
+pub const JOB_TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+const SAMPLES_PER_MS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub status: JobStatus,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    pub duration_ms: u64,
+    pub chunks_done: usize,
+    pub chunks_total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Job {
+    info: JobInfo,
+    result: Option<Transcript>,
+    finished: Option<std::time::Instant>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct JobParams {
+    /// `asr.lang` if unset
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+pub struct TranscriptionJobs {
+    pub asr: Option<WhisperASRConfig>,
+    pub admin_token: Option<String>,
+    client: reqwest::Client,
+    jobs: Mutex<HashMap<String, Job>>,
+    running: tokio::sync::Semaphore,
+}
+
+impl TranscriptionJobs {
+    pub fn new(asr: Option<WhisperASRConfig>, admin_token: Option<String>) -> Self {
+        Self {
+            asr,
+            admin_token,
+            client: reqwest::Client::new(),
+            jobs: Mutex::new(HashMap::new()),
+            running: tokio::sync::Semaphore::new(MAX_RUNNING_JOBS),
+        }
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+        }
+    }
+
+    /// drop the jobs finished more than [`JOB_TTL`] ago
+    fn prune(&self) {
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|_, job| job.finished.is_none_or(|t| t.elapsed() < JOB_TTL));
+    }
+
+    fn finish(&self, id: &str, result: anyhow::Result<Transcript>) {
+        self.update(id, |job| {
+            job.finished = Some(std::time::Instant::now());
+            job.info.finished_at = Some(chrono::Local::now().to_rfc3339());
+            match result {
+                Ok(transcript) => {
+                    job.info.status = JobStatus::Completed;
+                    job.result = Some(transcript);
+                }
+                Err(e) => {
+                    log::warn!("transcription job {id} failed: {e}");
+                    job.info.status = JobStatus::Failed;
+                    job.info.error = Some(e.to_string());
+                }
+            }
+        });
+    }
+
+    async fn run(&self, asr: &WhisperASRConfig, id: &str, pcm: Bytes, language: Option<String>) {
+        let _permit = self.running.acquire().await;
+        self.update(id, |job| job.info.status = JobStatus::Running);
+        let result = self.transcribe(asr, id, pcm, language.as_deref()).await;
+        self.finish(id, result);
+    }
+
+    async fn transcribe(
+        &self,
+        asr: &WhisperASRConfig,
+        id: &str,
+        pcm: Bytes,
+        language: Option<&str>,
+    ) -> anyhow::Result<Transcript> {
+        let samples = pcm
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect::<Vec<i16>>();
+        let speech = self.speech_segments(asr, &pcm, &samples).await?;
+        let chunks = plan_chunks(
+            &speech,
+            samples.len(),
+            MAX_CHUNK_MS * SAMPLES_PER_MS,
+            CHUNK_PADDING_MS * SAMPLES_PER_MS,
+        );
+        self.update(id, |job| job.info.chunks_total = chunks.len());
+
+        let mut texts = vec![];
+        let mut segments = vec![];
+        let mut detected_language = None;
+        for (start, end) in chunks {
+            let wav = crate::util::pcm_to_wav(
+                &pcm[start * 2..end * 2],
+                crate::util::WavConfig {
+                    sample_rate: 16000,
+                    ..Default::default()
+                },
+            );
+            let transcript =
+                crate::ai::transcribe(&self.client, asr, wav, "audio.wav", language, true).await?;
+
+            let offset_ms = (start / SAMPLES_PER_MS) as u32;
+            if transcript.segments.is_empty() && !transcript.text.is_empty() {
+                segments.push(TranscriptSegment {
+                    start_ms: offset_ms,
+                    end_ms: (end / SAMPLES_PER_MS) as u32,
+                    text: transcript.text.clone(),
+                });
+            }
+            segments.extend(transcript.segments.into_iter().map(|s| TranscriptSegment {
+                start_ms: s.start_ms + offset_ms,
+                end_ms: s.end_ms + offset_ms,
+                text: s.text,
+            }));
+            if !transcript.text.is_empty() {
+                texts.push(transcript.text);
+            }
+            detected_language = detected_language.or(transcript.language);
+            self.update(id, |job| job.info.chunks_done += 1);
+        }
+
+        Ok(Transcript {
+            text: texts.join("\n"),
+            language: detected_language,
+            segments,
+        })
+    }
+
+    /// (start, end) samples of the speech, found by the energy vad if no vad is configured
+    async fn speech_segments(
+        &self,
+        asr: &WhisperASRConfig,
+        pcm: &Bytes,
+        samples: &[i16],
+    ) -> anyhow::Result<Vec<(usize, usize)>> {
+        let wav = crate::util::pcm_to_wav(
+            pcm,
+            crate::util::WavConfig {
+                sample_rate: 16000,
+                ..Default::default()
+            },
+        );
+        let params = crate::ai::vad::VadParams::default();
+        let timestamps = match crate::ai::vad::detect_speech(&self.client, asr, &params, wav).await
+        {
+            Some(vad) => vad?.timestamps,
+            None => crate::ai::energy_vad::speech_segments(samples, &asr.energy_vad),
+        };
+        Ok(timestamps
+            .iter()
+            .map(|t| {
+                let start = (t.start.max(0) as usize).min(samples.len());
+                (start, (t.end.max(0) as usize).clamp(start, samples.len()))
+            })
+            .filter(|(start, end)| end > start)
+            .collect())
+    }
+
+    fn submit(self: &Arc<Self>, pcm: Bytes, duration_ms: u64, language: Option<String>) -> JobInfo {
+        self.prune();
+        let info = JobInfo {
+            id: uuid::Uuid::new_v4().to_string(),
+            status: JobStatus::Queued,
+            created_at: chrono::Local::now().to_rfc3339(),
+            finished_at: None,
+            duration_ms,
+            chunks_done: 0,
+            chunks_total: 0,
+            error: None,
+        };
+        let job = Job {
+            info: info.clone(),
+            result: None,
+            finished: None,
+        };
+        self.jobs.lock().unwrap().insert(info.id.clone(), job);
+
+        let jobs = self.clone();
+        let id = info.id.clone();
+        tokio::spawn(async move {
+            if let Some(asr) = &jobs.asr {
+                jobs.run(asr, &id, pcm, language).await;
+            }
+        });
+        info
+    }
+}
+
+/// merge the speech segments into chunks of at most `max_len` samples, padded by `padding`.
+/// a segment longer than `max_len` is cut into several chunks
+fn plan_chunks(
+    speech: &[(usize, usize)],
+    total: usize,
+    max_len: usize,
+    padding: usize,
+) -> Vec<(usize, usize)> {
+    let mut chunks: Vec<(usize, usize)> = vec![];
+    for &(start, end) in speech {
+        let start = start.saturating_sub(padding);
+        let end = (end + padding).min(total);
+        if let Some(last) = chunks.last_mut() {
+            if end - last.0 <= max_len {
+                last.1 = last.1.max(end);
+                continue;
+            }
+        }
+        let mut start = chunks.last().map_or(start, |last| start.max(last.1));
+        while end - start > max_len {
+            chunks.push((start, start + max_len));
+            start += max_len;
+        }
+        if end > start {
+            chunks.push((start, end));
+        }
+    }
+    chunks
+}
+
+/// POST /v1/transcriptions/jobs
+async fn submit_job(
+    Extension(jobs): Extension<Arc<TranscriptionJobs>>,
+    headers: HeaderMap,
+    Query(params): Query<JobParams>,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &jobs.admin_token) {
+        return code.into_response();
+    }
+    if jobs.asr.is_none() {
+        return (StatusCode::NOT_IMPLEMENTED, "no whisper asr configured").into_response();
+    }
+    if !body.starts_with(b"RIFF") {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "expected a wav file").into_response();
+    }
+    let (pcm, duration) = match crate::util::wav_to_pcm16_blocking(body, 16000).await {
+        Ok(r) => r,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("invalid wav: {e}")).into_response(),
+    };
+
+    let language = params.language.filter(|l| !l.is_empty());
+    let info = jobs.submit(pcm, duration.as_millis() as u64, language);
+    log::info!("transcription job {} queued", info.id);
+    (StatusCode::ACCEPTED, Json(info)).into_response()
+}
+
+/// GET /v1/transcriptions/jobs
+async fn list_jobs(
+    Extension(jobs): Extension<Arc<TranscriptionJobs>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &jobs.admin_token) {
+        return code.into_response();
+    }
+    jobs.prune();
+    let mut list = jobs
+        .jobs
+        .lock()
+        .unwrap()
+        .values()
+        .map(|job| job.info.clone())
+        .collect::<Vec<_>>();
+    list.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Json(list).into_response()
+}
+
+/// GET /v1/transcriptions/jobs/{id}
+async fn get_job(
+    Extension(jobs): Extension<Arc<TranscriptionJobs>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &jobs.admin_token) {
+        return code.into_response();
+    }
+    match jobs.jobs.lock().unwrap().get(&id) {
+        Some(job) => Json(job.info.clone()).into_response(),
+        None => (StatusCode::NOT_FOUND, "job not found").into_response(),
+    }
+}
+
+/// GET /v1/transcriptions/jobs/{id}/result
+async fn get_job_result(
+    Extension(jobs): Extension<Arc<TranscriptionJobs>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &jobs.admin_token) {
+        return code.into_response();
+    }
+    match jobs.jobs.lock().unwrap().get(&id) {
+        None => (StatusCode::NOT_FOUND, "job not found").into_response(),
+        Some(Job {
+            result: Some(transcript),
+            ..
+        }) => Json(transcript.clone()).into_response(),
+        Some(job) if job.info.status == JobStatus::Failed => (
+            StatusCode::CONFLICT,
+            job.info.error.clone().unwrap_or_default(),
+        )
+            .into_response(),
+        Some(_) => (StatusCode::CONFLICT, "job not finished").into_response(),
+    }
+}
+
+pub fn new_transcription_jobs_service(jobs: TranscriptionJobs) -> Router {
+    Router::new()
+        .route(
+            "/v1/transcriptions/jobs",
+            post(submit_job)
+                .layer(DefaultBodyLimit::max(MAX_JOB_UPLOAD))
+                .get(list_jobs),
+        )
+        .route("/v1/transcriptions/jobs/{id}", get(get_job))
+        .route("/v1/transcriptions/jobs/{id}/result", get(get_job_result))
+        .layer(Extension(Arc::new(jobs)))
+}
+
+#[test]
+fn test_plan_chunks() {
+    // speech close together is merged, padded by 10 samples
+    let chunks = plan_chunks(&[(100, 200), (300, 400)], 1000, 500, 10);
+    assert_eq!(chunks, vec![(90, 410)]);
+
+    // a chunk never exceeds the max length
+    let chunks = plan_chunks(&[(0, 300), (400, 700)], 1000, 500, 0);
+    assert_eq!(chunks, vec![(0, 300), (400, 700)]);
+
+    // a long monologue is cut
+    let chunks = plan_chunks(&[(0, 1200), (1205, 1300)], 2000, 500, 10);
+    assert_eq!(chunks, vec![(0, 500), (500, 1000), (1000, 1310)]);
+
+    // the padding does not overlap the previous chunk
+    let chunks = plan_chunks(&[(0, 490), (495, 900)], 1000, 500, 10);
+    assert_eq!(chunks, vec![(0, 500), (500, 910)]);
+
+    assert!(plan_chunks(&[], 1000, 500, 10).is_empty());
+}
@@ -0,0 +1,164 @@
+//! Speech-to-speech translation, for the devices of a profile with a `translation`:
+//!
+//! ```toml
+//! [profiles.travel.translation]
+//! source_language = "Chinese"
+//! target_language = "English"
+//! voice = "en_speaker"
+//! ```
+//!
+//! on `/ws/{id}` each transcript is sent as `ASR`, translated by the llm of the config
+//! without the conversation, then sent as `Translation` with the source text and spoken
+//! in the voice of the target language. the turns are not answered and no tool is called.
+
+use crate::{
+    ai::{llm::Content, ChatSession, StableLLMResponseChunk},
+    config::{LLMConfig, TranslationConfig},
+    protocol::Translation,
+    services::ws::{Target, WsCommand, WsPool},
+};
+
+const DEFAULT_PROMPT: &str = "You are a translator. Translate what the user says from {source} \
+    to {target}. Reply with the translation only, without quotes, notes or explanations, \
+    and keep the tone of the speaker.";
+
+impl TranslationConfig {
+    /// the system prompt, the languages filled in
+    pub fn system_prompt(&self) -> String {
+        let source = self
+            .source_language
+            .as_deref()
+            .unwrap_or("the language spoken");
+        self.prompt
+            .as_deref()
+            .unwrap_or(DEFAULT_PROMPT)
+            .replace("{source}", source)
+            .replace("{target}", &self.target_language)
+    }
+}
+
+/// translates the transcripts of a device, one at a time
+pub struct Translator {
+    pub config: TranslationConfig,
+    chat_session: ChatSession,
+    reasoning_filter: crate::ai::reasoning::ReasoningFilter,
+}
+
+impl Translator {
+    pub fn new(llm: &LLMConfig, config: TranslationConfig) -> Self {
+        let mut chat_session = ChatSession::from_config(llm, Default::default());
+        // each transcript is translated on its own
+        chat_session.history = 1;
+        chat_session.system_prompts = vec![Content {
+            role: crate::ai::llm::Role::System,
+            message: config.system_prompt(),
+            tool_calls: None,
+            tool_call_id: None,
+            images: vec![],
+        }];
+        Self {
+            config,
+            chat_session,
+            reasoning_filter: llm.reasoning_filter(),
+        }
+    }
+
+    /// the translation of `text`, each one on its own
+    pub async fn translate(&mut self, text: &str) -> anyhow::Result<String> {
+        self.chat_session.messages.clear();
+        self.chat_session.add_user_message(text.to_string());
+        let mut resp = self.chat_session.complete().await?;
+        let mut translation = String::new();
+        loop {
+            match resp.next_chunk().await? {
+                StableLLMResponseChunk::Text(chunk) => {
+                    translation.push_str(&self.reasoning_filter.push(&chunk).text)
+                }
+                StableLLMResponseChunk::Functions(_) => continue,
+                StableLLMResponseChunk::Stop => break,
+            }
+        }
+        translation.push_str(&self.reasoning_filter.finish().text);
+        Ok(translation.trim().to_string())
+    }
+}
+
+/// a turn of a translation device: the transcript and its translation are sent, then
+/// the translation is spoken
+pub async fn translate_turn(
+    pool: &WsPool,
+    id: &str,
+    translator: &mut Translator,
+    text: String,
+) -> anyhow::Result<()> {
+    // an injected system message, nothing to translate
+    if text.is_empty() {
+        return Ok(());
+    }
+    pool.send(id, WsCommand::AsrResult(vec![text.clone()]))
+        .await?;
+    pool.webhooks.transcription_completed(id, &text);
+    pool.sessions.record(id, crate::ai::llm::Role::User, &text);
+    pool.sessions.add_turn(id);
+
+    let st = std::time::Instant::now();
+    let translation = translator.translate(&text).await?;
+    log::info!("`{id}` translation took: {:?}", st.elapsed());
+    if translation.is_empty() {
+        return Err(anyhow::anyhow!("empty translation of {text:?}"));
+    }
+    let event = Translation {
+        source: text,
+        text: translation.clone(),
+        language: translator.config.target_language.clone(),
+    };
+    pool.send(id, WsCommand::Translation(event)).await?;
+    pool.sessions
+        .record(id, crate::ai::llm::Role::Assistant, &translation);
+
+    let voice = match translator.config.voice.clone() {
+        Some(voice) => Some(voice),
+        None => pool.profile(id).await.and_then(|p| p.voice),
+    };
+    pool.send(id, WsCommand::StartAudio(translation.clone()))
+        .await?;
+    let r = super::ws::tts_and_send_with(
+        pool,
+        Target::One(id),
+        translation,
+        voice,
+        Default::default(),
+    )
+    .await;
+    pool.send(id, WsCommand::EndAudio).await?;
+    r
+}
+
+#[tokio::test]
+async fn test_translator() {
+    let llm = LLMConfig {
+        mock: Some(crate::config::MockLLMConfig {
+            replies: vec!["<think>hmm</think> Where is the station? ".to_string()],
+            chunk_chars: 4,
+            chunk_delay_ms: 0,
+            next: Default::default(),
+        }),
+        reasoning_filter: Some(toml::from_str("").unwrap()),
+        ..toml::from_str("history = 5").unwrap()
+    };
+    let config: TranslationConfig = toml::from_str(
+        r#"
+        source_language = "Chinese"
+        target_language = "English"
+        "#,
+    )
+    .unwrap();
+    assert!(config.system_prompt().contains("from Chinese to English"));
+
+    let mut translator = Translator::new(&llm, config);
+    let translation = translator.translate("车站在哪里？").await.unwrap();
+    assert_eq!(translation, "Where is the station?");
+    // the previous turns are not sent again
+    translator.translate("谢谢").await.unwrap();
+    assert_eq!(translator.chat_session.messages.len(), 1);
+}
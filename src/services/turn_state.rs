@@ -0,0 +1,119 @@
+//! Turn-taking of a realtime session: who has the floor, the user or the server.
+//!
+//! - `idle` -> `listening` on input audio or the start of speech
+//! - `listening` -> `transcribing` on the commit, -> `idle` on a clear
+//! - `transcribing` -> `thinking` on the response, -> `idle` if the audio is not answered
+//! - `thinking` -> `speaking` on the first text or audio
+//! - `thinking` / `speaking` -> `idle` at the end of the response, -> `listening` if the
+//!   user interrupts it
+//!
+//! the transitions are validated, an event that does not apply in the current state is
+//! ignored, so a second `response.create` while a response is generated is rejected
+//! instead of racing the first one. each change is sent as a `turn.state.changed` event.
+
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    ai::openai::realtime::{ServerEvent, TurnState},
+    util::new_uuid,
+};
+
+/// what happened to the turn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnEvent {
+    AudioAppended,
+    SpeechStarted,
+    BufferCleared,
+    AudioCommitted,
+    /// the committed audio is not answered: silence, no `create_response` or an error
+    Unanswered,
+    /// the turn looks unfinished, the user goes on
+    AwaitingTurnEnd,
+    ResponseStarted,
+    /// the first text or audio of the response
+    OutputStarted,
+    /// completed, cancelled or failed
+    ResponseDone,
+    /// the user spoke over the response
+    Interrupted,
+}
+
+/// the state after `event`, `None` if it does not apply to `state`
+pub fn next(state: TurnState, event: TurnEvent) -> Option<TurnState> {
+    use TurnEvent::*;
+    use TurnState::*;
+
+    let next = match (state, event) {
+        (Idle, AudioAppended | SpeechStarted) => Listening,
+        (Transcribing, SpeechStarted | AwaitingTurnEnd) => Listening,
+        (Listening, BufferCleared) => Idle,
+        (Idle | Listening, AudioCommitted) => Transcribing,
+        (Transcribing, Unanswered) => Idle,
+        (Idle | Listening | Transcribing, ResponseStarted) => Thinking,
+        (Thinking, OutputStarted) => Speaking,
+        (Thinking | Speaking, ResponseDone) => Idle,
+        (Thinking | Speaking, Interrupted) => Listening,
+        _ => return None,
+    };
+    Some(next)
+}
+
+/// the turn state of a session, shared with its response task
+#[derive(Debug, Clone, Default)]
+pub struct TurnStateMachine(Arc<Mutex<TurnState>>);
+
+impl TurnStateMachine {
+    pub fn state(&self) -> TurnState {
+        *self.0.lock().unwrap()
+    }
+
+    /// a response is being generated or spoken
+    pub fn is_responding(&self) -> bool {
+        matches!(self.state(), TurnState::Thinking | TurnState::Speaking)
+    }
+
+    /// apply `event`, `Err` with the current state if it does not apply, the
+    /// `turn.state.changed` event if the state changed
+    pub fn on(&self, event: TurnEvent) -> Result<Option<ServerEvent>, TurnState> {
+        let mut state = self.0.lock().unwrap();
+        let previous = *state;
+        let next = next(previous, event).ok_or(previous)?;
+        *state = next;
+        Ok((next != previous).then(|| ServerEvent::TurnStateChanged {
+            event_id: new_uuid().to_string(),
+            previous,
+            state: next,
+        }))
+    }
+}
+
+#[test]
+fn test_turn_state() {
+    use TurnEvent::*;
+
+    let turn = TurnStateMachine::default();
+    assert_eq!(turn.state(), TurnState::Idle);
+    assert!(turn.on(AudioAppended).unwrap().is_some());
+    // more audio of the same turn
+    assert!(turn.on(AudioAppended).is_err());
+    assert!(turn.on(AudioCommitted).unwrap().is_some());
+    assert!(turn.on(ResponseStarted).is_ok());
+    assert!(turn.is_responding());
+    // a second response.create
+    assert_eq!(turn.on(ResponseStarted).unwrap_err(), TurnState::Thinking);
+
+    // input while the response is generated does not take the floor
+    assert!(turn.on(AudioAppended).is_err());
+    assert!(turn.on(AudioCommitted).is_err());
+    turn.on(OutputStarted).unwrap();
+    assert_eq!(turn.state(), TurnState::Speaking);
+    turn.on(Interrupted).unwrap();
+    assert_eq!(turn.state(), TurnState::Listening);
+    // the interrupted response ends after the user took the floor
+    assert!(turn.on(ResponseDone).is_err());
+    assert_eq!(turn.state(), TurnState::Listening);
+
+    turn.on(AudioCommitted).unwrap();
+    turn.on(Unanswered).unwrap();
+    assert_eq!(turn.state(), TurnState::Idle);
+}
@@ -0,0 +1,187 @@
+//! Events of the sessions posted as json to the `[[webhooks]]` urls.
+//!
+//! `{"event": "transcription_completed", "session_id": "...", "time": "...", "data": {...}}`
+//! - `transcription_completed` `{"text"}`
+//! - `response_done` `{"text"}`
+//! - `error` `{"message"}`
+//! - `tool_invoked` `{"name", "arguments"}`
+//!
+//! the posts never block the session, failures are only logged.
+//! the last errors are also kept for the console, with or without hooks
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use aho_corasick::AhoCorasick;
+
+use crate::config::{WebhookConfig, WebhookEvent};
+
+/// errors kept by [`Webhooks::recent_errors`]
+const MAX_RECENT_ERRORS: usize = 50;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ErrorRecord {
+    pub time: String,
+    pub session_id: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+struct Hook {
+    config: WebhookConfig,
+    phrases: Option<AhoCorasick>,
+}
+
+#[derive(Debug, Default)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    hooks: Vec<Hook>,
+    recent_errors: Mutex<VecDeque<ErrorRecord>>,
+}
+
+impl Hook {
+    fn new(config: WebhookConfig) -> Self {
+        let phrases = config.phrases.iter().filter(|p| !p.is_empty());
+        let phrases = match AhoCorasick::builder()
+            .ascii_case_insensitive(true)
+            .build(phrases)
+        {
+            Ok(ac) if ac.patterns_len() > 0 => Some(ac),
+            Ok(_) => None,
+            Err(e) => {
+                log::warn!("webhook {} phrases error: {e}", config.url);
+                None
+            }
+        };
+        Self { config, phrases }
+    }
+
+    fn accepts(&self, event: WebhookEvent, data: &serde_json::Value) -> bool {
+        if !self.config.events.is_empty() && !self.config.events.contains(&event) {
+            return false;
+        }
+        match (&self.phrases, event) {
+            (Some(phrases), WebhookEvent::TranscriptionCompleted | WebhookEvent::ResponseDone) => {
+                data["text"]
+                    .as_str()
+                    .is_some_and(|text| phrases.is_match(text))
+            }
+            _ => true,
+        }
+    }
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<WebhookConfig>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            hooks: hooks.into_iter().map(Hook::new).collect(),
+            recent_errors: Default::default(),
+        }
+    }
+
+    /// post `data` to the hooks of `event` in the background
+    pub fn emit(&self, event: WebhookEvent, session_id: &str, data: serde_json::Value) {
+        let hooks = self
+            .hooks
+            .iter()
+            .filter(|hook| hook.accepts(event, &data))
+            .collect::<Vec<_>>();
+        if hooks.is_empty() {
+            return;
+        }
+
+        let payload = serde_json::json!({
+            "event": event,
+            "session_id": session_id,
+            "time": chrono::Local::now().to_rfc3339(),
+            "data": data,
+        });
+        for hook in hooks {
+            let mut builder = self
+                .client
+                .post(&hook.config.url)
+                .json(&payload)
+                .timeout(std::time::Duration::from_millis(hook.config.timeout_ms));
+            if let Some(token) = hook.config.token.as_ref().filter(|t| !t.is_empty()) {
+                builder = builder.bearer_auth(token);
+            }
+            let url = hook.config.url.clone();
+            tokio::spawn(async move {
+                match builder.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => log::debug!("webhook {url} {event:?} posted"),
+                    Err(e) => log::warn!("webhook {url} {event:?} error: {e}"),
+                }
+            });
+        }
+    }
+
+    pub fn transcription_completed(&self, session_id: &str, text: &str) {
+        let data = serde_json::json!({ "text": text });
+        self.emit(WebhookEvent::TranscriptionCompleted, session_id, data);
+    }
+
+    pub fn response_done(&self, session_id: &str, text: &str) {
+        let data = serde_json::json!({ "text": text });
+        self.emit(WebhookEvent::ResponseDone, session_id, data);
+    }
+
+    pub fn error(&self, session_id: &str, message: impl std::fmt::Display) {
+        let message = message.to_string();
+        {
+            let mut recent_errors = self.recent_errors.lock().unwrap();
+            if recent_errors.len() >= MAX_RECENT_ERRORS {
+                recent_errors.pop_front();
+            }
+            recent_errors.push_back(ErrorRecord {
+                time: chrono::Local::now().to_rfc3339(),
+                session_id: session_id.to_string(),
+                message: message.clone(),
+            });
+        }
+        let data = serde_json::json!({ "message": message });
+        self.emit(WebhookEvent::Error, session_id, data);
+    }
+
+    /// newest first
+    pub fn recent_errors(&self) -> Vec<ErrorRecord> {
+        let recent_errors = self.recent_errors.lock().unwrap();
+        recent_errors.iter().rev().cloned().collect()
+    }
+
+    pub fn tool_invoked(&self, session_id: &str, name: &str, arguments: &str) {
+        let data = serde_json::json!({ "name": name, "arguments": arguments });
+        self.emit(WebhookEvent::ToolInvoked, session_id, data);
+    }
+}
+
+#[test]
+fn test_webhook_filter() {
+    let config: WebhookConfig = toml::from_str(
+        r#"
+        url = "http://localhost:9000/hook"
+        events = ["transcription_completed", "tool_invoked"]
+        phrases = ["记一下", "Remind me"]
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.timeout_ms, 5000);
+    let hook = Hook::new(config);
+
+    let text = |text: &str| serde_json::json!({ "text": text });
+    assert!(hook.accepts(
+        WebhookEvent::TranscriptionCompleted,
+        &text("帮我记一下明天开会")
+    ));
+    assert!(hook.accepts(
+        WebhookEvent::TranscriptionCompleted,
+        &text("remind me at 8")
+    ));
+    assert!(!hook.accepts(
+        WebhookEvent::TranscriptionCompleted,
+        &text("今天天气怎么样")
+    ));
+    assert!(!hook.accepts(WebhookEvent::ResponseDone, &text("记一下")));
+    // the phrases only filter the text events
+    let tool = serde_json::json!({ "name": "search", "arguments": "{}" });
+    assert!(hook.accepts(WebhookEvent::ToolInvoked, &tool));
+}
@@ -4,7 +4,7 @@ use axum::{
     body::Bytes,
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Path,
+        Path, Query,
     },
     response::IntoResponse,
     Extension,
@@ -20,58 +20,523 @@ use crate::{
             self,
             types::{Blob, GenerationConfig, RealtimeAudio},
         },
-        llm::Content,
+        llm::estimate_tokens,
+        moderation::ModerationFilter,
         openai::tool::{McpToolAdapter, ToolSet},
+        speech_text::{CodeBlockFilter, SentenceBudget},
         ChatSession, StableLLMResponseChunk,
     },
-    config::AIConfig,
+    config::{
+        AIConfig, EarconsConfig, PhrasesConfig, ProfileConfig, Prosody, SpeechTextConfig,
+        StreamConfig,
+    },
+    protocol::{AlarmEvent, DeviceControl, MediaEvent, RoutineEvent, Translation},
+    services::{
+        alarms::{self, Alarms, SET_TIMER_TOOL},
+        briefing::Briefing,
+        cluster::{Cluster, ClusterEvent},
+        costs,
+        earcons::{Earcon, EarconAudio, Earcons},
+        history::AnnotationRequest,
+        knowledge::{Knowledge, REMEMBER_TOOL},
+        media::{self, Media},
+        offline::OfflineAnswers,
+        phrases::{PhraseAudio, Phrases},
+        registry::{DeviceRegistry, NoiseProfile, Telemetry},
+        reminders::{NewReminder, Reminders, REMIND_TOOL},
+        routines::Routines,
+        sessions::{InjectedMessage, SessionManager},
+        tenants::scoped_id,
+        translation::{translate_turn, Translator},
+        webhooks::Webhooks,
+    },
+    storage::{recordings::Recordings, Usage},
 };
 
-// 添加常量定义
-const STANDARD_ERROR_RESPONSE: &str = "抱歉，我没能理解您的回复。请您换种表达方式重新说一下";
-
+#[derive(Clone)]
 pub enum WsCommand {
     AsrResult(Vec<String>),
     Action {
         action: String,
     },
     /// 16k, 16bit le, single-channel audio data
-    Audio(Vec<u8>),
+    Audio(Bytes),
     StartAudio(String),
     EndAudio,
     Video(Vec<Vec<u8>>),
     EndResponse,
+    Control(DeviceControl),
+    /// follow-up window open, ms
+    StartListening(u32),
+    EndListening,
+    /// noise floor of the calibration, dBFS
+    Calibrated(f32),
+    /// version of the live prompts
+    PromptsUpdated(u64),
+    Translation(Translation),
+    Alarm(AlarmEvent),
+    Media(MediaEvent),
+    Routine(RoutineEvent),
 }
+
+pub const DEVICE_CONTROL_TOOL: &str = "device_control";
+
+fn device_control_tool() -> crate::ai::llm::Function {
+    crate::ai::llm::Function {
+        name: DEVICE_CONTROL_TOOL.to_string(),
+        description: "Control the speaker itself: playback volume, LED and microphone mute. \
+            Use `volume_change` for relative requests like \"turn yourself down\"."
+            .to_string(),
+        parameters: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "volume": {"type": "integer", "minimum": 0, "maximum": 100, "description": "absolute volume"},
+                "volume_change": {"type": "integer", "minimum": -100, "maximum": 100, "description": "relative volume change"},
+                "led": {"type": "string", "description": "LED state, e.g. on, off, breathing, or a color"},
+                "mute": {"type": "boolean", "description": "mute the microphone"}
+            }
+        }),
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct DeviceControlArgs {
+    #[serde(default)]
+    volume: Option<i64>,
+    #[serde(default)]
+    volume_change: Option<i64>,
+    #[serde(default)]
+    led: Option<String>,
+    #[serde(default)]
+    mute: Option<bool>,
+}
+
+/// run the built-in `device_control` tool call, return the tool result for the llm
+async fn call_device_control(pool: &WsPool, id: &str, arguments: &str) -> String {
+    let args: DeviceControlArgs = serde_json::from_str(arguments).unwrap_or_default();
+    let current = pool.device_control(id).await;
+
+    let volume = match (args.volume, args.volume_change, current.volume) {
+        (Some(v), _, _) => Some(v),
+        (None, Some(change), Some(current)) => Some(current as i64 + change),
+        (None, Some(_), None) => {
+            return serde_json::json!({
+                "status": "error",
+                "message": "the current volume is unknown, ask for an absolute volume"
+            })
+            .to_string()
+        }
+        (None, None, _) => None,
+    };
+    let control = DeviceControl {
+        volume: volume.map(|v| v.clamp(0, 100) as u8),
+        led: args.led,
+        mute: args.mute,
+    };
+
+    match pool.send(id, WsCommand::Control(control.clone())).await {
+        Ok(_) => serde_json::json!({"status": "ok", "applied": control}).to_string(),
+        Err(e) => serde_json::json!({"status": "error", "message": e.to_string()}).to_string(),
+    }
+}
+#[derive(Debug, Default, serde::Deserialize)]
+struct RememberArgs {
+    #[serde(default)]
+    text: String,
+}
+
+/// run the built-in `remember` tool call, return the tool result for the llm
+async fn call_remember(pool: &WsPool, id: &str, arguments: &str) -> String {
+    let args: RememberArgs = serde_json::from_str(arguments).unwrap_or_default();
+    let (Some(knowledge), false) = (&pool.knowledge, args.text.trim().is_empty()) else {
+        return serde_json::json!({"status": "error", "message": "nothing to remember"})
+            .to_string();
+    };
+    match knowledge.remember(id, &args.text).await {
+        Ok(memory) => serde_json::json!({"status": "ok", "id": memory.id}).to_string(),
+        Err(e) => serde_json::json!({"status": "error", "message": e.to_string()}).to_string(),
+    }
+}
+
+/// run the built-in `remind` tool call, return the tool result for the llm
+async fn call_remind(pool: &WsPool, id: &str, arguments: &str) -> String {
+    let Some(reminders) = &pool.reminders else {
+        return serde_json::json!({"status": "error", "message": "no reminders"}).to_string();
+    };
+    let new: NewReminder = serde_json::from_str(arguments).unwrap_or_default();
+    match reminders.add(id, new).await {
+        Ok(reminder) => {
+            serde_json::json!({"status": "ok", "id": reminder.id, "due_at": reminder.due_at})
+                .to_string()
+        }
+        Err(e) => serde_json::json!({"status": "error", "message": e.to_string()}).to_string(),
+    }
+}
+
+/// run the built-in `set_timer` tool call, return the tool result for the llm
+async fn call_set_timer(pool: &WsPool, id: &str, arguments: &str) -> String {
+    let Some(alarms) = &pool.alarms else {
+        return serde_json::json!({"status": "error", "message": "no timers"}).to_string();
+    };
+    let event = match alarms.set(id, arguments, chrono::Local::now()) {
+        Ok(event) => event,
+        Err(e) => {
+            return serde_json::json!({"status": "error", "message": e.to_string()}).to_string()
+        }
+    };
+    let result = serde_json::json!({
+        "status": "ok",
+        "id": event.id,
+        "kind": event.kind,
+        "remaining_sec": event.remaining_ms / 1000,
+    });
+    if let Err(e) = pool.send(id, WsCommand::Alarm(event)).await {
+        log::warn!("`{id}` alarm error: {e}");
+    }
+    result.to_string()
+}
+
+/// run a built-in tool call, `None` if `name` is not one
+async fn call_builtin(pool: &WsPool, id: &str, name: &str, arguments: &str) -> Option<String> {
+    let result = match name {
+        DEVICE_CONTROL_TOOL => call_device_control(pool, id, arguments).await,
+        REMEMBER_TOOL => call_remember(pool, id, arguments).await,
+        REMIND_TOOL => call_remind(pool, id, arguments).await,
+        SET_TIMER_TOOL => call_set_timer(pool, id, arguments).await,
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// run a tool call without the llm, a built-in one or one of the mcp servers, an error if
+/// the tool failed
+pub async fn call_tool(
+    pool: &WsPool,
+    id: &str,
+    name: &str,
+    arguments: &str,
+) -> anyhow::Result<String> {
+    use crate::ai::openai::tool::Tool;
+
+    let result = match call_builtin(pool, id, name, arguments).await {
+        Some(result) => result,
+        None => {
+            let tool = pool
+                .tool_set
+                .get_tool(name)
+                .ok_or_else(|| anyhow::anyhow!("no tool {name}"))?;
+            let args = serde_json::from_str(arguments).unwrap_or_default();
+            let result = tool.call(args).await?;
+            if result.is_error.is_some_and(|b| b) {
+                anyhow::bail!("tool {name} failed");
+            }
+            result
+                .content
+                .iter()
+                .filter_map(|content| Some(content.as_text()?.text.clone()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    };
+    // the built-in tools report their errors in the result
+    let status = serde_json::from_str::<serde_json::Value>(&result).unwrap_or_default();
+    if status["status"] == "error" {
+        anyhow::bail!("{}", status["message"].as_str().unwrap_or("tool error"));
+    }
+    Ok(result)
+}
+
 type WsTx = tokio::sync::mpsc::UnboundedSender<WsCommand>;
 type WsRx = tokio::sync::mpsc::UnboundedReceiver<WsCommand>;
 
 #[derive(Debug)]
 pub struct WsPool {
     pub config: AIConfig,
+    pub stream: StreamConfig,
+    pub speech_text: SpeechTextConfig,
     pub connections: tokio::sync::RwLock<HashMap<String, (u128, WsTx)>>,
     pub hello_wav: Option<Vec<u8>>,
     pub bg_gif: Option<Vec<u8>>,
     pub tool_set: ToolSet<McpToolAdapter>,
+    pub registry: Option<Arc<DeviceRegistry>>,
+    /// bearer token of the device admin api (announce), disabled if unset
+    pub admin_token: Option<String>,
+    /// broadcast groups of the config, the registry can add more members
+    pub groups: HashMap<String, Vec<String>>,
+    /// last control state acked by each device
+    pub device_controls: tokio::sync::RwLock<HashMap<String, DeviceControl>>,
+    pub offline: Option<Arc<OfflineAnswers>>,
+    /// calibrated noise profiles, persisted by the registry if there is one
+    pub noise_profiles: tokio::sync::RwLock<HashMap<String, NoiseProfile>>,
+    pub sessions: Arc<SessionManager>,
+    pub webhooks: Arc<Webhooks>,
+    /// the other instances, the devices connected to them are reached through it
+    pub cluster: Option<Arc<Cluster>>,
+    pub recordings: Arc<Recordings>,
+    /// the memories and documents recalled before each turn
+    pub knowledge: Option<Arc<Knowledge>>,
+    /// the reminders spoken when due, set by the `remind` tool
+    pub reminders: Option<Arc<Reminders>>,
+    /// the timers and alarms of each device, set by the `set_timer` tool
+    pub alarms: Option<Arc<Alarms>>,
+    /// the audio streams returned by the tools, played after the answer
+    pub media: Option<Arc<Media>>,
+    /// the daily briefing, spoken on its keywords
+    pub briefing: Option<Arc<Briefing>>,
+    /// the routines of the config, run on their keywords
+    pub routines: Option<Arc<Routines>>,
+    pub phrases: PhrasesConfig,
+    pub phrase_audio: Arc<PhraseAudio>,
+    pub earcons: EarconsConfig,
+    pub earcon_audio: Arc<EarconAudio>,
+}
+
+/// what a [`WsPool`] is built from, the stream sizes are checked by [`WsPool::new`]
+#[derive(Debug)]
+pub struct WsPoolConfig {
+    pub hello_wav: Option<Vec<u8>>,
+    pub bg_gif: Option<Vec<u8>>,
+    pub config: AIConfig,
+    pub stream: StreamConfig,
+    pub speech_text: SpeechTextConfig,
+    pub tool_set: ToolSet<McpToolAdapter>,
+    pub registry: Option<Arc<DeviceRegistry>>,
+    pub admin_token: Option<String>,
+    pub groups: HashMap<String, Vec<String>>,
+    pub offline: Option<Arc<OfflineAnswers>>,
+    pub sessions: Arc<SessionManager>,
+    pub webhooks: Arc<Webhooks>,
+    pub cluster: Option<Arc<Cluster>>,
+    pub recordings: Arc<Recordings>,
+    pub knowledge: Option<Arc<Knowledge>>,
+    pub reminders: Option<Arc<Reminders>>,
+    pub alarms: Option<Arc<Alarms>>,
+    pub media: Option<Arc<Media>>,
+    pub briefing: Option<Arc<Briefing>>,
+    pub routines: Option<Arc<Routines>>,
+    pub phrases: PhrasesConfig,
+    pub phrase_audio: Arc<PhraseAudio>,
+    pub earcons: EarconsConfig,
+    pub earcon_audio: Arc<EarconAudio>,
 }
 
 impl WsPool {
-    pub fn new(
-        hello_wav: Option<Vec<u8>>,
-        bg_gif: Option<Vec<u8>>,
-        config: AIConfig,
-        tool_set: ToolSet<McpToolAdapter>,
-    ) -> Self {
-        Self {
+    pub fn new(pool_config: WsPoolConfig) -> anyhow::Result<Self> {
+        pool_config.stream.validate()?;
+        let WsPoolConfig {
+            hello_wav,
+            bg_gif,
+            config,
+            stream,
+            speech_text,
+            tool_set,
+            registry,
+            admin_token,
+            groups,
+            offline,
+            sessions,
+            webhooks,
+            cluster,
+            recordings,
+            knowledge,
+            reminders,
+            alarms,
+            media,
+            briefing,
+            routines,
+            phrases,
+            phrase_audio,
+            earcons,
+            earcon_audio,
+        } = pool_config;
+        Ok(Self {
             config,
+            stream,
+            speech_text,
             connections: tokio::sync::RwLock::new(HashMap::new()),
             hello_wav,
             bg_gif,
             tool_set,
-        }
+            registry,
+            admin_token,
+            groups,
+            device_controls: tokio::sync::RwLock::new(HashMap::new()),
+            offline,
+            noise_profiles: tokio::sync::RwLock::new(HashMap::new()),
+            sessions,
+            webhooks,
+            cluster,
+            recordings,
+            knowledge,
+            reminders,
+            alarms,
+            media,
+            briefing,
+            routines,
+            phrases,
+            phrase_audio,
+            earcons,
+            earcon_audio,
+        })
+    }
+
+    /// play the announcements of the other instances on the devices connected here
+    pub fn follow_cluster(self: &Arc<Self>) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        let mut events = cluster.subscribe();
+        let pool = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("ws pool missed {n} cluster events");
+                        continue;
+                    }
+                    Err(_) => return,
+                };
+                let pool = pool.clone();
+                match event {
+                    ClusterEvent::Announce {
+                        device_id,
+                        text,
+                        prosody,
+                    } if pool.is_online(&device_id).await => {
+                        tokio::spawn(async move {
+                            if let Err(e) = announce(&pool, &device_id, text, prosody).await {
+                                log::error!("`{device_id}` cluster announce error: {e}");
+                            }
+                        });
+                    }
+                    ClusterEvent::Broadcast {
+                        members,
+                        text,
+                        ask,
+                        prosody,
+                    } => {
+                        let mut local = vec![];
+                        for id in members {
+                            if pool.is_online(&id).await {
+                                local.push(id);
+                            }
+                        }
+                        if local.is_empty() {
+                            continue;
+                        }
+                        tokio::spawn(async move {
+                            if let Err(e) = broadcast(&pool, &local, text, ask, prosody).await {
+                                log::error!("cluster broadcast error: {e}");
+                            }
+                        });
+                    }
+                    ClusterEvent::Control { device_id, control } => {
+                        let _ = pool.send(&device_id, WsCommand::Control(control)).await;
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    /// the profile bound to the device by the registry
+    pub async fn profile(&self, id: &str) -> Option<ProfileConfig> {
+        self.registry.as_ref()?.profile(id).await
+    }
+
+    /// the `[stream]` of the device, with the knobs of its profile
+    pub async fn stream(&self, id: &str) -> StreamConfig {
+        self.stream.with_profile(self.profile(id).await.as_ref())
+    }
+
+    /// the phrases of the device, in the locale of its profile
+    pub async fn phrases(&self, id: &str) -> Phrases {
+        Phrases::new(&self.phrases.with_profile(self.profile(id).await.as_ref()))
+    }
+
+    /// the earcons of the device, in its profile
+    pub async fn earcons(&self, id: &str) -> Earcons {
+        let earcons = self.earcons.with_profile(self.profile(id).await.as_ref());
+        self.earcon_audio.earcons(&earcons)
     }
 }
 
+/// devices an audio response is sent to
+#[derive(Debug, Clone, Copy)]
+pub enum Target<'a> {
+    One(&'a str),
+    /// all the online members, offline members are skipped
+    Group(&'a [String]),
+}
+
 impl WsPool {
+    pub async fn send_to(&self, target: Target<'_>, cmd: WsCommand) -> anyhow::Result<()> {
+        match target {
+            Target::One(id) => self.send(id, cmd).await,
+            Target::Group(ids) => {
+                let pool = self.connections.read().await;
+                let mut sent = 0;
+                for id in ids {
+                    if let Some((_, ws_tx)) = pool.get(id) {
+                        if ws_tx.send(cmd.clone()).is_ok() {
+                            sent += 1;
+                        }
+                    }
+                }
+                if sent == 0 {
+                    return Err(anyhow::anyhow!("no member of the group is connected"));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// members of a broadcast group, from the config and the registry
+    pub async fn group_members(&self, group: &str) -> Vec<String> {
+        let mut members = self.groups.get(group).cloned().unwrap_or_default();
+        if let Some(registry) = &self.registry {
+            for id in registry.devices_in_group(group).await {
+                if !members.contains(&id) {
+                    members.push(id);
+                }
+            }
+        }
+        members
+    }
+
+    pub async fn device_control(&self, id: &str) -> DeviceControl {
+        self.device_controls
+            .read()
+            .await
+            .get(id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn noise_profile(&self, id: &str) -> Option<NoiseProfile> {
+        if let Some(profile) = self.noise_profiles.read().await.get(id) {
+            return Some(profile.clone());
+        }
+        self.registry.as_ref()?.noise_profile(id).await
+    }
+
+    pub async fn set_noise_profile(&self, id: &str, profile: NoiseProfile) {
+        if let Some(registry) = &self.registry {
+            if let Err(e) = registry.set_noise_profile(id, profile.clone()).await {
+                log::error!("`{id}` save noise profile error: {e}");
+            }
+        }
+        self.noise_profiles
+            .write()
+            .await
+            .insert(id.to_string(), profile);
+    }
+
+    pub async fn is_online(&self, id: &str) -> bool {
+        self.connections.read().await.contains_key(id)
+    }
+
     pub async fn send(&self, id: &str, cmd: WsCommand) -> anyhow::Result<()> {
         let pool = self.connections.read().await;
         let ws_tx = pool
@@ -83,11 +548,46 @@ impl WsPool {
     }
 }
 
+#[derive(Debug, serde::Deserialize)]
+pub struct WsQuery {
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
 pub async fn ws_handler(
     Extension(pool): Extension<Arc<WsPool>>,
     ws: WebSocketUpgrade,
     Path(id): Path<String>,
+    Query(query): Query<WsQuery>,
+    headers: http::HeaderMap,
 ) -> impl IntoResponse {
+    let mut paired = None;
+    if let Some(registry) = &pool.registry {
+        match registry.authorize(&id, query.token.as_deref()).await {
+            Ok(device) => paired = device,
+            Err(e) => {
+                log::warn!("`{id}` rejected: {e}");
+                return (http::StatusCode::UNAUTHORIZED, e.to_string()).into_response();
+            }
+        }
+    }
+    // a paired device belongs to the tenant of its pairing code
+    // the other devices of a tenant get an id of the tenant
+    let tenants = pool.sessions.tenants();
+    let (tenant, id) = match paired {
+        Some(device) => match tenants.connect(device.tenant.as_deref()).await {
+            Ok(()) => (device.tenant, id),
+            Err(code) => return code.into_response(),
+        },
+        None => match tenants.authorize(&headers).await {
+            Ok(tenant) => {
+                let id = scoped_id(tenant.as_deref(), &id);
+                (tenant, id)
+            }
+            Err(code) => return code.into_response(),
+        },
+    };
+
     let request_id = uuid::Uuid::new_v4().as_u128();
     log::info!("{id}:{request_id:x} connected.");
 
@@ -102,18 +602,257 @@ pub async fn ws_handler(
     ws.on_upgrade(move |socket| async move {
         let id = id.clone();
         let pool = pool.clone();
-        if let Err(e) = handle_socket(socket, &id, rx, pool.clone()).await {
+        let session_handle = pool.sessions.open(id.clone(), "ws", Some(id.clone()));
+        pool.sessions.set_tenant(&id, tenant);
+        pool.sessions.set_config(&id, pool.profile(&id).await);
+        let inbox = session_handle.inbox();
+        if let Err(e) = handle_socket(socket, &id, rx, inbox, pool.clone()).await {
             log::error!("{id}:{request_id:x} error: {e}");
         };
         log::info!("{id}:{request_id:x} disconnected.");
         {
+            let device_controls = &pool.device_controls;
             let mut pool = pool.connections.write().await;
             let (uuid_, _) = pool.get(&id).unwrap();
             if request_id == *uuid_ {
                 pool.remove(&id);
+                // acked by this connection, a new one acks again
+                device_controls.write().await.remove(&id);
             }
         }
     })
+    .into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AnnounceRequest {
+    pub text: String,
+    /// over the `speed` / `pitch` of `[tts]`
+    #[serde(flatten)]
+    pub prosody: Prosody,
+}
+
+/// the 400 of a speed or a pitch out of range
+fn check_prosody(prosody: &Prosody) -> Result<(), axum::response::Response> {
+    match prosody.out_of_range() {
+        Some((name, value)) => Err((
+            http::StatusCode::BAD_REQUEST,
+            format!(
+                "{name} {value} is not between {} and {}",
+                Prosody::MIN,
+                Prosody::MAX
+            ),
+        )
+            .into_response()),
+        None => Ok(()),
+    }
+}
+
+/// speak `text` on a connected device with its voice, as an unsolicited response
+pub async fn announce(
+    pool: &WsPool,
+    id: &str,
+    text: String,
+    prosody: Prosody,
+) -> anyhow::Result<()> {
+    log::info!("`{id}` announce: {text:?}");
+    pool.send(id, WsCommand::StartAudio(text.clone())).await?;
+    let r = tts_and_send_to(pool, Target::One(id), text, prosody).await;
+    pool.send(id, WsCommand::EndAudio).await?;
+    pool.send(id, WsCommand::EndResponse).await?;
+    r
+}
+
+/// POST /devices/{id}/announce `{"text": "...", "speed": 1.2, "pitch": 0.9}`
+pub async fn announce_handler(
+    Extension(pool): Extension<Arc<WsPool>>,
+    Path(id): Path<String>,
+    headers: http::HeaderMap,
+    axum::Json(req): axum::Json<AnnounceRequest>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &pool.admin_token) {
+        return code.into_response();
+    }
+    if req.text.trim().is_empty() {
+        return (http::StatusCode::BAD_REQUEST, "empty text").into_response();
+    }
+    if let Err(resp) = check_prosody(&req.prosody) {
+        return resp;
+    }
+    if !pool.is_online(&id).await {
+        return match &pool.cluster {
+            Some(cluster) if cluster.is_online(&id).await => {
+                cluster.publish(ClusterEvent::Announce {
+                    device_id: id,
+                    text: req.text,
+                    prosody: req.prosody,
+                });
+                http::StatusCode::ACCEPTED.into_response()
+            }
+            _ => (http::StatusCode::NOT_FOUND, "device not connected").into_response(),
+        };
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = announce(&pool, &id, req.text, req.prosody).await {
+            log::error!("`{id}` announce error: {e}");
+        }
+    });
+
+    http::StatusCode::ACCEPTED.into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct BroadcastRequest {
+    pub text: String,
+    /// `text` is a prompt, broadcast the answer of the llm instead of `text`
+    #[serde(default)]
+    pub ask: bool,
+    #[serde(flatten)]
+    pub prosody: Prosody,
+}
+
+/// speak on all the members of a group at once: the audio is synthesized once and
+/// queued to every member together, so they play roughly in sync
+pub async fn broadcast(
+    pool: &WsPool,
+    members: &[String],
+    text: String,
+    ask: bool,
+    prosody: Prosody,
+) -> anyhow::Result<()> {
+    let target = Target::Group(members);
+    if !ask {
+        log::info!("broadcast to {members:?}: {text:?}");
+        pool.send_to(target, WsCommand::StartAudio(text.clone()))
+            .await?;
+        let r = tts_and_send_to(pool, target, text, prosody).await;
+        pool.send_to(target, WsCommand::EndAudio).await?;
+        pool.send_to(target, WsCommand::EndResponse).await?;
+        return r;
+    }
+
+    let AIConfig::Stable { llm, .. } = &pool.config else {
+        return Err(anyhow::anyhow!("broadcast ask requires the stable llm"));
+    };
+    let mut chat_session = ChatSession::from_config(llm, pool.tool_set.clone());
+    chat_session.system_prompts = llm.sys_prompts.clone();
+    pool.sessions.prompts().start(&mut chat_session);
+    chat_session.add_user_message(text);
+
+    let mut resp = chat_session.complete().await?;
+    loop {
+        match resp.next_chunk().await? {
+            StableLLMResponseChunk::Text(chunk) => {
+                if chunk.trim().is_empty() {
+                    continue;
+                }
+                pool.send_to(target, WsCommand::StartAudio(chunk.clone()))
+                    .await?;
+                if let Err(e) = tts_and_send_to(pool, target, chunk, prosody).await {
+                    log::error!("broadcast tts error: {e}");
+                }
+                pool.send_to(target, WsCommand::EndAudio).await?;
+            }
+            // the mcp tools of the server, the built-in ones need a device
+            StableLLMResponseChunk::Functions(functions) => {
+                chat_session.add_assistant_tool_call(functions.clone());
+                for function in functions {
+                    chat_session.execute_tool(&function).await?
+                }
+                resp = chat_session.complete().await?;
+            }
+            StableLLMResponseChunk::Stop => break,
+        }
+    }
+    pool.send_to(target, WsCommand::EndResponse).await?;
+    Ok(())
+}
+
+/// POST /groups/{group}/announce `{"text": "...", "ask": false, "speed": 1.2}`
+pub async fn broadcast_handler(
+    Extension(pool): Extension<Arc<WsPool>>,
+    Path(group): Path<String>,
+    headers: http::HeaderMap,
+    axum::Json(req): axum::Json<BroadcastRequest>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &pool.admin_token) {
+        return code.into_response();
+    }
+    if req.text.trim().is_empty() {
+        return (http::StatusCode::BAD_REQUEST, "empty text").into_response();
+    }
+    if let Err(resp) = check_prosody(&req.prosody) {
+        return resp;
+    }
+    let members = pool.group_members(&group).await;
+    if members.is_empty() {
+        return (http::StatusCode::NOT_FOUND, "group not found").into_response();
+    }
+    let mut members = members;
+    if let Some(cluster) = &pool.cluster {
+        cluster.publish(ClusterEvent::Broadcast {
+            members: members.clone(),
+            text: req.text.clone(),
+            ask: req.ask,
+            prosody: req.prosody,
+        });
+        // the other members are played by their instances
+        let connections = pool.connections.read().await;
+        members.retain(|id| connections.contains_key(id));
+        if members.is_empty() {
+            return http::StatusCode::ACCEPTED.into_response();
+        }
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = broadcast(&pool, &members, req.text, req.ask, req.prosody).await {
+            log::error!("broadcast to `{group}` error: {e}");
+        }
+    });
+
+    http::StatusCode::ACCEPTED.into_response()
+}
+
+/// POST /devices/{id}/control `{"volume": 30, "led": "off", "mute": false}`
+pub async fn control_handler(
+    Extension(pool): Extension<Arc<WsPool>>,
+    Path(id): Path<String>,
+    headers: http::HeaderMap,
+    axum::Json(control): axum::Json<DeviceControl>,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &pool.admin_token) {
+        return code.into_response();
+    }
+    if control.volume.is_some_and(|volume| volume > 100) {
+        let message = "volume must be between 0 and 100";
+        return (http::StatusCode::BAD_REQUEST, message).into_response();
+    }
+    match pool.send(&id, WsCommand::Control(control.clone())).await {
+        Ok(_) => http::StatusCode::ACCEPTED.into_response(),
+        Err(e) => match &pool.cluster {
+            Some(cluster) if cluster.is_online(&id).await => {
+                cluster.publish(ClusterEvent::Control {
+                    device_id: id,
+                    control,
+                });
+                http::StatusCode::ACCEPTED.into_response()
+            }
+            _ => (http::StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        },
+    }
+}
+
+/// GET /devices/{id}/control, the last state acked by the device
+pub async fn get_control_handler(
+    Extension(pool): Extension<Arc<WsPool>>,
+    Path(id): Path<String>,
+    headers: http::HeaderMap,
+) -> impl IntoResponse {
+    if let Err(code) = super::check_admin_token(&headers, &pool.admin_token) {
+        return code.into_response();
+    }
+    axum::Json(pool.device_control(&id).await).into_response()
 }
 
 enum WsEvent {
@@ -128,10 +867,10 @@ async fn retry_asr(
     model: &str,
     lang: &str,
     prompt: &str,
-    wav_audio: Vec<u8>,
+    wav_audio: Bytes,
     retry: usize,
     timeout: std::time::Duration,
-) -> Vec<String> {
+) -> anyhow::Result<Vec<String>> {
     for i in 0..retry {
         let r = tokio::time::timeout(
             timeout,
@@ -139,7 +878,7 @@ async fn retry_asr(
         )
         .await;
         match r {
-            Ok(Ok(v)) => return v,
+            Ok(Ok(v)) => return Ok(v),
             Ok(Err(e)) => {
                 log::error!("asr error: {e}");
                 continue;
@@ -150,7 +889,7 @@ async fn retry_asr(
             }
         }
     }
-    vec![]
+    Err(anyhow::anyhow!("asr unreachable after {retry} retries"))
 }
 
 fn resample(audio_samples: &[i16], in_hz: u32, out_hz: u32) -> anyhow::Result<Audio<Samp16, 1>> {
@@ -160,18 +899,38 @@ fn resample(audio_samples: &[i16], in_hz: u32, out_hz: u32) -> anyhow::Result<Au
     Ok(audio)
 }
 
-async fn retry_tts(
+/// resample 16bit le pcm from `in_hz` to `out_hz`
+pub(crate) fn resample_pcm16(audio_data: &[u8], in_hz: u32, out_hz: u32) -> anyhow::Result<Bytes> {
+    if audio_data.len() % 2 != 0 {
+        log::warn!("Received audio chunk with odd length, skipping");
+    }
+    let sample = audio_data
+        .chunks_exact(2)
+        .map(|i| i16::from_le_bytes([i[0], i[1]]))
+        .collect::<Vec<_>>();
+
+    let mut audio = resample(&sample, in_hz, out_hz)?;
+    let samples = audio.as_i16_slice();
+    let mut buff = bytes::BytesMut::with_capacity(samples.len() * 2);
+    for i in samples.iter() {
+        buff.put_i16_le(*i);
+    }
+    Ok(buff.freeze())
+}
+
+pub(crate) async fn retry_tts(
     url: &str,
     speaker: &str,
     text: &str,
     sample_rate: Option<usize>,
+    speed: Option<f32>,
     retry: usize,
     timeout: std::time::Duration,
 ) -> anyhow::Result<Bytes> {
     for i in 0..retry {
         let r = tokio::time::timeout(
             timeout,
-            crate::ai::tts::gsv(url, speaker, text, sample_rate),
+            crate::ai::tts::gsv(url, speaker, text, sample_rate, speed),
         )
         .await;
         match r {
@@ -190,44 +949,22 @@ async fn retry_tts(
 
 async fn send_wav(
     pool: &WsPool,
-    id: &str,
+    target: Target<'_>,
+    stream: &StreamConfig,
     text: String,
     wav_data: Bytes,
+    tts: &crate::config::TTSConfig,
 ) -> anyhow::Result<std::time::Duration> {
-    let mut reader = wav_io::reader::Reader::from_vec(wav_data.into())
-        .map_err(|e| anyhow::anyhow!("wav_io reader error: {e}"))?;
-
-    let header = reader.read_header()?;
-    let mut samples = reader.get_samples_f32()?;
-    let duration_sec = samples.len() as f32 / (header.sample_rate as f32 * header.channels as f32);
-    let duration_sec = std::time::Duration::from_secs_f32(duration_sec);
-
-    let out_hz = 16000;
-
-    if header.sample_rate != out_hz {
-        // resample to 16000
-        log::info!("resampling from {} to 16000", header.sample_rate);
-        samples = wav_io::resample::linear(samples, header.channels, header.sample_rate, out_hz);
-    }
-    let audio_16k = wav_io::convert_samples_f32_to_i16(&samples);
+    let out_hz = stream.output_sample_rate;
+    let (audio, duration_sec) = crate::ai::tts::to_pcm16(tts, wav_data, out_hz).await?;
 
     log::info!("llm chunk:{:?}", text);
 
-    for chunk in audio_16k.chunks(5 * out_hz as usize / 10) {
-        let buff = if cfg!(target_endian = "big") {
-            let mut buff = Vec::with_capacity(chunk.len() * 2);
-            for i in chunk {
-                buff.extend_from_slice(&i.to_le_bytes());
-            }
-            buff
-        } else {
-            let chunk_bytes =
-                unsafe { std::slice::from_raw_parts(chunk.as_ptr() as *const u8, chunk.len() * 2) };
-            chunk_bytes.to_vec()
-        };
-
-        // std::mem::swap(&mut send_data, &mut buff);
-        pool.send(id, WsCommand::Audio(buff)).await?;
+    let chunk_size = stream.audio_chunk_bytes();
+    for i in (0..audio.len()).step_by(chunk_size) {
+        let end = (i + chunk_size).min(audio.len());
+        pool.send_to(target, WsCommand::Audio(audio.slice(i..end)))
+            .await?;
     }
 
     Ok(duration_sec)
@@ -235,16 +972,16 @@ async fn send_wav(
 
 async fn send_stream_chunk(
     pool: &WsPool,
-    id: &str,
+    target: Target<'_>,
+    stream_config: &StreamConfig,
     text: String,
     resp: reqwest::Response,
 ) -> anyhow::Result<()> {
     log::info!("llm chunk:{:?}", text);
 
-    let in_hz = 16000;
     let mut stream = resp.bytes_stream();
     let mut rest = bytes::BytesMut::new();
-    let read_chunk_size = 2 * 5 * in_hz as usize / 10; // 0.5 seconds of audio at 32kHz
+    let read_chunk_size = stream_config.audio_chunk_bytes();
 
     'next_chunk: while let Some(item) = stream.next().await {
         // 小端字节序
@@ -258,12 +995,11 @@ async fn send_stream_chunk(
                 let n = read_chunk_size - rest.len();
                 rest.put(chunk.slice(..n));
                 debug_assert_eq!(rest.len(), read_chunk_size);
-                let audio_16k = rest.to_vec();
+                let audio_16k = rest.split().freeze();
                 log::trace!("Sending audio chunk of size: {}", audio_16k.len());
-                pool.send(id, WsCommand::Audio(audio_16k))
+                pool.send_to(target, WsCommand::Audio(audio_16k))
                     .await
                     .map_err(|e| anyhow::anyhow!("send audio error: {e}"))?;
-                rest.clear();
                 chunk = chunk.slice(n..);
             } else {
                 rest.extend_from_slice(&chunk);
@@ -271,24 +1007,23 @@ async fn send_stream_chunk(
             }
         }
 
-        for samples_16k_data in chunk.chunks(read_chunk_size) {
-            if samples_16k_data.len() < read_chunk_size {
-                log::trace!("Received audio chunk with odd length, skipping");
-                rest.extend_from_slice(&samples_16k_data);
-                continue 'next_chunk;
-            }
-            let audio_16k = samples_16k_data.to_vec();
+        while chunk.len() >= read_chunk_size {
+            let audio_16k = chunk.split_to(read_chunk_size);
             log::trace!("Sending audio chunk of size: {}", audio_16k.len());
-            pool.send(id, WsCommand::Audio(audio_16k))
+            pool.send_to(target, WsCommand::Audio(audio_16k))
                 .await
                 .map_err(|e| anyhow::anyhow!("send audio error: {e}"))?;
         }
+        if !chunk.is_empty() {
+            log::trace!("Received audio chunk with odd length, skipping");
+            rest.extend_from_slice(&chunk);
+        }
     }
 
     if rest.len() > 0 {
-        let audio_16k = rest.to_vec();
+        let audio_16k = rest.freeze();
         log::trace!("Sending audio chunk of size: {}", audio_16k.len());
-        pool.send(id, WsCommand::Audio(audio_16k))
+        pool.send_to(target, WsCommand::Audio(audio_16k))
             .await
             .map_err(|e| anyhow::anyhow!("send audio error: {e}"))?;
     }
@@ -296,7 +1031,39 @@ async fn send_stream_chunk(
     Ok(())
 }
 
-async fn tts_and_send(pool: &WsPool, id: &str, text: String) -> anyhow::Result<()> {
+/// the tenant of the session of `id`, see [`crate::services::tenants`]
+fn tenant_of<'a>(pool: &'a WsPool, id: &str) -> Option<&'a crate::config::TenantConfig> {
+    let name = pool.sessions.tenant(id)?;
+    pool.sessions.tenants().get(&name)
+}
+
+pub async fn tts_and_send(pool: &WsPool, id: &str, text: String) -> anyhow::Result<()> {
+    tts_and_send_to(pool, Target::One(id), text, Prosody::default()).await
+}
+
+/// `prosody` over the one of the tts config
+async fn tts_and_send_to(
+    pool: &WsPool,
+    target: Target<'_>,
+    text: String,
+    prosody: Prosody,
+) -> anyhow::Result<()> {
+    // a group is spoken with the default voice
+    let voice = match target {
+        Target::One(id) => pool.profile(id).await.and_then(|p| p.voice),
+        Target::Group(_) => None,
+    };
+    tts_and_send_with(pool, target, text, voice, prosody).await
+}
+
+/// `voice` over the one of the tts config, unless the speech text config picks one
+pub async fn tts_and_send_with(
+    pool: &WsPool,
+    target: Target<'_>,
+    text: String,
+    voice: Option<String>,
+    prosody: Prosody,
+) -> anyhow::Result<()> {
     let tts_config = match &pool.config {
         AIConfig::Stable { tts, .. } => tts,
         AIConfig::GeminiAndTTS { tts, .. } => tts,
@@ -305,72 +1072,233 @@ async fn tts_and_send(pool: &WsPool, id: &str, text: String) -> anyhow::Result<(
         }
     };
 
-    match tts_config {
+    let tenant_key = match &target {
+        Target::One(id) => tenant_of(pool, id).and_then(|t| t.tts_api_key.as_deref()),
+        Target::Group(_) => None,
+    };
+    let tts_config = match tenant_key {
+        Some(api_key) => std::borrow::Cow::Owned(tts_config.with_api_key(api_key)),
+        None => std::borrow::Cow::Borrowed(tts_config),
+    };
+    let voice = pool.speech_text.voice_of(&text).cloned().or(voice);
+    let tts_config = match voice {
+        Some(voice) => std::borrow::Cow::Owned(tts_config.with_voice(&voice)),
+        None => tts_config,
+    };
+    let tts_config = if prosody == Prosody::default() {
+        tts_config
+    } else {
+        std::borrow::Cow::Owned(tts_config.with_prosody(prosody))
+    };
+
+    // a group is sent with the `[stream]` of the config
+    let stream = match target {
+        Target::One(id) => pool.stream(id).await,
+        Target::Group(_) => pool.stream.clone(),
+    };
+    let out_hz = stream.output_sample_rate;
+
+    let spoken = crate::ai::speech_text::normalize(&text, &pool.speech_text);
+    if spoken.trim().is_empty() {
+        return Ok(());
+    }
+    if let Target::One(id) = &target {
+        let usage = Usage {
+            tts_chars: spoken.chars().count() as u64,
+            ..Default::default()
+        };
+        pool.sessions.add_usage(id, usage);
+    }
+
+    #[cfg(feature = "chaos")]
+    crate::ai::chaos::inject("tts").await?;
+
+    match tts_config.as_ref() {
         crate::config::TTSConfig::Stable(tts) => {
             let timeout_sec = tts.timeout_sec.unwrap_or(15);
             let wav_data = retry_tts(
                 &tts.url,
                 &tts.speaker,
-                &text,
-                Some(16000),
+                &spoken,
+                Some(tts.sample_rate.unwrap_or(out_hz as usize)),
+                tts.prosody.speed,
                 3,
                 std::time::Duration::from_secs(timeout_sec),
             )
             .await?;
-            let duration_sec = send_wav(pool, id, text, wav_data).await?;
+            let duration_sec = send_wav(pool, target, &stream, text, wav_data, &tts_config).await?;
             log::info!("Stable TTS duration: {:?}", duration_sec);
             Ok(())
         }
         crate::config::TTSConfig::Fish(fish) => {
-            let wav_data = crate::ai::tts::fish_tts(&fish.api_key, &fish.speaker, &text).await?;
-            let duration_sec = send_wav(pool, id, text, wav_data).await?;
+            let wav_data =
+                crate::ai::tts::fish_tts(&fish.api_key, &fish.speaker, &spoken, fish.prosody.speed)
+                    .await?;
+            let duration_sec = send_wav(pool, target, &stream, text, wav_data, &tts_config).await?;
             log::info!("Fish TTS duration: {:?}", duration_sec);
             Ok(())
         }
         crate::config::TTSConfig::Groq(groq) => {
-            let wav_data =
-                crate::ai::tts::groq(&groq.model, &groq.api_key, &groq.voice, &text).await?;
-            let duration_sec = send_wav(pool, id, text, wav_data).await?;
+            let wav_data = crate::ai::tts::groq(
+                &groq.model,
+                &groq.api_key,
+                &groq.voice,
+                &spoken,
+                groq.prosody.speed,
+            )
+            .await?;
+            let duration_sec = send_wav(pool, target, &stream, text, wav_data, &tts_config).await?;
             log::info!("Fish TTS duration: {:?}", duration_sec);
             Ok(())
         }
+        crate::config::TTSConfig::Mock(mock) => {
+            let wav_data = crate::ai::mock::tts(mock, &spoken, out_hz);
+            let duration_sec = send_wav(pool, target, &stream, text, wav_data, &tts_config).await?;
+            log::info!("Mock TTS duration: {:?}", duration_sec);
+            Ok(())
+        }
         crate::config::TTSConfig::StreamGSV(stream_tts) => {
             let resp = crate::ai::tts::stream_gsv(
                 &stream_tts.url,
                 &stream_tts.speaker,
-                &text,
-                Some(16000),
+                &spoken,
+                Some(out_hz as usize),
+                stream_tts.prosody.speed,
             )
             .await?;
 
-            send_stream_chunk(pool, id, text, resp).await?;
+            send_stream_chunk(pool, target, &stream, text, resp).await?;
             log::info!("Stream GSV TTS sent");
             Ok(())
         }
+        crate::config::TTSConfig::CosyVoice(cosyvoice) => {
+            let mut tts =
+                crate::ai::bailian::cosyvoice::CosyVoiceTTS::connect(cosyvoice.token.clone())
+                    .await?;
+            tts.start_synthesis(
+                cosyvoice.version,
+                cosyvoice.speaker.as_deref(),
+                Some(out_hz),
+                cosyvoice.prosody,
+                &spoken,
+            )
+            .await?;
+            while let Some(chunk) = tts.next_audio_chunk().await? {
+                pool.send_to(target, WsCommand::Audio(chunk)).await?;
+            }
+            log::info!("CosyVoice TTS sent");
+            Ok(())
+        }
+    }
+}
+
+/// return: (wav_data,is_recording)
+async fn recv_audio_to_wav(
+    audio: &mut tokio::sync::mpsc::Receiver<AudioChunk>,
+) -> anyhow::Result<(Bytes, bool)> {
+    let head = wav_io::new_header(16000, 16, false, true);
+    let mut samples = Vec::new();
+    let mut is_recording = false;
+
+    while let Some(chunk) = audio.recv().await {
+        match chunk {
+            AudioChunk::Chunk(data) => {
+                if data.len() % 2 != 0 {
+                    log::warn!("Received audio chunk with odd length, skipping");
+                    for i in data[0..data.len() - 1].chunks_exact(2) {
+                        let sample = i16::from_le_bytes([i[0], i[1]]);
+                        samples.push(sample as f32 / std::i16::MAX as f32);
+                    }
+                } else {
+                    for i in data.chunks_exact(2) {
+                        let sample = i16::from_le_bytes([i[0], i[1]]);
+                        samples.push(sample as f32 / std::i16::MAX as f32);
+                    }
+                }
+            }
+            AudioChunk::Enb => {
+                log::info!("end audio");
+                break;
+            }
+            AudioChunk::Recording => {
+                is_recording = true;
+                break;
+            }
+        }
+    }
+
+    if samples.is_empty() {
+        return Err(anyhow::anyhow!("no audio received"));
+    }
+
+    let wav_audio = wav_io::write_to_bytes(&head, &samples)?;
+
+    Ok((Bytes::from(wav_audio), is_recording))
+}
+
+/// drop audio until the wake word is detected, then collect the speech after it
+/// until the device ends it or `max_speech_ms` is reached.
+/// return: (wav_data,is_recording)
+async fn recv_audio_after_wake_word(
+    pool: &WsPool,
+    client: &reqwest::Client,
+    id: &str,
+    wake_word: &crate::config::WakeWordConfig,
+    audio: &mut tokio::sync::mpsc::Receiver<AudioChunk>,
+) -> anyhow::Result<(Bytes, bool)> {
+    let (mut ww_tx, mut ww_rx) =
+        crate::ai::wakeword::wake_word_client(client, &wake_word.url).await?;
+    let mut pre_roll = crate::util::PreRoll::new(SAMPLE_RATE, wake_word.pre_roll_ms);
+
+    loop {
+        tokio::select! {
+            chunk = audio.recv() => {
+                match chunk {
+                    Some(AudioChunk::Chunk(data)) => {
+                        pre_roll.push(&data);
+                        ww_tx.push_audio_16k_chunk(data).await?;
+                    }
+                    // speech ended before any wake word
+                    Some(AudioChunk::Enb) | Some(AudioChunk::Recording) => {}
+                    None => return Err(anyhow::anyhow!("audio channel closed")),
+                }
+            }
+            keyword = ww_rx.wait_wake_word(&wake_word.keywords, wake_word.threshold) => {
+                log::info!("`{id}` wake word `{}` detected", keyword?);
+                send_earcon(pool, id, Earcon::Wake).await?;
+                break;
+            }
+        }
     }
+
+    recv_speech(id, wake_word, audio, pre_roll.take(), None).await
 }
 
+/// collect the speech until the device ends it, `vad` detects its end
+/// or `max_speech_ms` is reached.
 /// return: (wav_data,is_recording)
-async fn recv_audio_to_wav(
+async fn recv_speech(
+    id: &str,
+    wake_word: &crate::config::WakeWordConfig,
     audio: &mut tokio::sync::mpsc::Receiver<AudioChunk>,
-) -> anyhow::Result<(Vec<u8>, bool)> {
-    let head = wav_io::new_header(16000, 16, false, true);
-    let mut samples = Vec::new();
+    mut pcm: bytes::BytesMut,
+    mut vad: Option<crate::ai::energy_vad::EnergyVad>,
+) -> anyhow::Result<(Bytes, bool)> {
+    let max_len = 2 * SAMPLE_RATE as usize * wake_word.max_speech_ms as usize / 1000;
     let mut is_recording = false;
 
     while let Some(chunk) = audio.recv().await {
         match chunk {
             AudioChunk::Chunk(data) => {
-                if data.len() % 2 != 0 {
-                    log::warn!("Received audio chunk with odd length, skipping");
-                    for i in data[0..data.len() - 1].chunks_exact(2) {
-                        let sample = i16::from_le_bytes([i[0], i[1]]);
-                        samples.push(sample as f32 / std::i16::MAX as f32);
-                    }
-                } else {
-                    for i in data.chunks_exact(2) {
-                        let sample = i16::from_le_bytes([i[0], i[1]]);
-                        samples.push(sample as f32 / std::i16::MAX as f32);
+                pcm.extend_from_slice(&data);
+                if pcm.len() >= max_len {
+                    log::info!("`{id}` max speech duration reached");
+                    break;
+                }
+                if let Some(vad) = &mut vad {
+                    let events = vad.push_audio_16k_chunk(&data);
+                    if events.contains(&crate::ai::vad::VAD_SPEECH_END) {
+                        break;
                     }
                 }
             }
@@ -385,29 +1313,116 @@ async fn recv_audio_to_wav(
         }
     }
 
-    if samples.is_empty() {
-        return Err(anyhow::anyhow!("no audio received"));
+    pcm.truncate(pcm.len() & !1);
+    if pcm.is_empty() {
+        return Err(anyhow::anyhow!("no speech audio received"));
     }
 
-    let wav_audio = wav_io::write_to_bytes(&head, &samples)?;
+    let wav_audio = crate::util::pcm_to_wav(
+        &pcm,
+        crate::util::WavConfig {
+            sample_rate: SAMPLE_RATE,
+            ..Default::default()
+        },
+    );
 
     Ok((wav_audio, is_recording))
 }
 
+/// follow-up window after a response: listen without the wake word for `follow_up_ms`,
+/// segmenting the speech with the energy vad. None if the user stays silent.
+/// return: (wav_data,is_recording)
+async fn recv_follow_up(
+    pool: &WsPool,
+    id: &str,
+    asr: &crate::config::WhisperASRConfig,
+    vad_params: &crate::ai::vad::VadParams,
+    wake_word: &crate::config::WakeWordConfig,
+    audio: &mut tokio::sync::mpsc::Receiver<AudioChunk>,
+) -> anyhow::Result<Option<(Bytes, bool)>> {
+    pool.send(id, WsCommand::StartListening(wake_word.follow_up_ms))
+        .await?;
+    send_earcon(pool, id, Earcon::Listening).await?;
+
+    let mut vad =
+        crate::ai::energy_vad::EnergyVad::new(asr.energy_vad.adapted(vad_params.noise_floor_db));
+    let mut pre_roll = crate::util::PreRoll::new(SAMPLE_RATE, wake_word.pre_roll_ms);
+    let deadline = tokio::time::Instant::now()
+        + std::time::Duration::from_millis(wake_word.follow_up_ms as u64);
+
+    loop {
+        tokio::select! {
+            chunk = audio.recv() => {
+                match chunk {
+                    Some(AudioChunk::Chunk(data)) => {
+                        pre_roll.push(&data);
+                        let events = vad.push_audio_16k_chunk(&data);
+                        if events.contains(&crate::ai::vad::VAD_SPEECH_START) {
+                            log::info!("`{id}` follow-up speech detected");
+                            break;
+                        }
+                    }
+                    Some(AudioChunk::Enb) | Some(AudioChunk::Recording) => {}
+                    None => return Err(anyhow::anyhow!("audio channel closed")),
+                }
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                log::info!("`{id}` follow-up window closed");
+                pool.send(id, WsCommand::EndListening).await?;
+                return Ok(None);
+            }
+        }
+    }
+
+    let speech = recv_speech(id, wake_word, audio, pre_roll.take(), Some(vad)).await?;
+    pool.send(id, WsCommand::EndListening).await?;
+    Ok(Some(speech))
+}
+
+/// follow_up: right after a response, the wake word is not needed during
+/// `asr.wake_word.follow_up_ms`
 async fn get_asr_text(
+    pool: &WsPool,
     client: &reqwest::Client,
     id: &str,
-    asr: &crate::config::ASRConfig,
+    asr: &crate::config::WhisperASRConfig,
     audio: &mut tokio::sync::mpsc::Receiver<AudioChunk>,
+    mut follow_up: bool,
 ) -> anyhow::Result<String> {
-    std::fs::create_dir_all(format!("./record/{id}"))?;
+    let vad_params = crate::ai::vad::VadParams {
+        noise_floor_db: pool.noise_profile(id).await.map(|p| p.noise_floor_db),
+        ..Default::default()
+    };
     loop {
-        let (wav_data, is_recording) = recv_audio_to_wav(audio).await?;
+        let follow_up_speech = match &asr.wake_word {
+            Some(wake_word) if follow_up && wake_word.follow_up_ms > 0 => {
+                recv_follow_up(pool, id, asr, &vad_params, wake_word, audio).await?
+            }
+            _ => None,
+        };
+        follow_up = false;
 
-        std::fs::write(format!("./record/{id}/asr.last.wav"), &wav_data)?;
+        let (wav_data, is_recording) = if let Some(speech) = follow_up_speech {
+            speech
+        } else if let Some(wake_word) = &asr.wake_word {
+            recv_audio_after_wake_word(pool, client, id, wake_word, audio).await?
+        } else {
+            recv_audio_to_wav(audio).await?
+        };
+
+        // for debugging, a turn goes on without it
+        if let Err(e) = pool
+            .recordings
+            .put(&format!("{id}/asr.last.wav"), wav_data.clone())
+            .await
+        {
+            log::warn!("`{id}` error writing asr.last.wav: {e}");
+        }
 
-        if let Some(vad_url) = &asr.vad_url {
-            match crate::ai::vad_detect(client, vad_url, wav_data.clone()).await {
+        if let Some(r) =
+            crate::ai::vad::detect_speech(client, asr, &vad_params, wav_data.clone()).await
+        {
+            match r {
                 Ok(r) => {
                     if let Some(err) = r.error {
                         log::error!("`{id}` vad error: {err}, skipping ASR");
@@ -430,69 +1445,260 @@ async fn get_asr_text(
         if is_recording {
             let now = chrono::Local::now().to_rfc3339();
 
-            if let Err(e) = std::fs::write(format!("./record/{id}/recording_{now}.wav"), &wav_data)
+            if let Err(e) = pool
+                .recordings
+                .put(&format!("{id}/recording_{now}.wav"), wav_data)
+                .await
             {
                 log::error!("`{id}` error writing recording file {now}: {e}");
             };
             continue;
         }
 
+        let asr_ms = costs::wav_duration_ms(&wav_data);
         let st = std::time::Instant::now();
-        let text = retry_asr(
-            client,
-            &asr.url,
-            &asr.api_key,
-            &asr.model,
-            &asr.lang,
-            &asr.prompt,
-            wav_data,
-            3,
-            std::time::Duration::from_secs(10),
-        )
-        .await;
+        let text = match &asr.mock {
+            Some(mock) => crate::ai::mock::asr(mock),
+            None => {
+                let api_key = tenant_of(pool, id)
+                    .and_then(|t| t.asr_api_key.as_deref())
+                    .unwrap_or(&asr.api_key);
+                retry_asr(
+                    client,
+                    &asr.url,
+                    api_key,
+                    &asr.model,
+                    &asr.lang,
+                    &asr.prompt,
+                    wav_data,
+                    3,
+                    std::time::Duration::from_secs(10),
+                )
+                .await
+            }
+        };
+        #[cfg(feature = "chaos")]
+        let text = match text {
+            Ok(text) => crate::ai::chaos::inject("asr").await.map(|_| text),
+            Err(e) => Err(e),
+        };
         log::info!("`{id}` ASR took: {:?}", st.elapsed());
-        let text = text.join("\n");
+        let text = match text {
+            Ok(text) => {
+                let usage = Usage {
+                    asr_ms,
+                    ..Default::default()
+                };
+                pool.sessions.add_usage(id, usage);
+                text.join("\n")
+            }
+            Err(e) => {
+                log::error!("`{id}` {e}");
+                pool.sessions.turn_error(id);
+                match send_offline_answer(pool, id, None).await {
+                    Ok(true) => pool.send(id, WsCommand::EndResponse).await?,
+                    Ok(false) => {}
+                    Err(e) => log::error!("`{id}` offline answer error: {e}"),
+                }
+                continue;
+            }
+        };
         log::info!("ASR result: {:?}", text);
-        if text.is_empty() || text.trim().starts_with("(") {
+        let text = crate::ai::normalize_transcript(&text);
+        if text.is_empty() {
             continue;
         }
-        return Ok(hanconv::tw2sp(text));
+        return Ok(text);
+    }
+}
+
+/// speak the canned answer of `question` with its pre-rendered audio,
+/// return false if no offline answers are configured
+async fn send_offline_answer(
+    pool: &WsPool,
+    id: &str,
+    question: Option<&str>,
+) -> anyhow::Result<bool> {
+    let Some(offline) = &pool.offline else {
+        return Ok(false);
+    };
+    let text = offline.answer_text(question).to_string();
+    log::info!("`{id}` offline answer: {text:?}");
+
+    pool.send(id, WsCommand::StartAudio(text.clone())).await?;
+    match offline.audio(&text) {
+        Some(audio) => send_pcm(pool, id, audio).await?,
+        // 没有预渲染成功，尝试在线 tts
+        None => {
+            if let Err(e) = tts_and_send(pool, id, text).await {
+                log::error!("`{id}` tts error for offline answer: {e}");
+            }
+        }
+    }
+    pool.send(id, WsCommand::EndAudio).await?;
+
+    Ok(true)
+}
+
+/// pcm16 pre-rendered at `stream.output_sample_rate`, in chunks of `stream.audio_chunk_ms`
+/// of the device
+async fn send_pcm(pool: &WsPool, id: &str, audio: Bytes) -> anyhow::Result<()> {
+    let stream = pool.stream(id).await;
+    let (in_hz, out_hz) = (pool.stream.output_sample_rate, stream.output_sample_rate);
+    let audio = if in_hz == out_hz {
+        audio
+    } else {
+        resample_pcm16(&audio, in_hz, out_hz)?
+    };
+    let chunk_size = stream.audio_chunk_bytes();
+    for i in (0..audio.len()).step_by(chunk_size) {
+        let end = (i + chunk_size).min(audio.len());
+        pool.send(id, WsCommand::Audio(audio.slice(i..end))).await?;
+    }
+    Ok(())
+}
+
+/// an earcon of the device as an audio response of its own, nothing if it has none
+async fn send_earcon(pool: &WsPool, id: &str, earcon: Earcon) -> anyhow::Result<()> {
+    let Some(audio) = pool.earcons(id).await.get(earcon) else {
+        return Ok(());
+    };
+    pool.send(id, WsCommand::StartAudio(String::new())).await?;
+    send_pcm(pool, id, audio).await?;
+    pool.send(id, WsCommand::EndAudio).await
+}
+
+/// one of the [`Phrases`], spoken as a whole response, pre-rendered if it could be
+async fn send_phrase(pool: &WsPool, id: &str, text: &str) -> anyhow::Result<()> {
+    pool.send(id, WsCommand::StartAudio(text.to_string()))
+        .await?;
+    let audio = match &pool.config {
+        AIConfig::Stable { tts, .. } | AIConfig::GeminiAndTTS { tts, .. } => {
+            match pool.profile(id).await.and_then(|p| p.voice) {
+                Some(voice) => {
+                    pool.phrase_audio
+                        .get(&tts.with_voice(&voice), &pool.speech_text, text)
+                }
+                None => pool.phrase_audio.get(tts, &pool.speech_text, text),
+            }
+        }
+        AIConfig::Gemini { .. } => None,
+    };
+    match audio {
+        Some(audio) => send_pcm(pool, id, audio).await?,
+        None => {
+            let st = std::time::Instant::now();
+            if let Err(e) = tts_and_send(pool, id, text.to_string()).await {
+                log::error!("tts error for phrase {text:?}: {e}");
+            }
+            log::info!("phrase tts took: {:?}", st.elapsed());
+        }
     }
+    pool.send(id, WsCommand::EndAudio).await?;
+    Ok(())
 }
 
+/// an empty `asr_result` answers the history as it is, after an injected system message
+/// `delivered` is the text of the answer sent to the device so far, kept by the caller
+/// if the turn is interrupted
 async fn submit_to_ai(
     pool: &WsPool,
     id: &str,
     chat_session: &mut ChatSession,
+    delivered: &mut String,
     asr_result: String,
 ) -> anyhow::Result<()> {
     let message = asr_result;
+    let question = (!message.is_empty()).then(|| message.clone());
+
+    // a refused question is not kept in the history
+    let phrases = pool.phrases(id).await;
+    if let Err(e) = pool.sessions.check_quota(id).await {
+        log::warn!("`{id}` {e}");
+        send_phrase(pool, id, &phrases.error).await?;
+        return Ok(());
+    }
 
-    pool.send(id, WsCommand::AsrResult(vec![message.clone()]))
-        .await?;
+    if !message.is_empty() {
+        pool.send(id, WsCommand::AsrResult(vec![message.clone()]))
+            .await?;
+        pool.webhooks.transcription_completed(id, &message);
+        pool.sessions
+            .record(id, crate::ai::llm::Role::User, &message);
 
-    if matches!(
-        chat_session.messages.back(),
-        Some(Content {
-            role: crate::ai::llm::Role::User,
-            ..
-        })
-    ) {
-        chat_session.messages.pop_back();
+        if let Some(knowledge) = &pool.knowledge {
+            chat_session.set_context(knowledge.context(id, &message).await);
+        }
+        chat_session.add_user_message(message);
     }
-
-    chat_session.add_user_message(message);
+    pool.sessions.add_turn(id);
+    let mut thinking_sent = false;
 
     log::info!("start llm");
-    let mut resp = chat_session.complete().await?;
+    let mut abort = chat_session.abort_handle.watch();
+    let mut resp = match chat_session.complete().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::error!("`{id}` llm unreachable: {e}");
+            if send_offline_answer(pool, id, question.as_deref()).await? {
+                return Ok(());
+            }
+            return Err(e);
+        }
+    };
 
     let mut llm_response = String::with_capacity(128);
     let mut has_valid_response = false;
     let mut first_chunk = true;
+    let mut retries = 0;
+    let mut reasoning_filter = match &pool.config {
+        AIConfig::Stable { llm, .. } => llm.reasoning_filter(),
+        _ => Default::default(),
+    };
+    let mut budget = SentenceBudget::new(chat_session.max_spoken_sentences);
+    let mut code_blocks = CodeBlockFilter::new(chat_session.code_blocks.clone());
+    let mut moderation = match &pool.config {
+        AIConfig::Stable { llm, .. } => llm.moderation.as_ref().map(ModerationFilter::new),
+        _ => None,
+    };
+    let client = reqwest::Client::new();
+    let mut output_tokens = 0;
 
     loop {
-        match resp.next_chunk().await {
+        // the rest of the answer would not be spoken, stop the llm
+        let next = if budget.is_spent() {
+            chat_session.abort_handle.abort();
+            Ok(StableLLMResponseChunk::Stop)
+        } else {
+            resp.next_chunk().await
+        };
+        // nothing spoken yet, the same answer can be asked again
+        let chunk = match next {
+            Err(e)
+                if llm_response.is_empty()
+                    && chat_session.wait_retry(&e, retries, &mut abort).await =>
+            {
+                retries += 1;
+                match chat_session.complete().await {
+                    Ok(retried) => {
+                        resp = retried;
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            chunk => chunk,
+        };
+        match chunk {
             Ok(StableLLMResponseChunk::Text(chunk)) => {
+                output_tokens += estimate_tokens(&chunk);
+                let mut chunk = budget.take(&reasoning_filter.push(&chunk).text).to_string();
+                if let Some(moderation) = moderation.as_mut() {
+                    chunk = moderation.filter(&client, id, chunk).await;
+                }
+                if chunk.is_empty() {
+                    continue;
+                }
                 log::info!("start tts: {chunk:?}");
 
                 let chunk_ = chunk.trim();
@@ -515,10 +1721,12 @@ async fn submit_to_ai(
                 if chunk_.is_empty() {
                     continue;
                 }
-                
+
+                // the device shows the whole chunk, code included
                 pool.send(id, WsCommand::StartAudio(chunk.clone())).await?;
+                delivered.push_str(&chunk);
                 let st = std::time::Instant::now();
-                match tts_and_send(pool, id, chunk).await {
+                match tts_and_send(pool, id, code_blocks.push(&chunk)).await {
                     Ok(_) => {}
                     Err(e) => {
                         log::error!("tts error:{e}");
@@ -529,9 +1737,43 @@ async fn submit_to_ai(
             }
             Ok(StableLLMResponseChunk::Functions(functions)) => {
                 log::info!("llm functions: {:#?}", functions);
+                if let Some(thinking) = phrases.thinking.as_deref().filter(|_| !thinking_sent) {
+                    thinking_sent = true;
+                    send_phrase(pool, id, thinking).await?;
+                }
                 chat_session.add_assistant_tool_call(functions.clone());
                 for function in functions {
-                    chat_session.execute_tool(&function).await?
+                    output_tokens += estimate_tokens(&function.function.name)
+                        + estimate_tokens(&function.function.arguments);
+                    pool.webhooks.tool_invoked(
+                        id,
+                        &function.function.name,
+                        &function.function.arguments,
+                    );
+                    pool.sessions.turn_intent(id, &function.function.name);
+                    let builtin = call_builtin(
+                        pool,
+                        id,
+                        &function.function.name,
+                        &function.function.arguments,
+                    )
+                    .await;
+                    if let Some(result) = builtin {
+                        chat_session.add_tool_result(function.id.clone(), result);
+                    } else {
+                        chat_session.execute_tool(&function).await?;
+                        let result = chat_session
+                            .messages
+                            .back()
+                            .filter(|m| m.tool_call_id.as_ref() == Some(&function.id));
+                        if let Some((media, source)) = pool
+                            .media
+                            .as_ref()
+                            .zip(result.and_then(|m| media::source_of(&m.message)))
+                        {
+                            media.queue(id, source);
+                        }
+                    }
                 }
                 resp = chat_session.complete().await?;
                 continue;
@@ -539,24 +1781,31 @@ async fn submit_to_ai(
             Ok(StableLLMResponseChunk::Stop) => {
                 log::info!("llm done");
 
-                // 检查是否有有效响应，如果没有则发送标准错误回复
-                if !has_valid_response || llm_response.trim().is_empty() {
-                    log::warn!("Empty or invalid LLM response, sending standard error message");
-                    
-                    pool.send(id, WsCommand::StartAudio(STANDARD_ERROR_RESPONSE.to_string())).await?;
-                    let st = std::time::Instant::now();
-                    match tts_and_send(pool, id, STANDARD_ERROR_RESPONSE.to_string()).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            log::error!("tts error for standard response: {e}");
-                        }
+                let mut rest = budget.take(&reasoning_filter.finish().text).to_string();
+                if let Some(moderation) = moderation.as_mut() {
+                    rest = moderation.filter(&client, id, rest).await;
+                }
+                if !rest.trim().is_empty() {
+                    has_valid_response = true;
+                    llm_response.push_str(&rest);
+                    pool.send(id, WsCommand::StartAudio(rest.clone())).await?;
+                    if let Err(e) = tts_and_send(pool, id, code_blocks.push(&rest)).await {
+                        log::error!("tts error:{e}");
                     }
-                    log::info!("standard response tts took: {:?}", st.elapsed());
                     pool.send(id, WsCommand::EndAudio).await?;
-                    
+                }
+
+                // 检查是否有有效响应，如果没有则请用户换种说法
+                if !has_valid_response || llm_response.trim().is_empty() {
+                    log::warn!("Empty or invalid LLM response, sending the clarification phrase");
+                    send_phrase(pool, id, &phrases.clarification).await?;
+
                     // 仍然添加到会话历史中，但使用标准回复
-                    chat_session.add_assistant_message(STANDARD_ERROR_RESPONSE.to_string());
+                    chat_session.add_assistant_message(phrases.clarification);
                 } else if !llm_response.is_empty() {
+                    pool.webhooks.response_done(id, &llm_response);
+                    pool.sessions
+                        .record(id, crate::ai::llm::Role::Assistant, &llm_response);
                     chat_session.add_assistant_message(llm_response);
                 }
 
@@ -564,28 +1813,34 @@ async fn submit_to_ai(
             }
             Err(e) => {
                 log::error!("llm error: {:#?}", e);
-                
-                // LLM 出错时发送标准错误回复
-                log::warn!("LLM error occurred, sending standard error message");
-                
-                pool.send(id, WsCommand::StartAudio(STANDARD_ERROR_RESPONSE.to_string())).await?;
-                let st = std::time::Instant::now();
-                match tts_and_send(pool, id, STANDARD_ERROR_RESPONSE.to_string()).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("tts error for standard response: {e}");
-                    }
+                pool.webhooks.error(id, &e);
+                pool.sessions.turn_error(id);
+
+                // 还没有回复任何内容时，使用离线回答
+                if llm_response.is_empty()
+                    && send_offline_answer(pool, id, question.as_deref()).await?
+                {
+                    break;
                 }
-                log::info!("standard response tts took: {:?}", st.elapsed());
-                pool.send(id, WsCommand::EndAudio).await?;
-                
+
+                // LLM 出错时发送标准错误回复
+                log::warn!("LLM error occurred, sending the error phrase");
+                send_earcon(pool, id, Earcon::Error).await?;
+                send_phrase(pool, id, &phrases.error).await?;
+
                 // 添加到会话历史中
-                chat_session.add_assistant_message(STANDARD_ERROR_RESPONSE.to_string());
-                
+                chat_session.add_assistant_message(phrases.error);
+
                 break;
             }
         }
     }
+    let usage = Usage {
+        llm_input_tokens: std::mem::take(&mut chat_session.prompt_tokens),
+        llm_output_tokens: output_tokens,
+        ..Default::default()
+    };
+    pool.sessions.add_usage(id, usage);
     Ok(())
 }
 
@@ -627,18 +1882,21 @@ async fn submit_to_gemini_and_tts(
                 gemini::types::ServerContent::GenerationComplete(_) => {}
                 gemini::types::ServerContent::Interrupted(_) => {}
                 gemini::types::ServerContent::TurnComplete(_) => {
+                    if !asr_text.is_empty() {
+                        pool.webhooks.transcription_completed(id, &asr_text);
+                        pool.sessions
+                            .record(id, crate::ai::llm::Role::User, &asr_text);
+                    }
+                    if !text.trim().is_empty() {
+                        pool.webhooks.response_done(id, &text);
+                        pool.sessions
+                            .record(id, crate::ai::llm::Role::Assistant, &text);
+                    }
                     // 检查是否有有效响应文本
                     if text.trim().is_empty() {
-                        log::warn!("Empty Gemini response, sending standard error message");
-                        
-                        pool.send(id, WsCommand::StartAudio(STANDARD_ERROR_RESPONSE.to_string())).await?;
-                        match tts_and_send(pool, id, STANDARD_ERROR_RESPONSE.to_string()).await {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("tts error for standard response: {e}");
-                            }
-                        }
-                        pool.send(id, WsCommand::EndAudio).await?;
+                        log::warn!("Empty Gemini response, sending the clarification phrase");
+                        let phrases = pool.phrases(id).await;
+                        send_phrase(pool, id, &phrases.clarification).await?;
                     } else {
                         pool.send(id, WsCommand::StartAudio(text.clone())).await?;
                         match tts_and_send(pool, id, text).await {
@@ -682,7 +1940,7 @@ async fn submit_to_gemini_and_tts(
             GeminiEvent::AudioChunk(AudioChunk::Chunk(sample)) => {
                 client
                     .send_realtime_input(gemini::types::RealtimeInput::Audio(RealtimeAudio {
-                        data: Blob::new(sample.to_vec()),
+                        data: Blob::new(sample),
                         mime_type: "audio/pcm;rate=16000".to_string(),
                     }))
                     .await?;
@@ -716,21 +1974,10 @@ async fn submit_to_gemini(
     pool: &WsPool,
     client: &mut gemini::LiveClient,
     id: &str,
-    wav_audio: Vec<u8>,
+    wav_audio: Bytes,
 ) -> anyhow::Result<()> {
     // Gemini live api
-    let mut reader = wav_io::reader::Reader::from_vec(wav_audio)?;
-    let header = reader.read_header()?;
-    let mut samples = reader.get_samples_f32()?;
-    if header.sample_rate != 16000 {
-        samples = wav_io::resample::linear(samples, 1, header.sample_rate, 16000);
-    }
-
-    let data = wav_io::convert_samples_f32_to_i16(&samples);
-    let mut submit_data = Vec::with_capacity(data.len() * 2);
-    for sample in data {
-        submit_data.extend_from_slice(&sample.to_le_bytes());
-    }
+    let (submit_data, _) = crate::util::wav_to_pcm16_blocking(wav_audio, 16000).await?;
 
     log::info!("start gemini");
     client
@@ -743,8 +1990,7 @@ async fn submit_to_gemini(
     pool.send(id, WsCommand::AsrResult(vec![format!("Wait gemini")]))
         .await?;
 
-    let mut buff = Vec::with_capacity(5 * 1600 * 2);
-
+    let stream = pool.stream(id).await;
     loop {
         log::info!("`{id}` waiting gemini response");
         match client.receive().await? {
@@ -753,29 +1999,16 @@ async fn submit_to_gemini(
                     if let gemini::types::Parts::InlineData { data, mime_type } = item {
                         if mime_type.starts_with("audio/pcm") {
                             let audio_data = data.into_inner();
-                            let mut sample = Vec::with_capacity(audio_data.len() / 2);
-                            if audio_data.len() % 2 != 0 {
-                                log::warn!("Received audio chunk with odd length, skipping");
-                                for i in audio_data[0..audio_data.len() - 1].chunks_exact(2) {
-                                    let sample_value = i16::from_le_bytes([i[0], i[1]]);
-                                    sample.push(sample_value);
-                                }
-                            } else {
-                                for i in audio_data.chunks_exact(2) {
-                                    let sample_value = i16::from_le_bytes([i[0], i[1]]);
-                                    sample.push(sample_value);
-                                }
-                            }
-
-                            let mut audio_16k = resample(&sample, 24000, 16000)?;
-                            let samples = audio_16k.as_i16_slice();
-                            for chunk in samples.chunks(5 * 16000 / 10) {
-                                for i in chunk {
-                                    buff.extend_from_slice(&i.to_le_bytes());
-                                }
-                                // std::mem::swap(&mut send_data, &mut buff);
-                                pool.send(id, WsCommand::Audio(buff)).await?;
-                                buff = Vec::with_capacity(5 * 1600 * 2);
+                            let out_hz = stream.output_sample_rate;
+                            let audio = tokio::task::spawn_blocking(move || {
+                                resample_pcm16(&audio_data, 24000, out_hz)
+                            })
+                            .await??;
+
+                            let chunk_size = stream.audio_chunk_bytes();
+                            for i in (0..audio.len()).step_by(chunk_size) {
+                                let end = (i + chunk_size).min(audio.len());
+                                pool.send(id, WsCommand::Audio(audio.slice(i..end))).await?;
                             }
                         }
                     }
@@ -827,12 +2060,23 @@ pub enum AudioChunk {
     Recording,
 }
 
+/// 10s of room tone at most
+const CALIBRATION_MAX_SAMPLES: usize = 16000 * 10;
+
 // return: wav data
 async fn process_socket_io(
+    pool: &WsPool,
+    id: &str,
     rx: &mut WsRx,
     audio_tx: tokio::sync::mpsc::Sender<AudioChunk>,
     socket: &mut WebSocket,
 ) -> anyhow::Result<Vec<u8>> {
+    // set when the ASR result is sent, used to report the time to first audio chunk
+    let mut turn_start: Option<std::time::Instant> = None;
+    // room tone of the calibration in progress, not sent to asr
+    let mut calibration: Option<Vec<i16>> = None;
+    // the audio sent to asr is denoised once the device is calibrated
+    let mut denoise_floor = pool.noise_profile(id).await.map(|p| p.noise_floor_db);
     loop {
         let r = tokio::select! {
             cmd = rx.recv() => {
@@ -846,14 +2090,72 @@ async fn process_socket_io(
             }
         };
 
+        if let Some(WsEvent::Message(Ok(_))) = &r {
+            pool.sessions.touch(id);
+        }
         match r {
-            Some(WsEvent::Command(cmd)) => process_command(socket, cmd).await?,
+            Some(WsEvent::Command(cmd)) => {
+                match &cmd {
+                    WsCommand::AsrResult(_) => {
+                        turn_start = Some(std::time::Instant::now());
+                        pool.sessions.turn_started(id);
+                    }
+                    WsCommand::Audio(_) => {
+                        if let Some(st) = turn_start.take() {
+                            log::info!("time to first audio chunk: {:?}", st.elapsed());
+                            pool.sessions.turn_first_audio(id, st.elapsed());
+                        }
+                    }
+                    WsCommand::EndResponse => {
+                        turn_start = None;
+                        pool.sessions.turn_done(id);
+                    }
+                    _ => {}
+                }
+                process_command(socket, cmd).await?
+            }
             Some(WsEvent::Message(Ok(msg))) => match process_message(msg) {
                 // i16 16000
-                ProcessMessageResult::Ok(d) => audio_tx
-                    .send(AudioChunk::Chunk(d))
-                    .await
-                    .map_err(|_| anyhow::anyhow!("audio_tx closed"))?,
+                ProcessMessageResult::Ok(d) => match &mut calibration {
+                    Some(samples) => {
+                        let room = CALIBRATION_MAX_SAMPLES.saturating_sub(samples.len());
+                        samples.extend(
+                            d.chunks_exact(2)
+                                .take(room)
+                                .map(|b| i16::from_le_bytes([b[0], b[1]])),
+                        )
+                    }
+                    None => {
+                        let d = match denoise_floor {
+                            Some(floor) => Bytes::from(crate::ai::energy_vad::denoise(&d, floor)),
+                            None => d,
+                        };
+                        audio_tx
+                            .send(AudioChunk::Chunk(d))
+                            .await
+                            .map_err(|_| anyhow::anyhow!("audio_tx closed"))?
+                    }
+                },
+                ProcessMessageResult::CalibrateStart => {
+                    log::info!("`{id}` noise calibration started");
+                    calibration = Some(Vec::new());
+                }
+                ProcessMessageResult::CalibrateEnd => {
+                    let samples = calibration.take().unwrap_or_default();
+                    match crate::ai::energy_vad::noise_floor_db(&samples) {
+                        Some(noise_floor_db) => {
+                            log::info!("`{id}` noise floor: {noise_floor_db:.1} dBFS");
+                            let profile = NoiseProfile {
+                                noise_floor_db,
+                                calibrated_at: chrono::Local::now().to_rfc3339(),
+                            };
+                            pool.set_noise_profile(id, profile).await;
+                            denoise_floor = Some(noise_floor_db);
+                            process_command(socket, WsCommand::Calibrated(noise_floor_db)).await?;
+                        }
+                        None => log::warn!("`{id}` noise calibration without audio"),
+                    }
+                }
                 ProcessMessageResult::Skip => {}
                 ProcessMessageResult::Submit => audio_tx
                     .send(AudioChunk::Enb)
@@ -863,6 +2165,20 @@ async fn process_socket_io(
                     .send(AudioChunk::Recording)
                     .await
                     .map_err(|_| anyhow::anyhow!("audio_tx closed"))?,
+                ProcessMessageResult::ControlAck(control) => {
+                    log::info!("`{id}` control ack: {control:?}");
+                    pool.device_controls
+                        .write()
+                        .await
+                        .entry(id.to_string())
+                        .or_default()
+                        .merge(control);
+                }
+                ProcessMessageResult::Feedback(feedback) => pool.sessions.feedback(id, feedback),
+                ProcessMessageResult::Telemetry(telemetry) => match &pool.registry {
+                    Some(registry) => registry.record_telemetry(id, telemetry).await,
+                    None => log::debug!("`{id}` telemetry: {telemetry:?}"),
+                },
                 ProcessMessageResult::Close => {
                     return Err(anyhow::anyhow!("ws closed"));
                 }
@@ -877,36 +2193,156 @@ async fn process_socket_io(
     }
 }
 
+/// a message of `POST /v1/sessions/{id}/messages`, returns the turn to answer if any
+fn inject_message(chat_session: &mut ChatSession, message: InjectedMessage) -> Option<String> {
+    match message.role {
+        crate::ai::llm::Role::User if message.generate => Some(message.content),
+        crate::ai::llm::Role::User => {
+            chat_session.add_user_message(message.content);
+            None
+        }
+        _ => {
+            chat_session.add_system_message(message.content);
+            message.generate.then(String::new)
+        }
+    }
+}
+
 async fn handle_audio(
     id: String,
     pool: Arc<WsPool>,
     mut rx: tokio::sync::mpsc::Receiver<AudioChunk>,
+    mut inbox: tokio::sync::mpsc::UnboundedReceiver<InjectedMessage>,
 ) -> anyhow::Result<()> {
+    if pool.hello_wav.as_ref().is_none_or(|wav| wav.is_empty()) {
+        if let Some(greeting) = pool.phrases(&id).await.greeting {
+            send_phrase(&pool, &id, &greeting).await?;
+        }
+    }
+    // the timers and alarms go on across the connections of the device
+    if let Some(alarms) = &pool.alarms {
+        for event in alarms.events(&id, chrono::Local::now()) {
+            pool.send(&id, WsCommand::Alarm(event)).await?;
+        }
+    }
+    // the reminders that came due while the device was away
+    if let Some(reminders) = &pool.reminders {
+        if let Err(e) = reminders.deliver(&pool, &id).await {
+            log::warn!("`{id}` pending reminders error: {e}");
+        }
+    }
+
     match &pool.config {
         AIConfig::Stable { llm, asr, .. } => {
+            let Some(asr) = asr.whisper() else {
+                return Err(anyhow::anyhow!(
+                    "only whisper ASR is supported on the device endpoint"
+                ));
+            };
+            let asr = &asr;
             let client = reqwest::Client::new();
-            let mut chat_session = ChatSession::new(
-                llm.llm_chat_url.to_string(),
-                llm.api_key.clone().unwrap_or_default(),
-                llm.model.clone(),
-                None,
-                llm.history,
-                pool.tool_set.clone(),
-            );
+            let mut chat_session = ChatSession::from_config(llm, pool.tool_set.clone());
 
             chat_session.system_prompts = llm.sys_prompts.clone();
             chat_session.messages = llm.dynamic_prompts.clone();
+            if let Some(tenant) = tenant_of(&pool, &id) {
+                tenant.apply_chat(&mut chat_session);
+            }
+            let mut prompts = pool.sessions.prompts().start(&mut chat_session);
+            let profile = pool.profile(&id).await;
+            if let Some(profile) = profile.as_ref().filter(|p| !p.sys_prompts.is_empty()) {
+                chat_session.system_prompts = profile.sys_prompts.clone();
+            }
+            let style = profile
+                .as_ref()
+                .and_then(|p| p.spoken_style.as_ref())
+                .unwrap_or(&llm.spoken_style);
+            chat_session.set_spoken_style(style);
+            chat_session.builtin_tools.push(device_control_tool());
+            if let Some(tool) = pool.knowledge.as_ref().and_then(|k| k.remember_tool()) {
+                chat_session.builtin_tools.push(tool);
+            }
+            if let Some(tool) = pool.reminders.as_ref().and_then(|r| r.tool()) {
+                chat_session.builtin_tools.push(tool);
+            }
+            if let Some(tool) = pool.alarms.as_ref().and_then(|a| a.tool()) {
+                chat_session.builtin_tools.push(tool);
+            }
+            let mut translator = profile
+                .and_then(|p| p.translation)
+                .map(|t| Translator::new(llm, t));
+
+            // connected to another instance a moment ago
+            let handoff = pool.sessions.handoff(&id).await;
+            if !handoff.is_empty() {
+                log::info!("`{id}` goes on with {} turns", handoff.len());
+            }
+            for turn in handoff {
+                match turn.role {
+                    crate::ai::llm::Role::User => chat_session.add_user_message(turn.text),
+                    _ => chat_session.add_assistant_message(turn.text),
+                }
+            }
 
-            let mut asr_result = get_asr_text(&client, &id, asr, &mut rx).await?;
+            // the turn being answered, a new utterance interrupts it
+            let mut turn: Option<String> = None;
+            let mut follow_up = false;
+            let mut delivered = String::new();
 
             loop {
-                asr_result = tokio::select! {
-                    r = get_asr_text(&client, &id, asr, &mut rx) =>{
-                        r?
+                let current = turn.take();
+                if current.is_some() {
+                    if let Some(version) = prompts.apply(&mut chat_session) {
+                        log::info!("`{id}` prompts updated to version {version}");
+                        if let Err(e) = pool.send(&id, WsCommand::PromptsUpdated(version)).await {
+                            log::error!("`{id}` error: {e}");
+                        }
                     }
-                    r = submit_to_ai(&pool, &id,&mut chat_session, asr_result) => {
+                }
+                let mark = chat_session.mark();
+                turn = tokio::select! {
+                    r = get_asr_text(&pool, &client, &id, asr, &mut rx, follow_up) =>{
+                        follow_up = false;
+                        Some(r?)
+                    }
+                    r = async {
+                        let text = current.clone().unwrap_or_default();
+                        if let Some(alarms) = &pool.alarms {
+                            let cancelled = alarms::cancel_command(&text)
+                                .and_then(|c| alarms.cancel(&id, c, chrono::Local::now()));
+                            if let Some(cancelled) = cancelled {
+                                return alarms.cancel_turn(&pool, &id, text, cancelled).await;
+                            }
+                        }
+                        if let Some(media) = &pool.media {
+                            let command = media::media_command(&text)
+                                .filter(|_| media.has_player(&id));
+                            if let Some(command) = command {
+                                return media.command_turn(&pool, &id, text, command).await;
+                            }
+                            // the answer is not spoken over the stream
+                            if !text.is_empty() {
+                                media.control(&id, media::MediaCommand::Pause);
+                            }
+                        }
+                        if let Some(briefing) = pool.briefing.as_ref().filter(|b| b.is_trigger(&text)) {
+                            return briefing.turn(&pool, &id, text).await;
+                        }
+                        let routine = pool.routines.as_ref().and_then(|r| Some((r, r.find(&text)?)));
+                        if let Some((routines, routine)) = routine {
+                            return routines.turn(&pool, &id, text, routine).await;
+                        }
+                        match &mut translator {
+                            Some(translator) => translate_turn(&pool, &id, translator, text).await,
+                            None => submit_to_ai(&pool, &id, &mut chat_session, &mut delivered, text).await,
+                        }
+                    }, if current.is_some() => {
+                        // finished, submit_to_ai recorded the answer
+                        delivered.clear();
                         if let Err(e) = r {
                             log::error!("`{id}` error: {e}");
+                            // not answered, the question is not kept
+                            chat_session.rollback(mark);
                             if let Err(e) = pool.send(&id, WsCommand::AsrResult(vec![])).await{
                                 log::error!("`{id}` error: {e}");
                             };
@@ -914,10 +2350,36 @@ async fn handle_audio(
                         if let Err(e) = pool.send(&id, WsCommand::EndResponse).await{
                             log::error!("`{id}` error: {e}");
                         };
+                        if let Some(media) = &pool.media {
+                            media.start_pending(&pool, &id).await;
+                        }
 
-                        get_asr_text(&client, &id, asr, &mut rx).await?
+                        follow_up = true;
+                        None
+                    }
+                    // queued while a response is in progress
+                    Some(message) = inbox.recv(), if current.is_none() => {
+                        log::info!("`{id}` injected message: {message:?}");
+                        follow_up = false;
+                        inject_message(&mut chat_session, message)
                     }
                 };
+
+                // interrupted, the history is back to before the turn and the next turn
+                // knows what was heard of the answer
+                if let (Some(question), Some(_)) = (&current, &turn) {
+                    chat_session.rollback(mark);
+                    if !question.is_empty() && !delivered.is_empty() {
+                        chat_session.add_user_message(question.clone());
+                    }
+                }
+                if !delivered.is_empty() {
+                    let partial = std::mem::take(&mut delivered);
+                    log::info!("`{id}` answer interrupted after {} chars", partial.len());
+                    pool.sessions
+                        .record(&id, crate::ai::llm::Role::Assistant, &partial);
+                    chat_session.add_truncated_assistant_message(partial);
+                }
             }
         }
         AIConfig::GeminiAndTTS { gemini, .. } => loop {
@@ -930,7 +2392,13 @@ async fn handle_audio(
             let mut generation_config = GenerationConfig::default();
             generation_config.response_modalities = Some(vec![gemini::types::Modality::TEXT]);
 
-            let system_instruction = if let Some(sys_prompts) = gemini.sys_prompts.first() {
+            let profile_prompts = pool
+                .profile(&id)
+                .await
+                .map(|p| p.sys_prompts)
+                .filter(|p| !p.is_empty());
+            let sys_prompts = profile_prompts.as_ref().unwrap_or(&gemini.sys_prompts);
+            let system_instruction = if let Some(sys_prompts) = sys_prompts.first() {
                 Some(gemini::types::Content {
                     parts: vec![gemini::types::Parts::Text(sys_prompts.message.clone())],
                 })
@@ -957,7 +2425,13 @@ async fn handle_audio(
             let mut generation_config = GenerationConfig::default();
             generation_config.response_modalities = Some(vec![gemini::types::Modality::AUDIO]);
 
-            let system_instruction = if let Some(sys_prompts) = gemini.sys_prompts.first() {
+            let profile_prompts = pool
+                .profile(&id)
+                .await
+                .map(|p| p.sys_prompts)
+                .filter(|p| !p.is_empty());
+            let sys_prompts = profile_prompts.as_ref().unwrap_or(&gemini.sys_prompts);
+            let system_instruction = if let Some(sys_prompts) = sys_prompts.first() {
                 Some(gemini::types::Content {
                     parts: vec![gemini::types::Parts::Text(sys_prompts.message.clone())],
                 })
@@ -1044,6 +2518,7 @@ async fn handle_socket(
     mut socket: WebSocket,
     id: &str,
     mut rx: WsRx,
+    inbox: tokio::sync::mpsc::UnboundedReceiver<InjectedMessage>,
     pool: Arc<WsPool>,
 ) -> anyhow::Result<()> {
     if let Some(hello_wav) = &pool.hello_wav {
@@ -1058,20 +2533,22 @@ async fn handle_socket(
         }
     }
 
-    let (audio_tx, audio_rx) = tokio::sync::mpsc::channel::<AudioChunk>(1);
+    let (audio_tx, audio_rx) =
+        tokio::sync::mpsc::channel::<AudioChunk>(pool.stream(id).await.audio_channel_capacity);
     let pool_ = pool.clone();
-    let id = id.to_string();
-    tokio::spawn(async move {
-        let id_ = id.clone();
-        let r = handle_audio(id, pool_, audio_rx).await;
+    let id_ = id.to_string();
+    let audio_task = tokio::spawn(async move {
+        let r = handle_audio(id_.clone(), pool_, audio_rx, inbox).await;
         if let Err(e) = r {
             log::error!("`{id_}` handle audio error: {e}");
         }
     });
 
-    process_socket_io(&mut rx, audio_tx, &mut socket).await?;
+    let r = process_socket_io(&pool, id, &mut rx, audio_tx, &mut socket).await;
+    // 断开后立即丢弃进行中的 llm / tts 请求
+    audio_task.abort();
 
-    Ok(())
+    r.map(drop)
 }
 
 pub const SAMPLE_RATE: u32 = 16000;
@@ -1098,8 +2575,11 @@ async fn process_command(ws: &mut WebSocket, cmd: WsCommand) -> anyhow::Result<(
             ws.send(Message::binary(start_audio)).await?;
         }
         WsCommand::Audio(data) => {
-            let start_audio = rmp_serde::to_vec(&crate::protocol::ServerEvent::AudioChunk { data })
-                .expect("Failed to serialize StartAudio ServerEvent");
+            // the device protocol encodes chunks as a msgpack array, so copy once at the edge
+            let start_audio = rmp_serde::to_vec(&crate::protocol::ServerEvent::AudioChunk {
+                data: data.to_vec(),
+            })
+            .expect("Failed to serialize StartAudio ServerEvent");
             ws.send(Message::binary(start_audio)).await?;
         }
         WsCommand::EndAudio => {
@@ -1115,6 +2595,55 @@ async fn process_command(ws: &mut WebSocket, cmd: WsCommand) -> anyhow::Result<(
                 .expect("Failed to serialize JsonCommand");
             ws.send(Message::binary(end_response)).await?;
         }
+        WsCommand::Control(control) => {
+            let control = rmp_serde::to_vec(&crate::protocol::ServerEvent::Control(control))
+                .expect("Failed to serialize Control ServerEvent");
+            ws.send(Message::binary(control)).await?;
+        }
+        WsCommand::StartListening(timeout_ms) => {
+            let listening =
+                rmp_serde::to_vec(&crate::protocol::ServerEvent::StartListening { timeout_ms })
+                    .expect("Failed to serialize StartListening ServerEvent");
+            ws.send(Message::binary(listening)).await?;
+        }
+        WsCommand::EndListening => {
+            let listening = rmp_serde::to_vec(&crate::protocol::ServerEvent::EndListening)
+                .expect("Failed to serialize EndListening ServerEvent");
+            ws.send(Message::binary(listening)).await?;
+        }
+        WsCommand::Calibrated(noise_floor_db) => {
+            let calibrated =
+                rmp_serde::to_vec(&crate::protocol::ServerEvent::Calibrated { noise_floor_db })
+                    .expect("Failed to serialize Calibrated ServerEvent");
+            ws.send(Message::binary(calibrated)).await?;
+        }
+        WsCommand::PromptsUpdated(version) => {
+            let updated =
+                rmp_serde::to_vec(&crate::protocol::ServerEvent::PromptsUpdated { version })
+                    .expect("Failed to serialize PromptsUpdated ServerEvent");
+            ws.send(Message::binary(updated)).await?;
+        }
+        WsCommand::Translation(translation) => {
+            let translation =
+                rmp_serde::to_vec(&crate::protocol::ServerEvent::Translation(translation))
+                    .expect("Failed to serialize Translation ServerEvent");
+            ws.send(Message::binary(translation)).await?;
+        }
+        WsCommand::Alarm(alarm) => {
+            let alarm = rmp_serde::to_vec(&crate::protocol::ServerEvent::Alarm(alarm))
+                .expect("Failed to serialize Alarm ServerEvent");
+            ws.send(Message::binary(alarm)).await?;
+        }
+        WsCommand::Media(media) => {
+            let media = rmp_serde::to_vec(&crate::protocol::ServerEvent::Media(media))
+                .expect("Failed to serialize Media ServerEvent");
+            ws.send(Message::binary(media)).await?;
+        }
+        WsCommand::Routine(routine) => {
+            let routine = rmp_serde::to_vec(&crate::protocol::ServerEvent::Routine(routine))
+                .expect("Failed to serialize Routine ServerEvent");
+            ws.send(Message::binary(routine)).await?;
+        }
     }
     Ok(())
 }
@@ -1123,6 +2652,12 @@ enum ProcessMessageResult {
     Ok(Bytes),
     Submit,
     Recording,
+    Telemetry(Telemetry),
+    ControlAck(DeviceControl),
+    Feedback(AnnotationRequest),
+    /// room tone follows, until `Calibrate:End`
+    CalibrateStart,
+    CalibrateEnd,
     Close,
     Skip,
 }
@@ -1134,6 +2669,34 @@ fn process_message(msg: Message) -> ProcessMessageResult {
                 ProcessMessageResult::Submit
             } else if t.as_str() == "End:Recording" {
                 ProcessMessageResult::Recording
+            } else if t.as_str() == "Calibrate:Start" {
+                ProcessMessageResult::CalibrateStart
+            } else if t.as_str() == "Calibrate:End" {
+                ProcessMessageResult::CalibrateEnd
+            } else if let Some(json) = t.as_str().strip_prefix("Ack:Control:") {
+                match serde_json::from_str(json) {
+                    Ok(control) => ProcessMessageResult::ControlAck(control),
+                    Err(e) => {
+                        log::warn!("invalid control ack: {e}");
+                        ProcessMessageResult::Skip
+                    }
+                }
+            } else if let Some(json) = t.as_str().strip_prefix("Feedback:") {
+                match serde_json::from_str(json) {
+                    Ok(feedback) => ProcessMessageResult::Feedback(feedback),
+                    Err(e) => {
+                        log::warn!("invalid feedback: {e}");
+                        ProcessMessageResult::Skip
+                    }
+                }
+            } else if let Some(json) = t.as_str().strip_prefix("Telemetry:") {
+                match serde_json::from_str(json) {
+                    Ok(telemetry) => ProcessMessageResult::Telemetry(telemetry),
+                    Err(e) => {
+                        log::warn!("invalid telemetry: {e}");
+                        ProcessMessageResult::Skip
+                    }
+                }
             } else {
                 ProcessMessageResult::Skip
             }
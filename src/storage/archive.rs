@@ -0,0 +1,371 @@
+//! The archive of an instance, to back it up or move its devices to another one.
+//!
+//! an uncompressed tar, version [`FORMAT_VERSION`]:
+//! - `manifest.json` format, version and counts, read first
+//! - `devices.jsonl` the registry, with the profile of each device
+//! - `sessions.jsonl`, `turns.jsonl`, `annotations.jsonl`, `memories.jsonl` one record
+//!   per line
+//! - `audio/{device_id}/{file}` the recordings
+//!
+//! an import adds what is missing: the turns, annotations and memories already there
+//! are skipped, the devices and sessions are replaced.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    io::Read,
+};
+
+use super::{recordings::Recordings, Annotation, Memory, SessionRecord, Storage, Store, Turn};
+use crate::services::registry::{Device, DeviceRegistry};
+
+pub const FORMAT: &str = "echokit-archive";
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    pub created_at: String,
+    /// the archive has this device only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    pub devices: usize,
+    pub sessions: usize,
+    pub turns: usize,
+    #[serde(default)]
+    pub annotations: usize,
+    pub memories: usize,
+    pub audio: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct ImportReport {
+    pub devices: usize,
+    pub sessions: usize,
+    pub turns: usize,
+    pub annotations: usize,
+    pub memories: usize,
+    pub audio: usize,
+    /// already there
+    pub skipped_turns: usize,
+    pub skipped_annotations: usize,
+    pub skipped_memories: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub manifest: Manifest,
+    pub devices: Vec<Device>,
+    pub sessions: Vec<SessionRecord>,
+    pub turns: Vec<Turn>,
+    pub annotations: Vec<Annotation>,
+    pub memories: Vec<Memory>,
+    /// `{device_id}/{file}` and the content
+    pub audio: Vec<(String, bytes::Bytes)>,
+}
+
+fn to_jsonl<T: serde::Serialize>(values: &[T]) -> anyhow::Result<Vec<u8>> {
+    let mut out = vec![];
+    for value in values {
+        serde_json::to_writer(&mut out, value)?;
+        out.push(b'\n');
+    }
+    Ok(out)
+}
+
+fn from_jsonl<T: serde::de::DeserializeOwned>(name: &str, data: &[u8]) -> anyhow::Result<Vec<T>> {
+    let text = std::str::from_utf8(data)?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| anyhow::anyhow!("{name}:{}: {e}", i + 1))
+        })
+        .collect()
+}
+
+/// `audio/{device_id}/{file}` to `{device_id}/{file}`, nothing else is accepted
+fn audio_key(path: &str) -> Option<&str> {
+    let key = path.strip_prefix("audio/")?;
+    let mut parts = key.split('/');
+    let (Some(device), Some(file), None) = (parts.next(), parts.next(), parts.next()) else {
+        return None;
+    };
+    let valid = |s: &str| !s.is_empty() && s != "." && s != ".." && !s.contains('\\');
+    (valid(device) && valid(file)).then_some(key)
+}
+
+impl Archive {
+    /// everything, or what belongs to `device_id`
+    pub async fn collect(
+        store: &Storage,
+        recordings: &Recordings,
+        device_id: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut devices = store.devices().await?;
+        if let Some(device_id) = device_id {
+            devices.retain(|d| d.device_id == device_id);
+        }
+        devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        let sessions = store.sessions(device_id).await?;
+        let turns = store.turns(device_id).await?;
+        let annotations = store.annotations(device_id).await?;
+
+        let ids = match device_id {
+            Some(device_id) => BTreeSet::from([device_id.to_string()]),
+            None => devices
+                .iter()
+                .map(|d| d.device_id.clone())
+                .chain(sessions.iter().filter_map(|s| s.device_id.clone()))
+                .chain(turns.iter().filter_map(|t| t.device_id.clone()))
+                .collect(),
+        };
+        let mut memories = vec![];
+        let mut audio = vec![];
+        for id in &ids {
+            memories.extend(store.memories(id).await?);
+            // no recordings
+            let Ok(names) = recordings.list(id).await else {
+                continue;
+            };
+            for name in names {
+                let key = format!("{id}/{name}");
+                let data = recordings.get(&key).await?;
+                audio.push((key, data));
+            }
+        }
+
+        Ok(Self {
+            manifest: Manifest {
+                format: FORMAT.to_string(),
+                version: FORMAT_VERSION,
+                created_at: chrono::Local::now().to_rfc3339(),
+                device_id: device_id.map(str::to_string),
+                devices: devices.len(),
+                sessions: sessions.len(),
+                turns: turns.len(),
+                annotations: annotations.len(),
+                memories: memories.len(),
+                audio: audio.len(),
+            },
+            devices,
+            sessions,
+            turns,
+            annotations,
+            memories,
+            audio,
+        })
+    }
+
+    pub fn to_tar(&self) -> anyhow::Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(vec![]);
+        let mtime = chrono::Utc::now().timestamp().max(0) as u64;
+        let mut append = |path: &str, data: &[u8]| -> anyhow::Result<()> {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            builder.append_data(&mut header, path, data)?;
+            Ok(())
+        };
+        append("manifest.json", &serde_json::to_vec_pretty(&self.manifest)?)?;
+        append("devices.jsonl", &to_jsonl(&self.devices)?)?;
+        append("sessions.jsonl", &to_jsonl(&self.sessions)?)?;
+        append("turns.jsonl", &to_jsonl(&self.turns)?)?;
+        append("annotations.jsonl", &to_jsonl(&self.annotations)?)?;
+        append("memories.jsonl", &to_jsonl(&self.memories)?)?;
+        for (key, data) in &self.audio {
+            append(&format!("audio/{key}"), data)?;
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    /// blocking, read from a file or a slice
+    pub fn from_tar(reader: impl Read) -> anyhow::Result<Self> {
+        let mut manifest: Option<Manifest> = None;
+        let mut archive = Self {
+            manifest: Manifest::default(),
+            devices: vec![],
+            sessions: vec![],
+            turns: vec![],
+            annotations: vec![],
+            memories: vec![],
+            audio: vec![],
+        };
+
+        for entry in tar::Archive::new(reader).entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.to_string_lossy().to_string();
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            match path.as_str() {
+                "manifest.json" => {
+                    let m: Manifest = serde_json::from_slice(&data)?;
+                    if m.format != FORMAT {
+                        anyhow::bail!("not an {FORMAT}: `{}`", m.format);
+                    }
+                    if m.version > FORMAT_VERSION {
+                        anyhow::bail!(
+                            "archive version {} is newer than {FORMAT_VERSION}",
+                            m.version
+                        );
+                    }
+                    manifest = Some(m);
+                }
+                "devices.jsonl" => archive.devices = from_jsonl(&path, &data)?,
+                "sessions.jsonl" => archive.sessions = from_jsonl(&path, &data)?,
+                "turns.jsonl" => archive.turns = from_jsonl(&path, &data)?,
+                "annotations.jsonl" => archive.annotations = from_jsonl(&path, &data)?,
+                "memories.jsonl" => archive.memories = from_jsonl(&path, &data)?,
+                path => match audio_key(path) {
+                    Some(key) => archive.audio.push((key.to_string(), data.into())),
+                    None => log::warn!("archive: unknown entry `{path}` skipped"),
+                },
+            }
+        }
+
+        archive.manifest = manifest.ok_or_else(|| anyhow::anyhow!("no manifest.json"))?;
+        Ok(archive)
+    }
+
+    /// the devices go through the registry if there is one, so that it sees them
+    pub async fn import(
+        &self,
+        store: &Storage,
+        recordings: &Recordings,
+        registry: Option<&DeviceRegistry>,
+    ) -> anyhow::Result<ImportReport> {
+        let mut report = ImportReport::default();
+
+        for device in &self.devices {
+            match registry {
+                Some(registry) => registry.import(device.clone()).await?,
+                None => store.put_device(device).await?,
+            }
+            report.devices += 1;
+        }
+        for session in &self.sessions {
+            store.put_session(session).await?;
+            report.sessions += 1;
+        }
+
+        let key = |turn: &Turn| serde_json::to_string(turn).unwrap_or_default();
+        let existing = store
+            .turns(self.manifest.device_id.as_deref())
+            .await?
+            .iter()
+            .map(key)
+            .collect::<HashSet<_>>();
+        for turn in &self.turns {
+            if existing.contains(&key(turn)) {
+                report.skipped_turns += 1;
+                continue;
+            }
+            store.append_turn(turn).await?;
+            report.turns += 1;
+        }
+
+        let known_annotations = store
+            .annotations(self.manifest.device_id.as_deref())
+            .await?
+            .into_iter()
+            .map(|a| a.id)
+            .collect::<HashSet<_>>();
+        for annotation in &self.annotations {
+            if known_annotations.contains(&annotation.id) {
+                report.skipped_annotations += 1;
+                continue;
+            }
+            store.add_annotation(annotation).await?;
+            report.annotations += 1;
+        }
+
+        let mut known_memories = HashSet::new();
+        for device_id in self
+            .memories
+            .iter()
+            .map(|m| &m.device_id)
+            .collect::<BTreeSet<_>>()
+        {
+            known_memories.extend(store.memories(device_id).await?.into_iter().map(|m| m.id));
+        }
+        for memory in &self.memories {
+            if known_memories.contains(&memory.id) {
+                report.skipped_memories += 1;
+                continue;
+            }
+            store.add_memory(memory).await?;
+            report.memories += 1;
+        }
+
+        for (key, data) in &self.audio {
+            recordings.put(key, data.clone()).await?;
+            report.audio += 1;
+        }
+        Ok(report)
+    }
+}
+
+#[tokio::test]
+async fn test_archive() {
+    use super::file::FileStore;
+
+    let dir = std::env::temp_dir().join(format!("echokit_archive_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = Storage::File(FileStore::new(&dir.join("a").to_string_lossy(), None));
+    let target = Storage::File(FileStore::new(&dir.join("b").to_string_lossy(), None));
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::create_dir_all(dir.join("b")).unwrap();
+    let recordings = Recordings::Local(dir.join("a/record"));
+    let target_recordings = Recordings::Local(dir.join("b/record"));
+
+    let turn = Turn {
+        session_id: "dev1".to_string(),
+        device_id: Some("dev1".to_string()),
+        time: "2025-01-01T00:00:00+08:00".to_string(),
+        role: crate::ai::llm::Role::User,
+        text: "你好".to_string(),
+    };
+    source.append_turn(&turn).await.unwrap();
+    let memory = Memory {
+        id: "m1".to_string(),
+        device_id: "dev1".to_string(),
+        text: "likes jazz".to_string(),
+        created_at: String::new(),
+    };
+    source.add_memory(&memory).await.unwrap();
+    recordings
+        .put("dev1/recording_1.wav", bytes::Bytes::from_static(b"RIFF"))
+        .await
+        .unwrap();
+
+    let archive = Archive::collect(&source, &recordings, None).await.unwrap();
+    let tar = archive.to_tar().unwrap();
+    let archive = Archive::from_tar(&tar[..]).unwrap();
+    assert_eq!(archive.manifest.version, FORMAT_VERSION);
+    assert_eq!((archive.manifest.turns, archive.manifest.audio), (1, 1));
+
+    let report = archive
+        .import(&target, &target_recordings, None)
+        .await
+        .unwrap();
+    assert_eq!((report.turns, report.memories, report.audio), (1, 1, 1));
+    let report = archive
+        .import(&target, &target_recordings, None)
+        .await
+        .unwrap();
+    assert_eq!((report.skipped_turns, report.skipped_memories), (1, 1));
+    assert_eq!(target.turns(None).await.unwrap(), [turn]);
+    assert_eq!(
+        &target_recordings.get("dev1/recording_1.wav").await.unwrap()[..],
+        b"RIFF"
+    );
+
+    assert_eq!(audio_key("audio/dev1/a.wav"), Some("dev1/a.wav"));
+    assert_eq!(audio_key("audio/../a.wav"), None);
+    assert_eq!(audio_key("audio/dev1/x/a.wav"), None);
+    let _ = std::fs::remove_dir_all(dir);
+}
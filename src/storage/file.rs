@@ -0,0 +1,506 @@
+//! Json files in a directory, the default storage.
+//!
+//! - `devices.json` the registry, or `registry.path`
+//! - `sessions.jsonl`, `history.jsonl` appended, a session is written again when it ends
+//! - `annotations.jsonl` appended, rewritten when one is deleted
+//! - `metrics.jsonl` appended, rewritten by a rollup
+//! - `daily_stats.json`
+//! - `tenant_usage.json`
+//! - `prompts.json`
+//! - `memories.json`
+//! - `reminders.json`
+
+use std::path::{Path, PathBuf};
+
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use super::{
+    Annotation, DailyStats, DeletedData, Memory, Reminder, SessionRecord, Store, TenantDay, Turn,
+    TurnMetric,
+};
+use crate::services::{prompts::PromptsInfo, registry::Device};
+
+#[derive(Debug)]
+pub struct FileStore {
+    devices_path: PathBuf,
+    sessions_path: PathBuf,
+    turns_path: PathBuf,
+    annotations_path: PathBuf,
+    metrics_path: PathBuf,
+    daily_stats_path: PathBuf,
+    tenant_usage_path: PathBuf,
+    prompts_path: PathBuf,
+    memories_path: PathBuf,
+    reminders_path: PathBuf,
+    /// the rewritten files are read, changed and written under this lock
+    lock: Mutex<()>,
+}
+
+async fn append_line(path: &Path, value: &impl serde::Serialize) -> anyhow::Result<()> {
+    let line = serde_json::to_string(value)? + "\n";
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+/// the lines of a jsonl file, the bad ones are skipped
+async fn read_lines<T: serde::de::DeserializeOwned>(path: &Path) -> anyhow::Result<Vec<T>> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let values = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("{} bad line: {e}", path.display());
+                None
+            }
+        })
+        .collect();
+    Ok(values)
+}
+
+/// the lines written before the ids get one from their session and their time
+async fn read_metrics(path: &Path) -> anyhow::Result<Vec<TurnMetric>> {
+    let mut metrics = read_lines::<TurnMetric>(path).await?;
+    for m in metrics.iter_mut().filter(|m| m.id.is_empty()) {
+        m.id = format!("{}@{}", m.session_id, m.time);
+    }
+    Ok(metrics)
+}
+
+async fn write_lines(path: &Path, values: &[impl serde::Serialize]) -> anyhow::Result<()> {
+    let mut content = String::new();
+    for value in values {
+        content += &(serde_json::to_string(value)? + "\n");
+    }
+    tokio::fs::write(path, content).await?;
+    Ok(())
+}
+
+async fn read_json<T: serde::de::DeserializeOwned + Default>(path: &Path) -> anyhow::Result<T> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn write_json(path: &Path, value: &impl serde::Serialize) -> anyhow::Result<()> {
+    tokio::fs::write(path, serde_json::to_vec_pretty(value)?).await?;
+    Ok(())
+}
+
+impl FileStore {
+    pub fn new(dir: &str, devices_path: Option<&str>) -> Self {
+        let dir = Path::new(dir);
+        Self {
+            devices_path: devices_path
+                .map(PathBuf::from)
+                .unwrap_or_else(|| dir.join("devices.json")),
+            sessions_path: dir.join("sessions.jsonl"),
+            turns_path: dir.join("history.jsonl"),
+            annotations_path: dir.join("annotations.jsonl"),
+            metrics_path: dir.join("metrics.jsonl"),
+            daily_stats_path: dir.join("daily_stats.json"),
+            tenant_usage_path: dir.join("tenant_usage.json"),
+            prompts_path: dir.join("prompts.json"),
+            memories_path: dir.join("memories.json"),
+            reminders_path: dir.join("reminders.json"),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Store for FileStore {
+    async fn put_session(&self, session: &SessionRecord) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        append_line(&self.sessions_path, session).await
+    }
+
+    async fn sessions(&self, device_id: Option<&str>) -> anyhow::Result<Vec<SessionRecord>> {
+        let mut sessions: Vec<SessionRecord> = vec![];
+        for session in read_lines::<SessionRecord>(&self.sessions_path).await? {
+            if device_id.is_some_and(|id| session.device_id.as_deref() != Some(id)) {
+                continue;
+            }
+            // the last line of a session wins
+            match sessions
+                .iter_mut()
+                .find(|s| s.id == session.id && s.started_at == session.started_at)
+            {
+                Some(s) => *s = session,
+                None => sessions.push(session),
+            }
+        }
+        Ok(sessions)
+    }
+
+    async fn append_turn(&self, turn: &Turn) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        append_line(&self.turns_path, turn).await
+    }
+
+    async fn turns(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Turn>> {
+        let mut turns = read_lines::<Turn>(&self.turns_path).await?;
+        if let Some(device_id) = device_id {
+            turns.retain(|t| t.device_id.as_deref() == Some(device_id));
+        }
+        Ok(turns)
+    }
+
+    async fn purge_transcripts(
+        &self,
+        before: &chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u64> {
+        let _lock = self.lock.lock().await;
+        let old = |time: &str| {
+            chrono::DateTime::parse_from_rfc3339(time).is_ok_and(|time| time < *before)
+        };
+
+        let mut turns = read_lines::<Turn>(&self.turns_path).await?;
+        let len = turns.len();
+        turns.retain(|t| !old(&t.time));
+        let purged = len - turns.len();
+        if purged > 0 {
+            write_lines(&self.turns_path, &turns).await?;
+        }
+
+        let mut sessions = read_lines::<SessionRecord>(&self.sessions_path).await?;
+        let len = sessions.len();
+        sessions.retain(|s| !old(&s.started_at));
+        if sessions.len() < len {
+            write_lines(&self.sessions_path, &sessions).await?;
+        }
+        Ok(purged as u64)
+    }
+
+    async fn add_annotation(&self, annotation: &Annotation) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        append_line(&self.annotations_path, annotation).await
+    }
+
+    async fn annotations(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Annotation>> {
+        let mut annotations = read_lines::<Annotation>(&self.annotations_path).await?;
+        if let Some(device_id) = device_id {
+            annotations.retain(|a| a.device_id.as_deref() == Some(device_id));
+        }
+        Ok(annotations)
+    }
+
+    async fn delete_annotation(&self, id: &str) -> anyhow::Result<bool> {
+        let _lock = self.lock.lock().await;
+        let mut annotations = read_lines::<Annotation>(&self.annotations_path).await?;
+        let len = annotations.len();
+        annotations.retain(|a| a.id != id);
+        if annotations.len() == len {
+            return Ok(false);
+        }
+        write_lines(&self.annotations_path, &annotations).await?;
+        Ok(true)
+    }
+
+    async fn append_turn_metric(&self, metric: &TurnMetric) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        let metric = TurnMetric {
+            id: uuid::Uuid::new_v4().to_string(),
+            ..metric.clone()
+        };
+        append_line(&self.metrics_path, &metric).await
+    }
+
+    async fn turn_metrics(
+        &self,
+        before: Option<&chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<Vec<TurnMetric>> {
+        let mut metrics = read_metrics(&self.metrics_path).await?;
+        if let Some(before) = before {
+            metrics.retain(|m| {
+                chrono::DateTime::parse_from_rfc3339(&m.time).is_ok_and(|time| time < *before)
+            });
+        }
+        Ok(metrics)
+    }
+
+    async fn delete_turn_metrics(&self, ids: &[String]) -> anyhow::Result<u64> {
+        let _lock = self.lock.lock().await;
+        let ids = ids.iter().collect::<std::collections::HashSet<_>>();
+        let mut metrics = read_metrics(&self.metrics_path).await?;
+        let len = metrics.len();
+        metrics.retain(|m| !ids.contains(&m.id));
+        let deleted = len - metrics.len();
+        if deleted > 0 {
+            write_lines(&self.metrics_path, &metrics).await?;
+        }
+        Ok(deleted as u64)
+    }
+
+    async fn put_daily_stats(&self, stats: &DailyStats) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut all: std::collections::BTreeMap<String, DailyStats> =
+            read_json(&self.daily_stats_path).await?;
+        all.insert(stats.day.clone(), stats.clone());
+        write_json(&self.daily_stats_path, &all).await
+    }
+
+    async fn daily_stats(&self) -> anyhow::Result<Vec<DailyStats>> {
+        let all: std::collections::BTreeMap<String, DailyStats> =
+            read_json(&self.daily_stats_path).await?;
+        Ok(all.into_values().collect())
+    }
+
+    async fn add_tenant_usage(&self, usage: &TenantDay) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut all: std::collections::BTreeMap<String, TenantDay> =
+            read_json(&self.tenant_usage_path).await?;
+        let day = all
+            .entry(format!("{}/{}", usage.tenant, usage.day))
+            .or_insert_with(|| TenantDay {
+                tenant: usage.tenant.clone(),
+                day: usage.day.clone(),
+                ..Default::default()
+            });
+        day.turns += usage.turns;
+        day.usage.add(&usage.usage);
+        write_json(&self.tenant_usage_path, &all).await
+    }
+
+    async fn tenant_usage(&self, tenant: &str, day: &str) -> anyhow::Result<Option<TenantDay>> {
+        let mut all: std::collections::BTreeMap<String, TenantDay> =
+            read_json(&self.tenant_usage_path).await?;
+        Ok(all.remove(&format!("{tenant}/{day}")))
+    }
+
+    async fn put_prompts(&self, prompts: &PromptsInfo) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        write_json(&self.prompts_path, prompts).await
+    }
+
+    async fn prompts(&self) -> anyhow::Result<Option<PromptsInfo>> {
+        read_json(&self.prompts_path).await
+    }
+
+    async fn devices(&self) -> anyhow::Result<Vec<Device>> {
+        let devices: std::collections::HashMap<String, Device> =
+            read_json(&self.devices_path).await?;
+        Ok(devices.into_values().collect())
+    }
+
+    async fn put_device(&self, device: &Device) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut devices: std::collections::HashMap<String, Device> =
+            read_json(&self.devices_path).await?;
+        devices.insert(device.device_id.clone(), device.clone());
+        write_json(&self.devices_path, &devices).await
+    }
+
+    async fn add_memory(&self, memory: &Memory) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut memories: Vec<Memory> = read_json(&self.memories_path).await?;
+        memories.push(memory.clone());
+        write_json(&self.memories_path, &memories).await
+    }
+
+    async fn memories(&self, device_id: &str) -> anyhow::Result<Vec<Memory>> {
+        let mut memories: Vec<Memory> = read_json(&self.memories_path).await?;
+        memories.retain(|m| m.device_id == device_id);
+        Ok(memories)
+    }
+
+    async fn delete_memory(&self, id: &str) -> anyhow::Result<bool> {
+        let _lock = self.lock.lock().await;
+        let mut memories: Vec<Memory> = read_json(&self.memories_path).await?;
+        let len = memories.len();
+        memories.retain(|m| m.id != id);
+        if memories.len() == len {
+            return Ok(false);
+        }
+        write_json(&self.memories_path, &memories).await?;
+        Ok(true)
+    }
+
+    async fn put_reminder(&self, reminder: &Reminder) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut reminders: Vec<Reminder> = read_json(&self.reminders_path).await?;
+        match reminders.iter_mut().find(|r| r.id == reminder.id) {
+            Some(r) => *r = reminder.clone(),
+            None => reminders.push(reminder.clone()),
+        }
+        write_json(&self.reminders_path, &reminders).await
+    }
+
+    async fn reminders(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Reminder>> {
+        let mut reminders: Vec<Reminder> = read_json(&self.reminders_path).await?;
+        if let Some(device_id) = device_id {
+            reminders.retain(|r| r.device_id == device_id);
+        }
+        Ok(reminders)
+    }
+
+    async fn delete_reminder(&self, id: &str) -> anyhow::Result<bool> {
+        let _lock = self.lock.lock().await;
+        let mut reminders: Vec<Reminder> = read_json(&self.reminders_path).await?;
+        let len = reminders.len();
+        reminders.retain(|r| r.id != id);
+        if reminders.len() == len {
+            return Ok(false);
+        }
+        write_json(&self.reminders_path, &reminders).await?;
+        Ok(true)
+    }
+
+    async fn delete_device_data(&self, device_id: &str) -> anyhow::Result<DeletedData> {
+        let _lock = self.lock.lock().await;
+        let mut deleted = DeletedData::default();
+
+        let (sessions, removed): (Vec<SessionRecord>, Vec<_>) =
+            read_lines::<SessionRecord>(&self.sessions_path)
+                .await?
+                .into_iter()
+                .partition(|s| s.device_id.as_deref() != Some(device_id));
+        if !removed.is_empty() {
+            write_lines(&self.sessions_path, &sessions).await?;
+            // a session has a line per write
+            let ids = removed.iter().map(|s| (&s.id, &s.started_at));
+            deleted.sessions = ids.collect::<std::collections::HashSet<_>>().len() as u64;
+        }
+
+        let mut turns = read_lines::<Turn>(&self.turns_path).await?;
+        let len = turns.len();
+        turns.retain(|t| t.device_id.as_deref() != Some(device_id));
+        if turns.len() < len {
+            write_lines(&self.turns_path, &turns).await?;
+            deleted.turns = (len - turns.len()) as u64;
+        }
+
+        let mut annotations = read_lines::<Annotation>(&self.annotations_path).await?;
+        let len = annotations.len();
+        annotations.retain(|a| a.device_id.as_deref() != Some(device_id));
+        if annotations.len() < len {
+            write_lines(&self.annotations_path, &annotations).await?;
+            deleted.annotations = (len - annotations.len()) as u64;
+        }
+
+        let mut metrics = read_lines::<TurnMetric>(&self.metrics_path).await?;
+        let len = metrics.len();
+        metrics.retain(|m| m.device_id.as_deref() != Some(device_id));
+        if metrics.len() < len {
+            write_lines(&self.metrics_path, &metrics).await?;
+            deleted.metrics = (len - metrics.len()) as u64;
+        }
+
+        let mut memories: Vec<Memory> = read_json(&self.memories_path).await?;
+        let len = memories.len();
+        memories.retain(|m| m.device_id != device_id);
+        if memories.len() < len {
+            write_json(&self.memories_path, &memories).await?;
+            deleted.memories = (len - memories.len()) as u64;
+        }
+
+        let mut reminders: Vec<Reminder> = read_json(&self.reminders_path).await?;
+        let len = reminders.len();
+        reminders.retain(|r| r.device_id != device_id);
+        if reminders.len() < len {
+            write_json(&self.reminders_path, &reminders).await?;
+            deleted.reminders = (len - reminders.len()) as u64;
+        }
+        Ok(deleted)
+    }
+}
+
+#[tokio::test]
+async fn test_file_store() {
+    let dir = std::env::temp_dir().join(format!("echokit_store_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let store = FileStore::new(&dir.to_string_lossy(), None);
+
+    let mut session = SessionRecord {
+        id: "dev1".to_string(),
+        kind: "ws".to_string(),
+        device_id: Some("dev1".to_string()),
+        started_at: "2025-01-01T00:00:00+08:00".to_string(),
+        ended_at: None,
+        turns: 0,
+    };
+    store.put_session(&session).await.unwrap();
+    session.ended_at = Some("2025-01-01T00:05:00+08:00".to_string());
+    session.turns = 3;
+    store.put_session(&session).await.unwrap();
+    assert_eq!(store.sessions(Some("dev1")).await.unwrap(), [session]);
+    assert!(store.sessions(Some("dev2")).await.unwrap().is_empty());
+
+    let turn = Turn {
+        session_id: "dev1".to_string(),
+        device_id: Some("dev1".to_string()),
+        time: String::new(),
+        role: crate::ai::llm::Role::User,
+        text: "你好".to_string(),
+    };
+    store.append_turn(&turn).await.unwrap();
+    assert_eq!(store.turns(None).await.unwrap(), [turn]);
+
+    let annotation = Annotation {
+        id: "a1".to_string(),
+        session_id: "dev1".to_string(),
+        device_id: Some("dev1".to_string()),
+        turn_time: String::new(),
+        label: "bad_asr".to_string(),
+        note: None,
+        source: "admin".to_string(),
+        created_at: String::new(),
+    };
+    store.add_annotation(&annotation).await.unwrap();
+    assert_eq!(store.annotations(Some("dev1")).await.unwrap(), [annotation]);
+    assert!(store.delete_annotation("a1").await.unwrap());
+    assert!(store.annotations(None).await.unwrap().is_empty());
+
+    let memory = Memory {
+        id: "m1".to_string(),
+        device_id: "dev1".to_string(),
+        text: "likes jazz".to_string(),
+        created_at: String::new(),
+    };
+    store.add_memory(&memory).await.unwrap();
+    assert_eq!(store.memories("dev1").await.unwrap(), [memory.clone()]);
+    assert!(store.delete_memory("m1").await.unwrap());
+    assert!(!store.delete_memory("m1").await.unwrap());
+
+    let mut reminder = Reminder {
+        id: "r1".to_string(),
+        device_id: "dev1".to_string(),
+        text: "drink water".to_string(),
+        due_at: "2025-01-01T08:00:00+08:00".to_string(),
+        cron: Some("0 8 * * *".to_string()),
+        created_at: String::new(),
+    };
+    store.put_reminder(&reminder).await.unwrap();
+    reminder.due_at = "2025-01-02T08:00:00+08:00".to_string();
+    store.put_reminder(&reminder).await.unwrap();
+    assert_eq!(store.reminders(Some("dev1")).await.unwrap(), [reminder]);
+    assert!(store.reminders(Some("dev2")).await.unwrap().is_empty());
+
+    store.add_memory(&memory).await.unwrap();
+    let deleted = store.delete_device_data("dev1").await.unwrap();
+    assert_eq!(
+        (
+            deleted.sessions,
+            deleted.turns,
+            deleted.memories,
+            deleted.reminders
+        ),
+        (1, 1, 1, 1)
+    );
+    assert!(store.turns(Some("dev1")).await.unwrap().is_empty());
+    assert!(store.sessions(Some("dev1")).await.unwrap().is_empty());
+
+    assert!(store.devices().await.unwrap().is_empty());
+    let _ = std::fs::remove_dir_all(dir);
+}
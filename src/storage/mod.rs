@@ -0,0 +1,473 @@
+//! Persistence of the sessions, transcripts, annotations, analytics, devices, memories,
+//! reminders and the edited prompts.
+//!
+//! `[storage]` selects the backend:
+//! - `backend = "file"` (default) json files in `dir`
+//! - `backend = "sqlite"` one database at `path`, with the `sqlite` feature
+//! - `backend = "postgres"` the database at `url`, with the `postgres` feature
+//!
+//! every persistent feature goes through [`Store`], a new backend only implements it
+//! and gets a variant of [`Storage`]. the audio recordings are blobs, kept apart by
+//! [`recordings::Recordings`].
+
+use crate::{
+    ai::llm::Role,
+    config::StorageConfig,
+    services::{prompts::PromptsInfo, registry::Device},
+};
+
+pub mod archive;
+pub mod file;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod recordings;
+pub mod s3;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// a session of one of the websocket endpoints, the same `id` comes back when a
+/// device reconnects
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SessionRecord {
+    pub id: String,
+    /// `ws`, `realtime`, `chat` or `device`
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    pub started_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ended_at: Option<String>,
+    #[serde(default)]
+    pub turns: usize,
+}
+
+/// one transcript line of a session
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Turn {
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    pub time: String,
+    /// `user` or `assistant`
+    pub role: Role,
+    pub text: String,
+}
+
+/// a fact kept about the user of a device
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Memory {
+    pub id: String,
+    pub device_id: String,
+    pub text: String,
+    pub created_at: String,
+}
+
+/// something to tell the user of a device when it is due
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Reminder {
+    pub id: String,
+    pub device_id: String,
+    pub text: String,
+    /// rfc3339, the next occurrence of a recurring reminder
+    pub due_at: String,
+    /// `minute hour day month weekday`, none for a one-off reminder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    pub created_at: String,
+}
+
+/// a label put on a turn by an operator or by the client, `bad_asr`, `thumbs_up`...
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Annotation {
+    pub id: String,
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// the `time` of the turn in the session
+    pub turn_time: String,
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// `admin` or `client`
+    pub source: String,
+    pub created_at: String,
+}
+
+/// the timings of a turn, until it is rolled into a [`DailyStats`]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TurnMetric {
+    /// set by the store, a uuid in the files and the `seq` in sql
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// the kind of the session
+    pub kind: String,
+    /// the start of the turn, when the transcript is ready
+    pub time: String,
+    /// none for a text answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_audio_ms: Option<u64>,
+    pub response_ms: u64,
+    pub error: bool,
+    /// the tool called by the llm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub intent: Option<String>,
+    #[serde(default)]
+    pub usage: Usage,
+}
+
+/// what a turn or a session used of the providers, see [`crate::services::costs`]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Usage {
+    /// estimated from the text, the llm streams report none
+    pub llm_input_tokens: u64,
+    pub llm_output_tokens: u64,
+    pub tts_chars: u64,
+    /// of the audio transcribed
+    pub asr_ms: u64,
+    /// estimated with the prices of `[costs]` when it was used
+    pub cost: f64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.llm_input_tokens += other.llm_input_tokens;
+        self.llm_output_tokens += other.llm_output_tokens;
+        self.tts_chars += other.tts_chars;
+        self.asr_ms += other.asr_ms;
+        self.cost += other.cost;
+    }
+}
+
+/// the usage of a device in the stats of a day
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DeviceUsage {
+    pub device_id: String,
+    pub turns: u64,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Percentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+/// the count of the durations in ms of each bucket, a bucket keeps two significant digits.
+/// the percentiles of a day rolled twice come from the sum of its histograms
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Histogram(pub std::collections::BTreeMap<u64, u64>);
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct IntentCount {
+    pub intent: String,
+    pub count: u64,
+}
+
+/// the turns of a day, local time
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DailyStats {
+    /// `2025-01-31`
+    pub day: String,
+    pub turns: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    /// of the turns answered with audio
+    pub first_audio_ms: Percentiles,
+    pub response_ms: Percentiles,
+    /// none in the stats rolled before the histograms
+    #[serde(default)]
+    pub first_audio_histogram: Histogram,
+    #[serde(default)]
+    pub response_histogram: Histogram,
+    /// most common first
+    pub intents: Vec<IntentCount>,
+    #[serde(default)]
+    pub usage: Usage,
+    /// the turns of the devices, most expensive first
+    #[serde(default)]
+    pub devices: Vec<DeviceUsage>,
+}
+
+/// the usage of a tenant on a day, local time, see [`crate::services::tenants`]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TenantDay {
+    pub tenant: String,
+    /// `2025-01-31`
+    pub day: String,
+    /// responses generated
+    pub turns: u64,
+    pub usage: Usage,
+}
+
+/// what [`Store::delete_device_data`] deleted
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct DeletedData {
+    pub sessions: u64,
+    pub turns: u64,
+    pub memories: u64,
+    pub annotations: u64,
+    pub reminders: u64,
+    /// of the turns not rolled yet, the daily stats have no device
+    pub metrics: u64,
+}
+
+/// the `role` column of the sql backends
+#[cfg_attr(not(any(feature = "sqlite", feature = "postgres")), allow(dead_code))]
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::System => "system",
+        Role::User => "user",
+        Role::Assistant => "assistant",
+        Role::Tool => "tool",
+    }
+}
+
+#[cfg_attr(not(any(feature = "sqlite", feature = "postgres")), allow(dead_code))]
+fn parse_role(role: &str) -> anyhow::Result<Role> {
+    match role {
+        "system" => Ok(Role::System),
+        "user" => Ok(Role::User),
+        "assistant" => Ok(Role::Assistant),
+        "tool" => Ok(Role::Tool),
+        _ => anyhow::bail!("bad role `{role}`"),
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait Store: Send + Sync {
+    /// insert or replace the session with the same `id` and `started_at`
+    async fn put_session(&self, session: &SessionRecord) -> anyhow::Result<()>;
+    /// oldest first
+    async fn sessions(&self, device_id: Option<&str>) -> anyhow::Result<Vec<SessionRecord>>;
+
+    async fn append_turn(&self, turn: &Turn) -> anyhow::Result<()>;
+    /// oldest first
+    async fn turns(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Turn>>;
+    /// delete the turns older than `before` and the sessions started before it,
+    /// the number of turns deleted
+    async fn purge_transcripts(
+        &self,
+        before: &chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u64>;
+
+    async fn add_annotation(&self, annotation: &Annotation) -> anyhow::Result<()>;
+    /// oldest first
+    async fn annotations(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Annotation>>;
+    /// false if there is no such annotation
+    async fn delete_annotation(&self, id: &str) -> anyhow::Result<bool>;
+
+    async fn append_turn_metric(&self, metric: &TurnMetric) -> anyhow::Result<()>;
+    /// the metrics not rolled yet of the turns started before `before`, oldest first
+    async fn turn_metrics(
+        &self,
+        before: Option<&chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<Vec<TurnMetric>>;
+    /// delete the metrics of these [`TurnMetric::id`], once rolled
+    async fn delete_turn_metrics(&self, ids: &[String]) -> anyhow::Result<u64>;
+    /// insert or replace the stats of the same `day`
+    async fn put_daily_stats(&self, stats: &DailyStats) -> anyhow::Result<()>;
+    /// oldest first
+    async fn daily_stats(&self) -> anyhow::Result<Vec<DailyStats>>;
+
+    /// add `usage.turns` and `usage.usage` to the usage of the tenant on the day, the
+    /// instances sharing the store add up
+    async fn add_tenant_usage(&self, usage: &TenantDay) -> anyhow::Result<()>;
+    async fn tenant_usage(&self, tenant: &str, day: &str) -> anyhow::Result<Option<TenantDay>>;
+
+    /// the prompts of the last `PUT /v1/prompts`, see [`crate::services::prompts`]
+    async fn put_prompts(&self, prompts: &PromptsInfo) -> anyhow::Result<()>;
+    async fn prompts(&self) -> anyhow::Result<Option<PromptsInfo>>;
+
+    async fn devices(&self) -> anyhow::Result<Vec<Device>>;
+    /// insert or replace the device with the same `device_id`
+    async fn put_device(&self, device: &Device) -> anyhow::Result<()>;
+
+    async fn add_memory(&self, memory: &Memory) -> anyhow::Result<()>;
+    /// oldest first
+    async fn memories(&self, device_id: &str) -> anyhow::Result<Vec<Memory>>;
+    /// false if there is no such memory
+    async fn delete_memory(&self, id: &str) -> anyhow::Result<bool>;
+
+    /// insert or replace the reminder with the same `id`
+    async fn put_reminder(&self, reminder: &Reminder) -> anyhow::Result<()>;
+    /// oldest first
+    async fn reminders(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Reminder>>;
+    /// false if there is no such reminder
+    async fn delete_reminder(&self, id: &str) -> anyhow::Result<bool>;
+
+    /// delete the sessions, turns, annotations, memories and reminders of a device, the
+    /// device itself is kept
+    async fn delete_device_data(&self, device_id: &str) -> anyhow::Result<DeletedData>;
+}
+
+/// the backend of `[storage]`
+#[derive(Debug)]
+pub enum Storage {
+    File(file::FileStore),
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::SqliteStore),
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::PostgresStore),
+}
+
+impl Storage {
+    /// `devices_path` is `registry.path`, kept by the file backend for the existing
+    /// registries
+    pub async fn open(config: &StorageConfig, devices_path: Option<&str>) -> anyhow::Result<Self> {
+        match config {
+            StorageConfig::File { dir } => {
+                Ok(Storage::File(file::FileStore::new(dir, devices_path)))
+            }
+            #[cfg(feature = "sqlite")]
+            StorageConfig::Sqlite { path } => {
+                Ok(Storage::Sqlite(sqlite::SqliteStore::open(path).await?))
+            }
+            #[cfg(not(feature = "sqlite"))]
+            StorageConfig::Sqlite { .. } => {
+                anyhow::bail!("the sqlite storage needs the `sqlite` feature")
+            }
+            #[cfg(feature = "postgres")]
+            StorageConfig::Postgres {
+                url,
+                max_connections,
+            } => Ok(Storage::Postgres(
+                postgres::PostgresStore::open(url, *max_connections).await?,
+            )),
+            #[cfg(not(feature = "postgres"))]
+            StorageConfig::Postgres { .. } => {
+                anyhow::bail!("the postgres storage needs the `postgres` feature")
+            }
+        }
+    }
+}
+
+/// `store!(self, s => s.method(args).await)` on every backend
+macro_rules! store {
+    ($self:ident, $s:ident => $call:expr) => {
+        match $self {
+            Storage::File($s) => $call,
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite($s) => $call,
+            #[cfg(feature = "postgres")]
+            Storage::Postgres($s) => $call,
+        }
+    };
+}
+
+impl Store for Storage {
+    async fn put_session(&self, session: &SessionRecord) -> anyhow::Result<()> {
+        store!(self, s => s.put_session(session).await)
+    }
+
+    async fn sessions(&self, device_id: Option<&str>) -> anyhow::Result<Vec<SessionRecord>> {
+        store!(self, s => s.sessions(device_id).await)
+    }
+
+    async fn append_turn(&self, turn: &Turn) -> anyhow::Result<()> {
+        store!(self, s => s.append_turn(turn).await)
+    }
+
+    async fn turns(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Turn>> {
+        store!(self, s => s.turns(device_id).await)
+    }
+
+    async fn purge_transcripts(
+        &self,
+        before: &chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u64> {
+        store!(self, s => s.purge_transcripts(before).await)
+    }
+
+    async fn add_annotation(&self, annotation: &Annotation) -> anyhow::Result<()> {
+        store!(self, s => s.add_annotation(annotation).await)
+    }
+
+    async fn annotations(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Annotation>> {
+        store!(self, s => s.annotations(device_id).await)
+    }
+
+    async fn delete_annotation(&self, id: &str) -> anyhow::Result<bool> {
+        store!(self, s => s.delete_annotation(id).await)
+    }
+
+    async fn append_turn_metric(&self, metric: &TurnMetric) -> anyhow::Result<()> {
+        store!(self, s => s.append_turn_metric(metric).await)
+    }
+
+    async fn turn_metrics(
+        &self,
+        before: Option<&chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<Vec<TurnMetric>> {
+        store!(self, s => s.turn_metrics(before).await)
+    }
+
+    async fn delete_turn_metrics(&self, ids: &[String]) -> anyhow::Result<u64> {
+        store!(self, s => s.delete_turn_metrics(ids).await)
+    }
+
+    async fn put_daily_stats(&self, stats: &DailyStats) -> anyhow::Result<()> {
+        store!(self, s => s.put_daily_stats(stats).await)
+    }
+
+    async fn daily_stats(&self) -> anyhow::Result<Vec<DailyStats>> {
+        store!(self, s => s.daily_stats().await)
+    }
+
+    async fn add_tenant_usage(&self, usage: &TenantDay) -> anyhow::Result<()> {
+        store!(self, s => s.add_tenant_usage(usage).await)
+    }
+
+    async fn tenant_usage(&self, tenant: &str, day: &str) -> anyhow::Result<Option<TenantDay>> {
+        store!(self, s => s.tenant_usage(tenant, day).await)
+    }
+
+    async fn put_prompts(&self, prompts: &PromptsInfo) -> anyhow::Result<()> {
+        store!(self, s => s.put_prompts(prompts).await)
+    }
+
+    async fn prompts(&self) -> anyhow::Result<Option<PromptsInfo>> {
+        store!(self, s => s.prompts().await)
+    }
+
+    async fn devices(&self) -> anyhow::Result<Vec<Device>> {
+        store!(self, s => s.devices().await)
+    }
+
+    async fn put_device(&self, device: &Device) -> anyhow::Result<()> {
+        store!(self, s => s.put_device(device).await)
+    }
+
+    async fn add_memory(&self, memory: &Memory) -> anyhow::Result<()> {
+        store!(self, s => s.add_memory(memory).await)
+    }
+
+    async fn memories(&self, device_id: &str) -> anyhow::Result<Vec<Memory>> {
+        store!(self, s => s.memories(device_id).await)
+    }
+
+    async fn delete_memory(&self, id: &str) -> anyhow::Result<bool> {
+        store!(self, s => s.delete_memory(id).await)
+    }
+
+    async fn put_reminder(&self, reminder: &Reminder) -> anyhow::Result<()> {
+        store!(self, s => s.put_reminder(reminder).await)
+    }
+
+    async fn reminders(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Reminder>> {
+        store!(self, s => s.reminders(device_id).await)
+    }
+
+    async fn delete_reminder(&self, id: &str) -> anyhow::Result<bool> {
+        store!(self, s => s.delete_reminder(id).await)
+    }
+
+    async fn delete_device_data(&self, device_id: &str) -> anyhow::Result<DeletedData> {
+        store!(self, s => s.delete_device_data(device_id).await)
+    }
+}
@@ -0,0 +1,514 @@
+//! Postgres storage, `[storage] backend = "postgres"` with the `postgres` feature.
+//!
+//! several servers can share one database. the migrations of `migrations/postgres`
+//! are compiled in and run when the storage opens.
+
+use sqlx::{postgres::PgPoolOptions, PgPool, Row};
+
+use super::{
+    Annotation, DailyStats, DeletedData, Memory, Reminder, SessionRecord, Store, TenantDay, Turn,
+    TurnMetric,
+};
+use crate::services::{prompts::PromptsInfo, registry::Device};
+
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+fn turn_from_row(row: &sqlx::postgres::PgRow) -> anyhow::Result<Turn> {
+    Ok(Turn {
+        session_id: row.try_get("session_id")?,
+        device_id: row.try_get("device_id")?,
+        time: row.try_get("time")?,
+        role: super::parse_role(row.try_get("role")?)?,
+        text: row.try_get("text")?,
+    })
+}
+
+impl PostgresStore {
+    pub async fn open(url: &str, max_connections: u32) -> anyhow::Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(url)
+            .await?;
+        sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+impl Store for PostgresStore {
+    async fn put_session(&self, session: &SessionRecord) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO sessions (id, started_at, kind, device_id, ended_at, turns)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (id, started_at) DO UPDATE
+             SET kind = $3, device_id = $4, ended_at = $5, turns = $6",
+        )
+        .bind(&session.id)
+        .bind(&session.started_at)
+        .bind(&session.kind)
+        .bind(&session.device_id)
+        .bind(&session.ended_at)
+        .bind(session.turns as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn sessions(&self, device_id: Option<&str>) -> anyhow::Result<Vec<SessionRecord>> {
+        let rows = sqlx::query(
+            "SELECT id, kind, device_id, started_at, ended_at, turns FROM sessions
+             WHERE $1::TEXT IS NULL OR device_id = $1 ORDER BY started_at, id",
+        )
+        .bind(device_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(SessionRecord {
+                    id: row.try_get("id")?,
+                    kind: row.try_get("kind")?,
+                    device_id: row.try_get("device_id")?,
+                    started_at: row.try_get("started_at")?,
+                    ended_at: row.try_get("ended_at")?,
+                    turns: row.try_get::<i64, _>("turns")? as usize,
+                })
+            })
+            .collect()
+    }
+
+    async fn append_turn(&self, turn: &Turn) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO turns (session_id, device_id, time, role, text)
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&turn.session_id)
+        .bind(&turn.device_id)
+        .bind(&turn.time)
+        .bind(super::role_name(turn.role))
+        .bind(&turn.text)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn turns(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Turn>> {
+        let rows = sqlx::query(
+            "SELECT session_id, device_id, time, role, text FROM turns
+             WHERE $1::TEXT IS NULL OR device_id = $1 ORDER BY seq",
+        )
+        .bind(device_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(turn_from_row).collect()
+    }
+
+    async fn purge_transcripts(
+        &self,
+        before: &chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u64> {
+        let before = before.to_rfc3339();
+        let r = sqlx::query("DELETE FROM turns WHERE time::timestamptz < $1::timestamptz")
+            .bind(&before)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("DELETE FROM sessions WHERE started_at::timestamptz < $1::timestamptz")
+            .bind(&before)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn add_annotation(&self, annotation: &Annotation) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO annotations
+             (id, session_id, device_id, turn_time, label, note, source, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(&annotation.id)
+        .bind(&annotation.session_id)
+        .bind(&annotation.device_id)
+        .bind(&annotation.turn_time)
+        .bind(&annotation.label)
+        .bind(&annotation.note)
+        .bind(&annotation.source)
+        .bind(&annotation.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn annotations(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Annotation>> {
+        let rows = sqlx::query(
+            "SELECT id, session_id, device_id, turn_time, label, note, source, created_at
+             FROM annotations WHERE $1::TEXT IS NULL OR device_id = $1 ORDER BY seq",
+        )
+        .bind(device_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Annotation {
+                    id: row.try_get("id")?,
+                    session_id: row.try_get("session_id")?,
+                    device_id: row.try_get("device_id")?,
+                    turn_time: row.try_get("turn_time")?,
+                    label: row.try_get("label")?,
+                    note: row.try_get("note")?,
+                    source: row.try_get("source")?,
+                    created_at: row.try_get("created_at")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_annotation(&self, id: &str) -> anyhow::Result<bool> {
+        let r = sqlx::query("DELETE FROM annotations WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn append_turn_metric(&self, metric: &TurnMetric) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO turn_metrics
+             (session_id, device_id, kind, time, first_audio_ms, response_ms, error, intent, usage)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(&metric.session_id)
+        .bind(&metric.device_id)
+        .bind(&metric.kind)
+        .bind(&metric.time)
+        .bind(metric.first_audio_ms.map(|ms| ms as i64))
+        .bind(metric.response_ms as i64)
+        .bind(metric.error)
+        .bind(&metric.intent)
+        .bind(serde_json::to_value(&metric.usage)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn turn_metrics(
+        &self,
+        before: Option<&chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<Vec<TurnMetric>> {
+        let rows = sqlx::query(
+            "SELECT seq, session_id, device_id, kind, time, first_audio_ms, response_ms, error,
+             intent, usage FROM turn_metrics
+             WHERE $1::text IS NULL OR time::timestamptz < $1::timestamptz ORDER BY seq",
+        )
+        .bind(before.map(|before| before.to_rfc3339()))
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(TurnMetric {
+                    id: row.try_get::<i64, _>("seq")?.to_string(),
+                    session_id: row.try_get("session_id")?,
+                    device_id: row.try_get("device_id")?,
+                    kind: row.try_get("kind")?,
+                    time: row.try_get("time")?,
+                    first_audio_ms: row
+                        .try_get::<Option<i64>, _>("first_audio_ms")?
+                        .map(|ms| ms as u64),
+                    response_ms: row.try_get::<i64, _>("response_ms")? as u64,
+                    error: row.try_get("error")?,
+                    intent: row.try_get("intent")?,
+                    usage: row
+                        .try_get::<Option<serde_json::Value>, _>("usage")?
+                        .and_then(|usage| serde_json::from_value(usage).ok())
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_turn_metrics(&self, ids: &[String]) -> anyhow::Result<u64> {
+        let seqs = ids
+            .iter()
+            .filter_map(|id| id.parse::<i64>().ok())
+            .collect::<Vec<_>>();
+        let r = sqlx::query("DELETE FROM turn_metrics WHERE seq = ANY($1)")
+            .bind(seqs)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn put_daily_stats(&self, stats: &DailyStats) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO daily_stats (day, data) VALUES ($1, $2)
+             ON CONFLICT (day) DO UPDATE SET data = EXCLUDED.data",
+        )
+        .bind(&stats.day)
+        .bind(serde_json::to_value(stats)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn daily_stats(&self) -> anyhow::Result<Vec<DailyStats>> {
+        let rows = sqlx::query("SELECT data FROM daily_stats ORDER BY day")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter()
+            .map(|row| {
+                let data: serde_json::Value = row.try_get("data")?;
+                Ok(serde_json::from_value(data)?)
+            })
+            .collect()
+    }
+
+    async fn add_tenant_usage(&self, usage: &TenantDay) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO tenant_usage (tenant, day, turns, llm_input_tokens, llm_output_tokens,
+                 tts_chars, asr_ms, cost)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             ON CONFLICT (tenant, day) DO UPDATE SET
+                 turns = tenant_usage.turns + EXCLUDED.turns,
+                 llm_input_tokens = tenant_usage.llm_input_tokens + EXCLUDED.llm_input_tokens,
+                 llm_output_tokens = tenant_usage.llm_output_tokens + EXCLUDED.llm_output_tokens,
+                 tts_chars = tenant_usage.tts_chars + EXCLUDED.tts_chars,
+                 asr_ms = tenant_usage.asr_ms + EXCLUDED.asr_ms,
+                 cost = tenant_usage.cost + EXCLUDED.cost",
+        )
+        .bind(&usage.tenant)
+        .bind(&usage.day)
+        .bind(usage.turns as i64)
+        .bind(usage.usage.llm_input_tokens as i64)
+        .bind(usage.usage.llm_output_tokens as i64)
+        .bind(usage.usage.tts_chars as i64)
+        .bind(usage.usage.asr_ms as i64)
+        .bind(usage.usage.cost)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn tenant_usage(&self, tenant: &str, day: &str) -> anyhow::Result<Option<TenantDay>> {
+        let row = sqlx::query(
+            "SELECT turns, llm_input_tokens, llm_output_tokens, tts_chars, asr_ms, cost
+             FROM tenant_usage WHERE tenant = $1 AND day = $2",
+        )
+        .bind(tenant)
+        .bind(day)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let count = |name: &str| -> anyhow::Result<u64> { Ok(row.try_get::<i64, _>(name)? as u64) };
+        Ok(Some(TenantDay {
+            tenant: tenant.to_string(),
+            day: day.to_string(),
+            turns: count("turns")?,
+            usage: super::Usage {
+                llm_input_tokens: count("llm_input_tokens")?,
+                llm_output_tokens: count("llm_output_tokens")?,
+                tts_chars: count("tts_chars")?,
+                asr_ms: count("asr_ms")?,
+                cost: row.try_get("cost")?,
+            },
+        }))
+    }
+
+    async fn put_prompts(&self, prompts: &PromptsInfo) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO prompts (id, data) VALUES (0, $1)
+             ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data",
+        )
+        .bind(serde_json::to_value(prompts)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn prompts(&self) -> anyhow::Result<Option<PromptsInfo>> {
+        let row = sqlx::query("SELECT data FROM prompts WHERE id = 0")
+            .fetch_optional(&self.pool)
+            .await?;
+        match row {
+            Some(row) => {
+                let data: serde_json::Value = row.try_get("data")?;
+                Ok(Some(serde_json::from_value(data)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn devices(&self) -> anyhow::Result<Vec<Device>> {
+        let rows = sqlx::query("SELECT data FROM devices ORDER BY device_id")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter()
+            .map(|row| {
+                let data: serde_json::Value = row.try_get("data")?;
+                Ok(serde_json::from_value(data)?)
+            })
+            .collect()
+    }
+
+    async fn put_device(&self, device: &Device) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO devices (device_id, data) VALUES ($1, $2)
+             ON CONFLICT (device_id) DO UPDATE SET data = $2",
+        )
+        .bind(&device.device_id)
+        .bind(serde_json::to_value(device)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn add_memory(&self, memory: &Memory) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO memories (id, device_id, text, created_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(&memory.id)
+        .bind(&memory.device_id)
+        .bind(&memory.text)
+        .bind(&memory.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn memories(&self, device_id: &str) -> anyhow::Result<Vec<Memory>> {
+        let rows = sqlx::query(
+            "SELECT id, device_id, text, created_at FROM memories
+             WHERE device_id = $1 ORDER BY seq",
+        )
+        .bind(device_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Memory {
+                    id: row.try_get("id")?,
+                    device_id: row.try_get("device_id")?,
+                    text: row.try_get("text")?,
+                    created_at: row.try_get("created_at")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_memory(&self, id: &str) -> anyhow::Result<bool> {
+        let r = sqlx::query("DELETE FROM memories WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn put_reminder(&self, reminder: &Reminder) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO reminders (id, device_id, text, due_at, cron, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (id) DO UPDATE
+             SET device_id = $2, text = $3, due_at = $4, cron = $5, created_at = $6",
+        )
+        .bind(&reminder.id)
+        .bind(&reminder.device_id)
+        .bind(&reminder.text)
+        .bind(&reminder.due_at)
+        .bind(&reminder.cron)
+        .bind(&reminder.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn reminders(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Reminder>> {
+        let rows = sqlx::query(
+            "SELECT id, device_id, text, due_at, cron, created_at FROM reminders
+             WHERE $1::TEXT IS NULL OR device_id = $1 ORDER BY seq",
+        )
+        .bind(device_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Reminder {
+                    id: row.try_get("id")?,
+                    device_id: row.try_get("device_id")?,
+                    text: row.try_get("text")?,
+                    due_at: row.try_get("due_at")?,
+                    cron: row.try_get("cron")?,
+                    created_at: row.try_get("created_at")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_reminder(&self, id: &str) -> anyhow::Result<bool> {
+        let r = sqlx::query("DELETE FROM reminders WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn delete_device_data(&self, device_id: &str) -> anyhow::Result<DeletedData> {
+        let mut tx = self.pool.begin().await?;
+        let mut deleted = [0; 6];
+        let tables = [
+            "sessions",
+            "turns",
+            "memories",
+            "annotations",
+            "reminders",
+            "turn_metrics",
+        ];
+        for (n, table) in deleted.iter_mut().zip(tables) {
+            *n = sqlx::query(&format!("DELETE FROM {table} WHERE device_id = $1"))
+                .bind(device_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        let [sessions, turns, memories, annotations, reminders, metrics] = deleted;
+        Ok(DeletedData {
+            sessions,
+            turns,
+            memories,
+            annotations,
+            reminders,
+            metrics,
+        })
+    }
+}
+
+/// needs a database, `ECHOKIT_TEST_POSTGRES=postgres://... cargo test --features postgres`
+#[tokio::test]
+async fn test_postgres_store() {
+    let Ok(url) = std::env::var("ECHOKIT_TEST_POSTGRES") else {
+        return;
+    };
+    let store = PostgresStore::open(&url, 2).await.unwrap();
+
+    let device_id = format!("test-{}", uuid::Uuid::new_v4().simple());
+    let turn = Turn {
+        session_id: device_id.clone(),
+        device_id: Some(device_id.clone()),
+        time: chrono::Local::now().to_rfc3339(),
+        role: crate::ai::llm::Role::User,
+        text: "你好".to_string(),
+    };
+    store.append_turn(&turn).await.unwrap();
+    assert_eq!(store.turns(Some(&device_id)).await.unwrap(), [turn]);
+
+    let memory = Memory {
+        id: uuid::Uuid::new_v4().to_string(),
+        device_id: device_id.clone(),
+        text: "likes jazz".to_string(),
+        created_at: String::new(),
+    };
+    store.add_memory(&memory).await.unwrap();
+    assert_eq!(store.memories(&device_id).await.unwrap(), [memory.clone()]);
+    assert!(store.delete_memory(&memory.id).await.unwrap());
+}
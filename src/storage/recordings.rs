@@ -0,0 +1,177 @@
+//! The audio recorded by the devices, `[recordings]` picks the backend.
+//!
+//! the keys are `{device_id}/{file name}`, under `dir` on disk or `prefix` in the bucket.
+
+use std::path::PathBuf;
+
+use super::s3::S3Bucket;
+use crate::config::RecordingsConfig;
+
+#[derive(Debug)]
+pub enum Recordings {
+    /// served by `/record/download`
+    Local(PathBuf),
+    S3(S3Bucket),
+}
+
+impl Recordings {
+    pub fn new(config: &RecordingsConfig) -> anyhow::Result<Self> {
+        match config {
+            RecordingsConfig::Local { dir } => Ok(Recordings::Local(PathBuf::from(dir))),
+            RecordingsConfig::S3(s3) => Ok(Recordings::S3(S3Bucket::new(s3.clone())?)),
+        }
+    }
+
+    pub async fn put(&self, key: &str, data: bytes::Bytes) -> anyhow::Result<()> {
+        match self {
+            Recordings::Local(dir) => {
+                let path = dir.join(key);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(path, data).await?;
+                Ok(())
+            }
+            Recordings::S3(bucket) => bucket.put(key, data).await,
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> anyhow::Result<bytes::Bytes> {
+        match self {
+            Recordings::Local(dir) => Ok(tokio::fs::read(dir.join(key)).await?.into()),
+            Recordings::S3(bucket) => bucket.get(key).await,
+        }
+    }
+
+    /// the file names of a device
+    pub async fn list(&self, device_id: &str) -> anyhow::Result<Vec<String>> {
+        match self {
+            Recordings::Local(dir) => {
+                let mut names = vec![];
+                let mut entries = tokio::fs::read_dir(dir.join(device_id)).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    let is_file = entry.metadata().await.map(|m| m.is_file()).unwrap_or(false);
+                    if let (true, Some(name)) = (is_file, entry.file_name().to_str()) {
+                        names.push(name.to_string());
+                    }
+                }
+                Ok(names)
+            }
+            Recordings::S3(bucket) => Ok(bucket
+                .list(&format!("{device_id}/"))
+                .await?
+                .into_iter()
+                .filter(|name| !name.contains('/'))
+                .collect()),
+        }
+    }
+
+    /// delete the recordings older than `before`, the number deleted
+    pub async fn purge(&self, before: &chrono::DateTime<chrono::Utc>) -> anyhow::Result<usize> {
+        let mut purged = 0;
+        match self {
+            Recordings::Local(dir) => {
+                let mut devices = match tokio::fs::read_dir(dir).await {
+                    Ok(devices) => devices,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(device) = devices.next_entry().await? {
+                    if !device.metadata().await?.is_dir() {
+                        continue;
+                    }
+                    let mut files = tokio::fs::read_dir(device.path()).await?;
+                    while let Some(file) = files.next_entry().await? {
+                        let modified = file.metadata().await?.modified()?;
+                        if chrono::DateTime::<chrono::Utc>::from(modified) < *before {
+                            tokio::fs::remove_file(file.path()).await?;
+                            purged += 1;
+                        }
+                    }
+                }
+            }
+            Recordings::S3(bucket) => {
+                for object in bucket.list_objects("").await? {
+                    let old = chrono::DateTime::parse_from_rfc3339(&object.last_modified)
+                        .is_ok_and(|t| t < *before);
+                    if old {
+                        bucket.delete(&object.key).await?;
+                        purged += 1;
+                    }
+                }
+            }
+        }
+        Ok(purged)
+    }
+
+    /// delete every recording of a device, the number deleted
+    pub async fn delete_device(&self, device_id: &str) -> anyhow::Result<usize> {
+        let bad = matches!(device_id, "" | "." | "..") || device_id.contains(['/', '\\']);
+        if bad {
+            anyhow::bail!("bad device id `{device_id}`");
+        }
+        let mut deleted = 0;
+        match self {
+            Recordings::Local(dir) => {
+                let dir = dir.join(device_id);
+                let mut files = match tokio::fs::read_dir(&dir).await {
+                    Ok(files) => files,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(file) = files.next_entry().await? {
+                    if file.metadata().await?.is_file() {
+                        deleted += 1;
+                    }
+                }
+                tokio::fs::remove_dir_all(dir).await?;
+            }
+            Recordings::S3(bucket) => {
+                for name in bucket.list(&format!("{device_id}/")).await? {
+                    bucket.delete(&format!("{device_id}/{name}")).await?;
+                    deleted += 1;
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// where `key` is downloaded, a presigned url for a bucket
+    pub fn url(&self, key: &str) -> String {
+        match self {
+            Recordings::Local(_) => format!("/record/download/{key}"),
+            Recordings::S3(bucket) => bucket.url(key),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_local_recordings() {
+    let dir = std::env::temp_dir().join(format!("echokit_record_{}", uuid::Uuid::new_v4()));
+    let recordings = Recordings::Local(dir.clone());
+    recordings
+        .put("dev1/recording_1.wav", bytes::Bytes::from_static(b"RIFF"))
+        .await
+        .unwrap();
+    assert_eq!(recordings.list("dev1").await.unwrap(), ["recording_1.wav"]);
+    assert!(recordings.list("dev2").await.is_err());
+    assert_eq!(
+        recordings.url("dev1/recording_1.wav"),
+        "/record/download/dev1/recording_1.wav"
+    );
+
+    let an_hour_ago = chrono::Utc::now() - chrono::Duration::hours(1);
+    assert_eq!(recordings.purge(&an_hour_ago).await.unwrap(), 0);
+    let later = chrono::Utc::now() + chrono::Duration::seconds(1);
+    assert_eq!(recordings.purge(&later).await.unwrap(), 1);
+    assert!(recordings.list("dev1").await.unwrap().is_empty());
+
+    recordings
+        .put("dev1/recording_2.wav", bytes::Bytes::from_static(b"RIFF"))
+        .await
+        .unwrap();
+    assert_eq!(recordings.delete_device("dev1").await.unwrap(), 1);
+    assert!(recordings.list("dev1").await.is_err());
+    assert!(recordings.delete_device("..").await.is_err());
+    let _ = std::fs::remove_dir_all(dir);
+}
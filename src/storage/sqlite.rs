@@ -0,0 +1,713 @@
+//! SQLite storage, `[storage] backend = "sqlite"` with the `sqlite` feature.
+//!
+//! the devices are kept as json, the other tables have one column per field.
+//! the queries run on the blocking pool, one connection at a time.
+
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection};
+
+use super::{
+    Annotation, DailyStats, DeletedData, Memory, Reminder, SessionRecord, Store, TenantDay, Turn,
+    TurnMetric,
+};
+use crate::services::{prompts::PromptsInfo, registry::Device};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    device_id TEXT,
+    ended_at TEXT,
+    turns INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (id, started_at)
+);
+CREATE TABLE IF NOT EXISTS turns (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id TEXT NOT NULL,
+    device_id TEXT,
+    time TEXT NOT NULL,
+    role TEXT NOT NULL,
+    text TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS turns_device_id ON turns (device_id);
+CREATE TABLE IF NOT EXISTS devices (
+    device_id TEXT PRIMARY KEY,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS memories (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    device_id TEXT NOT NULL,
+    text TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS memories_device_id ON memories (device_id);
+CREATE TABLE IF NOT EXISTS reminders (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    device_id TEXT NOT NULL,
+    text TEXT NOT NULL,
+    due_at TEXT NOT NULL,
+    cron TEXT,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS reminders_device_id ON reminders (device_id);
+CREATE TABLE IF NOT EXISTS annotations (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    session_id TEXT NOT NULL,
+    device_id TEXT,
+    turn_time TEXT NOT NULL,
+    label TEXT NOT NULL,
+    note TEXT,
+    source TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS annotations_device_id ON annotations (device_id);
+CREATE TABLE IF NOT EXISTS turn_metrics (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id TEXT NOT NULL,
+    device_id TEXT,
+    kind TEXT NOT NULL,
+    time TEXT NOT NULL,
+    first_audio_ms INTEGER,
+    response_ms INTEGER NOT NULL,
+    error INTEGER NOT NULL,
+    intent TEXT
+);
+CREATE TABLE IF NOT EXISTS daily_stats (
+    day TEXT PRIMARY KEY,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tenant_usage (
+    tenant TEXT NOT NULL,
+    day TEXT NOT NULL,
+    turns INTEGER NOT NULL,
+    llm_input_tokens INTEGER NOT NULL,
+    llm_output_tokens INTEGER NOT NULL,
+    tts_chars INTEGER NOT NULL,
+    asr_ms INTEGER NOT NULL,
+    cost REAL NOT NULL,
+    PRIMARY KEY (tenant, day)
+);
+CREATE TABLE IF NOT EXISTS prompts (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    data TEXT NOT NULL
+);
+";
+
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+fn parse_role(role: String) -> rusqlite::Result<crate::ai::llm::Role> {
+    super::parse_role(&role).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+impl SqliteStore {
+    pub async fn open(path: &str) -> anyhow::Result<Self> {
+        let path = path.to_string();
+        let conn = tokio::task::spawn_blocking(move || -> anyhow::Result<Connection> {
+            let conn = Connection::open(&path)?;
+            conn.execute_batch(SCHEMA)?;
+            // added after the table, a database of an older version lacks it
+            let has_usage = conn
+                .prepare("SELECT 1 FROM pragma_table_info('turn_metrics') WHERE name = 'usage'")?
+                .exists([])?;
+            if !has_usage {
+                conn.execute_batch("ALTER TABLE turn_metrics ADD COLUMN usage TEXT")?;
+            }
+            Ok(conn)
+        })
+        .await??;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// run `f` with the connection on the blocking pool
+    async fn with_conn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+impl Store for SqliteStore {
+    async fn put_session(&self, session: &SessionRecord) -> anyhow::Result<()> {
+        let session = session.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO sessions (id, started_at, kind, device_id, ended_at, turns)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    session.id,
+                    session.started_at,
+                    session.kind,
+                    session.device_id,
+                    session.ended_at,
+                    session.turns as i64
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn sessions(&self, device_id: Option<&str>) -> anyhow::Result<Vec<SessionRecord>> {
+        let device_id = device_id.map(str::to_string);
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, kind, device_id, started_at, ended_at, turns FROM sessions
+                 WHERE ?1 IS NULL OR device_id = ?1 ORDER BY started_at, id",
+            )?;
+            let sessions = stmt
+                .query_map(params![device_id], |row| {
+                    Ok(SessionRecord {
+                        id: row.get(0)?,
+                        kind: row.get(1)?,
+                        device_id: row.get(2)?,
+                        started_at: row.get(3)?,
+                        ended_at: row.get(4)?,
+                        turns: row.get::<_, i64>(5)? as usize,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(sessions)
+        })
+        .await
+    }
+
+    async fn append_turn(&self, turn: &Turn) -> anyhow::Result<()> {
+        let turn = turn.clone();
+        let role = super::role_name(turn.role);
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO turns (session_id, device_id, time, role, text)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![turn.session_id, turn.device_id, turn.time, role, turn.text],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn turns(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Turn>> {
+        let device_id = device_id.map(str::to_string);
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT session_id, device_id, time, role, text FROM turns
+                 WHERE ?1 IS NULL OR device_id = ?1 ORDER BY seq",
+            )?;
+            let turns = stmt
+                .query_map(params![device_id], |row| {
+                    Ok(Turn {
+                        session_id: row.get(0)?,
+                        device_id: row.get(1)?,
+                        time: row.get(2)?,
+                        role: parse_role(row.get(3)?)?,
+                        text: row.get(4)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(turns)
+        })
+        .await
+    }
+
+    async fn purge_transcripts(
+        &self,
+        before: &chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u64> {
+        let before = before.to_rfc3339();
+        self.with_conn(move |conn| {
+            let purged = conn.execute(
+                "DELETE FROM turns WHERE julianday(time) < julianday(?1)",
+                params![before],
+            )?;
+            conn.execute(
+                "DELETE FROM sessions WHERE julianday(started_at) < julianday(?1)",
+                params![before],
+            )?;
+            Ok(purged as u64)
+        })
+        .await
+    }
+
+    async fn add_annotation(&self, annotation: &Annotation) -> anyhow::Result<()> {
+        let a = annotation.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO annotations
+                 (id, session_id, device_id, turn_time, label, note, source, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    a.id,
+                    a.session_id,
+                    a.device_id,
+                    a.turn_time,
+                    a.label,
+                    a.note,
+                    a.source,
+                    a.created_at
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn annotations(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Annotation>> {
+        let device_id = device_id.map(str::to_string);
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, session_id, device_id, turn_time, label, note, source, created_at
+                 FROM annotations WHERE ?1 IS NULL OR device_id = ?1 ORDER BY seq",
+            )?;
+            let annotations = stmt
+                .query_map(params![device_id], |row| {
+                    Ok(Annotation {
+                        id: row.get(0)?,
+                        session_id: row.get(1)?,
+                        device_id: row.get(2)?,
+                        turn_time: row.get(3)?,
+                        label: row.get(4)?,
+                        note: row.get(5)?,
+                        source: row.get(6)?,
+                        created_at: row.get(7)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(annotations)
+        })
+        .await
+    }
+
+    async fn delete_annotation(&self, id: &str) -> anyhow::Result<bool> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute("DELETE FROM annotations WHERE id = ?1", params![id])?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn append_turn_metric(&self, metric: &TurnMetric) -> anyhow::Result<()> {
+        let m = metric.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO turn_metrics
+                 (session_id, device_id, kind, time, first_audio_ms, response_ms, error, intent,
+                  usage)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    m.session_id,
+                    m.device_id,
+                    m.kind,
+                    m.time,
+                    m.first_audio_ms.map(|ms| ms as i64),
+                    m.response_ms as i64,
+                    m.error,
+                    m.intent,
+                    serde_json::to_string(&m.usage)?
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn turn_metrics(
+        &self,
+        before: Option<&chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<Vec<TurnMetric>> {
+        let before = before.map(|before| before.to_rfc3339());
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT seq, session_id, device_id, kind, time, first_audio_ms, response_ms, error,
+                 intent, usage FROM turn_metrics
+                 WHERE ?1 IS NULL OR julianday(time) < julianday(?1) ORDER BY seq",
+            )?;
+            let metrics = stmt
+                .query_map(params![before], |row| {
+                    Ok(TurnMetric {
+                        id: row.get::<_, i64>(0)?.to_string(),
+                        session_id: row.get(1)?,
+                        device_id: row.get(2)?,
+                        kind: row.get(3)?,
+                        time: row.get(4)?,
+                        first_audio_ms: row.get::<_, Option<i64>>(5)?.map(|ms| ms as u64),
+                        response_ms: row.get::<_, i64>(6)? as u64,
+                        error: row.get(7)?,
+                        intent: row.get(8)?,
+                        usage: row
+                            .get::<_, Option<String>>(9)?
+                            .and_then(|usage| serde_json::from_str(&usage).ok())
+                            .unwrap_or_default(),
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(metrics)
+        })
+        .await
+    }
+
+    async fn delete_turn_metrics(&self, ids: &[String]) -> anyhow::Result<u64> {
+        let seqs = ids
+            .iter()
+            .filter_map(|id| id.parse::<i64>().ok())
+            .collect::<Vec<_>>();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let mut deleted = 0;
+            {
+                let mut stmt = tx.prepare("DELETE FROM turn_metrics WHERE seq = ?1")?;
+                for seq in seqs {
+                    deleted += stmt.execute(params![seq])?;
+                }
+            }
+            tx.commit()?;
+            Ok(deleted as u64)
+        })
+        .await
+    }
+
+    async fn put_daily_stats(&self, stats: &DailyStats) -> anyhow::Result<()> {
+        let day = stats.day.clone();
+        let data = serde_json::to_string(stats)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO daily_stats (day, data) VALUES (?1, ?2)",
+                params![day, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn daily_stats(&self) -> anyhow::Result<Vec<DailyStats>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT data FROM daily_stats ORDER BY day")?;
+            let rows = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows.iter()
+                .map(|data| Ok(serde_json::from_str(data)?))
+                .collect()
+        })
+        .await
+    }
+
+    async fn add_tenant_usage(&self, usage: &TenantDay) -> anyhow::Result<()> {
+        let usage = usage.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO tenant_usage (tenant, day, turns, llm_input_tokens,
+                     llm_output_tokens, tts_chars, asr_ms, cost)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (tenant, day) DO UPDATE SET
+                     turns = tenant_usage.turns + excluded.turns,
+                     llm_input_tokens = tenant_usage.llm_input_tokens + excluded.llm_input_tokens,
+                     llm_output_tokens = tenant_usage.llm_output_tokens + excluded.llm_output_tokens,
+                     tts_chars = tenant_usage.tts_chars + excluded.tts_chars,
+                     asr_ms = tenant_usage.asr_ms + excluded.asr_ms,
+                     cost = tenant_usage.cost + excluded.cost",
+                params![
+                    usage.tenant,
+                    usage.day,
+                    usage.turns as i64,
+                    usage.usage.llm_input_tokens as i64,
+                    usage.usage.llm_output_tokens as i64,
+                    usage.usage.tts_chars as i64,
+                    usage.usage.asr_ms as i64,
+                    usage.usage.cost,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn tenant_usage(&self, tenant: &str, day: &str) -> anyhow::Result<Option<TenantDay>> {
+        let (tenant, day) = (tenant.to_string(), day.to_string());
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT turns, llm_input_tokens, llm_output_tokens, tts_chars, asr_ms, cost
+                 FROM tenant_usage WHERE tenant = ?1 AND day = ?2",
+            )?;
+            let mut rows = stmt.query_map(params![tenant, day], |row| {
+                Ok(TenantDay {
+                    tenant: tenant.clone(),
+                    day: day.clone(),
+                    turns: row.get::<_, i64>(0)? as u64,
+                    usage: super::Usage {
+                        llm_input_tokens: row.get::<_, i64>(1)? as u64,
+                        llm_output_tokens: row.get::<_, i64>(2)? as u64,
+                        tts_chars: row.get::<_, i64>(3)? as u64,
+                        asr_ms: row.get::<_, i64>(4)? as u64,
+                        cost: row.get(5)?,
+                    },
+                })
+            })?;
+            Ok(rows.next().transpose()?)
+        })
+        .await
+    }
+
+    async fn put_prompts(&self, prompts: &PromptsInfo) -> anyhow::Result<()> {
+        let data = serde_json::to_string(prompts)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO prompts (id, data) VALUES (0, ?1)",
+                params![data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn prompts(&self) -> anyhow::Result<Option<PromptsInfo>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT data FROM prompts WHERE id = 0")?;
+            let mut rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            match rows.next().transpose()? {
+                Some(data) => Ok(Some(serde_json::from_str(&data)?)),
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+
+    async fn devices(&self) -> anyhow::Result<Vec<Device>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT data FROM devices ORDER BY device_id")?;
+            let rows = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows.iter()
+                .map(|data| Ok(serde_json::from_str(data)?))
+                .collect()
+        })
+        .await
+    }
+
+    async fn put_device(&self, device: &Device) -> anyhow::Result<()> {
+        let device_id = device.device_id.clone();
+        let data = serde_json::to_string(device)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO devices (device_id, data) VALUES (?1, ?2)",
+                params![device_id, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn add_memory(&self, memory: &Memory) -> anyhow::Result<()> {
+        let memory = memory.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO memories (id, device_id, text, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![memory.id, memory.device_id, memory.text, memory.created_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn memories(&self, device_id: &str) -> anyhow::Result<Vec<Memory>> {
+        let device_id = device_id.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, device_id, text, created_at FROM memories
+                 WHERE device_id = ?1 ORDER BY seq",
+            )?;
+            let memories = stmt
+                .query_map(params![device_id], |row| {
+                    Ok(Memory {
+                        id: row.get(0)?,
+                        device_id: row.get(1)?,
+                        text: row.get(2)?,
+                        created_at: row.get(3)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(memories)
+        })
+        .await
+    }
+
+    async fn delete_memory(&self, id: &str) -> anyhow::Result<bool> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute("DELETE FROM memories WHERE id = ?1", params![id])?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn put_reminder(&self, reminder: &Reminder) -> anyhow::Result<()> {
+        let reminder = reminder.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO reminders (id, device_id, text, due_at, cron, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (id) DO UPDATE
+                 SET device_id = ?2, text = ?3, due_at = ?4, cron = ?5, created_at = ?6",
+                params![
+                    reminder.id,
+                    reminder.device_id,
+                    reminder.text,
+                    reminder.due_at,
+                    reminder.cron,
+                    reminder.created_at
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn reminders(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Reminder>> {
+        let device_id = device_id.map(str::to_string);
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, device_id, text, due_at, cron, created_at FROM reminders
+                 WHERE ?1 IS NULL OR device_id = ?1 ORDER BY seq",
+            )?;
+            let reminders = stmt
+                .query_map(params![device_id], |row| {
+                    Ok(Reminder {
+                        id: row.get(0)?,
+                        device_id: row.get(1)?,
+                        text: row.get(2)?,
+                        due_at: row.get(3)?,
+                        cron: row.get(4)?,
+                        created_at: row.get(5)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(reminders)
+        })
+        .await
+    }
+
+    async fn delete_reminder(&self, id: &str) -> anyhow::Result<bool> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute("DELETE FROM reminders WHERE id = ?1", params![id])?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn delete_device_data(&self, device_id: &str) -> anyhow::Result<DeletedData> {
+        let device_id = device_id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let deleted = DeletedData {
+                sessions: tx.execute(
+                    "DELETE FROM sessions WHERE device_id = ?1",
+                    params![device_id],
+                )? as u64,
+                turns: tx.execute("DELETE FROM turns WHERE device_id = ?1", params![device_id])?
+                    as u64,
+                memories: tx.execute(
+                    "DELETE FROM memories WHERE device_id = ?1",
+                    params![device_id],
+                )? as u64,
+                annotations: tx.execute(
+                    "DELETE FROM annotations WHERE device_id = ?1",
+                    params![device_id],
+                )? as u64,
+                reminders: tx.execute(
+                    "DELETE FROM reminders WHERE device_id = ?1",
+                    params![device_id],
+                )? as u64,
+                metrics: tx.execute(
+                    "DELETE FROM turn_metrics WHERE device_id = ?1",
+                    params![device_id],
+                )? as u64,
+            };
+            tx.commit()?;
+            Ok(deleted)
+        })
+        .await
+    }
+}
+
+#[tokio::test]
+async fn test_sqlite_store() {
+    let store = SqliteStore::open(":memory:").await.unwrap();
+
+    let session = SessionRecord {
+        id: "dev1".to_string(),
+        kind: "ws".to_string(),
+        device_id: Some("dev1".to_string()),
+        started_at: "2025-01-01T00:00:00+08:00".to_string(),
+        ended_at: None,
+        turns: 0,
+    };
+    store.put_session(&session).await.unwrap();
+    let ended = SessionRecord {
+        ended_at: Some("2025-01-01T00:05:00+08:00".to_string()),
+        turns: 2,
+        ..session
+    };
+    store.put_session(&ended).await.unwrap();
+    assert_eq!(store.sessions(None).await.unwrap(), [ended]);
+
+    let turn = Turn {
+        session_id: "dev1".to_string(),
+        device_id: Some("dev1".to_string()),
+        time: String::new(),
+        role: crate::ai::llm::Role::Assistant,
+        text: "你好呀".to_string(),
+    };
+    store.append_turn(&turn).await.unwrap();
+    assert_eq!(store.turns(Some("dev1")).await.unwrap(), [turn]);
+    assert!(store.turns(Some("dev2")).await.unwrap().is_empty());
+
+    let memory = Memory {
+        id: "m1".to_string(),
+        device_id: "dev1".to_string(),
+        text: "likes jazz".to_string(),
+        created_at: String::new(),
+    };
+    store.add_memory(&memory).await.unwrap();
+    assert_eq!(store.memories("dev1").await.unwrap(), [memory]);
+    assert!(store.delete_memory("m1").await.unwrap());
+    assert!(!store.delete_memory("m1").await.unwrap());
+
+    let mut reminder = Reminder {
+        id: "r1".to_string(),
+        device_id: "dev1".to_string(),
+        text: "drink water".to_string(),
+        due_at: "2025-01-01T08:00:00+08:00".to_string(),
+        cron: None,
+        created_at: String::new(),
+    };
+    store.put_reminder(&reminder).await.unwrap();
+    reminder.cron = Some("0 8 * * *".to_string());
+    store.put_reminder(&reminder).await.unwrap();
+    assert_eq!(store.reminders(None).await.unwrap(), [reminder]);
+
+    let deleted = store.delete_device_data("dev1").await.unwrap();
+    assert_eq!(
+        (deleted.sessions, deleted.turns, deleted.reminders),
+        (1, 1, 1)
+    );
+    assert!(store.turns(None).await.unwrap().is_empty());
+}
@@ -1,7 +1,10 @@
-use std::io::{Cursor, Write};
+use std::time::Duration;
 
+use bytes::{BufMut, Bytes, BytesMut};
 use wav_io::{header::SampleFormat, reader::DecodeError};
 
+use crate::config::{Prosody, RawAudioFormat, RawSampleFormat};
+
 /// WAV 音频参数结构体
 #[derive(Debug, Clone)]
 pub struct WavConfig {
@@ -20,40 +23,563 @@ impl Default for WavConfig {
     }
 }
 
-pub fn pcm_to_wav(pcm_data: &[u8], config: WavConfig) -> Vec<u8> {
-    let mut wav_data = Vec::new();
-    let mut cursor = Cursor::new(&mut wav_data);
-
+pub fn pcm_to_wav(pcm_data: &[u8], config: WavConfig) -> Bytes {
     let bytes_per_sample = config.bits_per_sample / 8;
     let byte_rate = config.sample_rate * config.channels as u32 * bytes_per_sample as u32;
     let block_align = config.channels * bytes_per_sample;
     let data_size = pcm_data.len() as u32;
     let file_size = 36 + data_size;
 
-    cursor.write_all(b"RIFF").unwrap(); // ChunkID
-    cursor.write_all(&file_size.to_le_bytes()).unwrap(); // ChunkSize (little-endian)
-    cursor.write_all(b"WAVE").unwrap(); // Format
+    let mut wav_data = BytesMut::with_capacity(44 + pcm_data.len());
+
+    wav_data.put_slice(b"RIFF"); // ChunkID
+    wav_data.put_u32_le(file_size); // ChunkSize (little-endian)
+    wav_data.put_slice(b"WAVE"); // Format
 
     // fmt 子块
-    cursor.write_all(b"fmt ").unwrap(); // Subchunk1ID
-    cursor.write_all(&16u32.to_le_bytes()).unwrap(); // Subchunk1Size (PCM = 16)
-    cursor.write_all(&1u16.to_le_bytes()).unwrap(); // AudioFormat (PCM = 1)
-    cursor.write_all(&config.channels.to_le_bytes()).unwrap(); // NumChannels
-    cursor.write_all(&config.sample_rate.to_le_bytes()).unwrap(); // SampleRate
-    cursor.write_all(&byte_rate.to_le_bytes()).unwrap(); // ByteRate
-    cursor.write_all(&block_align.to_le_bytes()).unwrap(); // BlockAlign
-    cursor
-        .write_all(&config.bits_per_sample.to_le_bytes())
-        .unwrap(); // BitsPerSample
+    wav_data.put_slice(b"fmt "); // Subchunk1ID
+    wav_data.put_u32_le(16); // Subchunk1Size (PCM = 16)
+    wav_data.put_u16_le(1); // AudioFormat (PCM = 1)
+    wav_data.put_u16_le(config.channels); // NumChannels
+    wav_data.put_u32_le(config.sample_rate); // SampleRate
+    wav_data.put_u32_le(byte_rate); // ByteRate
+    wav_data.put_u16_le(block_align); // BlockAlign
+    wav_data.put_u16_le(config.bits_per_sample); // BitsPerSample
 
     // data 子块
-    cursor.write_all(b"data").unwrap(); // Subchunk2ID
-    cursor.write_all(&data_size.to_le_bytes()).unwrap(); // Subchunk2Size
+    wav_data.put_slice(b"data"); // Subchunk2ID
+    wav_data.put_u32_le(data_size); // Subchunk2Size
 
     // 写入 PCM 数据
-    cursor.write_all(pcm_data).unwrap();
+    wav_data.put_slice(pcm_data);
+
+    wav_data.freeze()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SampleEncoding {
+    U8,
+    I16,
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl SampleEncoding {
+    fn bytes(self) -> usize {
+        match self {
+            SampleEncoding::U8 => 1,
+            SampleEncoding::I16 => 2,
+            SampleEncoding::I24 => 3,
+            SampleEncoding::I32 | SampleEncoding::F32 => 4,
+            SampleEncoding::F64 => 8,
+        }
+    }
+
+    fn decode(self, b: &[u8]) -> f32 {
+        match self {
+            SampleEncoding::U8 => (b[0] as f32 - 128.0) / 128.0,
+            SampleEncoding::I16 => i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32,
+            SampleEncoding::I24 => {
+                (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / ((1 << 23) - 1) as f32
+            }
+            SampleEncoding::I32 => {
+                i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / i32::MAX as f32
+            }
+            SampleEncoding::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            SampleEncoding::F64 => f64::from_le_bytes(b[..8].try_into().unwrap()) as f32,
+        }
+        .clamp(-1.0, 1.0)
+    }
+}
+
+impl From<RawSampleFormat> for SampleEncoding {
+    fn from(format: RawSampleFormat) -> Self {
+        match format {
+            RawSampleFormat::S16le => SampleEncoding::I16,
+            RawSampleFormat::F32le => SampleEncoding::F32,
+        }
+    }
+}
+
+/// interleaved samples to mono, a trailing partial frame is dropped
+fn decode_interleaved(data: &[u8], encoding: SampleEncoding, channels: u16) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    let frame_size = encoding.bytes() * channels;
+    data.chunks_exact(frame_size)
+        .map(|frame| {
+            frame
+                .chunks_exact(encoding.bytes())
+                .map(|b| encoding.decode(b))
+                .sum::<f32>()
+                / channels as f32
+        })
+        .collect()
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+/// Tolerant WAV parser: accepts pcm / ieee float / extensible `fmt ` chunks, skips unknown
+/// chunks and reads to the end of the file when the `data` length is 0, 0xFFFFFFFF or
+/// larger than the file (streaming headers).
+/// return: (mono samples, sample_rate)
+fn parse_wav(data: &[u8]) -> anyhow::Result<(Vec<f32>, u32)> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(anyhow::anyhow!("not a RIFF/WAVE file"));
+    }
+
+    let mut fmt = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let tag = &data[pos..pos + 4];
+        let size = read_u32(data, pos + 4) as usize;
+        pos += 8;
+        let rest = data.len() - pos;
+
+        match tag {
+            b"fmt " => {
+                if size < 16 || size > rest {
+                    return Err(anyhow::anyhow!("truncated wav fmt chunk"));
+                }
+                let mut format_tag = read_u16(data, pos);
+                let channels = read_u16(data, pos + 2);
+                let sample_rate = read_u32(data, pos + 4);
+                let bits = read_u16(data, pos + 14);
+                // WAVE_FORMAT_EXTENSIBLE, the real format is the head of the sub format guid
+                if format_tag == 0xFFFE && size >= 26 {
+                    format_tag = read_u16(data, pos + 24);
+                }
+                let encoding = match (format_tag, bits) {
+                    (1, 8) => SampleEncoding::U8,
+                    (1, 16) => SampleEncoding::I16,
+                    (1, 24) => SampleEncoding::I24,
+                    (1, 32) => SampleEncoding::I32,
+                    (3, 32) => SampleEncoding::F32,
+                    (3, 64) => SampleEncoding::F64,
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "unsupported wav format {format_tag} with {bits} bits"
+                        ))
+                    }
+                };
+                if sample_rate == 0 {
+                    return Err(anyhow::anyhow!("wav sample rate is 0"));
+                }
+                fmt = Some((encoding, channels, sample_rate));
+            }
+            b"data" => {
+                let (encoding, channels, sample_rate) =
+                    fmt.ok_or_else(|| anyhow::anyhow!("wav data chunk before fmt chunk"))?;
+                let len = if size == 0 || size > rest { rest } else { size };
+                let samples = decode_interleaved(&data[pos..pos + len], encoding, channels);
+                return Ok((samples, sample_rate));
+            }
+            _ => {}
+        }
+
+        // chunks are padded to an even size
+        pos = pos.saturating_add(size).saturating_add(size & 1);
+    }
+
+    Err(anyhow::anyhow!("wav has no data chunk"))
+}
+
+/// Decode tts audio and resample it to `out_hz` 16bit le mono pcm.
+/// `raw` is used if the audio has no RIFF header.
+/// return: (pcm_data, duration)
+pub fn audio_to_pcm16(
+    data: Bytes,
+    raw: Option<RawAudioFormat>,
+    out_hz: u32,
+) -> anyhow::Result<(Bytes, Duration)> {
+    let (mut samples, sample_rate) = match raw {
+        Some(raw) if !data.starts_with(b"RIFF") => (
+            decode_interleaved(&data, raw.sample_format.into(), raw.channels),
+            raw.sample_rate.get(),
+        ),
+        _ => parse_wav(&data)?,
+    };
+    let duration = Duration::from_secs_f32(samples.len() as f32 / sample_rate as f32);
+
+    if sample_rate != out_hz {
+        log::info!("resampling from {sample_rate} to {out_hz}");
+        samples = wav_io::resample::linear(samples, 1, sample_rate, out_hz);
+    }
+
+    let mut pcm_data = BytesMut::with_capacity(samples.len() * 2);
+    for i in wav_io::convert_samples_f32_to_i16(&samples) {
+        pcm_data.put_i16_le(i);
+    }
+
+    Ok((pcm_data.freeze(), duration))
+}
+
+/// Decode WAV audio and resample it to `out_hz` 16bit le pcm.
+/// return: (pcm_data, duration)
+pub fn wav_to_pcm16(wav_data: Bytes, out_hz: u32) -> anyhow::Result<(Bytes, Duration)> {
+    audio_to_pcm16(wav_data, None, out_hz)
+}
+
+/// Same as [`audio_to_pcm16`], but runs on the blocking thread pool
+/// so decoding and resampling don't stall the async executor.
+pub async fn audio_to_pcm16_blocking(
+    data: Bytes,
+    raw: Option<RawAudioFormat>,
+    out_hz: u32,
+) -> anyhow::Result<(Bytes, Duration)> {
+    tokio::task::spawn_blocking(move || audio_to_pcm16(data, raw, out_hz)).await?
+}
+
+/// Same as [`wav_to_pcm16`], but runs on the blocking thread pool
+/// so decoding and resampling don't stall the async executor.
+pub async fn wav_to_pcm16_blocking(
+    wav_data: Bytes,
+    out_hz: u32,
+) -> anyhow::Result<(Bytes, Duration)> {
+    audio_to_pcm16_blocking(wav_data, None, out_hz).await
+}
+
+/// wsola: hann windowed frames written every `hop` and read around every `hop * rate`,
+/// each frame is shifted to the best match of the continuation of the previous one
+/// so the overlaps do not cancel out. the audio is `rate` times shorter, same pitch
+fn time_stretch(samples: &[f32], rate: f32, frame: usize) -> Vec<f32> {
+    let hop = (frame / 2).max(1);
+    let tolerance = (frame / 4) as isize;
+    let window = (0..frame)
+        .map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / frame as f32).cos())
+        .collect::<Vec<_>>();
+    let sample = |i: isize| {
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| samples.get(i))
+            .copied()
+            .unwrap_or(0.0)
+    };
+
+    let out_len = (samples.len() as f32 / rate) as usize;
+    let mut out = vec![0.0; out_len + frame];
+    let mut previous: Option<isize> = None;
+    for write in (0..out_len).step_by(hop) {
+        let mut read = (write as f32 * rate) as isize;
+        if let Some(previous) = previous {
+            let natural = previous + hop as isize;
+            let similarity = |start: isize| {
+                (0..hop as isize)
+                    .step_by(2)
+                    .map(|i| sample(start + i) * sample(natural + i))
+                    .sum::<f32>()
+            };
+            read = (read - tolerance..=read + tolerance)
+                .filter(|start| *start >= 0)
+                .map(|start| (start, similarity(start)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(read, |(start, _)| start);
+        }
+        for (i, w) in window.iter().enumerate() {
+            out[write + i] += sample(read + i as isize) * w;
+        }
+        previous = Some(read);
+    }
+    out.truncate(out_len);
+    out
+}
+
+/// 16bit le mono pcm at `sample_rate` spoken `prosody.speed` times faster and
+/// `prosody.pitch` times higher
+pub fn change_prosody(pcm: &[u8], sample_rate: u32, prosody: Prosody) -> Bytes {
+    let speed = prosody.speed.unwrap_or(1.0);
+    let pitch = prosody.pitch.unwrap_or(1.0);
+    let samples = pcm
+        .chunks_exact(2)
+        .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / i16::MAX as f32)
+        .collect::<Vec<_>>();
+
+    // stretched by `speed / pitch` then resampled `pitch` times shorter
+    let frame = (sample_rate / 25) as usize;
+    let mut samples = time_stretch(&samples, speed / pitch, frame);
+    if pitch != 1.0 {
+        let out_hz = (sample_rate as f32 / pitch) as u32;
+        samples = wav_io::resample::linear(samples, 1, sample_rate, out_hz);
+    }
+
+    let mut out = BytesMut::with_capacity(samples.len() * 2);
+    for s in wav_io::convert_samples_f32_to_i16(&samples) {
+        out.put_i16_le(s);
+    }
+    out.freeze()
+}
+
+/// the 16 bit wav file as mono pcm16 at `out_hz`, the channels are averaged
+pub fn load_pcm(path: &str, out_hz: u32) -> anyhow::Result<Vec<u8>> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    if spec.sample_format != hound::SampleFormat::Int || spec.bits_per_sample != 16 {
+        anyhow::bail!("{path}: only 16 bit wav is supported");
+    }
+    let samples = reader.samples::<i16>().collect::<Result<Vec<_>, _>>()?;
+    let mono = samples
+        .chunks(spec.channels.max(1) as usize)
+        .map(|frame| (frame.iter().map(|s| *s as i32).sum::<i32>() / frame.len() as i32) as i16)
+        .collect::<Vec<_>>();
+    Ok(resample(&mono, spec.sample_rate, out_hz)
+        .into_iter()
+        .flat_map(i16::to_le_bytes)
+        .collect())
+}
+
+/// linear interpolation, good enough for speech
+pub fn resample(samples: &[i16], in_hz: u32, out_hz: u32) -> Vec<i16> {
+    if in_hz == out_hz || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let len = samples.len() as u64 * out_hz as u64 / in_hz as u64;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * in_hz as f64 / out_hz as f64;
+            let j = pos as usize;
+            let a = samples[j.min(samples.len() - 1)] as f64;
+            let b = samples[(j + 1).min(samples.len() - 1)] as f64;
+            (a + (b - a) * (pos - j as f64)) as i16
+        })
+        .collect()
+}
 
-    wav_data
+#[test]
+fn test_resample() {
+    assert_eq!(
+        resample(&[0, 100, 200, 300], 16000, 16000),
+        [0, 100, 200, 300]
+    );
+    assert_eq!(resample(&[0, 100, 200, 300], 16000, 8000), [0, 200]);
+    assert_eq!(resample(&[0, 100], 8000, 16000), [0, 50, 100, 100]);
+}
+
+/// rolling buffer of the last `max_ms` of pcm16, prepended to the speech once it is
+/// detected so the phonemes before the detection are not clipped
+#[derive(Debug, Clone)]
+pub struct PreRoll {
+    buf: std::collections::VecDeque<u8>,
+    max_bytes: usize,
+}
+
+impl PreRoll {
+    pub fn new(sample_rate: u32, max_ms: u32) -> Self {
+        let max_bytes = 2 * (sample_rate as u64 * max_ms as u64 / 1000) as usize;
+        Self {
+            buf: std::collections::VecDeque::with_capacity(max_bytes),
+            max_bytes,
+        }
+    }
+
+    pub fn push(&mut self, pcm: &[u8]) {
+        let pcm = &pcm[pcm.len().saturating_sub(self.max_bytes)..];
+        let overflow = (self.buf.len() + pcm.len()).saturating_sub(self.max_bytes);
+        self.buf.drain(..overflow);
+        self.buf.extend(pcm);
+    }
+
+    /// the buffered audio, the buffer is empty afterwards
+    pub fn take(&mut self) -> BytesMut {
+        let mut pcm = BytesMut::with_capacity(self.buf.len());
+        let (a, b) = self.buf.as_slices();
+        pcm.put_slice(a);
+        pcm.put_slice(b);
+        self.buf.clear();
+        // keep the samples aligned
+        let start = pcm.len() & 1;
+        pcm.split_off(start)
+    }
+}
+
+/// cuts a stream of audio into chunks of `size` bytes, whatever the size of the network
+/// chunks, the last chunk may be shorter
+#[derive(Debug)]
+pub struct Rechunker {
+    rest: BytesMut,
+    size: usize,
+}
+
+impl Rechunker {
+    pub fn new(size: usize) -> Self {
+        Self {
+            rest: BytesMut::with_capacity(size),
+            size: size.max(2),
+        }
+    }
+
+    /// the chunks completed by `chunk`
+    pub fn push(&mut self, mut chunk: Bytes) -> Vec<Bytes> {
+        let mut chunks = vec![];
+        if !self.rest.is_empty() {
+            let n = (self.size - self.rest.len()).min(chunk.len());
+            self.rest.extend_from_slice(&chunk.split_to(n));
+            if self.rest.len() < self.size {
+                return chunks;
+            }
+            chunks.push(self.rest.split().freeze());
+        }
+        while chunk.len() >= self.size {
+            chunks.push(chunk.split_to(self.size));
+        }
+        self.rest.extend_from_slice(&chunk);
+        chunks
+    }
+
+    /// what is left at the end of the stream
+    pub fn finish(&mut self) -> Option<Bytes> {
+        (!self.rest.is_empty()).then(|| self.rest.split().freeze())
+    }
+}
+
+#[test]
+fn test_rechunker() {
+    let mut rechunker = Rechunker::new(4);
+    assert!(rechunker.push(Bytes::from_static(&[1, 2, 3])).is_empty());
+    let chunks = rechunker.push(Bytes::from_static(&[4, 5, 6, 7, 8, 9, 10]));
+    assert_eq!(
+        chunks.iter().map(|c| c.to_vec()).collect::<Vec<_>>(),
+        [vec![1u8, 2, 3, 4], vec![5, 6, 7, 8]]
+    );
+    assert_eq!(rechunker.finish().map(|c| c.to_vec()), Some(vec![9u8, 10]));
+    assert_eq!(rechunker.finish(), None);
+}
+
+#[test]
+fn test_pre_roll() {
+    // 1ms at 16k is 32 bytes
+    let mut pre_roll = PreRoll::new(16000, 2);
+    pre_roll.push(&[1; 40]);
+    pre_roll.push(&[2; 40]);
+    let pcm = pre_roll.take();
+    assert_eq!(pcm.len(), 64);
+    assert_eq!(&pcm[..24], &[1; 24]);
+    assert_eq!(&pcm[24..], &[2; 40]);
+    assert!(pre_roll.take().is_empty());
+
+    pre_roll.push(&[3; 100]);
+    assert_eq!(pre_roll.take().len(), 64);
+}
+
+#[test]
+fn test_parse_wav_streaming_header() {
+    let pcm: Vec<u8> = [0i16, 16384, -16384, i16::MAX]
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect();
+    let mut wav = pcm_to_wav(
+        &pcm,
+        WavConfig {
+            sample_rate: 16000,
+            channels: 1,
+            bits_per_sample: 16,
+        },
+    )
+    .to_vec();
+    // streaming tts: unknown riff and data length
+    wav[4..8].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+    wav[40..44].copy_from_slice(&0u32.to_le_bytes());
+
+    let (pcm16, duration) = audio_to_pcm16(Bytes::from(wav), None, 16000).unwrap();
+    assert_eq!(pcm16.len(), pcm.len());
+    assert_eq!(duration, Duration::from_secs_f32(4.0 / 16000.0));
+}
+
+#[test]
+fn test_parse_wav_float_stereo() {
+    let mut wav = BytesMut::new();
+    let samples = [0.5f32, 0.5, -1.0, 0.0];
+    wav.put_slice(b"RIFF");
+    wav.put_u32_le(0);
+    wav.put_slice(b"WAVE");
+    wav.put_slice(b"LIST");
+    wav.put_u32_le(3);
+    wav.put_slice(b"abc\0");
+    wav.put_slice(b"fmt ");
+    wav.put_u32_le(16);
+    wav.put_u16_le(3); // IEEE float
+    wav.put_u16_le(2);
+    wav.put_u32_le(24000);
+    wav.put_u32_le(24000 * 8);
+    wav.put_u16_le(8);
+    wav.put_u16_le(32);
+    wav.put_slice(b"data");
+    wav.put_u32_le(0xFFFFFFFF);
+    for s in samples {
+        wav.put_f32_le(s);
+    }
+
+    let (samples, sample_rate) = parse_wav(&wav).unwrap();
+    assert_eq!(sample_rate, 24000);
+    assert_eq!(samples, vec![0.5, -0.5]);
+}
+
+#[test]
+fn test_change_prosody() {
+    let sine = |hz: f32, samples: usize| {
+        (0..samples)
+            .flat_map(|i| {
+                let s = (std::f32::consts::TAU * hz * i as f32 / 16000.0).sin() * 0.5;
+                ((s * i16::MAX as f32) as i16).to_le_bytes()
+            })
+            .collect::<Vec<u8>>()
+    };
+    let zero_crossings = |pcm: &[u8]| {
+        let samples = pcm
+            .chunks_exact(2)
+            .map(|s| i16::from_le_bytes([s[0], s[1]]))
+            .collect::<Vec<_>>();
+        samples
+            .windows(2)
+            .filter(|w| (w[0] < 0) != (w[1] < 0))
+            .count()
+    };
+    let pcm = sine(200.0, 16000);
+
+    let faster = change_prosody(
+        &pcm,
+        16000,
+        Prosody {
+            speed: Some(2.0),
+            pitch: None,
+        },
+    );
+    assert_eq!(faster.len(), pcm.len() / 2);
+    // the same pitch over half the time
+    let ratio = zero_crossings(&faster) as f32 / zero_crossings(&pcm) as f32;
+    assert!((ratio - 0.5).abs() < 0.05, "{ratio}");
+
+    let higher = change_prosody(
+        &pcm,
+        16000,
+        Prosody {
+            speed: None,
+            pitch: Some(1.5),
+        },
+    );
+    assert!((higher.len() as f32 / pcm.len() as f32 - 1.0).abs() < 0.01);
+    let ratio = zero_crossings(&higher) as f32 / zero_crossings(&pcm) as f32;
+    assert!((ratio - 1.5).abs() < 0.1, "{ratio}");
+}
+
+#[test]
+fn test_raw_audio() {
+    let raw = RawAudioFormat {
+        sample_rate: std::num::NonZeroU32::new(16000).unwrap(),
+        channels: 1,
+        sample_format: RawSampleFormat::S16le,
+    };
+    let (pcm16, _) =
+        audio_to_pcm16(Bytes::from_static(&[1, 0, 2, 0, 3]), Some(raw), 16000).unwrap();
+    assert_eq!(pcm16.len(), 4);
+    assert!(audio_to_pcm16(Bytes::from_static(&[1, 0, 2, 0]), None, 16000).is_err());
+    assert!(serde_json::from_str::<RawAudioFormat>(r#"{"sample_rate": 0}"#).is_err());
 }
 
 pub fn convert_samples_f32_to_i16_bytes(samples: &[f32]) -> Vec<u8> {
@@ -279,3 +805,89 @@ pub fn get_samples_i16(reader: &mut wav_io::reader::Reader) -> Result<Vec<i16>,
     }
     Ok(result)
 }
+
+/// the ids and times of the test / debug mode `[deterministic]`, a sequence of the seed
+#[derive(Debug)]
+pub struct Seeded {
+    seed: u64,
+    ids: std::sync::atomic::AtomicU64,
+    ticks: std::sync::atomic::AtomicU64,
+}
+
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            ids: Default::default(),
+            ticks: Default::default(),
+        }
+    }
+
+    fn mix(&self, n: u64) -> u64 {
+        // splitmix64
+        let mut z = (self.seed ^ n).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn uuid(&self) -> uuid::Uuid {
+        let n = self.ids.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.mix(2 * n).to_le_bytes());
+        bytes[8..].copy_from_slice(&self.mix(2 * n + 1).to_le_bytes());
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+
+    /// `seed` seconds after 2024-01-01, a second later at each call
+    pub fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        let n = self
+            .ticks
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let start = 1_704_067_200 + (self.seed % (1 << 30)) as i64;
+        chrono::DateTime::from_timestamp(start + n as i64, 0).unwrap_or_default()
+    }
+}
+
+static DETERMINISTIC: std::sync::OnceLock<Seeded> = std::sync::OnceLock::new();
+
+/// at startup, before the first session
+pub fn set_deterministic(seed: u64) {
+    if DETERMINISTIC.set(Seeded::new(seed)).is_err() {
+        log::warn!("deterministic mode already set");
+    }
+}
+
+pub fn is_deterministic() -> bool {
+    DETERMINISTIC.get().is_some()
+}
+
+/// a random uuid, the next of the seed in deterministic mode
+pub fn new_uuid() -> uuid::Uuid {
+    match DETERMINISTIC.get() {
+        Some(seeded) => seeded.uuid(),
+        None => uuid::Uuid::new_v4(),
+    }
+}
+
+/// the time of the events, a clock of the seed in deterministic mode
+pub fn now() -> chrono::DateTime<chrono::Utc> {
+    match DETERMINISTIC.get() {
+        Some(seeded) => seeded.now(),
+        None => chrono::Utc::now(),
+    }
+}
+
+#[test]
+fn test_seeded() {
+    let (a, b) = (Seeded::new(42), Seeded::new(42));
+    let ids = (0..3).map(|_| a.uuid()).collect::<Vec<_>>();
+    assert_eq!(ids, (0..3).map(|_| b.uuid()).collect::<Vec<_>>());
+    assert_ne!(ids[0], ids[1]);
+    assert_eq!(ids[0].get_version_num(), 4);
+    assert_ne!(Seeded::new(7).uuid(), ids[0]);
+
+    let t = a.now();
+    assert_eq!(a.now() - t, chrono::Duration::seconds(1));
+    assert_eq!(b.now(), t);
+}